@@ -0,0 +1,51 @@
+#!/bin/sh
+# Boots the exit_marker test without the isa-debug-exit device and checks the kernel still says it
+# passed: the `[QEMU-EXIT] status=success` marker has to show up on serial. With nothing to exit
+# through the kernel ends up halting, so QEMU gets killed once the marker is there or the time is up.
+#
+# usage: scripts/check_exit_marker.sh [seconds]
+
+set -u
+
+seconds=${1:-60}
+
+# the usual run, with the device, also leaves the bootable image next to the test binary
+output=$(cargo test-exit-marker 2>&1)
+status=$?
+echo "$output"
+if [ $status -ne 0 ]; then
+	echo "check_exit_marker: the run with the exit device failed" >&2
+	exit 1
+fi
+
+binary=$(echo "$output" | sed -n 's/.*Running tests\/exit_marker\.rs (\(.*\))$/\1/p')
+image=$(dirname "$binary")/bootimage-$(basename "$binary").bin
+if [ ! -f "$image" ]; then
+	echo "check_exit_marker: no boot image at $image" >&2
+	exit 1
+fi
+
+serial=$(mktemp)
+trap 'rm -f "$serial"' EXIT
+
+# the same as bootimage's test-args, minus the exit device
+qemu-system-x86_64 -drive format=raw,file="$image" -serial "file:$serial" -display none &
+qemu=$!
+
+waited=0
+# the shutdown ports the kernel tries after the marker may end QEMU on their own
+while ! grep -q '\[QEMU-EXIT\] status=success' "$serial" && [ $waited -lt "$seconds" ] &&
+	kill -0 $qemu 2>/dev/null; do
+	sleep 1
+	waited=$((waited + 1))
+done
+kill $qemu 2>/dev/null
+wait $qemu 2>/dev/null
+
+tr -d '\r' <"$serial"
+if ! grep -q '\[QEMU-EXIT\] status=success' "$serial"; then
+	echo "check_exit_marker: no success marker after ${seconds}s without the exit device" >&2
+	exit 1
+fi
+
+echo "check_exit_marker: found the marker without the exit device"
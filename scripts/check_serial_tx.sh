@@ -0,0 +1,31 @@
+#!/bin/sh
+# Runs the serial_tx test and checks the `tx-check` lines made it to the host whole and in order:
+# their count and hash have to match the `tx-check-end` line the kernel printed after them
+#
+# usage: scripts/check_serial_tx.sh
+
+set -u
+
+output=$(cargo test --test serial_tx 2>&1)
+status=$?
+output=$(echo "$output" | tr -d '\r')
+echo "$output"
+
+payload=$(echo "$output" | grep '^tx-check [0-9]')
+lines=$(printf '%s\n' "$payload" | grep -c .)
+# same hash as the test, h * 31 + byte mod 2^32, exact in awk's doubles
+hash=$(printf '%s\n' "$payload" | od -An -v -tu1 |
+	awk '{ for (i = 1; i <= NF; i++) h = (h * 31 + $i) % 4294967296 } END { printf "%.0f\n", h }')
+
+expected=$(echo "$output" | sed -n 's/^tx-check-end \(lines=[0-9]* hash=[0-9]*\)$/\1/p')
+if [ -z "$expected" ]; then
+	echo "check_serial_tx: no tx-check-end line" >&2
+	exit 1
+fi
+if [ "$expected" != "lines=$lines hash=$hash" ]; then
+	echo "check_serial_tx: kernel sent $expected, host got lines=$lines hash=$hash" >&2
+	exit 1
+fi
+
+echo "check_serial_tx: $expected, all there"
+exit $status
@@ -0,0 +1,40 @@
+#!/bin/sh
+# Runs one of the tests that fail on purpose and checks it failed the way it should: QEMU's exit
+# status, the `PANIC code=` line and the `[QEMU-EXIT]` marker all have to carry `code`
+#
+# usage: scripts/expect_exit.sh <cargo alias> <code> [another line to expect]
+#   scripts/expect_exit.sh test-unattended-panic 0x12
+#   scripts/expect_exit.sh test-unattended-double-fault 0x14
+#   scripts/expect_exit.sh test-early-panic 0x12 'KERNEL PANIC (early boot): .*panic-in-init'
+
+set -u
+
+if [ $# -ne 2 ] && [ $# -ne 3 ]; then
+	echo "usage: $0 <cargo alias> <code> [another line to expect]" >&2
+	exit 2
+fi
+
+alias=$1
+code=$2
+# what isa-debug-exit makes of it, see QemuExitCode::host_status
+status=$(((code << 1) | 1))
+
+output=$(cargo "$alias" 2>&1)
+echo "$output"
+
+failed=0
+check() {
+	if ! echo "$output" | grep -q "$1"; then
+		echo "expect_exit: $2" >&2
+		failed=1
+	fi
+}
+
+check "PANIC code=$code msg=\".*\" rip=0x[0-9a-f]* stage=[0-9]" "no PANIC line with code=$code"
+check "\[QEMU-EXIT\] status=[a-z-]* code=$code" "no [QEMU-EXIT] marker with code=$code"
+check "exit status: $status)" "QEMU didn't exit with status $status"
+if [ $# -eq 3 ]; then
+	check "$3" "no line matching '$3'"
+fi
+
+exit $failed
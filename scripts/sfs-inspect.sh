@@ -0,0 +1,18 @@
+#!/bin/sh
+# Runs tools/sfs-inspect on the host, read-only commands on an SFS image file
+#
+# usage: scripts/sfs-inspect.sh <image> <command> [args]
+#        scripts/sfs-inspect.sh --test     runs the tool's own tests
+#
+# Stable cargo and the host target: .cargo/config.toml builds for the kernel target with
+# build-std, and stable cargo leaves its [unstable] table alone.
+
+set -eu
+
+manifest="$(dirname "$0")/../tools/sfs-inspect/Cargo.toml"
+host=$(rustc +stable -vV | sed -n 's/^host: //p')
+
+if [ "${1:-}" = "--test" ]; then
+	exec cargo +stable test --quiet --manifest-path "$manifest" --target "$host"
+fi
+exec cargo +stable run --quiet --manifest-path "$manifest" --target "$host" -- "$@"
@@ -4,9 +4,18 @@ use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
 use linked_list_allocator::LockedHeap;
 
+pub mod attribution;
 pub mod bump;
 pub mod fixed_size_block;
+pub mod leaks;
 pub mod linked_list;
+pub mod shrink;
+pub mod slab;
+
+pub use attribution::{Attribution, Owner, TaskHeap};
+pub use leaks::{AllocCheckpoint, AllocDiff, checkpoint, diff};
+pub use shrink::{ShrinkList, ShrinkerStats, register_shrinker, shrinker_stats};
+pub use slab::{SlabBox, SlabCache, SlabStats, slab_stats};
 
 pub const HEAP_START: usize = 0x_4444_4444_0000; // some range from virtual memory
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB heap size
@@ -91,6 +100,14 @@ pub fn init_heap(
 		}
 	}
 
+	// here and not on first use, registering allocates and the slab caches are used everywhere.
+	// The same goes for what tasks set up once and keep, or the first test to touch it leaks it
+	register_shrinker("slab caches", slab::SHRINKER_PRIORITY, slab::shrink_slabs);
+	crate::fs::block_cache::register_shrinker();
+	crate::fs::dir_index::register_shrinker();
+	crate::task::timer::init_sleepers();
+	crate::task::keyboard::init_channel();
+
 	Ok(())
 }
 
@@ -110,6 +127,11 @@ impl<A> Locked<A> {
 	pub fn lock(&self) -> spin::MutexGuard<'_, A> {
 		self.inner.lock()
 	}
+
+	/// the lock if nobody holds it right now
+	pub fn try_lock(&self) -> Option<spin::MutexGuard<'_, A>> {
+		self.inner.try_lock()
+	}
 }
 
 /// Align the given address 'addr' upwards to alignment 'align'
@@ -133,7 +155,37 @@ use linked_list::LinkedListAllocator;
 // #[global_allocator]
 // static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
 
-use fixed_size_block::FixedSizeBlockAllocator;
+use fixed_size_block::{FixedSizeBlockAllocator, HeapStats};
 
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
\ No newline at end of file
+static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+
+/// returns a snapshot of the kernel heap's counters
+pub fn allocator_stats() -> HeapStats {
+	ALLOCATOR.lock().stats()
+}
+
+/// like `allocator_stats`, but None instead of spinning if the heap is locked, for panic paths
+pub fn try_allocator_stats() -> Option<HeapStats> {
+	ALLOCATOR.try_lock().map(|allocator| allocator.stats())
+}
+
+/// the heap's fragmentation in per mille, see `FixedSizeBlockAllocator::fragmentation`
+pub fn fragmentation() -> u16 {
+	ALLOCATOR.lock().fragmentation()
+}
+
+/// returns a snapshot of the heap bytes each task holds
+pub fn heap_by_task() -> Attribution {
+	*ALLOCATOR.lock().attribution()
+}
+
+/// like `heap_by_task`, but None if the heap is locked
+pub fn try_heap_by_task() -> Option<Attribution> {
+	ALLOCATOR.try_lock().map(|allocator| *allocator.attribution())
+}
+
+/// drops the row of task `id`, the executor calls it once the task is done
+pub fn forget_task_heap(id: u64) {
+	ALLOCATOR.lock().forget_owner(Owner::Task(id));
+}
\ No newline at end of file
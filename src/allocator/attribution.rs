@@ -0,0 +1,127 @@
+// in src/allocator/attribution.rs
+//
+// heap bytes per task, so a full heap can be pinned on whoever filled it
+
+use core::fmt;
+
+/// owners the table keeps a row for at once, the irq row included
+pub const ATTRIBUTION_ROWS: usize = 32;
+
+/// Who an allocation is charged to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+	/// the task with this id, as `Task::id` gives it
+	Task(u64),
+	/// an interrupt handler, which isn't supposed to allocate at all
+	Irq,
+}
+
+impl Owner {
+	/// whoever runs right now, None outside of tasks and interrupt handlers
+	pub fn current() -> Option<Owner> {
+		if crate::interrupts::in_interrupt() {
+			Some(Owner::Irq)
+		} else {
+			crate::task::current_task().map(Owner::Task)
+		}
+	}
+}
+
+/// `task 3` or `irq`
+impl fmt::Display for Owner {
+	fn fmt(
+		&self,
+		f: &mut fmt::Formatter,
+	) -> fmt::Result {
+		match self {
+			Owner::Task(id) => write!(f, "task {}", id),
+			Owner::Irq => write!(f, "irq"),
+		}
+	}
+}
+
+/// One owner's row, see `Attribution`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskHeap {
+	pub owner: Owner,
+	/// bytes charged minus bytes credited, block allocations count with their full block size
+	///
+	/// Negative for a task that freed more than it allocated, which only happens without
+	/// `alloc-debug`.
+	pub bytes: i64,
+	/// allocations charged so far
+	pub allocations: u64,
+}
+
+/// Heap bytes by owner, the allocator keeps it under its lock
+///
+/// Without `alloc-debug` a free is credited to whoever runs when it happens. With it, the table of
+/// outstanding allocations knows the owner an allocation was charged to, and that one gets it
+/// back. Only allocations make rows, a free for an owner without one is dropped, so a row cleared
+/// when its task finished stays gone.
+#[derive(Debug, Clone, Copy)]
+pub struct Attribution {
+	rows: [Option<TaskHeap>; ATTRIBUTION_ROWS],
+	/// allocations that came while every row was taken
+	unattributed: u64,
+}
+
+impl Attribution {
+	pub const fn new() -> Self {
+		Attribution { rows: [None; ATTRIBUTION_ROWS], unattributed: 0 }
+	}
+
+	/// the rows in use, in no particular order
+	pub fn rows(&self) -> impl Iterator<Item = &TaskHeap> {
+		self.rows.iter().flatten()
+	}
+
+	pub fn get(
+		&self,
+		owner: Owner,
+	) -> Option<&TaskHeap> {
+		self.rows().find(|row| row.owner == owner)
+	}
+
+	/// allocations charged to nobody because every row was taken
+	pub fn unattributed(&self) -> u64 {
+		self.unattributed
+	}
+
+	pub(super) fn charge(
+		&mut self,
+		owner: Owner,
+		bytes: usize,
+	) {
+		if let Some(row) = self.rows.iter_mut().flatten().find(|row| row.owner == owner) {
+			row.bytes += bytes as i64;
+			row.allocations += 1;
+			return;
+		}
+		match self.rows.iter_mut().find(|row| row.is_none()) {
+			Some(free) => *free = Some(TaskHeap { owner, bytes: bytes as i64, allocations: 1 }),
+			None => self.unattributed += 1,
+		}
+	}
+
+	pub(super) fn credit(
+		&mut self,
+		owner: Owner,
+		bytes: usize,
+	) {
+		if let Some(row) = self.rows.iter_mut().flatten().find(|row| row.owner == owner) {
+			row.bytes -= bytes as i64;
+		}
+	}
+
+	pub(super) fn clear(
+		&mut self,
+		owner: Owner,
+	) {
+		for row in self.rows.iter_mut() {
+			if row.map_or(false, |row| row.owner == owner) {
+				*row = None;
+			}
+		}
+	}
+}
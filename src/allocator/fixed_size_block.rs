@@ -14,11 +14,35 @@ struct ListNode {
 ///
 /// Each block should be able to store a 64-bit pointer to the next block.
 /// Hence, they cannot be smaller than 8 bytes.
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// one size class per block size, and the last one for everything the fallback heap hands out
+pub const SIZE_CLASSES: usize = BLOCK_SIZES.len() + 1;
 
 pub struct FixedSizeBlockAllocator {
 	list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
 	fallback_allocator: linked_list_allocator::Heap,
+	stats: HeapStats,
+	/// what each task has of the heap
+	attribution: Attribution,
+	#[cfg(feature = "alloc-debug")]
+	outstanding: Outstanding,
+}
+
+/// What the heap is up to, block allocations count with their full block size
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+	pub heap_size: usize,
+	/// bytes handed out and not freed yet
+	pub used: usize,
+	/// the most `used` has ever been
+	pub peak: usize,
+	pub allocations: u64,
+	pub frees: u64,
+	/// allocations that came back null
+	pub failed: u64,
+	/// allocations not freed yet per size class, see `size_class`
+	pub live: [u64; SIZE_CLASSES],
 }
 
 impl FixedSizeBlockAllocator {
@@ -29,9 +53,37 @@ impl FixedSizeBlockAllocator {
 		FixedSizeBlockAllocator {
 			list_heads: [EMPTY; BLOCK_SIZES.len()],
 			fallback_allocator: linked_list_allocator::Heap::empty(),
+			stats: HeapStats {
+				heap_size: 0,
+				used: 0,
+				peak: 0,
+				allocations: 0,
+				frees: 0,
+				failed: 0,
+				live: [0; SIZE_CLASSES],
+			},
+			attribution: Attribution::new(),
+			#[cfg(feature = "alloc-debug")]
+			outstanding: Outstanding::new(),
 		}
 	}
 
+	pub fn stats(&self) -> HeapStats {
+		self.stats
+	}
+
+	pub fn attribution(&self) -> &Attribution {
+		&self.attribution
+	}
+
+	/// drops `owner`'s row, for a task that finished
+	pub fn forget_owner(
+		&mut self,
+		owner: Owner,
+	) {
+		self.attribution.clear(owner);
+	}
+
 	/// Initialize the allocator with the given heap bounds
 	///
 	/// This function is unsafe because the caller must guarantee that the given
@@ -45,6 +97,26 @@ impl FixedSizeBlockAllocator {
 		unsafe {
 			self.fallback_allocator.init(heap_start, heap_size);
 		}
+		self.stats.heap_size = heap_size;
+	}
+
+	/// The largest allocation the fallback heap could hand out right now, in bytes, see
+	/// `largest_fit`
+	pub fn largest_free(&mut self) -> usize {
+		largest_fit(&mut self.fallback_allocator)
+	}
+
+	/// How scattered the fallback heap's free memory is, in per mille
+	///
+	/// `1 - largest_free / total_free`: 0 while it's all one hole, close to 1000 once it's in many
+	/// small ones. Blocks waiting on the free lists aren't in the fallback heap and don't count.
+	pub fn fragmentation(&mut self) -> u16 {
+		let total = self.fallback_allocator.free();
+		if total == 0 {
+			return 0;
+		}
+		let largest = self.largest_free().min(total);
+		(1000 - largest * 1000 / total) as u16
 	}
 
 	/// Allocates using the fallback allocator.
@@ -53,12 +125,90 @@ impl FixedSizeBlockAllocator {
 		layout: Layout,
 	) -> *mut u8 {
 		match self.fallback_allocator.allocate_first_fit(layout) {
-			Ok(ptr) => ptr.as_ptr(),
+			Ok(ptr) => {
+				// the hole's header may have been right there
+				unsafe { wipe(ptr.as_ptr(), HOLE_HEADER.min(layout.size())) };
+				ptr.as_ptr()
+			},
 			Err(_) => ptr::null_mut(),
 		}
 	}
 }
 
+/// Overwrites `len` bytes at `ptr` with zeros if the `zero-on-free` feature is on
+///
+/// Freed blocks get wiped whole, and the free list's own bookkeeping gets wiped again when a block
+/// is handed back out, so nothing a previous owner wrote survives a free.
+///
+/// The price is a memset of the freed size on every free, so small blocks barely notice and
+/// large fallback allocations pay the most. `alloc_free_cost` in tests/heap_allocation.rs prints
+/// the per-pair cycles, run it with and without the feature to compare.
+#[inline]
+unsafe fn wipe(
+	ptr: *mut u8,
+	len: usize,
+) {
+	if cfg!(feature = "zero-on-free") {
+		unsafe { ptr::write_bytes(ptr, 0, len) };
+	}
+}
+
+/// what linked_list_allocator keeps at the start of a hole, its size and the next pointer
+const HOLE_HEADER: usize = 2 * mem::size_of::<usize>();
+
+/// The largest block `heap` could hand out right now, which is its largest hole
+///
+/// linked_list_allocator doesn't let anyone walk its holes, so this asks it for blocks and gives
+/// each one straight back, freeing merges the hole again. What fits isn't monotonic in the size:
+/// a hole takes its own size and anything that leaves a `HOLE_HEADER` behind, but not the sizes
+/// in between. So the search only gets within a header of the largest hole, and the few sizes
+/// above where it stopped are tried one by one.
+fn largest_fit(heap: &mut linked_list_allocator::Heap) -> usize {
+	const ALIGN: usize = mem::align_of::<usize>();
+
+	// in units of ALIGN, `low` fits and `high` doesn't
+	let mut low = 0;
+	let mut high = heap.free() / ALIGN + 1;
+
+	let mut fits = |units: usize| {
+		let layout = Layout::from_size_align(units * ALIGN, ALIGN).unwrap();
+		match heap.allocate_first_fit(layout) {
+			Ok(block) => {
+				unsafe { heap.deallocate(block, layout) };
+				true
+			},
+			Err(_) => false,
+		}
+	};
+
+	while high - low > 1 {
+		let mid = low + (high - low) / 2;
+		if fits(mid) {
+			low = mid;
+		} else {
+			high = mid;
+		}
+	}
+
+	// everything up to a header short of the largest hole fits, so it's at most that far up
+	(low + 1..=low + HOLE_HEADER / ALIGN).rev().find(|&units| fits(units)).unwrap_or(low) * ALIGN
+}
+
+/// bytes an allocation really takes, blocks are always handed out whole
+fn charged_size(layout: &Layout) -> usize {
+	match list_index(layout) {
+		Some(index) => BLOCK_SIZES[index],
+		None => layout.size(),
+	}
+}
+
+/// The size class `layout` is counted in, an index into `HeapStats::live`
+///
+/// Block allocations go by their block size, `BLOCK_SIZES.len()` is the class of the larger ones.
+pub fn size_class(layout: &Layout) -> usize {
+	list_index(layout).unwrap_or(BLOCK_SIZES.len())
+}
+
 /// Choose an appropriate block size for the given layout
 ///
 /// Returns an index into the 'BLOCK_SIZES' array
@@ -67,23 +217,48 @@ fn list_index(layout: &Layout) -> Option<usize> {
 	BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
-use super::Locked;
+use super::{
+	HEAP_SIZE, HEAP_START, Locked,
+	attribution::{Attribution, Owner},
+	shrink,
+};
 use alloc::alloc::GlobalAlloc;
 use core::ptr::NonNull;
 
-unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
-	unsafe fn alloc(
-		&self,
+/// a block on a free list has to lie inside the heap and be aligned to its size
+fn in_heap(
+	block: *mut u8,
+	block_size: usize,
+) -> bool {
+	let addr = block as usize;
+	addr >= HEAP_START && addr + block_size <= HEAP_START + HEAP_SIZE && addr % block_size == 0
+}
+
+impl FixedSizeBlockAllocator {
+	/// Takes a block off the free list or out of the fallback heap, null if there's no room
+	///
+	/// Failures aren't counted here, the shrinkers may still make room. Charges `owner` if there
+	/// is one.
+	fn allocate(
+		&mut self,
 		layout: Layout,
+		owner: Option<Owner>,
 	) -> *mut u8 {
-		let mut allocator = self.lock();
-
-		match list_index(&layout) {
+		let block = match list_index(&layout) {
 			Some(index) => {
-				match allocator.list_heads[index].take() {
+				match self.list_heads[index].take() {
 					Some(node) => {
-						allocator.list_heads[index] = node.next.take();
-						node as *mut ListNode as *mut u8
+						self.list_heads[index] = node.next.take();
+						let block = node as *mut ListNode as *mut u8;
+						crate::kernel_assert!(
+							in_heap(block, BLOCK_SIZES[index]),
+							"free list {} handed out {:p}",
+							index,
+							block
+						);
+						// the next pointer lived there
+						unsafe { wipe(block, mem::size_of::<ListNode>()) };
+						block
 					},
 					None => {
 						// no block exists in list => allocate new block
@@ -93,12 +268,59 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
 						let block_align = block_size;
 						let layout = Layout::from_size_align(block_size, block_align).unwrap();
 
-						allocator.fallback_alloc(layout)
+						self.fallback_alloc(layout)
 					},
 				}
 			},
-			None => allocator.fallback_alloc(layout),
+			None => self.fallback_alloc(layout),
+		};
+
+		if !block.is_null() {
+			self.stats.used += charged_size(&layout);
+			self.stats.peak = self.stats.peak.max(self.stats.used);
+			self.stats.allocations += 1;
+			self.stats.live[size_class(&layout)] += 1;
+			if let Some(owner) = owner {
+				self.attribution.charge(owner, charged_size(&layout));
+			}
+		}
+		block
+	}
+}
+
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+	unsafe fn alloc(
+		&self,
+		layout: Layout,
+	) -> *mut u8 {
+		crate::kernel_assert!(
+			!shrink::in_shrinker(),
+			"allocating {} bytes from inside a shrinker",
+			layout.size()
+		);
+
+		// before the lock, walking the frames is the slow part
+		#[cfg(feature = "alloc-debug")]
+		let callers = callers();
+		let owner = Owner::current();
+
+		let block = self.lock().allocate(layout, owner);
+		if !block.is_null() {
+			#[cfg(feature = "alloc-debug")]
+			self.lock().remember(block, &layout, callers, owner);
+			return block;
 		}
+
+		// out of room, the caches give some back and we try again after every one that did
+		let block = shrink::reclaim(charged_size(&layout), || self.lock().allocate(layout, owner));
+		if block.is_null() {
+			self.lock().stats.failed += 1;
+			return block;
+		}
+
+		#[cfg(feature = "alloc-debug")]
+		self.lock().remember(block, &layout, callers, owner);
+		block
 	}
 
 	unsafe fn dealloc(
@@ -106,10 +328,31 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
 		ptr: *mut u8,
 		layout: Layout,
 	) {
+		#[cfg_attr(not(feature = "alloc-debug"), allow(unused_mut))]
+		let mut owner = Owner::current();
 		let mut allocator = self.lock();
 
+		allocator.stats.used = allocator.stats.used.saturating_sub(charged_size(&layout));
+		allocator.stats.frees += 1;
+		let class = size_class(&layout);
+		allocator.stats.live[class] = allocator.stats.live[class].saturating_sub(1);
+		// the owner it was charged to, if it's still in the table
+		#[cfg(feature = "alloc-debug")]
+		if let Some(allocation) = allocator.outstanding.forget(ptr) {
+			owner = allocation.owner;
+		}
+		if let Some(owner) = owner {
+			allocator.attribution.credit(owner, charged_size(&layout));
+		}
+
 		match list_index(&layout) {
 			Some(index) => {
+				crate::kernel_assert!(
+					in_heap(ptr, BLOCK_SIZES[index]),
+					"freeing {:p} into free list {}",
+					ptr,
+					index
+				);
 				let new_node = ListNode { next: allocator.list_heads[index].take() };
 
 				// verify that block has size and alignment required for storing node
@@ -119,6 +362,7 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
 				let new_node_ptr = ptr as *mut ListNode;
 
 				unsafe {
+					wipe(ptr, BLOCK_SIZES[index]);
 					new_node_ptr.write(new_node);
 					allocator.list_heads[index] = Some(&mut *new_node_ptr);
 				}
@@ -127,9 +371,139 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
 				let ptr = NonNull::new(ptr).unwrap();
 
 				unsafe {
+					wipe(ptr.as_ptr(), layout.size());
 					allocator.fallback_allocator.deallocate(ptr, layout);
 				}
 			},
 		}
 	}
 }
+
+/// return addresses kept per allocation under `alloc-debug`, innermost first
+pub const CALLER_DEPTH: usize = 4;
+
+/// An allocation that wasn't freed yet, as `alloc-debug` remembers it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allocation {
+	pub ptr: usize,
+	/// what it takes of the heap, the whole block for block allocations
+	pub size: usize,
+	/// `HeapStats::allocations` right after it, so later allocations have higher numbers
+	pub seq: u64,
+	/// return addresses from the code that allocated on up, 0 where the frame chain ended
+	pub callers: [u64; CALLER_DEPTH],
+	/// who it was charged to, see `Attribution`
+	pub owner: Option<Owner>,
+}
+
+/// how many outstanding allocations `alloc-debug` remembers, it only counts the others
+#[cfg(feature = "alloc-debug")]
+const TRACKED: usize = 256;
+
+/// The allocations `alloc-debug` remembers, a slot with a null `ptr` is free
+#[cfg(feature = "alloc-debug")]
+struct Outstanding {
+	slots: [Allocation; TRACKED],
+	/// outstanding allocations that came while every slot was taken
+	untracked: usize,
+}
+
+#[cfg(feature = "alloc-debug")]
+impl Outstanding {
+	const fn new() -> Self {
+		const FREE: Allocation =
+			Allocation { ptr: 0, size: 0, seq: 0, callers: [0; CALLER_DEPTH], owner: None };
+		Outstanding { slots: [FREE; TRACKED], untracked: 0 }
+	}
+
+	/// frees the slot of `ptr`, returns what it held
+	fn forget(
+		&mut self,
+		ptr: *mut u8,
+	) -> Option<Allocation> {
+		match self.slots.iter_mut().find(|slot| slot.ptr == ptr as usize) {
+			Some(slot) => {
+				let allocation = *slot;
+				slot.ptr = 0;
+				Some(allocation)
+			},
+			None => {
+				self.untracked = self.untracked.saturating_sub(1);
+				None
+			},
+		}
+	}
+}
+
+/// The return addresses of whoever called into the allocator
+///
+/// Walks the frame pointers, the kernel is built with them. The two innermost frames are this
+/// function's and `alloc`'s own and get skipped.
+#[cfg(feature = "alloc-debug")]
+#[inline(never)]
+fn callers() -> [u64; CALLER_DEPTH] {
+	let mut frames = [0; CALLER_DEPTH + 2];
+	let found = crate::panic_record::capture_backtrace(&mut frames);
+
+	let mut callers = [0; CALLER_DEPTH];
+	for (caller, &frame) in callers.iter_mut().zip(frames[..found].iter().skip(2)) {
+		*caller = frame;
+	}
+	callers
+}
+
+#[cfg(feature = "alloc-debug")]
+impl FixedSizeBlockAllocator {
+	/// Puts `block` in a free slot of the table, or only counts it if there's none
+	fn remember(
+		&mut self,
+		block: *mut u8,
+		layout: &Layout,
+		callers: [u64; CALLER_DEPTH],
+		owner: Option<Owner>,
+	) {
+		let allocation = Allocation {
+			ptr: block as usize,
+			size: charged_size(layout),
+			seq: self.stats.allocations,
+			callers,
+			owner,
+		};
+		match self.outstanding.slots.iter_mut().find(|slot| slot.ptr == 0) {
+			Some(slot) => *slot = allocation,
+			None => self.outstanding.untracked += 1,
+		}
+	}
+
+	/// the remembered allocations that are still outstanding, in no particular order
+	pub fn outstanding(&self) -> impl Iterator<Item = &Allocation> {
+		self.outstanding.slots.iter().filter(|slot| slot.ptr != 0)
+	}
+
+	/// outstanding allocations the table had no room for
+	pub fn untracked(&self) -> usize {
+		self.outstanding.untracked
+	}
+}
+
+#[test_case]
+fn test_largest_fit_is_the_whole_hole() {
+	#[repr(align(16))]
+	struct Arena([u8; 1024]);
+
+	let mut arena = Arena([0; 1024]);
+	let layout = |size| Layout::from_size_align(size, mem::align_of::<usize>()).unwrap();
+
+	// one block at the start and one hole behind it, the hole a different size each time
+	for used in (16..=80).step_by(8) {
+		let mut heap = linked_list_allocator::Heap::empty();
+		unsafe { heap.init(arena.0.as_mut_ptr() as usize, arena.0.len()) };
+		heap.allocate_first_fit(layout(used)).unwrap();
+
+		let hole = heap.free();
+		assert_eq!(largest_fit(&mut heap), hole, "behind {} bytes", used);
+		// and it gave everything back
+		assert_eq!(heap.free(), hole);
+		assert!(heap.allocate_first_fit(layout(hole)).is_ok());
+	}
+}
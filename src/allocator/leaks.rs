@@ -0,0 +1,143 @@
+// in src/allocator/leaks.rs
+//
+// checkpoints of the heap's counters, to tell what a stretch of code left allocated
+
+use super::fixed_size_block::{Allocation, BLOCK_SIZES, CALLER_DEPTH, SIZE_CLASSES};
+use core::fmt;
+
+/// the most leaked allocations an `AllocDiff` lists
+pub const MAX_SAMPLES: usize = 32;
+
+/// What the heap looked like at some point, see `checkpoint`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocCheckpoint {
+	used: usize,
+	live: [u64; SIZE_CLASSES],
+	/// allocations made up to here, the ones after the checkpoint have higher numbers
+	#[cfg_attr(not(feature = "alloc-debug"), allow(dead_code))]
+	allocations: u64,
+}
+
+/// Remembers the bytes in use and the live allocations of every size class
+pub fn checkpoint() -> AllocCheckpoint {
+	let stats = super::allocator_stats();
+	AllocCheckpoint { used: stats.used, live: stats.live, allocations: stats.allocations }
+}
+
+/// How the heap changed since a checkpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocDiff {
+	/// change in bytes in use, block allocations count with their full block size
+	pub bytes: isize,
+	/// change in live allocations per size class, indexed like `HeapStats::live`
+	pub live: [i64; SIZE_CLASSES],
+	samples: [Allocation; MAX_SAMPLES],
+	sample_count: usize,
+}
+
+/// Compares the heap now with `since`
+///
+/// With the `alloc-debug` feature the diff also lists up to `MAX_SAMPLES` allocations made after
+/// the checkpoint that are still around, with where they came from.
+pub fn diff(since: &AllocCheckpoint) -> AllocDiff {
+	const NONE: Allocation =
+		Allocation { ptr: 0, size: 0, seq: 0, callers: [0; CALLER_DEPTH], owner: None };
+
+	let allocator = super::ALLOCATOR.lock();
+	let stats = allocator.stats();
+
+	let mut diff = AllocDiff {
+		bytes: stats.used as isize - since.used as isize,
+		live: [0; SIZE_CLASSES],
+		samples: [NONE; MAX_SAMPLES],
+		sample_count: 0,
+	};
+	for (class, delta) in diff.live.iter_mut().enumerate() {
+		*delta = stats.live[class] as i64 - since.live[class] as i64;
+	}
+
+	#[cfg(feature = "alloc-debug")]
+	for allocation in
+		allocator.outstanding().filter(|allocation| allocation.seq > since.allocations)
+	{
+		if diff.sample_count == MAX_SAMPLES {
+			break;
+		}
+		diff.samples[diff.sample_count] = *allocation;
+		diff.sample_count += 1;
+	}
+	// the table is in no particular order
+	diff.samples[..diff.sample_count].sort_unstable_by_key(|sample| sample.seq);
+
+	diff
+}
+
+impl AllocDiff {
+	/// the heap is back where it was, in bytes and in every size class
+	pub fn is_zero(&self) -> bool {
+		self.bytes == 0 && self.live.iter().all(|&delta| delta == 0)
+	}
+
+	/// Something is still allocated that wasn't at the checkpoint
+	///
+	/// Any size class with more live allocations counts, even if the bytes add up to less.
+	pub fn has_leaks(&self) -> bool {
+		self.bytes > 0 || self.live.iter().any(|&delta| delta > 0)
+	}
+
+	/// Allocations since the checkpoint that are still outstanding, oldest first
+	///
+	/// Always empty without the `alloc-debug` feature.
+	pub fn samples(&self) -> &[Allocation] {
+		&self.samples[..self.sample_count]
+	}
+}
+
+/// the size class's block size, or what's above the largest one
+struct SizeClass(usize);
+
+impl fmt::Display for SizeClass {
+	fn fmt(
+		&self,
+		f: &mut fmt::Formatter,
+	) -> fmt::Result {
+		match BLOCK_SIZES.get(self.0) {
+			Some(size) => write!(f, "{} byte blocks", size),
+			None => write!(f, "over {} bytes", BLOCK_SIZES[BLOCK_SIZES.len() - 1]),
+		}
+	}
+}
+
+/// `+64 bytes (+1 in 64 byte blocks)`, with a line per sample under it
+///
+/// The call sites are return addresses, innermost first. Look them up with `addr2line -e` on the
+/// kernel binary.
+impl fmt::Display for AllocDiff {
+	fn fmt(
+		&self,
+		f: &mut fmt::Formatter,
+	) -> fmt::Result {
+		if self.is_zero() {
+			return write!(f, "no change");
+		}
+
+		write!(f, "{:+} bytes (", self.bytes)?;
+		let mut first = true;
+		for (class, &delta) in self.live.iter().enumerate().filter(|&(_, &delta)| delta != 0) {
+			if !first {
+				write!(f, ", ")?;
+			}
+			write!(f, "{:+} in {}", delta, SizeClass(class))?;
+			first = false;
+		}
+		write!(f, ")")?;
+
+		for sample in self.samples() {
+			write!(f, "\n  {} bytes at {:#x}, from", sample.size, sample.ptr)?;
+			for &caller in sample.callers.iter().take_while(|&&caller| caller != 0) {
+				write!(f, " {:#018x}", caller)?;
+			}
+		}
+		Ok(())
+	}
+}
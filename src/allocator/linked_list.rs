@@ -174,6 +174,11 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
 				}
 			}
 
+			// a ListNode sat there if the region started right at alloc_start
+			if cfg!(feature = "zero-on-free") {
+				unsafe { ptr::write_bytes(alloc_start as *mut u8, 0, mem::size_of::<ListNode>()) };
+			}
+
 			alloc_start as *mut u8
 		} else {
 			ptr::null_mut()
@@ -188,7 +193,12 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
 		// perform layout adjustments
 		let (size, _) = LinkedListAllocator::size_align(layout);
 
-		unsafe { self.lock().add_free_region(ptr as usize, size) }
+		unsafe {
+			if cfg!(feature = "zero-on-free") {
+				ptr::write_bytes(ptr, 0, size);
+			}
+			self.lock().add_free_region(ptr as usize, size)
+		}
 	}
 }
 
@@ -0,0 +1,215 @@
+// in src/allocator/shrink.rs
+//
+// memory pressure callbacks, caches give heap memory back when an allocation wouldn't fit
+
+use crate::sync::Mutex;
+use alloc::{
+	sync::{Arc, Weak},
+	vec::Vec,
+};
+use core::{
+	ptr::null_mut,
+	sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Frees what it can towards `needed_bytes` and returns how many bytes it freed
+///
+/// Runs from inside the allocator, so it must only free: allocating from a shrinker fails a
+/// kernel_assert and gets no help from the other shrinkers. It can't wait on locks either, the
+/// allocation may have come from code holding them, so `try_lock` and give up if that fails.
+pub type ShrinkerFn = fn(needed_bytes: usize) -> usize;
+
+/// What a shrinker has done since it was registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShrinkerStats {
+	pub name: &'static str,
+	pub priority: u8,
+	pub invocations: u64,
+	/// what the shrinker reported as freed, summed up
+	pub reclaimed: u64,
+}
+
+struct Shrinker {
+	callback: ShrinkerFn,
+	stats: ShrinkerStats,
+}
+
+/// registered shrinkers, lowest priority first
+static SHRINKERS: spin::Mutex<Vec<Shrinker>> = spin::Mutex::new(Vec::new());
+
+/// set while the shrinkers run, allocations have no business happening then
+static SHRINKING: AtomicBool = AtomicBool::new(false);
+
+/// Adds a shrinker, asked in `priority` order (lowest first) when the heap runs out
+///
+/// For driver and cache init, registering allocates.
+pub fn register_shrinker(
+	name: &'static str,
+	priority: u8,
+	callback: ShrinkerFn,
+) {
+	let mut shrinkers = SHRINKERS.lock();
+	// behind the ones of the same priority, first come first asked
+	let at = shrinkers.iter().position(|s| s.stats.priority > priority).unwrap_or(shrinkers.len());
+	shrinkers.insert(
+		at,
+		Shrinker {
+			callback,
+			stats: ShrinkerStats { name, priority, invocations: 0, reclaimed: 0 },
+		},
+	);
+}
+
+/// a snapshot of every shrinker's counters, in the order they're asked
+pub fn shrinker_stats() -> Vec<ShrinkerStats> {
+	SHRINKERS.lock().iter().map(|s| s.stats).collect()
+}
+
+/// whether the shrinkers are running right now, the allocator checks it on every allocation
+pub fn in_shrinker() -> bool {
+	SHRINKING.load(Ordering::Acquire)
+}
+
+/// Asks the shrinkers for `needed` bytes, calling `retry` after each one that freed something
+///
+/// Returns what `retry` gave once it isn't null, null if every shrinker had its turn. Call it
+/// without the allocator locked, shrinkers free memory.
+pub(super) fn reclaim(
+	needed: usize,
+	mut retry: impl FnMut() -> *mut u8,
+) -> *mut u8 {
+	if SHRINKING.swap(true, Ordering::AcqRel) {
+		return null_mut();
+	}
+
+	// held while something registers, and that something is the one allocating
+	let block = match SHRINKERS.try_lock() {
+		Some(mut shrinkers) => {
+			let mut block = null_mut();
+			for shrinker in shrinkers.iter_mut() {
+				let freed = (shrinker.callback)(needed);
+				shrinker.stats.invocations += 1;
+				shrinker.stats.reclaimed += freed as u64;
+
+				if freed > 0 {
+					block = retry();
+					if !block.is_null() {
+						break;
+					}
+				}
+			}
+			block
+		},
+		None => null_mut(),
+	};
+
+	SHRINKING.store(false, Ordering::Release);
+	block
+}
+
+/// Every live instance of some cache type, for its shrinker to get at
+///
+/// Each instance sits behind its own lock, the shrinker skips the ones that are locked.
+pub struct ShrinkList<T> {
+	live: spin::Mutex<Vec<Weak<Mutex<T>>>>,
+	/// set once the list is in `LISTS`
+	listed: AtomicBool,
+}
+
+/// what `forget_dropped` needs of a `ShrinkList`, whatever it holds
+trait ForgetDropped: Sync {
+	fn forget_dropped(&self);
+}
+
+/// the most `ShrinkList`s `forget_dropped` reaches, there's one per cache type
+const MAX_LISTS: usize = 8;
+
+/// every `ShrinkList` that tracked something
+static LISTS: spin::Mutex<[Option<&'static dyn ForgetDropped>; MAX_LISTS]> =
+	spin::Mutex::new([None; MAX_LISTS]);
+
+/// Lets go of what the `ShrinkList`s still keep of dropped instances
+///
+/// A list only notices a dropped instance when it tracks the next one, until then its `Weak` holds
+/// on to the instance's allocation. The test runner calls this before it looks for leaks.
+pub fn forget_dropped() {
+	for list in LISTS.lock().iter().flatten() {
+		list.forget_dropped();
+	}
+}
+
+impl<T: Send> ForgetDropped for ShrinkList<T> {
+	fn forget_dropped(&self) {
+		let mut live = self.live.lock();
+		live.retain(|weak| weak.strong_count() > 0);
+		// an empty list holds no heap at all
+		if live.is_empty() {
+			*live = Vec::new();
+		}
+	}
+}
+
+impl<T> ShrinkList<T> {
+	pub const fn new() -> Self {
+		ShrinkList { live: spin::Mutex::new(Vec::new()), listed: AtomicBool::new(false) }
+	}
+
+	/// adds `item`, forgetting the ones that were dropped in the meantime
+	pub fn track(
+		&'static self,
+		item: &Arc<Mutex<T>>,
+	) where
+		T: Send + 'static,
+	{
+		if !self.listed.swap(true, Ordering::AcqRel) {
+			// with no slot left its dropped instances stay until it tracks the next one
+			if let Some(slot) = LISTS.lock().iter_mut().find(|slot| slot.is_none()) {
+				*slot = Some(self);
+			}
+		}
+
+		let mut live = self.live.lock();
+		live.retain(|weak| weak.strong_count() > 0);
+		live.push(Arc::downgrade(item));
+	}
+
+	/// Calls `shrink` with what's still missing on every instance nobody holds, until `needed`
+	/// bytes came free
+	///
+	/// `shrink` returns what it freed, this returns the sum.
+	pub fn shrink(
+		&self,
+		needed: usize,
+		mut shrink: impl FnMut(&mut T, usize) -> usize,
+	) -> usize {
+		// whoever holds it is allocating to track a new instance
+		let live = match self.live.try_lock() {
+			Some(live) => live,
+			None => return 0,
+		};
+
+		let mut freed = 0;
+		for weak in live.iter() {
+			if freed >= needed {
+				break;
+			}
+			let item = match weak.upgrade() {
+				Some(item) => item,
+				None => continue,
+			};
+			if let Some(mut locked) = item.try_lock() {
+				freed += shrink(&mut *locked, needed - freed);
+			}
+		}
+		freed
+	}
+}
+
+/// Runs `free` and returns how much less of the heap is in use after it
+///
+/// For shrinkers to report what they really freed, bookkeeping like tree nodes included.
+pub fn measure_freed(free: impl FnOnce()) -> usize {
+	let before = super::allocator_stats().used;
+	free();
+	before.saturating_sub(super::allocator_stats().used)
+}
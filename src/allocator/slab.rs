@@ -0,0 +1,391 @@
+// in src/allocator/slab.rs
+//
+// typed object caches, small fixed-size kernel objects come out of page sized slabs instead of
+// going through the general allocator every time
+
+use alloc::{
+	alloc::{Layout, alloc, dealloc},
+	vec::Vec,
+};
+use core::{
+	fmt,
+	marker::PhantomData,
+	mem,
+	ops::{Deref, DerefMut},
+	ptr::{self, NonNull, null_mut},
+	sync::atomic::{AtomicBool, Ordering},
+};
+
+/// slabs are a whole number of these
+pub const SLAB_GRANULE: usize = 4096;
+
+/// the most slabs one cache holds at a time
+pub const MAX_SLABS: usize = 16;
+
+/// empty slabs are free memory already, nothing is cheaper to give back
+pub const SHRINKER_PRIORITY: u8 = 5;
+
+/// One block of memory carved into equal slots
+struct Slab {
+	base: NonNull<u8>,
+	bytes: usize,
+	/// first free slot, a free slot holds the address of the next one, null ends the list
+	free: *mut u8,
+	/// slots handed out and not back yet
+	in_use: usize,
+	slots: usize,
+}
+
+impl Slab {
+	fn contains(
+		&self,
+		slot: *mut u8,
+	) -> bool {
+		let start = self.base.as_ptr() as usize;
+		(start..start + self.bytes).contains(&(slot as usize))
+	}
+}
+
+/// the slabs of a cache, behind its lock
+struct Slabs {
+	slabs: [Option<Slab>; MAX_SLABS],
+	in_use: usize,
+	high_water: usize,
+}
+
+// the slabs are the cache's own memory, nothing else points into their free slots
+unsafe impl Send for Slabs {}
+
+/// What a cache holds, see `SlabCache::stats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabStats {
+	pub name: &'static str,
+	/// bytes per slot, the object rounded up to its alignment and at least a pointer
+	pub object_size: usize,
+	pub slabs: usize,
+	/// the slabs' memory taken together
+	pub bytes: usize,
+	/// slots in all slabs
+	pub capacity: usize,
+	pub in_use: usize,
+	/// the most objects that were ever in use at once
+	pub high_water: usize,
+}
+
+/// A cache of `T` sized slots, made for a `static`
+///
+/// The first `alloc` grows it to `capacity` objects, later ones add slabs of the same size when
+/// it's full. Slabs that empty out stay for reuse until the heap runs low, then the shrinker gives
+/// back the ones beyond the first `capacity` objects.
+pub struct SlabCache<T> {
+	name: &'static str,
+	capacity: usize,
+	slabs: spin::Mutex<Slabs>,
+	/// set once the cache is in `CACHES`
+	registered: AtomicBool,
+	_objects: PhantomData<fn() -> T>,
+}
+
+/// An object in a slab slot, the slot goes back to the cache when it's dropped
+pub struct SlabBox<T: 'static> {
+	object: NonNull<T>,
+	cache: &'static SlabCache<T>,
+}
+
+unsafe impl<T: Send + 'static> Send for SlabBox<T> {}
+unsafe impl<T: Sync + 'static> Sync for SlabBox<T> {}
+
+impl<T: 'static> SlabCache<T> {
+	/// the slot alignment, the free list needs a pointer's worth
+	const SLOT_ALIGN: usize = if mem::align_of::<T>() > mem::align_of::<usize>() {
+		mem::align_of::<T>()
+	} else {
+		mem::align_of::<usize>()
+	};
+
+	const SLOT_SIZE: usize = if mem::size_of::<T>() > mem::size_of::<usize>() {
+		mem::size_of::<T>().div_ceil(Self::SLOT_ALIGN) * Self::SLOT_ALIGN
+	} else {
+		Self::SLOT_ALIGN
+	};
+
+	/// an empty cache that grows to `capacity` objects on first use
+	pub const fn new(
+		name: &'static str,
+		capacity: usize,
+	) -> Self {
+		const NONE: Option<Slab> = None;
+		SlabCache {
+			name,
+			capacity,
+			slabs: spin::Mutex::new(Slabs { slabs: [NONE; MAX_SLABS], in_use: 0, high_water: 0 }),
+			registered: AtomicBool::new(false),
+			_objects: PhantomData,
+		}
+	}
+
+	/// Moves `value` into a free slot, None if the cache is full and can't grow
+	pub fn alloc(
+		&'static self,
+		value: T,
+	) -> Option<SlabBox<T>> {
+		let slot = match self.take_slot() {
+			Some(slot) => slot,
+			None => {
+				if !self.grow(self.capacity) {
+					return None;
+				}
+				self.take_slot()?
+			},
+		};
+
+		let object = slot.cast::<T>();
+		unsafe { object.as_ptr().write(value) };
+		Some(SlabBox { object, cache: self })
+	}
+
+	/// Adds a slab with room for at least `objects` more, false if the heap or `MAX_SLABS` says no
+	pub fn grow(
+		&'static self,
+		objects: usize,
+	) -> bool {
+		self.register();
+
+		let bytes = (objects.max(1) * Self::SLOT_SIZE).div_ceil(SLAB_GRANULE) * SLAB_GRANULE;
+		let layout = Self::slab_layout(bytes);
+		// the heap may ask the shrinkers for room, so not under our lock
+		let base = match NonNull::new(unsafe { alloc(layout) }) {
+			Some(base) => base,
+			None => return false,
+		};
+
+		let slots = bytes / Self::SLOT_SIZE;
+		for i in 0..slots {
+			let slot = unsafe { base.as_ptr().add(i * Self::SLOT_SIZE) };
+			let next =
+				if i + 1 < slots { unsafe { slot.add(Self::SLOT_SIZE) } } else { null_mut() };
+			unsafe { (slot as *mut *mut u8).write(next) };
+		}
+		let slab = Slab { base, bytes, free: base.as_ptr(), in_use: 0, slots };
+
+		match self.slabs.lock().slabs.iter_mut().find(|slab| slab.is_none()) {
+			Some(empty) => {
+				*empty = Some(slab);
+				true
+			},
+			None => {
+				unsafe { dealloc(base.as_ptr(), layout) };
+				false
+			},
+		}
+	}
+
+	/// Grows until `objects` slots are free, so they can be had later without the heap
+	pub fn reserve(
+		&'static self,
+		objects: usize,
+	) -> bool {
+		let free = {
+			let slabs = self.slabs.lock();
+			let capacity: usize = slabs.slabs.iter().flatten().map(|slab| slab.slots).sum();
+			capacity - slabs.in_use
+		};
+		free >= objects || self.grow(objects - free)
+	}
+
+	/// a snapshot of the cache's counters
+	pub fn stats(&self) -> SlabStats {
+		let slabs = self.slabs.lock();
+		let mut stats = SlabStats {
+			name: self.name,
+			object_size: Self::SLOT_SIZE,
+			slabs: 0,
+			bytes: 0,
+			capacity: 0,
+			in_use: slabs.in_use,
+			high_water: slabs.high_water,
+		};
+		for slab in slabs.slabs.iter().flatten() {
+			stats.slabs += 1;
+			stats.bytes += slab.bytes;
+			stats.capacity += slab.slots;
+		}
+		stats
+	}
+
+	fn slab_layout(bytes: usize) -> Layout {
+		Layout::from_size_align(bytes, Self::SLOT_ALIGN).expect("slab layout")
+	}
+
+	fn register(&'static self) {
+		if self.registered.swap(true, Ordering::AcqRel) {
+			return;
+		}
+		// with no slot left it still works, it just isn't shrunk or listed
+		if let Some(slot) = CACHES.lock().iter_mut().find(|slot| slot.is_none()) {
+			*slot = Some(self);
+		}
+	}
+
+	/// takes a slot from the fullest slab that has one, so the emptier ones can drain
+	fn take_slot(&self) -> Option<NonNull<u8>> {
+		let mut slabs = self.slabs.lock();
+		let slab = slabs
+			.slabs
+			.iter_mut()
+			.flatten()
+			.filter(|slab| !slab.free.is_null())
+			.max_by_key(|slab| slab.in_use)?;
+
+		let slot = slab.free;
+		slab.free = unsafe { (slot as *mut *mut u8).read() };
+		slab.in_use += 1;
+
+		slabs.in_use += 1;
+		slabs.high_water = slabs.high_water.max(slabs.in_use);
+		NonNull::new(slot)
+	}
+
+	fn give_back(
+		&self,
+		slot: NonNull<u8>,
+	) {
+		let mut slabs = self.slabs.lock();
+		let slab = slabs
+			.slabs
+			.iter_mut()
+			.flatten()
+			.find(|slab| slab.contains(slot.as_ptr()))
+			.expect("slot from another cache");
+
+		unsafe { (slot.as_ptr() as *mut *mut u8).write(slab.free) };
+		slab.free = slot.as_ptr();
+		slab.in_use -= 1;
+		slabs.in_use -= 1;
+	}
+}
+
+/// what the registry needs of a cache, whatever it holds
+trait Registered: Sync {
+	fn stats(&self) -> SlabStats;
+	/// frees empty slabs while more than `keep` slots remain, until `needed` bytes came free
+	fn release_empty(
+		&self,
+		keep: usize,
+		needed: usize,
+	) -> usize;
+	fn capacity(&self) -> usize;
+}
+
+impl<T: 'static> Registered for SlabCache<T> {
+	fn stats(&self) -> SlabStats {
+		SlabCache::stats(self)
+	}
+
+	fn release_empty(
+		&self,
+		keep: usize,
+		needed: usize,
+	) -> usize {
+		// whoever holds it is about to hand out or take back a slot
+		let mut slabs = match self.slabs.try_lock() {
+			Some(slabs) => slabs,
+			None => return 0,
+		};
+
+		let mut slots: usize = slabs.slabs.iter().flatten().map(|slab| slab.slots).sum();
+		let mut freed = 0;
+		for entry in slabs.slabs.iter_mut() {
+			if freed >= needed {
+				break;
+			}
+			let slab = match entry.take() {
+				Some(slab) if slab.in_use == 0 && slots - slab.slots >= keep => slab,
+				other => {
+					*entry = other;
+					continue;
+				},
+			};
+			unsafe { dealloc(slab.base.as_ptr(), Self::slab_layout(slab.bytes)) };
+			slots -= slab.slots;
+			freed += slab.bytes;
+		}
+		freed
+	}
+
+	fn capacity(&self) -> usize {
+		self.capacity
+	}
+}
+
+/// the most caches the registry holds, there's one per object type
+const MAX_CACHES: usize = 8;
+
+/// every cache that grew at least once
+static CACHES: spin::Mutex<[Option<&'static dyn Registered>; MAX_CACHES]> =
+	spin::Mutex::new([None; MAX_CACHES]);
+
+/// a snapshot of every cache's counters, in the order they first grew
+pub fn slab_stats() -> Vec<SlabStats> {
+	let caches = *CACHES.lock();
+	caches.iter().flatten().map(|cache| cache.stats()).collect()
+}
+
+/// Gives back empty slabs beyond each cache's initial capacity, the shrinker
+pub fn shrink_slabs(needed: usize) -> usize {
+	let caches = match CACHES.try_lock() {
+		Some(caches) => *caches,
+		None => return 0,
+	};
+
+	let mut freed = 0;
+	for cache in caches.iter().flatten() {
+		if freed >= needed {
+			break;
+		}
+		freed += cache.release_empty(cache.capacity(), needed - freed);
+	}
+	freed
+}
+
+/// Gives back every empty slab, the initial ones too
+///
+/// The test runner calls this before it looks for leaks, a test that emptied a cache leaves
+/// nothing behind then.
+pub fn release_empty() {
+	let caches = *CACHES.lock();
+	for cache in caches.iter().flatten() {
+		cache.release_empty(0, usize::MAX);
+	}
+}
+
+impl<T: 'static> Deref for SlabBox<T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		unsafe { self.object.as_ref() }
+	}
+}
+
+impl<T: 'static> DerefMut for SlabBox<T> {
+	fn deref_mut(&mut self) -> &mut T {
+		unsafe { self.object.as_mut() }
+	}
+}
+
+impl<T: 'static> Drop for SlabBox<T> {
+	fn drop(&mut self) {
+		// first, the object may hold slots of this cache itself
+		unsafe { ptr::drop_in_place(self.object.as_ptr()) };
+		self.cache.give_back(self.object.cast());
+	}
+}
+
+impl<T: fmt::Debug + 'static> fmt::Debug for SlabBox<T> {
+	fn fmt(
+		&self,
+		f: &mut fmt::Formatter,
+	) -> fmt::Result {
+		fmt::Debug::fmt(&**self, f)
+	}
+}
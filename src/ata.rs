@@ -0,0 +1,415 @@
+// in src/ata.rs
+//
+// ATA PIO, the IDE disks QEMU has when there's no virtio-blk device, polled a sector at a time
+
+use crate::fs::{block_dev::BlockDevice, layout::BLOCK_SIZE, simple_fs::FileSystemError};
+use crate::hw::ports::{self, ClaimedPort};
+use crate::println;
+use alloc::vec::Vec;
+use sa::const_assert_eq;
+
+// a sector is a block
+const_assert_eq!(BLOCK_SIZE, 512);
+
+// registers, as offsets from the channel's I/O base
+const DATA: u16 = 0;
+const ERROR: u16 = 1;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE_HEAD: u16 = 6;
+/// status on reads, command on writes
+const STATUS_COMMAND: u16 = 7;
+
+// status bits
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+/// keeps the drive from raising its IRQ, nothing handles IRQ 14 and 15
+const CONTROL_NIEN: u8 = 1 << 1;
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_CACHE_FLUSH: u8 = 0xE7;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+/// the sector count register is a byte and 0 means 256, so stay below that
+const MAX_SECTORS_PER_COMMAND: usize = 255;
+
+/// READ SECTORS and WRITE SECTORS take 28 bit addresses
+const LBA28_SECTORS: u64 = 1 << 28;
+
+/// status reads before the drive counts as hung
+const POLL_LIMIT: u32 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+	Primary,
+	Secondary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drive {
+	Master,
+	Slave,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaError {
+	/// nothing answered IDENTIFY DEVICE
+	NoDevice,
+	/// an ATAPI or SATA device answered, those want other commands
+	NotAta,
+	/// the drive only does CHS addressing
+	NoLba,
+	/// the drive stayed busy or never asked for data
+	Timeout,
+	/// the drive set ERR, with what its error register said
+	DeviceError(u8),
+	/// the drive set DF
+	DeviceFault,
+}
+
+/// a channel's command block and device control register
+struct ChannelPorts {
+	io: ClaimedPort,
+	control: ClaimedPort,
+	base: u16,
+	control_port: u16,
+}
+
+/// claimed on first use, both drives of a channel share them
+static PRIMARY: spin::Once<ChannelPorts> = spin::Once::new();
+static SECONDARY: spin::Once<ChannelPorts> = spin::Once::new();
+
+impl Channel {
+	fn ports(self) -> &'static ChannelPorts {
+		let (once, base, control_port, owner) = match self {
+			Channel::Primary => (&PRIMARY, 0x1F0, 0x3F6, "ata primary"),
+			Channel::Secondary => (&SECONDARY, 0x170, 0x376, "ata secondary"),
+		};
+		once.call_once(|| ChannelPorts {
+			io: ports::claim(base..=base + STATUS_COMMAND, owner).expect("ATA ports are taken"),
+			// the port above it belongs to the floppy controller
+			control: ports::claim(control_port..=control_port, owner)
+				.expect("ATA control port is taken"),
+			base,
+			control_port,
+		})
+	}
+}
+
+impl ChannelPorts {
+	fn read(
+		&self,
+		register: u16,
+	) -> u8 {
+		unsafe { self.io.read(self.base + register) }
+	}
+
+	fn write(
+		&self,
+		register: u16,
+		value: u8,
+	) {
+		unsafe { self.io.write(self.base + register, value) }
+	}
+
+	fn write_control(
+		&self,
+		value: u8,
+	) {
+		unsafe { self.control.write(self.control_port, value) }
+	}
+
+	/// a selected drive needs 400ns before its status means anything, four reads of the
+	/// alternate status take that long
+	fn delay_400ns(&self) {
+		for _ in 0..4 {
+			let _: u8 = unsafe { self.control.read(self.control_port) };
+		}
+	}
+
+	/// selects `drive` in LBA mode, with the top four bits of a 28 bit address
+	fn select(
+		&self,
+		drive: Drive,
+		lba_top: u8,
+	) {
+		// bits 7 and 5 are always set, bit 6 is LBA addressing
+		let slave = if drive == Drive::Slave { 1 << 4 } else { 0 };
+		self.write(DRIVE_HEAD, 0xE0 | slave | (lba_top & 0x0F));
+		self.delay_400ns();
+	}
+
+	/// waits for BSY to clear and checks the drive didn't report an error
+	fn wait_ready(&self) -> Result<(), AtaError> {
+		for _ in 0..POLL_LIMIT {
+			let status = self.read(STATUS_COMMAND);
+			if status & STATUS_BSY == 0 {
+				return self.check(status);
+			}
+		}
+		Err(AtaError::Timeout)
+	}
+
+	/// waits until the drive has a sector for us or wants one
+	fn wait_drq(&self) -> Result<(), AtaError> {
+		for _ in 0..POLL_LIMIT {
+			let status = self.read(STATUS_COMMAND);
+			if status & STATUS_BSY != 0 {
+				continue;
+			}
+			self.check(status)?;
+			if status & STATUS_DRQ != 0 {
+				return Ok(());
+			}
+		}
+		Err(AtaError::Timeout)
+	}
+
+	fn check(
+		&self,
+		status: u8,
+	) -> Result<(), AtaError> {
+		if status & STATUS_ERR != 0 {
+			Err(AtaError::DeviceError(self.read(ERROR)))
+		} else if status & STATUS_DF != 0 {
+			Err(AtaError::DeviceFault)
+		} else {
+			Ok(())
+		}
+	}
+
+	/// reads one sector from the data register, which hands out 16 bits at a time
+	fn read_sector(
+		&self,
+		sector: &mut [u8],
+	) {
+		for pair in sector.chunks_exact_mut(2) {
+			let word: u16 = unsafe { self.io.read(self.base + DATA) };
+			pair.copy_from_slice(&word.to_le_bytes());
+		}
+	}
+
+	fn write_sector(
+		&self,
+		sector: &[u8],
+	) {
+		for pair in sector.chunks_exact(2) {
+			unsafe { self.io.write(self.base + DATA, u16::from_le_bytes([pair[0], pair[1]])) }
+		}
+	}
+}
+
+/// An ATA disk driven through PIO, a `BlockDevice` with one block per sector
+///
+/// Only LBA28 commands, so at most the first 128 GiB are used. Everything is polled.
+pub struct AtaDrive {
+	channel: Channel,
+	drive: Drive,
+	ports: &'static ChannelPorts,
+	sectors: u64,
+	/// as IDENTIFY DEVICE reports it, padded with spaces
+	model: [u8; 40],
+}
+
+/// Every ATA disk on the two legacy channels, primary master first
+pub fn probe() -> Vec<AtaDrive> {
+	let mut drives = Vec::new();
+	for channel in [Channel::Primary, Channel::Secondary] {
+		for drive in [Drive::Master, Drive::Slave] {
+			if let Ok(found) = AtaDrive::identify(channel, drive) {
+				drives.push(found);
+			}
+		}
+	}
+	drives
+}
+
+impl AtaDrive {
+	/// Sends IDENTIFY DEVICE to `drive` on `channel`, the drive it describes if it's an ATA disk
+	pub fn identify(
+		channel: Channel,
+		drive: Drive,
+	) -> Result<Self, AtaError> {
+		let ports = channel.ports();
+		ports.write_control(CONTROL_NIEN);
+		ports.select(drive, 0);
+
+		// nothing drives the bus, there's no controller
+		if ports.read(STATUS_COMMAND) == 0xFF {
+			return Err(AtaError::NoDevice);
+		}
+
+		for register in [SECTOR_COUNT, LBA_LOW, LBA_MID, LBA_HIGH] {
+			ports.write(register, 0);
+		}
+		ports.write(STATUS_COMMAND, CMD_IDENTIFY);
+		if ports.read(STATUS_COMMAND) == 0 {
+			return Err(AtaError::NoDevice);
+		}
+
+		// ATAPI and SATA devices leave their signature here instead of answering
+		let busy = ports.wait_ready();
+		if ports.read(LBA_MID) != 0 || ports.read(LBA_HIGH) != 0 {
+			return Err(AtaError::NotAta);
+		}
+		busy?;
+		ports.wait_drq()?;
+
+		let mut identity = [0u8; BLOCK_SIZE];
+		ports.read_sector(&mut identity);
+		let word = |i: usize| u16::from_le_bytes([identity[2 * i], identity[2 * i + 1]]) as u64;
+
+		if word(49) & (1 << 9) == 0 {
+			return Err(AtaError::NoLba);
+		}
+		let sectors = (word(60) | word(61) << 16).min(LBA28_SECTORS);
+
+		// words 27 to 46, each with its two chars swapped
+		let mut model = [0u8; 40];
+		for (i, pair) in model.chunks_exact_mut(2).enumerate() {
+			pair[0] = identity[54 + 2 * i + 1];
+			pair[1] = identity[54 + 2 * i];
+		}
+
+		Ok(AtaDrive { channel, drive, ports, sectors, model })
+	}
+
+	pub fn channel(&self) -> Channel {
+		self.channel
+	}
+
+	pub fn drive(&self) -> Drive {
+		self.drive
+	}
+
+	/// the number of sectors LBA28 reaches
+	pub fn sectors(&self) -> u64 {
+		self.sectors
+	}
+
+	pub fn model(&self) -> &str {
+		core::str::from_utf8(&self.model).unwrap_or("?").trim_end()
+	}
+
+	/// True if sector 0 ends in 0x55AA
+	///
+	/// An MBR, most likely the disk the kernel was booted from, QEMU attaches the boot image as
+	/// the primary master.
+	pub fn has_boot_signature(&mut self) -> Result<bool, FileSystemError> {
+		let mut sector = [0u8; BLOCK_SIZE];
+		self.read_blocks(0, &mut sector)?;
+		Ok(sector[510..] == [0x55, 0xAA])
+	}
+
+	fn check_request(
+		&self,
+		block_id: u64,
+		len: usize,
+	) -> Result<(), FileSystemError> {
+		let end = block_id.checked_add((len / BLOCK_SIZE) as u64);
+		match end {
+			Some(end) if len % BLOCK_SIZE == 0 && end <= self.sectors => Ok(()),
+			_ => Err(FileSystemError::BlockError),
+		}
+	}
+
+	/// sends `command` for `count` sectors from `lba` on
+	fn command(
+		&self,
+		command: u8,
+		lba: u64,
+		count: usize,
+	) -> Result<(), AtaError> {
+		self.ports.wait_ready()?;
+		self.ports.select(self.drive, (lba >> 24) as u8);
+		self.ports.write(SECTOR_COUNT, count as u8);
+		self.ports.write(LBA_LOW, lba as u8);
+		self.ports.write(LBA_MID, (lba >> 8) as u8);
+		self.ports.write(LBA_HIGH, (lba >> 16) as u8);
+		self.ports.write(STATUS_COMMAND, command);
+		Ok(())
+	}
+
+	fn read_sectors(
+		&mut self,
+		lba: u64,
+		buffer: &mut [u8],
+	) -> Result<(), AtaError> {
+		for (i, chunk) in buffer.chunks_mut(MAX_SECTORS_PER_COMMAND * BLOCK_SIZE).enumerate() {
+			let lba = lba + (i * MAX_SECTORS_PER_COMMAND) as u64;
+			self.command(CMD_READ_SECTORS, lba, chunk.len() / BLOCK_SIZE)?;
+
+			for sector in chunk.chunks_exact_mut(BLOCK_SIZE) {
+				self.ports.wait_drq()?;
+				self.ports.read_sector(sector);
+			}
+		}
+		Ok(())
+	}
+
+	fn write_sectors(
+		&mut self,
+		lba: u64,
+		buffer: &[u8],
+	) -> Result<(), AtaError> {
+		for (i, chunk) in buffer.chunks(MAX_SECTORS_PER_COMMAND * BLOCK_SIZE).enumerate() {
+			let lba = lba + (i * MAX_SECTORS_PER_COMMAND) as u64;
+			self.command(CMD_WRITE_SECTORS, lba, chunk.len() / BLOCK_SIZE)?;
+
+			for sector in chunk.chunks_exact(BLOCK_SIZE) {
+				self.ports.wait_drq()?;
+				self.ports.write_sector(sector);
+			}
+			// the last sector is only taken once BSY drops again
+			self.ports.wait_ready()?;
+		}
+		Ok(())
+	}
+}
+
+impl BlockDevice for AtaDrive {
+	fn read_blocks(
+		&mut self,
+		block_id: u64,
+		buffer: &mut [u8],
+	) -> Result<(), FileSystemError> {
+		self.check_request(block_id, buffer.len())?;
+		self.read_sectors(block_id, buffer).map_err(|e| {
+			println!("[ATA] Read Error: {:?}", e);
+			FileSystemError::BlockError
+		})
+	}
+
+	fn write_blocks(
+		&mut self,
+		block_id: u64,
+		buffer: &[u8],
+	) -> Result<(), FileSystemError> {
+		self.check_request(block_id, buffer.len())?;
+		self.write_sectors(block_id, buffer).map_err(|e| {
+			println!("[ATA] Write Error: {:?}", e);
+			FileSystemError::BlockError
+		})
+	}
+
+	fn capacity(&self) -> usize {
+		self.sectors as usize
+	}
+
+	/// CACHE FLUSH, the drive may keep accepted writes in its own cache until then
+	fn flush(&mut self) -> Result<(), FileSystemError> {
+		self.ports.wait_ready().map_err(|_| FileSystemError::BlockError)?;
+		self.ports.select(self.drive, 0);
+		self.ports.write(STATUS_COMMAND, CMD_CACHE_FLUSH);
+		self.ports.wait_ready().map_err(|e| {
+			println!("[ATA] Flush Error: {:?}", e);
+			FileSystemError::BlockError
+		})
+	}
+}
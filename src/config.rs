@@ -0,0 +1,424 @@
+// in src/config.rs
+//
+// kernel.cfg, `key = value` settings read from the root directory, and telling whoever uses a
+// setting when it changed
+
+use crate::fs::simple_fs::{FileError, FileSystem};
+use crate::sync::Mutex;
+use crate::task::{keymap, timer};
+use crate::{console, println};
+use alloc::{
+	collections::BTreeMap,
+	string::{String, ToString},
+	vec::Vec,
+};
+use core::fmt;
+
+/// the file in the root directory the settings live in
+pub const CONFIG_FILE: &str = "kernel.cfg";
+
+/// longer files are refused, it's a handful of settings
+pub const CONFIG_FILE_MAX: usize = 4096;
+
+/// how often `watch` looks at the file, about three seconds
+pub const WATCH_INTERVAL_TICKS: u64 = 3 * timer::TICKS_PER_SECOND;
+
+/// settings that are only read at boot, a change gets reported instead of applied
+pub const REBOOT_KEYS: [&str; 1] = ["heap.size"];
+
+/// Checks a new value before anything is applied, the error says what's wrong with it
+///
+/// Gets the filesystem for settings that name a file.
+pub type CheckFn = fn(fs: &dyn FileSystem, key: &str, value: &str) -> Result<(), &'static str>;
+
+/// Puts a value its `CheckFn` let through into effect
+pub type ApplyFn = fn(fs: &dyn FileSystem, key: &str, value: &str);
+
+#[derive(Clone, Copy)]
+struct Subscriber {
+	prefix: &'static str,
+	check: CheckFn,
+	apply: ApplyFn,
+}
+
+static SUBSCRIBERS: Mutex<Vec<Subscriber>> = Mutex::new(Vec::new());
+
+/// the settings applied last, None until the first reload
+static CURRENT: Mutex<Option<KernelConfig>> = Mutex::new(None);
+
+/// size and modification time of the file the last reload read, rejected or not
+static LAST_STAMP: Mutex<Option<(u64, u64)>> = Mutex::new(None);
+
+/// Calls `check` and then `apply` for every changed key that starts with `prefix`
+///
+/// `log.` covers a whole section, `console.mode` just the one key. Subscribers stay for good,
+/// this is for boot and driver init.
+pub fn subscribe(
+	prefix: &'static str,
+	check: CheckFn,
+	apply: ApplyFn,
+) {
+	SUBSCRIBERS.lock().push(Subscriber { prefix, check, apply });
+}
+
+/// Subscribes the console to `console.mode` and the keyboard to `kbd.`, before the first reload
+pub fn subscribe_builtin() {
+	subscribe("console.mode", console::check_config, console::apply_config);
+	subscribe("kbd.", keymap::check_config, keymap::apply_config);
+}
+
+/// The settings of one version of the file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KernelConfig {
+	/// key to value and the line it's on
+	entries: BTreeMap<String, (String, usize)>,
+}
+
+impl KernelConfig {
+	/// Parses `key = value` lines, blank lines and the ones starting with `#` don't count
+	///
+	/// A key set twice keeps the later value.
+	pub fn parse(text: &str) -> Result<Self, ConfigError> {
+		let mut entries = BTreeMap::new();
+		for (i, line) in text.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+
+			let (key, value) = line.split_once('=').ok_or(ConfigError::Syntax { line: i + 1 })?;
+			let (key, value) = (key.trim(), value.trim());
+			if !valid_key(key) {
+				return Err(ConfigError::Syntax { line: i + 1 });
+			}
+			entries.insert(key.to_string(), (value.to_string(), i + 1));
+		}
+		Ok(KernelConfig { entries })
+	}
+
+	pub fn get(
+		&self,
+		key: &str,
+	) -> Option<&str> {
+		self.entries.get(key).map(|(value, _)| value.as_str())
+	}
+
+	/// the keys and values, sorted by key
+	pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+		self.entries.iter().map(|(key, (value, _))| (key.as_str(), value.as_str()))
+	}
+
+	/// Keys that are new in `self` or have another value than in `old`, with their line
+	///
+	/// Keys taken out of the file aren't in here, what they set stays until the next reboot.
+	fn changes_from<'a>(
+		&'a self,
+		old: &'a KernelConfig,
+	) -> impl Iterator<Item = (&'a str, &'a str, usize)> {
+		self.entries
+			.iter()
+			.filter(move |(key, (value, _))| old.get(key) != Some(value.as_str()))
+			.map(|(key, (value, line))| (key.as_str(), value.as_str(), *line))
+	}
+}
+
+/// a key is one word without `=`
+fn valid_key(key: &str) -> bool {
+	!key.is_empty() && !key.contains(|c: char| c.is_whitespace() || c == '=')
+}
+
+fn is_reboot_key(key: &str) -> bool {
+	REBOOT_KEYS.contains(&key)
+}
+
+/// the value `key` has since the last reload, None if it isn't set or nothing was loaded yet
+pub fn get(key: &str) -> Option<String> {
+	CURRENT.lock().as_ref()?.get(key).map(String::from)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+	/// a line that is neither `key = value`, a comment nor blank
+	Syntax {
+		line: usize,
+	},
+	/// a subscriber turned the value down, nothing of the file was applied
+	Rejected {
+		key: String,
+		line: usize,
+		reason: &'static str,
+	},
+	/// the file is longer than `CONFIG_FILE_MAX`
+	TooLarge,
+	NotUtf8,
+	File(FileError),
+}
+
+impl fmt::Display for ConfigError {
+	fn fmt(
+		&self,
+		f: &mut fmt::Formatter,
+	) -> fmt::Result {
+		match self {
+			ConfigError::Syntax { line } => {
+				write!(f, "{} line {}: not `key = value`", CONFIG_FILE, line)
+			},
+			ConfigError::Rejected { key, line, reason } => {
+				write!(f, "{} line {}: {}: {}", CONFIG_FILE, line, key, reason)
+			},
+			ConfigError::TooLarge => {
+				write!(f, "{} is over {} bytes", CONFIG_FILE, CONFIG_FILE_MAX)
+			},
+			ConfigError::NotUtf8 => write!(f, "{} isn't UTF-8", CONFIG_FILE),
+			ConfigError::File(e) => write!(f, "{}: {:?}", CONFIG_FILE, e),
+		}
+	}
+}
+
+/// What a reload did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReloadReport {
+	/// changed keys the subscribers took
+	pub applied: Vec<String>,
+	/// changed keys out of `REBOOT_KEYS`, the new value counts from the next boot
+	pub needs_reboot: Vec<String>,
+	/// changed keys nobody subscribed to
+	pub ignored: Vec<String>,
+}
+
+impl ReloadReport {
+	pub fn is_empty(&self) -> bool {
+		self.applied.is_empty() && self.needs_reboot.is_empty() && self.ignored.is_empty()
+	}
+}
+
+/// `applied a, b; c needs a reboot; nobody uses d`, or `no changes`
+impl fmt::Display for ReloadReport {
+	fn fmt(
+		&self,
+		f: &mut fmt::Formatter,
+	) -> fmt::Result {
+		if self.is_empty() {
+			return write!(f, "no changes");
+		}
+
+		let parts = [
+			("applied ", "", &self.applied),
+			("", " needs a reboot", &self.needs_reboot),
+			("nobody uses ", "", &self.ignored),
+		];
+		let mut first = true;
+		for (before, after, keys) in parts.iter().filter(|(_, _, keys)| !keys.is_empty()) {
+			if !first {
+				write!(f, "; ")?;
+			}
+			write!(f, "{}{}{}", before, keys.join(", "), after)?;
+			first = false;
+		}
+		Ok(())
+	}
+}
+
+/// A checked reload, `commit` applies it
+struct Plan {
+	config: KernelConfig,
+	/// per changed key the subscribers to apply it
+	due: Vec<(String, String, Vec<ApplyFn>)>,
+	report: ReloadReport,
+}
+
+/// Parses `text` and has every subscriber check its changed keys, applies nothing
+fn plan(
+	fs: &dyn FileSystem,
+	text: &str,
+) -> Result<Plan, ConfigError> {
+	let config = KernelConfig::parse(text)?;
+	let old = CURRENT.lock().clone();
+	// at boot everything is new, and the boot-only settings were read already
+	let first = old.is_none();
+	let old = old.unwrap_or_default();
+	// copied out, so a subscriber can subscribe or look at the config
+	let subscribers = SUBSCRIBERS.lock().clone();
+
+	let mut due = Vec::new();
+	let mut report = ReloadReport::default();
+	for (key, value, line) in config.changes_from(&old) {
+		if is_reboot_key(key) {
+			if !first {
+				report.needs_reboot.push(key.to_string());
+			}
+			continue;
+		}
+
+		let mut apply = Vec::new();
+		for subscriber in subscribers.iter().filter(|s| key.starts_with(s.prefix)) {
+			(subscriber.check)(fs, key, value).map_err(|reason| ConfigError::Rejected {
+				key: key.to_string(),
+				line,
+				reason,
+			})?;
+			apply.push(subscriber.apply);
+		}
+
+		if apply.is_empty() {
+			report.ignored.push(key.to_string());
+		} else {
+			report.applied.push(key.to_string());
+			due.push((key.to_string(), value.to_string(), apply));
+		}
+	}
+
+	Ok(Plan { config, due, report })
+}
+
+fn commit(
+	fs: &dyn FileSystem,
+	plan: Plan,
+) -> ReloadReport {
+	for (key, value, apply) in plan.due.iter() {
+		for apply in apply {
+			apply(fs, key, value);
+		}
+	}
+	*CURRENT.lock() = Some(plan.config);
+	plan.report
+}
+
+/// size and modification time of the file, what `check` compares
+fn stamp(fs: &dyn FileSystem) -> Result<(u64, u64), ConfigError> {
+	let handle = fs.open_file(CONFIG_FILE).map_err(ConfigError::File)?;
+	let inode = fs.stat(handle).map_err(ConfigError::File)?;
+	Ok((inode.size_in_bytes, inode.last_modification_time))
+}
+
+fn read_config(fs: &dyn FileSystem) -> Result<String, ConfigError> {
+	let handle = fs.open_file(CONFIG_FILE).map_err(ConfigError::File)?;
+	// one byte more, so a file that's too long shows
+	let mut bytes = alloc::vec![0u8; CONFIG_FILE_MAX + 1];
+	let len = fs.read_file(handle, &mut bytes).map_err(ConfigError::File)?;
+	if len > CONFIG_FILE_MAX {
+		return Err(ConfigError::TooLarge);
+	}
+	bytes.truncate(len);
+	String::from_utf8(bytes).map_err(|_| ConfigError::NotUtf8)
+}
+
+/// Reads the file again and applies what changed since the last reload
+///
+/// All or nothing: a line that doesn't parse or a value a subscriber turns down leaves every
+/// setting as it was.
+pub fn reload(fs: &dyn FileSystem) -> Result<ReloadReport, ConfigError> {
+	let stamp = stamp(fs)?;
+	// even if it's rejected, so `watch` warns about the file once and not on every look
+	*LAST_STAMP.lock() = Some(stamp);
+
+	let text = read_config(fs)?;
+	let plan = plan(fs, &text)?;
+	Ok(commit(fs, plan))
+}
+
+/// Reloads if the file's size or modification time moved since the last reload, None if not
+///
+/// Modification times are in whole seconds, two writes of the same length within one second
+/// look like one. `reload` reads the file regardless.
+pub fn check(fs: &dyn FileSystem) -> Result<Option<ReloadReport>, ConfigError> {
+	if Some(stamp(fs)?) == *LAST_STAMP.lock() {
+		return Ok(None);
+	}
+	reload(fs).map(Some)
+}
+
+/// `text` with `key` set to `value`, on the line that set it last or on a new one at the end
+fn with_value(
+	text: &str,
+	key: &str,
+	value: &str,
+) -> String {
+	let setting = alloc::format!("{} = {}", key, value);
+	let mut lines: Vec<&str> = text.lines().collect();
+
+	let sets_key = |line: &&str| {
+		line.split_once('=').map_or(false, |(k, _)| k.trim() == key)
+			&& !line.trim_start().starts_with('#')
+	};
+	match lines.iter().rposition(sets_key) {
+		Some(i) => lines[i] = &setting,
+		None => lines.push(&setting),
+	}
+
+	let mut text = lines.join("\n");
+	text.push('\n');
+	text
+}
+
+/// Sets `key` to `value` in the file and applies it like a reload
+///
+/// Checked before anything is written, a value the subscribers turn down never reaches the file.
+/// The file is rewritten in place and fsynced, a crash in between can leave a mix of the two.
+pub fn set(
+	fs: &mut dyn FileSystem,
+	key: &str,
+	value: &str,
+) -> Result<ReloadReport, ConfigError> {
+	let old = match read_config(fs) {
+		Ok(text) => text,
+		Err(ConfigError::File(FileError::FileNotFound)) => String::new(),
+		Err(e) => return Err(e),
+	};
+	if !valid_key(key) || value.contains('\n') {
+		return Err(ConfigError::Syntax { line: old.lines().count() + 1 });
+	}
+	let text = with_value(&old, key, value);
+	if text.len() > CONFIG_FILE_MAX {
+		return Err(ConfigError::TooLarge);
+	}
+	let plan = plan(fs, &text)?;
+
+	let handle = match fs.open_file(CONFIG_FILE) {
+		Err(FileError::FileNotFound) => fs.create_file(CONFIG_FILE),
+		found => found,
+	}
+	.map_err(ConfigError::File)?;
+	fs.write_file(handle, text.as_bytes()).map_err(ConfigError::File)?;
+	fs.fsync(handle).map_err(ConfigError::File)?;
+	*LAST_STAMP.lock() = Some(stamp(fs)?);
+
+	Ok(commit(fs, plan))
+}
+
+/// Checks the file every `interval_ticks` and applies what changed, forever
+///
+/// The first look applies the whole file. A missing file is fine, the built in defaults stay.
+pub async fn watch<F: FileSystem>(
+	fs: F,
+	interval_ticks: u64,
+) {
+	loop {
+		match check(&fs) {
+			Ok(Some(report)) => println!("[Config] {}: {}", CONFIG_FILE, report),
+			Ok(None) | Err(ConfigError::File(FileError::FileNotFound)) => {},
+			Err(e) => println!("[Config] WARNING: {}, keeping the old settings", e),
+		}
+		timer::sleep_ticks(interval_ticks).await;
+	}
+}
+
+#[test_case]
+fn test_config_parses_and_rewrites() {
+	let text = "# boot settings\nconsole.mode = serial\n\nkbd.layout=us\nconsole.mode = all\n";
+	let config = KernelConfig::parse(text).unwrap();
+	assert_eq!(config.get("console.mode"), Some("all"));
+	assert_eq!(config.get("kbd.layout"), Some("us"));
+	assert_eq!(config.iter().count(), 2);
+
+	assert_eq!(KernelConfig::parse("a = 1\nno value"), Err(ConfigError::Syntax { line: 2 }));
+	assert_eq!(KernelConfig::parse(" = 1"), Err(ConfigError::Syntax { line: 1 }));
+
+	// the last line setting the key is the one that counts, so that's the one replaced
+	assert_eq!(
+		with_value(text, "console.mode", "vga"),
+		"# boot settings\nconsole.mode = serial\n\nkbd.layout=us\nconsole.mode = vga\n"
+	);
+	assert_eq!(with_value("# a = 1", "a", "2"), "# a = 1\na = 2\n");
+	assert_eq!(with_value("", "a", "2"), "a = 2\n");
+}
@@ -0,0 +1,189 @@
+// in src/console.rs
+//
+// fans the output of print!/println! out to a configurable set of targets
+
+use crate::fs::simple_fs::FileSystem;
+use core::fmt;
+use core::ops::BitOr;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// Something the console can send formatted output to
+///
+/// Implementors have to take care of their own locking, the console calls them from anywhere
+pub trait OutputTarget: Sync {
+	fn write_args(
+		&self,
+		args: fmt::Arguments,
+	);
+}
+
+/// the VGA text buffer
+pub struct VgaTarget;
+
+impl OutputTarget for VgaTarget {
+	fn write_args(
+		&self,
+		args: fmt::Arguments,
+	) {
+		crate::vga_buffer::_print(args);
+	}
+}
+
+/// the first serial port (COM1)
+pub struct SerialTarget;
+
+impl OutputTarget for SerialTarget {
+	fn write_args(
+		&self,
+		args: fmt::Arguments,
+	) {
+		crate::serial::_print(args);
+	}
+}
+
+/// the framebuffer console, see `fb::init`
+pub struct FramebufferTarget;
+
+impl OutputTarget for FramebufferTarget {
+	fn write_args(
+		&self,
+		args: fmt::Arguments,
+	) {
+		crate::fb::_print(args);
+	}
+}
+
+/// set of output targets, combine them with `|`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Targets(u8);
+
+impl Targets {
+	pub const NONE: Targets = Targets(0);
+	pub const VGA: Targets = Targets(1 << 0);
+	pub const SERIAL: Targets = Targets(1 << 1);
+	pub const FRAMEBUFFER: Targets = Targets(1 << 2);
+	pub const ALL: Targets = Targets(Self::VGA.0 | Self::SERIAL.0 | Self::FRAMEBUFFER.0);
+
+	/// true if every target in `other` is also in `self`
+	pub const fn contains(
+		self,
+		other: Targets,
+	) -> bool {
+		self.0 & other.0 == other.0
+	}
+}
+
+/// a target name that isn't `serial`, `vga`, `fb` or `all`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseTargetsError;
+
+/// `serial`, `vga`, `fb` or `all`, several joined with `,` like `serial,vga`
+impl FromStr for Targets {
+	type Err = ParseTargetsError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		s.split(',').try_fold(Targets::NONE, |targets, name| {
+			let target = match name.trim() {
+				"serial" => Targets::SERIAL,
+				"vga" => Targets::VGA,
+				"fb" => Targets::FRAMEBUFFER,
+				"all" => Targets::ALL,
+				_ => return Err(ParseTargetsError),
+			};
+			Ok(targets | target)
+		})
+	}
+}
+
+impl BitOr for Targets {
+	type Output = Targets;
+
+	fn bitor(
+		self,
+		rhs: Targets,
+	) -> Targets {
+		Targets(self.0 | rhs.0)
+	}
+}
+
+/// every target the console knows about, together with the bit enabling it
+static REGISTERED: [(Targets, &dyn OutputTarget); 3] = [
+	(Targets::VGA, &VgaTarget),
+	(Targets::SERIAL, &SerialTarget),
+	(Targets::FRAMEBUFFER, &FramebufferTarget),
+];
+
+/// currently enabled targets -- serial by default since the QEMU window doesn't show the full
+/// output
+static TARGETS: AtomicU8 = AtomicU8::new(Targets::SERIAL.0);
+
+/// selects where print!/println! output goes from now on
+pub fn set_targets(targets: Targets) {
+	TARGETS.store(targets.0, Ordering::Relaxed);
+}
+
+/// returns the currently enabled targets
+pub fn targets() -> Targets {
+	Targets(TARGETS.load(Ordering::Relaxed))
+}
+
+/// `console.mode` in kernel.cfg, see `config::subscribe`
+pub fn check_config(
+	_fs: &dyn FileSystem,
+	_key: &str,
+	value: &str,
+) -> Result<(), &'static str> {
+	value
+		.parse::<Targets>()
+		.map(|_| ())
+		.map_err(|_| "not a console mode, try serial, vga, fb or all")
+}
+
+pub fn apply_config(
+	_fs: &dyn FileSystem,
+	_key: &str,
+	value: &str,
+) {
+	if let Ok(targets) = value.parse() {
+		set_targets(targets);
+	}
+}
+
+/// Writes the arguments to every enabled target
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+	let enabled = targets();
+
+	for (bit, target) in REGISTERED.iter() {
+		if enabled.contains(*bit) {
+			target.write_args(args);
+		}
+	}
+}
+
+#[test_case]
+fn test_set_targets_routing() {
+	use crate::println;
+	use crate::vga_buffer::{BUFFER_HEIGHT, WRITER};
+	use x86_64::instructions::interrupts;
+
+	let previous = targets();
+
+	let vga_line = "console routed to vga";
+	set_targets(Targets::VGA);
+	println!("\n{}", vga_line);
+
+	// only serial now, so the VGA buffer must not scroll
+	set_targets(Targets::SERIAL);
+	println!("\nconsole routed to serial");
+
+	set_targets(previous);
+
+	interrupts::without_interrupts(|| {
+		let writer = WRITER.lock();
+		for (i, c) in vga_line.chars().enumerate() {
+			assert_eq!(char::from(writer.char_at(BUFFER_HEIGHT - 2, i)), c);
+		}
+	});
+}
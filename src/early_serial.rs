@@ -0,0 +1,86 @@
+// in src/early_serial.rs
+//
+// polled COM1 output that works before interrupts, the heap or any lazy_static exist
+
+use crate::port_io;
+use core::fmt;
+
+/// same port SERIAL1 uses, so the output ends up in the same log
+const COM1: u16 = 0x3F8;
+
+// register offsets from the base port
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+/// transmitter holding register empty
+const LINE_STATUS_THR_EMPTY: u8 = 1 << 5;
+
+/// Programs COM1 for 38400 baud 8N1 without interrupts
+///
+/// Meant to be the very first thing the kernel does. SERIAL1 reprograms the same settings when it
+/// gets initialized later, which is fine.
+pub fn init() {
+	unsafe {
+		port_io!(u8, COM1 + INTERRUPT_ENABLE).write(0x00); // we poll, no interrupts
+		port_io!(u8, COM1 + LINE_CONTROL).write(0x80); // DLAB on to set the divisor
+		port_io!(u8, COM1 + DATA).write(0x03); // divisor 3 -> 38400 baud, low byte
+		port_io!(u8, COM1 + INTERRUPT_ENABLE).write(0x00); // high byte
+		port_io!(u8, COM1 + LINE_CONTROL).write(0x03); // DLAB off, 8 bits, no parity, 1 stop
+		port_io!(u8, COM1 + FIFO_CONTROL).write(0xC7); // FIFO on and cleared
+		port_io!(u8, COM1 + MODEM_CONTROL).write(0x0B); // DTR, RTS, OUT2
+	}
+}
+
+/// writes a single byte, spinning until the UART can take it
+pub fn write_byte(byte: u8) {
+	unsafe {
+		let mut line_status = port_io!(u8, COM1 + LINE_STATUS);
+		while line_status.read() & LINE_STATUS_THR_EMPTY == 0 {
+			core::hint::spin_loop();
+		}
+		port_io!(u8, COM1 + DATA).write(byte);
+	}
+}
+
+/// No state and no lock, every write goes straight to the port
+pub struct EarlyWriter;
+
+impl fmt::Write for EarlyWriter {
+	fn write_str(
+		&mut self,
+		s: &str,
+	) -> fmt::Result {
+		for byte in s.bytes() {
+			write_byte(byte);
+		}
+		Ok(())
+	}
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+	use core::fmt::Write;
+
+	// nothing here can fail, and there would be nowhere to report it anyway
+	let _ = EarlyWriter.write_fmt(args);
+}
+
+/// prints to serial without touching SERIAL1, usable before anything else is set up
+#[macro_export]
+macro_rules! early_print {
+	($($arg:tt)*) => {
+		$crate::early_serial::_print(format_args!($($arg)*))
+	};
+}
+
+/// prints to serial without touching SERIAL1, appending a newline
+#[macro_export]
+macro_rules! early_println {
+	() => ($crate::early_print!("\n"));
+	($fmt:expr) => ($crate::early_print!(concat!($fmt, "\n")));
+	($fmt:expr, $($arg:tt)*) => ($crate::early_print!(concat!($fmt, "\n"), $($arg)*));
+}
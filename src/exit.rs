@@ -0,0 +1,174 @@
+// in src/exit.rs
+//
+// leaving QEMU even when the isa-debug-exit device isn't attached
+//
+// CI and `cargo test` need the device, `test-args` in Cargo.toml attaches it. Without it the exit
+// code never reaches the host, all a runner gets is the `[QEMU-EXIT]` marker on serial.
+
+use crate::{
+	BootStage, QemuExitCode, boot_stage, early_println, exit_qemu, hlt_loop, port_io,
+	serial_println,
+};
+use core::{
+	convert::Infallible,
+	fmt::{self, Display, Write},
+	panic::PanicInfo,
+};
+
+/// how long we keep spinning after the exit port write before deciding the device is missing
+const EXIT_SPIN_ITERATIONS: usize = 1_000_000;
+
+/// Why we're still running after trying to leave QEMU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitError {
+	/// the isa-debug-exit write went nowhere, QEMU runs without the device or this isn't QEMU
+	DeviceMissing,
+}
+
+impl Display for ExitError {
+	fn fmt(
+		&self,
+		f: &mut fmt::Formatter,
+	) -> fmt::Result {
+		match self {
+			ExitError::DeviceMissing => write!(
+				f,
+				"no isa-debug-exit device, start QEMU with -device isa-debug-exit,iobase=0xf4,iosize=0x04"
+			),
+		}
+	}
+}
+
+/// Writes the exit code to the isa-debug-exit port
+///
+/// Only comes back if we are still running after a short delay, which means the device isn't
+/// attached and the write went nowhere.
+pub fn try_exit_qemu(exit_code: QemuExitCode) -> Result<Infallible, ExitError> {
+	exit_qemu(exit_code);
+
+	for _ in 0..EXIT_SPIN_ITERATIONS {
+		core::hint::spin_loop();
+	}
+
+	Err(ExitError::DeviceMissing)
+}
+
+/// Tries the ACPI shutdown ports of the usual emulators
+///
+/// Only returns if none of them powered the machine off
+pub fn shutdown() {
+	unsafe {
+		// QEMU (q35/piix with ACPI)
+		port_io!(u16, 0x604).write(0x2000);
+		// Bochs and older QEMU
+		port_io!(u16, 0xB004).write(0x2000);
+		// VirtualBox
+		port_io!(u16, 0x4004).write(0x3400);
+	}
+}
+
+/// Leaves QEMU with the given code no matter how it was started
+///
+/// The serial marker goes out first so a runner script can grep the result even if neither the
+/// exit device nor a shutdown port works, in which case we print a banner and halt.
+pub fn exit_qemu_or_halt(exit_code: QemuExitCode) -> ! {
+	exit_code.emit_marker();
+
+	// the exit code is lost from here on, the host sees a plain shutdown at best
+	let Err(err) = try_exit_qemu(exit_code);
+	print_either(format_args!("warning: {}, trying the shutdown ports", err));
+	shutdown();
+
+	print_either(format_args!("=================================================="));
+	print_either(format_args!("TESTS COMPLETE, exit device missing, halting"));
+	print_either(format_args!("=================================================="));
+
+	hlt_loop();
+}
+
+/// a line on SERIAL1, or through the early writer if SERIAL1 isn't up yet
+fn print_either(line: fmt::Arguments) {
+	if boot_stage() < BootStage::SerialReady {
+		early_println!("{}", line);
+	} else {
+		serial_println!("{}", line);
+	}
+}
+
+/// `Display` of the inner value with `"`, `\` and line breaks escaped, so it fits between quotes
+/// on one line
+struct Escaped<'a>(&'a dyn Display);
+
+/// forwards to a Formatter, escaping on the way
+struct EscapingWriter<'a, 'b>(&'a mut fmt::Formatter<'b>);
+
+impl Write for EscapingWriter<'_, '_> {
+	fn write_str(
+		&mut self,
+		s: &str,
+	) -> fmt::Result {
+		for c in s.chars() {
+			match c {
+				'"' => self.0.write_str("\\\"")?,
+				'\\' => self.0.write_str("\\\\")?,
+				'\n' => self.0.write_str("\\n")?,
+				'\r' => self.0.write_str("\\r")?,
+				c => self.0.write_char(c)?,
+			}
+		}
+		Ok(())
+	}
+}
+
+impl Display for Escaped<'_> {
+	fn fmt(
+		&self,
+		f: &mut fmt::Formatter,
+	) -> fmt::Result {
+		write!(EscapingWriter(f), "{}", self.0)
+	}
+}
+
+/// Ends an unattended run: prints the failure line and leaves QEMU with `code`
+///
+/// The line is the last thing on serial before the `[QEMU-EXIT]` marker, one line no matter
+/// what `msg` holds:
+///
+/// `PANIC code=0x12 msg="..." rip=0x... stage=N`
+///
+/// with `stage` the `BootStage` the kernel got to.
+pub fn report_failure(
+	code: QemuExitCode,
+	msg: &dyn Display,
+	rip: u64,
+) -> ! {
+	let stage = boot_stage();
+	crate::serial::enter_panic();
+	if stage < BootStage::SerialReady {
+		early_println!(
+			"PANIC code={:#x} msg=\"{}\" rip={:#x} stage={}",
+			code as u32,
+			Escaped(msg),
+			rip,
+			stage as u8
+		);
+	} else {
+		serial_println!(
+			"PANIC code={:#x} msg=\"{}\" rip={:#x} stage={}",
+			code as u32,
+			Escaped(msg),
+			rip,
+			stage as u8
+		);
+	}
+
+	exit_qemu_or_halt(code);
+}
+
+/// `report_failure` for a panic, with the panic message and the KernelPanic code
+pub fn report_panic(
+	info: &PanicInfo,
+	rip: u64,
+) -> ! {
+	report_failure(QemuExitCode::KernelPanic, &info.message(), rip);
+}
@@ -0,0 +1,464 @@
+// in src/fb.rs
+//
+// linear 32-bit framebuffer, the kind UEFI GOP hands over
+
+pub mod font;
+
+use alloc::{vec, vec::Vec};
+use core::{
+	fmt, ptr,
+	sync::atomic::{AtomicU64, Ordering},
+};
+use font::{GLYPH_HEIGHT, GLYPH_WIDTH};
+use spin::Mutex;
+
+/// The framebuffer, if the bootloader set one up, with the text console drawing on it
+///
+/// bootloader 0.9 only boots through the BIOS and keeps VGA text mode, so this stays None
+/// until the kernel moves to a bootloader whose `BootInfo` describes a framebuffer.
+/// Raw pixels go through `FramebufferConsole::framebuffer`.
+pub static FRAMEBUFFER: Mutex<Option<FramebufferConsole>> = Mutex::new(None);
+
+/// The double buffered screen, for drawing whole frames; the timer presents it
+///
+/// Separate from `FRAMEBUFFER`, only one of them should be set up on the same memory.
+pub static DOUBLE_BUFFER: Mutex<Option<DoubleBufferedFb>> = Mutex::new(None);
+
+/// how often the timer presents `DOUBLE_BUFFER`
+///
+/// The PIT only ticks ~18 times a second, so in practice this means every tick.
+pub const PRESENT_HZ: u64 = 30;
+
+/// light grey on black, like the VGA console
+pub const DEFAULT_FG: u32 = 0x00AA_AAAA;
+pub const DEFAULT_BG: u32 = 0x0000_0000;
+
+/// A linear framebuffer with one `u32` per pixel
+///
+/// `stride` is the length of a line in pixels, which can be larger than the visible `width`
+pub struct Framebuffer {
+	base: *mut u32,
+	width: u32,
+	height: u32,
+	stride: u32,
+}
+
+// the framebuffer is only reachable through FRAMEBUFFER's lock
+unsafe impl Send for Framebuffer {}
+
+impl Framebuffer {
+	/// Wraps the framebuffer memory at `base`
+	///
+	/// Unsafe because `base` has to be mapped and writable for `stride * height` pixels, and
+	/// nothing else may write to it while this exists.
+	pub unsafe fn new(
+		base: *mut u32,
+		width: u32,
+		height: u32,
+		stride: u32,
+	) -> Self {
+		assert!(stride >= width, "framebuffer stride {} is less than the width {}", stride, width);
+		Framebuffer { base, width, height, stride }
+	}
+
+	pub fn width(&self) -> u32 {
+		self.width
+	}
+
+	pub fn height(&self) -> u32 {
+		self.height
+	}
+
+	pub fn stride(&self) -> u32 {
+		self.stride
+	}
+
+	/// sets a single pixel, anything off screen is ignored
+	pub fn put_pixel(
+		&mut self,
+		x: u32,
+		y: u32,
+		color: u32,
+	) {
+		if x >= self.width || y >= self.height {
+			return;
+		}
+
+		let offset = y as usize * self.stride as usize + x as usize;
+		// volatile, so the compiler doesn't drop writes it never sees read back
+		unsafe { ptr::write_volatile(self.base.add(offset), color) };
+	}
+
+	/// fills a rectangle, clipped to the screen
+	pub fn fill_rect(
+		&mut self,
+		x: u32,
+		y: u32,
+		w: u32,
+		h: u32,
+		color: u32,
+	) {
+		let x_end = x.saturating_add(w).min(self.width);
+		let y_end = y.saturating_add(h).min(self.height);
+
+		for row in y..y_end {
+			for col in x..x_end {
+				self.put_pixel(col, row, color);
+			}
+		}
+	}
+
+	/// reads a pixel back, None if it's off screen
+	pub fn pixel(
+		&self,
+		x: u32,
+		y: u32,
+	) -> Option<u32> {
+		if x >= self.width || y >= self.height {
+			return None;
+		}
+
+		let offset = y as usize * self.stride as usize + x as usize;
+		Some(unsafe { ptr::read_volatile(self.base.add(offset)) })
+	}
+
+	/// moves everything up by `lines` pixel rows and fills the rows freed at the bottom
+	pub fn scroll_up(
+		&mut self,
+		lines: u32,
+		fill: u32,
+	) {
+		let lines = lines.min(self.height);
+		let stride = self.stride as usize;
+
+		// the rows overlap, so this has to be a memmove
+		unsafe {
+			ptr::copy(
+				self.base.add(lines as usize * stride),
+				self.base,
+				(self.height - lines) as usize * stride,
+			);
+		}
+
+		let width = self.width;
+		self.fill_rect(0, self.height - lines, width, lines, fill);
+	}
+}
+
+/// A framebuffer with a shadow copy in RAM
+///
+/// Drawing only touches `back`, `present` copies a finished frame to the screen in one go, so
+/// half drawn frames never show up.
+pub struct DoubleBufferedFb {
+	front: Framebuffer,
+	/// `stride * height` pixels, laid out like the front buffer
+	back: Vec<u32>,
+	/// something was drawn since the last `present`
+	dirty: bool,
+}
+
+impl DoubleBufferedFb {
+	/// allocates the back buffer, starting out black
+	pub fn new(front: Framebuffer) -> Self {
+		let back = vec![0; front.stride as usize * front.height as usize];
+		DoubleBufferedFb { front, back, dirty: true }
+	}
+
+	pub fn width(&self) -> u32 {
+		self.front.width
+	}
+
+	pub fn height(&self) -> u32 {
+		self.front.height
+	}
+
+	pub fn is_dirty(&self) -> bool {
+		self.dirty
+	}
+
+	/// sets a pixel in the back buffer, anything off screen is ignored
+	pub fn put_pixel(
+		&mut self,
+		x: u32,
+		y: u32,
+		color: u32,
+	) {
+		if x >= self.front.width || y >= self.front.height {
+			return;
+		}
+
+		self.back[y as usize * self.front.stride as usize + x as usize] = color;
+		self.dirty = true;
+	}
+
+	/// fills a rectangle in the back buffer, clipped to the screen
+	pub fn fill_rect(
+		&mut self,
+		x: u32,
+		y: u32,
+		w: u32,
+		h: u32,
+		color: u32,
+	) {
+		let x_end = x.saturating_add(w).min(self.front.width);
+		let y_end = y.saturating_add(h).min(self.front.height);
+		let stride = self.front.stride as usize;
+
+		for row in y..y_end {
+			let line = row as usize * stride;
+			self.back[line + x as usize..line + x_end as usize].fill(color);
+		}
+		self.dirty = true;
+	}
+
+	/// reads a pixel of the back buffer, None if it's off screen
+	pub fn pixel(
+		&self,
+		x: u32,
+		y: u32,
+	) -> Option<u32> {
+		if x >= self.front.width || y >= self.front.height {
+			return None;
+		}
+		Some(self.back[y as usize * self.front.stride as usize + x as usize])
+	}
+
+	/// the screen itself, writes to it bypass the back buffer
+	pub fn front(&mut self) -> &mut Framebuffer {
+		&mut self.front
+	}
+
+	/// Copies the back buffer to the screen
+	pub fn present(&mut self) {
+		// the padding past `width` goes along, one big copy beats a copy per line
+		unsafe { ptr::copy_nonoverlapping(self.back.as_ptr(), self.front.base, self.back.len()) };
+		self.dirty = false;
+	}
+}
+
+/// frames the timer presented so far
+static FRAMES_PRESENTED: AtomicU64 = AtomicU64::new(0);
+
+/// returns how many frames of `DOUBLE_BUFFER` the timer presented so far
+pub fn frames_presented() -> u64 {
+	FRAMES_PRESENTED.load(Ordering::Relaxed)
+}
+
+/// Called by the timer interrupt handler, presents `DOUBLE_BUFFER` at about `PRESENT_HZ`
+///
+/// Skips the frame if someone is drawing right now, the next tick picks it up.
+pub(crate) fn present_tick() {
+	let interval = (crate::task::timer::TICKS_PER_SECOND / PRESENT_HZ).max(1);
+	if !crate::interrupts::every_n_ticks(interval) {
+		return;
+	}
+
+	if let Some(mut screen) = DOUBLE_BUFFER.try_lock() {
+		if let Some(screen) = screen.as_mut().filter(|screen| screen.dirty) {
+			screen.present();
+			FRAMES_PRESENTED.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+}
+
+/// A text console on top of a framebuffer, `write!` to it
+///
+/// Keeps a cursor in character cells and scrolls once it runs off the bottom, just like the
+/// VGA writer.
+pub struct FramebufferConsole {
+	fb: Framebuffer,
+	col: u32,
+	row: u32,
+	fg: u32,
+	bg: u32,
+}
+
+impl FramebufferConsole {
+	pub fn new(fb: Framebuffer) -> Self {
+		FramebufferConsole { fb, col: 0, row: 0, fg: DEFAULT_FG, bg: DEFAULT_BG }
+	}
+
+	pub fn framebuffer(&mut self) -> &mut Framebuffer {
+		&mut self.fb
+	}
+
+	/// gives the framebuffer back
+	pub fn into_inner(self) -> Framebuffer {
+		self.fb
+	}
+
+	pub fn set_colors(
+		&mut self,
+		fg: u32,
+		bg: u32,
+	) {
+		self.fg = fg;
+		self.bg = bg;
+	}
+
+	/// cursor position in character cells, (column, row)
+	pub fn cursor(&self) -> (u32, u32) {
+		(self.col, self.row)
+	}
+
+	pub fn columns(&self) -> u32 {
+		self.fb.width / GLYPH_WIDTH
+	}
+
+	pub fn rows(&self) -> u32 {
+		self.fb.height / GLYPH_HEIGHT
+	}
+
+	/// clears the screen to the background color and homes the cursor
+	pub fn clear(&mut self) {
+		let (width, height, bg) = (self.fb.width, self.fb.height, self.bg);
+		self.fb.fill_rect(0, 0, width, height, bg);
+		self.col = 0;
+		self.row = 0;
+	}
+
+	pub fn write_char(
+		&mut self,
+		ch: char,
+	) {
+		match ch {
+			'\n' => self.new_line(),
+			'\r' => self.col = 0,
+			ch => {
+				if self.col >= self.columns() {
+					self.new_line();
+				}
+				// too small for a single cell, nothing to draw on
+				if self.rows() == 0 {
+					return;
+				}
+
+				let (x, y) = (self.col * GLYPH_WIDTH, self.row * GLYPH_HEIGHT);
+				font::draw_char(&mut self.fb, x, y, ch, self.fg, self.bg);
+				self.col += 1;
+			},
+		}
+	}
+
+	fn new_line(&mut self) {
+		self.col = 0;
+
+		if self.row + 1 < self.rows() {
+			self.row += 1;
+		} else {
+			let bg = self.bg;
+			self.fb.scroll_up(GLYPH_HEIGHT, bg);
+		}
+	}
+}
+
+impl fmt::Write for FramebufferConsole {
+	fn write_str(
+		&mut self,
+		s: &str,
+	) -> fmt::Result {
+		for ch in s.chars() {
+			self.write_char(ch);
+		}
+		Ok(())
+	}
+}
+
+/// Installs `framebuffer` as the global one, clears it and mirrors the console onto it
+pub fn init(framebuffer: Framebuffer) {
+	use crate::console::{self, Targets};
+
+	let mut console = FramebufferConsole::new(framebuffer);
+	console.clear();
+
+	*FRAMEBUFFER.lock() = Some(console);
+	console::set_targets(console::targets() | Targets::FRAMEBUFFER);
+}
+
+/// Installs `framebuffer` as `DOUBLE_BUFFER` instead of the console, the heap has to be up
+pub fn init_double_buffered(framebuffer: Framebuffer) {
+	*DOUBLE_BUFFER.lock() = Some(DoubleBufferedFb::new(framebuffer));
+}
+
+/// Prints to the framebuffer console, does nothing until `init` ran
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+	use core::fmt::Write;
+	use x86_64::instructions::interrupts;
+
+	// same as the VGA writer, an interrupt handler printing must not find the lock taken
+	interrupts::without_interrupts(|| {
+		if let Some(console) = FRAMEBUFFER.lock().as_mut() {
+			console.write_fmt(args).expect("printing to the framebuffer failed");
+		}
+	});
+}
+
+#[test_case]
+fn test_fill_rect_clips_and_keeps_padding() {
+	const WIDTH: u32 = 8;
+	const HEIGHT: u32 = 4;
+	const STRIDE: u32 = 10;
+
+	let mut memory = [0u32; (STRIDE * HEIGHT) as usize];
+	let mut fb = unsafe { Framebuffer::new(memory.as_mut_ptr(), WIDTH, HEIGHT, STRIDE) };
+
+	// runs off the right and bottom edge
+	fb.fill_rect(6, 2, 10, 10, 0x00FF_0000);
+	fb.put_pixel(WIDTH, 0, 0xFFFF_FFFF);
+	fb.put_pixel(0, HEIGHT, 0xFFFF_FFFF);
+	fb.put_pixel(1, 1, 0x0000_00FF);
+
+	assert_eq!(fb.pixel(6, 2), Some(0x00FF_0000));
+	assert_eq!(fb.pixel(7, 3), Some(0x00FF_0000));
+	assert_eq!(fb.pixel(5, 2), Some(0));
+	assert_eq!(fb.pixel(1, 1), Some(0x0000_00FF));
+	assert_eq!(fb.pixel(WIDTH, 0), None);
+
+	drop(fb);
+
+	// the padding between width and stride is never touched
+	for row in 0..HEIGHT as usize {
+		for col in WIDTH as usize..STRIDE as usize {
+			assert_eq!(memory[row * STRIDE as usize + col], 0);
+		}
+	}
+	assert_eq!(memory.iter().filter(|&&p| p == 0x00FF_0000).count(), 4);
+}
+
+#[test_case]
+fn test_console_wraps_and_scrolls() {
+	use core::fmt::Write;
+
+	// two columns, three rows of text
+	const WIDTH: u32 = 2 * GLYPH_WIDTH;
+	const HEIGHT: u32 = 3 * GLYPH_HEIGHT;
+
+	let mut memory = [0u32; (WIDTH * HEIGHT) as usize];
+	let fb = unsafe { Framebuffer::new(memory.as_mut_ptr(), WIDTH, HEIGHT, WIDTH) };
+	let mut console = FramebufferConsole::new(fb);
+	console.set_colors(0xFFFF_FFFF, 0);
+
+	// "ABC" wraps after two columns, the fourth line pushes "AB" off the top
+	write!(console, "ABC\nDE\nF").unwrap();
+	assert_eq!(console.cursor(), (1, 2));
+
+	let fb = console.framebuffer();
+	let cell_is = |fb: &Framebuffer, col: u32, row: u32, ch: char| {
+		let glyph = font::glyph(ch);
+		(0..GLYPH_HEIGHT).all(|y| {
+			(0..GLYPH_WIDTH).all(|x| {
+				let set = glyph[y as usize] & (0x80 >> x) != 0;
+				let pixel = fb.pixel(col * GLYPH_WIDTH + x, row * GLYPH_HEIGHT + y);
+				pixel == Some(if set { 0xFFFF_FFFF } else { 0 })
+			})
+		})
+	};
+
+	assert!(cell_is(fb, 0, 0, 'C'));
+	assert!(cell_is(fb, 1, 0, ' '));
+	assert!(cell_is(fb, 0, 1, 'D'));
+	assert!(cell_is(fb, 1, 1, 'E'));
+	assert!(cell_is(fb, 0, 2, 'F'));
+	assert!(cell_is(fb, 1, 2, ' '));
+}
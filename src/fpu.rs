@@ -0,0 +1,43 @@
+// in src/fpu.rs
+//
+// x87/SSE register state, saved and restored around the polls of tasks that use it
+
+use crate::task::FpuState;
+use core::arch::asm;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+/// Lets SSE instructions and fxsave/fxrstor run
+///
+/// The kernel itself is built soft-float and never touches these registers, but tasks that opt
+/// into FPU state can. Without OSFXSR every SSE instruction is a #UD.
+pub fn init() {
+	unsafe {
+		Cr0::update(|flags| {
+			flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+			flags.insert(Cr0Flags::MONITOR_COPROCESSOR);
+		});
+		Cr4::update(|flags| {
+			flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+		});
+	}
+}
+
+/// Saves the x87, MMX and SSE registers into `state`
+///
+/// Unsafe because `init` has to have run, fxsave is a #UD otherwise.
+pub unsafe fn fxsave(state: &mut FpuState) {
+	unsafe {
+		asm!("fxsave64 [{}]", in(reg) state.data.as_mut_ptr(), options(nostack, preserves_flags));
+	}
+	state.initialized = true;
+}
+
+/// Loads the x87, MMX and SSE registers from `state`
+///
+/// Unsafe because `init` has to have run, and a hand-edited image with reserved MXCSR bits set
+/// faults.
+pub unsafe fn fxrstor(state: &FpuState) {
+	unsafe {
+		asm!("fxrstor64 [{}]", in(reg) state.data.as_ptr(), options(nostack, preserves_flags));
+	}
+}
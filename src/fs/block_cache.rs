@@ -0,0 +1,474 @@
+//! in src/fs/block_cache.rs
+
+use super::{
+	block_dev::{BlockDevice, DirtyState},
+	layout::BLOCK_SIZE,
+	simple_fs::{FileSystemError, SFS},
+};
+use crate::println;
+use crate::{
+	allocator::{self, ShrinkList, SlabBox, SlabCache, shrink},
+	interrupts,
+	sync::Mutex,
+	task::{
+		sync::WaitQueue,
+		timer::{self, TICKS_PER_SECOND},
+	},
+};
+use alloc::{boxed::Box, collections::BTreeMap, rc::Rc, sync::Arc, vec::Vec};
+use core::cell::RefCell;
+
+/// blocks kept in memory by default, 16 KiB worth
+pub const DEFAULT_CACHE_BLOCKS: usize = 32;
+
+/// how often the flusher looks for old dirty blocks, about 500ms
+pub const FLUSH_INTERVAL_TICKS: u64 = TICKS_PER_SECOND / 2;
+
+/// blocks dirty for longer than this are written out by the flusher, about 5s
+pub const DEFAULT_MAX_DIRTY_AGE_TICKS: u64 = 5 * TICKS_PER_SECOND;
+
+/// longest run of adjacent blocks that goes out as a single device write
+const MAX_COALESCED_BLOCKS: usize = 8;
+
+/// clean blocks are the cheapest thing to give back, another read brings them in again
+pub const SHRINKER_PRIORITY: u8 = 10;
+
+/// Where the cache gets its time from, in timer ticks
+pub type TickSource = fn() -> u64;
+
+struct Entry {
+	data: Box<[u8; BLOCK_SIZE]>,
+	/// tick the block got dirty since it was last written out, None while it's clean
+	dirty_since: Option<u64>,
+	/// use counter at the last access, the smallest one gets evicted
+	last_used: u64,
+}
+
+type Entries = BTreeMap<u64, SlabBox<Entry>>;
+
+/// the entries of every cache, the blocks themselves stay on the heap
+static ENTRIES: SlabCache<Entry> = SlabCache::new("block cache entries", DEFAULT_CACHE_BLOCKS);
+
+/// the entries of every cache, for the shrinker
+static CACHES: ShrinkList<Entries> = ShrinkList::new();
+
+/// Drops clean blocks of any cache that isn't in use right now, least recently used first
+///
+/// Dirty blocks stay, writing them out means I/O and that can't happen in the allocator.
+fn shrink_caches(needed: usize) -> usize {
+	CACHES.shrink(needed, |entries, needed| {
+		let mut freed = 0;
+		while freed < needed {
+			let victim = match lru_clean(entries) {
+				Some(id) => id,
+				None => break,
+			};
+			freed += shrink::measure_freed(|| drop(entries.remove(&victim)));
+		}
+		freed
+	})
+}
+
+/// Called once by `allocator::init_heap`
+pub(crate) fn register_shrinker() {
+	allocator::register_shrinker("block cache", SHRINKER_PRIORITY, shrink_caches);
+}
+
+/// the least recently used block that the device already has
+fn lru_clean(entries: &Entries) -> Option<u64> {
+	entries
+		.iter()
+		.filter(|(_, entry)| entry.dirty_since.is_none())
+		.min_by_key(|(_, entry)| entry.last_used)
+		.map(|(&id, _)| id)
+}
+
+/// What the cache has been up to
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+	pub hits: u64,
+	pub misses: u64,
+	/// requests that reached the device, a coalesced run counts once
+	pub device_reads: u64,
+	pub device_writes: u64,
+}
+
+/// A write-back block cache in front of another BlockDevice
+///
+/// Writes only land in memory until `write_back`, `flush_older_than` or `sync_blocks` writes them out,
+/// dirty blocks next to each other go out as one request. Single block reads are kept as well,
+/// that's the bitmap, inode table and directory traffic.
+///
+/// Clean blocks can go at any time when the heap runs low, see `shrink_caches`.
+pub struct CachedDevice<D: BlockDevice> {
+	device: D,
+	/// shared with the shrinker
+	entries: Arc<Mutex<Entries>>,
+	capacity: usize,
+	clock: TickSource,
+	/// bumped on every access
+	uses: u64,
+	stats: CacheStats,
+	/// woken whenever dirty blocks reached the device
+	written: Arc<WaitQueue>,
+}
+
+impl<D: BlockDevice> CachedDevice<D> {
+	pub fn new(device: D) -> Self {
+		Self::with_capacity(device, DEFAULT_CACHE_BLOCKS)
+	}
+
+	/// a cache holding at most `blocks` blocks
+	pub fn with_capacity(
+		device: D,
+		blocks: usize,
+	) -> Self {
+		let entries = Arc::new(Mutex::new(BTreeMap::new()));
+		CACHES.track(&entries);
+		// so a full heap later on doesn't keep blocks out, the slab with their entries is there
+		ENTRIES.reserve(blocks.max(1));
+
+		CachedDevice {
+			device,
+			entries,
+			capacity: blocks.max(1),
+			clock: interrupts::ticks,
+			uses: 0,
+			stats: CacheStats::default(),
+			written: Arc::new(WaitQueue::new()),
+		}
+	}
+
+	/// replaces the tick source, the timer interrupt's counter by default
+	pub fn set_clock(
+		&mut self,
+		clock: TickSource,
+	) {
+		self.clock = clock;
+	}
+
+	pub fn stats(&self) -> CacheStats {
+		self.stats
+	}
+
+	/// true if `block` is cached and the device doesn't have what's in it yet
+	pub fn is_dirty(
+		&self,
+		block: u64,
+	) -> bool {
+		self.entries.lock().get(&block).map_or(false, |entry| entry.dirty_since.is_some())
+	}
+
+	/// Woken every time dirty blocks were written out, by an eviction as well
+	///
+	/// A task that needs a block on the device waits on it without holding on to the cache:
+	/// `written.wait_until(|| !fs.borrow().device().is_dirty(block))`.
+	pub fn written(&self) -> Arc<WaitQueue> {
+		self.written.clone()
+	}
+
+	/// the device underneath, it doesn't have the dirty blocks yet
+	pub fn get_ref(&self) -> &D {
+		&self.device
+	}
+
+	/// Writes everything out, flushes the device and hands it back
+	pub fn into_inner(mut self) -> Result<D, FileSystemError> {
+		self.write_back()?;
+		self.device.flush()?;
+		Ok(self.device)
+	}
+
+	/// Drops the cache without writing anything out, like pulling the plug
+	///
+	/// For crash tests, the device is left the way a power cut would leave it.
+	pub fn power_cut(self) -> D {
+		self.device
+	}
+
+	/// Writes out every dirty block
+	///
+	/// Not `BlockDevice::flush`, which only passes a flush on to the device.
+	pub fn write_back(&mut self) -> Result<(), FileSystemError> {
+		let dirty: Vec<u64> = self
+			.entries
+			.lock()
+			.iter()
+			.filter(|(_, entry)| entry.dirty_since.is_some())
+			.map(|(&id, _)| id)
+			.collect();
+		self.write_out(&dirty)
+	}
+
+	/// writes out the blocks that have been dirty for at least `max_age` ticks
+	pub fn flush_older_than(
+		&mut self,
+		max_age: u64,
+	) -> Result<(), FileSystemError> {
+		let now = (self.clock)();
+		let old: Vec<u64> = self
+			.entries
+			.lock()
+			.iter()
+			.filter(|(_, entry)| match entry.dirty_since {
+				Some(since) => now.saturating_sub(since) >= max_age,
+				None => false,
+			})
+			.map(|(&id, _)| id)
+			.collect();
+		self.write_out(&old)
+	}
+
+	/// Writes out `blocks`, dirty entries in ascending order
+	///
+	/// Runs of adjacent blocks share a request, up to `MAX_COALESCED_BLOCKS` of them.
+	fn write_out(
+		&mut self,
+		blocks: &[u64],
+	) -> Result<(), FileSystemError> {
+		let mut run = Vec::new();
+		let mut i = 0;
+
+		while i < blocks.len() {
+			let start = blocks[i];
+			let mut len = 1;
+			while i + len < blocks.len()
+				&& len < MAX_COALESCED_BLOCKS
+				&& blocks[i + len] == start + len as u64
+			{
+				len += 1;
+			}
+
+			// the shrinker leaves dirty blocks alone, they're all still there
+			run.clear();
+			for id in start..start + len as u64 {
+				run.extend_from_slice(&self.entries.lock()[&id].data[..]);
+			}
+			self.device.write_blocks(start, &run)?;
+			self.stats.device_writes += 1;
+
+			// only clean once the device has them, a failed write leaves them dirty
+			let mut entries = self.entries.lock();
+			for id in start..start + len as u64 {
+				if let Some(entry) = entries.get_mut(&id) {
+					entry.dirty_since = None;
+				}
+			}
+			drop(entries);
+			self.written.wake_all();
+
+			i += len;
+		}
+
+		Ok(())
+	}
+
+	/// Makes room for one more entry
+	///
+	/// Drops the least recently used clean block, if everything is dirty it all gets written out
+	/// first.
+	fn make_room(&mut self) -> Result<(), FileSystemError> {
+		let (len, all_dirty) = {
+			let entries = self.entries.lock();
+			(entries.len(), entries.values().all(|entry| entry.dirty_since.is_some()))
+		};
+		if len < self.capacity {
+			return Ok(());
+		}
+
+		if all_dirty {
+			self.write_back()?;
+		}
+
+		let mut entries = self.entries.lock();
+		if let Some(id) = lru_clean(&entries) {
+			entries.remove(&id);
+		}
+		Ok(())
+	}
+
+	fn next_use(&mut self) -> u64 {
+		self.uses += 1;
+		self.uses
+	}
+}
+
+impl<D: BlockDevice> BlockDevice for CachedDevice<D> {
+	fn read_blocks(
+		&mut self,
+		block_id: u64,
+		buffer: &mut [u8],
+	) -> Result<(), FileSystemError> {
+		if buffer.len() % BLOCK_SIZE != 0 {
+			return Err(FileSystemError::BlockError);
+		}
+
+		let count = (buffer.len() / BLOCK_SIZE) as u64;
+		let used = self.next_use();
+
+		// locked throughout, so the shrinker can't take a clean block between the check and the
+		// copy
+		let mut entries = self.entries.lock();
+		let cached = (block_id..block_id + count).filter(|id| entries.contains_key(id)).count();
+
+		if cached as u64 == count {
+			self.stats.hits += 1;
+		} else {
+			self.device.read_blocks(block_id, buffer)?;
+			self.stats.device_reads += 1;
+			self.stats.misses += 1;
+		}
+
+		// whatever is cached is at least as new as the device's copy
+		for (i, chunk) in buffer.chunks_mut(BLOCK_SIZE).enumerate() {
+			if let Some(entry) = entries.get_mut(&(block_id + i as u64)) {
+				chunk.copy_from_slice(&entry.data[..]);
+				entry.last_used = used;
+			}
+		}
+		drop(entries);
+
+		// file contents come in runs and would only push the metadata out
+		if count == 1 && cached == 0 {
+			self.make_room()?;
+			let mut data = Box::new([0u8; BLOCK_SIZE]);
+			data.copy_from_slice(buffer);
+			// without an entry it just isn't cached, the next read goes to the device
+			if let Some(entry) = ENTRIES.alloc(Entry { data, dirty_since: None, last_used: used }) {
+				self.entries.lock().insert(block_id, entry);
+			}
+		}
+
+		Ok(())
+	}
+
+	fn write_blocks(
+		&mut self,
+		block_id: u64,
+		buffer: &[u8],
+	) -> Result<(), FileSystemError> {
+		if self.device.is_read_only() {
+			return Err(FileSystemError::ReadOnly);
+		}
+
+		// the device only gets to complain at write back time, so check what it would check now
+		let end = (block_id as usize).checked_add(buffer.len() / BLOCK_SIZE);
+		if buffer.len() % BLOCK_SIZE != 0 || end.map_or(true, |end| end > self.device.capacity()) {
+			return Err(FileSystemError::BlockError);
+		}
+
+		let now = (self.clock)();
+		let used = self.next_use();
+
+		for (i, chunk) in buffer.chunks(BLOCK_SIZE).enumerate() {
+			let id = block_id + i as u64;
+
+			let cached = match self.entries.lock().get_mut(&id) {
+				Some(entry) => {
+					entry.data.copy_from_slice(chunk);
+					// the age counts from the first write that wasn't written out
+					entry.dirty_since.get_or_insert(now);
+					entry.last_used = used;
+					true
+				},
+				None => false,
+			};
+
+			if !cached {
+				self.make_room()?;
+				let mut data = Box::new([0u8; BLOCK_SIZE]);
+				data.copy_from_slice(chunk);
+				match ENTRIES.alloc(Entry { data, dirty_since: Some(now), last_used: used }) {
+					Some(entry) => {
+						self.entries.lock().insert(id, entry);
+					},
+					// nowhere to keep it, so it goes through
+					None => {
+						self.device.write_blocks(id, chunk)?;
+						self.stats.device_writes += 1;
+					},
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	fn capacity(&self) -> usize {
+		self.device.capacity()
+	}
+
+	fn sync_blocks(
+		&mut self,
+		blocks: &[u64],
+	) -> Result<(), FileSystemError> {
+		let entries = self.entries.lock();
+		let mut dirty: Vec<u64> = blocks
+			.iter()
+			.copied()
+			.filter(|id| entries.get(id).map_or(false, |entry| entry.dirty_since.is_some()))
+			.collect();
+		drop(entries);
+		dirty.sort_unstable();
+		dirty.dedup();
+		self.write_out(&dirty)
+	}
+
+	fn sync_all(&mut self) -> Result<(), FileSystemError> {
+		self.write_back()
+	}
+
+	fn dirty_state(&self) -> DirtyState {
+		let now = (self.clock)();
+		let mut state = DirtyState::default();
+
+		for since in self.entries.lock().values().filter_map(|entry| entry.dirty_since) {
+			state.blocks += 1;
+			state.oldest_age_ticks = state.oldest_age_ticks.max(now.saturating_sub(since));
+		}
+
+		state
+	}
+
+	fn is_read_only(&self) -> bool {
+		self.device.is_read_only()
+	}
+
+	/// dirty blocks stay where they are, that's what `sync_blocks` and `sync_all` are for
+	fn flush(&mut self) -> Result<(), FileSystemError> {
+		self.device.flush()
+	}
+}
+
+/// Background task writing out blocks that have been dirty for `max_age` ticks or longer
+///
+/// Looks every `interval` ticks, and moves the access times reads only noted in memory into the
+/// inode table first so they age like any other write. Once nobody else holds on to `fs` anymore
+/// it writes out the rest, marks the filesystem clean and ends.
+pub async fn flusher<D: BlockDevice>(
+	fs: Rc<RefCell<SFS<CachedDevice<D>>>>,
+	interval: u64,
+	max_age: u64,
+) {
+	loop {
+		timer::sleep_ticks(interval).await;
+
+		let last_owner = Rc::strong_count(&fs) == 1;
+		let mut guard = fs.borrow_mut();
+
+		// nobody else can write anymore, so it's as good as unmounted
+		let result = if last_owner {
+			guard.sync()
+		} else {
+			guard.flush_times().and_then(|()| guard.device_mut().flush_older_than(max_age))
+		};
+
+		if let Err(e) = result {
+			println!("[FS] WARNING: writing out the block cache failed: {:?}", e);
+		}
+
+		if last_owner {
+			return;
+		}
+	}
+}
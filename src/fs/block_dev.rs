@@ -1,7 +1,11 @@
+use super::image::{BlockSource, ImageError};
+use super::layout::BLOCK_SIZE;
 use super::simple_fs::FileSystemError;
 use crate::println;
-use crate::virtio::OsHal;
-use virtio_drivers::{device::blk::VirtIOBlk, transport::pci::PciTransport};
+use crate::virtio::{DmaBuffer, OsHal};
+use alloc::{boxed::Box, collections::BTreeMap, vec};
+use core::future::Future;
+use virtio_drivers::{PAGE_SIZE, device::blk::VirtIOBlk, transport::pci::PciTransport};
 
 /// Interface to any storage that presents itself in fixed-size-blocks
 ///
@@ -9,6 +13,7 @@ use virtio_drivers::{device::blk::VirtIOBlk, transport::pci::PciTransport};
 /// - Read data from a specific block into a buffer.
 /// - Write data from a buffer to a specific block.
 /// - Query the capacity.
+/// - Read and write byte ranges, by default on top of the block reads and writes.
 ///
 /// Errors during operations are reported via the `BlockError` enum.
 pub trait BlockDevice {
@@ -26,6 +31,126 @@ pub trait BlockDevice {
 	) -> Result<(), FileSystemError>;
 	/// returns the total number of blocks on the device
 	fn capacity(&self) -> usize;
+	/// Reads `buf.len()` bytes starting `byte_offset` bytes into the device
+	///
+	/// Fetches every block the range touches in one request and copies out the part asked for.
+	fn read_bytes(
+		&mut self,
+		byte_offset: u64,
+		buf: &mut [u8],
+	) -> Result<(), FileSystemError> {
+		if buf.is_empty() {
+			return Ok(());
+		}
+		let (first, start, block_count) = block_span(byte_offset, buf.len())?;
+
+		let mut blocks = vec![0u8; block_count * BLOCK_SIZE];
+		self.read_blocks(first, &mut blocks)?;
+		buf.copy_from_slice(&blocks[start..start + buf.len()]);
+		Ok(())
+	}
+	/// Writes `buf` starting `byte_offset` bytes into the device
+	///
+	/// The first and last block are read first if `buf` only covers part of them, so the rest of
+	/// them stays as it was. The whole span goes out in one write.
+	fn write_bytes(
+		&mut self,
+		byte_offset: u64,
+		buf: &[u8],
+	) -> Result<(), FileSystemError> {
+		if buf.is_empty() {
+			return Ok(());
+		}
+		let (first, start, block_count) = block_span(byte_offset, buf.len())?;
+		let end = start + buf.len();
+
+		let mut blocks = vec![0u8; block_count * BLOCK_SIZE];
+		if start != 0 {
+			self.read_blocks(first, &mut blocks[..BLOCK_SIZE])?;
+		}
+		// a single block that got read for its head already has its tail too
+		if end % BLOCK_SIZE != 0 && !(block_count == 1 && start != 0) {
+			let last = (block_count - 1) * BLOCK_SIZE;
+			self.read_blocks(first + block_count as u64 - 1, &mut blocks[last..])?;
+		}
+
+		blocks[start..end].copy_from_slice(buf);
+		self.write_blocks(first, &blocks)
+	}
+	/// Writes out whichever of `blocks` are only held in memory so far
+	///
+	/// Devices that write straight through have nothing to do.
+	fn sync_blocks(
+		&mut self,
+		_blocks: &[u64],
+	) -> Result<(), FileSystemError> {
+		Ok(())
+	}
+	/// writes out everything that is only held in memory
+	fn sync_all(&mut self) -> Result<(), FileSystemError> {
+		Ok(())
+	}
+	/// what the device holds in memory and hasn't written out yet
+	fn dirty_state(&self) -> DirtyState {
+		DirtyState::default()
+	}
+	/// true if writes are refused, SFS mounts such a device read-only
+	fn is_read_only(&self) -> bool {
+		false
+	}
+	/// Makes the writes the device accepted so far durable, like a host side write cache
+	///
+	/// Doesn't write out what `sync_blocks` and `sync_all` would, only what already got past them.
+	fn flush(&mut self) -> Result<(), FileSystemError> {
+		Ok(())
+	}
+}
+
+/// The first block a byte range touches, where in it the range starts and how many blocks it spans
+fn block_span(
+	byte_offset: u64,
+	len: usize,
+) -> Result<(u64, usize, usize), FileSystemError> {
+	let end = byte_offset.checked_add(len as u64).ok_or(FileSystemError::BlockError)?;
+	let first = byte_offset / BLOCK_SIZE as u64;
+	let last = (end - 1) / BLOCK_SIZE as u64;
+	Ok((first, (byte_offset % BLOCK_SIZE as u64) as usize, (last - first + 1) as usize))
+}
+
+/// A block device that can be read from a task without holding up the executor
+///
+/// Implementors may still block inside the read, like `VirtIOBlkAsync`, but give the other tasks
+/// a turn around it.
+pub trait BlockDeviceAsync {
+	/// reads one or more blocks starting from `block` into `buf`
+	fn read_blocks_async<'a>(
+		&'a mut self,
+		block: u64,
+		buf: &'a mut [u8],
+	) -> impl Future<Output = Result<(), FileSystemError>> + 'a;
+}
+
+/// so the shared image code reads what the filesystem on the device would
+impl<D: BlockDevice + ?Sized> BlockSource for D {
+	fn read_at(
+		&mut self,
+		block: u64,
+		buffer: &mut [u8],
+	) -> Result<(), ImageError> {
+		self.read_blocks(block, buffer).map_err(|_| ImageError::Read)
+	}
+
+	fn block_count(&self) -> u64 {
+		self.capacity() as u64
+	}
+}
+
+/// Writes a device is still sitting on, see `BlockDevice::dirty_state`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyState {
+	pub blocks: usize,
+	/// ticks since the oldest of them got dirty
+	pub oldest_age_ticks: u64,
 }
 
 /// Represents the different Errors that can occur when dealing with BlockDevices
@@ -37,16 +162,41 @@ pub enum BlockError {
 	InvalidDataStream,
 }
 
+/// true if the buffer doesn't cross a page boundary, so it is physically contiguous and the device
+/// can use it directly
+fn within_one_page(buffer: &[u8]) -> bool {
+	let start = buffer.as_ptr() as usize;
+	buffer.is_empty() || start / PAGE_SIZE == (start + buffer.len() - 1) / PAGE_SIZE
+}
+
 impl BlockDevice for VirtIOBlk<OsHal, PciTransport> {
 	fn read_blocks(
 		&mut self,
 		start_block_id: u64,
 		buffer: &mut [u8],
 	) -> Result<(), FileSystemError> {
-		self.read_blocks(start_block_id as usize, buffer).map_err(|e| {
-			println!("[BLOCK DEVICE] Read Error: {}", e);
-			FileSystemError::BlockError
-		})
+		if within_one_page(buffer) {
+			return self.read_blocks(start_block_id as usize, buffer).map_err(|e| {
+				println!("[BLOCK DEVICE] Read Error: {}", e);
+				FileSystemError::BlockError
+			});
+		}
+
+		// the device would write straight across the page boundary, bounce through DMA memory
+		let mut bounce = DmaBuffer::alloc(1).ok_or(FileSystemError::BlockError)?;
+		let mut block_id = start_block_id;
+
+		for chunk in buffer.chunks_mut(PAGE_SIZE) {
+			let staging = &mut bounce[..chunk.len()];
+			self.read_blocks(block_id as usize, staging).map_err(|e| {
+				println!("[BLOCK DEVICE] Read Error: {}", e);
+				FileSystemError::BlockError
+			})?;
+			chunk.copy_from_slice(staging);
+			block_id += (chunk.len() / BLOCK_SIZE) as u64;
+		}
+
+		Ok(())
 	}
 
 	fn write_blocks(
@@ -54,13 +204,141 @@ impl BlockDevice for VirtIOBlk<OsHal, PciTransport> {
 		start_block_id: u64,
 		buffer: &[u8],
 	) -> Result<(), FileSystemError> {
-		self.write_blocks(start_block_id as usize, buffer).map_err(|e| {
-			println!("[BLOCK DEVICE] Write Error: {}", e);
+		if within_one_page(buffer) {
+			return self.write_blocks(start_block_id as usize, buffer).map_err(|e| {
+				println!("[BLOCK DEVICE] Write Error: {}", e);
+				FileSystemError::BlockError
+			});
+		}
+
+		let mut bounce = DmaBuffer::alloc(1).ok_or(FileSystemError::BlockError)?;
+		let mut block_id = start_block_id;
+
+		for chunk in buffer.chunks(PAGE_SIZE) {
+			let staging = &mut bounce[..chunk.len()];
+			staging.copy_from_slice(chunk);
+			self.write_blocks(block_id as usize, staging).map_err(|e| {
+				println!("[BLOCK DEVICE] Write Error: {}", e);
+				FileSystemError::BlockError
+			})?;
+			block_id += (chunk.len() / BLOCK_SIZE) as u64;
+		}
+
+		Ok(())
+	}
+
+	fn capacity(&self) -> usize {
+		self.capacity() as usize
+	}
+
+	/// set when the device offered VIRTIO_BLK_F_RO, `-drive readonly=on`
+	fn is_read_only(&self) -> bool {
+		VirtIOBlk::readonly(self)
+	}
+
+	/// The driver only sends a flush request if VIRTIO_BLK_F_FLUSH was negotiated
+	///
+	/// Without it the device writes through and there is nothing to flush.
+	fn flush(&mut self) -> Result<(), FileSystemError> {
+		VirtIOBlk::flush(self).map_err(|e| {
+			println!("[BLOCK DEVICE] Flush Error: {}", e);
 			FileSystemError::BlockError
 		})
 	}
+}
+
+/// A BlockDevice backed by heap memory
+///
+/// Handy for tests and scratch filesystems, everything is lost once it's dropped. Only blocks that
+/// hold something other than zeroes take up memory, so large devices are fine as long as most of
+/// them stays empty.
+pub struct MemBlockDevice {
+	block_count: usize,
+	blocks: BTreeMap<u64, Box<[u8; BLOCK_SIZE]>>,
+	read_only: bool,
+}
+
+impl MemBlockDevice {
+	/// creates a zeroed device with `block_count` blocks
+	pub fn new(block_count: usize) -> Self {
+		MemBlockDevice { block_count, blocks: BTreeMap::new(), read_only: false }
+	}
+
+	/// makes writes fail with `FileSystemError::ReadOnly`, like a drive attached read-only
+	pub fn set_read_only(
+		&mut self,
+		read_only: bool,
+	) {
+		self.read_only = read_only;
+	}
+
+	/// checks that a request is in bounds and a whole number of blocks
+	fn check_request(
+		&self,
+		block_id: u64,
+		len: usize,
+	) -> Result<(), FileSystemError> {
+		if len % BLOCK_SIZE != 0 {
+			return Err(FileSystemError::BlockError);
+		}
+
+		let end =
+			(block_id as usize).checked_add(len / BLOCK_SIZE).ok_or(FileSystemError::BlockError)?;
+
+		if end > self.block_count {
+			return Err(FileSystemError::BlockError);
+		}
+
+		Ok(())
+	}
+}
+
+impl BlockDevice for MemBlockDevice {
+	fn read_blocks(
+		&mut self,
+		block_id: u64,
+		buffer: &mut [u8],
+	) -> Result<(), FileSystemError> {
+		self.check_request(block_id, buffer.len())?;
+
+		for (i, chunk) in buffer.chunks_mut(BLOCK_SIZE).enumerate() {
+			match self.blocks.get(&(block_id + i as u64)) {
+				Some(block) => chunk.copy_from_slice(&block[..]),
+				None => chunk.fill(0),
+			}
+		}
+		Ok(())
+	}
+
+	fn write_blocks(
+		&mut self,
+		block_id: u64,
+		buffer: &[u8],
+	) -> Result<(), FileSystemError> {
+		if self.read_only {
+			return Err(FileSystemError::ReadOnly);
+		}
+		self.check_request(block_id, buffer.len())?;
+
+		for (i, chunk) in buffer.chunks(BLOCK_SIZE).enumerate() {
+			let id = block_id + i as u64;
+
+			// all zeroes is what a missing block reads as anyway
+			if chunk.iter().all(|&b| b == 0) {
+				self.blocks.remove(&id);
+			} else {
+				let block = self.blocks.entry(id).or_insert_with(|| Box::new([0u8; BLOCK_SIZE]));
+				block.copy_from_slice(chunk);
+			}
+		}
+		Ok(())
+	}
 
 	fn capacity(&self) -> usize {
-		self.capacity() as usize
+		self.block_count
+	}
+
+	fn is_read_only(&self) -> bool {
+		self.read_only
 	}
 }
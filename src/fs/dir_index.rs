@@ -0,0 +1,237 @@
+//! in src/fs/dir_index.rs
+//!
+//! In-memory index over directory entries, used to skip the linear scan of dirent blocks.
+//! Nothing in here is ever written to disk, so it is rebuilt lazily after every mount.
+
+use crate::{
+	allocator::{self, ShrinkList, shrink},
+	sync::Mutex,
+};
+use alloc::{
+	collections::{BTreeMap, VecDeque},
+	sync::Arc,
+	vec::Vec,
+};
+
+/// default number of indexed names kept across all cached directories
+pub const DEFAULT_DIR_INDEX_BUDGET: usize = 256;
+
+/// an index costs a scan over the directory to rebuild, so the block cache goes first
+pub const SHRINKER_PRIORITY: u8 = 20;
+
+/// the caches made by `DirIndexCache::shared`, for the shrinker
+static INDEXES: ShrinkList<DirIndexCache> = ShrinkList::new();
+
+/// clears every directory index cache that isn't in use right now, they're rebuilt on demand
+fn shrink_indexes(needed: usize) -> usize {
+	INDEXES.shrink(needed, |cache, _| shrink::measure_freed(|| cache.clear()))
+}
+
+/// Called once by `allocator::init_heap`
+pub(crate) fn register_shrinker() {
+	allocator::register_shrinker("directory index", SHRINKER_PRIORITY, shrink_indexes);
+}
+
+/// Where a directory entry lives on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirSlot {
+	pub block: u64,
+	pub slot: usize,
+}
+
+/// FNV-1a hash over the name bytes
+pub fn fnv1a(name: &[u8]) -> u64 {
+	const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+	const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+	let mut hash = OFFSET_BASIS;
+	for &byte in name {
+		hash ^= byte as u64;
+		hash = hash.wrapping_mul(PRIME);
+	}
+	hash
+}
+
+/// name-hash -> dirent slots of a single directory
+///
+/// A hash only maps to more than one slot on collisions, callers must compare the name stored
+/// in the slot before trusting it
+#[derive(Debug, Default)]
+pub struct DirIndex {
+	slots: BTreeMap<u64, Vec<DirSlot>>,
+	len: usize,
+}
+
+impl DirIndex {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn insert(
+		&mut self,
+		hash: u64,
+		slot: DirSlot,
+	) {
+		let slots = self.slots.entry(hash).or_default();
+		if !slots.contains(&slot) {
+			slots.push(slot);
+			self.len += 1;
+		}
+	}
+
+	pub fn remove(
+		&mut self,
+		hash: u64,
+		slot: DirSlot,
+	) {
+		if let Some(slots) = self.slots.get_mut(&hash) {
+			if let Some(pos) = slots.iter().position(|s| *s == slot) {
+				slots.swap_remove(pos);
+				self.len -= 1;
+			}
+			if slots.is_empty() {
+				self.slots.remove(&hash);
+			}
+		}
+	}
+
+	/// slots whose name hashes to `hash`, empty if the name isn't in the directory
+	pub fn candidates(
+		&self,
+		hash: u64,
+	) -> &[DirSlot] {
+		self.slots.get(&hash).map(|slots| slots.as_slice()).unwrap_or(&[])
+	}
+
+	/// number of indexed entries
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+}
+
+/// Bounded LRU of directory indexes, keyed by the directory's inode
+///
+/// The budget counts indexed names across all directories, the least recently used directory
+/// is dropped first when it's exceeded. The most recently used one stays even if it alone is over
+/// the budget, large directories are what the index is for. A budget of 0 turns it off.
+#[derive(Debug)]
+pub struct DirIndexCache {
+	/// most recently used directory at the front
+	dirs: VecDeque<(u64, DirIndex)>,
+	budget: usize,
+	hits: u64,
+	misses: u64,
+}
+
+impl DirIndexCache {
+	pub fn new(budget: usize) -> Self {
+		DirIndexCache { dirs: VecDeque::new(), budget, hits: 0, misses: 0 }
+	}
+
+	/// A cache the heap shrinker may clear whenever it isn't locked
+	pub fn shared(budget: usize) -> Arc<Mutex<Self>> {
+		let cache = Arc::new(Mutex::new(Self::new(budget)));
+		INDEXES.track(&cache);
+		cache
+	}
+
+	/// returns the index of a directory and marks it as most recently used
+	pub fn get(
+		&mut self,
+		dir_inode: u64,
+	) -> Option<&mut DirIndex> {
+		let pos = self.dirs.iter().position(|(inode, _)| *inode == dir_inode)?;
+		let entry = self.dirs.remove(pos)?;
+		self.dirs.push_front(entry);
+		self.dirs.front_mut().map(|(_, index)| index)
+	}
+
+	/// caches a freshly built index for a directory
+	pub fn insert_dir(
+		&mut self,
+		dir_inode: u64,
+		index: DirIndex,
+	) {
+		self.invalidate(dir_inode);
+		self.dirs.push_front((dir_inode, index));
+		self.evict();
+	}
+
+	/// records a new entry in a directory, if that directory is currently indexed
+	pub fn insert_entry(
+		&mut self,
+		dir_inode: u64,
+		hash: u64,
+		slot: DirSlot,
+	) {
+		if let Some(index) = self.get(dir_inode) {
+			index.insert(hash, slot);
+			self.evict();
+		}
+	}
+
+	/// removes an entry from a directory's index, if that directory is currently indexed
+	pub fn remove_entry(
+		&mut self,
+		dir_inode: u64,
+		hash: u64,
+		slot: DirSlot,
+	) {
+		if let Some(index) = self.get(dir_inode) {
+			index.remove(hash, slot);
+		}
+	}
+
+	/// drops the index of a directory, it gets rebuilt on the next lookup
+	pub fn invalidate(
+		&mut self,
+		dir_inode: u64,
+	) {
+		self.dirs.retain(|(inode, _)| *inode != dir_inode);
+	}
+
+	/// drops every cached index
+	pub fn clear(&mut self) {
+		self.dirs.clear();
+	}
+
+	pub fn set_budget(
+		&mut self,
+		budget: usize,
+	) {
+		self.budget = budget;
+		self.evict();
+	}
+
+	pub fn record_hit(&mut self) {
+		self.hits += 1;
+	}
+
+	pub fn record_miss(&mut self) {
+		self.misses += 1;
+	}
+
+	pub fn hits(&self) -> u64 {
+		self.hits
+	}
+
+	pub fn misses(&self) -> u64 {
+		self.misses
+	}
+
+	/// total number of indexed names across all cached directories
+	pub fn entries(&self) -> usize {
+		self.dirs.iter().map(|(_, index)| index.len()).sum()
+	}
+
+	fn evict(&mut self) {
+		let keep = if self.budget == 0 { 0 } else { 1 };
+		while self.dirs.len() > keep && self.entries() > self.budget {
+			self.dirs.pop_back();
+		}
+	}
+}
@@ -0,0 +1,134 @@
+//! in src/fs/glob.rs
+//!
+//! shell style wildcards for names: `*`, `?` and `[...]` classes
+
+/// Whether `name` matches `pattern` as a whole
+///
+/// `*` stands for any run of chars, `?` for exactly one, `[abc]`, `[a-z]` and `[!a-z]` (or
+/// `[^a-z]`) for one char out of a class. A `]` right after the opening bracket is part of the
+/// class, a `[` without its `]` is just a `[`. Nothing is special about `/` here, see
+/// `matches_path` for that.
+///
+/// Goes through both strings once, going back only to the last `*`, so neither deep recursion
+/// nor allocations however many stars there are.
+pub fn matches(
+	pattern: &str,
+	name: &str,
+) -> bool {
+	let (mut p, mut n) = (0, 0);
+	// the pattern right behind the last `*` and where in the name it is tried next
+	let mut star: Option<(usize, usize)> = None;
+
+	while let Some(c) = name[n..].chars().next() {
+		if pattern[p..].starts_with('*') {
+			p += 1;
+			star = Some((p, n));
+			continue;
+		}
+
+		if let Some(next) = match_one(pattern, p, c) {
+			p = next;
+			n += c.len_utf8();
+			continue;
+		}
+
+		// the last `*` takes one more char and the rest is tried from there
+		match star {
+			Some((star_p, star_n)) => {
+				let skipped = name[star_n..].chars().next().map_or(1, char::len_utf8);
+				star = Some((star_p, star_n + skipped));
+				p = star_p;
+				n = star_n + skipped;
+			},
+			None => return false,
+		}
+	}
+
+	// the name is used up, only stars may be left
+	pattern[p..].chars().all(|c| c == '*')
+}
+
+/// Matches a `/` separated path one component at a time
+///
+/// Both need the same number of components, `*` never reaches past a `/`. Leading, trailing and
+/// doubled slashes don't count.
+pub fn matches_path(
+	pattern: &str,
+	path: &str,
+) -> bool {
+	let mut patterns = pattern.split('/').filter(|part| !part.is_empty());
+	let mut parts = path.split('/').filter(|part| !part.is_empty());
+
+	loop {
+		match (patterns.next(), parts.next()) {
+			(Some(pattern), Some(part)) if matches(pattern, part) => {},
+			(None, None) => return true,
+			_ => return false,
+		}
+	}
+}
+
+/// Matches the pattern item at `p`, which isn't a `*`, against `c`
+///
+/// Returns where the pattern goes on, None if `c` doesn't match or the pattern is used up.
+fn match_one(
+	pattern: &str,
+	p: usize,
+	c: char,
+) -> Option<usize> {
+	let first = pattern[p..].chars().next()?;
+	match first {
+		'?' => Some(p + 1),
+		'[' => match match_class(&pattern[p + 1..], c) {
+			Some((true, len)) => Some(p + 1 + len),
+			Some((false, _)) => None,
+			// no closing bracket, a plain `[`
+			None if c == '[' => Some(p + 1),
+			None => None,
+		},
+		literal if literal == c => Some(p + literal.len_utf8()),
+		_ => None,
+	}
+}
+
+/// Matches `c` against the class that `class` starts with, the part behind its `[`
+///
+/// Returns whether it matched and how long the class is up to and including the `]`, None if
+/// there is no `]`.
+fn match_class(
+	class: &str,
+	c: char,
+) -> Option<(bool, usize)> {
+	let mut chars = class.char_indices().peekable();
+
+	let negated = matches!(chars.peek(), Some((_, '!')) | Some((_, '^')));
+	if negated {
+		chars.next();
+	}
+
+	let mut matched = false;
+	let mut first = true;
+	while let Some((i, low)) = chars.next() {
+		if low == ']' && !first {
+			return Some((matched != negated, i + 1));
+		}
+		first = false;
+
+		// a `-` right before the `]` is a plain `-`
+		let mut ahead = chars.clone();
+		let range_end = match (ahead.next(), ahead.next()) {
+			(Some((_, '-')), Some((_, high))) if high != ']' => Some(high),
+			_ => None,
+		};
+		match range_end {
+			Some(high) => {
+				chars.next();
+				chars.next();
+				matched |= low <= c && c <= high;
+			},
+			None => matched |= low == c,
+		}
+	}
+
+	None
+}
@@ -0,0 +1,423 @@
+//! in src/fs/image.rs
+//!
+//! the read side of the SFS format as plain functions over a `BlockSource`: the superblock
+//! checks, inodes, bitmaps, directories, file contents and fsck. The kernel's SFS calls these
+//! for its reads, tools/sfs-inspect builds this file and layout.rs for the host, so the two can't
+//! disagree about what an image means. Nothing in here may reach into the rest of the kernel.
+
+use super::layout::*;
+use alloc::{string::String, vec, vec::Vec};
+use core::convert::TryFrom;
+use zerocopy::FromBytes;
+
+pub const ROOT_DIRECTORY_INODE: u64 = 0;
+
+/// Anything an image can be read from, in `BLOCK_SIZE` blocks
+///
+/// The kernel has it for every `BlockDevice`, the host tools for image files.
+pub trait BlockSource {
+	/// fills `buffer`, a whole number of blocks, starting at `block`
+	fn read_at(
+		&mut self,
+		block: u64,
+		buffer: &mut [u8],
+	) -> Result<(), ImageError>;
+
+	/// how many blocks there are
+	fn block_count(&self) -> u64;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageError {
+	/// the source couldn't deliver a block
+	Read,
+	InvalidSuperBlock(SuperBlockProblem),
+	/// past the end of the inode table
+	NoSuchInode(u64),
+	/// no entry by that name
+	NotFound,
+	/// a file where a directory has to be, or the other way round
+	WrongType,
+	/// the inode points somewhere it can't
+	Corrupt,
+}
+
+/// Why a superblock isn't mounted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuperBlockProblem {
+	/// a version this code doesn't know
+	UnknownVersion,
+	/// not an SFS image, or block 0 was overwritten
+	BadMagic,
+	/// see `SuperBlock::is_consistent`
+	Inconsistent,
+	/// the image claims more blocks than there are
+	PastTheEnd,
+}
+
+/// What `fsck` found
+#[derive(Debug, Default)]
+pub struct FsckReport {
+	pub used_inodes: u64,
+	pub used_data_blocks: u64,
+	pub problems: Vec<FsckProblem>,
+}
+
+impl FsckReport {
+	pub fn is_clean(&self) -> bool {
+		self.problems.is_empty()
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsckProblem {
+	/// an inode points outside of the data region
+	PointerOutOfRange { inode: u64, block: u64 },
+	/// an inode points at a data block the bitmap says is free
+	PointerToFreeBlock { inode: u64, block: u64 },
+	/// bits past the last inode are set in the inode bitmap
+	InodeBitmapTailSet,
+	/// bits past the last data block are set in the data bitmap
+	DataBitmapTailSet,
+}
+
+/// A used directory entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirListing {
+	pub name: String,
+	pub inode: u64,
+}
+
+/// A run of blocks that are next to each other on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRun {
+	/// first on-disk block of the run
+	pub start: u64,
+	/// position of that block within the file
+	pub first: usize,
+	pub len: usize,
+}
+
+/// Groups a file's block pointers into runs of consecutive on-disk blocks
+pub fn contiguous_runs(pointers: &[u64]) -> Vec<BlockRun> {
+	let mut runs: Vec<BlockRun> = Vec::new();
+
+	for (i, &block) in pointers.iter().enumerate() {
+		match runs.last_mut() {
+			Some(run) if run.start + run.len as u64 == block => run.len += 1,
+			_ => runs.push(BlockRun { start: block, first: i, len: 1 }),
+		}
+	}
+
+	runs
+}
+
+/// the superblock as it is in block 0, nothing checked yet
+pub fn disk_superblock(block: &[u8; BLOCK_SIZE]) -> DiskSuperBlock {
+	// unaligned little endian fields, any 84 bytes make one
+	let size = size_of::<DiskSuperBlock>();
+	*DiskSuperBlock::ref_from_bytes(&block[..size]).expect("DiskSuperBlock has no alignment")
+}
+
+/// Decodes a superblock and checks it's one that can be mounted from `block_count` blocks
+pub fn check_superblock(
+	disk: DiskSuperBlock,
+	block_count: u64,
+) -> Result<SuperBlock, SuperBlockProblem> {
+	let sb = SuperBlock::try_from(disk).map_err(|_| SuperBlockProblem::UnknownVersion)?;
+
+	if sb.magic_number != MAGIC_NUMBER {
+		return Err(SuperBlockProblem::BadMagic);
+	}
+	if !sb.is_consistent() {
+		return Err(SuperBlockProblem::Inconsistent);
+	}
+	if sb.total_blocks > block_count {
+		return Err(SuperBlockProblem::PastTheEnd);
+	}
+	Ok(sb)
+}
+
+/// reads and checks the superblock, see `check_superblock`
+pub fn read_superblock<S: BlockSource + ?Sized>(src: &mut S) -> Result<SuperBlock, ImageError> {
+	let mut block = [0u8; BLOCK_SIZE];
+	src.read_at(SUPERBLOCK_BLOCK, &mut block)?;
+	check_superblock(disk_superblock(&block), src.block_count())
+		.map_err(ImageError::InvalidSuperBlock)
+}
+
+/// the inode table block holding `inode_index` and the offset of the inode in it
+pub fn inode_location(
+	sb: &SuperBlock,
+	inode_index: u64,
+) -> (u64, usize) {
+	let block = sb.inode_table_start_block + inode_index / INODES_PER_BLOCK as u64;
+	let offset = (inode_index % INODES_PER_BLOCK as u64) as usize * INODE_SIZE;
+	(block, offset)
+}
+
+/// decodes the inode at `offset` in an inode table block
+pub fn decode_inode(
+	block: &[u8; BLOCK_SIZE],
+	offset: usize,
+) -> Result<Inode, ImageError> {
+	let bytes = block.get(offset..offset + INODE_SIZE).ok_or(ImageError::Corrupt)?;
+	let disk_inode = DiskInode::ref_from_bytes(bytes).map_err(|_| ImageError::Corrupt)?;
+	Inode::try_from(*disk_inode).map_err(|_| ImageError::Corrupt)
+}
+
+/// reads inode `inode_index` straight from the inode table
+pub fn read_inode<S: BlockSource + ?Sized>(
+	src: &mut S,
+	sb: &SuperBlock,
+	inode_index: u64,
+) -> Result<Inode, ImageError> {
+	if inode_index >= sb.inode_count {
+		return Err(ImageError::NoSuchInode(inode_index));
+	}
+
+	let (block, offset) = inode_location(sb, inode_index);
+	let mut buffer = [0u8; BLOCK_SIZE];
+	src.read_at(block, &mut buffer)?;
+	decode_inode(&buffer, offset)
+}
+
+/// the block pointers an indirect block holds, 0 for an unused one
+pub fn indirect_pointers(block: &[u8; BLOCK_SIZE]) -> [u64; POINTERS_PER_BLOCK] {
+	let mut pointers = [0u64; POINTERS_PER_BLOCK];
+	for (pointer, bytes) in pointers.iter_mut().zip(block.chunks_exact(size_of::<u64>())) {
+		*pointer = u64::from_le_bytes(<[u8; 8]>::try_from(bytes).expect("chunks of 8 bytes"));
+	}
+	pointers
+}
+
+/// The blocks of the directory `dir` in order, its direct pointers and then its indirect block's
+///
+/// A directory grows past its direct pointers through the indirect block, files don't use it.
+pub fn dir_blocks<S: BlockSource + ?Sized>(
+	src: &mut S,
+	sb: &SuperBlock,
+	dir: &Inode,
+) -> Result<Vec<u64>, ImageError> {
+	let mut blocks: Vec<u64> =
+		dir.direct_pointers.iter().copied().filter(|&block| block != 0).collect();
+
+	if dir.indirect_pointer != 0 {
+		let data_region = sb.data_block_start..sb.data_block_start + sb.data_block_count;
+		if !data_region.contains(&dir.indirect_pointer) {
+			return Err(ImageError::Corrupt);
+		}
+
+		let mut block_buf = [0u8; BLOCK_SIZE];
+		src.read_at(dir.indirect_pointer, &mut block_buf)?;
+		blocks.extend(indirect_pointers(&block_buf).iter().copied().filter(|&block| block != 0));
+	}
+
+	Ok(blocks)
+}
+
+/// Returns whether bit `idx` of a bitmap run is set
+pub fn bitmap_bit<S: BlockSource + ?Sized>(
+	src: &mut S,
+	start_block: u64,
+	idx: u64,
+) -> Result<bool, ImageError> {
+	let mut bitmap_buffer = [0u8; BLOCK_SIZE];
+	src.read_at(start_block + idx / BITS_PER_BITMAP_BLOCK, &mut bitmap_buffer)?;
+
+	Ok(Bitmap::new(&mut bitmap_buffer).is_set((idx % BITS_PER_BITMAP_BLOCK) as usize))
+}
+
+/// Calls `f` for every set bit below `count` in a bitmap run
+///
+/// Returns true if any bit at or past `count` is set, which a sane bitmap never has
+pub fn for_each_set_bit<S: BlockSource + ?Sized>(
+	src: &mut S,
+	start_block: u64,
+	blocks: u64,
+	count: u64,
+	mut f: impl FnMut(u64),
+) -> Result<bool, ImageError> {
+	let mut bitmap_buffer = [0u8; BLOCK_SIZE];
+	let mut tail_set = false;
+
+	for i in 0..blocks {
+		src.read_at(start_block + i, &mut bitmap_buffer)?;
+
+		for (byte_idx, &byte) in bitmap_buffer.iter().enumerate() {
+			if byte == 0 {
+				continue;
+			}
+			for bit in 0..8 {
+				if byte & (1 << bit) == 0 {
+					continue;
+				}
+				let idx = i * BITS_PER_BITMAP_BLOCK + (byte_idx * 8 + bit) as u64;
+				if idx < count {
+					f(idx);
+				} else {
+					tail_set = true;
+				}
+			}
+		}
+	}
+
+	Ok(tail_set)
+}
+
+/// Checks the bitmaps against the inodes
+///
+/// Every pointer of an allocated inode has to land inside the data region on a block the data
+/// bitmap has marked as used, and neither bitmap may have bits set past its end.
+pub fn fsck<S: BlockSource + ?Sized>(
+	src: &mut S,
+	sb: &SuperBlock,
+) -> Result<FsckReport, ImageError> {
+	let mut report = FsckReport::default();
+
+	let mut inodes = Vec::new();
+	if for_each_set_bit(
+		src,
+		sb.inode_bitmap_block,
+		sb.inode_bitmap_blocks,
+		sb.inode_count,
+		|idx| inodes.push(idx),
+	)? {
+		report.problems.push(FsckProblem::InodeBitmapTailSet);
+	}
+	report.used_inodes = inodes.len() as u64;
+
+	let mut used_data_blocks = 0;
+	if for_each_set_bit(
+		src,
+		sb.data_bitmap_block,
+		sb.data_bitmap_blocks,
+		sb.data_block_count,
+		|_| used_data_blocks += 1,
+	)? {
+		report.problems.push(FsckProblem::DataBitmapTailSet);
+	}
+	report.used_data_blocks = used_data_blocks;
+
+	let data_region = sb.data_block_start..sb.data_block_start + sb.data_block_count;
+
+	for inode_index in inodes {
+		let inode = read_inode(src, sb, inode_index)?;
+
+		let mut pointers: Vec<u64> = inode
+			.direct_pointers
+			.iter()
+			.chain(core::iter::once(&inode.indirect_pointer))
+			.copied()
+			.collect();
+		// a directory's indirect block lists more of its blocks, out of range is reported below
+		if inode.mode == FileType::Directory && data_region.contains(&inode.indirect_pointer) {
+			let mut block_buf = [0u8; BLOCK_SIZE];
+			src.read_at(inode.indirect_pointer, &mut block_buf)?;
+			pointers.extend_from_slice(&indirect_pointers(&block_buf));
+		}
+
+		for block in pointers.into_iter().filter(|&block| block != 0) {
+			let inode = inode_index;
+			if !data_region.contains(&block) {
+				report.problems.push(FsckProblem::PointerOutOfRange { inode, block });
+			} else if !bitmap_bit(src, sb.data_bitmap_block, block - sb.data_block_start)? {
+				report.problems.push(FsckProblem::PointerToFreeBlock { inode, block });
+			}
+		}
+	}
+
+	Ok(report)
+}
+
+/// the name of a directory entry, cut to the longest name there can be
+pub fn dirent_name(entry: &DiskDirEntry) -> &[u8] {
+	&entry.name[..(entry.name_len.get() as usize).min(DIR_NAME_MAX)]
+}
+
+/// The used entries of the directory at `dir_index` in the order they're stored, without `.`
+/// and `..`
+pub fn list_dir<S: BlockSource + ?Sized>(
+	src: &mut S,
+	sb: &SuperBlock,
+	dir_index: u64,
+) -> Result<Vec<DirListing>, ImageError> {
+	let dir = read_inode(src, sb, dir_index)?;
+	if dir.mode != FileType::Directory {
+		return Err(ImageError::WrongType);
+	}
+
+	let mut entries = Vec::new();
+	let mut block_buf = [0u8; BLOCK_SIZE];
+
+	for block in dir_blocks(src, sb, &dir)? {
+		src.read_at(block, &mut block_buf)?;
+
+		for entry in DirEntryBlock::new(&block_buf) {
+			if (entry.flags.get() & DIRENT_USED) == 0 {
+				continue;
+			}
+
+			let name = dirent_name(&entry);
+			if name != b"." && name != b".." {
+				entries.push(DirListing {
+					name: String::from_utf8_lossy(name).into_owned(),
+					inode: entry.inode.get(),
+				});
+			}
+		}
+	}
+
+	Ok(entries)
+}
+
+/// Follows a `/` separated path from the root directory to an inode, empty components don't
+/// count
+pub fn resolve<S: BlockSource + ?Sized>(
+	src: &mut S,
+	sb: &SuperBlock,
+	path: &str,
+) -> Result<u64, ImageError> {
+	let mut inode = ROOT_DIRECTORY_INODE;
+	for part in path.split('/').filter(|part| !part.is_empty()) {
+		inode = list_dir(src, sb, inode)?
+			.into_iter()
+			.find(|entry| entry.name == part)
+			.map(|entry| entry.inode)
+			.ok_or(ImageError::NotFound)?;
+	}
+	Ok(inode)
+}
+
+/// Everything in the regular file at `inode_index`
+///
+/// Pointers have to be inside the data region, a hole or one outside is `Corrupt` instead of
+/// whatever the block it points at holds.
+pub fn read_file<S: BlockSource + ?Sized>(
+	src: &mut S,
+	sb: &SuperBlock,
+	inode_index: u64,
+) -> Result<Vec<u8>, ImageError> {
+	let inode = read_inode(src, sb, inode_index)?;
+	if inode.mode != FileType::File {
+		return Err(ImageError::WrongType);
+	}
+
+	// checked before anything is allocated, the size may be garbage
+	let len = inode.size_in_bytes;
+	let block_count = len.div_ceil(BLOCK_SIZE as u64) as usize;
+	let pointers = inode.direct_pointers.get(..block_count).ok_or(ImageError::Corrupt)?;
+
+	let data_region = sb.data_block_start..sb.data_block_start + sb.data_block_count;
+	if !pointers.iter().all(|block| data_region.contains(block)) {
+		return Err(ImageError::Corrupt);
+	}
+
+	let mut data = vec![0u8; block_count * BLOCK_SIZE];
+	for run in contiguous_runs(pointers) {
+		let range = run.first * BLOCK_SIZE..(run.first + run.len) * BLOCK_SIZE;
+		src.read_at(run.start, &mut data[range])?;
+	}
+	data.truncate(len as usize);
+	Ok(data)
+}
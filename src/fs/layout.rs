@@ -1,13 +1,18 @@
 //! in src/fs/layout.rs
+//!
+//! the on-disk format, also built for the host by tools/sfs-inspect, so nothing from the rest of
+//! the kernel apart from `println!`
 
-use crate::fs::simple_fs::FileSystemError;
-use alloc::vec::Vec;
+use core::fmt;
 use sa::const_assert;
 use zerocopy::{
 	FromBytes, Immutable, IntoBytes, KnownLayout,
 	byteorder::{LE, U16, U32, U64},
 };
 
+/// in every superblock, anything else in block 0 isn't SFS
+pub const MAGIC_NUMBER: u32 = 0x_DEAD_BEEF;
+
 pub const BLOCK_SIZE: usize = 512;
 pub const INODE_SIZE: usize = 128;
 pub const INODES_PER_BLOCK: usize = BLOCK_SIZE / INODE_SIZE; // --- 4
@@ -15,16 +20,38 @@ pub const INODES_PER_BLOCK: usize = BLOCK_SIZE / INODE_SIZE; // --- 4
 // BLOCK ADDRESSES for different sections of the file system
 pub const SUPERBLOCK_BLOCK: u64 = 0;
 pub const INODE_BITMAP_BLOCK: u64 = 1;
+// the next two only hold for version 1 images, newer ones record where their regions start
 pub const DATA_BITMAP_BLOCK: u64 = 2;
 pub const INODE_TABLE_START_BLOCK: u64 = 3;
 
+/// number of resources a single bitmap block keeps track of
+pub const BITS_PER_BITMAP_BLOCK: u64 = (BLOCK_SIZE * 8) as u64;
+
+/// version 1 had exactly one block per bitmap, version 2 stores the length of each bitmap run
+pub const SUPERBLOCK_VERSION: u32 = 2;
+
+/// superblock flag: mounted for writing and not unmounted or synced since
+pub const SUPERBLOCK_DIRTY: u32 = 1 << 0;
+
+/// bytes of block 0 the superblock takes, see `DiskSuperBlock`
+pub const DISK_SUPERBLOCK_SIZE: usize = 84;
+
 // Directory Entry Layout: 64 bytes per entry -> 8 entries per 512 block
 pub const DIR_ENTRY_SIZE: usize = 64;
 pub const DIR_NAME_MAX: usize = 52;
 pub const DIR_ENTRIES_PER_BLOCK: usize = BLOCK_SIZE / DIR_ENTRY_SIZE;
 
+// an indirect block is a plain array of little endian block pointers, 0 for an unused one
+pub const POINTERS_PER_BLOCK: usize = BLOCK_SIZE / size_of::<u64>();
+
 type U32Le = U32<LE>;
 
+/// The superblock as it sits at the start of block 0
+///
+/// Version 1 was 64 bytes, its padding became `version`. Version 2 added the two bitmap run
+/// lengths after it, and `flags` after those, where older images have the zeroed rest of the
+/// block. Moving those back into 64 bytes would take another version and a migration, nothing
+/// reads past `DISK_SUPERBLOCK_SIZE` and the block has room for it.
 #[derive(Debug, Copy, Clone, IntoBytes, FromBytes, Immutable, KnownLayout)]
 #[repr(C)]
 pub struct DiskSuperBlock {
@@ -36,7 +63,10 @@ pub struct DiskSuperBlock {
 	pub data_block_start: U64<LE>,
 	pub data_block_count: U64<LE>,
 	pub magic_number: U32Le,
-	pub _pad0: U32Le, // explicit padding to avoid implicit tail padding so total is 64 bytes
+	pub version: U32Le, // was padding in version 1, so reads as 0 on those images
+	pub inode_bitmap_blocks: U64<LE>,
+	pub data_bitmap_blocks: U64<LE>,
+	pub flags: U32Le, // past the end of older superblocks, the zeroed rest of the block
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -49,10 +79,89 @@ pub struct SuperBlock {
 	pub inode_count: u64,
 	pub data_block_start: u64,
 	pub data_block_count: u64,
+	pub inode_bitmap_blocks: u64,
+	pub data_bitmap_blocks: u64,
 	pub magic_number: u32, // kept at the end .. so there is no alignment padding
+	pub version: u32,
+	/// `SUPERBLOCK_DIRTY` and nothing else so far
+	pub flags: u32,
+}
+
+impl SuperBlock {
+	/// number of bitmap blocks needed to track `count` resources, at least one
+	pub fn bitmap_blocks_for(count: u64) -> u64 {
+		count.div_ceil(BITS_PER_BITMAP_BLOCK).max(1)
+	}
+
+	/// Checks that the recorded regions are in order, don't overlap and fit on the device
+	pub fn is_consistent(&self) -> bool {
+		let inode_table_blocks = self.inode_count.div_ceil(INODES_PER_BLOCK as u64);
+
+		let fits = |start: u64, len: u64, next: u64| match start.checked_add(len) {
+			Some(end) => end <= next,
+			None => false,
+		};
+		let covers = |blocks: u64, count: u64| match blocks.checked_mul(BITS_PER_BITMAP_BLOCK) {
+			Some(bits) => bits >= count,
+			None => true,
+		};
+
+		self.inode_bitmap_blocks >= 1
+			&& self.data_bitmap_blocks >= 1
+			&& self.inode_bitmap_block > SUPERBLOCK_BLOCK
+			&& covers(self.inode_bitmap_blocks, self.inode_count)
+			&& covers(self.data_bitmap_blocks, self.data_block_count)
+			&& fits(self.inode_bitmap_block, self.inode_bitmap_blocks, self.data_bitmap_block)
+			&& fits(self.data_bitmap_block, self.data_bitmap_blocks, self.inode_table_start_block)
+			&& fits(self.inode_table_start_block, inode_table_blocks, self.data_block_start)
+			&& fits(self.data_block_start, self.data_block_count, self.total_blocks)
+	}
+}
+
+/// `1024 (0x400)`, a block number or count both ways
+struct Num(u64);
+
+impl fmt::Display for Num {
+	fn fmt(
+		&self,
+		f: &mut fmt::Formatter,
+	) -> fmt::Result {
+		write!(f, "{} ({:#x})", self.0, self.0)
+	}
+}
+
+/// One field per line, `SuperBlock {\n    total_blocks: 1024 (0x400),\n    ...\n}`
+impl fmt::Display for SuperBlock {
+	fn fmt(
+		&self,
+		f: &mut fmt::Formatter,
+	) -> fmt::Result {
+		let fields: [(&str, u64); 9] = [
+			("total_blocks", self.total_blocks),
+			("inode_bitmap_block", self.inode_bitmap_block),
+			("inode_bitmap_blocks", self.inode_bitmap_blocks),
+			("data_bitmap_block", self.data_bitmap_block),
+			("data_bitmap_blocks", self.data_bitmap_blocks),
+			("inode_table_start_block", self.inode_table_start_block),
+			("inode_count", self.inode_count),
+			("data_block_start", self.data_block_start),
+			("data_block_count", self.data_block_count),
+		];
+
+		writeln!(f, "SuperBlock {{")?;
+		for (name, value) in fields.iter() {
+			writeln!(f, "    {}: {},", name, Num(*value))?;
+		}
+		writeln!(f, "    magic_number: {:#x},", self.magic_number)?;
+		writeln!(f, "    version: {},", self.version)?;
+		let dirty = if self.flags & SUPERBLOCK_DIRTY != 0 { " (dirty)" } else { "" };
+		writeln!(f, "    flags: {:#x}{},", self.flags, dirty)?;
+		write!(f, "}}")
+	}
 }
 
-const_assert!(core::mem::size_of::<DiskSuperBlock>() == 64);
+// no padding anywhere, it's read and written as raw bytes
+const_assert!(core::mem::size_of::<DiskSuperBlock>() == DISK_SUPERBLOCK_SIZE);
 // A single SuperBlock struct fits within a disk
 const_assert!(core::mem::size_of::<DiskSuperBlock>() <= BLOCK_SIZE);
 
@@ -67,7 +176,10 @@ impl From<SuperBlock> for DiskSuperBlock {
 			data_block_start: U64::new(sb.data_block_start),
 			data_block_count: U64::new(sb.data_block_count),
 			magic_number: U32Le::new(sb.magic_number),
-			_pad0: U32Le::new(0),
+			version: U32Le::new(sb.version),
+			inode_bitmap_blocks: U64::new(sb.inode_bitmap_blocks),
+			data_bitmap_blocks: U64::new(sb.data_bitmap_blocks),
+			flags: U32Le::new(sb.flags),
 		}
 	}
 }
@@ -76,7 +188,7 @@ impl core::convert::TryFrom<DiskSuperBlock> for SuperBlock {
 	type Error = ();
 
 	fn try_from(value: DiskSuperBlock) -> Result<Self, Self::Error> {
-		Ok(SuperBlock {
+		let mut sb = SuperBlock {
 			total_blocks: value.total_blocks.get(),
 			inode_bitmap_block: value.inode_bitmap_block.get(),
 			data_bitmap_block: value.data_bitmap_block.get(),
@@ -84,8 +196,28 @@ impl core::convert::TryFrom<DiskSuperBlock> for SuperBlock {
 			inode_count: value.inode_count.get(),
 			data_block_start: value.data_block_start.get(),
 			data_block_count: value.data_block_count.get(),
+			inode_bitmap_blocks: value.inode_bitmap_blocks.get(),
+			data_bitmap_blocks: value.data_bitmap_blocks.get(),
 			magic_number: value.magic_number.get(),
-		})
+			version: value.version.get(),
+			flags: value.flags.get(),
+		};
+
+		match sb.version {
+			// version 1 images don't have the run lengths, they always used one block per bitmap
+			// and anything past what a single block can track was never reachable
+			0 | 1 => {
+				sb.version = 1;
+				sb.inode_bitmap_blocks = 1;
+				sb.data_bitmap_blocks = 1;
+				sb.inode_count = sb.inode_count.min(BITS_PER_BITMAP_BLOCK);
+				sb.data_block_count = sb.data_block_count.min(BITS_PER_BITMAP_BLOCK);
+			},
+			SUPERBLOCK_VERSION => {},
+			_ => return Err(()),
+		}
+
+		Ok(sb)
 	}
 }
 
@@ -99,11 +231,39 @@ pub struct Inode {
 	pub size_in_bytes: u64,
 	pub last_access_time: u64,
 	pub last_modification_time: u64,
+	/// doubles as the change time, nothing touches an inode's metadata after creation yet
 	pub creation_time: u64,
 	pub direct_pointers: [u64; 10], // direct pointers for simplicity
 	pub indirect_pointer: u64,
 }
 
+/// One field per line like the superblock, the mode by name and the pointers as a hex list
+impl fmt::Display for Inode {
+	fn fmt(
+		&self,
+		f: &mut fmt::Formatter,
+	) -> fmt::Result {
+		writeln!(f, "Inode {{")?;
+		writeln!(f, "    mode: {:?},", self.mode)?;
+		writeln!(f, "    size_in_bytes: {},", Num(self.size_in_bytes))?;
+		writeln!(f, "    link_count: {},", self.link_count)?;
+		writeln!(f, "    user:group: {}:{},", self.user_id, self.group_id)?;
+		writeln!(f, "    last_access_time: {},", self.last_access_time)?;
+		writeln!(f, "    last_modification_time: {},", self.last_modification_time)?;
+		writeln!(f, "    creation_time: {},", self.creation_time)?;
+		write!(f, "    direct_pointers: [")?;
+		for (i, pointer) in self.direct_pointers.iter().enumerate() {
+			if i > 0 {
+				write!(f, ", ")?;
+			}
+			write!(f, "{:#x}", pointer)?;
+		}
+		writeln!(f, "],")?;
+		writeln!(f, "    indirect_pointer: {:#x},", self.indirect_pointer)?;
+		write!(f, "}}")
+	}
+}
+
 #[derive(Debug, Copy, Clone, IntoBytes, FromBytes, Immutable, KnownLayout)]
 #[repr(C)]
 pub struct DiskInode {
@@ -144,7 +304,7 @@ impl core::convert::TryFrom<DiskInode> for Inode {
 	type Error = ();
 	fn try_from(di: DiskInode) -> Result<Self, ()> {
 		Ok(Inode {
-			mode: FileType::try_from(di.mode.get())?,
+			mode: FileType::from(di.mode.get()),
 			user_id: di.user_id.get(),
 			group_id: di.group_id.get(),
 			link_count: di.link_count.get(),
@@ -170,6 +330,23 @@ pub struct DiskDirEntry {
 	pub name: [u8; DIR_NAME_MAX],
 }
 
+/// `DiskDirEntry { inode: 3 (0x3), name: "notes.txt", used }`, a name that isn't UTF-8 as bytes
+impl fmt::Display for DiskDirEntry {
+	fn fmt(
+		&self,
+		f: &mut fmt::Formatter,
+	) -> fmt::Result {
+		let name = &self.name[..(self.name_len.get() as usize).min(DIR_NAME_MAX)];
+		write!(f, "DiskDirEntry {{ inode: {}, name: ", Num(self.inode.get()))?;
+		match core::str::from_utf8(name) {
+			Ok(name) => write!(f, "{:?}", name)?,
+			Err(_) => write!(f, "{:x?}", name)?,
+		}
+		let used = if self.flags.get() & DIRENT_USED != 0 { "used" } else { "free" };
+		write!(f, ", {} }}", used)
+	}
+}
+
 // Helper struct to interate over different DiskDirEntries in a buffer
 pub struct DirEntryBlock<'a> {
 	block: &'a [u8; BLOCK_SIZE],
@@ -277,7 +454,7 @@ impl<'a> Bitmap<'a> {
 					if (byte & (1 << bit)) == 0 {
 						// Bounds check: idx may exceed logical size if map length is not exact
 						// Caller should ensure bitmap length maps exactly to resource count
-						let _ = self.set(idx).ok()?;
+						self.set(idx).ok()?;
 						return Some(idx);
 					}
 				}
@@ -302,9 +479,9 @@ pub enum FileType {
 	Directory = 0x2,
 }
 
-impl core::convert::TryFrom<u16> for FileType {
-	type Error = ();
-	fn try_from(value: u16) -> Result<Self, Self::Error> {
+impl FileType {
+	/// Like the `From<u16>` conversion, but refuses modes we don't know about
+	pub fn try_from_strict(value: u16) -> Result<Self, ()> {
 		match value {
 			0 => Ok(FileType::Unknown),
 			0x1 => Ok(FileType::File),
@@ -314,6 +491,20 @@ impl core::convert::TryFrom<u16> for FileType {
 	}
 }
 
+/// Unrecognized modes become `Unknown` instead of failing, so the rest of the inode can still be
+/// read and looked at by fsck
+impl From<u16> for FileType {
+	fn from(value: u16) -> Self {
+		FileType::try_from_strict(value).unwrap_or_else(|_| {
+			crate::println!(
+				"[FS] Warning: unknown inode mode {:#x}, treating it as Unknown",
+				value
+			);
+			FileType::Unknown
+		})
+	}
+}
+
 impl From<FileType> for u16 {
 	fn from(value: FileType) -> Self {
 		value as u16
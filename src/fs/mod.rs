@@ -1,3 +1,8 @@
+pub mod block_cache;
 pub mod block_dev;
+pub mod dir_index;
+pub mod glob;
+pub mod image;
 pub mod layout;
 pub mod simple_fs;
+pub mod walk;
@@ -1,16 +1,51 @@
 //! in src/fs/simple_fs.rs
 
-use super::{block_dev::BlockDevice, layout::*};
+use super::{
+	block_dev::BlockDevice,
+	dir_index::{DEFAULT_DIR_INDEX_BUDGET, DirIndex, DirIndexCache, DirSlot, fnv1a},
+	image::{self, ImageError, contiguous_runs},
+	layout::*,
+};
 use crate::fs::layout::FileType::File;
 use crate::println;
-use alloc::{string::String, vec::Vec};
+use crate::{interrupts, stack, sync::Mutex, task::timer::TICKS_PER_SECOND};
+use alloc::{collections::BTreeMap, rc::Rc, string::String, sync::Arc, vec, vec::Vec};
+use core::cell::{Cell, Ref, RefCell};
 use core::convert::TryFrom;
+use core::fmt;
 use core::ptr::write;
 use pc_keyboard::KeyCode::P;
 use zerocopy::{FromBytes, IntoBytes, KnownLayout, U16, U32, U64};
 
-const MAGIC_NUMBER: u32 = 0x_DEAD_BEEF;
-const ROOT_DIRECTORY_INODE: u64 = 0;
+pub use super::image::{FsckProblem, FsckReport, ROOT_DIRECTORY_INODE};
+
+/// reads refresh an access time at least this often (in seconds), see `set_relatime_interval`
+pub const DEFAULT_RELATIME_INTERVAL: u64 = 24 * 60 * 60;
+
+/// how many inodes can have unwritten access times before they're flushed
+const DIRTY_TIMES_MAX: usize = 16;
+
+/// how many inodes stay in memory, a few blocks worth of the inode table
+pub const INODE_CACHE_LEN: usize = 64;
+
+/// the shared stack fsck runs on
+const FSCK_STACK: &str = "fs::fsck";
+
+/// the share of the device `format` gives to the inode table, in percent
+pub const DEFAULT_INODE_RATIO_PERCENT: u8 = 10;
+
+/// the inode table can take between this many percent of the device...
+pub const MIN_INODE_RATIO_PERCENT: u8 = 1;
+/// ...and this many, the rest needs room for data
+pub const MAX_INODE_RATIO_PERCENT: u8 = 50;
+
+/// Where timestamps come from, in seconds
+pub type Clock = fn() -> u64;
+
+/// seconds since boot, until there's an RTC to ask for the real time
+fn uptime_seconds() -> u64 {
+	interrupts::ticks() / TICKS_PER_SECOND
+}
 
 // TODO: Write a Wrapper for the VirtIoBlkDevice --- currently just using the trait implementations
 
@@ -18,33 +53,145 @@ const ROOT_DIRECTORY_INODE: u64 = 0;
 #[derive(Debug)]
 #[repr(C)]
 pub struct SFS<D: BlockDevice> {
-	device: D,
+	/// readers only get `&self`, they need the device and the caches below all the same
+	device: RefCell<D>,
 	superblock: SuperBlock,
+	/// lookup acceleration only, never persisted
+	/// shared with the heap shrinker, which may clear it
+	dir_index: Arc<Mutex<DirIndexCache>>,
+	stats: RefCell<FsStats>,
+	clock: Clock,
+	relatime_interval: u64,
+	/// access times (inode, atime) not written back yet, so reads don't each cost an inode write
+	dirty_atimes: RefCell<Vec<(u64, u64)>>,
+	/// the last `INODE_CACHE_LEN` inodes read or written, always the same as on disk
+	inodes: RefCell<BTreeMap<u64, CachedInode>>,
+	/// bumped on every inode cache access
+	inode_uses: Cell<u64>,
+	/// set when the device refuses writes, everything that would write fails with ReadOnly
+	read_only: bool,
+	/// the superblock was still marked dirty when this mounted
+	mounted_unclean: bool,
+}
+
+/// An inode in `SFS::inodes`
+#[derive(Clone, Copy)]
+struct CachedInode {
+	inode: Inode,
+	/// use counter at the last access, the smallest one gets evicted
+	last_used: u64,
+}
+
+/// What `SFS::defragment` did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DefragReport {
+	/// regular files looked at
+	pub files: u64,
+	/// files that had blocks moved
+	pub files_moved: u64,
+	/// of those, the ones still in more than one piece, no free run was long enough
+	pub files_partial: u64,
+	pub blocks_relocated: u64,
+	/// longest run of free data blocks, see `SFS::largest_free_run`
+	pub largest_free_run_before: u64,
+	pub largest_free_run_after: u64,
+}
+
+/// How `SFS::format_with` lays a device out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatOptions {
+	/// how much of the device goes to the inode table, more for many small files and less for a
+	/// few big ones
+	pub inode_ratio_percent: u8,
+}
+
+impl Default for FormatOptions {
+	fn default() -> Self {
+		FormatOptions { inode_ratio_percent: DEFAULT_INODE_RATIO_PERCENT }
+	}
+}
+
+/// Runtime statistics of a mounted filesystem
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FsStats {
+	pub dir_index_hits: u64,
+	pub dir_index_misses: u64,
+	/// device requests issued for file contents, contiguous blocks share one request
+	pub data_read_requests: u64,
+	pub data_write_requests: u64,
+	/// blocks the device holds in memory and hasn't written out yet
+	pub dirty_blocks: usize,
+	/// ticks since the oldest of them got dirty
+	pub oldest_dirty_ticks: u64,
+	/// inodes with an access time that is only noted in memory so far
+	pub dirty_times: usize,
 }
 
 impl<D: BlockDevice> SFS<D> {
 	/// writes the superblock in the block device at block_id: 0
-	pub fn format(mut device: D) -> Result<Self, FileSystemError> {
+	///
+	/// 10% of the device goes to the inode table, see `format_with` for another share.
+	pub fn format(device: D) -> Result<Self, FileSystemError> {
+		Self::format_with(device, FormatOptions::default())
+	}
+
+	/// `format` with the layout in `options`
+	///
+	/// Fails with `BadFormatOptions` if the inode ratio is outside
+	/// `MIN_INODE_RATIO_PERCENT..=MAX_INODE_RATIO_PERCENT`.
+	pub fn format_with(
+		mut device: D,
+		options: FormatOptions,
+	) -> Result<Self, FileSystemError> {
+		let ratio = options.inode_ratio_percent;
+		if !(MIN_INODE_RATIO_PERCENT..=MAX_INODE_RATIO_PERCENT).contains(&ratio) {
+			return Err(FileSystemError::BadFormatOptions);
+		}
+		if device.is_read_only() {
+			return Err(FileSystemError::ReadOnly);
+		}
+
 		println!("[FS] Formatting Device");
 
 		let capacity: u64 = device.capacity() as u64;
 
-		let inode_table_blocks = capacity / 10; // 10% of the total capacity goes to the INODE_TABLE
+		// that share of the total capacity goes to the INODE_TABLE
+		let inode_table_blocks = capacity * ratio as u64 / 100;
 		let inode_count = inode_table_blocks * INODES_PER_BLOCK as u64;
+		let inode_bitmap_blocks = SuperBlock::bitmap_blocks_for(inode_count);
+
+		// whatever is left is shared between the data bitmap and the data blocks it tracks, every
+		// bitmap block pays for itself plus BITS_PER_BITMAP_BLOCK data blocks
+		let rest = capacity
+			.checked_sub(1 + inode_bitmap_blocks + inode_table_blocks)
+			.ok_or(FileSystemError::FormatFailed)?;
+		let data_bitmap_blocks = rest.div_ceil(BITS_PER_BITMAP_BLOCK + 1);
+		let data_block_count = rest - data_bitmap_blocks;
+
+		if inode_count == 0 || data_block_count == 0 {
+			return Err(FileSystemError::FormatFailed);
+		}
 
-		let data_block_start = INODE_TABLE_START_BLOCK + inode_table_blocks;
-		let data_block_count = capacity - data_block_start; // this works … think about it
+		// superblock, inode bitmap run, data bitmap run, inode table, data
+		let data_bitmap_block = INODE_BITMAP_BLOCK + inode_bitmap_blocks;
+		let inode_table_start_block = data_bitmap_block + data_bitmap_blocks;
+		let data_block_start = inode_table_start_block + inode_table_blocks;
 
 		let sb = SuperBlock {
 			magic_number: MAGIC_NUMBER,
+			version: SUPERBLOCK_VERSION,
 			total_blocks: capacity,
 			inode_bitmap_block: INODE_BITMAP_BLOCK,
-			data_bitmap_block: DATA_BITMAP_BLOCK,
-			inode_table_start_block: INODE_TABLE_START_BLOCK,
+			inode_bitmap_blocks,
+			data_bitmap_block,
+			data_bitmap_blocks,
+			inode_table_start_block,
 			inode_count,
 			data_block_start,
 			data_block_count,
+			flags: 0,
 		};
+		debug_assert!(sb.is_consistent());
 
 		let mut superblock_buffer = [0u8; BLOCK_SIZE];
 		let dsb = DiskSuperBlock::from(sb);
@@ -55,108 +202,894 @@ impl<D: BlockDevice> SFS<D> {
 			.write_blocks(SUPERBLOCK_BLOCK, &superblock_buffer)
 			.map_err(|_| FileSystemError::BlockError)?;
 
+		// Clearing both bitmap runs, they sit right after each other
 		let empty_bitmap_block = [0u8; BLOCK_SIZE];
-		// Writing the INODE BITMAP BLOCK
-		device
-			.write_blocks(INODE_BITMAP_BLOCK, empty_bitmap_block.as_bytes())
-			.map_err(|_| FileSystemError::BlockError)?;
-		// Writing the DATA BITMAP BLOCK
-		device
-			.write_blocks(DATA_BITMAP_BLOCK, empty_bitmap_block.as_bytes())
-			.map_err(|_| FileSystemError::BlockError)?;
+		for block in INODE_BITMAP_BLOCK..inode_table_start_block {
+			device
+				.write_blocks(block, empty_bitmap_block.as_bytes())
+				.map_err(|_| FileSystemError::BlockError)?;
+		}
 
-		Ok(Self { device, superblock: sb })
+		// a cached device would otherwise still hold the fresh superblock and bitmaps
+		device.sync_all()?;
+
+		Ok(Self::new(device, sb))
 	}
 
 	/// Mounts an existing file system from a block device
+	///
+	/// A device that reports itself read-only gets a read-only mount, see `is_read_only`.
 	pub fn mount(mut device: D) -> Result<Self, FileSystemError> {
+		let superblock =
+			image::read_superblock(&mut device).map_err(|_| FileSystemError::InvalidSuperBlock)?;
+
+		let read_only = device.is_read_only();
+		if read_only {
+			println!("[FS] device is read-only, mounting read-only");
+		}
+
+		let unclean = superblock.flags & SUPERBLOCK_DIRTY != 0;
+		if unclean {
+			println!("[FS] WARNING: filesystem was not unmounted cleanly");
+		}
+
+		let mut fs = Self::new(device, superblock);
+		fs.read_only = read_only;
+		fs.mounted_unclean = unclean;
+		if !read_only {
+			fs.mark_dirty()?;
+		}
+		Ok(fs)
+	}
+
+	fn new(
+		device: D,
+		superblock: SuperBlock,
+	) -> Self {
+		Self {
+			device: RefCell::new(device),
+			superblock,
+			dir_index: DirIndexCache::shared(DEFAULT_DIR_INDEX_BUDGET),
+			stats: RefCell::new(FsStats::default()),
+			clock: uptime_seconds,
+			relatime_interval: DEFAULT_RELATIME_INTERVAL,
+			dirty_atimes: RefCell::new(Vec::new()),
+			inodes: RefCell::new(BTreeMap::new()),
+			inode_uses: Cell::new(0),
+			read_only: false,
+			mounted_unclean: false,
+		}
+	}
+
+	/// Unmounts the filesystem and hands back the device
+	///
+	/// Pending access times are written out first, then whatever the device still holds in memory,
+	/// and then the device is flushed. A read-only mount has nothing to write.
+	pub fn unmount(mut self) -> D {
+		if self.read_only {
+			return self.device.into_inner();
+		}
+
+		if let Err(e) = self.flush_times() {
+			println!("[FS] WARNING: lost access times on unmount: {:?}", e);
+		}
+		if let Err(e) = self.device.get_mut().sync_all() {
+			println!("[FS] WARNING: unwritten blocks on unmount: {:?}", e);
+		} else if let Err(e) = self.mark_clean() {
+			println!("[FS] WARNING: couldn't mark the filesystem clean: {:?}", e);
+		}
+		if let Err(e) = self.device.get_mut().flush() {
+			println!("[FS] WARNING: device flush failed on unmount: {:?}", e);
+		}
+		self.device.into_inner()
+	}
+
+	/// Writes everything out and marks the filesystem clean, like an unmount that keeps it mounted
+	///
+	/// The next write marks it dirty again. Nothing to do on a read-only mount.
+	pub fn sync(&mut self) -> Result<(), FileSystemError> {
+		if self.read_only {
+			return Ok(());
+		}
+		self.flush_times()?;
+		self.device.get_mut().sync_all()?;
+		self.mark_clean()?;
+		self.device.get_mut().flush()
+	}
+
+	/// true if the superblock said dirty at mount time, the last session didn't end with an
+	/// unmount or a sync
+	pub fn mounted_unclean(&self) -> bool {
+		self.mounted_unclean
+	}
+
+	/// Sets `SUPERBLOCK_DIRTY` on disk unless it's set already
+	///
+	/// Synced right away, the flag has to be on the device before anything it warns about.
+	fn mark_dirty(&mut self) -> Result<(), FileSystemError> {
+		if self.superblock.flags & SUPERBLOCK_DIRTY != 0 {
+			return Ok(());
+		}
+		self.superblock.flags |= SUPERBLOCK_DIRTY;
+		let result = self.write_superblock();
+		if result.is_err() {
+			// try again on the next write
+			self.superblock.flags &= !SUPERBLOCK_DIRTY;
+		}
+		result
+	}
+
+	/// Clears `SUPERBLOCK_DIRTY` on disk, everything else has to be on the device already
+	fn mark_clean(&mut self) -> Result<(), FileSystemError> {
+		if self.superblock.flags & SUPERBLOCK_DIRTY == 0 {
+			return Ok(());
+		}
+		self.superblock.flags &= !SUPERBLOCK_DIRTY;
+		let result = self.write_superblock();
+		if result.is_err() {
+			self.superblock.flags |= SUPERBLOCK_DIRTY;
+		}
+		result
+	}
+
+	fn write_superblock(&mut self) -> Result<(), FileSystemError> {
 		let mut buffer = [0u8; BLOCK_SIZE];
+		let dsb = DiskSuperBlock::from(self.superblock);
+		buffer[..size_of::<DiskSuperBlock>()].copy_from_slice(dsb.as_bytes());
 
-		device
-			.read_blocks(SUPERBLOCK_BLOCK, &mut buffer)
-			.map_err(|_| FileSystemError::InvalidSuperBlock);
+		self.device.get_mut().write_blocks(SUPERBLOCK_BLOCK, &buffer)?;
+		self.device.get_mut().sync_blocks(&[SUPERBLOCK_BLOCK])
+	}
+
+	/// Every write past the superblock goes through here, the first one after a mount or sync
+	/// marks the filesystem dirty first
+	fn write_device(
+		&mut self,
+		block: u64,
+		buffer: &[u8],
+	) -> Result<(), FileSystemError> {
+		self.mark_dirty()?;
+		self.device.get_mut().write_blocks(block, buffer)
+	}
+
+	/// `write_device` for a range of bytes, see `BlockDevice::write_bytes`
+	fn write_device_bytes(
+		&mut self,
+		byte_offset: u64,
+		data: &[u8],
+	) -> Result<(), FileSystemError> {
+		self.mark_dirty()?;
+		self.device.get_mut().write_bytes(byte_offset, data)
+	}
+
+	/// true if the device refused writes at mount time
+	pub fn is_read_only(&self) -> bool {
+		self.read_only
+	}
+
+	fn check_writable(&self) -> Result<(), FileSystemError> {
+		if self.read_only { Err(FileSystemError::ReadOnly) } else { Ok(()) }
+	}
+
+	/// Hands back the device without writing anything out, unlike `unmount`
+	///
+	/// What a crash looks like to the device, for tests.
+	pub fn into_device(self) -> D {
+		self.device.into_inner()
+	}
+
+	/// Borrows the device, the borrow has to be gone before the next call into the filesystem
+	pub fn device(&self) -> Ref<'_, D> {
+		self.device.borrow()
+	}
+
+	/// the device underneath, for flushing a cache from outside
+	pub fn device_mut(&mut self) -> &mut D {
+		self.device.get_mut()
+	}
+
+	/// replaces the time source, seconds since boot by default
+	pub fn set_clock(
+		&mut self,
+		clock: Clock,
+	) {
+		self.clock = clock;
+	}
 
-		let size = size_of::<DiskSuperBlock>();
-		let disk_superblock = DiskSuperBlock::ref_from_bytes(&buffer[..size])
-			.map_err(|_| FileSystemError::InvalidSuperBlock)?;
+	/// Sets how stale an access time may get before a read refreshes it
+	///
+	/// Reads always refresh it when it's not newer than the modification time, like relatime.
+	pub fn set_relatime_interval(
+		&mut self,
+		seconds: u64,
+	) {
+		self.relatime_interval = seconds;
+	}
+
+	fn now(&self) -> u64 {
+		(self.clock)()
+	}
 
-		let superblock = SuperBlock::try_from(*disk_superblock)
-			.map_err(|_| FileSystemError::InvalidSuperBlock)?;
+	/// the file's inode, including an access time that isn't on disk yet
+	pub fn stat(
+		&self,
+		handle: FileHandler,
+	) -> Result<Inode, FileError> {
+		self.file_inode(handle)
+	}
 
-		if superblock.magic_number != MAGIC_NUMBER {
-			return Err(FileSystemError::InvalidSuperBlock);
+	/// Writes every pending access time back to the inode table
+	pub fn flush_times(&mut self) -> Result<(), FileSystemError> {
+		while let Some(&(inode_index, _)) = self.dirty_atimes.get_mut().first() {
+			self.flush_inode_times(inode_index)?;
 		}
+		Ok(())
+	}
 
-		Ok(Self { device, superblock })
+	fn flush_inode_times(
+		&mut self,
+		inode_index: u64,
+	) -> Result<(), FileSystemError> {
+		let pos = match self.dirty_atimes.get_mut().iter().position(|&(i, _)| i == inode_index) {
+			Some(pos) => pos,
+			None => return Ok(()),
+		};
+
+		let mut inode = self.read_inode(inode_index)?;
+		inode.last_access_time = self.dirty_atimes.get_mut()[pos].1;
+		self.write_inode(inode, inode_index)?;
+
+		self.dirty_atimes.get_mut().swap_remove(pos);
+		Ok(())
 	}
 
-	pub fn allocate_inode(&mut self) -> Result<u64, FileSystemError> {
-		let mut bitmap_buffer = [0u8; BLOCK_SIZE];
+	/// Writes the pending access times back once more than `DIRTY_TIMES_MAX` piled up
+	///
+	/// Reads only note them, they can't write. So this is up to whatever writes next.
+	fn flush_times_if_full(&mut self) -> Result<(), FileSystemError> {
+		if self.dirty_atimes.get_mut().len() > DIRTY_TIMES_MAX {
+			self.flush_times()?;
+		}
+		Ok(())
+	}
 
-		self.device
-			.read_blocks(INODE_BITMAP_BLOCK, &mut bitmap_buffer)
-			.map_err(|_| FileSystemError::BlockError)?;
+	/// Records a read of `inode`, relatime style
+	///
+	/// The access time only moves when it's not newer than the modification time or older than
+	/// the relatime interval, and even then it's only noted in memory until the next flush. The
+	/// access time it goes by is the one noted in memory if there is one, `inode` may come from the
+	/// table. There is at most one entry per inode, the list can't grow past the inode count.
+	fn touch_atime(
+		&self,
+		inode_index: u64,
+		inode: &Inode,
+	) {
+		// nowhere to put it
+		if self.read_only {
+			return;
+		}
 
-		// we gotta wrap the buffer around this to work on it as a Bitmap
-		let mut inode_bitmap = Bitmap::new(&mut bitmap_buffer);
+		let now = self.now();
+		let mut dirty_atimes = self.dirty_atimes.borrow_mut();
+		let noted = dirty_atimes.iter_mut().find(|(i, _)| *i == inode_index);
+		let atime = noted.as_ref().map_or(inode.last_access_time, |entry| entry.1);
 
-		let free_inode_index =
-			inode_bitmap.find_and_set_first_free().ok_or(FileSystemError::NoSpace)?;
+		let stale = now.saturating_sub(atime) >= self.relatime_interval;
+		if atime > inode.last_modification_time && !stale {
+			return;
+		}
 
-		// here we're working a reference of the bitmap_buffer -- so it is still valid and can be
-		// passed as the buffer to the write_blocks
+		match noted {
+			Some(entry) => entry.1 = now,
+			None => dirty_atimes.push((inode_index, now)),
+		}
+	}
 
-		// so the write_blocks of the BlockDevice should be able to overwrite the contents of the
-		// block if any exists
-		self.device
-			.write_blocks(self.superblock.inode_bitmap_block, &bitmap_buffer)
-			.map_err(|_| FileSystemError::BlockError)?;
+	pub fn superblock(&self) -> &SuperBlock {
+		&self.superblock
+	}
 
-		Ok(free_inode_index as u64)
+	/// Prints the superblock, the inodes the bitmap has in use and the root inode
+	///
+	/// For looking at a filesystem that misbehaves, a block that can't be read is printed in its
+	/// place.
+	pub fn dump(&self) {
+		let sb = self.superblock;
+		println!("{}", sb);
+
+		let mut used = Vec::new();
+		let tail_set = image::for_each_set_bit(
+			&mut *self.device.borrow_mut(),
+			sb.inode_bitmap_block,
+			sb.inode_bitmap_blocks,
+			sb.inode_count,
+			|idx| used.push(idx),
+		);
+		match tail_set {
+			Ok(tail_set) => {
+				println!(
+					"inode bitmap: {} of {} in use {}{}",
+					used.len(),
+					sb.inode_count,
+					Runs(&used),
+					if tail_set { ", bits set past the end" } else { "" }
+				);
+			},
+			Err(e) => println!("inode bitmap: {:?}", e),
+		}
+
+		match self.read_inode(ROOT_DIRECTORY_INODE) {
+			Ok(root) => println!("root {}", root),
+			Err(e) => println!("root inode: {:?}", e),
+		}
+	}
+
+	/// returns the runtime statistics
+	pub fn stats(&self) -> FsStats {
+		let dirty = self.device.borrow().dirty_state();
+		FsStats {
+			dir_index_hits: self.dir_index.lock().hits(),
+			dir_index_misses: self.dir_index.lock().misses(),
+			dirty_blocks: dirty.blocks,
+			oldest_dirty_ticks: dirty.oldest_age_ticks,
+			dirty_times: self.dirty_atimes.borrow().len(),
+			..*self.stats.borrow()
+		}
+	}
+
+	/// limits how many directory entries the in-memory directory index keeps, 0 turns it off
+	pub fn set_dir_index_budget(
+		&mut self,
+		budget: usize,
+	) {
+		self.dir_index.lock().set_budget(budget);
+	}
+
+	pub fn allocate_inode(&mut self) -> Result<u64, FileSystemError> {
+		let sb = self.superblock;
+		self.allocate_in_bitmap(sb.inode_bitmap_block, sb.inode_bitmap_blocks, sb.inode_count)
 	}
 
 	/// Allocates a data block following a read-modify-write pattern
 	pub fn allocate_data_block(&mut self) -> Result<u64, FileSystemError> {
-		let mut bm_buffer = [0u8; BLOCK_SIZE];
+		let sb = self.superblock;
+		let free_idx = self.allocate_in_bitmap(
+			sb.data_bitmap_block,
+			sb.data_bitmap_blocks,
+			sb.data_block_count,
+		)?;
 
-		self.device
-			.read_blocks(DATA_BITMAP_BLOCK, &mut bm_buffer)
-			.map_err(|_| FileSystemError::BlockError)?;
+		let abs_block = sb.data_block_start + free_idx;
+
+		Ok(abs_block)
+	}
+
+	/// Marks an inode as free again
+	///
+	/// The bitmap goes to disk right away, so nothing on disk may still point at the inode.
+	pub fn free_inode(
+		&mut self,
+		inode_index: u64,
+	) -> Result<(), FileSystemError> {
+		let sb = self.superblock;
+		self.inodes.get_mut().remove(&inode_index);
+		self.free_in_bitmap(sb.inode_bitmap_block, sb.inode_count, inode_index)
+	}
+
+	/// Marks a data block, given by its absolute block number, as free again
+	///
+	/// The bitmap goes to disk right away, so the inode that pointed at the block has to be synced
+	/// without it first, see `sync_inode`.
+	pub fn free_data_block(
+		&mut self,
+		block: u64,
+	) -> Result<(), FileSystemError> {
+		let sb = self.superblock;
+		let idx = block.checked_sub(sb.data_block_start).ok_or(FileSystemError::CorruptLayout)?;
+		self.free_in_bitmap(sb.data_bitmap_block, sb.data_block_count, idx)
+	}
+
+	/// Marks the `len` data blocks from absolute block `start` on as used, all of them have to be
+	/// free
+	fn allocate_data_run(
+		&mut self,
+		start: u64,
+		len: u64,
+	) -> Result<(), FileSystemError> {
+		let sb = self.superblock;
+		let first = start.checked_sub(sb.data_block_start).ok_or(FileSystemError::CorruptLayout)?;
+		let end = first + len;
+		if end > sb.data_block_count {
+			return Err(FileSystemError::CorruptLayout);
+		}
+
+		let mut bitmap_buffer = [0u8; BLOCK_SIZE];
+		let mut idx = first;
+		while idx < end {
+			let block = sb.data_bitmap_block + idx / BITS_PER_BITMAP_BLOCK;
+			// the part of the run this bitmap block tracks
+			let block_end = end.min((idx / BITS_PER_BITMAP_BLOCK + 1) * BITS_PER_BITMAP_BLOCK);
+
+			self.device
+				.get_mut()
+				.read_blocks(block, &mut bitmap_buffer)
+				.map_err(|_| FileSystemError::BlockError)?;
+			let mut bitmap = Bitmap::new(&mut bitmap_buffer);
+			for i in idx..block_end {
+				bitmap
+					.set((i % BITS_PER_BITMAP_BLOCK) as usize)
+					.map_err(|_| FileSystemError::CorruptLayout)?;
+			}
+			self.write_metadata(block, &bitmap_buffer)?;
+
+			idx = block_end;
+		}
+		Ok(())
+	}
+
+	/// Finds and sets the first clear bit in a run of bitmap blocks
+	///
+	/// `count` is the number of resources the run tracks, bits past it are never handed out
+	fn allocate_in_bitmap(
+		&mut self,
+		start_block: u64,
+		blocks: u64,
+		count: u64,
+	) -> Result<u64, FileSystemError> {
+		let mut bitmap_buffer = [0u8; BLOCK_SIZE];
 
-		let mut data_bitmap = Bitmap::new(&mut bm_buffer);
+		for i in 0..blocks {
+			let first_bit = i * BITS_PER_BITMAP_BLOCK;
+			if first_bit >= count {
+				break;
+			}
+
+			self.device
+				.get_mut()
+				.read_blocks(start_block + i, &mut bitmap_buffer)
+				.map_err(|_| FileSystemError::BlockError)?;
 
-		let free_idx = data_bitmap.find_and_set_first_free().ok_or(FileSystemError::NoSpace)?;
+			// we gotta wrap the buffer around this to work on it as a Bitmap
+			let free_bit = match Bitmap::new(&mut bitmap_buffer).find_and_set_first_free() {
+				Some(bit) => bit as u64,
+				None => continue, // this block is full, try the next one
+			};
+
+			let idx = first_bit + free_bit;
+			if idx >= count {
+				// only the bits past the end were left
+				break;
+			}
 
+			self.write_metadata(start_block + i, &bitmap_buffer)?;
+
+			return Ok(idx);
+		}
+
+		Err(FileSystemError::NoSpace)
+	}
+
+	/// Clears bit `idx` of a bitmap run, it lives in block `idx / BITS_PER_BITMAP_BLOCK` of the run
+	fn free_in_bitmap(
+		&mut self,
+		start_block: u64,
+		count: u64,
+		idx: u64,
+	) -> Result<(), FileSystemError> {
+		if idx >= count {
+			return Err(FileSystemError::CorruptLayout);
+		}
+
+		let block = start_block + idx / BITS_PER_BITMAP_BLOCK;
+		let bit = (idx % BITS_PER_BITMAP_BLOCK) as usize;
+
+		let mut bitmap_buffer = [0u8; BLOCK_SIZE];
 		self.device
-			.write_blocks(DATA_BITMAP_BLOCK, &bm_buffer)
+			.get_mut()
+			.read_blocks(block, &mut bitmap_buffer)
 			.map_err(|_| FileSystemError::BlockError)?;
 
-		let abs_block = self.superblock.data_block_start + free_idx as u64;
+		// clearing a clear bit means someone freed it twice
+		Bitmap::new(&mut bitmap_buffer).clear(bit).map_err(|_| FileSystemError::CorruptLayout)?;
+
+		self.write_metadata(block, &bitmap_buffer)
+	}
+
+	/// Writes a superblock or bitmap block and makes sure it reaches the device
+	///
+	/// Everything else may sit in a write-back cache. An allocation is on disk before anything
+	/// points at it, and a free only after nothing points at it anymore, so a power cut can leak
+	/// blocks but never leave an inode pointing at a free one.
+	fn write_metadata(
+		&mut self,
+		block: u64,
+		buffer: &[u8; BLOCK_SIZE],
+	) -> Result<(), FileSystemError> {
+		self.check_writable()?;
+		self.write_device(block, buffer).map_err(|_| FileSystemError::BlockError)?;
+		self.device.get_mut().sync_blocks(&[block]).map_err(|_| FileSystemError::BlockError)
+	}
+
+	/// Returns whether bit `idx` of a bitmap run is set, see `image::bitmap_bit`
+	fn bitmap_bit(
+		&mut self,
+		start_block: u64,
+		idx: u64,
+	) -> Result<bool, FileSystemError> {
+		Ok(image::bitmap_bit(self.device.get_mut(), start_block, idx)?)
+	}
+
+	/// Calls `f` for every set bit below `count` in a bitmap run, see `image::for_each_set_bit`
+	fn for_each_set_bit(
+		&mut self,
+		start_block: u64,
+		blocks: u64,
+		count: u64,
+		f: impl FnMut(u64),
+	) -> Result<bool, FileSystemError> {
+		Ok(image::for_each_set_bit(self.device.get_mut(), start_block, blocks, count, f)?)
+	}
+
+	/// Checks the bitmaps against the inodes, see `image::fsck`
+	///
+	/// Runs on its own 64 KiB stack once the page mapper is up, the boot stack's size is anyone's
+	/// guess.
+	pub fn fsck(&mut self) -> Result<FsckReport, FileSystemError> {
+		stack::run_on_shared(FSCK_STACK, stack::DEFAULT_STACK_PAGES, || self.check())
+	}
+
+	fn check(&mut self) -> Result<FsckReport, FileSystemError> {
+		Ok(image::fsck(self.device.get_mut(), &self.superblock)?)
+	}
+
+	/// Longest run of free data blocks as (first block, length), the length is 0 on a full disk
+	pub fn largest_free_run(&mut self) -> Result<(u64, u64), FileSystemError> {
+		let sb = self.superblock;
+		let mut best = (0, 0);
+		let mut run_start = 0;
+
+		// the set bits come in order, the free runs are the gaps between them
+		self.for_each_set_bit(
+			sb.data_bitmap_block,
+			sb.data_bitmap_blocks,
+			sb.data_block_count,
+			|idx| {
+				if idx - run_start > best.1 {
+					best = (run_start, idx - run_start);
+				}
+				run_start = idx + 1;
+			},
+		)?;
+		if sb.data_block_count - run_start > best.1 {
+			best = (run_start, sb.data_block_count - run_start);
+		}
+
+		Ok((sb.data_block_start + best.0, best.1))
+	}
+
+	/// Moves the blocks of every file that's in more than one piece next to each other
+	///
+	/// Walks the inode bitmap like fsck does. A fragmented file is copied into the longest run of
+	/// free blocks: the run is allocated, the copy written and synced, the inode pointed at it and
+	/// synced, and only then are the old blocks freed. That's the same order every write keeps, so
+	/// a power cut leaves each file in its old or its new place and at worst leaks blocks. When no
+	/// run fits the whole file, its first blocks go into the longest one, as long as that makes a
+	/// longer piece than the file already has.
+	///
+	/// `progress` gets (files done, files total) after every file. Directories stay put, the
+	/// directory index points into their blocks. Nobody may have a file open meanwhile.
+	pub fn defragment(
+		&mut self,
+		progress: &mut dyn FnMut(u64, u64),
+	) -> Result<DefragReport, FileSystemError> {
+		self.check_writable()?;
+		let sb = self.superblock;
+		let mut report = DefragReport::default();
+		report.largest_free_run_before = self.largest_free_run()?.1;
+
+		let mut inodes = Vec::new();
+		self.for_each_set_bit(
+			sb.inode_bitmap_block,
+			sb.inode_bitmap_blocks,
+			sb.inode_count,
+			|idx| inodes.push(idx),
+		)?;
+		let mut files = Vec::new();
+		for inode_index in inodes {
+			if self.read_inode(inode_index)?.mode == FileType::File {
+				files.push(inode_index);
+			}
+		}
+		report.files = files.len() as u64;
+
+		for (done, &inode_index) in files.iter().enumerate() {
+			let (moved, whole) = self.defragment_file(inode_index)?;
+			if moved > 0 {
+				report.files_moved += 1;
+				report.blocks_relocated += moved;
+				if !whole {
+					report.files_partial += 1;
+				}
+			}
+			progress(done as u64 + 1, report.files);
+		}
+
+		report.largest_free_run_after = self.largest_free_run()?.1;
+		Ok(report)
+	}
+
+	/// Moves one file's blocks into the longest free run, see `defragment`
+	///
+	/// Returns how many blocks moved and whether the file is in one piece now.
+	fn defragment_file(
+		&mut self,
+		inode_index: u64,
+	) -> Result<(u64, bool), FileSystemError> {
+		let mut inode = self.read_inode(inode_index)?;
+		let block_count =
+			(inode.size_in_bytes as usize).div_ceil(BLOCK_SIZE).min(inode.direct_pointers.len());
+		let pointers = &inode.direct_pointers[..block_count];
+
+		// a hole is for fsck to look at, not something to copy around
+		if pointers.contains(&0) {
+			return Ok((0, false));
+		}
+		let runs = contiguous_runs(pointers);
+		if runs.len() <= 1 {
+			return Ok((0, true));
+		}
+
+		let longest = runs.iter().map(|run| run.len).max().unwrap_or(0);
+		let (free_start, free_len) = self.largest_free_run()?;
+		let moving = block_count.min(free_len as usize);
+		if moving <= longest {
+			return Ok((0, false));
+		}
+
+		self.allocate_data_run(free_start, moving as u64)?;
+
+		let old = inode.direct_pointers[..moving].to_vec();
+		let mut buf = vec![0u8; moving * BLOCK_SIZE];
+		for run in contiguous_runs(&old) {
+			let range = run.first * BLOCK_SIZE..(run.first + run.len) * BLOCK_SIZE;
+			self.device
+				.get_mut()
+				.read_blocks(run.start, &mut buf[range])
+				.map_err(|_| FileSystemError::BlockError)?;
+		}
+
+		let new: Vec<u64> = (free_start..free_start + moving as u64).collect();
+		self.write_device(free_start, &buf).map_err(|_| FileSystemError::BlockError)?;
+		self.device.get_mut().sync_blocks(&new).map_err(|_| FileSystemError::BlockError)?;
+
+		// the switch, before this the file is where it was and after it where it's going
+		inode.direct_pointers[..moving].copy_from_slice(&new);
+		self.write_inode(inode, inode_index)?;
+		self.sync_inode(inode_index)?;
+
+		for block in old {
+			self.free_data_block(block)?;
+		}
+		Ok((moving as u64, moving == block_count))
+	}
+
+	/// Reads the file's contents from the start into `buf`, returns the number of bytes read
+	///
+	/// Blocks that sit next to each other on disk are fetched with a single device request.
+	pub fn read_file(
+		&self,
+		handle: FileHandler,
+		buf: &mut [u8],
+	) -> Result<usize, FileError> {
+		let inode = self.file_inode(handle)?;
+
+		let len = buf.len().min(inode.size_in_bytes as usize);
+		let block_count = len.div_ceil(BLOCK_SIZE);
+		// a size past what the direct pointers hold is a damaged inode, not a reason to panic
+		let pointers = inode.direct_pointers.get(..block_count).ok_or(FileError::Corrupt)?;
+
+		if pointers.contains(&0) {
+			return Err(FileError::Corrupt);
+		}
+
+		for run in contiguous_runs(pointers) {
+			let mut run_buf = vec![0u8; run.len * BLOCK_SIZE];
+			self.device
+				.borrow_mut()
+				.read_blocks(run.start, &mut run_buf)
+				.map_err(|_| FileError::BlockReadError)?;
+			self.stats.borrow_mut().data_read_requests += 1;
+
+			let offset = run.first * BLOCK_SIZE;
+			let end = (offset + run_buf.len()).min(len);
+			buf[offset..end].copy_from_slice(&run_buf[..end - offset]);
+		}
+
+		self.touch_atime(handle.0 as u64, &inode);
+
+		Ok(len)
+	}
+
+	/// Replaces the file's contents with `data`, returns the number of bytes written
+	///
+	/// Only the direct pointers are used so far, which caps files at 10 blocks. Blocks the new
+	/// contents don't need anymore are freed.
+	pub fn write_file(
+		&mut self,
+		handle: FileHandler,
+		data: &[u8],
+	) -> Result<usize, FileError> {
+		if self.read_only {
+			return Err(FileError::ReadOnly);
+		}
+		let mut inode = self.file_inode(handle)?;
+
+		let block_count = data.len().div_ceil(BLOCK_SIZE);
+		if block_count > inode.direct_pointers.len() {
+			return Err(FileError::NoSpace);
+		}
+
+		// freed only once the inode without them is on disk
+		let mut unused = Vec::new();
+
+		for i in 0..inode.direct_pointers.len() {
+			let pointer = inode.direct_pointers[i];
+
+			if i < block_count && pointer == 0 {
+				inode.direct_pointers[i] = self.allocate_data_block().map_err(allocate_error)?;
+			} else if i >= block_count && pointer != 0 {
+				unused.push(pointer);
+				inode.direct_pointers[i] = 0;
+			}
+		}
+
+		for run in contiguous_runs(&inode.direct_pointers[..block_count]) {
+			// the last block gets zero padded
+			let mut run_buf = vec![0u8; run.len * BLOCK_SIZE];
+			let offset = run.first * BLOCK_SIZE;
+			let end = (offset + run_buf.len()).min(data.len());
+			run_buf[..end - offset].copy_from_slice(&data[offset..end]);
+
+			self.write_device(run.start, &run_buf).map_err(|_| FileError::BlockWriteError)?;
+			self.stats.get_mut().data_write_requests += 1;
+		}
+
+		inode.size_in_bytes = data.len() as u64;
+		self.finish_write(handle, inode)?;
+
+		if !unused.is_empty() {
+			self.sync_inode(handle.0 as u64).map_err(|_| FileError::BlockWriteError)?;
+			for block in unused {
+				self.free_data_block(block).map_err(|_| FileError::Corrupt)?;
+			}
+		}
+
+		Ok(data.len())
+	}
+
+	/// Writes `data` after the end of the file, returns the number of bytes written
+	///
+	/// The blocks before the old end aren't touched, the last one is filled up and new ones are
+	/// allocated for the rest. Like `write_file`, nothing is written if the file would grow past
+	/// its direct pointers.
+	pub fn append(
+		&mut self,
+		handle: FileHandler,
+		data: &[u8],
+	) -> Result<usize, FileError> {
+		if self.read_only {
+			return Err(FileError::ReadOnly);
+		}
+		let mut inode = self.file_inode(handle)?;
+
+		let start = inode.size_in_bytes as usize;
+		let end = start + data.len();
+		let first = start / BLOCK_SIZE;
+		let block_count = end.div_ceil(BLOCK_SIZE);
+		if block_count > inode.direct_pointers.len() {
+			return Err(FileError::NoSpace);
+		}
+
+		for pointer in &mut inode.direct_pointers[first..block_count] {
+			if *pointer == 0 {
+				*pointer = self.allocate_data_block().map_err(allocate_error)?;
+			}
+		}
+
+		for run in contiguous_runs(&inode.direct_pointers[first..block_count]) {
+			// from the old end or the run's first block up to the run's last, zero padded
+			let run_start = (first + run.first) * BLOCK_SIZE;
+			let from = start.max(run_start);
+			let mut run_buf = vec![0u8; run_start + run.len * BLOCK_SIZE - from];
+			let to = end.min(from + run_buf.len());
+			run_buf[..to - from].copy_from_slice(&data[from - start..to - start]);
+
+			let byte_offset = run.start * BLOCK_SIZE as u64 + (from - run_start) as u64;
+			self.write_device_bytes(byte_offset, &run_buf)
+				.map_err(|_| FileError::BlockWriteError)?;
+			self.stats.get_mut().data_write_requests += 1;
+		}
+
+		inode.size_in_bytes = end as u64;
+		self.finish_write(handle, inode)?;
+
+		Ok(data.len())
+	}
+
+	/// writes out the inode of a file whose contents just changed
+	fn finish_write(
+		&mut self,
+		handle: FileHandler,
+		mut inode: Inode,
+	) -> Result<(), FileError> {
+		inode.last_modification_time = self.now();
+		// the inode goes out anyway, with any pending access time folded in
+		self.write_inode(inode, handle.0 as u64).map_err(|_| FileError::BlockWriteError)?;
+		self.dirty_atimes.get_mut().retain(|&(i, _)| i != handle.0 as u64);
+		self.flush_times_if_full().map_err(|_| FileError::BlockWriteError)
+	}
+
+	/// reads the inode behind a handle, making sure it is a regular file
+	fn file_inode(
+		&self,
+		handle: FileHandler,
+	) -> Result<Inode, FileError> {
+		if handle.0 as u64 >= self.superblock.inode_count {
+			return Err(FileError::InvalidHandle);
+		}
+
+		let mut inode = self.read_inode(handle.0 as u64).map_err(|_| FileError::BlockReadError)?;
+		if inode.mode != FileType::File {
+			return Err(FileError::InvalidHandle);
+		}
+
+		if let Some(&(_, atime)) =
+			self.dirty_atimes.borrow().iter().find(|&&(i, _)| i == handle.0 as u64)
+		{
+			inode.last_access_time = atime;
+		}
+
+		Ok(inode)
+	}
+
+	/// the inode table block an inode lives in
+	fn inode_block(
+		&self,
+		inode_index: u64,
+	) -> u64 {
+		image::inode_location(&self.superblock, inode_index).0
+	}
 
-		Ok(abs_block)
+	/// makes sure the inode table block holding `inode_index` reached the device
+	pub fn sync_inode(
+		&mut self,
+		inode_index: u64,
+	) -> Result<(), FileSystemError> {
+		let block = self.inode_block(inode_index);
+		self.device.get_mut().sync_blocks(&[block]).map_err(|_| FileSystemError::BlockError)
 	}
 
 	pub fn read_inode(
-		&mut self,
+		&self,
 		inode_index: u64,
 	) -> Result<Inode, FileSystemError> {
-		let block_num =
-			self.superblock.inode_table_start_block + (inode_index / INODES_PER_BLOCK as u64);
+		let last_used = self.inode_use();
+		if let Some(cached) = self.inodes.borrow_mut().get_mut(&inode_index) {
+			cached.last_used = last_used;
+			return Ok(cached.inode);
+		}
 
-		let offset_in_block = (inode_index % INODES_PER_BLOCK as u64) as usize * INODE_SIZE;
+		let (block_num, offset_in_block) = image::inode_location(&self.superblock, inode_index);
 
 		let mut buffer = [0u8; BLOCK_SIZE];
 		self.device
+			.borrow_mut()
 			.read_blocks(block_num, &mut buffer)
 			.map_err(|_| FileSystemError::BlockError)?;
 
-		// so here we read the disk inode from the buffer
-		let size = size_of::<DiskInode>();
-		let disk_inode =
-			DiskInode::ref_from_bytes(&buffer[offset_in_block..(offset_in_block + size)])
-				.map_err(|_| FileSystemError::BlockError)?;
-
-		let inode = Inode::try_from(*disk_inode).map_err(|_| FileSystemError::BlockError)?;
+		let inode = image::decode_inode(&buffer, offset_in_block)
+			.map_err(|_| FileSystemError::BlockError)?;
+		self.cache_inode(inode_index, inode);
 
 		Ok(inode)
 	}
@@ -166,34 +1099,55 @@ impl<D: BlockDevice> SFS<D> {
 		inode: Inode,
 		inode_idx: u64,
 	) -> Result<(), FileSystemError> {
+		self.check_writable()?;
+
 		// then we have to know which actual inode to write this into
 		// the free_inode_idx is just the index of the bit in the inode_bitmap
 		// so we gotta fetch the inode tables now, then index from those tables
 
-		let block_num =
-			self.superblock.inode_table_start_block + (inode_idx / INODES_PER_BLOCK as u64);
-
-		let offset_in_block = (inode_idx % INODES_PER_BLOCK as u64) as usize * INODE_SIZE;
-
-		let mut buffer = [0u8; BLOCK_SIZE];
-		self.device
-			.read_blocks(block_num, &mut buffer)
-			.map_err(|_| FileSystemError::BlockError)?;
+		let (block_num, offset_in_block) = image::inode_location(&self.superblock, inode_idx);
+		let byte_offset = block_num * BLOCK_SIZE as u64 + offset_in_block as u64;
 
-		// so here we read the disk inode from the buffer
+		// the neighbours in the table block are read and written back as they are
 		let disk_inode = DiskInode::from(inode);
-		//let inode_m = Inode::try_from(disk_inode).unwrap();
-		let size = size_of::<DiskInode>();
-		let inode_slice = &mut buffer[offset_in_block..(offset_in_block + size)];
-		inode_slice.copy_from_slice(disk_inode.as_bytes());
-
-		self.device
-			.write_blocks(block_num, &buffer)
-			.map_err(|_| FileSystemError::BlockError)?;
+		if self.write_device_bytes(byte_offset, disk_inode.as_bytes()).is_err() {
+			// no telling what the table holds now, the next read goes to the device
+			self.inodes.get_mut().remove(&inode_idx);
+			return Err(FileSystemError::BlockError);
+		}
+		self.cache_inode(inode_idx, inode);
 
 		Ok(())
 	}
 
+	/// Remembers `inode` as what's on disk, pushing out the least recently used one when full
+	///
+	/// Every write goes to the device right away, a block cache underneath holds it until
+	/// `sync_inode` if there is one. So nothing cached is ever newer than the device and the
+	/// allocate, point, free order of the writes stays as it is.
+	fn cache_inode(
+		&self,
+		inode_index: u64,
+		inode: Inode,
+	) {
+		let last_used = self.inode_use();
+		let mut inodes = self.inodes.borrow_mut();
+		if inodes.len() >= INODE_CACHE_LEN && !inodes.contains_key(&inode_index) {
+			let lru = inodes.iter().min_by_key(|(_, cached)| cached.last_used).map(|(&i, _)| i);
+			if let Some(lru) = lru {
+				inodes.remove(&lru);
+			}
+		}
+		inodes.insert(inode_index, CachedInode { inode, last_used });
+	}
+
+	/// the next value of the inode use counter
+	fn inode_use(&self) -> u64 {
+		let uses = self.inode_uses.get() + 1;
+		self.inode_uses.set(uses);
+		uses
+	}
+
 	/// Adds a new directory entry into a directory block buffer at a given slot index
 	///
 	/// A block would have multiple directory entries, slot is the index of this.
@@ -252,9 +1206,12 @@ impl<D: BlockDevice> SFS<D> {
 
 	// Initialize Root Directory: Inode 0, allocate one data block
 	pub fn init_root_directory(&mut self) -> Result<(), FileSystemError> {
+		self.check_writable()?;
+		// root is inode 0, which is always bit 0 of the first inode bitmap block
 		let mut ibuf = [0u8; BLOCK_SIZE];
 		self.device
-			.read_blocks(INODE_BITMAP_BLOCK, &mut ibuf)
+			.get_mut()
+			.read_blocks(self.superblock.inode_bitmap_block, &mut ibuf)
 			.map_err(|_| FileSystemError::BlockError)?;
 
 		{
@@ -264,11 +1221,10 @@ impl<D: BlockDevice> SFS<D> {
 			}
 		}
 
-		self.device
-			.write_blocks(INODE_BITMAP_BLOCK, &ibuf)
-			.map_err(|_| FileSystemError::BlockError)?;
+		self.write_metadata(self.superblock.inode_bitmap_block, &ibuf)?;
 
 		let data_block = self.allocate_data_block()?;
+		let now = self.now();
 
 		let mut root = Inode {
 			mode: FileType::Directory,
@@ -276,9 +1232,9 @@ impl<D: BlockDevice> SFS<D> {
 			group_id: 0,
 			link_count: 2, // "." and ".."
 			size_in_bytes: 0,
-			last_access_time: 0,
-			last_modification_time: 0,
-			creation_time: 0,
+			last_access_time: now,
+			last_modification_time: now,
+			creation_time: now,
 			direct_pointers: [0u64; 10],
 			indirect_pointer: 0,
 		};
@@ -290,11 +1246,10 @@ impl<D: BlockDevice> SFS<D> {
 		self.write_dirent_into_block(&mut dir_block, 0, 0, b".")?;
 		self.write_dirent_into_block(&mut dir_block, 1, 0, b"..")?;
 
-		self.device
-			.write_blocks(data_block, &dir_block)
-			.map_err(|_| FileSystemError::BlockError)?;
+		self.write_device(data_block, &dir_block).map_err(|_| FileSystemError::BlockError)?;
 
-		Ok(())
+		// part of formatting, a fresh filesystem shouldn't come back without its root
+		self.device.get_mut().sync_all()
 	}
 
 	pub fn add_root_dir_entry(
@@ -306,80 +1261,173 @@ impl<D: BlockDevice> SFS<D> {
 			return Err(FileSystemError::NameTooLong);
 		}
 
-		// Root is inode 0
-		let root = self.read_inode(0)?;
-		let block = root.direct_pointers[0];
-
-		if block == 0 {
-			return Err(FileSystemError::CorruptLayout);
-		}
+		let DirSlot { block, slot } = self.free_root_slot()?;
 
 		let mut dir_block = [0u8; BLOCK_SIZE];
 		self.device
+			.get_mut()
 			.read_blocks(block, &mut dir_block)
 			.map_err(|_| FileSystemError::BlockError)?;
 
-		let slot = self.find_free_dir_slot(&dir_block).ok_or(FileSystemError::NoSpace)?;
-
 		self.write_dirent_into_block(&mut dir_block, slot, inode, name.as_bytes())?;
 
-		self.device
-			.write_blocks(block, &dir_block)
-			.map_err(|_| FileSystemError::BlockError)?;
+		self.write_device(block, &dir_block).map_err(|_| FileSystemError::BlockError)?;
+		self.dir_index.lock().insert_entry(
+			ROOT_DIRECTORY_INODE,
+			fnv1a(name.as_bytes()),
+			DirSlot { block, slot },
+		);
 
 		Ok(())
 	}
 
+	/// the root directory's inode and its blocks in order, see `image::dir_blocks`
+	fn root_dir_blocks(&self) -> Result<(Inode, Vec<u64>), FileSystemError> {
+		let root = self.read_inode(ROOT_DIRECTORY_INODE)?;
+		if root.mode != FileType::Directory {
+			return Err(FileSystemError::CorruptLayout);
+		}
+
+		let blocks = image::dir_blocks(&mut *self.device.borrow_mut(), &self.superblock, &root)?;
+		Ok((root, blocks))
+	}
+
+	/// A free dirent slot in the root directory, which grows by a block when it's full
+	///
+	/// The last block is looked at first, a directory that only ever grew has its room there. A new
+	/// block goes into the first free direct pointer, after those into the indirect block. It's
+	/// written out zeroed before anything points at it.
+	fn free_root_slot(&mut self) -> Result<DirSlot, FileSystemError> {
+		let (mut root, blocks) = self.root_dir_blocks()?;
+		if blocks.is_empty() {
+			return Err(FileSystemError::CorruptLayout);
+		}
+
+		let mut block_buf = [0u8; BLOCK_SIZE];
+		for &block in blocks.iter().rev() {
+			self.device
+				.get_mut()
+				.read_blocks(block, &mut block_buf)
+				.map_err(|_| FileSystemError::BlockError)?;
+			// `.` and `..` point at inode 0, only the flag says whether a slot is taken
+			let free = DirEntryBlock::new(&block_buf)
+				.position(|entry| (entry.flags.get() & DIRENT_USED) == 0);
+			if let Some(slot) = free {
+				return Ok(DirSlot { block, slot });
+			}
+		}
+
+		// room for the new block's pointer, checked before anything is allocated
+		let direct = root.direct_pointers.iter().position(|&pointer| pointer == 0);
+		let mut indirect = [0u8; BLOCK_SIZE];
+		if direct.is_none() && root.indirect_pointer != 0 {
+			self.device
+				.get_mut()
+				.read_blocks(root.indirect_pointer, &mut indirect)
+				.map_err(|_| FileSystemError::BlockError)?;
+		}
+		let indirect_slot =
+			image::indirect_pointers(&indirect).iter().position(|&pointer| pointer == 0);
+		if direct.is_none() && indirect_slot.is_none() {
+			return Err(FileSystemError::NoSpace);
+		}
+
+		let block = self.allocate_data_block()?;
+		self.write_device(block, &[0u8; BLOCK_SIZE])?;
+
+		match (direct, indirect_slot) {
+			(Some(i), _) => root.direct_pointers[i] = block,
+			(None, Some(i)) => {
+				if root.indirect_pointer == 0 {
+					root.indirect_pointer = match self.allocate_data_block() {
+						Ok(indirect_block) => indirect_block,
+						Err(e) => {
+							self.free_data_block(block)?;
+							return Err(e);
+						},
+					};
+				}
+				indirect[i * size_of::<u64>()..][..size_of::<u64>()]
+					.copy_from_slice(&block.to_le_bytes());
+				self.write_device(root.indirect_pointer, &indirect)?;
+			},
+			(None, None) => unreachable!("checked above"),
+		}
+		self.write_inode(root, ROOT_DIRECTORY_INODE)?;
+
+		Ok(DirSlot { block, slot: 0 })
+	}
+
+	/// Opens `name`, creating it first if it doesn't exist
+	///
+	/// One lookup decides which, so the root directory block is read and written once at most.
+	/// Opening works on a read-only mount, creating doesn't.
+	pub fn open_or_create(
+		&mut self,
+		name: &str,
+	) -> Result<FileHandler, FileError> {
+		match self.lookup_in_root(name.as_bytes()) {
+			Ok(Some((inode_index, _slot))) => return Ok(FileHandler(inode_index as usize)),
+			Ok(None) => {},
+			Err(_) => return Err(FileError::BlockReadError),
+		}
+
+		self.check_new_name(name).map_err(create_error)?;
+		let (inode_index, _dir_block) = self.add_to_root(name).map_err(create_error)?;
+		println!("[FS] Created file '{}' with inode #{}", name, inode_index);
+		Ok(FileHandler(inode_index as usize))
+	}
+
 	fn create_file_in_root(
 		&mut self,
 		name: &str,
 	) -> Result<(u64 /*inode index*/, u64 /*dir block*/), FileSystemError> {
-		if name.as_bytes().len() > DIR_NAME_MAX || name.is_empty() {
-			return Err(FileSystemError::NameTooLong);
-		}
+		self.check_new_name(name)?;
 
-		// Read root directory block
-		let root_dir_inode = self.read_inode(ROOT_DIRECTORY_INODE)?;
-		if root_dir_inode.mode != FileType::Directory {
-			return Err(FileSystemError::CorruptLayout);
+		// Collision check, goes through the directory index
+		if self.lookup_in_root(name.as_bytes())?.is_some() {
+			return Err(FileSystemError::CorruptLayout); // use FileError::FileExists at call site
 		}
 
-		let dir_block = root_dir_inode.direct_pointers[0];
-		if dir_block == 0 {
-			return Err(FileSystemError::CorruptLayout);
+		self.add_to_root(name)
+	}
+
+	/// fails unless a file called `name` could be created, apart from the name being taken
+	fn check_new_name(
+		&self,
+		name: &str,
+	) -> Result<(), FileSystemError> {
+		self.check_writable()?;
+		if name.as_bytes().len() > DIR_NAME_MAX || name.is_empty() {
+			return Err(FileSystemError::NameTooLong);
 		}
+		Ok(())
+	}
+
+	/// Creates the file `name` in the root directory, the caller checked the name isn't taken
+	fn add_to_root(
+		&mut self,
+		name: &str,
+	) -> Result<(u64 /*inode index*/, u64 /*dir block*/), FileSystemError> {
+		let DirSlot { block: dir_block, slot: slot_index } = self.free_root_slot()?;
 		let mut dir_block_buf = [0u8; BLOCK_SIZE];
 		self.device
+			.get_mut()
 			.read_blocks(dir_block, &mut dir_block_buf)
 			.map_err(|_| FileSystemError::BlockError)?;
 
-		// Collision check and find slot
-		let mut empty_slot_index: Option<usize> = None;
-		let entries = DirEntryBlock::new(&dir_block_buf);
-		for (i, entry) in entries.enumerate() {
-			let is_used = (entry.flags.get() & DIRENT_USED) != 0;
-			if is_used {
-				let entry_name_len = entry.name_len.get() as usize;
-				if &entry.name[..entry_name_len] == name.as_bytes() {
-					return Err(FileSystemError::CorruptLayout); // use FileError::FileExists at call site
-				}
-			} else if empty_slot_index.is_none() {
-				empty_slot_index = Some(i);
-			}
-		}
-		let slot_index = empty_slot_index.ok_or(FileSystemError::NoSpace)?;
-
 		// Allocate inode and write it
 		let inode_index = self.allocate_inode()?;
+		let now = self.now();
 		let new_inode = Inode {
 			mode: FileType::File,
 			user_id: 0,
 			group_id: 0,
 			link_count: 1,
 			size_in_bytes: 0,
-			last_access_time: 0,
-			last_modification_time: 0,
-			creation_time: 0,
+			last_access_time: now,
+			last_modification_time: now,
+			creation_time: now,
 			direct_pointers: [0u64; 10],
 			indirect_pointer: 0,
 		};
@@ -389,11 +1437,179 @@ impl<D: BlockDevice> SFS<D> {
 		self.write_dirent_into_block(&mut dir_block_buf, slot_index, inode_index, name.as_bytes())?;
 
 		// PERSIST THE UPDATED DIRECTORY BLOCK (this was missing)
+		self.write_device(dir_block, &dir_block_buf).map_err(|_| FileSystemError::BlockError)?;
+
+		// keep the index in sync with the new entry
+		self.dir_index.lock().insert_entry(
+			ROOT_DIRECTORY_INODE,
+			fnv1a(name.as_bytes()),
+			DirSlot { block: dir_block, slot: slot_index },
+		);
+
+		Ok((inode_index, dir_block))
+	}
+
+	/// Deletes the file `name` from the root directory, false if there's no such file
+	///
+	/// The dirent goes first and is synced, only then are the data blocks and the inode freed. A
+	/// power cut in between leaks them, nothing is left pointing at a free block.
+	fn remove_from_root(
+		&mut self,
+		name: &str,
+	) -> Result<bool, FileSystemError> {
+		self.check_writable()?;
+		if name == "." || name == ".." {
+			return Err(FileSystemError::NameTooLong);
+		}
+
+		let (inode_index, slot) = match self.lookup_in_root(name.as_bytes())? {
+			Some(found) => found,
+			None => return Ok(false),
+		};
+		let inode = self.read_inode(inode_index)?;
+		if inode.mode != FileType::File {
+			return Err(FileSystemError::CorruptLayout);
+		}
+
+		let mut dir_block = [0u8; BLOCK_SIZE];
+		self.device
+			.get_mut()
+			.read_blocks(slot.block, &mut dir_block)
+			.map_err(|_| FileSystemError::BlockError)?;
+		let start = slot.slot * DIR_ENTRY_SIZE;
+		dir_block[start..start + DIR_ENTRY_SIZE].fill(0);
+		self.write_device(slot.block, &dir_block)?;
 		self.device
-			.write_blocks(dir_block, &dir_block_buf)
+			.get_mut()
+			.sync_blocks(&[slot.block])
 			.map_err(|_| FileSystemError::BlockError)?;
+		self.dir_index.lock().remove_entry(ROOT_DIRECTORY_INODE, fnv1a(name.as_bytes()), slot);
 
-		Ok((inode_index, dir_block))
+		let pointers =
+			inode.direct_pointers.iter().chain(core::iter::once(&inode.indirect_pointer));
+		for &block in pointers.filter(|&&block| block != 0) {
+			self.free_data_block(block)?;
+		}
+		self.dirty_atimes.get_mut().retain(|&(i, _)| i != inode_index);
+		self.free_inode(inode_index)?;
+		Ok(true)
+	}
+
+	/// Looks up `name` in the root directory, returns its inode and dirent slot
+	///
+	/// Goes through the directory index first. The index is built by a linear scan the first
+	/// time the directory is looked at, and on a hash collision that doesn't verify we fall back
+	/// to the linear scan as well.
+	fn lookup_in_root(
+		&self,
+		name: &[u8],
+	) -> Result<Option<(u64, DirSlot)>, FileSystemError> {
+		let hash = fnv1a(name);
+
+		let indexed = self
+			.dir_index
+			.lock()
+			.get(ROOT_DIRECTORY_INODE)
+			.map(|index| index.candidates(hash).to_vec());
+		let candidates = match indexed {
+			Some(candidates) => candidates,
+			None => {
+				// not indexed yet: build it, the scan answers this lookup too
+				self.dir_index.lock().record_miss();
+				let (index, found) = self.scan_root_dir(name)?;
+				self.dir_index.lock().insert_dir(ROOT_DIRECTORY_INODE, index);
+				return Ok(found);
+			},
+		};
+
+		// the index covers the whole directory, so no candidates means no such name
+		if candidates.is_empty() {
+			self.dir_index.lock().record_hit();
+			return Ok(None);
+		}
+
+		for slot in candidates {
+			if let Some(inode) = self.read_dirent_named(slot, name)? {
+				self.dir_index.lock().record_hit();
+				return Ok(Some((inode, slot)));
+			}
+		}
+
+		// only collisions matched, don't trust the index for this one
+		self.dir_index.lock().record_miss();
+		let (index, found) = self.scan_root_dir(name)?;
+		self.dir_index.lock().insert_dir(ROOT_DIRECTORY_INODE, index);
+		Ok(found)
+	}
+
+	/// Linear scan over the root directory blocks
+	///
+	/// Builds a fresh index of the directory and returns the entry called `name` if there is one
+	fn scan_root_dir(
+		&self,
+		name: &[u8],
+	) -> Result<(DirIndex, Option<(u64, DirSlot)>), FileSystemError> {
+		let (_, blocks) = self.root_dir_blocks()?;
+
+		let mut index = DirIndex::new();
+		let mut found = None;
+		let mut block_buf = [0u8; BLOCK_SIZE];
+
+		for block in blocks {
+			self.device
+				.borrow_mut()
+				.read_blocks(block, &mut block_buf)
+				.map_err(|_| FileSystemError::BlockError)?;
+
+			for (slot, entry) in DirEntryBlock::new(&block_buf).enumerate() {
+				if (entry.flags.get() & DIRENT_USED) == 0 {
+					continue;
+				}
+
+				let entry_name = &entry.name[..(entry.name_len.get() as usize).min(DIR_NAME_MAX)];
+				let dir_slot = DirSlot { block, slot };
+				index.insert(fnv1a(entry_name), dir_slot);
+
+				if found.is_none() && entry_name == name {
+					found = Some((entry.inode.get(), dir_slot));
+				}
+			}
+		}
+
+		Ok((index, found))
+	}
+
+	/// Names of the files in the root directory in on-disk order, without `.` and `..`
+	fn list_root_dir(&self) -> Result<Vec<String>, FileSystemError> {
+		let entries = image::list_dir(
+			&mut *self.device.borrow_mut(),
+			&self.superblock,
+			ROOT_DIRECTORY_INODE,
+		)?;
+		Ok(entries.into_iter().map(|entry| entry.name).collect())
+	}
+
+	/// Reads the dirent at `slot` and returns its inode if it's in use and called `name`
+	fn read_dirent_named(
+		&self,
+		slot: DirSlot,
+		name: &[u8],
+	) -> Result<Option<u64>, FileSystemError> {
+		let mut block_buf = [0u8; BLOCK_SIZE];
+		self.device
+			.borrow_mut()
+			.read_blocks(slot.block, &mut block_buf)
+			.map_err(|_| FileSystemError::BlockError)?;
+
+		let entry = match DirEntryBlock::new(&block_buf).nth(slot.slot) {
+			Some(entry) => entry,
+			None => return Ok(None),
+		};
+
+		let used = (entry.flags.get() & DIRENT_USED) != 0;
+		let entry_name = &entry.name[..(entry.name_len.get() as usize).min(DIR_NAME_MAX)];
+
+		Ok(if used && entry_name == name { Some(entry.inode.get()) } else { None })
 	}
 }
 
@@ -401,7 +1617,7 @@ impl<D: BlockDevice> SFS<D> {
 #[derive(Debug, Copy, Clone)]
 pub struct FileHandler(pub usize);
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FileError {
 	BlockReadError,
 	DirectoryFull,
@@ -413,6 +1629,8 @@ pub enum FileError {
 	InvalidHandle,
 	InvalidName,
 	Corrupt,
+	/// the filesystem is mounted read-only
+	ReadOnly,
 }
 
 pub trait FileSystem {
@@ -425,10 +1643,37 @@ pub trait FileSystem {
 		name: &str,
 	) -> Result<(), FileError>;
 	fn open_file(
-		&mut self,
+		&self,
 		name: &str,
 	) -> Result<FileHandler, FileError>;
-	fn list_file(&mut self) -> Result<Vec<String>, FileError>;
+	fn list_file(&self) -> Result<Vec<String>, FileError>;
+	/// the inode of a regular file, for its size and times
+	fn stat(
+		&self,
+		handle: FileHandler,
+	) -> Result<Inode, FileError>;
+	/// reads the file from the start into `buf`, returns how many bytes that was
+	fn read_file(
+		&self,
+		handle: FileHandler,
+		buf: &mut [u8],
+	) -> Result<usize, FileError>;
+	/// replaces the file's contents with `data`, returns how many bytes that was
+	fn write_file(
+		&mut self,
+		handle: FileHandler,
+		data: &[u8],
+	) -> Result<usize, FileError>;
+	/// writes out anything about the file that is only held in memory
+	fn fsync(
+		&mut self,
+		handle: FileHandler,
+	) -> Result<(), FileError>;
+	/// puts the blocks of fragmented files next to each other, see `SFS::defragment`
+	fn defrag(
+		&mut self,
+		progress: &mut dyn FnMut(u64, u64),
+	) -> Result<DefragReport, FileError>;
 }
 
 #[derive(Debug)]
@@ -440,6 +1685,68 @@ pub enum FileSystemError {
 	NameTooLong,
 	CorruptLayout,
 	InvalidSuperBlock,
+	/// the device or the mount doesn't take writes
+	ReadOnly,
+	/// `FormatOptions` that don't make a usable layout
+	BadFormatOptions,
+}
+
+/// what the shared image code's errors mean to the filesystem
+impl From<ImageError> for FileSystemError {
+	fn from(e: ImageError) -> Self {
+		match e {
+			ImageError::Read => FileSystemError::BlockError,
+			ImageError::InvalidSuperBlock(_) => FileSystemError::InvalidSuperBlock,
+			ImageError::NoSuchInode(_)
+			| ImageError::NotFound
+			| ImageError::WrongType
+			| ImageError::Corrupt => FileSystemError::CorruptLayout,
+		}
+	}
+}
+
+/// what a failed `create_file_in_root` or `add_to_root` means to the caller
+fn create_error(e: FileSystemError) -> FileError {
+	match e {
+		FileSystemError::NameTooLong => FileError::InvalidName,
+		FileSystemError::NoSpace => FileError::NoSpace,
+		FileSystemError::CorruptLayout => FileError::Corrupt,
+		FileSystemError::ReadOnly => FileError::ReadOnly,
+		_ => FileError::CreationFailed,
+	}
+}
+
+/// what a failed data block allocation means for a file write
+fn allocate_error(e: FileSystemError) -> FileError {
+	match e {
+		FileSystemError::NoSpace => FileError::NoSpace,
+		_ => FileError::BlockWriteError,
+	}
+}
+
+/// sorted indices as hex ranges, `[0x0-0x3, 0x7]`
+struct Runs<'a>(&'a [u64]);
+
+impl fmt::Display for Runs<'_> {
+	fn fmt(
+		&self,
+		f: &mut fmt::Formatter,
+	) -> fmt::Result {
+		write!(f, "[")?;
+		let mut rest = self.0;
+		while let Some(&start) = rest.first() {
+			let len = rest.iter().zip(start..).take_while(|&(&idx, want)| idx == want).count();
+			if start != self.0[0] {
+				write!(f, ", ")?;
+			}
+			match len {
+				1 => write!(f, "{:#x}", start)?,
+				_ => write!(f, "{:#x}-{:#x}", start, start + len as u64 - 1)?,
+			}
+			rest = &rest[len..];
+		}
+		write!(f, "]")
+	}
 }
 
 impl<D: BlockDevice> FileSystem for SFS<D> {
@@ -447,12 +1754,8 @@ impl<D: BlockDevice> FileSystem for SFS<D> {
 		&mut self,
 		name: &str,
 	) -> Result<FileHandler, FileError> {
-		let (inode_index, _dir_block) = self.create_file_in_root(name).map_err(|e| match e {
-			FileSystemError::NameTooLong => FileError::InvalidName,
-			FileSystemError::NoSpace => FileError::NoSpace,
-			FileSystemError::CorruptLayout => FileError::Corrupt,
-			_ => FileError::CreationFailed,
-		})?;
+		self.flush_times_if_full().map_err(create_error)?;
+		let (inode_index, _dir_block) = self.create_file_in_root(name).map_err(create_error)?;
 		println!("[FS] Created file '{}' with inode #{}", name, inode_index);
 		Ok(FileHandler(inode_index as usize))
 	}
@@ -461,17 +1764,163 @@ impl<D: BlockDevice> FileSystem for SFS<D> {
 		&mut self,
 		name: &str,
 	) -> Result<(), FileError> {
-		todo!()
+		let found = self.remove_from_root(name).map_err(|e| match e {
+			FileSystemError::ReadOnly => FileError::ReadOnly,
+			FileSystemError::NameTooLong => FileError::InvalidName,
+			FileSystemError::CorruptLayout => FileError::Corrupt,
+			_ => FileError::BlockWriteError,
+		})?;
+		if !found {
+			return Err(FileError::FileNotFound);
+		}
+		println!("[FS] Deleted file '{}'", name);
+		Ok(())
 	}
 
 	fn open_file(
+		&self,
+		name: &str,
+	) -> Result<FileHandler, FileError> {
+		match self.lookup_in_root(name.as_bytes()) {
+			Ok(Some((inode_index, _slot))) => Ok(FileHandler(inode_index as usize)),
+			Ok(None) => Err(FileError::FileNotFound),
+			Err(_) => Err(FileError::BlockReadError),
+		}
+	}
+
+	fn list_file(&self) -> Result<Vec<String>, FileError> {
+		self.list_root_dir().map_err(|_| FileError::BlockReadError)
+	}
+
+	fn stat(
+		&self,
+		handle: FileHandler,
+	) -> Result<Inode, FileError> {
+		SFS::stat(self, handle)
+	}
+
+	fn read_file(
+		&self,
+		handle: FileHandler,
+		buf: &mut [u8],
+	) -> Result<usize, FileError> {
+		SFS::read_file(self, handle, buf)
+	}
+
+	fn write_file(
+		&mut self,
+		handle: FileHandler,
+		data: &[u8],
+	) -> Result<usize, FileError> {
+		SFS::write_file(self, handle, data)
+	}
+
+	fn fsync(
+		&mut self,
+		handle: FileHandler,
+	) -> Result<(), FileError> {
+		if handle.0 as u64 >= self.superblock.inode_count {
+			return Err(FileError::InvalidHandle);
+		}
+
+		// a read-only mount never wrote anything
+		if self.read_only {
+			return Ok(());
+		}
+
+		let inode_index = handle.0 as u64;
+		self.flush_inode_times(inode_index).map_err(|_| FileError::BlockWriteError)?;
+
+		// the contents, the inode and the root directory entry naming it
+		let inode = self.read_inode(inode_index).map_err(|_| FileError::BlockReadError)?;
+		let root = self.read_inode(ROOT_DIRECTORY_INODE).map_err(|_| FileError::BlockReadError)?;
+
+		let mut blocks: Vec<u64> = inode
+			.direct_pointers
+			.iter()
+			.chain(root.direct_pointers.iter())
+			.copied()
+			.filter(|&block| block != 0)
+			.collect();
+		blocks.push(self.inode_block(inode_index));
+
+		self.device.get_mut().sync_blocks(&blocks).map_err(|_| FileError::BlockWriteError)?;
+		self.device.get_mut().flush().map_err(|_| FileError::BlockWriteError)
+	}
+
+	fn defrag(
+		&mut self,
+		progress: &mut dyn FnMut(u64, u64),
+	) -> Result<DefragReport, FileError> {
+		self.defragment(progress).map_err(|e| match e {
+			FileSystemError::ReadOnly => FileError::ReadOnly,
+			FileSystemError::CorruptLayout => FileError::Corrupt,
+			_ => FileError::BlockWriteError,
+		})
+	}
+}
+
+/// Lets several tasks share one filesystem, the executor is single threaded
+impl<F: FileSystem> FileSystem for Rc<RefCell<F>> {
+	fn create_file(
+		&mut self,
+		name: &str,
+	) -> Result<FileHandler, FileError> {
+		self.borrow_mut().create_file(name)
+	}
+
+	fn delete_file(
 		&mut self,
 		name: &str,
+	) -> Result<(), FileError> {
+		self.borrow_mut().delete_file(name)
+	}
+
+	fn open_file(
+		&self,
+		name: &str,
 	) -> Result<FileHandler, FileError> {
-		todo!()
+		self.borrow().open_file(name)
 	}
 
-	fn list_file(&mut self) -> Result<Vec<String>, FileError> {
-		todo!()
+	fn read_file(
+		&self,
+		handle: FileHandler,
+		buf: &mut [u8],
+	) -> Result<usize, FileError> {
+		self.borrow().read_file(handle, buf)
+	}
+
+	fn write_file(
+		&mut self,
+		handle: FileHandler,
+		data: &[u8],
+	) -> Result<usize, FileError> {
+		self.borrow_mut().write_file(handle, data)
+	}
+
+	fn list_file(&self) -> Result<Vec<String>, FileError> {
+		self.borrow().list_file()
+	}
+
+	fn stat(
+		&self,
+		handle: FileHandler,
+	) -> Result<Inode, FileError> {
+		self.borrow().stat(handle)
+	}
+
+	fn fsync(
+		&mut self,
+		handle: FileHandler,
+	) -> Result<(), FileError> {
+		self.borrow_mut().fsync(handle)
+	}
+
+	fn defrag(
+		&mut self,
+		progress: &mut dyn FnMut(u64, u64),
+	) -> Result<DefragReport, FileError> {
+		self.borrow_mut().defrag(progress)
 	}
 }
@@ -0,0 +1,231 @@
+//! in src/fs/walk.rs
+//!
+//! depth-first traversal of a filesystem, for `ls -R`, `find` and `rm -r`
+
+use super::{
+	layout::FileType,
+	simple_fs::{FileError, FileSystem, ROOT_DIRECTORY_INODE},
+};
+use alloc::{
+	boxed::Box,
+	collections::BTreeSet,
+	format,
+	string::{String, ToString},
+	vec::{self, Vec},
+};
+
+/// What the walk found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalkEntry {
+	/// from the root, starting with `/`
+	pub path: String,
+	/// 0 for where the walk started, 1 for what's in it, and so on
+	pub depth: usize,
+	pub file_type: FileType,
+	/// in bytes, 0 for directories, SFS keeps no size for them
+	pub size: u64,
+	pub inode: u64,
+}
+
+impl WalkEntry {
+	/// the last component of the path, `/` for the root
+	pub fn name(&self) -> &str {
+		match self.path.rfind('/') {
+			Some(i) if self.path.len() > 1 => &self.path[i + 1..],
+			_ => &self.path,
+		}
+	}
+
+	pub fn is_dir(&self) -> bool {
+		self.file_type == FileType::Directory
+	}
+}
+
+/// a directory the walk is in the middle of
+struct Frame {
+	/// the directory itself, held back until its contents are done when those come first
+	dir: Option<WalkEntry>,
+	entries: vec::IntoIter<WalkEntry>,
+}
+
+/// Depth-first walk over everything below a path
+///
+/// Yields the starting point first, then each directory followed by its contents, in the order
+/// they're stored. `contents_first` turns that around for a directory and its contents, which
+/// is the order deleting wants. Nothing is read ahead of where the walk is, apart from the
+/// entries of the directories it's in.
+///
+/// SFS only has the root directory so far, so the walk sees the root and the files in it. An
+/// inode is never entered twice, for when links make the tree a graph.
+pub struct WalkDir<'a> {
+	fs: &'a mut dyn FileSystem,
+	/// where the walk starts, taken by the first `next`
+	start: Option<String>,
+	max_depth: usize,
+	contents_first: bool,
+	prune: Option<Box<dyn FnMut(&WalkEntry) -> bool + 'a>>,
+	stack: Vec<Frame>,
+	/// directories entered so far
+	visited: BTreeSet<u64>,
+}
+
+impl<'a> WalkDir<'a> {
+	/// A walk from `path`, `/` or empty for the root
+	pub fn new(
+		fs: &'a mut dyn FileSystem,
+		path: &str,
+	) -> Self {
+		WalkDir {
+			fs,
+			start: Some(path.to_string()),
+			max_depth: usize::MAX,
+			contents_first: false,
+			prune: None,
+			stack: Vec::new(),
+			visited: BTreeSet::new(),
+		}
+	}
+
+	/// nothing deeper than `depth` is yielded, 0 is just the starting point
+	pub fn max_depth(
+		mut self,
+		depth: usize,
+	) -> Self {
+		self.max_depth = depth;
+		self
+	}
+
+	/// yield each directory after its contents instead of before
+	pub fn contents_first(
+		mut self,
+		yes: bool,
+	) -> Self {
+		self.contents_first = yes;
+		self
+	}
+
+	/// Asks `prune` about each directory before going in, true skips what's in it
+	///
+	/// The directory itself is still yielded.
+	pub fn prune(
+		mut self,
+		prune: impl FnMut(&WalkEntry) -> bool + 'a,
+	) -> Self {
+		self.prune = Some(Box::new(prune));
+		self
+	}
+
+	/// the entry for `path`, which has to be the root or a file in it
+	fn resolve(
+		&mut self,
+		path: &str,
+	) -> Result<WalkEntry, FileError> {
+		let name = path.trim_matches('/');
+		if name.is_empty() {
+			return Ok(WalkEntry {
+				path: String::from("/"),
+				depth: 0,
+				file_type: FileType::Directory,
+				size: 0,
+				inode: ROOT_DIRECTORY_INODE,
+			});
+		}
+		if name.contains('/') {
+			return Err(FileError::FileNotFound);
+		}
+
+		let mut entry = self.file_entry(name)?;
+		entry.depth = 0;
+		Ok(entry)
+	}
+
+	/// the entry for the file called `name` in the root, one level down
+	fn file_entry(
+		&mut self,
+		name: &str,
+	) -> Result<WalkEntry, FileError> {
+		let handle = self.fs.open_file(name)?;
+		let inode = self.fs.stat(handle)?;
+		Ok(WalkEntry {
+			path: format!("/{}", name),
+			depth: 1,
+			file_type: inode.mode,
+			size: inode.size_in_bytes,
+			inode: handle.0 as u64,
+		})
+	}
+
+	/// what's in the directory `dir`
+	fn read_dir(
+		&mut self,
+		dir: &WalkEntry,
+	) -> Result<Vec<WalkEntry>, FileError> {
+		if dir.inode != ROOT_DIRECTORY_INODE {
+			return Err(FileError::FileNotFound);
+		}
+		let names = self.fs.list_file()?;
+		names.iter().map(|name| self.file_entry(name)).collect()
+	}
+
+	/// Goes into `entry` if it's a directory the walk may enter
+	///
+	/// Returns the entry if it's to be yielded right now.
+	fn visit(
+		&mut self,
+		entry: WalkEntry,
+	) -> Result<Option<WalkEntry>, FileError> {
+		if !entry.is_dir() || entry.depth >= self.max_depth || self.visited.contains(&entry.inode) {
+			return Ok(Some(entry));
+		}
+		if let Some(prune) = self.prune.as_mut() {
+			if prune(&entry) {
+				return Ok(Some(entry));
+			}
+		}
+
+		self.visited.insert(entry.inode);
+		let mut entries = self.read_dir(&entry)?;
+		for child in entries.iter_mut() {
+			child.depth = entry.depth + 1;
+		}
+
+		if self.contents_first {
+			self.stack.push(Frame { dir: Some(entry), entries: entries.into_iter() });
+			Ok(None)
+		} else {
+			self.stack.push(Frame { dir: None, entries: entries.into_iter() });
+			Ok(Some(entry))
+		}
+	}
+}
+
+impl Iterator for WalkDir<'_> {
+	type Item = Result<WalkEntry, FileError>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if let Some(path) = self.start.take() {
+			let visited = self.resolve(&path).and_then(|entry| self.visit(entry));
+			match visited {
+				Ok(Some(entry)) => return Some(Ok(entry)),
+				Ok(None) => {},
+				Err(e) => return Some(Err(e)),
+			}
+		}
+
+		loop {
+			let frame = self.stack.last_mut()?;
+			match frame.entries.next() {
+				Some(entry) => match self.visit(entry) {
+					Ok(Some(entry)) => return Some(Ok(entry)),
+					Ok(None) => {},
+					Err(e) => return Some(Err(e)),
+				},
+				None => {
+					if let Some(dir) = self.stack.pop().and_then(|frame| frame.dir) {
+						return Some(Ok(dir));
+					}
+				},
+			}
+		}
+	}
+}
@@ -2,20 +2,89 @@
 //
 // creates a dedicated stack for handling double faults
 
+use core::cell::UnsafeCell;
+use core::mem::{offset_of, size_of};
 use lazy_static::lazy_static;
-use x86_64::VirtAddr; // represents a virtual address in the memory
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::structures::gdt::{DescriptorFlags, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
+use x86_64::{PrivilegeLevel, VirtAddr}; // VirtAddr represents a virtual address in the memory
 
 /// indicates which entry in the IST array will be used as a dedicated stack for handling double faults
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+/// the page fault handler gets its own stack too, a fault on a stack's guard page can't push
+/// anything onto that stack
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+
+/// one bit per I/O port, 64Ki ports
+const IOPB_BYTES: usize = 0x10000 / 8;
+
+/// COM1's data register, what a user mode debug process gets to write to
+const COM1_DATA: u16 = 0x3F8;
+
+/// The I/O permission bitmap, a set bit denies the port to user mode
+///
+/// The CPU reads two bytes at a time, so a 0xFF byte has to follow the last real one.
+#[repr(C)]
+pub struct IoPermissionBitmap {
+	bits: [u8; IOPB_BYTES],
+	terminator: u8,
+}
+
+impl IoPermissionBitmap {
+	/// every port denied
+	pub const fn new() -> Self {
+		IoPermissionBitmap { bits: [0xFF; IOPB_BYTES], terminator: 0xFF }
+	}
+
+	/// true if user mode may access `port`
+	pub fn is_allowed(
+		&self,
+		port: u16,
+	) -> bool {
+		self.bits[port as usize / 8] & (1 << (port % 8)) == 0
+	}
+}
+
+/// The TSS with its I/O permission bitmap right behind it, the segment limit covers both
+#[repr(C)]
+pub struct TssWithIopb {
+	pub tss: TaskStateSegment,
+	pub iopb: IoPermissionBitmap,
+}
+
+// iomap_base is relative to the start of the TSS
+sa::const_assert_eq!(offset_of!(TssWithIopb, iopb), size_of::<TaskStateSegment>());
+
+/// Lets user mode access `port` directly, or takes that away again
+///
+/// Ring 3 code with a port allowed here can talk to the device behind it without the kernel
+/// noticing: it can reprogram it, read whatever passes through it and, for a DMA capable device,
+/// make it read or write any physical memory. Only open ports whose device can't do harm, and
+/// only for processes that are trusted with that device anyway. The TSS is shared by everything
+/// running on the CPU, so a port opened here is open for every user mode process.
+pub fn set_iopb_port(
+	iopb: &mut IoPermissionBitmap,
+	port: u16,
+	allow: bool,
+) {
+	let byte = &mut iopb.bits[port as usize / 8];
+	let bit = 1 << (port % 8);
+	if allow {
+		*byte &= !bit;
+	} else {
+		*byte |= bit;
+	}
+}
+
 lazy_static! {
 	/// A TSS is a data structure used by x86_64 CPUs to store information about a task’s state. <br>
 	/// One of its key roles is to hold an Interrupt Stack Table (IST), which is an array of stack pointers. <br>
 	/// These pointers are used to switch to known-good stacks when handling critical exceptions—like double faults.
 	///
 	/// The TSS in-turn is stored within the GDT
-	static ref TSS: TaskStateSegment = {
+	static ref TSS: TssWithIopb = {
 
 		let mut tss = TaskStateSegment::new();
 
@@ -38,51 +107,205 @@ lazy_static! {
 			stack_end // write this pointer for the double fault handler
 		};
 
-		tss
+		tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
+			const STACK_SIZE: usize = 4096 * 5;
+			static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+			VirtAddr::from_ptr(&raw const STACK) + STACK_SIZE
+		};
+
+		// the bitmap starts right after the TSS
+		tss.iomap_base = size_of::<TaskStateSegment>() as u16;
+
+		let mut iopb = IoPermissionBitmap::new();
+		// Debug output from user mode. Only the data register: the process can push bytes out but
+		// can't touch the baud rate or the interrupt enables, and it can't poll the line status
+		// either, so bytes sent faster than the UART drains them are lost.
+		set_iopb_port(&mut iopb, COM1_DATA, true);
+
+		TssWithIopb { tss, iopb }
 	};
 }
 
-use x86_64::structures::gdt::SegmentSelector;
+/// The 64-bit kernel code segment as a raw GDT entry
+///
+/// What `Descriptor::kernel_code_segment` holds, but as a number a const GDT can take.
+pub const fn kernel_code_segment() -> u64 {
+	DescriptorFlags::KERNEL_CODE64.bits()
+}
 
-#[derive(Debug)]
-struct Selectors {
-	code_selector: SegmentSelector,
-	tss_selector: SegmentSelector,
+/// Builds the two raw entries of an available 64-bit TSS at `tss_base`
+///
+/// `tss_limit` is the offset of the TSS's last byte. `Descriptor::tss_segment` sets it to the
+/// TaskStateSegment's own, which would leave the I/O permission bitmap behind it out and deny every
+/// port.
+pub const fn tss_segment_raw(
+	tss_base: u64,
+	tss_limit: u32,
+) -> [u64; 2] {
+	// available 64-bit TSS
+	const TYPE_TSS_AVAILABLE: u64 = 0b1001;
+
+	let limit = tss_limit as u64;
+	let low = DescriptorFlags::PRESENT.bits()
+		| TYPE_TSS_AVAILABLE << 40
+		| (limit & 0xFFFF)
+		| ((limit >> 16) & 0xF) << 48
+		| (tss_base & 0xFF_FFFF) << 16
+		| ((tss_base >> 24) & 0xFF) << 56;
+	let high = tss_base >> 32;
+
+	[low, high]
 }
 
-use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
+/// Puts raw GDT entries together in a const, so the GDT can be a plain `static`
+///
+/// Slot 0 is the null descriptor every GDT starts with, `add_entry` fills the slots after it in
+/// order. Slots nobody filled stay null.
+pub struct GdtBuilder<const N: usize> {
+	entries: [u64; N],
+	count: usize,
+}
 
-lazy_static! {
-	// data structure that defines the memory segments.
-	// <br> each entry is called a Descriptor.
-	// <br> This thing holds the TSS -- for your usage
-	static ref GDT: (GlobalDescriptorTable, Selectors) = {
-		let mut gdt = GlobalDescriptorTable::new();
-
-		let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-		// check out what the kernle_code_segment entails .. it's some useful stuff
-
-		let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-		// add the TSS you created to the newly created GDT
-
-		(gdt, Selectors{
-			code_selector,
-			tss_selector
-		})
-	};
+impl<const N: usize> GdtBuilder<N> {
+	pub const fn new() -> Self {
+		GdtBuilder { entries: [0; N], count: 1 }
+	}
+
+	/// Appends `entry`, a system segment like the TSS takes two
+	///
+	/// Running out of slots fails the build, in a const.
+	pub const fn add_entry(
+		mut self,
+		entry: u64,
+	) -> Self {
+		assert!(self.count < N, "no slot left in the GDT");
+		self.entries[self.count] = entry;
+		self.count += 1;
+		self
+	}
+
+	pub const fn build(self) -> [u64; N] {
+		self.entries
+	}
 }
 
+/// the GDT slot of the kernel code segment
+const KERNEL_CODE_INDEX: u16 = 1;
+
+/// the first of the TSS's two GDT slots
+const TSS_INDEX: u16 = 2;
+
+const CODE_SELECTOR: SegmentSelector =
+	SegmentSelector::new(KERNEL_CODE_INDEX, PrivilegeLevel::Ring0);
+const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(TSS_INDEX, PrivilegeLevel::Ring0);
+
+/// the null descriptor, the kernel code segment and the TSS's two slots
+const GDT_LEN: usize = 4;
+
+/// The raw entries of the live GDT
+///
+/// Only `init` writes them, before the `lgdt` that hands them to the CPU. After that the CPU sets
+/// the TSS's busy bit in there itself.
+#[repr(C, align(8))]
+struct Gdt(UnsafeCell<[u64; GDT_LEN]>);
+
+// see above, nothing writes it while anyone else could be reading
+unsafe impl Sync for Gdt {}
+
+impl Gdt {
+	/// a copy of the entries as they are now
+	fn entries(&self) -> [u64; GDT_LEN] {
+		unsafe { *self.0.get() }
+	}
+}
+
+/// The GDT: the null descriptor, the kernel code segment and the TSS
+///
+/// The TSS's slots stay null until `init`. Its address is only known once the kernel is linked,
+/// and a const can't turn a pointer into a number.
+static GDT: Gdt = Gdt(UnsafeCell::new(GdtBuilder::new().add_entry(kernel_code_segment()).build()));
+
 pub fn init() {
 	use x86_64::instructions::segmentation::{CS, Segment};
-	use x86_64::instructions::tables::load_tss;
+	use x86_64::instructions::tables::{lgdt, load_tss};
 
-	GDT.0.load(); // loads the GDT in 'static form
+	let tss = &*TSS as *const TssWithIopb as u64;
+	let tss_limit = (size_of::<TssWithIopb>() - 1) as u32;
 
 	unsafe {
+		// only init writes it, and the CPU doesn't look at it before the lgdt below
+		let gdt = &mut *GDT.0.get();
+		gdt[TSS_INDEX as usize..][..2].copy_from_slice(&tss_segment_raw(tss, tss_limit));
+
+		lgdt(&DescriptorTablePointer {
+			limit: (size_of::<[u64; GDT_LEN]>() - 1) as u16,
+			base: VirtAddr::from_ptr(gdt.as_ptr()),
+		});
+
 		// the old code segment register might be pointing to a different GDT
-		CS::set_reg(GDT.1.code_selector); // reload the code segment register
+		CS::set_reg(CODE_SELECTOR); // reload the code segment register
 
 		// tell the CPU to use this TSS .. we loaded a GDT that contains a TSS selector
-		load_tss(GDT.1.tss_selector); // load the TSS
+		load_tss(TSS_SELECTOR); // load the TSS
 	}
 }
+
+#[test_case]
+fn test_iopb_port_bits() {
+	static mut IOPB: IoPermissionBitmap = IoPermissionBitmap::new();
+	// only this test touches it
+	let iopb = unsafe { &mut *(&raw mut IOPB) };
+
+	assert!(!iopb.is_allowed(0x3F8));
+	set_iopb_port(iopb, 0x3F8, true);
+	assert!(iopb.is_allowed(0x3F8));
+	assert!(!iopb.is_allowed(0x3F9) && !iopb.is_allowed(0x3F7));
+	// 0x3F8 is bit 0 of byte 0x7F
+	assert_eq!(iopb.bits[0x7F], 0xFE);
+
+	set_iopb_port(iopb, 0xFFFF, true);
+	assert_eq!(iopb.bits[IOPB_BYTES - 1], 0x7F);
+	assert_eq!(iopb.terminator, 0xFF);
+
+	set_iopb_port(iopb, 0x3F8, false);
+	assert!(!iopb.is_allowed(0x3F8));
+}
+
+#[test_case]
+fn test_live_tss_allows_only_com1_data() {
+	assert_eq!(TSS.tss.iomap_base as usize, size_of::<TaskStateSegment>());
+	assert!(TSS.iopb.is_allowed(COM1_DATA));
+	assert!(!TSS.iopb.is_allowed(COM1_DATA + 1));
+	assert!(!TSS.iopb.is_allowed(0xF4));
+
+	// the live descriptor's limit and base have to cover the bitmap
+	let gdt = GDT.entries();
+	let (low, high) = (gdt[TSS_INDEX as usize], gdt[TSS_INDEX as usize + 1]);
+	let limit = (low & 0xFFFF) | ((low >> 48) & 0xF) << 16;
+	let base = ((low >> 16) & 0xFF_FFFF) | ((low >> 56) & 0xFF) << 24 | high << 32;
+	assert_eq!(limit as usize, size_of::<TssWithIopb>() - 1);
+	assert_eq!(base, &*TSS as *const TssWithIopb as u64);
+}
+
+#[test_case]
+fn test_gdt_builder_matches_add_entry() {
+	use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
+
+	const BUILT: [u64; 5] = GdtBuilder::new()
+		.add_entry(kernel_code_segment())
+		.add_entry(DescriptorFlags::KERNEL_DATA.bits())
+		.add_entry(tss_segment_raw(0x1234_5678_9ABC_DEF0, 0x2_0067)[0])
+		.add_entry(tss_segment_raw(0x1234_5678_9ABC_DEF0, 0x2_0067)[1])
+		.build();
+
+	let mut gdt = GlobalDescriptorTable::new();
+	gdt.add_entry(Descriptor::kernel_code_segment());
+	gdt.add_entry(Descriptor::kernel_data_segment());
+	let [low, high] = tss_segment_raw(0x1234_5678_9ABC_DEF0, 0x2_0067);
+	gdt.add_entry(Descriptor::SystemSegment(low, high));
+
+	assert_eq!(gdt.as_raw_slice(), &BUILT[..]);
+	// the TSS slots of the live GDT are filled in by now, the rest is what the const says
+	assert_eq!(GDT.entries()[..TSS_INDEX as usize], [0, kernel_code_segment()]);
+}
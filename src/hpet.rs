@@ -0,0 +1,272 @@
+// in src/hpet.rs
+//
+// the High Precision Event Timer, a free running counter and the timer interrupt in place of the PIT
+
+use crate::{interrupts, task::timer::TICKS_PER_SECOND};
+use core::ptr::{read_volatile, write_volatile};
+use x86_64::PhysAddr;
+
+/// general capabilities and ID
+const GCAP_ID: u64 = 0x000;
+/// general configuration
+const GEN_CONF: u64 = 0x010;
+/// the main counter
+const MAIN_CNT: u64 = 0x0F0;
+/// configuration and comparator of timer n are at these plus 0x20 * n
+const TIMER_CONF: u64 = 0x100;
+const TIMER_COMPARATOR: u64 = 0x108;
+
+// GCAP_ID bits
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+const LEG_RT_CAP: u64 = 1 << 15;
+
+// GEN_CONF bits
+const ENABLE_CNF: u64 = 1 << 0;
+/// timer 0 goes to IRQ0 and timer 1 to IRQ8, the PIT and RTC are cut off
+const LEG_RT_CNF: u64 = 1 << 1;
+
+// timer configuration bits
+/// set means level triggered, the PIC wants edges
+const TN_INT_TYPE_CNF: u64 = 1 << 1;
+const TN_INT_ENB_CNF: u64 = 1 << 2;
+const TN_TYPE_CNF: u64 = 1 << 3;
+const TN_PER_INT_CAP: u64 = 1 << 4;
+/// the next comparator write sets the period, not just the next deadline
+const TN_VAL_SET_CNF: u64 = 1 << 6;
+
+/// femtoseconds in a second
+const FS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+/// the spec caps the counter period at 100 ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+/// how often comparator 0 interrupts
+pub const INTERRUPT_INTERVAL_FS: u64 = 1_000_000_000_000; // 1 ms
+
+/// What `init_hpet` found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HpetInfo {
+	/// where the registers are
+	pub base: PhysAddr,
+	/// femtoseconds per counter increment
+	pub period_fs: u64,
+	/// how many comparators there are
+	pub timers: u8,
+	pub vendor_id: u16,
+}
+
+impl HpetInfo {
+	/// counter increments per second
+	pub fn frequency(&self) -> u64 {
+		FS_PER_SECOND / self.period_fs
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+	/// the table isn't an HPET table or its checksum is off
+	BadTable,
+	/// the registers are supposed to be in I/O space, only memory is supported
+	NotMemoryMapped,
+	/// the capabilities register reports a period of 0 or over 100 ns
+	BadPeriod(u64),
+	/// the main counter is 32 bits and would wrap every few minutes
+	Counter32Bit,
+	/// timer 0 can't route to IRQ0, and there's no IOAPIC driver to route it anywhere else
+	NoLegacyRoute,
+	/// timer 0 can't fire periodically
+	NoPeriodicMode,
+	AlreadyInitialized,
+}
+
+/// the HPET once it drives the timer interrupt
+struct Hpet {
+	/// the registers, through the physical memory mapping
+	registers: u64,
+	/// counter increments per tick of `interrupts::ticks`
+	counts_per_tick: u64,
+	/// the tick count when the counter started at 0
+	base_ticks: u64,
+}
+
+static HPET: spin::Once<Hpet> = spin::Once::new();
+
+fn read_register(
+	registers: u64,
+	offset: u64,
+) -> u64 {
+	unsafe { read_volatile((registers + offset) as *const u64) }
+}
+
+fn write_register(
+	registers: u64,
+	offset: u64,
+	value: u64,
+) {
+	unsafe { write_volatile((registers + offset) as *mut u64, value) }
+}
+
+/// where physical memory at `addr` shows up, see `virtio::physical_memory_offset`
+fn phys_to_virt(addr: u64) -> u64 {
+	addr + crate::virtio::physical_memory_offset()
+}
+
+/// reads `len` bytes of physical memory at `addr`
+///
+/// Unsafe because the memory has to be there and mapped, the ACPI tables are.
+unsafe fn phys_bytes(
+	addr: u64,
+	len: usize,
+) -> &'static [u8] {
+	unsafe { core::slice::from_raw_parts(phys_to_virt(addr) as *const u8, len) }
+}
+
+/// ACPI wants the bytes of a table to sum up to 0
+fn checksum_ok(bytes: &[u8]) -> bool {
+	bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+fn u32_at(
+	bytes: &[u8],
+	offset: usize,
+) -> u32 {
+	u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn u64_at(
+	bytes: &[u8],
+	offset: usize,
+) -> u64 {
+	u32_at(bytes, offset) as u64 | (u32_at(bytes, offset + 4) as u64) << 32
+}
+
+/// Looks for the HPET table through the RSDP in the BIOS area, None if there's no HPET
+///
+/// Goes through the RSDT, which every ACPI revision has, the tables are all below 4 GiB anyway.
+/// Needs `virtio::set_physical_memory_offset` to have run.
+pub fn find_table() -> Option<PhysAddr> {
+	// the RSDP sits on a 16 byte boundary in the first KiB of the EBDA or in the BIOS ROM
+	let ebda = unsafe { phys_bytes(0x40E, 2) };
+	let ebda = (u16::from_le_bytes([ebda[0], ebda[1]]) as u64) << 4;
+	let rsdp = (ebda..ebda + 1024)
+		.step_by(16)
+		.chain((0xE0000..0x100000).step_by(16))
+		.map(|addr| unsafe { phys_bytes(addr, 20) })
+		.find(|rsdp| &rsdp[..8] == b"RSD PTR " && checksum_ok(rsdp))?;
+
+	let rsdt = u32_at(rsdp, 16) as u64;
+	let len = u32_at(unsafe { phys_bytes(rsdt, 8) }, 4) as usize;
+	let rsdt = unsafe { phys_bytes(rsdt, len) };
+	if len < 36 || &rsdt[..4] != b"RSDT" || !checksum_ok(rsdt) {
+		return None;
+	}
+
+	rsdt[36..]
+		.chunks_exact(4)
+		.map(|entry| u32_at(entry, 0) as u64)
+		.find(|&table| unsafe { phys_bytes(table, 4) } == b"HPET")
+		.map(PhysAddr::new)
+}
+
+/// Sets the HPET up from its ACPI table at `acpi_base` and makes it drive the timer interrupt
+///
+/// Comparator 0 fires every millisecond. There is no IOAPIC driver yet, so it goes to IRQ0
+/// through the legacy replacement route, which cuts the PIT off. `interrupts::ticks` still counts
+/// `TICKS_PER_SECOND` ticks a second, the timer interrupt asks the counter how many went by.
+/// Sleepers get woken every millisecond.
+///
+/// Call it once, after `virtio::set_physical_memory_offset`. Nothing is changed when it fails,
+/// the PIT keeps going.
+pub fn init_hpet(acpi_base: PhysAddr) -> Result<HpetInfo, HpetError> {
+	if HPET.r#try().is_some() {
+		return Err(HpetError::AlreadyInitialized);
+	}
+
+	// the standard header, then the event timer block ID and the address of the registers
+	let table = unsafe { phys_bytes(acpi_base.as_u64(), 56) };
+	let len = u32_at(table, 4) as usize;
+	if &table[..4] != b"HPET" || len < 56 {
+		return Err(HpetError::BadTable);
+	}
+	if !checksum_ok(unsafe { phys_bytes(acpi_base.as_u64(), len) }) {
+		return Err(HpetError::BadTable);
+	}
+
+	// a generic address structure at offset 40, address space 0 is memory
+	if table[40] != 0 {
+		return Err(HpetError::NotMemoryMapped);
+	}
+	let base = PhysAddr::new(u64_at(table, 44));
+	let registers = phys_to_virt(base.as_u64());
+
+	let capabilities = read_register(registers, GCAP_ID);
+	let period_fs = capabilities >> 32;
+	if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+		return Err(HpetError::BadPeriod(period_fs));
+	}
+	if capabilities & COUNT_SIZE_CAP == 0 {
+		return Err(HpetError::Counter32Bit);
+	}
+	if capabilities & LEG_RT_CAP == 0 {
+		return Err(HpetError::NoLegacyRoute);
+	}
+	let timer0 = read_register(registers, TIMER_CONF);
+	if timer0 & TN_PER_INT_CAP == 0 {
+		return Err(HpetError::NoPeriodicMode);
+	}
+
+	let info = HpetInfo {
+		base,
+		period_fs,
+		// the field holds the number of the last timer
+		timers: ((capabilities >> 8) & 0x1F) as u8 + 1,
+		vendor_id: (capabilities >> 16) as u16,
+	};
+
+	// stopped while it's set up, from 0 so the ticks line up with base_ticks
+	let config = read_register(registers, GEN_CONF);
+	write_register(registers, GEN_CONF, config & !(ENABLE_CNF | LEG_RT_CNF));
+	write_register(registers, MAIN_CNT, 0);
+
+	// periodic and edge triggered, the first comparator write is the first deadline and the
+	// second one the period
+	let interval = INTERRUPT_INTERVAL_FS / period_fs;
+	write_register(
+		registers,
+		TIMER_CONF,
+		(timer0 & !TN_INT_TYPE_CNF) | TN_INT_ENB_CNF | TN_TYPE_CNF | TN_VAL_SET_CNF,
+	);
+	write_register(registers, TIMER_COMPARATOR, interval);
+	write_register(registers, TIMER_COMPARATOR, interval);
+
+	// the timer interrupt starts asking the counter from here on, it stands at 0 until enabled
+	HPET.call_once(|| Hpet {
+		registers,
+		counts_per_tick: FS_PER_SECOND / TICKS_PER_SECOND / period_fs,
+		base_ticks: interrupts::ticks(),
+	});
+
+	write_register(registers, GEN_CONF, config | ENABLE_CNF | LEG_RT_CNF);
+	Ok(info)
+}
+
+/// The main counter, one increment every `HpetInfo::period_fs` femtoseconds
+///
+/// 0 until `init_hpet` went through.
+pub fn hpet_read_counter() -> u64 {
+	HPET.r#try().map_or(0, |hpet| read_register(hpet.registers, MAIN_CNT))
+}
+
+/// counter increments per tick, None while the PIT drives the ticks
+pub fn counts_per_tick() -> Option<u64> {
+	HPET.r#try().map(|hpet| hpet.counts_per_tick)
+}
+
+/// How many ticks `interrupts::ticks` should be at by now, None without the HPET
+///
+/// For the timer interrupt, which comes every millisecond with the HPET and not once a tick.
+pub(crate) fn tick_count() -> Option<u64> {
+	HPET.r#try().map(|hpet| {
+		hpet.base_ticks + read_register(hpet.registers, MAIN_CNT) / hpet.counts_per_tick
+	})
+}
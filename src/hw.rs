@@ -0,0 +1,5 @@
+// in src/hw.rs
+//
+// bookkeeping for hardware resources shared between drivers
+
+pub mod ports;
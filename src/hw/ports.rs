@@ -0,0 +1,219 @@
+// in src/hw/ports.rs
+//
+// I/O port ranges handed out to named owners, so two drivers can't drive the same port
+
+use core::{fmt, ops::RangeInclusive};
+use spin::Mutex;
+use x86_64::instructions::port::{Port, PortRead, PortWrite};
+
+/// how many ranges can be claimed at once, there are only a handful of drivers
+const MAX_CLAIMS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Claim {
+	first: u16,
+	last: u16,
+	owner: &'static str,
+}
+
+static CLAIMS: Mutex<[Option<Claim>; MAX_CLAIMS]> = Mutex::new([None; MAX_CLAIMS]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClaimError {
+	/// part of the range already belongs to `owner`
+	Overlap { owner: &'static str, first: u16, last: u16 },
+	/// the range has no ports in it
+	Empty,
+	/// all `MAX_CLAIMS` slots are taken
+	TableFull,
+}
+
+/// Whether the kernel may touch `port` through a bare `Port`, without claiming it
+///
+/// The PICs, the PIT, the PS/2 controller, COM1, PCI config space, isa-debug-exit and the ACPI
+/// shutdown ports of QEMU, Bochs and VirtualBox. `port_io!` checks against this at compile time,
+/// everything else goes through `claim`.
+pub const fn is_kernel_safe_port(port: u16) -> bool {
+	matches!(
+		port,
+		0x20 | 0x21
+			| 0x40..=0x43
+			| 0x60
+			| 0x64
+			| 0xA0
+			| 0xA1
+			| 0xF4
+			| 0x3F8..=0x3FF
+			| 0xCF8
+			| 0xCFC
+			| 0x604
+			| 0xB004
+			| 0x4004
+	)
+}
+
+/// A `Port` for a port number known at compile time, which has to pass `is_kernel_safe_port`
+///
+/// A typo in the number fails the build instead of poking some device. The value type can be
+/// given first, `port_io!(u8, COM1 + 5)`, otherwise it's inferred.
+#[macro_export]
+macro_rules! port_io {
+	($ty:ty, $port:expr) => {{
+		const PORT: u16 = $port;
+		const _: () = assert!(
+			$crate::hw::ports::is_kernel_safe_port(PORT),
+			"port_io!: port is not on the allowlist in hw::ports::is_kernel_safe_port"
+		);
+		::x86_64::instructions::port::Port::<$ty>::new(PORT)
+	}};
+	($port:expr) => {
+		$crate::port_io!(_, $port)
+	};
+}
+
+/// Claims the ports in `range` for `owner`
+///
+/// Fails if any of them is claimed already, the conflict is logged since it means two drivers
+/// are fighting over the same hardware.
+pub fn claim(
+	range: RangeInclusive<u16>,
+	owner: &'static str,
+) -> Result<ClaimedPort, ClaimError> {
+	let (first, last) = (*range.start(), *range.end());
+	if first > last {
+		return Err(ClaimError::Empty);
+	}
+
+	let mut claims = CLAIMS.lock();
+
+	let conflict = claims.iter().flatten().find(|c| c.first <= last && first <= c.last);
+	if let Some(existing) = conflict {
+		// no lock in early_println, this can run inside SERIAL1's own initialization
+		crate::early_println!(
+			"[ERROR] {} wants ports {:#06x}-{:#06x}, but {} owns {:#06x}-{:#06x}",
+			owner,
+			first,
+			last,
+			existing.owner,
+			existing.first,
+			existing.last
+		);
+		return Err(ClaimError::Overlap {
+			owner: existing.owner,
+			first: existing.first,
+			last: existing.last,
+		});
+	}
+
+	let slot = claims.iter().position(Option::is_none).ok_or(ClaimError::TableFull)?;
+	claims[slot] = Some(Claim { first, last, owner });
+
+	Ok(ClaimedPort { slot, first, last })
+}
+
+/// Writes the claimed ranges sorted by port, one `first-last : owner` per line
+pub fn report<W: fmt::Write>(out: &mut W) -> fmt::Result {
+	// sort a copy, `out` may be SERIAL1 and its first use claims ports itself
+	let mut claims = *CLAIMS.lock();
+	claims.sort_unstable_by_key(|c| c.map_or((1, 0), |c| (0, c.first)));
+
+	for claim in claims.iter().flatten() {
+		writeln!(out, "{:04x}-{:04x} : {}", claim.first, claim.last, claim.owner)?;
+	}
+	Ok(())
+}
+
+/// A claimed port range, the only way a converted driver touches its ports
+///
+/// Dropping it gives the range back. Drivers that stay loaded keep it in a static.
+#[derive(Debug)]
+pub struct ClaimedPort {
+	slot: usize,
+	first: u16,
+	last: u16,
+}
+
+impl ClaimedPort {
+	pub fn range(&self) -> RangeInclusive<u16> {
+		self.first..=self.last
+	}
+
+	fn check(
+		&self,
+		port: u16,
+	) {
+		assert!(
+			self.range().contains(&port),
+			"port {:#06x} is outside the claimed range {:#06x}-{:#06x}",
+			port,
+			self.first,
+			self.last
+		);
+	}
+
+	/// Reads `port`, which has to be inside the claim
+	///
+	/// Unsafe for the same reason `Port::read` is, reads can have side effects on the device.
+	pub unsafe fn read<T: PortRead>(
+		&self,
+		port: u16,
+	) -> T {
+		self.check(port);
+		unsafe { Port::<T>::new(port).read() }
+	}
+
+	/// Writes `value` to `port`, which has to be inside the claim
+	pub unsafe fn write<T: PortWrite>(
+		&self,
+		port: u16,
+		value: T,
+	) {
+		self.check(port);
+		unsafe { Port::<T>::new(port).write(value) }
+	}
+}
+
+impl Drop for ClaimedPort {
+	fn drop(&mut self) {
+		CLAIMS.lock()[self.slot] = None;
+	}
+}
+
+#[test_case]
+fn test_overlapping_claim_rejected() {
+	let first = claim(0x0500..=0x050F, "test-a").expect("0x500 is free");
+
+	assert_eq!(
+		claim(0x0508..=0x0510, "test-b").err(),
+		Some(ClaimError::Overlap { owner: "test-a", first: 0x0500, last: 0x050F })
+	);
+	assert!(claim(0x04F0..=0x0500, "test-b").is_err());
+	assert_eq!(claim(0x0510..=0x0500, "test-b").err(), Some(ClaimError::Empty));
+
+	// right next to it is fine
+	let second = claim(0x0510..=0x0517, "test-b").expect("0x510 is free");
+	assert_eq!(second.range(), 0x0510..=0x0517);
+
+	// dropping gives the range back
+	drop(first);
+	let again = claim(0x0500..=0x050F, "test-c").expect("0x500 was released");
+	drop(again);
+	drop(second);
+}
+
+#[test_case]
+fn test_kernel_safe_ports() {
+	for &port in
+		[0x20, 0x21, 0x40, 0x43, 0x60, 0x64, 0xA0, 0xA1, 0xF4, 0x3F8, 0x3FD, 0x3FF, 0xCF8].iter()
+	{
+		assert!(is_kernel_safe_port(port), "{:#x}", port);
+	}
+	// the neighbours of the allowed ones, and ports drivers have to claim
+	for &port in [0x22, 0x44, 0x61, 0xF5, 0x3F7, 0x400, 0xCF9, 0xCFD, 0x1F0, 0x3D4].iter() {
+		assert!(!is_kernel_safe_port(port), "{:#x}", port);
+	}
+
+	// checked at compile time, a port off the list doesn't build
+	let port: x86_64::instructions::port::Port<u8> = crate::port_io!(0x3F8 + 5);
+	let _ = port;
+}
@@ -3,7 +3,9 @@
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 // you can check their docs for detailed stuff
 use crate::gdt;
-use crate::{print, println};
+use crate::hw::ports::{self, ClaimedPort};
+use crate::{port_io, print, println};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
 // static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
 // the CPU will access this table on every interrupt so it needs to live until we
@@ -33,7 +35,19 @@ lazy_static! {
 
 		idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
 
-		idt.page_fault.set_handler_fn(page_fault_handler);
+		idt[InterruptIndex::SerialCom1.as_usize()].set_handler_fn(com1_interrupt_handler);
+
+		// every line the kernel doesn't use itself dispatches to whoever registered for it
+		for &(irq, handler) in SHARED_IRQ_HANDLERS.iter() {
+			idt[usize::from(PIC_1_OFFSET + irq)].set_handler_fn(handler);
+		}
+
+		unsafe {
+			// a stack overflow faults with RSP on the guard page, the frame has to go elsewhere
+			idt.page_fault
+				.set_handler_fn(page_fault_handler)
+				.set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+		}
 
 		idt
 	};
@@ -43,7 +57,23 @@ pub fn init_idt() {
 	IDT.load(); // lidt - Load Interrupt Descriptor Table
 }
 
+/// number of breakpoint exceptions handled, lets the init self-check see the handler ran
+static BREAKPOINTS: AtomicU64 = AtomicU64::new(0);
+
+/// returns how many breakpoint exceptions were handled so far
+pub fn breakpoint_count() -> u64 {
+	BREAKPOINTS.load(Ordering::Relaxed)
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+	BREAKPOINTS.fetch_add(1, Ordering::Relaxed);
+
+	// a failed kernel_assert! leaves its context behind before trapping
+	if let Some(context) = crate::kassert::take_assert_context() {
+		println!("ASSERTION BREAKPOINT: {}\n {:#?}", context, stack_frame);
+		return;
+	}
+
 	println!("EXCEPTION: BREAKPOINT\n {:#?}", stack_frame);
 }
 
@@ -56,8 +86,25 @@ extern "x86-interrupt" fn double_fault_handler(
 	// error code for the double fault is always 0 -- so no need to print it ...
 	// display the exception stack frame
 	// panic!("EXCEPTION: DOUBLE_FAULT\n=== EXCEPTION_STACK_FRAME ===\n{:#?}", stack_frame);
+	crate::serial::enter_panic();
+	if REPORTING_FAULT.swap(true, Ordering::SeqCst) {
+		// most likely the page fault report below faulted again
+		report_without_fmt("DOUBLE FAULT", None, &stack_frame);
+		if cfg!(feature = "unattended") {
+			let _ = crate::exit::try_exit_qemu(crate::QemuExitCode::DoubleFault);
+		}
+		loop {}
+	}
 	println!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 
+	if cfg!(feature = "unattended") {
+		crate::exit::report_failure(
+			crate::QemuExitCode::DoubleFault,
+			&"double fault",
+			stack_frame.instruction_pointer.as_u64(),
+		);
+	}
+
 	loop {}
 }
 
@@ -66,6 +113,55 @@ fn test_breakpoint_exception() {
 	x86_64::instructions::interrupts::int3();
 }
 
+#[test_case]
+fn test_heartbeat_frequency() {
+	use x86_64::instructions::hlt;
+
+	const INTERVAL: u64 = 2;
+	const WAIT: u64 = 10;
+
+	let wait_ticks = |count: u64| {
+		let target = ticks() + count;
+		while ticks() < target {
+			hlt();
+		}
+	};
+
+	set_heartbeat_interval(INTERVAL);
+	let before = heartbeat_count();
+	let start = ticks();
+
+	set_heartbeat(true);
+	wait_ticks(WAIT);
+	set_heartbeat(false);
+
+	let elapsed = ticks() - start;
+	let dots = heartbeat_count() - before;
+	assert!(dots + 1 >= elapsed / INTERVAL && dots <= elapsed / INTERVAL + 1);
+
+	// off again, no more dots
+	let after = heartbeat_count();
+	wait_ticks(WAIT);
+	assert_eq!(heartbeat_count(), after);
+
+	set_heartbeat_interval(DEFAULT_HEARTBEAT_TICKS);
+	crate::println!();
+}
+
+#[test_case]
+fn test_every_n_ticks() {
+	use x86_64::instructions::interrupts;
+
+	// nothing ticks in between with interrupts off
+	interrupts::without_interrupts(|| {
+		let now = ticks();
+		assert!(every_n_ticks(1));
+		assert!(every_n_ticks(0));
+		assert_eq!(every_n_ticks(7), now % 7 == 0);
+		assert_eq!(every_n_ticks(now + 1), now == 0);
+	});
+}
+
 // there is an abstraction for the PIC in this crate
 use pic8259::ChainedPics; // a pair of chained PICs .. check source in doc
 use spin;
@@ -84,6 +180,8 @@ pub static PICS: spin::Mutex<ChainedPics> =
 pub enum InterruptIndex {
 	Timer = PIC_1_OFFSET,
 	Keyboard, // defaults to the pervious value + 1 = 33 .. so interrupt 33
+	/// IRQ 4, COM1's UART
+	SerialCom1 = PIC_1_OFFSET + 4,
 }
 
 impl InterruptIndex {
@@ -96,22 +194,116 @@ impl InterruptIndex {
 	}
 }
 
+/// hardware interrupt handlers running right now, a nested one counts twice
+static IRQ_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// true while a hardware interrupt handler runs, the heap charges what it allocates to `irq`
+pub fn in_interrupt() -> bool {
+	IRQ_DEPTH.load(Ordering::Relaxed) != 0
+}
+
+/// Counts a hardware interrupt handler as running until it's dropped
+///
+/// Every IRQ handler makes one first thing. Exception handlers don't, they run on behalf of the
+/// code that faulted.
+struct IrqContext;
+
+impl IrqContext {
+	fn enter() -> Self {
+		IRQ_DEPTH.fetch_add(1, Ordering::Relaxed);
+		IrqContext
+	}
+}
+
+impl Drop for IrqContext {
+	fn drop(&mut self) {
+		IRQ_DEPTH.fetch_sub(1, Ordering::Relaxed);
+	}
+}
+
+/// number of timer interrupts since the PIC was initialized
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// returns the number of timer ticks so far
+pub fn ticks() -> u64 {
+	TICKS.load(Ordering::Relaxed)
+}
+
+/// Whether the tick that just happened is one of every `n`th, 0 counts as 1
+///
+/// For rate limiting what the timer handler does: printing or redrawing on every interrupt is
+/// wasted work and flickers.
+pub fn every_n_ticks(n: u64) -> bool {
+	ticks() % n.max(1) == 0
+}
+
+/// ticks between two heartbeat dots unless told otherwise, about one second
+pub const DEFAULT_HEARTBEAT_TICKS: u64 = crate::task::timer::TICKS_PER_SECOND;
+
+/// whether the timer prints a heartbeat dot, off so it doesn't get in the way of the output
+static HEARTBEAT: AtomicBool = AtomicBool::new(false);
+static HEARTBEAT_TICKS: AtomicU64 = AtomicU64::new(DEFAULT_HEARTBEAT_TICKS);
+/// dots printed so far
+static HEARTBEATS: AtomicU64 = AtomicU64::new(0);
+
+/// turns the heartbeat dot on or off, for checking the timer is still alive
+pub fn set_heartbeat(enabled: bool) {
+	HEARTBEAT.store(enabled, Ordering::Relaxed);
+}
+
+/// prints a dot every `ticks` ticks while the heartbeat is on, 0 counts as 1
+pub fn set_heartbeat_interval(ticks: u64) {
+	HEARTBEAT_TICKS.store(ticks.max(1), Ordering::Relaxed);
+}
+
+/// returns how many heartbeat dots were printed so far
+pub fn heartbeat_count() -> u64 {
+	HEARTBEATS.load(Ordering::Relaxed)
+}
+
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-	// print!("Inside the timer_interrupt_handler!");
-	// print!(" .itr. ");
+	let _irq = IrqContext::enter();
+	// the PIT interrupts once a tick, the HPET every millisecond, so ask it how many ticks are due
+	let due = crate::hpet::tick_count().map_or(1, |count| count.saturating_sub(ticks()));
+
+	for _ in 0..due {
+		TICKS.fetch_add(1, Ordering::Relaxed);
+		crate::fb::present_tick();
+
+		if HEARTBEAT.load(Ordering::Relaxed)
+			&& every_n_ticks(HEARTBEAT_TICKS.load(Ordering::Relaxed))
+		{
+			HEARTBEATS.fetch_add(1, Ordering::Relaxed);
+			print!(".");
+		}
+	}
+	// every interrupt, HPET sleepers can be due between two ticks
+	crate::task::timer::wake_sleepers();
+	// the UART can't interrupt for more output while a received byte sits unread
+	crate::serial::transmit_interrupt();
 
-	// print!(".");
 	// You also gotta setup an end of interrupt function .. since the PIC expects an explicit EOI
 	unsafe {
 		PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
 	}
 }
 
+/// the PS/2 controller's data port, scancodes come in here
+const PS2_DATA: u16 = 0x60;
+
+/// data port at 0x60 up to the status/command port at 0x64, the keyboard IRQ reads through this
+static PS2_PORTS: spin::Once<ClaimedPort> = spin::Once::new();
+
+/// Claims the PS/2 controller ports, has to happen before interrupts are enabled
+pub fn init_keyboard() {
+	PS2_PORTS.call_once(|| ports::claim(PS2_DATA..=0x64, "ps2").expect("PS/2 ports are taken"));
+}
+
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+	let _irq = IrqContext::enter();
 	// use lazy_static::lazy_static;
 	// use pc_keyboard::{DecodedKey, HandleControl, Keyboard, ScancodeSet1, layouts};
 	// use spin::Mutex;
-	use x86_64::instructions::port::Port;
 
 	// lazy_static! {
 	// 	/// defines a KEYBOARD from the pc_keyboard crate. <br>
@@ -126,9 +318,9 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
 
 	// Acquires a KEYBOARD lock
 	// let mut keyboard = KEYBOARD.lock();
-	let mut port = Port::new(0x60);
+	let ps2 = PS2_PORTS.r#try().expect("keyboard interrupt before init_keyboard");
 
-	let scancode: u8 = unsafe { port.read() };
+	let scancode: u8 = unsafe { ps2.read(PS2_DATA) };
 
 	// if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
 	// 	if let Some(key) = keyboard.process_keyevent(key_event) {
@@ -152,9 +344,360 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
 	}
 }
 
+/// IRQs the kernel handles itself: the timer, the keyboard, the cascade and COM1
+pub const KERNEL_IRQS: [u8; 4] = [0, 1, 2, 4];
+
+/// how many handlers can share one line
+pub const HANDLERS_PER_IRQ: usize = 4;
+
+/// Why `register_irq_handler` turned a handler down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+	/// not a PIC line, or one of `KERNEL_IRQS`
+	Reserved(u8),
+	/// the line has `HANDLERS_PER_IRQ` handlers already
+	Full(u8),
+}
+
+/// the handlers of each PIC line, only the lines not in `KERNEL_IRQS` ever get any
+static IRQ_HANDLERS: spin::Mutex<[[Option<fn()>; HANDLERS_PER_IRQ]; 16]> =
+	spin::Mutex::new([[None; HANDLERS_PER_IRQ]; 16]);
+
+/// Adds `handler` to the ones called for `irq` and unmasks it at the PICs
+///
+/// For devices on legacy INTx, with the line from their interrupt line register. INTx is level
+/// triggered and can be shared, so every handler of the line runs on every interrupt and has to
+/// check its device for itself. Handlers run in interrupt context, they must not block or
+/// allocate.
+pub fn register_irq_handler(
+	irq: u8,
+	handler: fn(),
+) -> Result<(), IrqError> {
+	if irq >= 16 || KERNEL_IRQS.contains(&irq) {
+		return Err(IrqError::Reserved(irq));
+	}
+
+	x86_64::instructions::interrupts::without_interrupts(|| {
+		let mut handlers = IRQ_HANDLERS.lock();
+		let slot = handlers[usize::from(irq)]
+			.iter_mut()
+			.find(|slot| slot.is_none())
+			.ok_or(IrqError::Full(irq))?;
+		*slot = Some(handler);
+		Ok(())
+	})?;
+	unmask_irq(irq);
+
+	Ok(())
+}
+
+/// Takes `handler` off `irq`, the line gets masked again once it has none left
+///
+/// False if it wasn't registered there.
+pub fn unregister_irq_handler(
+	irq: u8,
+	handler: fn(),
+) -> bool {
+	if irq >= 16 {
+		return false;
+	}
+
+	let (found, now_unused) = x86_64::instructions::interrupts::without_interrupts(|| {
+		let mut handlers = IRQ_HANDLERS.lock();
+		let line = &mut handlers[usize::from(irq)];
+		let found =
+			match line.iter_mut().find(|slot| slot.map(|h| h as usize) == Some(handler as usize)) {
+				Some(slot) => {
+					*slot = None;
+					true
+				},
+				None => false,
+			};
+		(found, line.iter().all(Option::is_none))
+	});
+	if found && now_unused {
+		mask_irq(irq);
+	}
+
+	found
+}
+
+/// What came in on one PIC line
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IrqStats {
+	pub count: u64,
+	/// interrupts with no handler registered, a device nobody drives pulling the line
+	pub unhandled: u64,
+}
+
+const NO_IRQS: AtomicU64 = AtomicU64::new(0);
+static IRQ_COUNTS: [AtomicU64; 16] = [NO_IRQS; 16];
+static IRQ_UNHANDLED: [AtomicU64; 16] = [NO_IRQS; 16];
+/// IRQ 7 and 15 the PICs raised without anything in service, see `shared_irq_handler`
+static SPURIOUS_IRQS: AtomicU64 = AtomicU64::new(0);
+
+/// the counters of a line the shared handler dispatches, zeros for `KERNEL_IRQS`
+pub fn irq_stats(irq: u8) -> IrqStats {
+	match IRQ_COUNTS.get(usize::from(irq)) {
+		Some(count) => IrqStats {
+			count: count.load(Ordering::Relaxed),
+			unhandled: IRQ_UNHANDLED[usize::from(irq)].load(Ordering::Relaxed),
+		},
+		None => IrqStats::default(),
+	}
+}
+
+/// spurious IRQ 7 and 15 so far
+pub fn spurious_irqs() -> u64 {
+	SPURIOUS_IRQS.load(Ordering::Relaxed)
+}
+
+/// Lets IRQ `line` through the PICs
+pub(crate) fn unmask_irq(line: u8) {
+	x86_64::instructions::interrupts::without_interrupts(|| {
+		let mut pics = PICS.lock();
+		let mut masks = unsafe { pics.read_masks() };
+		if line < 8 {
+			masks[0] &= !(1 << line);
+		} else {
+			// the slave only gets through if the cascade line on the master is open too
+			masks[0] &= !(1 << 2);
+			masks[1] &= !(1 << (line - 8));
+		}
+		unsafe { pics.write_masks(masks[0], masks[1]) };
+	});
+}
+
+/// Blocks IRQ `line` at the PICs, the cascade stays open for the other slave lines
+pub(crate) fn mask_irq(line: u8) {
+	x86_64::instructions::interrupts::without_interrupts(|| {
+		let mut pics = PICS.lock();
+		let mut masks = unsafe { pics.read_masks() };
+		if line < 8 {
+			masks[0] |= 1 << line;
+		} else {
+			masks[1] |= 1 << (line - 8);
+		}
+		unsafe { pics.write_masks(masks[0], masks[1]) };
+	});
+}
+
+extern "x86-interrupt" fn com1_interrupt_handler(_stack_frame: InterruptStackFrame) {
+	let _irq = IrqContext::enter();
+	crate::serial::receive_interrupt();
+	crate::serial::transmit_interrupt();
+
+	unsafe {
+		PICS.lock().notify_end_of_interrupt(InterruptIndex::SerialCom1.as_u8());
+	}
+}
+
+/// OCW3 asking a PIC for its in-service register on the next read
+const READ_ISR: u8 = 0x0B;
+
+/// non-specific end of interrupt
+const PIC_EOI: u8 = 0x20;
+
+/// Whether `irq` really is in service, IRQ 7 and 15 also come in spurious
+///
+/// A line that drops before the CPU acknowledges it gets reported as the lowest priority line of
+/// its PIC, with nothing in service.
+///
+/// Takes the locked PICs so pic8259 stays off the command ports meanwhile.
+fn irq_in_service(
+	_pics: &mut ChainedPics,
+	irq: u8,
+) -> bool {
+	unsafe {
+		if irq < 8 {
+			port_io!(u8, 0x20).write(READ_ISR);
+			port_io!(u8, 0x20).read() & (1 << irq) != 0
+		} else {
+			port_io!(u8, 0xA0).write(READ_ISR);
+			port_io!(u8, 0xA0).read() & (1 << (irq - 8)) != 0
+		}
+	}
+}
+
+/// Runs every handler of `irq`, then ends the interrupt at the PICs
+///
+/// A spurious IRQ 7 gets no EOI, there's nothing to end. A spurious IRQ 15 came through the
+/// master's cascade line though, so the master gets one.
+fn shared_irq_handler(irq: u8) {
+	let _irq = IrqContext::enter();
+	if irq == 7 || irq == 15 {
+		let mut pics = PICS.lock();
+		if !irq_in_service(&mut pics, irq) {
+			SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+			if irq == 15 {
+				unsafe { port_io!(u8, 0x20).write(PIC_EOI) };
+			}
+			return;
+		}
+	}
+
+	// copied out so a handler can register or unregister without deadlocking
+	let handlers = IRQ_HANDLERS.lock()[usize::from(irq)];
+	let mut handled = false;
+	for handler in handlers.iter().flatten() {
+		handler();
+		handled = true;
+	}
+
+	IRQ_COUNTS[usize::from(irq)].fetch_add(1, Ordering::Relaxed);
+	if !handled {
+		IRQ_UNHANDLED[usize::from(irq)].fetch_add(1, Ordering::Relaxed);
+	}
+
+	// for a slave line pic8259 ends it at the slave and then at the master's cascade
+	unsafe {
+		PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq);
+	}
+}
+
+/// One interrupt handler per line, the CPU doesn't tell a handler which vector it came in on
+macro_rules! shared_irq_handlers {
+	($($irq:literal => $name:ident),* $(,)?) => {
+		$(
+			extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+				shared_irq_handler($irq);
+			}
+		)*
+
+		/// the lines `register_irq_handler` hands out with their handlers, every one not in
+		/// `KERNEL_IRQS`
+		const SHARED_IRQ_HANDLERS: &[(u8, extern "x86-interrupt" fn(InterruptStackFrame))] =
+			&[$(($irq, $name)),*];
+	};
+}
+
+shared_irq_handlers! {
+	3 => irq3_handler,
+	5 => irq5_handler,
+	6 => irq6_handler,
+	7 => irq7_handler,
+	8 => irq8_handler,
+	9 => irq9_handler,
+	10 => irq10_handler,
+	11 => irq11_handler,
+	12 => irq12_handler,
+	13 => irq13_handler,
+	14 => irq14_handler,
+	15 => irq15_handler,
+}
+
+/// calls of the test handlers below
+static TEST_IRQ_CALLS: [AtomicU64; 2] = [NO_IRQS; 2];
+
+fn first_test_irq_handler() {
+	TEST_IRQ_CALLS[0].fetch_add(1, Ordering::Relaxed);
+}
+
+fn second_test_irq_handler() {
+	TEST_IRQ_CALLS[1].fetch_add(1, Ordering::Relaxed);
+}
+
+fn test_irq_calls() -> [u64; 2] {
+	[TEST_IRQ_CALLS[0].load(Ordering::Relaxed), TEST_IRQ_CALLS[1].load(Ordering::Relaxed)]
+}
+
+#[test_case]
+fn test_shared_irq_calls_every_handler() {
+	assert_eq!(register_irq_handler(0, first_test_irq_handler), Err(IrqError::Reserved(0)));
+	assert_eq!(register_irq_handler(4, first_test_irq_handler), Err(IrqError::Reserved(4)));
+	assert_eq!(register_irq_handler(16, first_test_irq_handler), Err(IrqError::Reserved(16)));
+
+	// nothing is wired to IRQ 10 under test, `int 42` comes in on the vector a device would
+	crate::assert_ok!(register_irq_handler(10, first_test_irq_handler));
+	crate::assert_ok!(register_irq_handler(10, second_test_irq_handler));
+	let calls = test_irq_calls();
+	let stats = irq_stats(10);
+
+	unsafe { core::arch::asm!("int 42") };
+	assert_eq!(test_irq_calls(), [calls[0] + 1, calls[1] + 1]);
+	assert_eq!(irq_stats(10), IrqStats { count: stats.count + 1, unhandled: stats.unhandled });
+
+	assert!(unregister_irq_handler(10, first_test_irq_handler));
+	assert!(!unregister_irq_handler(10, first_test_irq_handler));
+	unsafe { core::arch::asm!("int 42") };
+	assert_eq!(test_irq_calls(), [calls[0] + 1, calls[1] + 2]);
+
+	// the last one gone, an interrupt now only counts as unhandled
+	assert!(unregister_irq_handler(10, second_test_irq_handler));
+	unsafe { core::arch::asm!("int 42") };
+	assert_eq!(test_irq_calls(), [calls[0] + 1, calls[1] + 2]);
+	assert_eq!(irq_stats(10), IrqStats { count: stats.count + 3, unhandled: stats.unhandled + 1 });
+}
+
+#[test_case]
+fn test_spurious_irq_skips_the_handlers() {
+	crate::assert_ok!(register_irq_handler(7, first_test_irq_handler));
+	let calls = test_irq_calls();
+	let spurious = spurious_irqs();
+	let count = irq_stats(7).count;
+
+	// raised by hand, so IRQ 7 and 15 aren't in service at the PICs, like a spurious one
+	unsafe { core::arch::asm!("int 39") };
+	unsafe { core::arch::asm!("int 47") };
+	assert_eq!(spurious_irqs(), spurious + 2);
+	assert_eq!(test_irq_calls(), calls);
+	assert_eq!(irq_stats(7).count, count);
+
+	assert!(unregister_irq_handler(7, first_test_irq_handler));
+}
+
+#[test_case]
+fn test_irq_masking_leaves_timer_and_keyboard_alone() {
+	use x86_64::instructions::{hlt, interrupts};
+
+	let masks = || interrupts::without_interrupts(|| unsafe { PICS.lock().read_masks() });
+
+	crate::assert_ok!(register_irq_handler(11, first_test_irq_handler));
+	let [master, slave] = masks();
+	assert_eq!(slave & 1 << 3, 0);
+	// timer, keyboard and the cascade
+	assert_eq!(master & 0b111, 0);
+
+	assert!(unregister_irq_handler(11, first_test_irq_handler));
+	let [master, slave] = masks();
+	assert_ne!(slave & 1 << 3, 0);
+	assert_eq!(master & 0b111, 0);
+
+	// and the timer still gets through
+	let start = ticks();
+	while ticks() < start + 2 {
+		hlt();
+	}
+}
+
 use crate::hlt_loop;
 use x86_64::structures::idt::PageFaultErrorCode;
 
+/// set once a fault handler started printing its report, none of them return
+static REPORTING_FAULT: AtomicBool = AtomicBool::new(false);
+
+/// `EXCEPTION: <what> at <rip>`, with the accessed address if there is one, straight to serial
+///
+/// For a fault that hit while another was being reported, `println!` and the formatter may be
+/// what faulted.
+fn report_without_fmt(
+	what: &str,
+	accessed: Option<u64>,
+	stack_frame: &InterruptStackFrame,
+) {
+	use crate::serial::{serial_write_hex, serial_write_str};
+
+	serial_write_str("EXCEPTION: ");
+	serial_write_str(what);
+	serial_write_str(" while reporting a fault, at ");
+	serial_write_hex(stack_frame.instruction_pointer.as_u64());
+	if let Some(addr) = accessed {
+		serial_write_str(", accessed ");
+		serial_write_hex(addr);
+	}
+	serial_write_str("\n");
+}
+
 /// function to handle page_faults - interrupt 14?
 ///
 /// takes in the interrupt stack frame and the error code for page faults
@@ -164,6 +707,17 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
 	use x86_64::registers::control::Cr2;
 
+	// running off the bottom of a managed stack lands on its guard page
+	if let Some(name) = crate::stack::guard_page_owner(Cr2::read()) {
+		panic!("kernel stack overflow in {} (accessed {:?})", name, Cr2::read());
+	}
+
+	// a fault while printing one, the formatter or the console may be what broke
+	if REPORTING_FAULT.swap(true, Ordering::SeqCst) {
+		report_without_fmt("PAGE FAULT", Some(Cr2::read().as_u64()), &stack_frame);
+		hlt_loop();
+	}
+
 	println!("EXCEPTION: PAGE FAULT");
 	// the cr2 register contains the accessed virtual address that caused the page fault
 	println!("Accessed Address: {:?}", Cr2::read());
@@ -0,0 +1,155 @@
+// in src/kassert.rs
+//
+// non-fatal assertions for soft invariants, see kernel_assert!
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+
+/// false when built with the `strip-kernel-asserts` feature, kernel_assert! is folded away then
+///
+/// Checked here rather than in the macro, a `cfg!` in the macro would look at the features of
+/// whatever crate uses it.
+pub const ENABLED: bool = cfg!(not(feature = "strip-kernel-asserts"));
+
+/// how many kernel_assert!s failed so far
+static FAILED: AtomicU64 = AtomicU64::new(0);
+
+/// whether a failed assertion also traps into the breakpoint handler
+static BREAK_ON_ASSERT: AtomicBool = AtomicBool::new(false);
+
+/// Where the assertion that's about to `int3` came from, for the breakpoint handler to print
+static PENDING: Mutex<Option<AssertContext>> = Mutex::new(None);
+
+/// A failed assertion, as seen by the breakpoint handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AssertContext {
+	/// the condition as written
+	pub condition: &'static str,
+	pub file: &'static str,
+	pub line: u32,
+}
+
+impl fmt::Display for AssertContext {
+	fn fmt(
+		&self,
+		f: &mut fmt::Formatter,
+	) -> fmt::Result {
+		write!(f, "`{}` at {}:{}", self.condition, self.file, self.line)
+	}
+}
+
+/// returns how many kernel_assert!s have failed since boot
+pub fn failed_assertions() -> u64 {
+	FAILED.load(Ordering::Relaxed)
+}
+
+/// Makes failed assertions execute `int3` after logging, off by default
+///
+/// Execution carries on right after the assertion once the breakpoint handler returns.
+pub fn set_break_on_assert(enabled: bool) {
+	BREAK_ON_ASSERT.store(enabled, Ordering::Relaxed);
+}
+
+pub fn break_on_assert() -> bool {
+	BREAK_ON_ASSERT.load(Ordering::Relaxed)
+}
+
+/// Called by the breakpoint handler, Some if the breakpoint came from a failed assertion
+///
+/// Clears the context, so a later plain `int3` isn't mistaken for an assertion.
+pub fn take_assert_context() -> Option<AssertContext> {
+	// never spin in the handler, the context is only written right before the int3
+	PENDING.try_lock()?.take()
+}
+
+/// The slow path of kernel_assert!, kept out of line
+#[doc(hidden)]
+#[cold]
+pub fn _failed(
+	condition: &'static str,
+	file: &'static str,
+	line: u32,
+	args: fmt::Arguments,
+) {
+	FAILED.fetch_add(1, Ordering::Relaxed);
+
+	// straight to serial, this can be called with the VGA writer or the heap locked
+	crate::serial_println!(
+		"[ERROR] kernel_assert failed at {}:{}: `{}`: {}",
+		file,
+		line,
+		condition,
+		args
+	);
+
+	if break_on_assert() {
+		*PENDING.lock() = Some(AssertContext { condition, file, line });
+		x86_64::instructions::interrupts::int3();
+	}
+}
+
+/// Checks a soft invariant without panicking
+///
+/// A failed check logs the message with file and line, bumps `failed_assertions` and, if
+/// `set_break_on_assert` is on, traps into the breakpoint handler. Either way execution goes on.
+/// With the `strip-kernel-asserts` feature the condition isn't even evaluated.
+#[macro_export]
+macro_rules! kernel_assert {
+	($cond:expr $(,)?) => {
+		$crate::kernel_assert!($cond, "assertion failed")
+	};
+	($cond:expr, $($arg:tt)+) => {
+		if $crate::kassert::ENABLED && !$cond {
+			$crate::kassert::_failed(stringify!($cond), file!(), line!(), format_args!($($arg)+));
+		}
+	};
+}
+
+#[cfg(not(feature = "strip-kernel-asserts"))]
+#[test_case]
+fn test_failed_assert_continues() {
+	let before = failed_assertions();
+	let value = 3;
+
+	kernel_assert!(value == 4, "value was {}", value);
+	kernel_assert!(value == 3, "this one holds");
+
+	assert_eq!(failed_assertions(), before + 1);
+	assert_eq!(take_assert_context(), None);
+}
+
+#[cfg(not(feature = "strip-kernel-asserts"))]
+#[test_case]
+fn test_failed_assert_breaks_when_asked() {
+	use crate::interrupts::breakpoint_count;
+
+	let before = breakpoint_count();
+	let two = 2;
+
+	set_break_on_assert(true);
+	kernel_assert!(two == 3);
+	set_break_on_assert(false);
+
+	// the handler ran, took the context and returned here
+	assert_eq!(breakpoint_count(), before + 1);
+	assert_eq!(take_assert_context(), None);
+}
+
+#[cfg(feature = "strip-kernel-asserts")]
+#[test_case]
+fn test_stripped_assert_does_nothing() {
+	let before = failed_assertions();
+	let mut evaluated = false;
+
+	kernel_assert!(
+		{
+			evaluated = true;
+			false
+		},
+		"never checked"
+	);
+
+	assert!(!evaluated);
+	assert_eq!(failed_assertions(), before);
+}
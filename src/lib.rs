@@ -9,14 +9,31 @@
 #![feature(associated_type_defaults)]
 #![feature(trivial_bounds)]
 pub mod allocator;
+pub mod ata;
+pub mod config;
+pub mod console;
+pub mod early_serial;
+pub mod exit;
+pub mod fb;
+pub mod fpu;
 // pub mod fs;
 pub mod fs;
 pub mod gdt;
+pub mod hpet;
+pub mod hw;
 pub mod interrupts;
+pub mod kassert;
 pub mod memory;
+pub mod multiboot2;
+pub mod net;
+pub mod panic_record;
 pub mod scanc;
 pub mod serial;
+pub mod shell;
+pub mod stack;
+pub mod sync;
 pub mod task;
+pub mod test_harness;
 pub mod vga_buffer;
 pub mod virtio;
 
@@ -24,6 +41,7 @@ extern crate alloc;
 extern crate static_assertions as sa;
 
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU8, Ordering};
 
 /// trait for `test` functions
 pub trait Testable {
@@ -41,11 +59,49 @@ where
 		// implemented by the compiler
 		// for functions their type is their name                  // and returns a string
 		// description of every type
+		let before = allocator::checkpoint();
 		self();
+		// the caches' lists still point at what the test dropped
+		allocator::shrink::forget_dropped();
+		// and the slab caches keep the slabs it emptied
+		allocator::slab::release_empty();
+		let leaked = allocator::diff(&before);
+		assert!(!leaked.has_leaks(), "leaked heap memory: {}", leaked);
 		serial_println!("[ok]");
 	}
 }
 
+/// A test that may leave heap memory behind, it runs without the leak check
+///
+/// For tests that leak on purpose, a buffer handed to something global for good or a leak the
+/// test looks for. What the kernel sets up once and keeps belongs in `allocator::init_heap`
+/// instead. Make one with `may_leak!` and put `#[test_case]` on that instead of the function.
+pub struct MayLeak {
+	pub name: &'static str,
+	pub test: fn(),
+}
+
+impl Testable for MayLeak {
+	fn run(&self) -> () {
+		serial_print!("{}....\t", self.name);
+		(self.test)();
+		serial_println!("[ok]");
+	}
+}
+
+/// Wraps a test function in a `MayLeak`, named like `Testable` would name the function
+///
+/// ```ignore
+/// #[test_case]
+/// const FILLS_THE_CACHE: blog_os::MayLeak = blog_os::may_leak!(fills_the_cache);
+/// ```
+#[macro_export]
+macro_rules! may_leak {
+	($test:path) => {
+		$crate::MayLeak { name: concat!(module_path!(), "::", stringify!($test)), test: $test }
+	};
+}
+
 // #[cfg(test)] not added so that it is available to all executables and itegration tests -- it is
 // also public
 /// takes the tests(functions) as arguments
@@ -61,40 +117,104 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 	}
 
 	// to exit_qemu -- cargo considers all error codes other than 0 as Failures
-	exit_qemu(QemuExitCode::Success);
+	exit::exit_qemu_or_halt(QemuExitCode::Success);
+}
+
+/// What the panic handler does before SERIAL1 is ready
+///
+/// SERIAL1 and the VGA writer may not be usable yet, so it's the early serial writer only. Halts
+/// afterwards, with `unattended` it reports the panic and exits QEMU instead.
+pub fn early_boot_panic(
+	info: &PanicInfo,
+	rip: u64,
+) -> ! {
+	early_println!("KERNEL PANIC (early boot): {}", info);
+	if cfg!(feature = "unattended") {
+		exit::report_panic(info, rip);
+	}
+	hlt_loop();
 }
 
 /// our panic handler in test mode -- no need to gate it here .... the actual function is gated in
 /// main.rs using #[cfg(test)]
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
-	serial_println!("[failed] \n");
-	serial_println!("Error: {} \n", info);
-	exit_qemu(QemuExitCode::Failed);
-
-	serial_println!("QemuExitCode::Failed didn't work");
+	serial::enter_panic();
+	if boot_stage() < BootStage::SerialReady {
+		early_println!("[failed] \n");
+		early_println!("Error: {} \n", info);
+	} else {
+		serial_println!("[failed] \n");
+		serial_println!("Error: {} \n", info);
+	}
 
-	hlt_loop();
+	// falls back to shutdown ports and a serial marker if the exit device isn't there
+	exit::exit_qemu_or_halt(QemuExitCode::Failed);
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 /// QemuExitCode:
 /// - Success: 0x10
-/// - Failure: 0x11
+/// - Failed: 0x11, a test assertion failed
+/// - KernelPanic: 0x12, a panic outside of a test, only with the `unattended` feature
+/// - Timeout: 0x13, a test waited longer than it was allowed to
+/// - DoubleFault: 0x14, only with the `unattended` feature
 ///
-/// They shouldn't clash with the default exit codes of QEMU
+/// They shouldn't clash with the default exit codes of QEMU, which exits with
+/// `host_status()` for each of them
 pub enum QemuExitCode {
 	Success = 0x10,
 	Failed = 0x11,
+	KernelPanic = 0x12,
+	Timeout = 0x13,
+	DoubleFault = 0x14,
+}
+
+impl QemuExitCode {
+	/// What the QEMU process exits with, 33 for Success up to 41 for DoubleFault
+	///
+	/// isa-debug-exit turns a write of `code` into `(code << 1) | 1`, runner configurations match
+	/// on this (bootimage's `test-success-exit-code` is Success's).
+	pub const fn host_status(self) -> u32 {
+		(self as u32) << 1 | 1
+	}
+
+	/// the `status=` word of the serial marker
+	pub fn status(self) -> &'static str {
+		match self {
+			QemuExitCode::Success => "success",
+			QemuExitCode::Failed => "failed",
+			QemuExitCode::KernelPanic => "panic",
+			QemuExitCode::Timeout => "timeout",
+			QemuExitCode::DoubleFault => "double-fault",
+		}
+	}
+
+	/// Prints a machine-parsable result line on serial
+	///
+	/// Lets a runner tell success from failure by grepping the log, even when the
+	/// isa-debug-exit device is missing and the exit code never reaches the host
+	pub fn emit_marker(self) {
+		// a panic this early can't use SERIAL1 yet
+		if boot_stage() < BootStage::SerialReady {
+			early_println!("[QEMU-EXIT] status={} code={:#x}", self.status(), self as u32);
+		} else {
+			serial_println!("[QEMU-EXIT] status={} code={:#x}", self.status(), self as u32);
+		}
+	}
 }
 
 /// function to exit QEMU
 /// Takes in a QemuExitCode as its argument
+///
+/// Simply returns if the isa-debug-exit device isn't attached, `exit::exit_qemu_or_halt` is what
+/// tests should end with.
 pub fn exit_qemu(exit_code: QemuExitCode) {
-	use x86_64::instructions::port::Port;
+	// whatever is still queued for the UART would go down with QEMU
+	serial::flush();
 
 	unsafe {
-		let mut port = Port::new(0xf4); // creates a new Port at 0xf4, which is the iobase of the isa-debug-exit device
+		let mut port = port_io!(u32, 0xf4); // 0xf4 is the iobase of the isa-debug-exit device
 		port.write(exit_code as u32);
 	}
 }
@@ -106,8 +226,9 @@ entry_point!(test_kernel_main);
 
 /// actual entry point?
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
-	init(); // for breakpoints
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+	// the heap too, some unit tests need it (the keyboard's scancode channel)
+	let _env = test_harness::init_full(boot_info);
 	test_main();
 	hlt_loop();
 }
@@ -133,19 +254,109 @@ fn panic(info: &PanicInfo) -> ! {
 	test_panic_handler(info)
 }
 
+/// How far the boot got, panics before `SerialReady` go through the early serial path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum BootStage {
+	/// only `early_serial` can be relied on
+	Start = 0,
+	/// SERIAL1 is initialized and usable
+	SerialReady = 1,
+	/// `init()` is done, interrupts are on
+	InterruptsReady = 2,
+}
+
+static BOOT_STAGE: AtomicU8 = AtomicU8::new(BootStage::Start as u8);
+
+/// returns how far the boot got
+pub fn boot_stage() -> BootStage {
+	match BOOT_STAGE.load(Ordering::Relaxed) {
+		0 => BootStage::Start,
+		1 => BootStage::SerialReady,
+		_ => BootStage::InterruptsReady,
+	}
+}
+
+fn set_boot_stage(stage: BootStage) {
+	BOOT_STAGE.store(stage as u8, Ordering::Relaxed);
+}
+
 /// to initialize the IDT for exception handling
 pub fn init() {
 	gdt::init();
+	#[cfg(feature = "panic-in-init")]
+	panic!("panic-in-init: panicking inside init, before SERIAL1 is ready");
 	interrupts::init_idt();
+	fpu::init();
+
+	// from here on panics can use SERIAL1, which tolerates the UART being set up already
+	lazy_static::initialize(&serial::SERIAL1);
+	set_boot_stage(BootStage::SerialReady);
+
+	interrupts::init_keyboard();
 
 	unsafe {
 		interrupts::PICS.lock().initialize();
 	}
+	// serial output goes out in the background from InterruptsReady on
+	serial::init_tx_interrupt();
 
 	x86_64::instructions::interrupts::enable(); // to enable the interrupts
 	// executes the "sti" instruction called Set interrupts to enable external interrupts!
 	// there is also our default hardware timer Intel 8253 .. we have to be careful .. simply
 	// enabling this results in a double fault
+
+	set_boot_stage(BootStage::InterruptsReady);
+}
+
+/// Reasons the post-init self check can fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfCheckError {
+	/// `int3` returned without our breakpoint handler running
+	BreakpointHandlerNotReached,
+	/// interrupts are still disabled, so the timer can't fire
+	InterruptsDisabled,
+	/// no timer tick arrived within the waiting window
+	TimerNotTicking,
+}
+
+/// how many `hlt`s we wait for a timer tick before giving up
+const SELF_CHECK_MAX_HLTS: usize = 100;
+
+/// Confirms the breakpoint and timer handlers are reachable after `init()`
+///
+/// Fires an `int3` and waits for a timer tick. Both would triple fault or hang much later in
+/// confusing ways if the IDT or PIC setup was broken, so check them right after init.
+pub fn self_check() -> Result<(), SelfCheckError> {
+	use x86_64::instructions::interrupts as cpu;
+
+	let breakpoints = interrupts::breakpoint_count();
+	// not cpu::int3(), that one is marked `nomem` so the counter read could be moved across it
+	unsafe { core::arch::asm!("int3") };
+	if interrupts::breakpoint_count() == breakpoints {
+		return Err(SelfCheckError::BreakpointHandlerNotReached);
+	}
+
+	// hlt with interrupts off would never wake up
+	if !cpu::are_enabled() {
+		return Err(SelfCheckError::InterruptsDisabled);
+	}
+
+	// any interrupt ends a hlt, so keep waiting until the tick count actually moves
+	let ticks = interrupts::ticks();
+	for _ in 0..SELF_CHECK_MAX_HLTS {
+		if interrupts::ticks() != ticks {
+			return Ok(());
+		}
+		x86_64::instructions::hlt();
+	}
+
+	if interrupts::ticks() != ticks { Ok(()) } else { Err(SelfCheckError::TimerNotTicking) }
+}
+
+#[test_case]
+fn test_self_check_after_init() {
+	assert_eq!(self_check(), Ok(()));
 }
 
 /// thin wrapper around hlt instruction
@@ -6,17 +6,28 @@
 #![test_runner(blog_os::test_runner)]
 
 use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
-use blog_os::fs::simple_fs::{FileSystem, FileSystemError, SFS};
+use blog_os::fs::{
+	block_cache::{self, CachedDevice},
+	block_dev::BlockDevice,
+	image,
+	simple_fs::{FileSystem, FileSystemError, SFS},
+};
 use blog_os::{
-	allocator,
+	allocator, ata, config, hpet,
 	interrupts::InterruptIndex::Keyboard,
 	memory::{self, BootInfoFrameAllocator, translate_addr},
-	print, println,
-	task::{Task, executor::Executor, keyboard, simple_executor::SimpleExecutor},
+	panic_record::{self, PanicRecord, PanicReserved},
+	print, println, shell,
+	task::{Priority, Task, blocking, executor::Executor, simple_executor::SimpleExecutor},
 	virtio::{FRAME_ALLOCATOR, OsHal, PAGE_MAPPER, pci, pci::PciConfigIo},
 };
 use bootloader::{BootInfo, entry_point};
-use core::{arch::asm, panic::PanicInfo};
+use core::{
+	arch::asm,
+	cell::RefCell,
+	panic::PanicInfo,
+	sync::atomic::{AtomicBool, Ordering},
+};
 use virtio_drivers::{
 	Hal, PhysAddr,
 	device::blk::VirtIOBlk,
@@ -37,33 +48,44 @@ extern crate alloc;
 entry_point!(kernel_main);
 
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
+	blog_os::early_serial::init();
+
 	println!("Hello zen-zap{}", "!");
 
-	println!("[INFO] Boot Info Received:");
-	println!("  - Physical Memory Offset: {:#x}", boot_info.physical_memory_offset);
-	println!("  - Memory Map:");
-	for region in boot_info.memory_map.iter() {
-		println!(
-			"    - Start: {:#010x}, End: {:#010x}, Size: {} KB, Type: {:?}",
-			region.range.start_addr(),
-			region.range.end_addr(),
-			region.range.end_addr().saturating_sub(region.range.start_addr()) / 1024,
-			region.region_type
-		);
-	}
-	println!("=================");
+	let boot_report = memory::boot_report(boot_info);
+	println!("[BOOT] {}", boot_report);
 
 	blog_os::init(); // for the exception things
 
-	let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+	match blog_os::self_check() {
+		Ok(()) => println!("[INIT] Breakpoint and timer handlers verified"),
+		Err(e) => println!("[INIT] FATAL: interrupt self check failed: {:?}", e),
+	}
+
+	let phys_mem_offset = VirtAddr::new(boot_report.physical_memory_offset);
 
 	// Set the physical memory offset for VirtIO
-	unsafe {
-		blog_os::virtio::PHYSICAL_MEMORY_OFFSET = boot_info.physical_memory_offset;
+	blog_os::virtio::set_physical_memory_offset(boot_report.physical_memory_offset);
+
+	// the PIT keeps the ticks going if there is no HPET or it can't be used
+	match hpet::find_table().map(hpet::init_hpet) {
+		Some(Ok(info)) => println!(
+			"[HPET] {} timers at {:#x}, {} Hz, drives the timer interrupt now",
+			info.timers,
+			info.base,
+			info.frequency()
+		),
+		Some(Err(e)) => println!("[HPET] not used: {:?}", e),
+		None => println!("[HPET] no ACPI HPET table, staying on the PIT"),
 	}
 
 	let mut mapper = unsafe { memory::init(phys_mem_offset) };
 	let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+	println!(
+		"[MEM] usable: {} KiB, reserved: {} KiB",
+		frame_allocator.usable_bytes() / 1024,
+		frame_allocator.reserved_bytes() / 1024
+	);
 
 	*FRAME_ALLOCATOR.lock() = Some(frame_allocator);
 	*PAGE_MAPPER.lock() = Some(mapper);
@@ -80,7 +102,11 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 	let pci_config_access = PciConfigIo;
 	let mut pci_root = PciRoot::new(pci_config_access);
 
-	if let Some(device_function) = pci::scan(&mut pci_root) {
+	let mut executor = Executor::new();
+	// before the config watcher's first look at kernel.cfg
+	config::subscribe_builtin();
+
+	let fs = if let Some(device_function) = pci::scan(&mut pci_root) {
 		let mut pci_root_mut = pci_root;
 		let transport = PciTransport::new::<OsHal, _>(&mut pci_root_mut, device_function)
 			.expect("Failed to create PCI transport");
@@ -128,52 +154,91 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 			println!("[VirtIO] Write/Read test FAILED!");
 		}
 
-		println!("[SFS] Initializing...");
+		// block 0 holds the superblock, the filesystem has to find it again
+		blk_dev.write_blocks(0, &buffer).expect("restoring block 0 failed");
+
+		match PanicRecord::read_from_device(&mut blk_dev) {
+			Ok(Some(record)) => println!(
+				"[PANIC] last run panicked at tick {}: {} (rip {:#x})",
+				record.timestamp,
+				record.message(),
+				record.rip
+			),
+			Ok(None) => {},
+			Err(e) => println!("[PANIC] couldn't read the panic record: {:?}", e),
+		}
 
-		let mut fs = match SFS::mount(blk_dev) {
-			Ok(fs) => {
-				println!("[SFS] Filesystem mounted successfully");
-				fs
+		let fs = mount_or_format(blk_dev, || {
+			// We need to re-create the block device
+			let mut pci_root_for_format = PciRoot::new(pci_config_access);
+			let transport =
+				PciTransport::new::<OsHal, _>(&mut pci_root_for_format, device_function)
+					.expect("Failed to re-create transport for format");
+
+			VirtIOBlk::<OsHal, _>::new(transport).expect("Failed to re-create blk_dev for format")
+		});
+
+		fs.map(|mut fs| {
+			println!("[SFS] Testing File creation..");
+			match fs.create_file("hello.txt") {
+				Ok(handle) => println!("File created with handle {:?}", handle),
+				Err(e) => println!("Failed to create file: {:?}", e),
+			}
+
+			// You can try creating it again to test the "FileExists" error path
+			match fs.create_file("hello.txt") {
+				Ok(_) => println!("[FS] This should not happen!"),
+				Err(e) => println!("[FS] Correctly failed to create existing file: {:?}", e),
+			}
+
+			share_fs(&mut executor, fs)
+		})
+	} else {
+		println!("[PCI] No VirtIO block device found, looking for ATA disks");
+
+		// QEMU puts the boot image on the primary master, that one must not get formatted
+		let drive = ata::probe().into_iter().find_map(|mut drive| {
+			println!(
+				"[ATA] {:?} {:?}: {}, {} sectors",
+				drive.channel(),
+				drive.drive(),
+				drive.model(),
+				drive.sectors()
+			);
+			match drive.has_boot_signature() {
+				Ok(false) => Some(drive),
+				Ok(true) => {
+					println!("[ATA] has a boot sector, leaving it alone");
+					None
+				},
+				Err(e) => {
+					println!("[ATA] can't read sector 0: {:?}", e);
+					None
+				},
+			}
+		});
+
+		match drive {
+			Some(drive) => {
+				let (channel, position) = (drive.channel(), drive.drive());
+				let fs = mount_or_format(drive, || {
+					ata::AtaDrive::identify(channel, position)
+						.expect("ATA drive went away before formatting")
+				});
+				fs.map(|fs| share_fs(&mut executor, fs))
 			},
-			Err(_) => {
-				println!("[SFS] Mount failed or filesystem not found! Formatting disk...");
-
-				// We need to re-create the block device
-				let mut pci_root_for_format = PciRoot::new(pci_config_access);
-				let transport =
-					PciTransport::new::<OsHal, _>(&mut pci_root_for_format, device_function)
-						.expect("Failed to re-create transport for format");
-
-				let blk_dev_for_format = VirtIOBlk::<OsHal, _>::new(transport)
-					.expect("Failed to re-create blk_dev for format");
-
-				let mut fs = SFS::format(blk_dev_for_format).expect("Failed to format disk.");
-
-				fs.init_root_directory().expect("Failed to init root directory");
-
-				fs
+			None => {
+				println!("[ATA] no disk to use, running without a filesystem");
+				None
 			},
-		};
-
-		println!("[SFS] Testing File creation..");
-		match fs.create_file("hello.txt") {
-			Ok(handle) => println!("File created with handle {:?}", handle),
-			Err(e) => println!("Failed to create file: {:?}", e),
 		}
-
-		// You can try creating it again to test the "FileExists" error path
-		match fs.create_file("hello.txt") {
-			Ok(_) => println!("[FS] This should not happen!"),
-			Err(e) => println!("[FS] Correctly failed to create existing file: {:?}", e),
-		}
-	} else {
-		println!("[PCI] No VirtIO block device found.");
-	}
-
-	let mut executor = Executor::new();
+	};
 
 	executor.spawn(Task::new(example_task()));
-	executor.spawn(Task::new(keyboard::print_keypresses()));
+	executor.spawn(Task::with_priority(Priority::LOW, blocking::worker()));
+
+	// the shell reads the keyboard too, so it's the only task that does, and someone is typing
+	executor.spawn(Task::with_priority(Priority::HIGH, shell::shell_task(fs)));
 	executor.run();
 
 	#[cfg(test)]
@@ -183,12 +248,85 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 	blog_os::hlt_loop();
 }
 
+/// Mounts SFS on `device`, formatting it only if there's no filesystem on it
+///
+/// Disks formatted here keep their last block for the panic record. One formatted on the bare
+/// device covers that block too, it gets mounted as it is and does without the record. A failed
+/// mount keeps the device, so `reopen` has to hand out a new driver for it. None if the disk
+/// holds a filesystem that doesn't mount, that one is left alone.
+fn mount_or_format<D: BlockDevice>(
+	mut device: D,
+	reopen: impl FnOnce() -> D,
+) -> Option<SFS<CachedDevice<PanicReserved<D>>>> {
+	println!("[SFS] Initializing...");
+
+	let superblock = image::read_superblock(&mut device).ok();
+	let device = match superblock {
+		Some(sb) if sb.total_blocks == device.capacity() as u64 => {
+			println!("[SFS] filesystem covers the whole disk, no panic record on this one");
+			PANIC_RECORD_BLOCK.store(false, Ordering::Relaxed);
+			PanicReserved::whole(device)
+		},
+		_ => PanicReserved::new(device),
+	};
+
+	match SFS::mount(CachedDevice::new(device)) {
+		Ok(mut fs) => {
+			println!("[SFS] Filesystem mounted successfully");
+			if fs.mounted_unclean() {
+				match fs.fsck() {
+					Ok(report) if report.is_clean() => println!("[SFS] fsck found nothing wrong"),
+					Ok(report) => println!("[SFS] fsck found problems: {:?}", report.problems),
+					Err(e) => println!("[SFS] fsck failed: {:?}", e),
+				}
+			}
+			Some(fs)
+		},
+		Err(e) if superblock.is_some() => {
+			println!("[SFS] filesystem on the disk doesn't mount ({:?}), leaving it alone", e);
+			None
+		},
+		Err(_) => {
+			println!("[SFS] No filesystem found! Formatting disk...");
+
+			let mut fs = SFS::format(CachedDevice::new(PanicReserved::new(reopen())))
+				.expect("Failed to format disk.");
+
+			fs.init_root_directory().expect("Failed to init root directory");
+
+			Some(fs)
+		},
+	}
+}
+
+/// cleared when the filesystem uses the disk's last block, the panic record must stay off it then
+static PANIC_RECORD_BLOCK: AtomicBool = AtomicBool::new(true);
+
+/// Hands `fs` to the block cache flusher and the kernel.cfg watcher, the shell gets the other half
+fn share_fs<D: BlockDevice + 'static>(
+	executor: &mut Executor,
+	fs: SFS<CachedDevice<D>>,
+) -> Box<dyn FileSystem> {
+	let fs = Rc::new(RefCell::new(fs));
+	executor.spawn(Task::with_priority(
+		Priority::LOW,
+		block_cache::flusher(
+			fs.clone(),
+			block_cache::FLUSH_INTERVAL_TICKS,
+			block_cache::DEFAULT_MAX_DIRTY_AGE_TICKS,
+		),
+	));
+	executor.spawn(Task::with_priority(
+		Priority::LOW,
+		config::watch(fs.clone(), config::WATCH_INTERVAL_TICKS),
+	));
+	Box::new(fs)
+}
+
 /// our panic handler in general mode
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-	println!("KERNEL PANIC: {}\n", info);
-
 	// reading RIP [current instruction pointer]
 	let rip: u64;
 	unsafe {
@@ -199,35 +337,73 @@ fn panic(info: &PanicInfo) -> ! {
 		);
 	}
 
+	if blog_os::boot_stage() < blog_os::BootStage::SerialReady {
+		blog_os::early_boot_panic(info, rip);
+	}
+
+	// the ring goes out first, then every line waits for the UART
+	blog_os::serial::enter_panic();
+	println!("KERNEL PANIC: {}\n", info);
 	println!("RIP: {:#018x}", rip);
 
 	// stack backtrace
 	println!("\nStack Backtrace:");
-	let mut rbp: u64;
-	unsafe {
-		asm!(
-			"mov {rbp}, rbp",
-			rbp = out(reg) rbp,
-			options(nomem, preserves_flags),
-		)
+	let mut backtrace = [0; panic_record::BACKTRACE_DEPTH];
+	let frames = panic_record::capture_backtrace(&mut backtrace);
+	for ret in &backtrace[..frames] {
+		println!("  {:#018x}", ret);
 	}
 
-	let mut stack_trace_count = 0;
+	// who holds the heap, unless the panic came from inside the allocator
+	if let Some(heap) = blog_os::allocator::try_heap_by_task() {
+		println!("\nHeap by task:");
+		for row in heap.rows() {
+			println!("  {}: {} bytes in {} allocations", row.owner, row.bytes, row.allocations);
+		}
+	}
 
-	while rbp != 0 && stack_trace_count < 20 {
-		// return address is saved at [RBP + 8]
-		let ret = unsafe { *((rbp + 8) as *const u64) };
-		println!("  {:#018x}", ret);
-		// the previous frame's RBP is at [RBP]
-		rbp = unsafe { *(rbp as *const u64) };
+	// a panic while writing the record must not try again
+	if !PANIC_RECORD_BLOCK.load(Ordering::Relaxed) {
+		println!("\nthe filesystem uses the disk's last block, no panic record");
+	} else if !IN_PANIC.swap(true, Ordering::SeqCst) {
+		match panic_device() {
+			Some(mut device) => match panic_record::serialize_panic_to_disk(info, &mut device) {
+				Ok(()) => println!("\npanic record written to the disk's last block"),
+				Err(e) => println!("\ncouldn't write the panic record: {:?}", e),
+			},
+			None => println!("\nno disk for the panic record"),
+		}
+	}
 
-		stack_trace_count += 1;
+	// nobody is watching an unattended run, QEMU has to go away with a code that says why
+	if cfg!(feature = "unattended") {
+		blog_os::exit::report_panic(info, rip);
 	}
 
 	// halt it forever,
 	blog_os::hlt_loop();
 }
 
+/// set once the panic handler got as far as the disk
+#[cfg(not(test))]
+static IN_PANIC: AtomicBool = AtomicBool::new(false);
+
+/// A fresh driver for the block device, to write the panic record through
+///
+/// Whatever driver the kernel had may be mid request, so the device gets reset. Needs the frame
+/// allocator for the queue, None if it isn't set up or the panic left it locked.
+#[cfg(not(test))]
+fn panic_device() -> Option<VirtIOBlk<OsHal, PciTransport>> {
+	if FRAME_ALLOCATOR.try_lock().map_or(true, |frames| frames.is_none()) {
+		return None;
+	}
+
+	let mut pci_root = PciRoot::new(PciConfigIo);
+	let device_function = pci::scan(&mut pci_root)?;
+	let transport = PciTransport::new::<OsHal, _>(&mut pci_root, device_function).ok()?;
+	VirtIOBlk::<OsHal, _>::new(transport).ok()
+}
+
 #[cfg(test)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
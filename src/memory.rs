@@ -2,12 +2,17 @@
 
 use x86_64::{
     structures::paging::{PageTable, OffsetPageTable, Page, PhysFrame, Mapper, Size4KiB, FrameAllocator, PageTableFlags as Flags},
+    structures::paging::{page::PageRangeInclusive, mapper::{MapToError, FlagUpdateError}, Translate},
     structures::paging::page_table::FrameError,
     VirtAddr, 
     PhysAddr,
     registers::control::Cr3,
 };
+use bootloader::BootInfo;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::fmt;
+use core::ops::Range;
+use spin::Mutex;
 
 /// Returns a mutable reference to the active level 4 table.
 ///
@@ -27,13 +32,51 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     }
 }
 
-/// Translates the given virtual address to the mapped physical address, or
-/// `None` if the address is not mapped.
+/// Size of the page an address was found in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappedPageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl MappedPageSize {
+    pub fn bytes(self) -> u64
+    {
+        match self {
+            MappedPageSize::Size4KiB => 4096,
+            MappedPageSize::Size2MiB => 2 * 1024 * 1024,
+            MappedPageSize::Size1GiB => 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// What the page walk found for a virtual address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranslateResult {
+    /// `flags` are the ones of the entry that maps the page, the upper levels aren't merged in
+    Mapped { phys: PhysAddr, flags: Flags, page_size: MappedPageSize },
+    NotMapped,
+}
+
+impl TranslateResult {
+    /// the physical address, whatever the page size
+    pub fn phys_addr(&self) -> Option<PhysAddr>
+    {
+        match *self {
+            TranslateResult::Mapped { phys, .. } => Some(phys),
+            TranslateResult::NotMapped => None,
+        }
+    }
+}
+
+/// Translates the given virtual address to the mapped physical address, along with the size
+/// and flags of the page it lives in.
 ///
 /// This function is unsafe because the caller must guarantee that the
 /// complete physical memory is mapped to virtual memory at the passed
 /// `physical_memory_offset`.
-pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr>
+pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr) -> TranslateResult
 {
     translate_addr_inner(addr, physical_memory_offset)
 }
@@ -43,7 +86,7 @@ pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr) -
 /// This function is safe to limit the scope of `unsafe` because Rust treats
 /// the whole body of unsafe functions as an unsafe block. This function must
 /// only be reachable through `unsafe fn` from outside of this module.
-fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr>
+fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> TranslateResult
 {
     // read the active level 4 frame from the CR3 register
     let (level_4_table_frame, _) = Cr3::read();
@@ -54,9 +97,10 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
     ];
 
     let mut frame = level_4_table_frame;
+    let mut flags = Flags::empty();
 
     // traverse the multilevel page table
-    for &index in &table_indexes {
+    for (level, &index) in table_indexes.iter().enumerate() {
 
         // convert the frame into a page table reference
         let virt = physical_memory_offset + frame.start_address().as_u64();
@@ -65,16 +109,32 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
 
         // read the page table entry and update "frame"
         let entry = &table[index];
+        flags = entry.flags();
 
         frame = match entry.frame() {
             Ok(frame) => frame,
-            Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("Huge Frames are not supported"),
+            Err(FrameError::FrameNotPresent) => return TranslateResult::NotMapped,
+            Err(FrameError::HugeFrame) => {
+                // a P3 entry maps 1 GiB, a P2 entry 2 MiB. The bit is reserved in P4.
+                let page_size = match level {
+                    1 => MappedPageSize::Size1GiB,
+                    2 => MappedPageSize::Size2MiB,
+                    _ => return TranslateResult::NotMapped,
+                };
+                let mask = page_size.bytes() - 1;
+
+                // bit 12 is the PAT bit in huge entries, not part of the frame address
+                let base = entry.addr().as_u64() & !mask;
+                let phys = PhysAddr::new(base + (addr.as_u64() & mask));
+
+                return TranslateResult::Mapped { phys, flags, page_size };
+            }
         };
     }
 
     // calculate the physical address by adding the page offset
-    Some(frame.start_address() + u64::from(addr.page_offset()))
+    let phys = frame.start_address() + u64::from(addr.page_offset());
+    TranslateResult::Mapped { phys, flags, page_size: MappedPageSize::Size4KiB }
 }
 
 /// Initialize a new OffsetPageTable.
@@ -107,6 +167,61 @@ pub fn create_example_mapping(page: Page, mapper: &mut OffsetPageTable, frame_al
     map_to_result.expect("map_to failed").flush();
 }
 
+/// Maps every page in `pages` to a fresh frame
+pub fn map_range(
+    pages: PageRangeInclusive,
+    flags: Flags,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>>
+{
+    for page in pages {
+        let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+    Ok(())
+}
+
+/// Unmaps whatever is mapped in `pages`, returns how many pages that was
+///
+/// The frames are leaked, hand them to `BootInfoFrameAllocator::dealloc_frame` to reuse them.
+pub fn unmap_range(pages: PageRangeInclusive, mapper: &mut impl Mapper<Size4KiB>) -> usize
+{
+    let mut unmapped = 0;
+    for page in pages {
+        if let Ok((_frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            unmapped += 1;
+        }
+    }
+    unmapped
+}
+
+/// Replaces the flags of the entry that maps `page`, and flushes the page from the TLB
+///
+/// Unsafe because the new flags can break whoever uses the page, clearing PRESENT under the heap
+/// or handing a kernel page to user mode is on the caller.
+pub unsafe fn set_page_flags(page: Page, flags: Flags, mapper: &mut impl Mapper<Size4KiB>) -> Result<(), FlagUpdateError>
+{
+    unsafe { mapper.update_flags(page, flags)?.flush() };
+    Ok(())
+}
+
+/// Clears WRITABLE on `page`, writes to it page fault from then on
+///
+/// Also from ring 0, the bootloader turns on CR0.WP. Fails with `PageNotMapped` if nothing maps
+/// the page, and with `ParentEntryHugePage` if a huge page does.
+pub fn make_readonly(page: Page, mapper: &mut (impl Mapper<Size4KiB> + Translate)) -> Result<(), FlagUpdateError>
+{
+    let flags = match mapper.translate(page.start_address()) {
+        x86_64::structures::paging::mapper::TranslateResult::Mapped { flags, .. } => flags,
+        _ => return Err(FlagUpdateError::PageNotMapped),
+    };
+
+    // only takes a permission away, nothing that was safe to do before becomes unsafe
+    unsafe { set_page_flags(page, flags - Flags::WRITABLE, mapper) }
+}
+
 /// A FrameAllocator that always returns `None`
 pub struct EmptyFrameAllocator;
 
@@ -119,10 +234,81 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
+/// Frames below this are kept back for the things that really need low memory (SMP trampoline,
+/// ISA DMA, BIOS data) and only handed out through `allocate_frame_below`/`allocate_frame_in`
+pub const LOW_MEMORY_LIMIT: u64 = 0x10_0000; // 1 MiB
+
+const MAX_RESERVED_RANGES: usize = 16;
+
+/// how many frames of the reserved pool we keep track of
+const RESERVED_POOL_FRAMES: usize = 1024;
+
+/// how many freed frames we can hold on to for reuse, frames freed past that are leaked
+const FREED_CAPACITY: usize = 1024;
+
+/// Physical ranges `[start, end)` that regular allocations never hand out
+#[derive(Debug, Clone, Copy)]
+pub struct ReservedRanges {
+    ranges: [(u64, u64); MAX_RESERVED_RANGES],
+    len: usize,
+}
+
+impl ReservedRanges {
+    /// starts out with everything below `LOW_MEMORY_LIMIT`
+    const fn new() -> Self
+    {
+        let mut ranges = [(0, 0); MAX_RESERVED_RANGES];
+        ranges[0] = (0, LOW_MEMORY_LIMIT);
+        ReservedRanges { ranges, len: 1 }
+    }
+
+    /// adds a range, returns false if the table is full
+    fn push(&mut self, start: u64, end: u64) -> bool
+    {
+        if self.len == MAX_RESERVED_RANGES {
+            return false;
+        }
+        self.ranges[self.len] = (start, end);
+        self.len += 1;
+        true
+    }
+
+    /// true if any part of the frame lies in a reserved range
+    pub fn contains_frame(&self, frame: PhysFrame) -> bool
+    {
+        let start = frame.start_address().as_u64();
+        let end = start + frame.size();
+        self.ranges[..self.len].iter().any(|&(r_start, r_end)| start < r_end && r_start < end)
+    }
+}
+
+/// ranges registered so far, copied into the frame allocator when it's created
+static RESERVED_RANGES: Mutex<ReservedRanges> = Mutex::new(ReservedRanges::new());
+
+/// Keeps `[start, end)` away from regular frame allocations
+///
+/// Only affects frame allocators created afterwards, so call it before
+/// `BootInfoFrameAllocator::init`. Returns false if no more ranges can be registered.
+pub fn reserve_range(start: PhysAddr, end: PhysAddr) -> bool
+{
+    RESERVED_RANGES.lock().push(start.as_u64(), end.as_u64())
+}
+
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
+///
+/// Usable frames inside a reserved range form a separate pool, `allocate_frame` never returns
+/// them.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    /// frames regular allocations can hand out, counted once in `init`
+    total: usize,
+    reserved: ReservedRanges,
+    /// bit i set means the i-th frame of the reserved pool was handed out
+    reserved_used: [u64; RESERVED_POOL_FRAMES / 64],
+    /// start addresses of frames given back through `dealloc_frame`, the first `freed_len` count
+    freed: [u64; FREED_CAPACITY],
+    freed_len: usize,
 }
 
 impl BootInfoFrameAllocator {
@@ -133,14 +319,110 @@ impl BootInfoFrameAllocator {
     /// memory map is valid. The main requirement is that all frames that are marked
     /// as `USABLE` in it are really unused.
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
+        let mut allocator = BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            total: 0,
+            reserved: *RESERVED_RANGES.lock(),
+            reserved_used: [0; RESERVED_POOL_FRAMES / 64],
+            freed: [0; FREED_CAPACITY],
+            freed_len: 0,
+        };
+        allocator.total = allocator.usable_frames().count();
+        allocator
+    }
+
+    /// frames regular allocations can draw from, the reserved pool isn't part of it
+    pub fn frames_total(&self) -> usize {
+        self.total
+    }
+
+    /// frames `allocate_frame` handed out and not given back
+    pub fn frames_used(&self) -> usize {
+        // next keeps counting once we're out
+        self.next.min(self.total).saturating_sub(self.freed_len)
+    }
+
+    /// frames given back and waiting to be handed out again
+    pub fn freed_count(&self) -> usize {
+        self.freed_len
+    }
+
+    /// Gives a frame back, the next allocations hand it out again before fresh ones
+    ///
+    /// Frames from the reserved pool go back to the pool. The caller must not use the frame
+    /// afterwards, and must not free it twice. Only `FREED_CAPACITY` frames can wait for reuse,
+    /// any beyond that are leaked.
+    pub fn dealloc_frame(&mut self, frame: PhysFrame)
+    {
+        if self.reserved.contains_frame(frame) {
+            let index = self.reserved_frames().take(RESERVED_POOL_FRAMES).position(|f| f == frame);
+            if let Some(i) = index {
+                self.reserved_used[i / 64] &= !(1 << (i % 64));
+            }
+            return;
+        }
+
+        if self.freed_len == FREED_CAPACITY {
+            return;
         }
+        self.freed[self.freed_len] = frame.start_address().as_u64();
+        self.freed_len += 1;
     }
 
-    /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
+    /// Hands out `count` physically contiguous frames, returns the first of them
+    ///
+    /// Only fresh frames are looked at, freed ones are rarely next to each other. The fresh frames
+    /// passed over because a run broke off go on the freed list, so `allocate_frame` still gets
+    /// them. Nothing is taken until the whole run is found, and if the frames passed over wouldn't
+    /// fit on the freed list the allocation fails instead of leaking them.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame>
+    {
+        if count == 0 {
+            return None;
+        }
+
+        // the first frame of the run so far and its length
+        let mut run: Option<(PhysFrame, u64)> = None;
+        let mut passed_over = 0;
+        let mut end = self.next;
+
+        for frame in self.usable_frames().skip(self.next) {
+            end += 1;
+            run = match run {
+                Some((first, len)) if first + len == frame => Some((first, len + 1)),
+                Some((_, len)) => {
+                    passed_over += len as usize;
+                    Some((frame, 1))
+                }
+                None => Some((frame, 1)),
+            };
+
+            if passed_over > FREED_CAPACITY - self.freed_len {
+                return None;
+            }
+            if run.map_or(false, |(_, len)| len == count as u64) {
+                break;
+            }
+        }
+
+        // out of fresh frames before the run got long enough
+        let (first, _) = run.filter(|&(_, len)| len == count as u64)?;
+
+        for frame in self.usable_frames().skip(self.next).take(passed_over) {
+            self.dealloc_frame(frame);
+        }
+        self.next = end;
+        Some(first)
+    }
+
+    /// frames `allocate_frame` can still hand out
+    pub fn frames_free(&self) -> usize {
+        self.total - self.frames_used()
+    }
+
+    /// Returns an iterator over every frame the memory map marks usable, reserved or not.
+    fn all_usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
 
         // get usable regions from memory map
         let regions = self.memory_map.iter();
@@ -157,12 +439,122 @@ impl BootInfoFrameAllocator {
         // create `PhysFrame` types from the start addresses
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
+
+    /// Returns an iterator over the usable frames regular allocations may use.
+    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        let reserved = self.reserved;
+        self.all_usable_frames().filter(move |frame| !reserved.contains_frame(*frame))
+    }
+
+    /// Returns an iterator over the usable frames that sit in a reserved range.
+    fn reserved_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        let reserved = self.reserved;
+        self.all_usable_frames().filter(move |frame| reserved.contains_frame(*frame))
+    }
+
+    /// Hands out a frame from the reserved pool that lies completely below `limit`
+    pub fn allocate_frame_below(&mut self, limit: PhysAddr) -> Option<PhysFrame>
+    {
+        self.allocate_frame_in(PhysAddr::new(0)..limit)
+    }
+
+    /// Hands out a frame from the reserved pool that lies completely inside `range`
+    pub fn allocate_frame_in(&mut self, range: Range<PhysAddr>) -> Option<PhysFrame>
+    {
+        let candidates = self.reserved_frames().take(RESERVED_POOL_FRAMES).enumerate();
+
+        for (i, frame) in candidates {
+            let start = frame.start_address();
+            if start < range.start || start + frame.size() > range.end {
+                continue;
+            }
+
+            let (word, bit) = (i / 64, i % 64);
+            if self.reserved_used[word] & (1 << bit) != 0 {
+                continue;
+            }
+
+            self.reserved_used[word] |= 1 << bit;
+            return Some(frame);
+        }
+
+        None
+    }
+
+    /// bytes of usable memory regular allocations can draw from
+    pub fn usable_bytes(&self) -> u64 {
+        self.total as u64 * 4096
+    }
+
+    /// bytes of usable memory held back in reserved ranges
+    pub fn reserved_bytes(&self) -> u64 {
+        self.reserved_frames().count() as u64 * 4096
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.freed_len > 0 {
+            self.freed_len -= 1;
+            return Some(PhysFrame::containing_address(PhysAddr::new(self.freed[self.freed_len])));
+        }
+
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
+
+/// What the bootloader handed over, boiled down from the memory map
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootReport {
+    /// bytes in regions marked `Usable`, reserved ranges included
+    pub total_usable_bytes: u64,
+    /// regions in the map, of every type
+    pub region_count: usize,
+    /// the largest usable region, None if there is none
+    pub largest_region: Option<Range<PhysAddr>>,
+    pub physical_memory_offset: u64,
+}
+
+impl BootReport {
+    /// Sums up `memory_map`, which doesn't have to be the one we booted with
+    pub fn new(memory_map: &MemoryMap, physical_memory_offset: u64) -> Self
+    {
+        let usable = memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| r.range.start_addr()..r.range.end_addr());
+
+        BootReport {
+            total_usable_bytes: usable.clone().map(|r| r.end - r.start).sum(),
+            region_count: memory_map.iter().count(),
+            largest_region: usable
+                .max_by_key(|r| r.end - r.start)
+                .map(|r| PhysAddr::new(r.start)..PhysAddr::new(r.end)),
+            physical_memory_offset,
+        }
+    }
+}
+
+/// The boot summary, to print right away before anything else uses the memory map
+pub fn boot_report(boot_info: &BootInfo) -> BootReport
+{
+    BootReport::new(&boot_info.memory_map, boot_info.physical_memory_offset)
+}
+
+/// `130048 KiB usable in 7 regions, largest 0x400000..0x8000000 (126976 KiB), physical memory at ...`
+impl fmt::Display for BootReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} KiB usable in {} regions", self.total_usable_bytes / 1024, self.region_count)?;
+        if let Some(largest) = &self.largest_region {
+            write!(
+                f,
+                ", largest {:#x}..{:#x} ({} KiB)",
+                largest.start.as_u64(),
+                largest.end.as_u64(),
+                (largest.end - largest.start) / 1024
+            )?;
+        }
+        write!(f, ", physical memory at {:#x}", self.physical_memory_offset)
+    }
+}
@@ -0,0 +1,309 @@
+// in src/multiboot2.rs
+//
+// the boot information a multiboot2 loader like GRUB hands over
+
+use bootloader::bootinfo::{FrameRange, MemoryMap, MemoryRegion, MemoryRegionType};
+use core::convert::TryInto;
+
+/// what a multiboot2 loader leaves in eax, next to the info pointer in ebx
+pub const BOOTLOADER_MAGIC: u32 = 0x36D7_6289;
+
+const TAG_END: u32 = 0;
+const TAG_MEMORY_MAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+
+/// memory map entry type of usable RAM
+const MEMORY_AVAILABLE: u32 = 1;
+const MEMORY_ACPI_RECLAIMABLE: u32 = 3;
+const MEMORY_ACPI_NVS: u32 = 4;
+const MEMORY_BAD: u32 = 5;
+
+/// regions `BootParams` keeps, as many as bootloader's `MemoryMap` holds
+pub const MAX_REGIONS: usize = 64;
+
+/// A physical memory range out of the loader's memory map, `[start, end)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemRegion {
+	pub start: u64,
+	pub end: u64,
+	/// the multiboot2 type, 1 is usable RAM
+	pub kind: u32,
+}
+
+impl MemRegion {
+	pub fn is_usable(&self) -> bool {
+		self.kind == MEMORY_AVAILABLE
+	}
+}
+
+/// The framebuffer the loader set up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramebufferInfo {
+	/// physical address
+	pub addr: u64,
+	/// bytes per line
+	pub pitch: u32,
+	pub width: u32,
+	pub height: u32,
+	pub bpp: u8,
+}
+
+impl FramebufferInfo {
+	/// A `fb::Framebuffer` on it, None unless it's 32 bits per pixel
+	///
+	/// Unsafe because physical memory has to be mapped at `physical_memory_offset`, and nothing
+	/// else may draw on the framebuffer.
+	pub unsafe fn framebuffer(
+		&self,
+		physical_memory_offset: u64,
+	) -> Option<crate::fb::Framebuffer> {
+		if self.bpp != 32 {
+			return None;
+		}
+		let base = (physical_memory_offset + self.addr) as *mut u32;
+		Some(unsafe { crate::fb::Framebuffer::new(base, self.width, self.height, self.pitch / 4) })
+	}
+}
+
+/// What the kernel needs out of the multiboot2 information, in a form it can keep
+#[derive(Debug, Clone, Copy)]
+pub struct BootParams {
+	regions: [MemRegion; MAX_REGIONS],
+	region_count: usize,
+	pub framebuffer: Option<FramebufferInfo>,
+}
+
+impl BootParams {
+	/// the memory map, regions past `MAX_REGIONS` are dropped
+	pub fn regions(&self) -> &[MemRegion] {
+		&self.regions[..self.region_count]
+	}
+
+	/// The memory map as bootloader's `MemoryMap`, for `BootInfoFrameAllocator::init`
+	///
+	/// Usable regions shrink to whole frames, everything else grows to them.
+	pub fn memory_map(&self) -> MemoryMap {
+		let mut map = MemoryMap::new();
+		for region in self.regions() {
+			let range = if region.is_usable() {
+				align_up(region.start).map(|start| (start, region.end & !0xfff))
+			} else {
+				align_up(region.end).map(|end| (region.start & !0xfff, end))
+			};
+			let (start, end) = match range {
+				Some((start, end)) if start < end => (start, end),
+				_ => continue,
+			};
+
+			let region_type = match region.kind {
+				MEMORY_AVAILABLE => MemoryRegionType::Usable,
+				MEMORY_ACPI_RECLAIMABLE => MemoryRegionType::AcpiReclaimable,
+				MEMORY_ACPI_NVS => MemoryRegionType::AcpiNvs,
+				MEMORY_BAD => MemoryRegionType::BadMemory,
+				_ => MemoryRegionType::Reserved,
+			};
+			map.add_region(MemoryRegion { range: FrameRange::new(start, end), region_type });
+		}
+		map
+	}
+}
+
+/// None for an address in the last frame of the address space, there's no boundary above it
+fn align_up(addr: u64) -> Option<u64> {
+	Some(addr.checked_add(0xfff)? & !0xfff)
+}
+
+fn read_u32(
+	bytes: &[u8],
+	offset: usize,
+) -> Option<u32> {
+	Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(
+	bytes: &[u8],
+	offset: usize,
+) -> Option<u64> {
+	Some(u64::from_le_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+/// The boot information structure a multiboot2 loader passes in ebx
+pub struct MultibootInfo<'a> {
+	bytes: &'a [u8],
+}
+
+impl<'a> MultibootInfo<'a> {
+	/// None if `bytes` is shorter than the size the structure claims
+	pub fn from_bytes(bytes: &'a [u8]) -> Option<Self> {
+		let total_size = read_u32(bytes, 0)? as usize;
+		Some(MultibootInfo { bytes: bytes.get(..total_size)? })
+	}
+
+	/// Unsafe because `ptr` has to point at a complete boot information structure
+	pub unsafe fn from_ptr(ptr: *const u8) -> Self {
+		let total_size = unsafe { (ptr as *const u32).read_unaligned() } as usize;
+		MultibootInfo { bytes: unsafe { core::slice::from_raw_parts(ptr, total_size) } }
+	}
+
+	/// Parses the structure at `ptr`, see `from_ptr`
+	pub unsafe fn parse(ptr: *const u8) -> BootParams {
+		unsafe { MultibootInfo::from_ptr(ptr) }.boot_params()
+	}
+
+	/// the tags as (type, body) pairs, up to the end tag or the first broken one
+	pub fn tags(&self) -> impl Iterator<Item = (u32, &'a [u8])> {
+		let bytes = self.bytes;
+		// the tags start after total_size and a reserved word
+		let mut offset = 8;
+
+		core::iter::from_fn(move || {
+			let kind = read_u32(bytes, offset)?;
+			let size = read_u32(bytes, offset + 4)? as usize;
+			if kind == TAG_END || size < 8 {
+				return None;
+			}
+			let body = bytes.get(offset + 8..offset + size)?;
+			// every tag starts 8 byte aligned
+			offset += (size + 7) & !7;
+			Some((kind, body))
+		})
+	}
+
+	pub fn boot_params(&self) -> BootParams {
+		let empty = MemRegion { start: 0, end: 0, kind: 0 };
+		let mut params =
+			BootParams { regions: [empty; MAX_REGIONS], region_count: 0, framebuffer: None };
+
+		for (kind, body) in self.tags() {
+			match kind {
+				TAG_MEMORY_MAP => parse_memory_map(body, &mut params),
+				TAG_FRAMEBUFFER => params.framebuffer = parse_framebuffer(body),
+				_ => {},
+			}
+		}
+		params
+	}
+}
+
+/// entry_size, entry_version, then entries of base, length, type, reserved
+fn parse_memory_map(
+	body: &[u8],
+	params: &mut BootParams,
+) {
+	let entry_size = match read_u32(body, 0) {
+		Some(size) if size >= 20 => size as usize,
+		_ => return,
+	};
+
+	let entries = match body.get(8..) {
+		Some(entries) => entries,
+		None => return,
+	};
+
+	for entry in entries.chunks_exact(entry_size) {
+		if params.region_count == MAX_REGIONS {
+			return;
+		}
+		let (base, length, kind) =
+			match (read_u64(entry, 0), read_u64(entry, 8), read_u32(entry, 16)) {
+				(Some(base), Some(length), Some(kind)) => (base, length, kind),
+				_ => return,
+			};
+		params.regions[params.region_count] =
+			MemRegion { start: base, end: base.saturating_add(length), kind };
+		params.region_count += 1;
+	}
+}
+
+/// address, pitch, width, height, bpp, then the color info we don't need
+fn parse_framebuffer(body: &[u8]) -> Option<FramebufferInfo> {
+	Some(FramebufferInfo {
+		addr: read_u64(body, 0)?,
+		pitch: read_u32(body, 8)?,
+		width: read_u32(body, 12)?,
+		height: read_u32(body, 16)?,
+		bpp: *body.get(20)?,
+	})
+}
+
+#[test_case]
+fn test_parse_boot_information() {
+	#[repr(C, align(8))]
+	struct Info([u8; 128]);
+
+	fn put(
+		buf: &mut [u8],
+		offset: usize,
+		bytes: &[u8],
+	) {
+		buf[offset..offset + bytes.len()].copy_from_slice(bytes);
+	}
+
+	let mut info = Info([0; 128]);
+	let buf = &mut info.0;
+
+	// memory map tag at 8: 16 byte header plus two 24 byte entries
+	put(buf, 8, &TAG_MEMORY_MAP.to_le_bytes());
+	put(buf, 12, &64u32.to_le_bytes());
+	put(buf, 16, &24u32.to_le_bytes());
+	put(buf, 24, &0u64.to_le_bytes());
+	put(buf, 32, &0x9fc00u64.to_le_bytes());
+	put(buf, 40, &MEMORY_AVAILABLE.to_le_bytes());
+	put(buf, 48, &0x10_0800u64.to_le_bytes());
+	put(buf, 56, &0x100_0000u64.to_le_bytes());
+	put(buf, 64, &2u32.to_le_bytes());
+
+	// framebuffer tag at 72
+	put(buf, 72, &TAG_FRAMEBUFFER.to_le_bytes());
+	put(buf, 76, &31u32.to_le_bytes());
+	put(buf, 80, &0xfd00_0000u64.to_le_bytes());
+	put(buf, 88, &4096u32.to_le_bytes());
+	put(buf, 92, &1024u32.to_le_bytes());
+	put(buf, 96, &768u32.to_le_bytes());
+	buf[100] = 32;
+
+	// end tag at 104, then the total size
+	put(buf, 104, &TAG_END.to_le_bytes());
+	put(buf, 108, &8u32.to_le_bytes());
+	put(buf, 0, &112u32.to_le_bytes());
+
+	let params = unsafe { MultibootInfo::parse(info.0.as_ptr()) };
+
+	assert_eq!(params.regions().len(), 2);
+	assert_eq!(params.regions()[0], MemRegion { start: 0, end: 0x9fc00, kind: 1 });
+	assert!(params.regions()[0].is_usable());
+	assert!(!params.regions()[1].is_usable());
+
+	let fb = params.framebuffer.expect("no framebuffer");
+	assert_eq!(
+		(fb.addr, fb.pitch, fb.width, fb.height, fb.bpp),
+		(0xfd00_0000, 4096, 1024, 768, 32)
+	);
+
+	// whole frames only, the usable region loses its partial last frame
+	let map = params.memory_map();
+	let usable = map.iter().find(|r| r.region_type == MemoryRegionType::Usable).unwrap();
+	assert_eq!(usable.range.start_addr(), 0);
+	assert_eq!(usable.range.end_addr(), 0x9f000);
+	let reserved = map.iter().find(|r| r.region_type == MemoryRegionType::Reserved).unwrap();
+	assert_eq!(reserved.range.start_addr(), 0x10_0000);
+
+	// cut short, total_size says there's more
+	assert!(MultibootInfo::from_bytes(&info.0[..100]).is_none());
+}
+
+#[test_case]
+fn test_memory_map_skips_regions_at_the_top() {
+	let empty = MemRegion { start: 0, end: 0, kind: 0 };
+	let mut params =
+		BootParams { regions: [empty; MAX_REGIONS], region_count: 3, framebuffer: None };
+	params.regions[0] = MemRegion { start: 0x1000, end: 0x3000, kind: MEMORY_AVAILABLE };
+	// neither can be rounded up to a frame boundary
+	params.regions[1] =
+		MemRegion { start: u64::MAX - 0x800, end: u64::MAX, kind: MEMORY_AVAILABLE };
+	params.regions[2] = MemRegion { start: 0xffff_0000, end: u64::MAX, kind: MEMORY_BAD };
+
+	let map = params.memory_map();
+	assert_eq!(map.iter().count(), 1);
+	assert_eq!(map.iter().next().unwrap().range.start_addr(), 0x1000);
+}
@@ -0,0 +1,164 @@
+//! in src/net/arp.rs
+//!
+//! ARP for IPv4 over Ethernet, just enough to find the MAC behind an IP address.
+
+use super::{
+	Ipv4Addr, MacAddr, VirtioNet,
+	ethernet::{self, ETHERNET_MIN_FRAME_LEN, EtherType, EthernetFrame},
+};
+use crate::interrupts;
+use crate::task::timer::{TICKS_PER_SECOND, sleep_ticks};
+use alloc::{collections::BTreeMap, vec::Vec};
+
+/// size of an ARP packet for IPv4 over Ethernet
+pub const ARP_PACKET_LEN: usize = 28;
+
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const HTYPE_ETHERNET: u16 = 1;
+
+const OPER_REQUEST: u16 = 1;
+const OPER_REPLY: u16 = 2;
+
+/// Known IPv4 -> MAC mappings
+#[derive(Debug, Default)]
+pub struct ArpCache {
+	entries: BTreeMap<Ipv4Addr, MacAddr>,
+}
+
+impl ArpCache {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn get(
+		&self,
+		ip: Ipv4Addr,
+	) -> Option<MacAddr> {
+		self.entries.get(&ip).copied()
+	}
+
+	pub fn insert(
+		&mut self,
+		ip: Ipv4Addr,
+		mac: MacAddr,
+	) {
+		self.entries.insert(ip, mac);
+	}
+
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.entries.is_empty()
+	}
+}
+
+/// Builds the ARP payload asking who has `target_ip`
+///
+/// Goes into an Ethernet frame with ethertype 0x0806, sent to the broadcast address
+pub fn arp_request(
+	target_ip: Ipv4Addr,
+	sender_ip: Ipv4Addr,
+	sender_mac: MacAddr,
+) -> [u8; ARP_PACKET_LEN] {
+	let mut packet = [0u8; ARP_PACKET_LEN];
+
+	packet[0..2].copy_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+	packet[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+	packet[4] = 6; // hardware address length
+	packet[5] = 4; // protocol address length
+	packet[6..8].copy_from_slice(&OPER_REQUEST.to_be_bytes());
+	packet[8..14].copy_from_slice(&sender_mac.0);
+	packet[14..18].copy_from_slice(&sender_ip.octets());
+	// target MAC stays zero, that's what we're asking for
+	packet[24..28].copy_from_slice(&target_ip.octets());
+
+	packet
+}
+
+/// Records the sender of an ARP reply in the cache
+///
+/// `frame` is the whole Ethernet frame, anything that isn't an ARP reply for IPv4 over Ethernet
+/// is ignored.
+pub fn handle_arp_reply(
+	frame: &[u8],
+	cache: &mut ArpCache,
+) {
+	let frame = match EthernetFrame::new(frame) {
+		Some(frame) if frame.ethertype() == EtherType::Arp => frame,
+		_ => return,
+	};
+
+	if frame.payload().len() < ARP_PACKET_LEN {
+		return;
+	}
+
+	let packet = &frame.payload()[..ARP_PACKET_LEN];
+	let be16 = |at: usize| u16::from_be_bytes([packet[at], packet[at + 1]]);
+
+	let is_reply = be16(0) == HTYPE_ETHERNET
+		&& be16(2) == ETHERTYPE_IPV4
+		&& packet[4] == 6
+		&& packet[5] == 4
+		&& be16(6) == OPER_REPLY;
+	if !is_reply {
+		return;
+	}
+
+	let mut mac = [0u8; 6];
+	mac.copy_from_slice(&packet[8..14]);
+	let ip = Ipv4Addr::new(packet[14], packet[15], packet[16], packet[17]);
+
+	cache.insert(ip, MacAddr(mac));
+}
+
+/// Wraps an ARP request into a broadcast Ethernet frame
+fn request_frame(
+	target_ip: Ipv4Addr,
+	sender_ip: Ipv4Addr,
+	sender_mac: MacAddr,
+) -> Vec<u8> {
+	let packet = arp_request(target_ip, sender_ip, sender_mac);
+	let mut frame = ethernet::build(sender_mac.0, MacAddr::BROADCAST.0, EtherType::Arp, &packet);
+
+	// ARP is shorter than the minimum frame size
+	frame.resize(ETHERNET_MIN_FRAME_LEN, 0);
+	frame
+}
+
+/// Looks up the MAC of `ip`, asking the network if it isn't cached
+///
+/// Gives up after about a second without a reply. Frames that aren't ARP replies are dropped
+/// while we wait.
+pub async fn resolve(
+	ip: Ipv4Addr,
+	cache: &mut ArpCache,
+	net: &mut VirtioNet,
+) -> Option<MacAddr> {
+	if let Some(mac) = cache.get(ip) {
+		return Some(mac);
+	}
+
+	let frame = request_frame(ip, net.ipv4_addr(), net.mac());
+	if let Err(e) = net.send(&frame) {
+		crate::println!("[ARP] failed to send request for {}: {:?}", ip, e);
+		return None;
+	}
+
+	let deadline = interrupts::ticks() + TICKS_PER_SECOND;
+	while interrupts::ticks() < deadline {
+		while let Some(frame) = net.receive() {
+			handle_arp_reply(&frame, cache);
+		}
+
+		if let Some(mac) = cache.get(ip) {
+			return Some(mac);
+		}
+
+		sleep_ticks(1).await;
+	}
+
+	None
+}
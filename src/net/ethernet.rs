@@ -0,0 +1,111 @@
+//! in src/net/ethernet.rs
+//!
+//! Ethernet II frames, without the FCS (the NIC strips and appends that one).
+
+use alloc::vec::Vec;
+use sa::const_assert;
+use zerocopy::{
+	FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned,
+	byteorder::{BE, U16},
+};
+
+/// destination + source MAC + ethertype
+pub const ETHERNET_HEADER_LEN: usize = 14;
+
+/// frames shorter than this (without the FCS) have to be padded before they go on the wire
+pub const ETHERNET_MIN_FRAME_LEN: usize = 60;
+
+/// What the payload of a frame is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EtherType {
+	Ipv4,
+	Arp,
+	Ipv6,
+	Unknown(u16),
+}
+
+impl From<u16> for EtherType {
+	fn from(value: u16) -> Self {
+		match value {
+			0x0800 => EtherType::Ipv4,
+			0x0806 => EtherType::Arp,
+			0x86DD => EtherType::Ipv6,
+			other => EtherType::Unknown(other),
+		}
+	}
+}
+
+impl From<EtherType> for u16 {
+	fn from(value: EtherType) -> Self {
+		match value {
+			EtherType::Ipv4 => 0x0800,
+			EtherType::Arp => 0x0806,
+			EtherType::Ipv6 => 0x86DD,
+			EtherType::Unknown(other) => other,
+		}
+	}
+}
+
+/// The fixed part at the start of every frame, everything in network byte order
+#[derive(Debug, Copy, Clone, IntoBytes, FromBytes, Immutable, KnownLayout, Unaligned)]
+#[repr(C)]
+pub struct EthernetHeader {
+	pub dst: [u8; 6],
+	pub src: [u8; 6],
+	pub ethertype: U16<BE>,
+}
+
+const_assert!(core::mem::size_of::<EthernetHeader>() == ETHERNET_HEADER_LEN);
+
+/// A received frame, borrowed from the rx buffer
+#[derive(Debug, Clone, Copy)]
+pub struct EthernetFrame<'a> {
+	raw: &'a [u8],
+}
+
+impl<'a> EthernetFrame<'a> {
+	/// Wraps a raw frame, None if it's too short to even hold the header
+	pub fn new(raw: &'a [u8]) -> Option<Self> {
+		if raw.len() < ETHERNET_HEADER_LEN {
+			return None;
+		}
+		Some(EthernetFrame { raw })
+	}
+
+	fn header(&self) -> &'a EthernetHeader {
+		// the length was checked in new()
+		EthernetHeader::ref_from_bytes(&self.raw[..ETHERNET_HEADER_LEN]).unwrap()
+	}
+
+	pub fn src_mac(&self) -> [u8; 6] {
+		self.header().src
+	}
+
+	pub fn dst_mac(&self) -> [u8; 6] {
+		self.header().dst
+	}
+
+	pub fn ethertype(&self) -> EtherType {
+		EtherType::from(self.header().ethertype.get())
+	}
+
+	/// everything after the header, including any padding the sender added
+	pub fn payload(&self) -> &'a [u8] {
+		&self.raw[ETHERNET_HEADER_LEN..]
+	}
+}
+
+/// Builds an outbound frame around `payload`
+pub fn build(
+	src: [u8; 6],
+	dst: [u8; 6],
+	ethertype: EtherType,
+	payload: &[u8],
+) -> Vec<u8> {
+	let header = EthernetHeader { dst, src, ethertype: U16::new(ethertype.into()) };
+
+	let mut frame = Vec::with_capacity(ETHERNET_HEADER_LEN + payload.len());
+	frame.extend_from_slice(header.as_bytes());
+	frame.extend_from_slice(payload);
+	frame
+}
@@ -0,0 +1,88 @@
+//! in src/net/icmp.rs
+//!
+//! Answers pings, nothing else of ICMP is handled.
+
+use super::{
+	Ipv4Addr, MacAddr, VirtioNet,
+	ethernet::{self, EtherType},
+	ipv4::{self, DEFAULT_TTL, IPV4_HEADER_LEN, IpProto},
+};
+use alloc::vec::Vec;
+use zerocopy::IntoBytes;
+
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+
+/// type, code, checksum, identifier, sequence number
+const ICMP_ECHO_HEADER_LEN: usize = 8;
+
+/// Turns an echo request into the matching reply, None for anything else
+///
+/// Identifier, sequence number and data are copied over as they are, only the type and the
+/// checksum change. Requests with a bad checksum are dropped.
+pub fn echo_reply(request: &[u8]) -> Option<Vec<u8>> {
+	if request.len() < ICMP_ECHO_HEADER_LEN || request[0] != ICMP_ECHO_REQUEST || request[1] != 0 {
+		return None;
+	}
+	if ipv4::checksum(request) != 0 {
+		return None;
+	}
+
+	let mut reply = request.to_vec();
+	reply[0] = ICMP_ECHO_REPLY;
+	reply[2..4].copy_from_slice(&[0, 0]);
+
+	let sum = ipv4::checksum(&reply);
+	reply[2..4].copy_from_slice(&sum.to_be_bytes());
+
+	Some(reply)
+}
+
+/// The whole Ethernet frame answering `request` from `src_ip`/`src_mac`
+pub fn echo_reply_frame(
+	request: &[u8],
+	src_ip: Ipv4Addr,
+	src_mac: MacAddr,
+	our_ip: Ipv4Addr,
+	our_mac: MacAddr,
+) -> Option<Vec<u8>> {
+	let reply = echo_reply(request)?;
+	let header = ipv4::build(our_ip, src_ip, IpProto::Icmp, DEFAULT_TTL, reply.len() as u16);
+
+	let mut packet = Vec::with_capacity(IPV4_HEADER_LEN + reply.len());
+	packet.extend_from_slice(header.as_bytes());
+	packet.extend_from_slice(&reply);
+
+	Some(ethernet::build(our_mac.0, src_mac.0, EtherType::Ipv4, &packet))
+}
+
+/// Answers an echo request that came in from `src_ip`
+///
+/// `payload` is the ICMP message, i.e. the IPv4 payload cut to the header's total length so
+/// Ethernet padding doesn't end up in the checksum. The reply goes back to `src_mac`, the
+/// sender of the frame.
+pub fn handle_icmp(
+	payload: &[u8],
+	src_ip: Ipv4Addr,
+	src_mac: MacAddr,
+	net: &mut VirtioNet,
+) {
+	let frame = match echo_reply_frame(payload, src_ip, src_mac, net.ipv4_addr(), net.mac()) {
+		Some(frame) => frame,
+		None => return,
+	};
+
+	let id = u16::from_be_bytes([payload[4], payload[5]]);
+	let seq = u16::from_be_bytes([payload[6], payload[7]]);
+	crate::serial_println!(
+		"[ICMP] echo request from {} id={} seq={} ({} bytes)",
+		src_ip,
+		id,
+		seq,
+		payload.len()
+	);
+
+	if let Err(e) = net.send(&frame) {
+		crate::serial_println!("[ICMP] failed to send reply to {}: {:?}", src_ip, e);
+	}
+}
@@ -0,0 +1,156 @@
+//! in src/net/ipv4.rs
+//!
+//! IPv4 headers without options, which is all we send and all QEMU sends us.
+
+use super::Ipv4Addr;
+use sa::const_assert;
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned};
+
+/// a header without options
+pub const IPV4_HEADER_LEN: usize = 20;
+
+/// what we put in the TTL when nobody asks for anything else
+pub const DEFAULT_TTL: u8 = 64;
+
+/// don't fragment, we never send anything bigger than a frame
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+
+/// What the payload of a packet is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpProto {
+	Icmp,
+	Tcp,
+	Udp,
+	Unknown(u8),
+}
+
+impl From<u8> for IpProto {
+	fn from(value: u8) -> Self {
+		match value {
+			1 => IpProto::Icmp,
+			6 => IpProto::Tcp,
+			17 => IpProto::Udp,
+			other => IpProto::Unknown(other),
+		}
+	}
+}
+
+impl From<IpProto> for u8 {
+	fn from(value: IpProto) -> Self {
+		match value {
+			IpProto::Icmp => 1,
+			IpProto::Tcp => 6,
+			IpProto::Udp => 17,
+			IpProto::Unknown(other) => other,
+		}
+	}
+}
+
+/// The fixed 20 bytes at the start of a packet, in network byte order
+#[derive(
+	Debug, Copy, Clone, PartialEq, Eq, IntoBytes, FromBytes, Immutable, KnownLayout, Unaligned,
+)]
+#[repr(C)]
+pub struct Ipv4Header {
+	raw: [u8; IPV4_HEADER_LEN],
+}
+
+const_assert!(core::mem::size_of::<Ipv4Header>() == IPV4_HEADER_LEN);
+
+impl Ipv4Header {
+	/// Copies the header out of the start of `packet`, None if it's too short
+	///
+	/// Doesn't validate anything, use `version`, `ihl` and `verify_checksum` for that.
+	pub fn parse(packet: &[u8]) -> Option<Self> {
+		Self::read_from_prefix(packet).ok().map(|(header, _)| header)
+	}
+
+	fn be16(
+		&self,
+		at: usize,
+	) -> u16 {
+		u16::from_be_bytes([self.raw[at], self.raw[at + 1]])
+	}
+
+	pub fn version(&self) -> u8 {
+		self.raw[0] >> 4
+	}
+
+	/// header length in 32-bit words
+	pub fn ihl(&self) -> u8 {
+		self.raw[0] & 0x0F
+	}
+
+	/// header and payload, in bytes
+	pub fn total_length(&self) -> u16 {
+		self.be16(2)
+	}
+
+	pub fn ttl(&self) -> u8 {
+		self.raw[8]
+	}
+
+	pub fn protocol(&self) -> IpProto {
+		IpProto::from(self.raw[9])
+	}
+
+	pub fn src_addr(&self) -> Ipv4Addr {
+		Ipv4Addr::new(self.raw[12], self.raw[13], self.raw[14], self.raw[15])
+	}
+
+	pub fn dst_addr(&self) -> Ipv4Addr {
+		Ipv4Addr::new(self.raw[16], self.raw[17], self.raw[18], self.raw[19])
+	}
+
+	/// summing a header with the right checksum in it gives zero
+	pub fn verify_checksum(&self) -> bool {
+		checksum(&self.raw) == 0
+	}
+}
+
+/// Builds the header for a packet carrying `payload_len` bytes, checksum included
+pub fn build(
+	src: Ipv4Addr,
+	dst: Ipv4Addr,
+	proto: IpProto,
+	ttl: u8,
+	payload_len: u16,
+) -> Ipv4Header {
+	let mut raw = [0u8; IPV4_HEADER_LEN];
+
+	raw[0] = 0x45; // version 4, 5 words of header
+	raw[2..4].copy_from_slice(&(IPV4_HEADER_LEN as u16 + payload_len).to_be_bytes());
+	// identification stays zero, nothing gets fragmented
+	raw[6..8].copy_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+	raw[8] = ttl;
+	raw[9] = proto.into();
+	raw[12..16].copy_from_slice(&src.octets());
+	raw[16..20].copy_from_slice(&dst.octets());
+
+	let sum = checksum(&raw);
+	raw[10..12].copy_from_slice(&sum.to_be_bytes());
+
+	Ipv4Header { raw }
+}
+
+/// The RFC 1071 internet checksum, also used by ICMP, UDP and TCP
+///
+/// An odd trailing byte is padded with zero.
+pub fn checksum(data: &[u8]) -> u16 {
+	let mut sum: u32 = 0;
+
+	let mut words = data.chunks_exact(2);
+	for word in &mut words {
+		sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+	}
+	if let [last] = words.remainder() {
+		sum += u32::from(*last) << 8;
+	}
+
+	// fold the carries back in
+	while sum > 0xFFFF {
+		sum = (sum & 0xFFFF) + (sum >> 16);
+	}
+
+	!(sum as u16)
+}
@@ -0,0 +1,95 @@
+//! in src/net/mod.rs
+
+pub mod arp;
+pub mod ethernet;
+pub mod icmp;
+pub mod ipv4;
+
+use crate::virtio::OsHal;
+use alloc::vec::Vec;
+use core::fmt;
+pub use core::net::Ipv4Addr;
+use virtio_drivers::{Error as VirtIOError, device::net::VirtIONet, transport::pci::PciTransport};
+
+/// A 48-bit Ethernet hardware address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+	pub const BROADCAST: MacAddr = MacAddr([0xFF; 6]);
+	pub const ZERO: MacAddr = MacAddr([0; 6]);
+}
+
+impl fmt::Display for MacAddr {
+	fn fmt(
+		&self,
+		f: &mut fmt::Formatter,
+	) -> fmt::Result {
+		let m = self.0;
+		write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", m[0], m[1], m[2], m[3], m[4], m[5])
+	}
+}
+
+/// size of the rx/tx virtqueues
+const NET_QUEUE_SIZE: usize = 16;
+
+/// big enough for a full Ethernet frame
+const NET_BUFFER_LEN: usize = 2048;
+
+/// QEMU's user networking hands this address to the first guest
+pub const DEFAULT_IPV4_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+
+/// A VirtIO network card together with the IPv4 address we use on it
+pub struct VirtioNet {
+	device: VirtIONet<OsHal, PciTransport, NET_QUEUE_SIZE>,
+	ip: Ipv4Addr,
+}
+
+impl VirtioNet {
+	pub fn new(transport: PciTransport) -> Result<Self, VirtIOError> {
+		let device = VirtIONet::new(transport, NET_BUFFER_LEN)?;
+		Ok(VirtioNet { device, ip: DEFAULT_IPV4_ADDR })
+	}
+
+	pub fn mac(&self) -> MacAddr {
+		MacAddr(self.device.mac_address())
+	}
+
+	pub fn ipv4_addr(&self) -> Ipv4Addr {
+		self.ip
+	}
+
+	pub fn set_ipv4_addr(
+		&mut self,
+		ip: Ipv4Addr,
+	) {
+		self.ip = ip;
+	}
+
+	/// sends a complete Ethernet frame
+	pub fn send(
+		&mut self,
+		frame: &[u8],
+	) -> Result<(), VirtIOError> {
+		let mut tx = self.device.new_tx_buffer(frame.len());
+		tx.packet_mut().copy_from_slice(frame);
+		self.device.send(tx)
+	}
+
+	/// returns the next received frame, if there is one
+	pub fn receive(&mut self) -> Option<Vec<u8>> {
+		if !self.device.can_recv() {
+			return None;
+		}
+
+		let rx = self.device.receive().ok()?;
+		let frame = rx.packet().to_vec();
+
+		// hand the buffer back to the device or we run out of them
+		if let Err(e) = self.device.recycle_rx_buffer(rx) {
+			crate::println!("[NET] failed to recycle rx buffer: {:?}", e);
+		}
+
+		Some(frame)
+	}
+}
@@ -0,0 +1,291 @@
+// in src/panic_record.rs
+//
+// what a panic leaves on the disk, so it survives QEMU exiting
+
+use crate::fs::{
+	block_dev::{BlockDevice, DirtyState},
+	layout::BLOCK_SIZE,
+	simple_fs::FileSystemError,
+};
+use core::{
+	arch::asm,
+	fmt::{self, Write},
+	panic::PanicInfo,
+};
+use sa::const_assert;
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+/// the first bytes of a valid record
+pub const PANIC_MAGIC: [u8; 4] = *b"PANC";
+
+/// bytes of the panic message kept, the rest is cut off
+pub const MESSAGE_LEN: usize = 256;
+
+/// return addresses kept, innermost first
+pub const BACKTRACE_DEPTH: usize = 20;
+
+/// One panic, laid out the way it sits in the device's last block
+///
+/// All little endian, repr(C) without padding, so a host tool can read it with the same struct.
+/// The rest of the block after it is zero.
+#[derive(Debug, Clone, Copy, IntoBytes, FromBytes, Immutable, KnownLayout)]
+#[repr(C)]
+pub struct PanicRecord {
+	pub magic: [u8; 4],
+	/// bytes of `message` that are used
+	pub message_len: u32,
+	/// timer ticks since boot
+	pub timestamp: u64,
+	pub rip: u64,
+	/// utf-8, zero padded
+	pub message: [u8; MESSAGE_LEN],
+	/// return addresses, the first zero ends the trace
+	pub backtrace: [[u64; BACKTRACE_DEPTH]; 1],
+	/// the heap's high water mark in bytes, 0 if the heap was locked
+	pub allocator_peak: u64,
+}
+
+const_assert!(core::mem::size_of::<PanicRecord>() <= BLOCK_SIZE);
+
+/// a fmt::Write into a fixed buffer that drops whatever doesn't fit
+struct Truncating<'a> {
+	buf: &'a mut [u8],
+	len: usize,
+}
+
+impl Write for Truncating<'_> {
+	fn write_str(
+		&mut self,
+		s: &str,
+	) -> fmt::Result {
+		let room = self.buf.len() - self.len;
+		// don't split a character
+		let mut take = s.len().min(room);
+		while !s.is_char_boundary(take) {
+			take -= 1;
+		}
+		self.buf[self.len..self.len + take].copy_from_slice(&s.as_bytes()[..take]);
+		self.len += take;
+		Ok(())
+	}
+}
+
+impl PanicRecord {
+	/// Builds a record from a message, the address the panic came from and the trace
+	///
+	/// Frames past `BACKTRACE_DEPTH` are dropped.
+	pub fn new(
+		message: impl fmt::Display,
+		rip: u64,
+		backtrace: &[u64],
+	) -> Self {
+		let mut record = PanicRecord {
+			magic: PANIC_MAGIC,
+			message_len: 0,
+			timestamp: crate::interrupts::ticks(),
+			rip,
+			message: [0; MESSAGE_LEN],
+			backtrace: [[0; BACKTRACE_DEPTH]; 1],
+			allocator_peak: crate::allocator::try_allocator_stats().map_or(0, |s| s.peak as u64),
+		};
+
+		let mut writer = Truncating { buf: &mut record.message, len: 0 };
+		// Truncating never fails, a Display impl erroring just ends the message early
+		let _ = write!(writer, "{}", message);
+		record.message_len = writer.len as u32;
+
+		let frames = backtrace.len().min(BACKTRACE_DEPTH);
+		record.backtrace[0][..frames].copy_from_slice(&backtrace[..frames]);
+		record
+	}
+
+	pub fn is_valid(&self) -> bool {
+		self.magic == PANIC_MAGIC && self.message_len as usize <= MESSAGE_LEN
+	}
+
+	/// the message, up to the first invalid utf-8 if the record is damaged
+	pub fn message(&self) -> &str {
+		let bytes = &self.message[..(self.message_len as usize).min(MESSAGE_LEN)];
+		match core::str::from_utf8(bytes) {
+			Ok(message) => message,
+			Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or_default(),
+		}
+	}
+
+	pub fn backtrace(&self) -> &[u64] {
+		let frames = &self.backtrace[0];
+		let len = frames.iter().position(|&ret| ret == 0).unwrap_or(BACKTRACE_DEPTH);
+		&frames[..len]
+	}
+
+	/// Writes the record into the device's last block and flushes the device
+	///
+	/// Whatever else uses the device has to stay out of that block, see `PanicReserved`.
+	pub fn write_to(
+		&self,
+		device: &mut impl BlockDevice,
+	) -> Result<(), FileSystemError> {
+		if device.is_read_only() {
+			return Err(FileSystemError::ReadOnly);
+		}
+
+		let block_id = record_block(device)?;
+		let mut block = [0u8; BLOCK_SIZE];
+		block[..core::mem::size_of::<Self>()].copy_from_slice(self.as_bytes());
+
+		device.write_blocks(block_id, &block)?;
+		device.flush()
+	}
+
+	/// Reads back whatever the last block holds, None if that's no panic record
+	pub fn read_from_device(
+		device: &mut impl BlockDevice
+	) -> Result<Option<Self>, FileSystemError> {
+		let block_id = record_block(device)?;
+		let mut block = [0u8; BLOCK_SIZE];
+		device.read_blocks(block_id, &mut block)?;
+
+		let (record, _) =
+			PanicRecord::read_from_prefix(&block).map_err(|_| FileSystemError::BlockError)?;
+		Ok(Some(record).filter(PanicRecord::is_valid))
+	}
+}
+
+/// the device's last block, where the record lives
+fn record_block(device: &impl BlockDevice) -> Result<u64, FileSystemError> {
+	(device.capacity() as u64).checked_sub(1).ok_or(FileSystemError::BlockError)
+}
+
+/// Walks the frame pointer chain, fills `out` with return addresses and returns how many
+///
+/// Relies on the kernel being built with frame pointers, like the panic handler's trace always
+/// did.
+#[inline(never)]
+pub fn capture_backtrace(out: &mut [u64]) -> usize {
+	let mut rbp: u64;
+	unsafe {
+		asm!("mov {rbp}, rbp", rbp = out(reg) rbp, options(nomem, nostack, preserves_flags));
+	}
+
+	let mut count = 0;
+	while rbp != 0 && count < out.len() {
+		// return address is saved at [RBP + 8], the previous frame's RBP at [RBP]
+		out[count] = unsafe { *((rbp + 8) as *const u64) };
+		rbp = unsafe { *(rbp as *const u64) };
+		count += 1;
+	}
+	count
+}
+
+/// Saves the panic, where it happened and a backtrace into the device's last block
+///
+/// For the panic handler, so a host tool can pick the record up after QEMU is gone. The heap
+/// isn't touched.
+pub fn serialize_panic_to_disk(
+	info: &PanicInfo,
+	device: &mut impl BlockDevice,
+) -> Result<(), FileSystemError> {
+	let rip: u64;
+	unsafe {
+		asm!("lea {rip}, [rip]", rip = out(reg) rip, options(nomem, nostack, preserves_flags));
+	}
+
+	let mut backtrace = [0; BACKTRACE_DEPTH];
+	let frames = capture_backtrace(&mut backtrace);
+
+	PanicRecord::new(info, rip, &backtrace[..frames]).write_to(device)
+}
+
+/// A device with its last block hidden, so a filesystem on it never overwrites the panic record
+///
+/// A disk formatted on the bare device is one block too big for this, `whole` mounts it as it is.
+pub struct PanicReserved<D> {
+	device: D,
+	/// false for `whole`, the last block belongs to the filesystem then
+	reserved: bool,
+}
+
+impl<D: BlockDevice> PanicReserved<D> {
+	pub fn new(device: D) -> Self {
+		PanicReserved { device, reserved: true }
+	}
+
+	/// Hides nothing, for a filesystem that was formatted on the bare device
+	///
+	/// It uses the last block itself, so there's no room for a panic record on that disk.
+	pub fn whole(device: D) -> Self {
+		PanicReserved { device, reserved: false }
+	}
+
+	/// true if the last block is kept out of the filesystem's way
+	pub fn has_record_block(&self) -> bool {
+		self.reserved
+	}
+
+	/// the device underneath, last block included
+	pub fn inner_mut(&mut self) -> &mut D {
+		&mut self.device
+	}
+
+	fn check_request(
+		&self,
+		block_id: u64,
+		len: usize,
+	) -> Result<(), FileSystemError> {
+		let end = block_id.checked_add((len / BLOCK_SIZE) as u64);
+		match end {
+			Some(end) if end <= self.capacity() as u64 => Ok(()),
+			_ => Err(FileSystemError::BlockError),
+		}
+	}
+}
+
+impl<D: BlockDevice> BlockDevice for PanicReserved<D> {
+	fn read_blocks(
+		&mut self,
+		block_id: u64,
+		buffer: &mut [u8],
+	) -> Result<(), FileSystemError> {
+		self.check_request(block_id, buffer.len())?;
+		self.device.read_blocks(block_id, buffer)
+	}
+
+	fn write_blocks(
+		&mut self,
+		block_id: u64,
+		buffer: &[u8],
+	) -> Result<(), FileSystemError> {
+		self.check_request(block_id, buffer.len())?;
+		self.device.write_blocks(block_id, buffer)
+	}
+
+	fn capacity(&self) -> usize {
+		match self.reserved {
+			true => self.device.capacity().saturating_sub(1),
+			false => self.device.capacity(),
+		}
+	}
+
+	fn sync_blocks(
+		&mut self,
+		blocks: &[u64],
+	) -> Result<(), FileSystemError> {
+		self.device.sync_blocks(blocks)
+	}
+
+	fn sync_all(&mut self) -> Result<(), FileSystemError> {
+		self.device.sync_all()
+	}
+
+	fn dirty_state(&self) -> DirtyState {
+		self.device.dirty_state()
+	}
+
+	fn is_read_only(&self) -> bool {
+		self.device.is_read_only()
+	}
+
+	fn flush(&mut self) -> Result<(), FileSystemError> {
+		self.device.flush()
+	}
+}
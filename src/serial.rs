@@ -1,31 +1,514 @@
 use uart_16550::SerialPort;
 use spin::Mutex;
 use lazy_static::lazy_static;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use crate::hw::ports::{self, ClaimedPort};
+use crate::sync::RingBuf;
+use crate::task::channel::{self, Sender};
+use conquer_once::spin::OnceCell;
+use futures_util::stream::Stream;
+
+/// standard port number for the first serial interface
+const COM1: u16 = 0x3F8;
+
+/// the UART divides this clock by the divisor latch to get the baud rate
+const UART_CLOCK: u32 = 115200;
+
+/// What `SerialPort::init` programs (divisor 3), always 8N1
+pub const DEFAULT_BAUD: u32 = 38400;
+
+// register offsets from the base port, the first two are the divisor latch while DLAB is set
+const DIVISOR_LOW: u16 = 0;
+const DIVISOR_HIGH: u16 = 1;
+const LINE_CONTROL: u16 = 3;
+const RECEIVE: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const LINE_STATUS: u16 = 5;
+
+/// interrupt enable bit for "received data available"
+const IER_RECEIVED_DATA: u8 = 1;
+
+/// interrupt enable bit for "transmit holding register empty"
+const IER_TRANSMIT_EMPTY: u8 = 1 << 1;
+
+/// COM1 raises IRQ 4
+const COM1_IRQ: u8 = 4;
+
+/// how many received bytes can pile up before the interrupt handler starts dropping them
+const RX_CAPACITY: usize = 128;
+
+/// a received byte is waiting in the receive register
+const LINE_STATUS_DATA_READY: u8 = 1;
+
+/// the transmit FIFO is empty, `TX_FIFO_DEPTH` bytes can go without waiting
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// the 16550's transmit FIFO, SerialPort::init turns it on
+const TX_FIFO_DEPTH: usize = 16;
+
+/// bytes of output that can wait for the UART before the oldest ones get dropped
+pub const TX_CAPACITY: usize = 4096;
+
+const TRANSMIT: u16 = 0;
+
+/// divisor latch access bit in the line control register
+const DLAB: u8 = 0x80;
+
+/// baud rate SERIAL1 runs at, or will run at once it's initialized
+static BAUD: AtomicU32 = AtomicU32::new(DEFAULT_BAUD);
+
+/// set once SERIAL1 went through its lazy initialization
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// COM1's eight registers, claimed when SERIAL1 comes up
+///
+/// uart_16550 still does its own data and status port accesses, the claim keeps other drivers
+/// off COM1 and is what our own register pokes go through.
+static PORTS: spin::Once<ClaimedPort> = spin::Once::new();
+
+/// hands received bytes from the interrupt handler to `serial_read_stream`
+static RX_SENDER: OnceCell<Sender<u8>> = OnceCell::uninit();
+
+/// bytes the receive interrupt had no room for
+static RX_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// output waiting for the UART, only touched with interrupts off
+static TX_RING: Mutex<TxRing> = Mutex::new(TxRing::new());
+
+/// bytes of output dropped because the ring was full
+static TX_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// set by `enter_panic`, from then on every print waits for the UART
+static IN_PANIC: AtomicBool = AtomicBool::new(false);
+
+/// Output on its way to the UART
+///
+/// The mutex makes it one pusher and one popper at a time, so the ring never turns a push or
+/// pop away for running alongside another.
+struct TxRing
+{
+    bytes: RingBuf<u8, TX_CAPACITY>,
+}
+
+impl TxRing
+{
+    const fn new() -> Self
+    {
+        TxRing { bytes: RingBuf::new() }
+    }
+
+    /// appends `byte`, dropping the oldest one if there's no room
+    fn push(&mut self, byte: u8)
+    {
+        if !self.bytes.push(byte) {
+            self.bytes.pop();
+            self.bytes.push(byte);
+            TX_DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8>
+    {
+        self.bytes.pop()
+    }
+
+    fn len(&self) -> usize
+    {
+        self.bytes.len()
+    }
+}
+
+impl core::fmt::Write for TxRing
+{
+    fn write_str(&mut self, s: &str) -> core::fmt::Result
+    {
+        for byte in s.bytes() {
+            match byte {
+                // what SerialPort::send makes of a backspace, so both paths look the same
+                8 | 0x7F => {
+                    self.push(8);
+                    self.push(b' ');
+                    self.push(8);
+                }
+                byte => self.push(byte),
+            }
+        }
+        Ok(())
+    }
+}
 
 lazy_static! // init method called exactly once on its first use 
 {
     pub static ref SERIAL1: Mutex<SerialPort> = {
 
+        PORTS.call_once(|| ports::claim(COM1..=COM1 + 7, "serial").expect("COM1 is already claimed"));
+
         let mut serial_port = unsafe {
-            SerialPort::new(0x3F8)  // standard port number for the first serial interface
+            SerialPort::new(COM1)
         };
 
         serial_port.init();
+
+        // init() always sets the default, put back whatever init_with asked for
+        let baud = BAUD.load(Ordering::Relaxed);
+        if baud != DEFAULT_BAUD {
+            unsafe { set_divisor((UART_CLOCK / baud) as u16) };
+        }
+
+        // IRQ 4 stays masked until init() wants the transmit interrupt
+        let com1 = PORTS.r#try().expect("COM1 is not claimed yet");
+        unsafe { com1.write(COM1 + INTERRUPT_ENABLE, IER_RECEIVED_DATA) };
+
+        INITIALIZED.store(true, Ordering::Release);
         Mutex::new(serial_port)
     };
 }
 
-#[doc(hidden)]
-pub fn _print(args: ::core::fmt::Arguments) {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialConfigError {
+    /// the UART clock can't be divided down to exactly this rate
+    UnsupportedBaud(u32),
+    /// SERIAL1 is already up, use `configure` instead
+    AlreadyInitialized,
+}
+
+/// the divisor latch value for `baud`, only rates the clock divides exactly are accepted
+fn divisor_for(baud: u32) -> Result<u16, SerialConfigError>
+{
+    if baud == 0 || UART_CLOCK % baud != 0 {
+        return Err(SerialConfigError::UnsupportedBaud(baud));
+    }
+    Ok((UART_CLOCK / baud) as u16)
+}
+
+/// Writes the divisor latch, leaving the line settings (8N1) alone
+///
+/// Unsafe because it pokes COM1 directly, the caller has to keep SERIAL1 from writing meanwhile.
+unsafe fn set_divisor(divisor: u16)
+{
+    let com1 = PORTS.r#try().expect("COM1 is not claimed yet");
+
+    unsafe {
+        let line: u8 = com1.read(COM1 + LINE_CONTROL);
+        com1.write(COM1 + LINE_CONTROL, line | DLAB);
+        com1.write(COM1 + DIVISOR_LOW, divisor as u8);
+        com1.write(COM1 + DIVISOR_HIGH, (divisor >> 8) as u8);
+        com1.write(COM1 + LINE_CONTROL, line & !DLAB);
+    }
+}
+
+/// the baud rate SERIAL1 is set to
+pub fn baud() -> u32
+{
+    BAUD.load(Ordering::Relaxed)
+}
+
+/// Reprograms SERIAL1 to `baud`, any time after boot
+///
+/// Anything already in the UART's FIFO may go out garbled, the host has to switch rates too.
+pub fn configure(baud: u32) -> Result<(), SerialConfigError>
+{
+    use x86_64::instructions::interrupts;
+
+    let divisor = divisor_for(baud)?;
+
+    interrupts::without_interrupts(|| {
+        // holding the lock keeps other writers out while the latch is switched
+        let _serial = SERIAL1.lock();
+        BAUD.store(baud, Ordering::Relaxed);
+        unsafe { set_divisor(divisor) };
+    });
+
+    Ok(())
+}
+
+/// Brings up SERIAL1 at `baud` instead of `DEFAULT_BAUD`
+///
+/// Has to come before the first use of SERIAL1, which `blog_os::init` already is.
+pub fn init_with(baud: u32) -> Result<(), SerialConfigError>
+{
+    divisor_for(baud)?;
+
+    if INITIALIZED.load(Ordering::Acquire) {
+        return Err(SerialConfigError::AlreadyInitialized);
+    }
+
+    BAUD.store(baud, Ordering::Relaxed);
+    lazy_static::initialize(&SERIAL1);
+    Ok(())
+}
+
+/// Takes a received byte off COM1 if one is waiting, never blocks
+///
+/// Polled, for use before `serial_read_stream` exists. Bytes taken here never reach the stream.
+pub fn try_read_byte() -> Option<u8>
+{
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        // the lock keeps configure from flipping DLAB under us
+        let _serial = SERIAL1.lock();
+        let com1 = PORTS.r#try().expect("COM1 is not claimed yet");
+
+        unsafe {
+            let status: u8 = com1.read(COM1 + LINE_STATUS);
+            if status & LINE_STATUS_DATA_READY == 0 {
+                return None;
+            }
+            Some(com1.read(COM1 + RECEIVE))
+        }
+    })
+}
+
+/// Bytes received on COM1, delivered by the receive interrupt
+///
+/// Unmasks IRQ 4 on the first call. There is only one stream, like there is only one
+/// ScancodeStream, so a second call panics.
+pub fn serial_read_stream() -> impl Stream<Item = u8> + Unpin
+{
+    let (sender, receiver) = channel::channel(RX_CAPACITY);
+
+    RX_SENDER
+        .try_init_once(|| sender)
+        .expect("serial_read_stream should only be called once");
+
+    lazy_static::initialize(&SERIAL1);
+    crate::interrupts::unmask_irq(COM1_IRQ);
+
+    // whatever came in while nobody was listening didn't raise an interrupt we handled
+    x86_64::instructions::interrupts::without_interrupts(receive_interrupt);
+
+    receiver
+}
+
+/// how many received bytes were dropped because the stream wasn't keeping up
+pub fn rx_dropped() -> u64
+{
+    RX_DROPPED.load(Ordering::Relaxed)
+}
+
+/// Called by the COM1 interrupt handler, moves every waiting byte into the stream
+///
+/// Must not block or allocate! Without a stream the bytes stay in the UART for `try_read_byte`.
+/// Anyone flipping DLAB does it with interrupts off, so the registers are the normal ones here.
+pub(crate) fn receive_interrupt()
+{
+    let sender = match RX_SENDER.try_get() {
+        Ok(sender) => sender,
+        Err(_) => return,
+    };
+    let com1 = match PORTS.r#try() {
+        Some(com1) => com1,
+        None => return,
+    };
+
+    loop {
+        let byte = unsafe {
+            let status: u8 = com1.read(COM1 + LINE_STATUS);
+            if status & LINE_STATUS_DATA_READY == 0 {
+                break;
+            }
+            com1.read(COM1 + RECEIVE)
+        };
+
+        // a successful send wakes the stream
+        if sender.try_send(byte).is_err() {
+            RX_DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Unmasks IRQ 4 so the UART can ask for more output, `init` does this before interrupts go on
+pub(crate) fn init_tx_interrupt()
+{
+    lazy_static::initialize(&SERIAL1);
+    crate::interrupts::unmask_irq(COM1_IRQ);
+}
+
+/// Moves as much of the ring into the UART as its FIFO takes, with interrupts off
+///
+/// Called by the COM1 interrupt handler once the FIFO ran empty, by `_print` to get things
+/// going, and by the timer in case an unread received byte keeps the UART from interrupting.
+/// Must not block.
+pub(crate) fn transmit_interrupt()
+{
+    let com1 = match PORTS.r#try() {
+        Some(com1) => com1,
+        None => return,
+    };
+    // whoever holds it is about to drain anyway
+    let mut ring = match TX_RING.try_lock() {
+        Some(ring) => ring,
+        None => return,
+    };
+
+    unsafe {
+        let status: u8 = com1.read(COM1 + LINE_STATUS);
+        if status & LINE_STATUS_TRANSMIT_EMPTY != 0 {
+            for _ in 0..TX_FIFO_DEPTH {
+                match ring.pop() {
+                    Some(byte) => com1.write(COM1 + TRANSMIT, byte),
+                    None => break,
+                }
+            }
+        }
+
+        // only ask for the interrupt while there's something left, it fires on an empty FIFO
+        let ier: u8 = com1.read(COM1 + INTERRUPT_ENABLE);
+        let wanted = if ring.len() == 0 {
+            ier & !IER_TRANSMIT_EMPTY
+        } else {
+            ier | IER_TRANSMIT_EMPTY
+        };
+        if wanted != ier {
+            com1.write(COM1 + INTERRUPT_ENABLE, wanted);
+        }
+    }
+}
+
+/// Writes out whatever the ring still holds and waits until the UART took all of it
+///
+/// For tests that want their output on the host before `exit_qemu`, which calls this too.
+pub fn flush()
+{
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let com1 = match PORTS.r#try() {
+            Some(com1) => com1,
+            None => return,
+        };
+        // a panic may have happened with it held, those bytes are lost
+        let mut ring = match TX_RING.try_lock() {
+            Some(ring) => ring,
+            None => return,
+        };
+
+        while let Some(byte) = ring.pop() {
+            unsafe {
+                while com1.read::<u8>(COM1 + LINE_STATUS) & LINE_STATUS_TRANSMIT_EMPTY == 0 {
+                    core::hint::spin_loop();
+                }
+                com1.write(COM1 + TRANSMIT, byte);
+            }
+        }
+    });
+}
+
+/// Switches serial output to waiting for the UART, for the panic and fault handlers
+///
+/// What the ring still holds goes out first, so nothing comes out of order.
+pub fn enter_panic()
+{
+    if !IN_PANIC.swap(true, Ordering::SeqCst) {
+        flush();
+    }
+}
+
+/// how many bytes of output were dropped because the ring was full
+pub fn tx_dropped() -> u64
+{
+    TX_DROPPED.load(Ordering::Relaxed)
+}
+
+/// Prints through the UART and waits until it took every byte, what `_print` does early on
+///
+/// Anything still in the ring goes first.
+pub fn print_polled(args: ::core::fmt::Arguments)
+{
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
+    flush();
     interrupts::without_interrupts(|| {
         SERIAL1.lock().write_fmt(args).expect("Printing to Serial failed!");
     });
+}
+
+/// Writes `s` to the UART without going through `fmt`, waiting for every byte
+///
+/// For the fault handlers and very early boot, where the formatting machinery is one thing too
+/// many. What the ring still holds goes out first.
+#[inline(never)]
+pub fn serial_write_str(s: &str)
+{
+    write_polled(s.as_bytes());
+}
+
+/// `val` as `0x` and 16 hex digits, like `{:#018x}`, see `serial_write_str`
+#[inline(never)]
+pub fn serial_write_hex(val: u64)
+{
+    write_polled(&hex_digits(val));
+}
 
-    // disbaling interrupts shouldn't be the general solution .. it increases the worst-case
-    // interrupt latency 
+/// `val` in decimal, see `serial_write_str`
+#[inline(never)]
+pub fn serial_write_dec(val: u64)
+{
+    let mut buf = [0u8; 20];
+    write_polled(dec_digits(val, &mut buf));
+}
+
+fn write_polled(bytes: &[u8])
+{
+    use x86_64::instructions::interrupts;
+
+    flush();
+    interrupts::without_interrupts(|| {
+        let mut serial = SERIAL1.lock();
+        for &byte in bytes {
+            serial.send(byte);
+        }
+    });
+}
+
+fn hex_digits(val: u64) -> [u8; 18]
+{
+    let mut digits = [b'0'; 18];
+    digits[1] = b'x';
+    for (i, digit) in digits[2..].iter_mut().enumerate() {
+        let nibble = (val >> (60 - 4 * i)) & 0xF;
+        *digit = b"0123456789abcdef"[nibble as usize];
+    }
+    digits
+}
+
+/// the digits of `val`, at the end of `buf`, 20 places fit `u64::MAX`
+fn dec_digits(mut val: u64, buf: &mut [u8; 20]) -> &[u8]
+{
+    let mut start = buf.len();
+    loop {
+        start -= 1;
+        buf[start] = b'0' + (val % 10) as u8;
+        val /= 10;
+        if val == 0 {
+            break;
+        }
+    }
+    &buf[start..]
+}
+
+/// Queues the output for the UART's transmit interrupt, never waits for the UART
+///
+/// Before interrupts are up and after a panic it waits for the UART instead. A ring full of
+/// output nobody drains, interrupts off for long, loses its oldest bytes, see `tx_dropped`.
+#[doc(hidden)]
+pub fn _print(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    let interrupts_ready = crate::boot_stage() >= crate::BootStage::InterruptsReady;
+    if !interrupts_ready || IN_PANIC.load(Ordering::Relaxed) {
+        print_polled(args);
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        // formatting can't fail, the ring makes room
+        let _ = TX_RING.lock().write_fmt(args);
+        transmit_interrupt();
+    });
 }
 
 // using macro_export makes it live directly under the crate root .. so crate::serial::serial_println will not work
@@ -49,3 +532,111 @@ macro_rules! serial_println {
 }
 
 // SerialPort type already implements the fmt::Write trait
+
+/// reads the divisor latch back from the UART
+#[cfg(test)]
+fn read_divisor() -> u16
+{
+    let _serial = SERIAL1.lock();
+    let com1 = PORTS.r#try().expect("COM1 is not claimed yet");
+
+    unsafe {
+        let line: u8 = com1.read(COM1 + LINE_CONTROL);
+        com1.write(COM1 + LINE_CONTROL, line | DLAB);
+        let low: u8 = com1.read(COM1 + DIVISOR_LOW);
+        let high: u8 = com1.read(COM1 + DIVISOR_HIGH);
+        com1.write(COM1 + LINE_CONTROL, line);
+        u16::from_le_bytes([low, high])
+    }
+}
+
+#[test_case]
+fn test_configure_then_print()
+{
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    assert_eq!(configure(115200), Ok(()));
+    serial_println!("test_configure_then_print at {} baud", baud());
+    assert_eq!(without_interrupts(read_divisor), 1);
+
+    // back to what the test runner expects
+    assert_eq!(configure(DEFAULT_BAUD), Ok(()));
+    serial_println!("test_configure_then_print back at {} baud", baud());
+    assert_eq!(without_interrupts(read_divisor), 3);
+}
+
+#[test_case]
+fn test_receive_interrupt_enabled()
+{
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    let ier: u8 = without_interrupts(|| {
+        let _serial = SERIAL1.lock();
+        let com1 = PORTS.r#try().expect("COM1 is not claimed yet");
+        unsafe { com1.read(COM1 + INTERRUPT_ENABLE) }
+    });
+    assert_eq!(ier & IER_RECEIVED_DATA, IER_RECEIVED_DATA);
+}
+
+#[test_case]
+fn test_unsupported_baud_rejected()
+{
+    assert_eq!(configure(0), Err(SerialConfigError::UnsupportedBaud(0)));
+    assert_eq!(configure(1000), Err(SerialConfigError::UnsupportedBaud(1000)));
+    assert_eq!(baud(), DEFAULT_BAUD);
+
+    // the test runner printed long before this
+    assert_eq!(init_with(9600), Err(SerialConfigError::AlreadyInitialized));
+}
+
+#[test_case]
+fn test_tx_ring_drops_the_oldest()
+{
+    use core::fmt::Write;
+
+    let mut ring = TxRing::new();
+    let dropped = tx_dropped();
+
+    for i in 0..TX_CAPACITY + 3 {
+        ring.push(i as u8);
+    }
+    assert_eq!(ring.len(), TX_CAPACITY);
+    assert_eq!(tx_dropped(), dropped + 3);
+    assert_eq!(ring.pop(), Some(3));
+
+    let mut ring = TxRing::new();
+    write!(ring, "a\x08b").unwrap();
+    let mut out = [0u8; 5];
+    for byte in out.iter_mut() {
+        *byte = ring.pop().unwrap();
+    }
+    assert_eq!(&out, b"a\x08 \x08b");
+    assert_eq!(ring.pop(), None);
+}
+
+#[test_case]
+fn test_flush_empties_the_ring()
+{
+    serial_println!("test_flush_empties_the_ring queued");
+    flush();
+    assert_eq!(x86_64::instructions::interrupts::without_interrupts(|| TX_RING.lock().len()), 0);
+}
+
+#[test_case]
+fn test_digits_without_fmt()
+{
+    assert_eq!(&hex_digits(0), b"0x0000000000000000");
+    assert_eq!(&hex_digits(0xDEAD_BEEF), b"0x00000000deadbeef");
+    assert_eq!(&hex_digits(u64::MAX), b"0xffffffffffffffff");
+
+    let mut buf = [0u8; 20];
+    assert_eq!(dec_digits(0, &mut buf), b"0");
+    assert_eq!(dec_digits(1234, &mut buf), b"1234");
+    assert_eq!(dec_digits(u64::MAX, &mut buf), b"18446744073709551615");
+
+    serial_write_str("test_digits_without_fmt ");
+    serial_write_hex(0x2A);
+    serial_write_str(" ");
+    serial_write_dec(42);
+    serial_write_str("\n");
+}
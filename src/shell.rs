@@ -0,0 +1,517 @@
+// in src/shell.rs
+//
+// a small debug shell on COM1 and the keyboard, run `-serial stdio` and type `help`
+
+pub mod line_editor;
+
+use crate::{
+	allocator, config,
+	fs::{
+		glob,
+		simple_fs::FileSystem,
+		walk::{WalkDir, WalkEntry},
+	},
+	hw::ports,
+	interrupts, serial_print,
+	task::{
+		Priority, executor,
+		keyboard::{self, KeyEvent, KeyEventStream},
+		timer, trace,
+	},
+	vga_buffer::{self, BUFFER_HEIGHT, BUFFER_WIDTH, WRITER},
+	vga_print,
+	virtio::FRAME_ALLOCATOR,
+};
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::fmt::{self, Write};
+use futures_util::{
+	future,
+	stream::{self, StreamExt},
+};
+use line_editor::{Action, Key, LineEditor, ReadLineError, Redraw, SerialKeys, read_line};
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// longest line the shell takes, anything typed past it is dropped
+pub const MAX_LINE: usize = 128;
+
+const PROMPT: &str = "> ";
+
+/// the row the VGA side edits in, the writer always writes to the bottom one
+const VGA_ROW: usize = BUFFER_HEIGHT - 1;
+
+/// Longest line the editor takes
+///
+/// The prompt, the line and the cursor behind its last char have to fit in one VGA row, so the
+/// cursor tops out at column 79 and the writer never wraps the line.
+const LINE_LEN: usize = {
+	let room = BUFFER_WIDTH - PROMPT.len() - 1;
+	if room < MAX_LINE { room } else { MAX_LINE }
+};
+
+const HELP: &str = "\
+help                  this text
+mem                   heap, shrinker, slab and frame usage
+tasks                 unfinished tasks and the heap bytes they hold
+ps -v                 tasks with their base and dynamic priority
+nice <id> <level>     set a task's priority, idle low normal high critical or 0 to 255
+ls                    files on the disk
+ls -l                 files with their size, modification and access time
+ls -R [path]          everything below <path>, one level of indent per directory
+stat <path>           a file's inode, size and times
+find [dir] <pattern>  paths below <dir> whose name matches, * ? and [a-z] work
+rm [-r] <path>        delete a file, -r for a directory and everything in it
+defrag                move the blocks of every file together, close all files first
+kbd load <path>       switch the keyboard to the keymap file at <path>
+kbd builtin us        back to the builtin US layout
+config reload         read kernel.cfg again and apply what changed
+config set <k> <v>    set a key in kernel.cfg and apply it
+uptime                time since boot
+ioports               claimed I/O port ranges
+trace exec on|off     record executor events
+trace exec dump       print the recorded events as CSV
+
+keys: Left/Right/Home/End move, Up/Down go through the last commands, Ctrl+C drops the line,
+Ctrl+L clears the screen
+";
+
+/// a byte from COM1 or a key from the keyboard
+enum Input {
+	Serial(u8),
+	Keyboard(KeyEvent),
+}
+
+/// Reads lines from COM1 and the keyboard and runs them, forever
+///
+/// Both inputs feed the same line, which shows on the serial console and on the VGA screen.
+/// `fs` is whatever `ls` lists, None if no disk was found. Creates the `KeyEventStream`, so
+/// nothing else can read the keyboard.
+pub async fn shell_task(mut fs: Option<Box<dyn FileSystem>>) {
+	let mut editor = LineEditor::new(LINE_LEN);
+	let mut serial_keys = SerialKeys::new();
+	let mut output = String::new();
+	let mut keys = stream::select(
+		crate::serial::serial_read_stream().map(Input::Serial),
+		KeyEventStream::new().map(Input::Keyboard),
+	)
+	.filter_map(move |input| {
+		future::ready(match input {
+			Input::Serial(byte) => serial_keys.feed(byte),
+			Input::Keyboard(event) => Key::from_event(&event),
+		})
+	});
+
+	serial_print!("\n");
+	prompt();
+	loop {
+		let line = read_line(&mut editor, &mut keys, |editor, action| match action {
+			Action::Redraw(redraw) => render(editor, redraw),
+			Action::ClearScreen => {
+				// ANSI erase display and cursor home, the one escape sequence the shell sends
+				serial_print!("\x1b[2J\x1b[H");
+				without_interrupts(|| WRITER.lock().clear_screen());
+				prompt();
+				render(editor, editor.repaint_all());
+			},
+			_ => {},
+		})
+		.await;
+
+		match line {
+			Ok(line) => {
+				echo("\n");
+				output.clear();
+				// writing to a String can't fail
+				let _ = run_command(&line, fs.as_deref_mut(), &mut output);
+				echo(&output);
+				// whatever was typed while the command ran wasn't meant for the next prompt
+				keyboard::flush();
+				prompt();
+			},
+			Err(ReadLineError::Interrupted) => {
+				echo("^C\n");
+				prompt();
+			},
+			Err(ReadLineError::Closed) => break,
+		}
+	}
+}
+
+/// prints to both sides of the shell
+fn echo(s: &str) {
+	serial_print!("{}", s);
+	vga_print!("{}", s);
+}
+
+/// prints the prompt, on the VGA at the start of a row so the line fits behind it
+fn prompt() {
+	serial_print!("{}", PROMPT);
+	without_interrupts(|| {
+		let mut writer = WRITER.lock();
+		if writer.column_position() != 0 {
+			writer.new_line();
+		}
+		writer.write_string(PROMPT);
+		vga_buffer::set_cursor(VGA_ROW, PROMPT.len());
+	});
+}
+
+/// repaints what changed on both sides
+fn render(
+	editor: &LineEditor,
+	redraw: Redraw,
+) {
+	let mut echo = String::new();
+	let _ = editor.render_serial(redraw, &mut echo);
+	serial_print!("{}", echo);
+
+	without_interrupts(|| editor.render_vga(redraw, &mut WRITER.lock(), VGA_ROW, PROMPT.len()));
+}
+
+/// Runs one command line and writes what it prints to `out`
+pub fn run_command(
+	line: &str,
+	fs: Option<&mut dyn FileSystem>,
+	out: &mut impl Write,
+) -> fmt::Result {
+	let mut words = line.split_whitespace();
+	let command = match words.next() {
+		Some(command) => command,
+		None => return Ok(()),
+	};
+
+	match (command, words.next(), words.next()) {
+		("help", ..) => out.write_str(HELP),
+		("mem", ..) => {
+			let stats = allocator::allocator_stats();
+			let fragmentation = allocator::fragmentation();
+			writeln!(
+				out,
+				"heap: {} of {} bytes used, {} allocations, {} frees, {} failed, {}.{}% fragmented",
+				stats.used,
+				stats.heap_size,
+				stats.allocations,
+				stats.frees,
+				stats.failed,
+				fragmentation / 10,
+				fragmentation % 10
+			)?;
+			for shrinker in allocator::shrinker_stats() {
+				writeln!(
+					out,
+					"shrinker {} (priority {}): {} runs, {} bytes reclaimed",
+					shrinker.name, shrinker.priority, shrinker.invocations, shrinker.reclaimed
+				)?;
+			}
+			for slab in allocator::slab_stats() {
+				writeln!(
+					out,
+					"slab {}: {} of {} objects in use ({} at most), {} slabs, {} bytes",
+					slab.name, slab.in_use, slab.capacity, slab.high_water, slab.slabs, slab.bytes
+				)?;
+			}
+			match FRAME_ALLOCATOR.lock().as_ref() {
+				Some(frames) => writeln!(
+					out,
+					"frames: {} of {} used, {} free",
+					frames.frames_used(),
+					frames.frames_total(),
+					frames.frames_free()
+				),
+				None => Ok(()),
+			}
+		},
+		("tasks", ..) => {
+			let tasks = executor::list_tasks();
+			let heap = allocator::heap_by_task();
+			let bytes = |owner| heap.get(owner).map_or(0, |row| row.bytes);
+			writeln!(out, "{} tasks", tasks.len())?;
+			for task in tasks {
+				writeln!(
+					out,
+					"  task {:>4}  priority {}  heap {} bytes",
+					task.id,
+					task.priority,
+					bytes(allocator::Owner::Task(task.id))
+				)?;
+			}
+			// interrupt handlers shouldn't allocate, so this row should never show up
+			if let Some(irq) = heap.get(allocator::Owner::Irq) {
+				writeln!(out, "  irq        heap {} bytes", irq.bytes)?;
+			}
+			Ok(())
+		},
+		("ps", Some("-v"), _) => executor::write_report(out, &executor::list_tasks()),
+		("nice", Some(id), Some(level)) => {
+			let id = match id.parse::<u64>() {
+				Ok(id) => id,
+				Err(_) => return writeln!(out, "nice: {}: not a task id", id),
+			};
+			let priority = match level.parse::<Priority>() {
+				Ok(priority) => priority,
+				Err(_) => {
+					return writeln!(out, "nice: {}: not a priority, try high or 0 to 255", level);
+				},
+			};
+			if executor::request_priority(id, priority) {
+				writeln!(out, "task {} gets priority {}", id, priority)
+			} else {
+				writeln!(out, "nice: no task {}", id)
+			}
+		},
+		("ls", Some("-R"), path) => match fs {
+			Some(fs) => list_recursive(fs, path.unwrap_or("/"), out),
+			None => writeln!(out, "ls: no filesystem mounted"),
+		},
+		("ls", Some("-l"), None) => match fs {
+			Some(fs) => list_long(fs, out),
+			None => writeln!(out, "ls: no filesystem mounted"),
+		},
+		("ls", ..) => match fs {
+			Some(fs) => match fs.list_file() {
+				Ok(files) => files.iter().try_for_each(|name| writeln!(out, "{}", name)),
+				Err(e) => writeln!(out, "ls: {:?}", e),
+			},
+			None => writeln!(out, "ls: no filesystem mounted"),
+		},
+		("stat", Some(path), None) => match fs {
+			Some(fs) => stat(fs, path, out),
+			None => writeln!(out, "stat: no filesystem mounted"),
+		},
+		("defrag", ..) => match fs {
+			Some(fs) => {
+				let report = fs.defrag(&mut |done, total| {
+					let _ = writeln!(out, "defrag: {} of {} files", done, total);
+				});
+				match report {
+					Ok(report) => writeln!(
+						out,
+						"moved {} files ({} only partly), {} blocks, largest free run {} -> {} blocks",
+						report.files_moved,
+						report.files_partial,
+						report.blocks_relocated,
+						report.largest_free_run_before,
+						report.largest_free_run_after
+					),
+					Err(e) => writeln!(out, "defrag: {:?}", e),
+				}
+			},
+			None => writeln!(out, "defrag: no filesystem mounted"),
+		},
+		("find", Some(first), second) => match fs {
+			Some(fs) => match second {
+				Some(pattern) => find(fs, first, pattern, out),
+				None => find(fs, "/", first, out),
+			},
+			None => writeln!(out, "find: no filesystem mounted"),
+		},
+		("rm", Some("-r"), Some(path)) => match fs {
+			Some(fs) => remove(fs, path, true, out),
+			None => writeln!(out, "rm: no filesystem mounted"),
+		},
+		("rm", Some(path), None) => match fs {
+			Some(fs) => remove(fs, path, false, out),
+			None => writeln!(out, "rm: no filesystem mounted"),
+		},
+		("kbd", Some("load"), Some(path)) => match fs {
+			Some(fs) => match keyboard::load_keymap(fs, path) {
+				Ok(keymap) => {
+					keyboard::set_keymap(Some(keymap));
+					writeln!(out, "keymap {} active", path)
+				},
+				Err(e) => writeln!(out, "kbd: {}: {:?}", path, e),
+			},
+			None => writeln!(out, "kbd: no filesystem mounted"),
+		},
+		("kbd", Some("builtin"), Some("us")) => {
+			keyboard::set_keymap(None);
+			writeln!(out, "builtin US layout active")
+		},
+		("config", Some("reload"), None) => match fs {
+			Some(fs) => match config::reload(fs) {
+				Ok(report) => writeln!(out, "{}", report),
+				Err(e) => writeln!(out, "config: {}", e),
+			},
+			None => writeln!(out, "config: no filesystem mounted"),
+		},
+		("config", Some("set"), Some(key)) => {
+			// the value can have spaces in it
+			let value = words.collect::<Vec<_>>().join(" ");
+			match fs {
+				_ if value.is_empty() => writeln!(out, "config set <key> <value>"),
+				Some(fs) => match config::set(fs, key, &value) {
+					Ok(report) => writeln!(out, "{}", report),
+					Err(e) => writeln!(out, "config: {}", e),
+				},
+				None => writeln!(out, "config: no filesystem mounted"),
+			}
+		},
+		("uptime", ..) => {
+			let ticks = interrupts::ticks();
+			let seconds = ticks / timer::TICKS_PER_SECOND;
+			writeln!(out, "up {}m {}s ({} ticks)", seconds / 60, seconds % 60, ticks)
+		},
+		("ioports", ..) => ports::report(out),
+		("trace", Some("exec"), Some("on")) => {
+			trace::set_enabled(true);
+			writeln!(out, "executor tracing on")
+		},
+		("trace", Some("exec"), Some("off")) => {
+			trace::set_enabled(false);
+			writeln!(out, "executor tracing off")
+		},
+		("trace", Some("exec"), Some("dump")) => trace::dump(out),
+		_ => writeln!(out, "unknown command: {}, try help", line.trim()),
+	}
+}
+
+/// `ls -R`, directories get a `/` behind the name and files their size
+fn list_recursive(
+	fs: &mut dyn FileSystem,
+	path: &str,
+	out: &mut impl Write,
+) -> fmt::Result {
+	for entry in WalkDir::new(fs, path) {
+		match entry {
+			Ok(entry) if entry.is_dir() => {
+				let slash = if entry.depth == 0 { "" } else { "/" };
+				writeln!(out, "{:indent$}{}{}", "", entry.name(), slash, indent = entry.depth * 2)?
+			},
+			Ok(entry) => writeln!(
+				out,
+				"{:indent$}{}  {} bytes",
+				"",
+				entry.name(),
+				entry.size,
+				indent = entry.depth * 2
+			)?,
+			Err(e) => return writeln!(out, "ls: {}: {:?}", path, e),
+		}
+	}
+	Ok(())
+}
+
+/// `ls -l`, times are in seconds of the filesystem clock, the uptime when they were taken
+fn list_long(
+	fs: &mut dyn FileSystem,
+	out: &mut impl Write,
+) -> fmt::Result {
+	let names = match fs.list_file() {
+		Ok(names) => names,
+		Err(e) => return writeln!(out, "ls: {:?}", e),
+	};
+	for name in names {
+		match fs.open_file(&name).and_then(|handle| fs.stat(handle)) {
+			Ok(inode) => writeln!(
+				out,
+				"{:>8}  modified {:>6}s  accessed {:>6}s  {}",
+				inode.size_in_bytes, inode.last_modification_time, inode.last_access_time, name
+			)?,
+			Err(e) => writeln!(out, "ls: {}: {:?}", name, e)?,
+		}
+	}
+	Ok(())
+}
+
+/// `stat`, the access time includes reads that are only noted in memory so far
+fn stat(
+	fs: &mut dyn FileSystem,
+	path: &str,
+	out: &mut impl Write,
+) -> fmt::Result {
+	let handle = match fs.open_file(path.trim_start_matches('/')) {
+		Ok(handle) => handle,
+		Err(e) => return writeln!(out, "stat: {}: {:?}", path, e),
+	};
+	let inode = match fs.stat(handle) {
+		Ok(inode) => inode,
+		Err(e) => return writeln!(out, "stat: {}: {:?}", path, e),
+	};
+	writeln!(out, "  file: {}", path)?;
+	writeln!(out, " inode: {}", handle.0)?;
+	writeln!(out, "  size: {} bytes", inode.size_in_bytes)?;
+	writeln!(out, "access: {}s", inode.last_access_time)?;
+	writeln!(out, "modify: {}s", inode.last_modification_time)?;
+	writeln!(out, "change: {}s", inode.creation_time)
+}
+
+/// `find`, a pattern with a `/` in it goes against the whole path, one without against the name
+fn find(
+	fs: &mut dyn FileSystem,
+	dir: &str,
+	pattern: &str,
+	out: &mut impl Write,
+) -> fmt::Result {
+	for entry in WalkDir::new(fs, dir) {
+		match entry {
+			Ok(entry) => {
+				let found = if pattern.contains('/') {
+					glob::matches_path(pattern, &entry.path)
+				} else {
+					glob::matches(pattern, entry.name())
+				};
+				if found {
+					writeln!(out, "{}", entry.path)?;
+				}
+			},
+			Err(e) => return writeln!(out, "find: {}: {:?}", dir, e),
+		}
+	}
+	Ok(())
+}
+
+/// Deletes `path`, a directory only with `recursive` and then everything in it first
+///
+/// The root itself stays, there's nothing to hold the files otherwise.
+fn remove(
+	fs: &mut dyn FileSystem,
+	path: &str,
+	recursive: bool,
+	out: &mut impl Write,
+) -> fmt::Result {
+	let depth = if recursive { usize::MAX } else { 0 };
+	// all of it up front, the walk reads the directories that the deleting changes
+	let entries: Result<Vec<WalkEntry>, _> =
+		WalkDir::new(&mut *fs, path).max_depth(depth).contents_first(true).collect();
+	let entries = match entries {
+		Ok(entries) => entries,
+		Err(e) => return writeln!(out, "rm: {}: {:?}", path, e),
+	};
+
+	if !recursive && entries.iter().any(WalkEntry::is_dir) {
+		return writeln!(out, "rm: {}: is a directory, use -r", path);
+	}
+
+	let mut removed = 0;
+	for entry in entries.iter().filter(|entry| !entry.is_dir()) {
+		match fs.delete_file(entry.path.trim_start_matches('/')) {
+			Ok(()) => removed += 1,
+			Err(e) => return writeln!(out, "rm: {}: {:?}", entry.path, e),
+		}
+	}
+	if recursive {
+		writeln!(out, "removed {} files", removed)?;
+	}
+	Ok(())
+}
+
+#[test_case]
+fn test_unknown_and_empty_commands() {
+	struct Sink(usize);
+
+	impl Write for Sink {
+		fn write_str(
+			&mut self,
+			s: &str,
+		) -> fmt::Result {
+			self.0 += s.len();
+			Ok(())
+		}
+	}
+
+	let mut sink = Sink(0);
+	run_command("   ", None, &mut sink).unwrap();
+	assert_eq!(sink.0, 0);
+
+	run_command("help", None, &mut sink).unwrap();
+	assert_eq!(sink.0, HELP.len());
+}
@@ -0,0 +1,430 @@
+// in src/shell/line_editor.rs
+//
+// the shell's input line: editing at the cursor, history, and repainting just what changed
+
+use crate::{task::keyboard::KeyEvent, vga_buffer::Writer};
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::fmt::{self, Write};
+use futures_util::stream::{Stream, StreamExt};
+use pc_keyboard::{DecodedKey, KeyCode};
+
+/// commands Up and Down walk through
+pub const HISTORY_LEN: usize = 32;
+
+/// A key the editor knows what to do with, whichever input it came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+	Char(char),
+	Left,
+	Right,
+	Home,
+	End,
+	Up,
+	Down,
+	Backspace,
+	Delete,
+	Enter,
+	/// Ctrl+C
+	Cancel,
+	/// Ctrl+L
+	ClearScreen,
+}
+
+impl Key {
+	/// the key a keyboard event stands for, None for the ones the editor ignores
+	pub fn from_event(event: &KeyEvent) -> Option<Key> {
+		if event.is_ctrl_c() {
+			return Some(Key::Cancel);
+		}
+
+		match event.key {
+			DecodedKey::Unicode('\n') | DecodedKey::Unicode('\r') => Some(Key::Enter),
+			DecodedKey::Unicode('\u{8}') => Some(Key::Backspace),
+			// pc_keyboard decodes the Delete key to DEL
+			DecodedKey::Unicode('\u{7f}') => Some(Key::Delete),
+			// Ctrl+L maps to form feed, like Ctrl+C does to ETX
+			DecodedKey::Unicode('\u{c}') => Some(Key::ClearScreen),
+			DecodedKey::Unicode('l') if event.modifiers.ctrl => Some(Key::ClearScreen),
+			DecodedKey::Unicode(c) if !c.is_control() => Some(Key::Char(c)),
+			DecodedKey::RawKey(KeyCode::ArrowLeft) => Some(Key::Left),
+			DecodedKey::RawKey(KeyCode::ArrowRight) => Some(Key::Right),
+			DecodedKey::RawKey(KeyCode::ArrowUp) => Some(Key::Up),
+			DecodedKey::RawKey(KeyCode::ArrowDown) => Some(Key::Down),
+			DecodedKey::RawKey(KeyCode::Home) => Some(Key::Home),
+			DecodedKey::RawKey(KeyCode::End) => Some(Key::End),
+			DecodedKey::RawKey(KeyCode::Delete) => Some(Key::Delete),
+			_ => None,
+		}
+	}
+}
+
+/// how far into an escape sequence the serial input is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+	None,
+	/// got ESC
+	Esc,
+	/// got ESC [ and maybe a number
+	Csi(u8),
+	/// got ESC O, some terminals send Home and End like that
+	Ss3,
+}
+
+/// Turns what a serial terminal sends into keys, the arrows and friends come as escape sequences
+pub struct SerialKeys {
+	state: Escape,
+}
+
+impl SerialKeys {
+	pub fn new() -> Self {
+		SerialKeys { state: Escape::None }
+	}
+
+	/// feeds one byte, returns a key once one is complete
+	pub fn feed(
+		&mut self,
+		byte: u8,
+	) -> Option<Key> {
+		let (state, key) = match (self.state, byte) {
+			(Escape::None, 0x1b) => (Escape::Esc, None),
+			(Escape::None, b'\r') | (Escape::None, b'\n') => (Escape::None, Some(Key::Enter)),
+			// backspace and DEL, terminals send either
+			(Escape::None, 0x08) | (Escape::None, 0x7f) => (Escape::None, Some(Key::Backspace)),
+			(Escape::None, 0x03) => (Escape::None, Some(Key::Cancel)),
+			(Escape::None, 0x0c) => (Escape::None, Some(Key::ClearScreen)),
+			(Escape::None, byte) if (0x20..0x7f).contains(&byte) => {
+				(Escape::None, Some(Key::Char(byte as char)))
+			},
+			(Escape::None, _) => (Escape::None, None),
+
+			(Escape::Esc, b'[') => (Escape::Csi(0), None),
+			(Escape::Esc, b'O') => (Escape::Ss3, None),
+			(Escape::Csi(n), digit @ b'0'..=b'9') => {
+				(Escape::Csi(n.saturating_mul(10).saturating_add(digit - b'0')), None)
+			},
+			(Escape::Csi(_), b'A') => (Escape::None, Some(Key::Up)),
+			(Escape::Csi(_), b'B') => (Escape::None, Some(Key::Down)),
+			(Escape::Csi(_), b'C') => (Escape::None, Some(Key::Right)),
+			(Escape::Csi(_), b'D') => (Escape::None, Some(Key::Left)),
+			(Escape::Csi(_), b'H') | (Escape::Ss3, b'H') => (Escape::None, Some(Key::Home)),
+			(Escape::Csi(_), b'F') | (Escape::Ss3, b'F') => (Escape::None, Some(Key::End)),
+			// ESC [ n ~, the vt220 way
+			(Escape::Csi(1), b'~') | (Escape::Csi(7), b'~') => (Escape::None, Some(Key::Home)),
+			(Escape::Csi(4), b'~') | (Escape::Csi(8), b'~') => (Escape::None, Some(Key::End)),
+			(Escape::Csi(3), b'~') => (Escape::None, Some(Key::Delete)),
+			// a sequence we don't know, drop all of it
+			_ => (Escape::None, None),
+		};
+
+		self.state = state;
+		key
+	}
+}
+
+/// What the caller has to do after a key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+	/// nothing changed
+	None,
+	/// repaint with `render_serial` and `render_vga`
+	Redraw(Redraw),
+	/// Enter, run this line, the editor starts over empty
+	Submit(String),
+	/// Ctrl+C, the line is gone, print a new prompt
+	Cancel,
+	/// Ctrl+L, clear the screen, print a new prompt and repaint with `LineEditor::repaint_all`
+	ClearScreen,
+}
+
+/// Why `read_line` came back without a line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadLineError {
+	/// Ctrl+C, what was typed so far is gone
+	Interrupted,
+	/// the keys ran out
+	Closed,
+}
+
+/// Feeds `keys` to `editor` until a line is submitted or Ctrl+C drops it
+///
+/// Redraws and Ctrl+L go to `on_action` for the caller to show. The cursor moves behind the
+/// end of the line before it's done, so what comes next goes below it and not into its middle.
+pub async fn read_line<S>(
+	editor: &mut LineEditor,
+	keys: &mut S,
+	mut on_action: impl FnMut(&LineEditor, Action),
+) -> Result<String, ReadLineError>
+where
+	S: Stream<Item = Key> + Unpin,
+{
+	while let Some(key) = keys.next().await {
+		if let Key::Enter | Key::Cancel = key {
+			if let Action::Redraw(redraw) = editor.feed(Key::End) {
+				on_action(editor, Action::Redraw(redraw));
+			}
+		}
+
+		match editor.feed(key) {
+			Action::Submit(line) => return Ok(line),
+			Action::Cancel => return Err(ReadLineError::Interrupted),
+			Action::None => {},
+			action => on_action(editor, action),
+		}
+	}
+	Err(ReadLineError::Closed)
+}
+
+/// What a key changed, everything in front of `from` is still the same
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Redraw {
+	/// first char that changed
+	pub from: usize,
+	/// the cursor before the key
+	pub old_cursor: usize,
+	/// the line's length before the key
+	pub old_len: usize,
+}
+
+/// A line being typed, with the cursor somewhere in it, and the commands typed before
+///
+/// Knows nothing about screens, `feed` says what changed and `render_serial` and `render_vga`
+/// bring a screen up to date.
+pub struct LineEditor {
+	line: Vec<char>,
+	cursor: usize,
+	max_len: usize,
+	/// oldest first
+	history: VecDeque<String>,
+	/// 0 is the line being typed, n the n-th newest history entry
+	slot: usize,
+	/// the line being typed, kept here while Up shows history entries
+	draft: Vec<char>,
+}
+
+impl LineEditor {
+	/// An empty editor that takes lines of up to `max_len` chars
+	///
+	/// Chars typed past `max_len` are dropped.
+	pub fn new(max_len: usize) -> Self {
+		LineEditor {
+			line: Vec::with_capacity(max_len),
+			cursor: 0,
+			max_len,
+			history: VecDeque::with_capacity(HISTORY_LEN),
+			slot: 0,
+			draft: Vec::new(),
+		}
+	}
+
+	/// the line as it is now
+	pub fn text(&self) -> String {
+		self.line.iter().collect()
+	}
+
+	/// chars in front of the cursor
+	pub fn cursor(&self) -> usize {
+		self.cursor
+	}
+
+	/// the submitted lines, oldest first
+	pub fn history(&self) -> impl Iterator<Item = &str> {
+		self.history.iter().map(String::as_str)
+	}
+
+	/// a redraw of the whole line, for right after a fresh prompt
+	pub fn repaint_all(&self) -> Redraw {
+		Redraw { from: 0, old_cursor: 0, old_len: 0 }
+	}
+
+	pub fn feed(
+		&mut self,
+		key: Key,
+	) -> Action {
+		match key {
+			Key::Char(c) => {
+				if self.line.len() >= self.max_len {
+					return Action::None;
+				}
+				let redraw = self.redraw_from(self.cursor);
+				self.line.insert(self.cursor, c);
+				self.cursor += 1;
+				self.edited(redraw)
+			},
+			Key::Backspace => {
+				if self.cursor == 0 {
+					return Action::None;
+				}
+				let redraw = self.redraw_from(self.cursor - 1);
+				self.cursor -= 1;
+				self.line.remove(self.cursor);
+				self.edited(redraw)
+			},
+			Key::Delete => {
+				if self.cursor == self.line.len() {
+					return Action::None;
+				}
+				let redraw = self.redraw_from(self.cursor);
+				self.line.remove(self.cursor);
+				self.edited(redraw)
+			},
+			Key::Left => self.move_to(self.cursor.saturating_sub(1)),
+			Key::Right => self.move_to((self.cursor + 1).min(self.line.len())),
+			Key::Home => self.move_to(0),
+			Key::End => self.move_to(self.line.len()),
+			Key::Up => {
+				if self.slot == self.history.len() {
+					return Action::None;
+				}
+				if self.slot == 0 {
+					self.draft = self.line.clone();
+				}
+				self.slot += 1;
+				let entry = self.history[self.history.len() - self.slot].chars().collect();
+				self.replace(entry)
+			},
+			Key::Down => {
+				if self.slot == 0 {
+					return Action::None;
+				}
+				self.slot -= 1;
+				let entry = match self.slot {
+					0 => core::mem::take(&mut self.draft),
+					slot => self.history[self.history.len() - slot].chars().collect(),
+				};
+				self.replace(entry)
+			},
+			Key::Enter => {
+				let line = self.text();
+				// no blank lines and no repeats in a row, Up would only show the same thing again
+				let repeat = self.history.back().map_or(false, |last| *last == line);
+				if !line.trim().is_empty() && !repeat {
+					if self.history.len() == HISTORY_LEN {
+						self.history.pop_front();
+					}
+					self.history.push_back(line.clone());
+				}
+				self.reset();
+				Action::Submit(line)
+			},
+			Key::Cancel => {
+				self.reset();
+				Action::Cancel
+			},
+			Key::ClearScreen => Action::ClearScreen,
+		}
+	}
+
+	fn redraw_from(
+		&self,
+		from: usize,
+	) -> Redraw {
+		Redraw { from, old_cursor: self.cursor, old_len: self.line.len() }
+	}
+
+	/// A history entry that gets edited becomes the line being typed
+	///
+	/// So Up goes back to the newest entry, which is left as it was, and Down brings the edit back
+	/// from slot zero.
+	fn edited(
+		&mut self,
+		redraw: Redraw,
+	) -> Action {
+		self.slot = 0;
+		self.draft.clear();
+		Action::Redraw(redraw)
+	}
+
+	fn move_to(
+		&mut self,
+		cursor: usize,
+	) -> Action {
+		if cursor == self.cursor {
+			return Action::None;
+		}
+		// nothing changed, so nothing in front of the end is repainted
+		let redraw = self.redraw_from(self.line.len());
+		self.cursor = cursor;
+		Action::Redraw(redraw)
+	}
+
+	/// swaps in a history entry, the cursor goes to its end
+	fn replace(
+		&mut self,
+		line: Vec<char>,
+	) -> Action {
+		let same = self.line.iter().zip(&line).take_while(|(a, b)| a == b).count();
+		let redraw = self.redraw_from(same);
+		self.line = line;
+		self.cursor = self.line.len();
+		Action::Redraw(redraw)
+	}
+
+	fn reset(&mut self) {
+		self.line.clear();
+		self.cursor = 0;
+		self.slot = 0;
+		self.draft.clear();
+	}
+
+	/// Brings a serial terminal showing the line as it was before the key up to date
+	///
+	/// Only backspaces, chars and spaces, so any terminal does: back up to the first change, print
+	/// the rest of the line, blank out what's left of a longer old one, back up to the cursor.
+	pub fn render_serial(
+		&self,
+		redraw: Redraw,
+		out: &mut impl Write,
+	) -> fmt::Result {
+		let len = self.line.len();
+		if redraw.from >= len && redraw.old_len == len {
+			return self.serial_move(redraw.old_cursor, self.cursor, out);
+		}
+
+		self.serial_move(redraw.old_cursor, redraw.from, out)?;
+		self.line[redraw.from..].iter().try_for_each(|&c| out.write_char(c))?;
+		for _ in len..redraw.old_len {
+			out.write_char(' ')?;
+		}
+		self.serial_move(len.max(redraw.old_len), self.cursor, out)
+	}
+
+	/// moves the terminal's cursor over chars that didn't change
+	fn serial_move(
+		&self,
+		from: usize,
+		to: usize,
+		out: &mut impl Write,
+	) -> fmt::Result {
+		if to < from {
+			(to..from).try_for_each(|_| out.write_char('\x08'))
+		} else {
+			// no escape sequences, to go right just print what's there again
+			self.line[from..to].iter().try_for_each(|&c| out.write_char(c))
+		}
+	}
+
+	/// Same for the VGA, where the line starts at `col` in `row`
+	///
+	/// Writes the cells from the first change on and moves the hardware cursor. `col` plus
+	/// `max_len` has to stay below `BUFFER_WIDTH`, then the cursor behind a full line sits in the
+	/// last column at most and nothing wraps.
+	pub fn render_vga(
+		&self,
+		redraw: Redraw,
+		writer: &mut Writer,
+		row: usize,
+		col: usize,
+	) {
+		let len = self.line.len();
+		if redraw.from < len || redraw.old_len != len {
+			let mut tail: String = self.line[redraw.from..].iter().collect();
+			tail.extend((len..redraw.old_len).map(|_| ' '));
+			// past the row is cut off, nothing to do about that here
+			let _ = writer.write_at(row, col + redraw.from, &tail);
+		}
+
+		// more output goes behind the line, not into it
+		writer.set_column_position(col + len);
+		crate::vga_buffer::set_cursor(row, col + self.cursor);
+	}
+}
@@ -0,0 +1,225 @@
+//! in src/stack.rs
+
+use crate::allocator::HEAP_START;
+use crate::memory;
+use crate::println;
+use crate::virtio::{FRAME_ALLOCATOR, PAGE_MAPPER};
+use alloc::{boxed::Box, vec::Vec};
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{
+	VirtAddr,
+	structures::paging::{
+		FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, mapper::MapToError,
+	},
+};
+
+/// managed stacks are carved out of this area, well clear of the heap
+const STACK_AREA_START: u64 = HEAP_START as u64 + 0x1000_0000;
+
+/// 64 KiB, what the filesystem's recursive walks get
+pub const DEFAULT_STACK_PAGES: usize = 16;
+
+/// how many stacks can be registered with the page fault handler at once
+const MAX_STACKS: usize = 16;
+
+/// where the next stack's guard page goes, virtual addresses are never reused
+static NEXT_STACK: AtomicU64 = AtomicU64::new(STACK_AREA_START);
+
+/// guard page address and name of every live stack
+static GUARDS: Mutex<[Option<(u64, &'static str)>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+
+/// stacks handed out by `run_on_shared`, they live forever
+static SHARED: Mutex<Vec<&'static KernelStack>> = Mutex::new(Vec::new());
+
+#[derive(Debug)]
+pub enum StackError {
+	Map(MapToError<Size4KiB>),
+	/// every guard page slot is taken
+	TooManyStacks,
+}
+
+/// A kernel stack with an unmapped guard page below it
+///
+/// Running off the bottom faults on the guard page, and the page fault handler reports it as a
+/// stack overflow in `name` instead of letting it trash whatever lies below.
+#[derive(Debug)]
+pub struct KernelStack {
+	name: &'static str,
+	guard: Page,
+	pages: usize,
+	slot: usize,
+	/// set while something runs on it
+	in_use: AtomicBool,
+}
+
+impl KernelStack {
+	/// Maps a `pages` page stack with a guard page below it
+	///
+	/// `name` shows up in the overflow report, something like "fs::fsck".
+	pub fn new(
+		name: &'static str,
+		pages: usize,
+		mapper: &mut impl Mapper<Size4KiB>,
+		frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+	) -> Result<Self, StackError> {
+		let pages = pages.max(1);
+		let start = NEXT_STACK.fetch_add((pages as u64 + 1) * 4096, Ordering::Relaxed);
+		let guard = Page::containing_address(VirtAddr::new(start));
+		let range = Page::range_inclusive(guard + 1, guard + pages as u64);
+
+		let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+		if let Err(e) = memory::map_range(range, flags, mapper, frame_allocator) {
+			memory::unmap_range(range, mapper);
+			return Err(StackError::Map(e));
+		}
+
+		let slot = {
+			let mut guards = GUARDS.lock();
+			let slot = guards.iter().position(|entry| entry.is_none());
+			if let Some(slot) = slot {
+				guards[slot] = Some((guard.start_address().as_u64(), name));
+			}
+			slot
+		};
+
+		match slot {
+			Some(slot) => {
+				Ok(KernelStack { name, guard, pages, slot, in_use: AtomicBool::new(false) })
+			},
+			None => {
+				memory::unmap_range(range, mapper);
+				Err(StackError::TooManyStacks)
+			},
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		self.name
+	}
+
+	/// usable size in bytes, the guard page doesn't count
+	pub fn size(&self) -> usize {
+		self.pages * 4096
+	}
+
+	/// the initial stack pointer, stacks grow down from here
+	pub fn top(&self) -> VirtAddr {
+		(self.guard + 1).start_address() + self.size()
+	}
+
+	/// Unmaps the stack and forgets its guard page
+	///
+	/// The frames leak for now, the frame allocator can't take them back.
+	pub fn release(
+		self,
+		mapper: &mut impl Mapper<Size4KiB>,
+	) {
+		memory::unmap_range(
+			Page::range_inclusive(self.guard + 1, self.guard + self.pages as u64),
+			mapper,
+		);
+		GUARDS.lock()[self.slot] = None;
+	}
+}
+
+/// The name of the stack whose guard page `addr` is on, for the page fault handler
+///
+/// Doesn't wait for the lock, a fault while it's held just goes unrecognized.
+pub fn guard_page_owner(addr: VirtAddr) -> Option<&'static str> {
+	let page = addr.align_down(4096u64).as_u64();
+	let guards = GUARDS.try_lock()?;
+	guards.iter().flatten().find(|&&(guard, _)| guard == page).map(|&(_, name)| name)
+}
+
+/// Runs `f` on `stack` and switches back to the current one afterwards
+///
+/// If the stack is in use already we're running on it, so `f` just runs where it is.
+pub fn run_on<R>(
+	stack: &KernelStack,
+	f: impl FnOnce() -> R,
+) -> R {
+	if stack.in_use.swap(true, Ordering::Acquire) {
+		return f();
+	}
+
+	let mut f = Some(f);
+	let mut result = None;
+	let mut call = || result = Some((f.take().expect("closure ran twice"))());
+	let mut closure: &mut dyn FnMut() = &mut call;
+
+	unsafe { switch_and_call(stack.top(), &mut closure) };
+
+	stack.in_use.store(false, Ordering::Release);
+	result.expect("the stack trampoline didn't run the closure")
+}
+
+/// Runs `f` on the shared stack called `name`
+///
+/// The stack is created with `pages` pages on first use, from the global page mapper and frame
+/// allocator. Until those are set up, or if that fails, `f` runs on the current stack.
+pub fn run_on_shared<R>(
+	name: &'static str,
+	pages: usize,
+	f: impl FnOnce() -> R,
+) -> R {
+	match shared(name, pages) {
+		Some(stack) => run_on(stack, f),
+		None => f(),
+	}
+}
+
+fn shared(
+	name: &'static str,
+	pages: usize,
+) -> Option<&'static KernelStack> {
+	let mut shared = SHARED.lock();
+	if let Some(&stack) = shared.iter().find(|stack| stack.name == name) {
+		return Some(stack);
+	}
+
+	let mut mapper = PAGE_MAPPER.lock();
+	let mut frame_allocator = FRAME_ALLOCATOR.lock();
+
+	let stack = KernelStack::new(name, pages, mapper.as_mut()?, frame_allocator.as_mut()?)
+		.map_err(|e| println!("[STACK] WARNING: no stack for {}: {:?}", name, e))
+		.ok()?;
+
+	let stack: &'static KernelStack = Box::leak(Box::new(stack));
+	shared.push(stack);
+	Some(stack)
+}
+
+/// Points RSP at `top`, calls the closure there and comes back to the old stack
+unsafe fn switch_and_call(
+	top: VirtAddr,
+	closure: &mut &mut dyn FnMut(),
+) {
+	let closure = closure as *mut &mut dyn FnMut() as *mut u8;
+
+	unsafe {
+		asm!(
+			// the old RSP is the first thing on the new stack
+			"mov {old}, rsp",
+			"mov rsp, {top}",
+			"push {old}",
+			// 16 byte aligned at the call
+			"sub rsp, 8",
+			"call {trampoline}",
+			"add rsp, 8",
+			"pop rsp",
+			top = in(reg) top.as_u64(),
+			old = out(reg) _,
+			trampoline = sym trampoline,
+			in("rdi") closure,
+			clobber_abi("C"),
+		);
+	}
+}
+
+/// first frame on the new stack, `closure` is the `&mut &mut dyn FnMut()` from `switch_and_call`
+extern "C" fn trampoline(closure: *mut u8) {
+	let closure = closure as *mut &mut dyn FnMut();
+	unsafe { (**closure)() }
+}
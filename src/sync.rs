@@ -0,0 +1,17 @@
+// in src/sync.rs
+//
+// locking helpers on top of spin, and a ring buffer that needs no lock
+
+pub mod debug_mutex;
+pub mod ring_buf;
+
+pub use debug_mutex::DebugMutex;
+pub use ring_buf::RingBuf;
+
+/// The mutex for globals that are easy to lock twice, a `DebugMutex` with the `debug-mutex`
+/// feature and a plain `spin::Mutex` otherwise
+#[cfg(feature = "debug-mutex")]
+pub type Mutex<T> = DebugMutex<T>;
+
+#[cfg(not(feature = "debug-mutex"))]
+pub type Mutex<T> = spin::Mutex<T>;
@@ -0,0 +1,159 @@
+// in src/sync/debug_mutex.rs
+//
+// a spin::Mutex that panics instead of spinning forever when it's locked twice
+
+use core::{
+	fmt,
+	ops::{Deref, DerefMut},
+	panic::Location,
+	ptr,
+	sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+use spin::{Mutex, MutexGuard};
+
+/// A mutex that remembers who holds it
+///
+/// There's only one CPU and nothing preempts a locked section except interrupts, so whoever
+/// finds the lock taken is either further down the same call stack or an interrupt handler that
+/// cut in. Either way the holder never runs again while we spin, so instead of spinning
+/// `lock` panics and names both places.
+pub struct DebugMutex<T> {
+	inner: Mutex<T>,
+	/// where the current holder locked it, null while unlocked
+	holder: AtomicPtr<Location<'static>>,
+	/// whether interrupts were on when the holder locked it
+	holder_interrupts: AtomicBool,
+}
+
+/// Both ends of a lock that could never be taken
+#[derive(Debug, Clone, Copy)]
+pub struct Deadlock {
+	pub holder: Option<&'static Location<'static>>,
+	/// false means the holder had interrupts off, so we can't be an interrupt handler cutting in
+	pub holder_interrupts: bool,
+	pub requester: &'static Location<'static>,
+}
+
+impl fmt::Display for Deadlock {
+	fn fmt(
+		&self,
+		f: &mut fmt::Formatter,
+	) -> fmt::Result {
+		write!(f, "lock at {} would deadlock, it's already held", self.requester)?;
+		match self.holder {
+			Some(holder) => write!(f, " since {}", holder)?,
+			None => write!(f, " by someone")?,
+		}
+		if self.holder_interrupts {
+			write!(f, " (with interrupts on)")
+		} else {
+			write!(f, " (with interrupts off)")
+		}
+	}
+}
+
+impl<T> DebugMutex<T> {
+	pub const fn new(value: T) -> Self {
+		DebugMutex {
+			inner: Mutex::new(value),
+			holder: AtomicPtr::new(ptr::null_mut()),
+			holder_interrupts: AtomicBool::new(false),
+		}
+	}
+
+	/// Locks it, panicking if it's already held
+	#[track_caller]
+	pub fn lock(&self) -> DebugMutexGuard<T> {
+		match self.lock_checked() {
+			Ok(guard) => guard,
+			Err(deadlock) => {
+				// straight to the port, whatever prints normally might be what's locked
+				crate::early_println!("[ERROR] {}", deadlock);
+				panic!("{}", deadlock);
+			},
+		}
+	}
+
+	/// Locks it, or tells who holds it instead of panicking
+	#[track_caller]
+	pub fn lock_checked(&self) -> Result<DebugMutexGuard<T>, Deadlock> {
+		let requester = Location::caller();
+
+		match self.inner.try_lock() {
+			Some(guard) => {
+				let enabled = x86_64::instructions::interrupts::are_enabled();
+				self.holder_interrupts.store(enabled, Ordering::Relaxed);
+				self.holder.store(requester as *const _ as *mut _, Ordering::Release);
+				Ok(DebugMutexGuard { guard, holder: &self.holder })
+			},
+			None => {
+				let holder = self.holder.load(Ordering::Acquire);
+				Err(Deadlock {
+					// the pointer only ever comes from Location::caller
+					holder: unsafe { holder.as_ref() },
+					holder_interrupts: self.holder_interrupts.load(Ordering::Relaxed),
+					requester,
+				})
+			},
+		}
+	}
+
+	/// like `spin::Mutex::try_lock`, None if it's held
+	#[track_caller]
+	pub fn try_lock(&self) -> Option<DebugMutexGuard<T>> {
+		self.lock_checked().ok()
+	}
+
+	/// where the current holder locked it, None while it's free
+	pub fn holder(&self) -> Option<&'static Location<'static>> {
+		unsafe { self.holder.load(Ordering::Acquire).as_ref() }
+	}
+}
+
+pub struct DebugMutexGuard<'a, T> {
+	guard: MutexGuard<'a, T>,
+	holder: &'a AtomicPtr<Location<'static>>,
+}
+
+impl<T> Deref for DebugMutexGuard<'_, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		&self.guard
+	}
+}
+
+impl<T> DerefMut for DebugMutexGuard<'_, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		&mut self.guard
+	}
+}
+
+impl<T> Drop for DebugMutexGuard<'_, T> {
+	fn drop(&mut self) {
+		// cleared before `guard` unlocks, so nobody sees a free lock with a stale holder
+		self.holder.store(ptr::null_mut(), Ordering::Release);
+	}
+}
+
+#[test_case]
+fn test_reentrant_lock_detected() {
+	let mutex = DebugMutex::new(5u32);
+
+	let guard = mutex.lock();
+	let locked_at = mutex.holder().expect("holder not recorded");
+	assert_eq!(locked_at.file(), file!());
+
+	let deadlock = mutex.lock_checked().err().expect("relocking went through");
+	assert_eq!(deadlock.holder, Some(locked_at));
+	assert_eq!(deadlock.requester.file(), file!());
+	assert!(deadlock.requester.line() > locked_at.line());
+	assert!(mutex.try_lock().is_none());
+
+	drop(guard);
+	assert!(mutex.holder().is_none());
+
+	// free again, and it still holds the value
+	*mutex.lock() += 1;
+	assert_eq!(*mutex.lock(), 6);
+}
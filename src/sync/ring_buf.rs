@@ -0,0 +1,188 @@
+// in src/sync/ring_buf.rs
+//
+// a fixed size FIFO of Copy values that pushes and pops without a lock
+
+use core::{
+	cell::UnsafeCell,
+	mem::MaybeUninit,
+	sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use sa::assert_impl_all;
+
+/// A FIFO of at most `N` values, stored inline
+///
+/// Meant for one side in an interrupt handler and the other in a task, neither allocates or
+/// blocks. A full ring refuses the push, whoever pushes decides what to drop.
+///
+/// One push and one pop can run at the same time. A second push that starts while one is
+/// still running, an interrupt handler cutting into a task's push say, fails like the ring was
+/// full, and the same goes for pops.
+pub struct RingBuf<T: Copy, const N: usize> {
+	buf: UnsafeCell<[MaybeUninit<T>; N]>,
+	/// pops so far, the oldest value is in slot `head % N`
+	head: AtomicUsize,
+	/// pushes so far, the next push goes to slot `tail % N`
+	///
+	/// Both only count up, so `tail - head` is the length. 64 bits of pushes don't wrap.
+	tail: AtomicUsize,
+	/// a push is running
+	pushing: AtomicBool,
+	/// a pop is running
+	popping: AtomicBool,
+}
+
+// Sync: the slots between `head` and `tail` belong to the popper and the others to the pusher,
+// and `pushing`/`popping` make sure there is at most one of each. The pusher only writes a free
+// slot and publishes it with the Release store to `tail`, the popper only reads a slot after its
+// Acquire load of `tail` saw it published and hands it back with the Release store to `head`.
+// So no slot is ever written and read at the same time. Values are moved out by copy, which
+// hands them to another thread, hence `T: Send`. Send itself comes from the fields.
+unsafe impl<T: Copy + Send, const N: usize> Sync for RingBuf<T, N> {}
+
+assert_impl_all!(RingBuf<u8, 16>: Send, Sync);
+
+impl<T: Copy, const N: usize> RingBuf<T, N> {
+	pub const fn new() -> Self {
+		RingBuf {
+			buf: UnsafeCell::new([MaybeUninit::uninit(); N]),
+			head: AtomicUsize::new(0),
+			tail: AtomicUsize::new(0),
+			pushing: AtomicBool::new(false),
+			popping: AtomicBool::new(false),
+		}
+	}
+
+	/// Appends `val`, false if the ring is full or another push is running
+	pub fn push(
+		&self,
+		val: T,
+	) -> bool {
+		if self.pushing.swap(true, Ordering::Acquire) {
+			return false;
+		}
+
+		let tail = self.tail.load(Ordering::Relaxed);
+		let head = self.head.load(Ordering::Acquire);
+		let room = tail.wrapping_sub(head) < N;
+		if room {
+			// the slot is free, the popper is done with it since `head` moved past
+			unsafe { self.slot(tail).write(MaybeUninit::new(val)) };
+			self.tail.store(tail.wrapping_add(1), Ordering::Release);
+		}
+
+		self.pushing.store(false, Ordering::Release);
+		room
+	}
+
+	/// Takes the oldest value, None if the ring is empty or another pop is running
+	pub fn pop(&self) -> Option<T> {
+		if self.popping.swap(true, Ordering::Acquire) {
+			return None;
+		}
+
+		let head = self.head.load(Ordering::Relaxed);
+		let tail = self.tail.load(Ordering::Acquire);
+		let val = if head != tail {
+			// pushed and published by the Release store to `tail`
+			let val = unsafe { self.slot(head).read().assume_init() };
+			self.head.store(head.wrapping_add(1), Ordering::Release);
+			Some(val)
+		} else {
+			None
+		};
+
+		self.popping.store(false, Ordering::Release);
+		val
+	}
+
+	/// Values in the ring right now, may be stale by the time it returns
+	pub fn len(&self) -> usize {
+		// head first, tail never falls behind it
+		let head = self.head.load(Ordering::Acquire);
+		let tail = self.tail.load(Ordering::Acquire);
+		tail.wrapping_sub(head).min(N)
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	pub const fn capacity(&self) -> usize {
+		N
+	}
+
+	/// the slot counter `n` lands in
+	fn slot(
+		&self,
+		n: usize,
+	) -> *mut MaybeUninit<T> {
+		// only called with values to push or pop, so N isn't 0
+		unsafe { self.buf.get().cast::<MaybeUninit<T>>().add(n % N) }
+	}
+}
+
+impl<T: Copy, const N: usize> Default for RingBuf<T, N> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[test_case]
+fn test_ring_buf_wraps_around() {
+	let ring: RingBuf<u32, 4> = RingBuf::new();
+
+	// many times around, with the ring at every fill level on the way
+	let mut next_in = 0;
+	let mut next_out = 0;
+	for round in 0..20 {
+		for _ in 0..round % 4 + 1 {
+			assert!(ring.push(next_in));
+			next_in += 1;
+		}
+		assert_eq!(ring.len(), (next_in - next_out) as usize);
+		while let Some(val) = ring.pop() {
+			assert_eq!(val, next_out);
+			next_out += 1;
+		}
+		assert!(ring.is_empty());
+	}
+	assert_eq!(next_out, next_in);
+}
+
+#[test_case]
+fn test_ring_buf_refuses_when_full() {
+	let ring: RingBuf<u8, 3> = RingBuf::new();
+	assert_eq!(ring.pop(), None);
+
+	assert!(ring.push(1));
+	assert!(ring.push(2));
+	assert!(ring.push(3));
+	assert!(!ring.push(4));
+	assert_eq!(ring.len(), ring.capacity());
+
+	// room for exactly one more, in the slot 1 left
+	assert_eq!(ring.pop(), Some(1));
+	assert!(ring.push(4));
+	assert!(!ring.push(5));
+	assert_eq!([ring.pop(), ring.pop(), ring.pop(), ring.pop()], [Some(2), Some(3), Some(4), None]);
+
+	let empty: RingBuf<u8, 0> = RingBuf::new();
+	assert!(!empty.push(1));
+	assert_eq!(empty.pop(), None);
+}
+
+#[test_case]
+fn test_ring_buf_one_push_at_a_time() {
+	let ring: RingBuf<u8, 4> = RingBuf::new();
+
+	// what an interrupt handler sees when it cuts into a push
+	ring.pushing.store(true, Ordering::Relaxed);
+	assert!(!ring.push(1));
+	ring.pushing.store(false, Ordering::Relaxed);
+	assert!(ring.push(1));
+
+	ring.popping.store(true, Ordering::Relaxed);
+	assert_eq!(ring.pop(), None);
+	ring.popping.store(false, Ordering::Relaxed);
+	assert_eq!(ring.pop(), Some(1));
+}
@@ -0,0 +1,118 @@
+// in src/task/blocking.rs
+//
+// blocking device work, run one closure at a time on a low priority worker task
+
+use super::channel;
+use crate::sync::Mutex;
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+	future::Future,
+	pin::Pin,
+	task::{Context, Poll},
+};
+use futures_util::{stream::StreamExt, task::AtomicWaker};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// closures waiting for the worker, oldest first
+static QUEUE: Mutex<VecDeque<Job>> = Mutex::new(VecDeque::new());
+
+/// wakes the worker when something is queued
+static WORKER_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Runs `op` on the blocking worker and resolves to what it returned
+///
+/// `op` is queued right away, so closures run in the order `run_blocking` was called, no matter
+/// when the futures are awaited. The worker runs one closure per poll, so every other task gets a
+/// turn between two of them. Split long device work into one closure per block, see
+/// `run_blocking_chunked`.
+///
+/// Nothing runs unless some executor polls `worker()`.
+pub fn run_blocking<R>(op: impl FnOnce() -> R + Send + 'static) -> impl Future<Output = R>
+where
+	R: Send + 'static,
+{
+	let (sender, mut receiver) = channel::channel(1);
+
+	QUEUE.lock().push_back(Box::new(move || {
+		// the caller may have dropped the future, nobody wants the result then
+		let _ = sender.try_send(op());
+	}));
+	WORKER_WAKER.wake();
+
+	async move { receiver.next().await.expect("blocking job dropped without running") }
+}
+
+/// Runs `per_item` on every item of `items`, each call its own closure on the worker
+///
+/// The calls run one after the other and the results come back in the order of `items`.
+pub async fn run_blocking_chunked<I, F, R>(
+	items: I,
+	per_item: F,
+) -> Vec<R>
+where
+	I: IntoIterator,
+	I::Item: Send + 'static,
+	F: Fn(I::Item) -> R + Send + Sync + 'static,
+	R: Send + 'static,
+{
+	let per_item = Arc::new(per_item);
+	let mut results = Vec::new();
+
+	for item in items {
+		let per_item = per_item.clone();
+		results.push(run_blocking(move || per_item(item)).await);
+	}
+	results
+}
+
+/// closures waiting for the worker
+pub fn queued() -> usize {
+	QUEUE.lock().len()
+}
+
+/// The task that runs what `run_blocking` queues, spawn it once at `Priority::LOW`
+pub fn worker() -> Worker {
+	Worker { _private: () }
+}
+
+/// See `worker`
+pub struct Worker {
+	_private: (),
+}
+
+impl Future for Worker {
+	type Output = ();
+
+	fn poll(
+		self: Pin<&mut Self>,
+		cx: &mut Context,
+	) -> Poll<()> {
+		let job = {
+			let mut queue = QUEUE.lock();
+			let job = queue.pop_front();
+			// a burst of jobs doesn't keep the room it grew the queue to
+			if queue.is_empty() {
+				queue.shrink_to_fit();
+			}
+			job
+		};
+
+		match job {
+			Some(job) => {
+				// the lock is gone, so jobs can queue more jobs
+				job();
+				// one per poll, go to the back of the ready queue for the next
+				cx.waker().wake_by_ref();
+			},
+			None => {
+				WORKER_WAKER.register(cx.waker());
+				// something could have been queued between the pop and registering
+				if !QUEUE.lock().is_empty() {
+					cx.waker().wake_by_ref();
+				}
+			},
+		}
+		Poll::Pending
+	}
+}
@@ -0,0 +1,207 @@
+// in src/task/channel.rs
+
+use alloc::sync::Arc;
+use core::{
+	future::poll_fn,
+	pin::Pin,
+	sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+	task::{Context, Poll, Waker},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+
+/// how many senders can be parked on a full channel before the rest fall back to yielding
+const PARKED_SENDERS: usize = 16;
+
+/// State shared by all ends of a channel, allocated once when the channel is created
+struct Shared<T> {
+	queue: ArrayQueue<T>,
+	/// wakes the receiver when something was pushed or the last sender went away
+	receiver_waker: AtomicWaker,
+	/// senders waiting for room, all of them are woken whenever an item is taken out
+	sender_wakers: ArrayQueue<Waker>,
+	senders: AtomicUsize,
+	receiver_alive: AtomicBool,
+}
+
+impl<T> Shared<T> {
+	fn wake_senders(&self) {
+		// only drain what is there now, woken senders that still find it full park again
+		for _ in 0..self.sender_wakers.len() {
+			match self.sender_wakers.pop() {
+				Some(waker) => waker.wake(),
+				None => break,
+			}
+		}
+	}
+}
+
+/// Creates a bounded channel that holds at most `capacity` items
+///
+/// The buffer is allocated here, sending never allocates.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+	let shared = Arc::new(Shared {
+		queue: ArrayQueue::new(capacity),
+		receiver_waker: AtomicWaker::new(),
+		sender_wakers: ArrayQueue::new(PARKED_SENDERS),
+		senders: AtomicUsize::new(1),
+		receiver_alive: AtomicBool::new(true),
+	});
+
+	(Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+/// the receiver is gone, the item comes back
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+	/// no room right now
+	Full(T),
+	/// the receiver is gone
+	Closed(T),
+}
+
+/// The sending half, clone it for more producers
+pub struct Sender<T> {
+	shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+	/// Pushes `item` without waiting
+	///
+	/// Doesn't block or allocate, so it's fine to call from an interrupt handler as long as
+	/// dropping a `T` that comes back in the error doesn't do either (plain data, no Box/Vec).
+	pub fn try_send(
+		&self,
+		item: T,
+	) -> Result<(), TrySendError<T>> {
+		if !self.shared.receiver_alive.load(Ordering::Acquire) {
+			return Err(TrySendError::Closed(item));
+		}
+
+		match self.shared.queue.push(item) {
+			Ok(()) => {
+				self.shared.receiver_waker.wake();
+				Ok(())
+			},
+			Err(item) => Err(TrySendError::Full(item)),
+		}
+	}
+
+	/// Takes the oldest item back out before the receiver got it, for input nobody should see
+	///
+	/// Lock-free like `try_send`, so a handler sending at the same time is fine.
+	pub fn take_back(&self) -> Option<T> {
+		let item = self.shared.queue.pop()?;
+		self.shared.wake_senders();
+		Some(item)
+	}
+
+	/// Pushes `item`, parking the task while the channel is full
+	pub async fn send(
+		&self,
+		item: T,
+	) -> Result<(), SendError<T>> {
+		let mut item = Some(item);
+		poll_fn(|cx| self.poll_send(cx, &mut item)).await
+	}
+
+	fn poll_send(
+		&self,
+		cx: &mut Context,
+		slot: &mut Option<T>,
+	) -> Poll<Result<(), SendError<T>>> {
+		let item = slot.take().expect("send polled after completion");
+
+		// fast path
+		let item = match self.try_send(item) {
+			Ok(()) => return Poll::Ready(Ok(())),
+			Err(TrySendError::Closed(item)) => return Poll::Ready(Err(SendError(item))),
+			Err(TrySendError::Full(item)) => item,
+		};
+
+		// the receiver could've made room right after the check, so park before trying again
+		if let Err(waker) = self.shared.sender_wakers.push(cx.waker().clone()) {
+			// too many parked already, come back on the next round
+			waker.wake();
+		}
+
+		match self.try_send(item) {
+			Ok(()) => Poll::Ready(Ok(())),
+			Err(TrySendError::Closed(item)) => Poll::Ready(Err(SendError(item))),
+			Err(TrySendError::Full(item)) => {
+				*slot = Some(item);
+				Poll::Pending
+			},
+		}
+	}
+}
+
+impl<T> Clone for Sender<T> {
+	fn clone(&self) -> Self {
+		self.shared.senders.fetch_add(1, Ordering::Relaxed);
+		Sender { shared: self.shared.clone() }
+	}
+}
+
+impl<T> Drop for Sender<T> {
+	fn drop(&mut self) {
+		// the last one out ends the stream
+		if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+			self.shared.receiver_waker.wake();
+		}
+	}
+}
+
+/// The receiving half, a stream that ends once every sender is dropped and the channel is empty
+pub struct Receiver<T> {
+	shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+	/// takes the next item if there is one, without waiting
+	pub fn try_recv(&self) -> Option<T> {
+		let item = self.shared.queue.pop()?;
+		self.shared.wake_senders();
+		Some(item)
+	}
+}
+
+impl<T> Stream for Receiver<T> {
+	type Item = T;
+
+	fn poll_next(
+		self: Pin<&mut Self>,
+		cx: &mut Context,
+	) -> Poll<Option<T>> {
+		// fast path
+		if let Some(item) = self.try_recv() {
+			return Poll::Ready(Some(item));
+		}
+
+		// same dance as the scancode stream, register before looking again
+		self.shared.receiver_waker.register(cx.waker());
+
+		if let Some(item) = self.try_recv() {
+			self.shared.receiver_waker.take();
+			return Poll::Ready(Some(item));
+		}
+
+		if self.shared.senders.load(Ordering::Acquire) == 0 {
+			// whatever the last sender pushed before going away is visible by now
+			return Poll::Ready(self.try_recv());
+		}
+
+		Poll::Pending
+	}
+}
+
+impl<T> Drop for Receiver<T> {
+	fn drop(&mut self) {
+		self.shared.receiver_alive.store(false, Ordering::Release);
+		// parked senders find out the channel is closed
+		self.shared.wake_senders();
+	}
+}
@@ -1,25 +1,167 @@
 // in src/task/executor.rs
 
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc};
+use super::{
+	MAX_AGING_BOOST, NUM_PRIORITIES, Priority, Running, Task, TaskId,
+	trace::{self, TraceKind},
+};
+use crate::{
+	allocator::{self, SlabBox, SlabCache},
+	interrupts,
+};
+use alloc::{
+	collections::{BTreeMap, VecDeque},
+	sync::Arc,
+	vec::Vec,
+};
+use core::fmt::{self, Write};
 use core::task::{Context, Poll, Waker};
 use crossbeam_queue::ArrayQueue;
 use futures_util::task::waker;
+use spin::Mutex;
+
+/// A live task, as listed by `list_tasks`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskInfo {
+	pub id: u64,
+	/// the priority it was spawned with, or the one it was last set to
+	pub priority: Priority,
+	/// the priority it's scheduled at right now, aging boosts included
+	pub dyn_priority: Priority,
+}
+
+/// the bookkeeping of every spawned task, its future stays on the heap
+static TASKS: SlabCache<Task> = SlabCache::new("tasks", 32);
+
+/// every spawned task that hasn't finished, on whichever executor
+///
+/// Tasks can't reach the executor polling them, so this is how a shell lists them.
+static REGISTRY: Mutex<BTreeMap<TaskId, TaskInfo>> = Mutex::new(BTreeMap::new());
+
+/// Takes a task that finished, or whose executor is gone, out of the registry
+fn unregister(
+	registry: &mut BTreeMap<TaskId, TaskInfo>,
+	task_id: &TaskId,
+) {
+	registry.remove(task_id);
+	// the last remove leaves the root node allocated, a new map holds no heap
+	if registry.is_empty() {
+		*registry = BTreeMap::new();
+	}
+}
+
+/// returns the unfinished tasks of all executors, ordered by id
+pub fn list_tasks() -> Vec<TaskInfo> {
+	REGISTRY.lock().values().copied().collect()
+}
+
+/// Writes a table with one row per task and its base and dynamic priority, what `ps -v` shows
+///
+/// Drawn with box drawing characters, the VGA writer maps them to code page 437.
+pub fn write_report(
+	out: &mut impl Write,
+	tasks: &[TaskInfo],
+) -> fmt::Result {
+	writeln!(out, "{} tasks", tasks.len())?;
+	writeln!(out, "┌────────┬──────┬──────┐")?;
+	writeln!(out, "│ {:>6} │ {:>4} │ {:>4} │", "id", "base", "dyn")?;
+	writeln!(out, "├────────┼──────┼──────┤")?;
+	for task in tasks {
+		let boosted = if task.dyn_priority > task.priority { " (aged)" } else { "" };
+		writeln!(
+			out,
+			"│ {:>6} │ {:>4} │ {:>4} │{}",
+			task.id, task.priority, task.dyn_priority, boosted
+		)?;
+	}
+	writeln!(out, "└────────┴──────┴──────┘")
+}
+
+/// keeps the registry's copy of a task's dynamic priority current
+fn update_dyn_priority(
+	task_id: TaskId,
+	dyn_priority: Priority,
+) {
+	if let Some(info) = REGISTRY.lock().get_mut(&task_id) {
+		info.dyn_priority = dyn_priority;
+	}
+}
+
+/// priority changes asked for from outside the executors, applied on their next poll
+static PRIORITY_REQUESTS: Mutex<VecDeque<(u64, Priority)>> = Mutex::new(VecDeque::new());
+
+/// Asks whichever executor runs task `id` to give it `priority`, what `nice` does
+///
+/// A task can't reach the executor polling it, so the change is queued and applied before that
+/// executor's next poll. Returns false if no unfinished task has that id.
+pub fn request_priority(
+	id: u64,
+	priority: Priority,
+) -> bool {
+	if !REGISTRY.lock().contains_key(&TaskId(id)) {
+		return false;
+	}
+	PRIORITY_REQUESTS.lock().push_back((id, priority));
+	true
+}
+
+/// Scheduling knobs for the [`Executor`]
+#[derive(Debug, Clone, Copy)]
+pub struct ExecutorConfig {
+	/// consecutive polls the highest bucket gets before one task from a lower bucket runs
+	pub burst_limit: usize,
+	/// ticks a task may sit in the ready queue before it gets a +1 priority boost, 0 disables
+	/// aging
+	pub aging_ticks: u64,
+}
+
+impl Default for ExecutorConfig {
+	fn default() -> Self {
+		ExecutorConfig { burst_limit: 16, aging_ticks: 10 }
+	}
+}
+
+/// Counters to make starvation observable
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ExecutorStats {
+	/// polls done from each priority bucket, indexed by `Priority::bucket`
+	pub polls_per_bucket: [u64; NUM_PRIORITIES],
+	/// polls handed to a lower bucket because the burst limit was hit
+	pub forced_polls: u64,
+	/// number of aging boosts given out
+	pub aging_boosts: u64,
+}
 
 pub struct Executor {
-	tasks: BTreeMap<TaskId, Task>,
+	tasks: BTreeMap<TaskId, SlabBox<Task>>,
 	/// reference counted ArrayQueue, shared between Executors and Wakers
 	task_queue: Arc<ArrayQueue<TaskId>>,
 	waker_cache: BTreeMap<TaskId, Waker>,
+	/// ready tasks bucketed by their dynamic priority, the index is `Priority::bucket`
+	///
+	/// wakers can't touch these since they may run in interrupt context, the executor moves
+	/// woken tasks over from `task_queue`
+	ready: [VecDeque<TaskId>; NUM_PRIORITIES],
+	config: ExecutorConfig,
+	stats: ExecutorStats,
+	/// bucket that got the latest polls and how many in a row
+	streak: (usize, usize),
 }
 
 impl Executor {
 	pub fn new() -> Self {
+		Executor::with_config(ExecutorConfig::default())
+	}
+
+	pub fn with_config(config: ExecutorConfig) -> Self {
 		Executor {
 			tasks: BTreeMap::new(),
 			// using a fixed queue, since interrupt handlers should not allocate on push
 			task_queue: Arc::new(ArrayQueue::new(100)),
 			waker_cache: BTreeMap::new(),
+			ready: core::array::from_fn(|_| VecDeque::new()),
+			config,
+			stats: ExecutorStats::default(),
+			streak: (0, 0),
 		}
 	}
 
@@ -28,43 +170,304 @@ impl Executor {
 		task: Task,
 	) {
 		let task_id = task.id;
-		if self.tasks.insert(task.id, task).is_some() {
+		let info = TaskInfo {
+			id: task.id.0,
+			priority: task.base_priority,
+			dyn_priority: task.dyn_priority,
+		};
+		let task = TASKS.alloc(task).expect("no memory left for the task");
+		if self.tasks.insert(task_id, task).is_some() {
 			panic!("task with same ID already in tasks");
 		}
+		REGISTRY.lock().insert(task_id, info);
 		self.task_queue.push(task_id).expect("queue full");
 	}
 
+	/// Gives task `id` a new base priority, false if this executor doesn't have it
+	///
+	/// Any aging boost is dropped with it. A task already waiting in a ready bucket moves to the
+	/// new one right away, behind the tasks that are there.
+	pub fn set_priority(
+		&mut self,
+		id: u64,
+		priority: Priority,
+	) -> bool {
+		let task_id = TaskId(id);
+		let task = match self.tasks.get_mut(&task_id) {
+			Some(task) => task,
+			None => return false,
+		};
+
+		let old_bucket = task.dyn_priority.bucket();
+		task.base_priority = priority;
+		task.dyn_priority = priority;
+		if let Some(info) = REGISTRY.lock().get_mut(&task_id) {
+			info.priority = priority;
+			info.dyn_priority = priority;
+		}
+
+		if task.ready_since.is_some() && old_bucket != priority.bucket() {
+			self.ready[old_bucket].retain(|&queued| queued != task_id);
+			self.ready[priority.bucket()].push_back(task_id);
+		}
+		true
+	}
+
+	/// returns a copy of the scheduling counters
+	pub fn stats(&self) -> ExecutorStats {
+		self.stats
+	}
+
+	/// This executor's tasks with their scheduling state, ordered by id
+	pub fn task_infos(&self) -> Vec<TaskInfo> {
+		self.tasks
+			.values()
+			.map(|task| TaskInfo {
+				id: task.id.0,
+				priority: task.base_priority,
+				dyn_priority: task.dyn_priority,
+			})
+			.collect()
+	}
+
+	/// Prints every task's id, base and dynamic priority to serial
+	///
+	/// Only metadata, futures can't be saved. Meant for chasing scheduling bugs, `ps -v` in the
+	/// shell shows the same for all executors.
+	pub fn dump_state(&self) {
+		let mut report = alloc::string::String::new();
+		// writing to a String can't fail
+		let _ = write_report(&mut report, &self.task_infos());
+		crate::serial_print!("{}", report);
+	}
+
 	pub fn run(&mut self) -> ! {
 		loop {
 			self.run_ready_tasks();
 		}
 	}
 
-	/// To execute all tasks in the task_queue
+	/// Polls at most `max_polls` tasks, returns how many were actually polled
 	///
-	/// Loop over all tasks in the task_queue, create a waker for each task and then poll them
+	/// `run` never returns, so this is the way to drive the executor step by step
+	pub fn run_polls(
+		&mut self,
+		max_polls: usize,
+	) -> usize {
+		let mut polled = 0;
+		while polled < max_polls && self.poll_next() {
+			polled += 1;
+		}
+		polled
+	}
+
+	/// To execute all tasks in the ready queues
+	///
+	/// Keeps polling until no task is ready anymore
 	fn run_ready_tasks(&mut self) {
+		while self.poll_next() {}
+	}
+
+	/// Picks the next task according to the priority and fairness policy and polls it
+	///
+	/// Returns false if no task was ready
+	fn poll_next(&mut self) -> bool {
+		let now = interrupts::ticks();
+		self.apply_priority_requests();
+		self.collect_woken(now);
+		self.age_ready_tasks(now);
+
+		let bucket = match self.pick_bucket() {
+			Some(bucket) => bucket,
+			None => return false,
+		};
+
 		// destructure 'self' to avoid borrow checker errors
-		let Self { tasks, task_queue, waker_cache } = self;
+		let Self { tasks, task_queue, waker_cache, ready, stats, .. } = self;
+
+		let task_id = match ready[bucket].pop_front() {
+			Some(task_id) => task_id,
+			None => return false,
+		};
+		let task = match tasks.get_mut(&task_id) {
+			Some(task) => task,
+			None => return true,
+		};
+
+		// leaving the ready queue, so any aging boost is dropped
+		task.ready_since = None;
+		if task.dyn_priority != task.base_priority {
+			task.dyn_priority = task.base_priority;
+			update_dyn_priority(task_id, task.dyn_priority);
+		}
+		stats.polls_per_bucket[bucket] += 1;
+
+		let waker = waker_cache
+			.entry(task_id)
+			.or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+		let mut context = Context::from_waker(waker);
+
+		let tracing = trace::enabled();
+		let started = if tracing { trace::cycles() } else { 0 };
+		// other FPU tasks load their own registers in between, this one finds its own as it left them
+		if let Some(fpu) = task.fpu.as_deref() {
+			unsafe { crate::fpu::fxrstor(fpu) };
+		}
+		let result = {
+			let _running = Running::enter(task_id);
+			task.poll(&mut context)
+		};
+		if let Some(fpu) = task.fpu.as_deref_mut() {
+			unsafe { crate::fpu::fxsave(fpu) };
+		}
+		if tracing {
+			let kind = if result.is_ready() { TraceKind::Completed } else { TraceKind::Polled };
+			trace::record(task_id.0, kind, trace::cycles().wrapping_sub(started));
+		}
+
+		match result {
+			Poll::Ready(()) => {
+				// task done -> remove it and its cached waker
+				tasks.remove(&task_id);
+				waker_cache.remove(&task_id);
+				unregister(&mut REGISTRY.lock(), &task_id);
+				// what its future held is freed by now, the rest isn't its doing anymore
+				allocator::forget_task_heap(task_id.0);
+				crate::kernel_assert!(
+					waker_cache.len() <= tasks.len(),
+					"{} cached wakers for {} tasks",
+					waker_cache.len(),
+					tasks.len()
+				);
+			},
+			Poll::Pending => {},
+		}
 
-		while let Some(task_id) = task_queue.pop() {
-			let task = match tasks.get_mut(&task_id) {
+		true
+	}
+
+	/// Applies the `request_priority` calls meant for tasks of this executor
+	fn apply_priority_requests(&mut self) {
+		let mut requests = PRIORITY_REQUESTS.lock();
+		if requests.is_empty() {
+			return;
+		}
+
+		// the others are for another executor's tasks, or for tasks that finished since
+		let mut i = 0;
+		while i < requests.len() {
+			let (id, priority) = requests[i];
+			if self.set_priority(id, priority) || !REGISTRY.lock().contains_key(&TaskId(id)) {
+				requests.remove(i);
+			} else {
+				i += 1;
+			}
+		}
+		// like the registry, nothing queued means no heap kept
+		if requests.is_empty() {
+			*requests = VecDeque::new();
+		}
+	}
+
+	/// Moves woken task ids from the interrupt-safe queue into the priority buckets
+	fn collect_woken(
+		&mut self,
+		now: u64,
+	) {
+		while let Some(task_id) = self.task_queue.pop() {
+			let task = match self.tasks.get_mut(&task_id) {
 				Some(task) => task,
 				None => continue,
 			};
-			let waker = waker_cache
-				.entry(task_id)
-				.or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-			let mut context = Context::from_waker(waker);
-			match task.poll(&mut context) {
-				Poll::Ready(()) => {
-					// task done -> remove it and its cached waker
-					tasks.remove(&task_id);
-					waker_cache.remove(&task_id);
-				},
-				Poll::Pending => {},
+
+			// already waiting in a bucket, another wake-up changes nothing
+			if task.ready_since.is_some() {
+				continue;
+			}
+
+			task.ready_since = Some(now);
+			self.ready[task.dyn_priority.bucket()].push_back(task_id);
+			trace::record(task_id.0, TraceKind::Ready, 0);
+		}
+	}
+
+	/// Lifts every task that waited `aging_ticks` without being polled one bucket
+	///
+	/// The boost is capped at `MAX_AGING_BOOST` buckets above the base priority and at
+	/// `Priority::CRITICAL`, and the wait restarts after each boost, so a starving task climbs one
+	/// bucket per `aging_ticks`
+	fn age_ready_tasks(
+		&mut self,
+		now: u64,
+	) {
+		if self.config.aging_ticks == 0 {
+			return;
+		}
+
+		let Self { tasks, ready, config, stats, .. } = self;
+
+		// top down, so a task that just moved up isn't looked at twice in one pass
+		for bucket in (0..NUM_PRIORITIES - 1).rev() {
+			let mut i = 0;
+			while i < ready[bucket].len() {
+				let task_id = ready[bucket][i];
+				let boosted = match tasks.get_mut(&task_id) {
+					Some(task) => {
+						let waited = now.saturating_sub(task.ready_since.unwrap_or(now));
+						let cap = task.base_priority.boosted(MAX_AGING_BOOST);
+
+						if waited >= config.aging_ticks && task.dyn_priority < cap {
+							task.dyn_priority = task.dyn_priority.boosted(1).min(cap);
+							task.ready_since = Some(now);
+							update_dyn_priority(task_id, task.dyn_priority);
+							Some(task.dyn_priority.bucket())
+						} else {
+							None
+						}
+					},
+					None => None,
+				};
+
+				match boosted {
+					Some(to) if to != bucket => {
+						ready[bucket].remove(i);
+						ready[to].push_back(task_id);
+						stats.aging_boosts += 1;
+					},
+					// stopped short by the cap inside the bucket it was in
+					Some(_) => {
+						stats.aging_boosts += 1;
+						i += 1;
+					},
+					None => i += 1,
+				}
+			}
+		}
+	}
+
+	/// Chooses the bucket to poll from
+	///
+	/// Normally the highest non-empty bucket, but once it had `burst_limit` polls in a row the
+	/// highest non-empty lower bucket gets one turn before the burst starts over
+	fn pick_bucket(&mut self) -> Option<usize> {
+		let highest = (0..NUM_PRIORITIES).rev().find(|&bucket| !self.ready[bucket].is_empty())?;
+		let (streak_bucket, streak_len) = self.streak;
+
+		if streak_bucket == highest && streak_len >= self.config.burst_limit {
+			if let Some(lower) = (0..highest).rev().find(|&bucket| !self.ready[bucket].is_empty()) {
+				self.streak = (highest, 0);
+				self.stats.forced_polls += 1;
+				return Some(lower);
 			}
 		}
+
+		if streak_bucket == highest {
+			self.streak.1 += 1;
+		} else {
+			self.streak = (highest, 1);
+		}
+
+		Some(highest)
 	}
 
 	/// save power when no tasks are available
@@ -75,7 +478,7 @@ impl Executor {
 
 		interrupts::disable();
 
-		if self.task_queue.is_empty() {
+		if self.task_queue.is_empty() && self.ready.iter().all(|bucket| bucket.is_empty()) {
 			enable_and_hlt();
 		} else {
 			interrupts::enable();
@@ -83,6 +486,17 @@ impl Executor {
 	}
 }
 
+impl Drop for Executor {
+	fn drop(&mut self) {
+		// whatever didn't finish is gone with it
+		let mut registry = REGISTRY.lock();
+		for task_id in self.tasks.keys() {
+			unregister(&mut registry, task_id);
+			allocator::forget_task_heap(task_id.0);
+		}
+	}
+}
+
 struct TaskWaker {
 	task_id: TaskId,
 	task_queue: Arc<ArrayQueue<TaskId>>,
@@ -97,6 +511,7 @@ impl TaskWaker {
 	}
 
 	fn wake_task(&self) {
+		trace::record_wake(self.task_id.0);
 		self.task_queue.push(self.task_id).expect("task_queue full");
 	}
 }
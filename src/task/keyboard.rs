@@ -1,14 +1,39 @@
 // in src/task/keyboard.rs
 
+use super::channel::{self, Receiver, Sender, TrySendError};
+use crate::sync::{Mutex, RingBuf};
 use conquer_once::spin::OnceCell;
 use core::iter::Scan;
-use crossbeam_queue::ArrayQueue;
 
-/// Used to store the tasks from the Interrupt Handler
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+/// how many scancodes can pile up before the interrupt handler starts dropping them
+const SCANCODE_CAPACITY: usize = 100;
+
+/// Used to hand the scancodes from the Interrupt Handler to the ScancodeStream
+static SCANCODE_SENDER: OnceCell<Sender<u8>> = OnceCell::uninit();
+
+/// the other end of the channel, until `ScancodeStream::new` takes it
+static SCANCODE_RECEIVER: Mutex<Option<Receiver<u8>>> = Mutex::new(None);
+
+/// scancodes `flush` kept, the ScancodeStream hands them out before the queued ones
+static KEPT: RingBuf<u8, 16> = RingBuf::new();
+
+/// break codes of the keys `KeyDecoder` tracks as held, Shift, Ctrl and Alt (AltGr with E0)
+const MODIFIER_RELEASES: [u8; 4] = [0xAA, 0xB6, 0x9D, 0xB8];
 
 use crate::println;
 
+/// Makes the scancode channel, called once by `allocator::init_heap`
+///
+/// Scancodes that come in before the ScancodeStream is made wait in it.
+pub(crate) fn init_channel() {
+	let (sender, scancodes) = channel::channel(SCANCODE_CAPACITY);
+
+	SCANCODE_SENDER
+		.try_init_once(|| sender)
+		.expect("keyboard::init_channel should only be called once");
+	*SCANCODE_RECEIVER.lock() = Some(scancodes);
+}
+
 /// Called by the keyboard interrupt handler
 ///
 /// Not callable from main.rs
@@ -16,35 +41,81 @@ use crate::println;
 ///
 /// Must not block or allocate!
 pub(crate) fn add_scancode(scancode: u8) {
-	// get a reference to the initialized queue
-	if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-		if let Err(_) = queue.push(scancode) {
-			println!("WARNING: SCANCODE_QUEUE full; dropping keyboard input");
-		} else {
-			// you get an input, you wake up the SCANCODE_WAKER
-			SCANCODE_WAKER.wake();
-			// the waker in turn notifies the executor
+	// get a reference to the initialized channel
+	if let Ok(sender) = SCANCODE_SENDER.try_get() {
+		// a successful send wakes the stream, which in turn notifies the executor
+		match sender.try_send(scancode) {
+			Ok(()) => {},
+			Err(TrySendError::Full(_)) => {
+				println!("WARNING: scancode channel full; dropping keyboard input")
+			},
+			Err(TrySendError::Closed(_)) => {
+				println!("WARNING: scancode stream dropped; ignoring keyboard input")
+			},
 		}
 	} else {
 		println!("WARNING: scancode queue uninitialized!");
 	}
 }
 
-/// To initialize the SCANCODE_QUEUE and read the scancodes in the queue in an
+/// Drops every scancode that's queued and not read yet, returns how many
+///
+/// For after a long command, so keys typed while it ran don't end up in the next prompt. Letting
+/// go of Shift, Ctrl or Alt still reaches the decoder, it would think them held otherwise. So
+/// does an E0 prefix at the very end whose second half is still to come. Fine to call with
+/// keyboard interrupts on.
+pub fn flush() -> usize {
+	let sender = match SCANCODE_SENDER.try_get() {
+		Ok(sender) => sender,
+		Err(_) => return 0,
+	};
+
+	let mut dropped = 0;
+	let mut after_e0 = false;
+	while let Some(scancode) = sender.take_back() {
+		if scancode == 0xE0 {
+			after_e0 = true;
+			continue;
+		}
+
+		if MODIFIER_RELEASES.contains(&scancode) {
+			if after_e0 {
+				keep(0xE0);
+			}
+			keep(scancode);
+		} else {
+			dropped += 1 + after_e0 as usize;
+		}
+		after_e0 = false;
+	}
+	if after_e0 {
+		keep(0xE0);
+	}
+	dropped
+}
+
+fn keep(scancode: u8) {
+	if !KEPT.push(scancode) {
+		println!("WARNING: too many keys let go during a flush; dropping one");
+	}
+}
+
+/// To initialize the scancode channel and read the scancodes in it in an
 /// asynchronous way, we make a scancode stream
 pub struct ScancodeStream {
-	/// purpose of this field is to prevent construction of this outside of the module
-	_private: (),
+	/// private, so this can't be constructed outside of the module
+	scancodes: Receiver<u8>,
 }
 
 impl ScancodeStream {
 	/// made for exclusive creation of ScancodeStream since it is a private struct
 	pub fn new() -> Self {
-		SCANCODE_QUEUE
-			.try_init_once(|| ArrayQueue::new(100))
+		let scancodes = SCANCODE_RECEIVER
+			.lock()
+			.take()
 			.expect("ScancodeStream::new should only be called once");
 
-		ScancodeStream { _private: () }
+		ScancodeStream { scancodes }
 	}
 
 	// Next, we need to make something so that we can poll continuously from the stream
@@ -54,15 +125,6 @@ impl ScancodeStream {
 }
 
 use futures_util::stream::Stream;
-use futures_util::task::AtomicWaker;
-
-/// Waker for scancode stream
-///
-/// The poll_next implementation stores the current waker in this static,
-/// and the add_scancode function calls wake() on this when a new scancode is added.
-///
-/// AtomicWaker --> can be modified safely in concurrent scenarios
-static SCANCODE_WAKER: AtomicWaker = AtomicWaker::new();
 
 use core::pin::Pin;
 use core::task::{Context, Poll};
@@ -74,51 +136,335 @@ impl Stream for ScancodeStream {
 		self: Pin<&mut Self>,
 		cx: &mut Context,
 	) -> Poll<Option<u8>> {
-		let queue = SCANCODE_QUEUE.try_get().expect("scancode not initialized");
-
-		// fast path
-		if let Some(scancode) = queue.pop() {
+		if let Some(scancode) = KEPT.pop() {
 			return Poll::Ready(Some(scancode));
-			// don't need the waker if it's not pending
 		}
 
-		// the queue might be potentially empty here .. since the interrupt handler
-		// could've filled it immediately after the check
-		// .. hence we have to register the waker before the second check
-		// We get a guarantee that we get a wakeup for any scancodes pushed after the check
+		// the channel registers the waker for us, the same way this used to do by hand
+		Pin::new(&mut self.get_mut().scancodes).poll_next(cx)
+	}
+}
+
+use super::keymap::{self, Composed, Composer};
+use crate::print;
+use futures_util::stream::StreamExt;
+use pc_keyboard::{DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, ScancodeSet1, layouts};
+
+pub use super::keymap::{Keymap, KeymapError, load_keymap, set_keymap};
+
+/// State of the modifier keys at the time a key was decoded
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+	pub shift: bool,
+	pub ctrl: bool,
+	pub alt: bool,
+	/// the right alt key, which also sets `alt`
+	pub altgr: bool,
+	pub caps_lock: bool,
+}
+
+/// A decoded key together with the modifiers that were held when it was pressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+	pub key: DecodedKey,
+	pub modifiers: Modifiers,
+}
+
+impl KeyEvent {
+	/// true for Ctrl-C, which readers use to cancel the current input
+	pub fn is_ctrl_c(&self) -> bool {
+		// with HandleControl::MapLettersToUnicode, Ctrl+C decodes to ETX (0x03)
+		self.modifiers.ctrl
+			&& matches!(self.key, DecodedKey::Unicode('\u{3}') | DecodedKey::Unicode('c'))
+	}
+}
+
+/// Turns raw scancodes into [`KeyEvent`]s, tracking the modifier state on the way
+///
+/// Kept separate from the stream so it can be fed scancodes directly. pc_keyboard always does the
+/// make, break and E0 handling. Keys the active keymap has an entry for skip its US layout, see
+/// `keymap::set_keymap`.
+pub struct KeyDecoder {
+	keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+	modifiers: Modifiers,
+	lshift: bool,
+	rshift: bool,
+	lctrl: bool,
+	rctrl: bool,
+	lalt: bool,
+	/// AltGr
+	ralt: bool,
+	/// the last byte was the E0 prefix, keymaps only cover the keys without it
+	after_e0: bool,
+	composer: Composer,
+	/// the second of two events one scancode made, see `take_queued`
+	queued: Option<KeyEvent>,
+}
+
+impl KeyDecoder {
+	pub fn new() -> Self {
+		KeyDecoder {
+			// letters pressed with Ctrl are mapped to the control characters (Ctrl-C -> 0x03)
+			keyboard: Keyboard::new(
+				ScancodeSet1::new(),
+				layouts::Us104Key,
+				HandleControl::MapLettersToUnicode,
+			),
+			modifiers: Modifiers::default(),
+			lshift: false,
+			rshift: false,
+			lctrl: false,
+			rctrl: false,
+			lalt: false,
+			ralt: false,
+			after_e0: false,
+			composer: Composer::new(),
+			queued: None,
+		}
+	}
+
+	/// current modifier state
+	pub fn modifiers(&self) -> Modifiers {
+		self.modifiers
+	}
+
+	/// feeds one scancode, returns an event once a full key press has been decoded
+	///
+	/// modifier keys only update the state and don't produce events on their own
+	pub fn feed(
+		&mut self,
+		scancode: u8,
+	) -> Option<KeyEvent> {
+		let extended = core::mem::replace(&mut self.after_e0, scancode == 0xE0);
+
+		let key_event = match self.keyboard.add_byte(scancode) {
+			Ok(Some(key_event)) => key_event,
+			_ => return None,
+		};
 
-		SCANCODE_WAKER.register(&cx.waker());
+		self.track_modifier(key_event.code, key_event.state);
 
-		match queue.pop() {
-			Some(scancode) => {
-				// it succeeds so need for the SCANCODE_WAKER anymore
-				SCANCODE_WAKER.take();
-				Poll::Ready(Some(scancode))
+		if !extended {
+			if let Some(entry) = keymap::active_entry(scancode & 0x7F) {
+				if key_event.state != KeyState::Down {
+					return None;
+				}
+				let m = self.modifiers;
+				let (c, dead) = entry.lookup(m.shift, m.caps_lock, m.altgr)?;
+				return self.compose(c, dead);
+			}
+		}
+
+		let key = self.keyboard.process_keyevent(key_event)?;
+		self.pass_through(key)
+	}
+
+	/// A second event the last scancode made, take it before feeding the next one
+	///
+	/// A dead key that doesn't combine with what comes after it gives two events.
+	pub fn take_queued(&mut self) -> Option<KeyEvent> {
+		self.queued.take()
+	}
+
+	fn event(
+		&self,
+		key: DecodedKey,
+	) -> KeyEvent {
+		KeyEvent { key, modifiers: self.modifiers }
+	}
+
+	/// a character from the keymap, through the dead key composer
+	fn compose(
+		&mut self,
+		c: char,
+		dead: bool,
+	) -> Option<KeyEvent> {
+		// like pc_keyboard's MapLettersToUnicode, Ctrl-C gives ETX
+		if self.modifiers.ctrl && c.is_ascii_alphabetic() {
+			return self.pass_through(DecodedKey::Unicode((c as u8 & 0x1F) as char));
+		}
+
+		match self.composer.feed(c, dead) {
+			Composed::Nothing => None,
+			Composed::One(c) => Some(self.event(DecodedKey::Unicode(c))),
+			Composed::Two(accent, c) => {
+				self.queued = Some(self.event(DecodedKey::Unicode(c)));
+				Some(self.event(DecodedKey::Unicode(accent)))
+			},
+		}
+	}
+
+	/// anything else, a dead key still waiting goes out on its own first
+	fn pass_through(
+		&mut self,
+		key: DecodedKey,
+	) -> Option<KeyEvent> {
+		match self.composer.flush() {
+			Some(accent) => {
+				self.queued = Some(self.event(key));
+				Some(self.event(DecodedKey::Unicode(accent)))
 			},
-			None => Poll::Pending, // returned with a registered waker
+			None => Some(self.event(key)),
+		}
+	}
+
+	fn track_modifier(
+		&mut self,
+		code: KeyCode,
+		state: KeyState,
+	) {
+		let down = state == KeyState::Down;
+
+		match code {
+			KeyCode::LShift => self.lshift = down,
+			KeyCode::RShift => self.rshift = down,
+			KeyCode::LControl => self.lctrl = down,
+			KeyCode::RControl => self.rctrl = down,
+			KeyCode::LAlt => self.lalt = down,
+			KeyCode::RAltGr => self.ralt = down,
+			// caps lock toggles on press, the release does nothing
+			KeyCode::CapsLock if down => self.modifiers.caps_lock = !self.modifiers.caps_lock,
+			_ => return,
 		}
+
+		self.modifiers.shift = self.lshift || self.rshift;
+		self.modifiers.ctrl = self.lctrl || self.rctrl;
+		self.modifiers.alt = self.lalt || self.ralt;
+		self.modifiers.altgr = self.ralt;
 	}
 }
 
-use crate::print;
-use futures_util::stream::StreamExt;
-use pc_keyboard::{DecodedKey, HandleControl, Keyboard, ScancodeSet1, layouts};
+/// Stream of decoded [`KeyEvent`]s on top of the scancode queue
+pub struct KeyEventStream {
+	scancodes: ScancodeStream,
+	decoder: KeyDecoder,
+}
 
-pub async fn print_keypresses() {
-	let mut scancodes = ScancodeStream::new();
-	let mut keyboard = Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::Ignore);
-
-	while let Some(scancode) = scancodes.next().await {
-		if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-			if let Some(key) = keyboard.process_keyevent(key_event) {
-				match key {
-					DecodedKey::RawKey(key) => {
-						// ignore raw keys -- if you want .. you don't wanna print them .. looks
-						// ugly
-					},
-					DecodedKey::Unicode(character) => print!("{}", character),
-				}
+impl KeyEventStream {
+	/// Initializes the scancode queue, so it can only be created once as well
+	pub fn new() -> Self {
+		KeyEventStream { scancodes: ScancodeStream::new(), decoder: KeyDecoder::new() }
+	}
+}
+
+impl Stream for KeyEventStream {
+	type Item = KeyEvent;
+
+	fn poll_next(
+		self: Pin<&mut Self>,
+		cx: &mut Context,
+	) -> Poll<Option<KeyEvent>> {
+		let this = self.get_mut();
+
+		if let Some(event) = this.decoder.take_queued() {
+			return Poll::Ready(Some(event));
+		}
+
+		// keep pulling scancodes until one completes a key, or the queue runs dry
+		loop {
+			match Pin::new(&mut this.scancodes).poll_next(cx) {
+				Poll::Ready(Some(scancode)) => {
+					if let Some(event) = this.decoder.feed(scancode) {
+						return Poll::Ready(Some(event));
+					}
+				},
+				Poll::Ready(None) => return Poll::Ready(None),
+				Poll::Pending => return Poll::Pending,
 			}
 		}
 	}
 }
+
+pub async fn print_keypresses() {
+	let mut key_events = KeyEventStream::new();
+
+	while let Some(event) = key_events.next().await {
+		if event.is_ctrl_c() {
+			print!("^C\n");
+			continue;
+		}
+
+		match event.key {
+			DecodedKey::RawKey(key) => {
+				// ignore raw keys -- if you want .. you don't wanna print them .. looks
+				// ugly
+			},
+			DecodedKey::Unicode(character) => print!("{}", character),
+		}
+	}
+}
+
+#[test_case]
+fn test_ctrl_key_sets_modifier() {
+	let mut decoder = KeyDecoder::new();
+
+	// left ctrl make code, no event for the modifier itself
+	assert_eq!(decoder.feed(0x1D), None);
+	assert!(decoder.modifiers().ctrl);
+
+	// 'c' make code while ctrl is held
+	let event = decoder.feed(0x2E).expect("ctrl+c should decode to an event");
+	assert!(event.modifiers.ctrl);
+	assert!(!event.modifiers.shift);
+	assert!(event.is_ctrl_c());
+
+	// 'c' break, then left ctrl break
+	assert_eq!(decoder.feed(0xAE), None);
+	assert_eq!(decoder.feed(0x9D), None);
+	assert!(!decoder.modifiers().ctrl);
+
+	let event = decoder.feed(0x2E).expect("plain c should decode to an event");
+	assert_eq!(event.key, DecodedKey::Unicode('c'));
+	assert!(!event.modifiers.ctrl);
+}
+
+#[test_case]
+fn test_alt_and_altgr_tracked_apart() {
+	let mut decoder = KeyDecoder::new();
+
+	// left alt down, then AltGr down and up again
+	for &scancode in [0x38, 0xE0, 0x38, 0xE0, 0xB8].iter() {
+		decoder.feed(scancode);
+	}
+	// left alt is still held
+	assert!(decoder.modifiers().alt);
+	assert!(!decoder.modifiers().altgr);
+
+	// AltGr down, then left alt up
+	for &scancode in [0xE0, 0x38, 0xB8].iter() {
+		decoder.feed(scancode);
+	}
+	assert!(decoder.modifiers().alt);
+	assert!(decoder.modifiers().altgr);
+
+	for &scancode in [0xE0, 0xB8].iter() {
+		decoder.feed(scancode);
+	}
+	assert!(!decoder.modifiers().alt);
+	assert!(!decoder.modifiers().altgr);
+}
+
+#[test_case]
+fn test_flush_drops_queued_scancodes() {
+	use futures_util::FutureExt;
+
+	let mut scancodes = ScancodeStream::new();
+
+	// a down and up, Shift down, b down
+	for &scancode in [0x1E, 0x9E, 0x2A, 0x30].iter() {
+		add_scancode(scancode);
+	}
+	assert_eq!(flush(), 4);
+	assert_eq!(scancodes.next().now_or_never(), None);
+	assert_eq!(flush(), 0);
+
+	// Shift and AltGr let go in between, and the first half of a right arrow
+	for &scancode in [0x1E, 0xAA, 0xE0, 0xB8, 0xE0, 0x4D, 0xE0].iter() {
+		add_scancode(scancode);
+	}
+	assert_eq!(flush(), 3);
+	for &kept in [0xAA, 0xE0, 0xB8, 0xE0].iter() {
+		assert_eq!(scancodes.next().now_or_never(), Some(Some(kept)));
+	}
+	assert_eq!(scancodes.next().now_or_never(), None);
+}
@@ -0,0 +1,503 @@
+// in src/task/keymap.rs
+//
+// keyboard layouts loaded from files, for whatever pc_keyboard doesn't ship
+
+use crate::fs::simple_fs::{FileError, FileSystem};
+use crate::sync::Mutex;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use sa::const_assert_eq;
+use zerocopy::{
+	FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout,
+	byteorder::{LE, U16, U32},
+};
+
+pub const KEYMAP_MAGIC: [u8; 4] = *b"KMAP";
+pub const KEYMAP_VERSION: u16 = 1;
+
+/// one entry per scancode set 1 make code, the E0 keys keep their builtin meaning
+pub const KEYMAP_KEYS: usize = 128;
+
+// entry flags
+/// the base character is a dead key
+pub const FLAG_DEAD_BASE: u8 = 1 << 0;
+pub const FLAG_DEAD_SHIFTED: u8 = 1 << 1;
+pub const FLAG_DEAD_ALTGR: u8 = 1 << 2;
+/// caps lock switches between base and shifted, for letters
+pub const FLAG_CAPS: u8 = 1 << 3;
+const KNOWN_FLAGS: u8 = FLAG_DEAD_BASE | FLAG_DEAD_SHIFTED | FLAG_DEAD_ALTGR | FLAG_CAPS;
+
+/// One key in a keymap file, 0 means the layer has no character
+#[derive(Debug, Copy, Clone, IntoBytes, FromBytes, Immutable, KnownLayout)]
+#[repr(C)]
+pub struct DiskKeymapEntry {
+	pub base: U32<LE>,
+	pub shifted: U32<LE>,
+	pub altgr: U32<LE>,
+	pub flags: u8,
+	pub _reserved: [u8; 3],
+}
+
+/// A whole keymap file, nothing comes after the entries
+#[derive(Debug, Copy, Clone, IntoBytes, FromBytes, Immutable, KnownLayout)]
+#[repr(C)]
+pub struct DiskKeymap {
+	pub magic: [u8; 4],
+	pub version: U16<LE>,
+	pub _reserved: U16<LE>,
+	pub entries: [DiskKeymapEntry; KEYMAP_KEYS],
+}
+
+pub const KEYMAP_FILE_SIZE: usize = size_of::<DiskKeymap>();
+const_assert_eq!(KEYMAP_FILE_SIZE, 8 + 16 * KEYMAP_KEYS);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeymapError {
+	/// opening or reading the file failed
+	File(FileError),
+	/// the file isn't `KEYMAP_FILE_SIZE` bytes long
+	WrongSize(usize),
+	BadMagic,
+	/// a version this kernel doesn't know
+	BadVersion(u16),
+	/// a character that isn't a Unicode scalar value
+	BadScalar {
+		scancode: u8,
+		value: u32,
+	},
+	/// unknown flag bits, or a dead key flag on a layer without a character
+	BadFlags {
+		scancode: u8,
+		flags: u8,
+	},
+	/// the text description doesn't parse, counted from 1
+	Syntax {
+		line: usize,
+	},
+}
+
+/// What one key gives on each layer
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct KeymapEntry {
+	pub base: Option<char>,
+	pub shifted: Option<char>,
+	pub altgr: Option<char>,
+	pub flags: u8,
+}
+
+impl KeymapEntry {
+	/// keys without any character are left to the builtin layout
+	pub fn is_empty(&self) -> bool {
+		self.base.is_none() && self.shifted.is_none() && self.altgr.is_none()
+	}
+
+	/// The character for the given modifiers and whether it's a dead key
+	pub fn lookup(
+		&self,
+		shift: bool,
+		caps_lock: bool,
+		altgr: bool,
+	) -> Option<(char, bool)> {
+		let (c, dead) = if altgr {
+			(self.altgr, FLAG_DEAD_ALTGR)
+		} else if shift != (caps_lock && self.flags & FLAG_CAPS != 0) {
+			(self.shifted, FLAG_DEAD_SHIFTED)
+		} else {
+			(self.base, FLAG_DEAD_BASE)
+		};
+		c.map(|c| (c, self.flags & dead != 0))
+	}
+}
+
+/// A keyboard layout, see `set_keymap`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keymap {
+	entries: [KeymapEntry; KEYMAP_KEYS],
+}
+
+/// what the keyboard decodes with, None for pc_keyboard's US layout
+static ACTIVE: Mutex<Option<Keymap>> = Mutex::new(None);
+
+/// Makes `keymap` the layout every `KeyDecoder` uses, None goes back to the builtin US one
+pub fn set_keymap(keymap: Option<Keymap>) {
+	*ACTIVE.lock() = keymap;
+}
+
+/// true while a loaded keymap is in use
+pub fn keymap_active() -> bool {
+	ACTIVE.lock().is_some()
+}
+
+/// the active keymap's entry for `make`, None without one or if it leaves the key alone
+pub(crate) fn active_entry(make: u8) -> Option<KeymapEntry> {
+	let active = ACTIVE.lock();
+	let entry = active.as_ref()?.entries[make as usize % KEYMAP_KEYS];
+	if entry.is_empty() { None } else { Some(entry) }
+}
+
+/// Reads and checks the keymap file at `path`, doesn't activate it
+///
+/// There are no directories yet, so the path without its leading `/` is the name of a file in
+/// the root directory.
+pub fn load_keymap(
+	fs: &dyn FileSystem,
+	path: &str,
+) -> Result<Keymap, KeymapError> {
+	let name = path.strip_prefix('/').unwrap_or(path);
+	let handle = fs.open_file(name).map_err(KeymapError::File)?;
+
+	// one byte more, so a file that's too long shows
+	let mut bytes = alloc::vec![0u8; KEYMAP_FILE_SIZE + 1];
+	let len = fs.read_file(handle, &mut bytes).map_err(KeymapError::File)?;
+	Keymap::from_bytes(&bytes[..len])
+}
+
+/// `kbd.layout` in kernel.cfg, `us` for the builtin layout or the path of a keymap file
+pub fn check_config(
+	fs: &dyn FileSystem,
+	key: &str,
+	value: &str,
+) -> Result<(), &'static str> {
+	if key != "kbd.layout" {
+		return Err("not a keyboard setting, there's only kbd.layout");
+	}
+	if value == "us" {
+		return Ok(());
+	}
+	load_keymap(fs, value).map(|_| ()).map_err(|_| "no keymap file there")
+}
+
+pub fn apply_config(
+	fs: &dyn FileSystem,
+	_key: &str,
+	value: &str,
+) {
+	if value == "us" {
+		set_keymap(None);
+	} else if let Ok(keymap) = load_keymap(fs, value) {
+		set_keymap(Some(keymap));
+	}
+}
+
+fn scalar(
+	scancode: u8,
+	value: u32,
+) -> Result<Option<char>, KeymapError> {
+	match value {
+		0 => Ok(None),
+		_ => char::from_u32(value).map(Some).ok_or(KeymapError::BadScalar { scancode, value }),
+	}
+}
+
+impl Keymap {
+	/// a keymap that leaves every key to the builtin layout
+	pub fn empty() -> Self {
+		Keymap { entries: [KeymapEntry::default(); KEYMAP_KEYS] }
+	}
+
+	pub fn entry(
+		&self,
+		make: u8,
+	) -> KeymapEntry {
+		self.entries[make as usize % KEYMAP_KEYS]
+	}
+
+	/// Parses a keymap file
+	pub fn from_bytes(bytes: &[u8]) -> Result<Self, KeymapError> {
+		let disk =
+			DiskKeymap::read_from_bytes(bytes).map_err(|_| KeymapError::WrongSize(bytes.len()))?;
+		if disk.magic != KEYMAP_MAGIC {
+			return Err(KeymapError::BadMagic);
+		}
+		if disk.version.get() != KEYMAP_VERSION {
+			return Err(KeymapError::BadVersion(disk.version.get()));
+		}
+
+		let mut keymap = Keymap::empty();
+		for (make, disk_entry) in disk.entries.iter().enumerate() {
+			let scancode = make as u8;
+			let entry = &mut keymap.entries[make];
+			*entry = KeymapEntry {
+				base: scalar(scancode, disk_entry.base.get())?,
+				shifted: scalar(scancode, disk_entry.shifted.get())?,
+				altgr: scalar(scancode, disk_entry.altgr.get())?,
+				flags: disk_entry.flags,
+			};
+
+			let dead_without_char = (entry.flags & FLAG_DEAD_BASE != 0 && entry.base.is_none())
+				|| (entry.flags & FLAG_DEAD_SHIFTED != 0 && entry.shifted.is_none())
+				|| (entry.flags & FLAG_DEAD_ALTGR != 0 && entry.altgr.is_none());
+			if entry.flags & !KNOWN_FLAGS != 0 || dead_without_char {
+				return Err(KeymapError::BadFlags { scancode, flags: entry.flags });
+			}
+		}
+		Ok(keymap)
+	}
+
+	/// the keymap as a file `from_bytes` reads back
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut disk = DiskKeymap::new_zeroed();
+		disk.magic = KEYMAP_MAGIC;
+		disk.version.set(KEYMAP_VERSION);
+
+		for (disk_entry, entry) in disk.entries.iter_mut().zip(&self.entries) {
+			disk_entry.base.set(entry.base.map_or(0, u32::from));
+			disk_entry.shifted.set(entry.shifted.map_or(0, u32::from));
+			disk_entry.altgr.set(entry.altgr.map_or(0, u32::from));
+			disk_entry.flags = entry.flags;
+		}
+		disk.as_bytes().to_vec()
+	}
+
+	/// Compiles the plain text description of a keymap
+	///
+	/// One key per line, `#` starts a comment:
+	///
+	/// ```text
+	/// # make code, then base, shifted and altgr
+	/// 0x12  f  F
+	/// 0x28  !'  !"   # ! in front makes a dead key
+	/// 0x39  U+0020  U+0020
+	/// 0x1e  a  A  -  # - for nothing
+	/// ```
+	///
+	/// Layers left out have no character, a `#` has to be written as U+0023. Caps lock works on
+	/// keys whose shifted character is the uppercase of their base one.
+	pub fn from_text(text: &str) -> Result<Self, KeymapError> {
+		let mut keymap = Keymap::empty();
+
+		for (i, line) in text.lines().enumerate() {
+			let line_number = i + 1;
+			let syntax = KeymapError::Syntax { line: line_number };
+			let line = line.split('#').next().unwrap_or("");
+			let mut words = line.split_whitespace();
+
+			let make = match words.next() {
+				Some(word) => parse_make(word).ok_or(syntax)?,
+				None => continue,
+			};
+			let entry = &mut keymap.entries[make as usize];
+			if !entry.is_empty() {
+				return Err(syntax);
+			}
+
+			let layers = [
+				(&mut entry.base, FLAG_DEAD_BASE),
+				(&mut entry.shifted, FLAG_DEAD_SHIFTED),
+				(&mut entry.altgr, FLAG_DEAD_ALTGR),
+			];
+			let mut flags = 0;
+			for (slot, dead_flag) in layers {
+				let word = match words.next() {
+					Some(word) => word,
+					None => break,
+				};
+				let (c, dead) = parse_char(word, make)?.ok_or(syntax)?;
+				*slot = c;
+				if dead {
+					flags |= dead_flag;
+				}
+			}
+			if words.next().is_some() || entry.is_empty() {
+				return Err(syntax);
+			}
+
+			let upper = entry.base.map(|c| c.to_uppercase());
+			if let (Some(mut upper), Some(shifted)) = (upper, entry.shifted) {
+				if entry.base.map_or(false, char::is_lowercase)
+					&& upper.next() == Some(shifted)
+					&& upper.next().is_none()
+				{
+					flags |= FLAG_CAPS;
+				}
+			}
+			entry.flags = flags;
+		}
+		Ok(keymap)
+	}
+}
+
+/// a make code in hex with 0x or decimal
+fn parse_make(word: &str) -> Option<u8> {
+	let make = match word.strip_prefix("0x") {
+		Some(hex) => u8::from_str_radix(hex, 16).ok()?,
+		None => word.parse().ok()?,
+	};
+	if (make as usize) < KEYMAP_KEYS { Some(make) } else { None }
+}
+
+/// One layer of a text line, Ok(None) if it doesn't parse
+///
+/// Gives the character, None for `-`, and whether it's dead.
+fn parse_char(
+	word: &str,
+	make: u8,
+) -> Result<Option<(Option<char>, bool)>, KeymapError> {
+	if word == "-" {
+		return Ok(Some((None, false)));
+	}
+
+	let (word, dead) = match word.strip_prefix('!') {
+		Some(rest) if !rest.is_empty() => (rest, true),
+		_ => (word, false),
+	};
+
+	if let Some(hex) = word.strip_prefix("U+") {
+		let value = match u32::from_str_radix(hex, 16) {
+			Ok(value) => value,
+			Err(_) => return Ok(None),
+		};
+		return match scalar(make, value)? {
+			Some(c) => Ok(Some((Some(c), dead))),
+			None => Ok(None),
+		};
+	}
+
+	let mut chars = word.chars();
+	match (chars.next(), chars.next()) {
+		(Some(c), None) => Ok(Some((Some(c), dead))),
+		_ => Ok(None),
+	}
+}
+
+/// The precomposed character a dead key and the next one make, if there is one
+fn compose(
+	accent: char,
+	c: char,
+) -> Option<char> {
+	let table: &[(char, char)] = match accent {
+		'´' | '\'' => &[
+			('a', 'á'),
+			('e', 'é'),
+			('i', 'í'),
+			('o', 'ó'),
+			('u', 'ú'),
+			('y', 'ý'),
+			('c', 'ç'),
+			('A', 'Á'),
+			('E', 'É'),
+			('I', 'Í'),
+			('O', 'Ó'),
+			('U', 'Ú'),
+			('Y', 'Ý'),
+			('C', 'Ç'),
+		],
+		'`' => &[
+			('a', 'à'),
+			('e', 'è'),
+			('i', 'ì'),
+			('o', 'ò'),
+			('u', 'ù'),
+			('A', 'À'),
+			('E', 'È'),
+			('I', 'Ì'),
+			('O', 'Ò'),
+			('U', 'Ù'),
+		],
+		'^' => &[
+			('a', 'â'),
+			('e', 'ê'),
+			('i', 'î'),
+			('o', 'ô'),
+			('u', 'û'),
+			('A', 'Â'),
+			('E', 'Ê'),
+			('I', 'Î'),
+			('O', 'Ô'),
+			('U', 'Û'),
+		],
+		'¨' | '"' => &[
+			('a', 'ä'),
+			('e', 'ë'),
+			('i', 'ï'),
+			('o', 'ö'),
+			('u', 'ü'),
+			('y', 'ÿ'),
+			('A', 'Ä'),
+			('E', 'Ë'),
+			('I', 'Ï'),
+			('O', 'Ö'),
+			('U', 'Ü'),
+		],
+		'~' => &[('a', 'ã'), ('n', 'ñ'), ('o', 'õ'), ('A', 'Ã'), ('N', 'Ñ'), ('O', 'Õ')],
+		_ => return None,
+	};
+	table.iter().find(|&&(base, _)| base == c).map(|&(_, composed)| composed)
+}
+
+/// What feeding the composer gave
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Composed {
+	/// a dead key, waiting for the next character
+	Nothing,
+	One(char),
+	/// a dead key that didn't go with what came next, both come out
+	Two(char, char),
+}
+
+/// Holds a dead key back until the next character says what it turns into
+#[derive(Debug, Default)]
+pub struct Composer {
+	pending: Option<char>,
+}
+
+impl Composer {
+	pub fn new() -> Self {
+		Composer { pending: None }
+	}
+
+	/// Feeds a character, `dead` if it came from a dead key
+	///
+	/// A dead key followed by space or by itself gives the accent on its own.
+	pub fn feed(
+		&mut self,
+		c: char,
+		dead: bool,
+	) -> Composed {
+		let accent = match self.pending.take() {
+			Some(accent) => accent,
+			None if dead => {
+				self.pending = Some(c);
+				return Composed::Nothing;
+			},
+			None => return Composed::One(c),
+		};
+
+		if c == ' ' || c == accent {
+			return Composed::One(accent);
+		}
+		match compose(accent, c) {
+			Some(composed) => Composed::One(composed),
+			None if dead => {
+				self.pending = Some(c);
+				Composed::One(accent)
+			},
+			None => Composed::Two(accent, c),
+		}
+	}
+
+	/// gives up on the dead key, for when something other than a character comes next
+	pub fn flush(&mut self) -> Option<char> {
+		self.pending.take()
+	}
+}
+
+#[test_case]
+fn test_composer_combines_and_gives_up() {
+	let mut composer = Composer::new();
+
+	assert_eq!(composer.feed('´', true), Composed::Nothing);
+	assert_eq!(composer.feed('e', false), Composed::One('é'));
+
+	assert_eq!(composer.feed('¨', true), Composed::Nothing);
+	assert_eq!(composer.feed(' ', false), Composed::One('¨'));
+
+	assert_eq!(composer.feed('^', true), Composed::Nothing);
+	assert_eq!(composer.feed('x', false), Composed::Two('^', 'x'));
+
+	// a second dead key sends the first one out and waits itself
+	assert_eq!(composer.feed('`', true), Composed::Nothing);
+	assert_eq!(composer.feed('~', true), Composed::One('`'));
+	assert_eq!(composer.flush(), Some('~'));
+	assert_eq!(composer.flush(), None);
+}
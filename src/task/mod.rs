@@ -1,20 +1,174 @@
 // in src/task/mod.rs
 
+pub mod blocking;
+pub mod channel;
 pub mod executor;
 pub mod keyboard;
+pub mod keymap;
+pub mod priority_queue;
 pub mod simple_executor;
+pub mod sync;
+pub mod timer;
+pub mod trace;
 
 use alloc::boxed::Box;
 use core::{
+	fmt,
 	future::Future,
 	pin::Pin,
+	str::FromStr,
 	task::{Context, Poll},
 };
 
+/// number of buckets the executor sorts ready tasks into, see `Priority::bucket`
+pub const NUM_PRIORITIES: usize = 8;
+
+/// the priorities one bucket covers
+const BUCKET_WIDTH: u8 = (256 / NUM_PRIORITIES) as u8;
+
+/// the most buckets that aging can lift a waiting task above its base priority
+pub const MAX_AGING_BOOST: u8 = 4;
+
+/// How urgent a task is, higher gets polled first
+///
+/// Any `u8` is a valid priority, tasks should stick to the named levels though. The executor
+/// only tells apart which of its `NUM_PRIORITIES` buckets a priority falls in, the named levels
+/// each get their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Priority(u8);
+
+impl Priority {
+	/// only runs when nothing else wants to
+	pub const IDLE: Priority = Priority(0);
+	/// background work, the block cache flusher and the blocking worker
+	pub const LOW: Priority = Priority(64);
+	/// what `Task::new` gives
+	pub const NORMAL: Priority = Priority(128);
+	/// tasks someone is waiting on, like the shell reading the keyboard
+	pub const HIGH: Priority = Priority(192);
+	pub const CRITICAL: Priority = Priority(255);
+
+	pub const fn new(raw: u8) -> Priority {
+		Priority(raw)
+	}
+
+	/// Clamps `raw` into the range, for values that come in wider than a `u8`
+	pub fn clamped(raw: i64) -> Priority {
+		Priority(raw.clamp(0, u8::MAX as i64) as u8)
+	}
+
+	pub const fn get(self) -> u8 {
+		self.0
+	}
+
+	/// the executor bucket this priority is scheduled in, 0 up to `NUM_PRIORITIES - 1`
+	pub const fn bucket(self) -> usize {
+		self.0 as usize / BUCKET_WIDTH as usize
+	}
+
+	/// `buckets` buckets higher, stopping at `CRITICAL`
+	pub fn boosted(
+		self,
+		buckets: u8,
+	) -> Priority {
+		Priority(self.0.saturating_add(buckets.saturating_mul(BUCKET_WIDTH)))
+	}
+
+	/// the name of a named level, None for the values in between
+	pub fn name(self) -> Option<&'static str> {
+		match self {
+			Priority::IDLE => Some("idle"),
+			Priority::LOW => Some("low"),
+			Priority::NORMAL => Some("normal"),
+			Priority::HIGH => Some("high"),
+			Priority::CRITICAL => Some("critical"),
+			_ => None,
+		}
+	}
+}
+
+impl Default for Priority {
+	fn default() -> Self {
+		Priority::NORMAL
+	}
+}
+
+/// the number, padded like one
+impl fmt::Display for Priority {
+	fn fmt(
+		&self,
+		f: &mut fmt::Formatter,
+	) -> fmt::Result {
+		fmt::Display::fmt(&self.0, f)
+	}
+}
+
+/// a priority that is neither a level's name nor a number from 0 to 255
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParsePriorityError;
+
+/// `idle`, `low`, `normal`, `high` or `critical` in any case, or a number from 0 to 255
+impl FromStr for Priority {
+	type Err = ParsePriorityError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let named =
+			[Priority::IDLE, Priority::LOW, Priority::NORMAL, Priority::HIGH, Priority::CRITICAL];
+		if let Some(&level) = named
+			.iter()
+			.find(|level| level.name().map_or(false, |name| name.eq_ignore_ascii_case(s)))
+		{
+			return Ok(level);
+		}
+		s.parse::<u8>().map(Priority).map_err(|_| ParsePriorityError)
+	}
+}
+
 pub struct Task {
 	id: TaskId,
 	future: Pin<Box<dyn Future<Output = ()>>>,
 	// methods on the Future are dynamically dispatched
+	/// priority the task was spawned with, or the last one `Executor::set_priority` gave it
+	base_priority: Priority,
+	/// priority used for scheduling, base plus any temporary aging boost
+	dyn_priority: Priority,
+	/// tick at which the task entered the ready queue, None while it's not queued
+	ready_since: Option<u64>,
+	/// the task's x87/SSE registers between polls, None for tasks that don't use them
+	fpu: Option<Box<FpuState>>,
+}
+
+/// An fxsave image, what a task's x87/SSE registers look like while it isn't running
+#[repr(C, align(16))]
+pub struct FpuState {
+	data: [u8; 512],
+	/// set once `fpu::fxsave` wrote into it, a fresh state holds the power-on defaults
+	initialized: bool,
+}
+
+impl FpuState {
+	/// x87 control word with every exception masked
+	const DEFAULT_FCW: u16 = 0x037F;
+	/// MXCSR with every exception masked, round to nearest
+	const DEFAULT_MXCSR: u32 = 0x1F80;
+
+	/// the register state right after `fninit`, with zeroed XMM registers
+	pub fn new() -> Self {
+		let mut data = [0u8; 512];
+		data[0..2].copy_from_slice(&Self::DEFAULT_FCW.to_le_bytes());
+		data[24..28].copy_from_slice(&Self::DEFAULT_MXCSR.to_le_bytes());
+		FpuState { data, initialized: false }
+	}
+
+	pub fn is_initialized(&self) -> bool {
+		self.initialized
+	}
+}
+
+impl Default for FpuState {
+	fn default() -> Self {
+		FpuState::new()
+	}
 }
 
 impl Task {
@@ -25,12 +179,38 @@ impl Task {
 	/// The static lifetime is required because
 	/// the Future can live for an arbitrary amount of time.
 	pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+		Task::with_priority(Priority::NORMAL, future)
+	}
+
+	/// Creates a task with an explicit priority, higher values get polled first
+	pub fn with_priority(
+		priority: Priority,
+		future: impl Future<Output = ()> + 'static,
+	) -> Task {
 		Task {
 			id: TaskId::new(), // makes it possible for uniquely naming a task for specific wake-ups
 			future: Box::pin(future),
+			base_priority: priority,
+			dyn_priority: priority,
+			ready_since: None,
+			fpu: None,
 		}
 	}
 
+	/// Gives the task its own x87/SSE registers, the executor swaps them in for every poll
+	///
+	/// Only needed for tasks that use SSE through asm, the kernel is built soft-float. Costs an
+	/// fxsave and an fxrstor per poll.
+	pub fn with_fpu(mut self) -> Task {
+		self.fpu = Some(Box::new(FpuState::new()));
+		self
+	}
+
+	/// the id the task shows up under in the executor trace
+	pub fn id(&self) -> u64 {
+		self.id.0
+	}
+
 	fn poll(
 		&mut self,
 		context: &mut Context,
@@ -56,3 +236,146 @@ impl TaskId {
 		TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
 	}
 }
+
+/// no task is being polled, task ids count up from 0 and never get here
+const NO_TASK: u64 = u64::MAX;
+
+/// the task an executor is polling right now
+static CURRENT_TASK: AtomicU64 = AtomicU64::new(NO_TASK);
+
+/// the id of the task being polled right now, None between polls
+pub fn current_task() -> Option<u64> {
+	match CURRENT_TASK.load(Ordering::Relaxed) {
+		NO_TASK => None,
+		id => Some(id),
+	}
+}
+
+/// Marks a task as the one being polled until it's dropped, the executor wraps every poll in one
+///
+/// Whatever ran before is back afterwards, so a task that runs an executor of its own is the
+/// current one again once the inner poll is done.
+struct Running {
+	previous: u64,
+}
+
+impl Running {
+	fn enter(id: TaskId) -> Self {
+		Running { previous: CURRENT_TASK.swap(id.0, Ordering::Relaxed) }
+	}
+}
+
+impl Drop for Running {
+	fn drop(&mut self) {
+		CURRENT_TASK.store(self.previous, Ordering::Relaxed);
+	}
+}
+
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::AtomicUsize;
+use executor::Executor;
+use futures_util::task::AtomicWaker;
+
+/// what a group's tasks share with its `join_all` future
+struct GroupState {
+	/// tasks spawned and not finished yet
+	remaining: AtomicUsize,
+	/// woken by the last task to finish
+	waker: AtomicWaker,
+}
+
+/// A set of tasks spawned together, `join_all` waits until every one of them finished
+///
+/// Spawning needs the executor, waiting doesn't. `join_all` lets go of it, so the future can be
+/// awaited by another task on the same executor.
+pub struct TaskGroup<'e> {
+	executor: &'e mut Executor,
+	task_ids: Vec<TaskId>,
+	state: Arc<GroupState>,
+}
+
+impl<'e> TaskGroup<'e> {
+	pub fn new(executor: &'e mut Executor) -> Self {
+		TaskGroup {
+			executor,
+			task_ids: Vec::new(),
+			state: Arc::new(GroupState {
+				remaining: AtomicUsize::new(0),
+				waker: AtomicWaker::new(),
+			}),
+		}
+	}
+
+	/// spawns `future` as a task of the group, `priority` like `Task::with_priority`
+	pub fn spawn<F: Future<Output = ()> + 'static>(
+		&mut self,
+		priority: Priority,
+		future: F,
+	) {
+		let state = self.state.clone();
+		state.remaining.fetch_add(1, Ordering::AcqRel);
+
+		let task = Task::with_priority(priority, async move {
+			future.await;
+			if state.remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+				state.waker.wake();
+			}
+		});
+		self.task_ids.push(task.id);
+		self.executor.spawn(task);
+	}
+
+	/// the ids of the tasks spawned so far, as in the executor trace
+	pub fn task_ids(&self) -> Vec<u64> {
+		self.task_ids.iter().map(|id| id.0).collect()
+	}
+
+	/// Resolves once every task of the group ran to completion, right away for an empty group
+	///
+	/// Not an `async fn`: that future would hold on to the executor borrow, and then nothing
+	/// could run the tasks it waits for.
+	pub fn join_all(self) -> impl Future<Output = ()> + 'static {
+		let state = self.state;
+		core::future::poll_fn(move |cx| {
+			if state.remaining.load(Ordering::Acquire) == 0 {
+				return Poll::Ready(());
+			}
+			// the last task could finish right after the check, so register and look again
+			state.waker.register(cx.waker());
+			if state.remaining.load(Ordering::Acquire) == 0 {
+				Poll::Ready(())
+			} else {
+				Poll::Pending
+			}
+		})
+	}
+}
+
+/// Lets the other ready tasks of the same or a higher priority run before the caller continues
+///
+/// The first poll wakes the task right away and returns Pending, so it goes to the back of its
+/// ready queue.
+pub fn yield_now() -> YieldNow {
+	YieldNow { yielded: false }
+}
+
+/// The future `yield_now` returns
+pub struct YieldNow {
+	yielded: bool,
+}
+
+impl Future for YieldNow {
+	type Output = ();
+
+	fn poll(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context,
+	) -> Poll<()> {
+		if self.yielded {
+			return Poll::Ready(());
+		}
+		self.yielded = true;
+		cx.waker().wake_by_ref();
+		Poll::Pending
+	}
+}
@@ -0,0 +1,155 @@
+// in src/task/priority_queue.rs
+//
+// a bounded max-heap that never allocates, for queues interrupt handlers push to
+
+/// A binary max-heap of at most `N` items, stored inline
+///
+/// `alloc`'s `BinaryHeap` grows on push, which an interrupt handler mustn't do, and has no
+/// order among equal items. Here a full queue hands the item back instead, and equal items come
+/// out in the order they went in, so tasks of the same priority take turns.
+///
+/// The executor keeps its ready tasks in one FIFO per priority, with only `NUM_PRIORITIES`
+/// levels that's cheaper than a heap. This is for queues keyed by something finer.
+pub struct FixedPriorityQueue<T, const N: usize> {
+	/// the heap, `slots[..len]` are all Some
+	slots: [Option<Entry<T>>; N],
+	len: usize,
+	/// pushes so far, breaks ties between equal items
+	pushed: u64,
+}
+
+struct Entry<T> {
+	item: T,
+	seq: u64,
+}
+
+impl<T: Ord> Entry<T> {
+	/// true if `self` comes out before `other`
+	fn before(
+		&self,
+		other: &Entry<T>,
+	) -> bool {
+		match self.item.cmp(&other.item) {
+			core::cmp::Ordering::Equal => self.seq < other.seq,
+			order => order == core::cmp::Ordering::Greater,
+		}
+	}
+}
+
+impl<T: Ord, const N: usize> FixedPriorityQueue<T, N> {
+	pub fn new() -> Self {
+		FixedPriorityQueue { slots: core::array::from_fn(|_| None), len: 0, pushed: 0 }
+	}
+
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	pub fn is_full(&self) -> bool {
+		self.len == N
+	}
+
+	pub fn capacity(&self) -> usize {
+		N
+	}
+
+	/// Adds `item`, or gives it back if the queue is full
+	pub fn push(
+		&mut self,
+		item: T,
+	) -> Result<(), T> {
+		if self.is_full() {
+			return Err(item);
+		}
+
+		self.slots[self.len] = Some(Entry { item, seq: self.pushed });
+		self.pushed += 1;
+		self.len += 1;
+		self.sift_up(self.len - 1);
+		Ok(())
+	}
+
+	/// the largest item, the one that went in first among equal ones
+	pub fn peek_max(&self) -> Option<&T> {
+		self.slots[..self.len].first().map(|slot| &self.entry(slot).item)
+	}
+
+	/// Takes out the largest item, the one that went in first among equal ones
+	pub fn pop_max(&mut self) -> Option<T> {
+		if self.is_empty() {
+			return None;
+		}
+
+		self.len -= 1;
+		self.slots.swap(0, self.len);
+		let top = self.slots[self.len].take();
+		self.sift_down(0);
+		top.map(|entry| entry.item)
+	}
+
+	/// drops every item
+	pub fn clear(&mut self) {
+		for slot in self.slots[..self.len].iter_mut() {
+			*slot = None;
+		}
+		self.len = 0;
+	}
+
+	fn entry<'a>(
+		&self,
+		slot: &'a Option<Entry<T>>,
+	) -> &'a Entry<T> {
+		slot.as_ref().expect("hole in the heap")
+	}
+
+	fn before(
+		&self,
+		a: usize,
+		b: usize,
+	) -> bool {
+		self.entry(&self.slots[a]).before(self.entry(&self.slots[b]))
+	}
+
+	fn sift_up(
+		&mut self,
+		mut i: usize,
+	) {
+		while i > 0 {
+			let parent = (i - 1) / 2;
+			if !self.before(i, parent) {
+				break;
+			}
+			self.slots.swap(i, parent);
+			i = parent;
+		}
+	}
+
+	fn sift_down(
+		&mut self,
+		mut i: usize,
+	) {
+		loop {
+			let mut first = i;
+			for child in [2 * i + 1, 2 * i + 2] {
+				if child < self.len && self.before(child, first) {
+					first = child;
+				}
+			}
+			if first == i {
+				break;
+			}
+			self.slots.swap(i, first);
+			i = first;
+		}
+	}
+}
+
+impl<T: Ord, const N: usize> Default for FixedPriorityQueue<T, N> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
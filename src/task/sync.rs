@@ -0,0 +1,486 @@
+// in src/task/sync.rs
+//
+// primitives for tasks to wait on each other
+
+use crate::sync::Mutex;
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{
+	cell::{Cell, UnsafeCell},
+	future::Future,
+	mem,
+	ops::{Deref, DerefMut},
+	pin::Pin,
+	sync::atomic::{AtomicI64, AtomicUsize, Ordering},
+	task::{Context, Poll, Waker},
+};
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// A counting semaphore, `acquire` takes one of a fixed number of permits and parks the task
+/// while there are none left
+///
+/// Permits aren't tied to the task that took them, whoever is done calls `release`. Woken tasks
+/// take their permit the same way as everyone else, one that shows up between the release and
+/// the wakeup can take it first.
+pub struct Semaphore {
+	/// permits that are free right now, never below 0
+	count: AtomicI64,
+	/// tasks waiting for a permit, the oldest first
+	waiters: Mutex<VecDeque<Waker>>,
+}
+
+impl Semaphore {
+	pub const fn new(initial: u64) -> Self {
+		Semaphore { count: AtomicI64::new(initial as i64), waiters: Mutex::new(VecDeque::new()) }
+	}
+
+	/// permits that are free right now
+	pub fn available(&self) -> u64 {
+		self.count.load(Ordering::Acquire) as u64
+	}
+
+	/// takes a permit if there is one, without waiting
+	pub fn try_acquire(&self) -> bool {
+		let mut count = self.count.load(Ordering::Acquire);
+		while count > 0 {
+			match self.count.compare_exchange_weak(
+				count,
+				count - 1,
+				Ordering::AcqRel,
+				Ordering::Acquire,
+			) {
+				Ok(_) => return true,
+				Err(now) => count = now,
+			}
+		}
+		false
+	}
+
+	/// Takes a permit, parking the task until one is released
+	pub async fn acquire(&self) {
+		Acquire { semaphore: self, waker: None, done: false }.await
+	}
+
+	/// Gives a permit back and wakes the task that has waited longest
+	///
+	/// Doesn't wait or allocate, but it does take the waiters lock, so not from an interrupt
+	/// handler.
+	pub fn release(&self) {
+		self.count.fetch_add(1, Ordering::AcqRel);
+		self.wake_next();
+	}
+
+	fn wake_next(&self) {
+		// wake outside the lock, the waker might want to poll right away
+		let waker = self.waiters.lock().pop_front();
+		if let Some(waker) = waker {
+			waker.wake();
+		}
+	}
+}
+
+/// the future behind `Semaphore::acquire`
+struct Acquire<'a> {
+	semaphore: &'a Semaphore,
+	/// what this future left in the waiters queue the last time it parked
+	waker: Option<Waker>,
+	done: bool,
+}
+
+impl Acquire<'_> {
+	/// queues `waker`, in the old place if the future is still parked from an earlier poll
+	fn park(
+		&mut self,
+		waker: &Waker,
+	) {
+		let mut waiters = self.semaphore.waiters.lock();
+		let parked = self
+			.waker
+			.as_ref()
+			.and_then(|old| waiters.iter().position(|parked| parked.will_wake(old)));
+		match parked {
+			Some(i) => waiters[i] = waker.clone(),
+			None => waiters.push_back(waker.clone()),
+		}
+		self.waker = Some(waker.clone());
+	}
+
+	/// takes this future's waker out of the queue, false if a release already took it
+	fn unpark(&mut self) -> bool {
+		let waker = match self.waker.take() {
+			Some(waker) => waker,
+			None => return false,
+		};
+		let mut waiters = self.semaphore.waiters.lock();
+		match waiters.iter().position(|parked| parked.will_wake(&waker)) {
+			Some(i) => {
+				waiters.remove(i);
+				true
+			},
+			None => false,
+		}
+	}
+}
+
+impl Future for Acquire<'_> {
+	type Output = ();
+
+	fn poll(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context,
+	) -> Poll<()> {
+		// fast path
+		if self.semaphore.try_acquire() {
+			self.unpark();
+			self.done = true;
+			return Poll::Ready(());
+		}
+
+		// a release between the check and parking would wake nobody, so park first and look
+		// again after
+		self.park(cx.waker());
+
+		if self.semaphore.try_acquire() {
+			// the next release is supposed to wake someone who still waits
+			self.unpark();
+			self.done = true;
+			return Poll::Ready(());
+		}
+
+		Poll::Pending
+	}
+}
+
+impl Drop for Acquire<'_> {
+	fn drop(&mut self) {
+		if self.done {
+			return;
+		}
+		// a release woke this future but it's gone before taking the permit, so the wakeup goes
+		// to the next waiter
+		let woken = self.waker.is_some() && !self.unpark();
+		if woken && self.semaphore.count.load(Ordering::Acquire) > 0 {
+			self.semaphore.wake_next();
+		}
+	}
+}
+
+/// Tasks waiting for a condition of their own, whoever changes the state wakes them to look again
+///
+/// Nothing is remembered between a wake and a wait. `wait_until` queues the task before it
+/// checks, so a change that comes with a `wake_all` after the check isn't missed. The lock is
+/// only taken with interrupts off, interrupt handlers can wake too.
+pub struct WaitQueue {
+	/// the oldest first
+	waiters: Mutex<Vec<Waker>>,
+}
+
+impl WaitQueue {
+	pub const fn new() -> Self {
+		WaitQueue { waiters: Mutex::new(Vec::new()) }
+	}
+
+	/// how many tasks are queued right now
+	pub fn waiting(&self) -> usize {
+		without_interrupts(|| self.waiters.lock().len())
+	}
+
+	/// Wakes the task that has waited longest
+	///
+	/// If its condition still doesn't hold it queues again at the back, the wakeup isn't passed
+	/// on. For state any one waiter can make use of, `wake_all` otherwise.
+	pub fn wake_one(&self) {
+		let waker = without_interrupts(|| {
+			let mut waiters = self.waiters.lock();
+			if waiters.is_empty() { None } else { Some(waiters.remove(0)) }
+		});
+		if let Some(waker) = waker {
+			waker.wake();
+		}
+	}
+
+	/// wakes every queued task, each checks its condition again
+	pub fn wake_all(&self) {
+		let waiters = without_interrupts(|| mem::take(&mut *self.waiters.lock()));
+		for waker in waiters {
+			waker.wake();
+		}
+	}
+
+	/// Parks the task until `condition` returns true, it's checked again on every wakeup
+	pub async fn wait_until<F: Fn() -> bool>(
+		&self,
+		condition: F,
+	) {
+		WaitUntil { queue: self, condition, waker: None }.await
+	}
+}
+
+impl Default for WaitQueue {
+	fn default() -> Self {
+		WaitQueue::new()
+	}
+}
+
+/// the future behind `WaitQueue::wait_until`
+struct WaitUntil<'a, F> {
+	queue: &'a WaitQueue,
+	condition: F,
+	/// what this future left in the queue the last time it parked
+	waker: Option<Waker>,
+}
+
+// the condition is only ever called through a shared reference, never pinned
+impl<F> Unpin for WaitUntil<'_, F> {}
+
+impl<F> WaitUntil<'_, F> {
+	/// queues `waker`, in the old place if an earlier poll left one there
+	fn park(
+		&mut self,
+		waker: &Waker,
+	) {
+		let old = self.waker.replace(waker.clone());
+		without_interrupts(|| {
+			let mut waiters = self.queue.waiters.lock();
+			let parked =
+				old.and_then(|old| waiters.iter().position(|parked| parked.will_wake(&old)));
+			match parked {
+				Some(i) => waiters[i] = waker.clone(),
+				None => waiters.push(waker.clone()),
+			}
+		});
+	}
+
+	/// takes this future's waker out of the queue, if a wake didn't already
+	fn unpark(&mut self) {
+		if let Some(waker) = self.waker.take() {
+			without_interrupts(|| {
+				let mut waiters = self.queue.waiters.lock();
+				if let Some(i) = waiters.iter().position(|parked| parked.will_wake(&waker)) {
+					waiters.remove(i);
+				}
+			});
+		}
+	}
+}
+
+impl<F: Fn() -> bool> Future for WaitUntil<'_, F> {
+	type Output = ();
+
+	fn poll(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context,
+	) -> Poll<()> {
+		if (self.condition)() {
+			self.unpark();
+			return Poll::Ready(());
+		}
+
+		// a change and wake_all between the check and parking would wake nobody, so park first
+		// and look again after
+		self.park(cx.waker());
+
+		if (self.condition)() {
+			self.unpark();
+			return Poll::Ready(());
+		}
+
+		Poll::Pending
+	}
+}
+
+impl<F> Drop for WaitUntil<'_, F> {
+	fn drop(&mut self) {
+		// so a wake_one doesn't go to a waiter that's gone
+		self.unpark();
+	}
+}
+
+/// `AsyncRwLock::state` while a writer holds the lock
+const WRITER: usize = usize::MAX;
+
+/// A reader-writer lock for tasks, any number of readers or one writer at a time
+///
+/// Tasks that can't have it park on a `WaitQueue` instead of spinning, every unlock wakes them
+/// all to try again. Once a writer waits, new readers queue up behind it, so a steady stream of
+/// readers can't keep it out forever.
+///
+/// For an `SFS` shared between tasks: `read_file`, `list_file` and the other `&self` methods
+/// through `read`, `create_file`, `delete_file` and everything else through `write`.
+pub struct AsyncRwLock<T> {
+	/// how many readers hold the lock, or `WRITER`
+	state: AtomicUsize,
+	/// writers parked on `queue`, readers hold back while there are any
+	writers_waiting: AtomicUsize,
+	queue: WaitQueue,
+	value: UnsafeCell<T>,
+}
+
+// Sync: `state` hands out either shared references to any number of readers or one mutable
+// reference, the same as a `RwLock`. So `T: Sync` for the readers and `T: Send` for a writer that
+// could be on another CPU.
+unsafe impl<T: Send + Sync> Sync for AsyncRwLock<T> {}
+
+impl<T> AsyncRwLock<T> {
+	pub const fn new(value: T) -> Self {
+		AsyncRwLock {
+			state: AtomicUsize::new(0),
+			writers_waiting: AtomicUsize::new(0),
+			queue: WaitQueue::new(),
+			value: UnsafeCell::new(value),
+		}
+	}
+
+	/// Takes a read lock if nobody writes and no writer is waiting, without waiting itself
+	pub fn try_read(&self) -> Option<ReadGuard<'_, T>> {
+		let mut state = self.state.load(Ordering::Acquire);
+		loop {
+			// WRITER - 1 readers would make the count look like a writer
+			if state >= WRITER - 1 || self.writers_waiting.load(Ordering::Acquire) > 0 {
+				return None;
+			}
+			match self.state.compare_exchange_weak(
+				state,
+				state + 1,
+				Ordering::AcqRel,
+				Ordering::Acquire,
+			) {
+				Ok(_) => return Some(ReadGuard { lock: self }),
+				Err(now) => state = now,
+			}
+		}
+	}
+
+	/// Takes the write lock if nobody holds the lock at all, without waiting
+	pub fn try_write(&self) -> Option<WriteGuard<'_, T>> {
+		self.state
+			.compare_exchange(0, WRITER, Ordering::AcqRel, Ordering::Acquire)
+			.ok()
+			.map(|_| WriteGuard { lock: self })
+	}
+
+	/// Shares the value with the other readers, parking the task while a writer has it or waits
+	/// for it
+	pub async fn read(&self) -> ReadGuard<'_, T> {
+		if let Some(guard) = self.try_read() {
+			return guard;
+		}
+
+		// the condition takes the lock when it says yes, nothing can come in between
+		let guard = Cell::new(None);
+		self.queue
+			.wait_until(|| {
+				let taken = self.try_read();
+				let locked = taken.is_some();
+				guard.set(taken);
+				locked
+			})
+			.await;
+		guard.into_inner().expect("woken without the lock")
+	}
+
+	/// Takes the value for itself, parking the task until every reader and writer let go
+	pub async fn write(&self) -> WriteGuard<'_, T> {
+		if let Some(guard) = self.try_write() {
+			return guard;
+		}
+
+		let _waiting = WaitingWriter::new(self);
+		let guard = Cell::new(None);
+		self.queue
+			.wait_until(|| {
+				let taken = self.try_write();
+				let locked = taken.is_some();
+				guard.set(taken);
+				locked
+			})
+			.await;
+		guard.into_inner().expect("woken without the lock")
+	}
+
+	/// how many readers hold the lock right now, 0 while a writer has it
+	pub fn readers(&self) -> usize {
+		match self.state.load(Ordering::Acquire) {
+			WRITER => 0,
+			readers => readers,
+		}
+	}
+
+	/// the value, the `&mut` shows nobody else can hold the lock
+	pub fn get_mut(&mut self) -> &mut T {
+		self.value.get_mut()
+	}
+
+	pub fn into_inner(self) -> T {
+		self.value.into_inner()
+	}
+}
+
+/// Counts a writer in `writers_waiting` for as long as it's parked
+struct WaitingWriter<'a, T> {
+	lock: &'a AsyncRwLock<T>,
+}
+
+impl<'a, T> WaitingWriter<'a, T> {
+	fn new(lock: &'a AsyncRwLock<T>) -> Self {
+		lock.writers_waiting.fetch_add(1, Ordering::AcqRel);
+		WaitingWriter { lock }
+	}
+}
+
+impl<T> Drop for WaitingWriter<'_, T> {
+	fn drop(&mut self) {
+		// readers held back for this writer look again, it might be gone without the lock
+		self.lock.writers_waiting.fetch_sub(1, Ordering::AcqRel);
+		self.lock.queue.wake_all();
+	}
+}
+
+/// Shared access to the value of an `AsyncRwLock`, unlocks on drop
+pub struct ReadGuard<'a, T> {
+	lock: &'a AsyncRwLock<T>,
+}
+
+impl<T> Deref for ReadGuard<'_, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		// the read lock keeps writers out
+		unsafe { &*self.lock.value.get() }
+	}
+}
+
+impl<T> Drop for ReadGuard<'_, T> {
+	fn drop(&mut self) {
+		// only the last reader out lets anyone in
+		if self.lock.state.fetch_sub(1, Ordering::AcqRel) == 1 {
+			self.lock.queue.wake_all();
+		}
+	}
+}
+
+/// Exclusive access to the value of an `AsyncRwLock`, unlocks on drop
+pub struct WriteGuard<'a, T> {
+	lock: &'a AsyncRwLock<T>,
+}
+
+impl<T> Deref for WriteGuard<'_, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		unsafe { &*self.lock.value.get() }
+	}
+}
+
+impl<T> DerefMut for WriteGuard<'_, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		// the write lock keeps everyone else out
+		unsafe { &mut *self.lock.value.get() }
+	}
+}
+
+impl<T> Drop for WriteGuard<'_, T> {
+	fn drop(&mut self) {
+		self.lock.state.store(0, Ordering::Release);
+		self.lock.queue.wake_all();
+	}
+}
@@ -0,0 +1,94 @@
+// in src/task/timer.rs
+
+use crate::{hpet, interrupts};
+use conquer_once::spin::OnceCell;
+use core::{
+	future::Future,
+	pin::Pin,
+	task::{Context, Poll, Waker},
+};
+use crossbeam_queue::ArrayQueue;
+
+/// the PIT runs at its power-on default of ~18.2 Hz, close enough for timeouts
+pub const TICKS_PER_SECOND: u64 = 18;
+
+/// how many sleeping tasks can be parked on the timer at once
+const SLEEPER_CAPACITY: usize = 32;
+
+/// Wakers of sleeping tasks, emptied by the timer interrupt on every tick
+static SLEEPERS: OnceCell<ArrayQueue<Waker>> = OnceCell::uninit();
+
+/// Makes the sleeper queue, called once by `allocator::init_heap`
+pub(crate) fn init_sleepers() {
+	SLEEPERS
+		.try_init_once(|| ArrayQueue::new(SLEEPER_CAPACITY))
+		.expect("timer::init_sleepers should only be called once");
+}
+
+/// Called by the timer interrupt handler
+///
+/// Must not block or allocate! Every sleeper is woken and the ones that aren't due yet park
+/// themselves again.
+pub(crate) fn wake_sleepers() {
+	if let Ok(sleepers) = SLEEPERS.try_get() {
+		// only drain what is there now, woken tasks re-register from the executor, not from here
+		for _ in 0..sleepers.len() {
+			match sleepers.pop() {
+				Some(waker) => waker.wake(),
+				None => break,
+			}
+		}
+	}
+}
+
+/// when a `Sleep` is done
+#[derive(Debug, Clone, Copy)]
+enum Deadline {
+	/// `interrupts::ticks` reaching this
+	Ticks(u64),
+	/// the HPET's counter reaching this
+	Counter(u64),
+}
+
+/// Future that resolves once its deadline passed
+pub struct Sleep {
+	deadline: Deadline,
+}
+
+/// Sleeps for `ticks` timer ticks without blocking the executor
+///
+/// With the HPET up that's `ticks` whole ticks from now, measured on its counter. Without it the
+/// sleep ends on a tick, so the first one can be anything from 0 to a full tick short.
+pub fn sleep_ticks(ticks: u64) -> Sleep {
+	let deadline = match hpet::counts_per_tick() {
+		Some(per_tick) => Deadline::Counter(hpet::hpet_read_counter() + ticks * per_tick),
+		None => Deadline::Ticks(interrupts::ticks() + ticks),
+	};
+	Sleep { deadline }
+}
+
+impl Future for Sleep {
+	type Output = ();
+
+	fn poll(
+		self: Pin<&mut Self>,
+		cx: &mut Context,
+	) -> Poll<()> {
+		let done = match self.deadline {
+			Deadline::Ticks(deadline) => interrupts::ticks() >= deadline,
+			Deadline::Counter(deadline) => hpet::hpet_read_counter() >= deadline,
+		};
+		if done {
+			return Poll::Ready(());
+		}
+
+		let sleepers = SLEEPERS.try_get().expect("timer::init_sleepers wasn't called");
+
+		// no room on the timer, fall back to yielding until the deadline passes
+		if let Err(waker) = sleepers.push(cx.waker().clone()) {
+			waker.wake();
+		}
+
+		Poll::Pending
+	}
+}
@@ -0,0 +1,195 @@
+// in src/task/trace.rs
+//
+// opt-in per-poll event log of the executor, dumped as CSV for a host script to turn into a
+// timeline
+
+use core::{
+	fmt,
+	sync::atomic::{AtomicBool, Ordering},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// events kept, the oldest get overwritten once it's full
+pub const TRACE_CAPACITY: usize = 8192;
+
+/// what happened to a task
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceKind {
+	/// polled and still pending
+	Polled,
+	/// moved from the wake queue into a ready bucket
+	Ready,
+	/// polled for the last time
+	Completed,
+	/// its waker was called with interrupts on, i.e. by another task
+	Woken,
+	/// its waker was called with interrupts off, which in practice means a handler
+	WokenFromInterrupt,
+}
+
+impl TraceKind {
+	pub fn as_str(self) -> &'static str {
+		match self {
+			TraceKind::Polled => "polled",
+			TraceKind::Ready => "ready",
+			TraceKind::Completed => "completed",
+			TraceKind::Woken => "woken",
+			TraceKind::WokenFromInterrupt => "woken-irq",
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEvent {
+	/// timer tick it happened at
+	pub tick: u64,
+	pub task: u64,
+	pub kind: TraceKind,
+	/// how long the poll took in TSC cycles, 0 for anything but polls
+	///
+	/// There's no calibrated clock, so cycles are as fine grained as it gets.
+	pub cycles: u64,
+}
+
+const EMPTY: TraceEvent = TraceEvent { tick: 0, task: 0, kind: TraceKind::Polled, cycles: 0 };
+
+struct Ring {
+	events: [TraceEvent; TRACE_CAPACITY],
+	/// slot the next event goes into
+	next: usize,
+	len: usize,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// only ever locked with interrupts off, so a handler recording a wake can't find it taken
+static RING: Mutex<Ring> = Mutex::new(Ring { events: [EMPTY; TRACE_CAPACITY], next: 0, len: 0 });
+
+/// Starts or stops recording, what's already in the ring stays
+pub fn set_enabled(enabled: bool) {
+	ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// the one branch every record site pays while tracing is off
+#[inline]
+pub fn enabled() -> bool {
+	ENABLED.load(Ordering::Relaxed)
+}
+
+/// drops every recorded event
+pub fn clear() {
+	interrupts::without_interrupts(|| {
+		let mut ring = RING.lock();
+		ring.next = 0;
+		ring.len = 0;
+	});
+}
+
+/// the TSC, for timing polls
+#[inline]
+pub fn cycles() -> u64 {
+	unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Records an event if tracing is on, safe from interrupt handlers and never allocates
+#[inline]
+pub fn record(
+	task: u64,
+	kind: TraceKind,
+	cycles: u64,
+) {
+	if !enabled() {
+		return;
+	}
+	record_slow(TraceEvent { tick: crate::interrupts::ticks(), task, kind, cycles });
+}
+
+#[cold]
+fn record_slow(event: TraceEvent) {
+	interrupts::without_interrupts(|| {
+		let mut ring = RING.lock();
+		let slot = ring.next;
+		ring.events[slot] = event;
+		ring.next = (slot + 1) % TRACE_CAPACITY;
+		ring.len = (ring.len + 1).min(TRACE_CAPACITY);
+	});
+}
+
+/// Records a wake-up, telling handlers apart from tasks by whether interrupts are on
+#[inline]
+pub fn record_wake(task: u64) {
+	if !enabled() {
+		return;
+	}
+	let kind =
+		if interrupts::are_enabled() { TraceKind::Woken } else { TraceKind::WokenFromInterrupt };
+	record(task, kind, 0);
+}
+
+/// Writes the ring oldest first as CSV, a `tick,task,event,cycles` header and a line per event
+///
+/// Interrupts are off for the whole dump so the ring holds still, keep `out` quick.
+pub fn dump<W: fmt::Write>(out: &mut W) -> fmt::Result {
+	interrupts::without_interrupts(|| {
+		let ring = RING.lock();
+		let oldest = (ring.next + TRACE_CAPACITY - ring.len) % TRACE_CAPACITY;
+
+		writeln!(out, "tick,task,event,cycles")?;
+		for i in 0..ring.len {
+			let event = &ring.events[(oldest + i) % TRACE_CAPACITY];
+			writeln!(
+				out,
+				"{},{},{},{}",
+				event.tick,
+				event.task,
+				event.kind.as_str(),
+				event.cycles
+			)?;
+		}
+		Ok(())
+	})
+}
+
+/// dumps the ring straight to COM1
+pub fn dump_serial() {
+	// SERIAL1 is taken with interrupts off, same as serial_print! does
+	interrupts::without_interrupts(|| {
+		let mut serial = crate::serial::SERIAL1.lock();
+		dump(&mut *serial).expect("dumping the trace to serial failed");
+	});
+}
+
+#[test_case]
+fn test_ring_overwrites_oldest() {
+	let was_enabled = enabled();
+	clear();
+	set_enabled(true);
+
+	for i in 0..TRACE_CAPACITY as u64 + 3 {
+		record(i, TraceKind::Polled, i);
+	}
+	set_enabled(was_enabled);
+
+	interrupts::without_interrupts(|| {
+		let ring = RING.lock();
+		assert_eq!(ring.len, TRACE_CAPACITY);
+		// the first three fell out, the oldest left is task 3
+		assert_eq!(ring.events[ring.next].task, 3);
+		assert_eq!(ring.events[ring.next - 1].task, TRACE_CAPACITY as u64 + 2);
+	});
+	clear();
+}
+
+#[test_case]
+fn test_disabled_records_nothing() {
+	let was_enabled = enabled();
+	clear();
+	set_enabled(false);
+
+	record(1, TraceKind::Completed, 0);
+	record_wake(1);
+
+	assert_eq!(interrupts::without_interrupts(|| RING.lock().len), 0);
+	set_enabled(was_enabled);
+}
@@ -0,0 +1,244 @@
+//! in src/test_harness.rs
+//!
+//! the setup every integration test needs, so their entry points stop copying kernel_main
+//!
+//! Test binaries end through `exit::exit_qemu_or_halt`, which needs QEMU's isa-debug-exit device
+//! to hand the result to the host. There's no way to ask whether it's attached, so it writes the
+//! code and takes still running a moment later as the answer. It warns on serial then and halts
+//! after the `[QEMU-EXIT]` marker, a runner without the device has to go by that line.
+
+use crate::{
+	QemuExitCode, allocator, exit,
+	fs::{block_dev::MemBlockDevice, simple_fs::SFS},
+	interrupts,
+	memory::{self, BootInfoFrameAllocator},
+	task::{Task, executor::Executor},
+	virtio::{FRAME_ALLOCATOR, PAGE_MAPPER},
+};
+use alloc::rc::Rc;
+use bootloader::BootInfo;
+use core::{cell::RefCell, future::Future};
+use x86_64::VirtAddr;
+
+/// blocks in the MemBlockDevice behind `TestEnv::fs`, 256 KiB worth
+pub const TEST_FS_BLOCKS: usize = 512;
+
+/// how long `run_async` waits for its future by default, about 10s
+pub const DEFAULT_TICK_BUDGET: u64 = 10 * crate::task::timer::TICKS_PER_SECOND;
+
+/// polls between checks whether the future is done
+const POLLS_PER_ROUND: usize = 16;
+
+/// set by `init_full`, test functions can't be handed it
+static BOOT_INFO: spin::Once<&'static BootInfo> = spin::Once::new();
+
+/// What a test gets to work with, an executor and a filesystem on demand
+pub struct TestEnv {
+	executor: Executor,
+	/// formatted the first time a test asks for it
+	fs: Option<SFS<MemBlockDevice>>,
+}
+
+/// Brings the test kernel up like kernel_main does
+///
+/// GDT, IDT and PICs, paging, the heap, and the virtio FRAME_ALLOCATOR and PAGE_MAPPER, so
+/// anything touching OsHal or the managed stacks works too. Only once per test binary, later
+/// environments come from `TestEnv::new`.
+pub fn init_full(boot_info: &'static BootInfo) -> TestEnv {
+	if BOOT_INFO.r#try().is_some() {
+		panic!("test_harness::init_full called twice");
+	}
+	BOOT_INFO.call_once(|| boot_info);
+
+	crate::early_serial::init();
+	crate::init();
+
+	let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+	crate::virtio::set_physical_memory_offset(boot_info.physical_memory_offset);
+
+	let mapper = unsafe { memory::init(phys_mem_offset) };
+	let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+	*FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+	*PAGE_MAPPER.lock() = Some(mapper);
+
+	{
+		let mut mapper = PAGE_MAPPER.lock();
+		let mut frame_allocator = FRAME_ALLOCATOR.lock();
+
+		allocator::init_heap(mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap())
+			.expect("heap initialization failed");
+	}
+
+	TestEnv { executor: Executor::new(), fs: None }
+}
+
+impl Default for TestEnv {
+	fn default() -> Self {
+		TestEnv::new()
+	}
+}
+
+impl TestEnv {
+	/// A fresh environment with its own executor and no filesystem yet
+	///
+	/// For test functions, they can't be handed the one `init_full` returned.
+	pub fn new() -> Self {
+		assert!(BOOT_INFO.r#try().is_some(), "test_harness::init_full has to run first");
+		TestEnv { executor: Executor::new(), fs: None }
+	}
+
+	pub fn boot_info(&self) -> &'static BootInfo {
+		BOOT_INFO.r#try().expect("test_harness::init_full has to run first")
+	}
+
+	pub fn executor(&mut self) -> &mut Executor {
+		&mut self.executor
+	}
+
+	/// an SFS on a MemBlockDevice of `TEST_FS_BLOCKS` blocks, formatted on first use
+	pub fn fs(&mut self) -> &mut SFS<MemBlockDevice> {
+		self.fs.get_or_insert_with(|| {
+			SFS::format(MemBlockDevice::new(TEST_FS_BLOCKS)).expect("formatting the test fs failed")
+		})
+	}
+
+	/// Runs `future` on the executor and returns its output, see `run_async_for`
+	pub fn run_async<F>(
+		&mut self,
+		future: F,
+	) -> Option<F::Output>
+	where
+		F: Future + 'static,
+	{
+		self.run_async_for(future, DEFAULT_TICK_BUDGET)
+	}
+
+	/// Like `run_async_for`, but a future that runs out of `budget` ends the whole test binary
+	///
+	/// QEMU exits with the Timeout code then, so an unattended run can tell a hang from a failed
+	/// assertion.
+	pub fn run_async_or_timeout<F>(
+		&mut self,
+		future: F,
+		budget: u64,
+	) -> F::Output
+	where
+		F: Future + 'static,
+	{
+		match self.run_async_for(future, budget) {
+			Some(output) => output,
+			None => exit::report_failure(
+				QemuExitCode::Timeout,
+				&format_args!("test future still pending after {} ticks", budget),
+				0,
+			),
+		}
+	}
+
+	/// Runs `future` on the executor until it's done or `budget` ticks went by
+	///
+	/// Other tasks on the executor get polled meanwhile. None if the budget ran out, the task
+	/// stays on the executor then.
+	pub fn run_async_for<F>(
+		&mut self,
+		future: F,
+		budget: u64,
+	) -> Option<F::Output>
+	where
+		F: Future + 'static,
+	{
+		let output = Rc::new(RefCell::new(None));
+		let slot = output.clone();
+		self.executor.spawn(Task::new(async move {
+			*slot.borrow_mut() = Some(future.await);
+		}));
+
+		let deadline = interrupts::ticks() + budget;
+		loop {
+			if let Some(value) = output.borrow_mut().take() {
+				return Some(value);
+			}
+			if interrupts::ticks() >= deadline {
+				return None;
+			}
+
+			// nothing ready, the next interrupt is what wakes anyone up
+			if self.executor.run_polls(POLLS_PER_ROUND) == 0 {
+				x86_64::instructions::hlt();
+			}
+		}
+	}
+}
+
+/// Unwraps an `Ok`, panics with the expression and the `Err` value otherwise
+///
+/// `let handle = assert_ok!(fs.create_file("a.txt"));` in place of an `expect` with a message
+/// that only says what the expression already does.
+#[macro_export]
+macro_rules! assert_ok {
+	($expr:expr $(,)?) => {
+		match $expr {
+			Ok(value) => value,
+			Err(e) => panic!("assertion failed: `{}` is Err({:?})", stringify!($expr), e),
+		}
+	};
+}
+
+/// Checks that the expression is an `Err` matching the pattern and hands back the error
+///
+/// `assert_err!(fs.open_file("gone"), FileError::FileNotFound);` Patterns can be or-ed and
+/// bind like in `matches!`. The `Ok` value isn't printed, it needn't be `Debug`.
+#[macro_export]
+macro_rules! assert_err {
+	($expr:expr, $($pattern:pat)|+ $(,)?) => {
+		match $expr {
+			Err(e) => {
+				if !matches!(e, $($pattern)|+) {
+					panic!(
+						"assertion failed: `{}` is Err({:?}), expected Err({})",
+						stringify!($expr),
+						e,
+						stringify!($($pattern)|+)
+					);
+				}
+				e
+			},
+			Ok(_) => panic!(
+				"assertion failed: `{}` is Ok, expected Err({})",
+				stringify!($expr),
+				stringify!($($pattern)|+)
+			),
+		}
+	};
+}
+
+#[cfg(test)]
+#[derive(Debug, PartialEq, Eq)]
+enum SelfTestError {
+	Empty,
+	TooLong(usize),
+}
+
+#[cfg(test)]
+fn self_test_parse(s: &str) -> Result<usize, SelfTestError> {
+	match s.len() {
+		0 => Err(SelfTestError::Empty),
+		len if len > 4 => Err(SelfTestError::TooLong(len)),
+		len => Ok(len),
+	}
+}
+
+#[test_case]
+fn assert_ok_hands_back_the_value() {
+	assert_eq!(assert_ok!(self_test_parse("abc")), 3);
+	let len = assert_ok!(self_test_parse("abcd"),);
+	assert_eq!(len, 4);
+}
+
+#[test_case]
+fn assert_err_matches_patterns() {
+	assert_eq!(assert_err!(self_test_parse(""), SelfTestError::Empty), SelfTestError::Empty);
+	assert_err!(self_test_parse("abcdef"), SelfTestError::TooLong(6));
+	assert_err!(self_test_parse("abcdefg"), SelfTestError::TooLong(5..=8));
+	assert_err!(self_test_parse(""), SelfTestError::Empty | SelfTestError::TooLong(_),);
+}
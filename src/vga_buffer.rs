@@ -6,6 +6,8 @@
 /// refer [here](https://os.phil-opp.com/vga-text-mode/#volatile)
 use volatile::Volatile; // helps avoid optimizations by the compiler .. since they could break the code
 
+use core::sync::atomic::{AtomicBool, Ordering};
+
 // gotta make some colors .. the bright bit combines with the normal bits to form the bright colors
 // in the VGA buffer
 //
@@ -34,10 +36,10 @@ pub enum Color {
 /// to represent a full color code that specifies the foreground and background color
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(transparent)] // tells that it should have the exact same memory layout as its fields i.e. u8
-struct ColorCode(u8); // see the newtype idiom
+pub struct ColorCode(u8); // see the newtype idiom
 
 impl ColorCode {
-	fn new(
+	pub fn new(
 		foreground: Color,
 		background: Color,
 	) -> ColorCode {
@@ -51,23 +53,63 @@ impl ColorCode {
 /// to represent a screen character in the VGA text buffer
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)] // so that the struct fields are laid out in order as in C .. not in rust by default
-struct ScreenChar {
-	ascii_character: u8,
-	color_code: ColorCode,
+pub struct ScreenChar {
+	pub ascii_character: u8,
+	pub color_code: ColorCode,
+}
+
+impl ScreenChar {
+	pub fn new(
+		ascii_character: u8,
+		color_code: ColorCode,
+	) -> ScreenChar {
+		ScreenChar { ascii_character, color_code }
+	}
 }
 
-// the VGA text buffer is a 2D array that has 25 rows and 80 columns
+// the VGA text buffer is a 2D array that has 25 rows and 80 columns, everything below sizes
+// itself from these two
 /// the VGA screen displays 25 lines of text
-const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_HEIGHT: usize = 25;
 /// each VGA line can show 80 characters
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_WIDTH: usize = 80;
+
+/// where the text buffer is mapped
+const BUFFER_ADDR: usize = 0xb8000;
+
+/// a position that's off screen, row and col as they were asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfRange {
+	pub row: usize,
+	pub col: usize,
+}
+
+/// one line of cells
+type Row = [Volatile<ScreenChar>; BUFFER_WIDTH];
 
 /// to represent the VGA Buffer -- 2D array <br>
 /// It is a contiguous block of memory starting at 0xb8000
 #[repr(transparent)]
 struct Buffer {
 	/// 2D array to represent characters
-	chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
+	chars: [Row; BUFFER_HEIGHT],
+}
+
+/// set once the buffer has been handed out
+static BUFFER_TAKEN: AtomicBool = AtomicBool::new(false);
+
+impl Buffer {
+	/// The real buffer, the first time this is called and None after that
+	///
+	/// A second `&mut` to the same memory would alias the first, so there's only ever one and
+	/// it's the WRITER's. Everything else prints through the WRITER. The bootloader maps
+	/// 0xb8000 for the whole run, which is what makes the `'static` hold.
+	fn take() -> Option<&'static mut Buffer> {
+		if BUFFER_TAKEN.swap(true, Ordering::AcqRel) {
+			return None;
+		}
+		Some(unsafe { &mut *(BUFFER_ADDR as *mut Buffer) })
+	}
 }
 
 /// writer type to write into the screen [VGA]
@@ -122,6 +164,88 @@ impl Writer {
 		self.column_position = 0;
 	}
 
+	/// reads back the character currently shown at the given position
+	pub(crate) fn char_at(
+		&self,
+		row: usize,
+		col: usize,
+	) -> u8 {
+		self.buffer.chars[row][col].read().ascii_character
+	}
+
+	/// the column the next character goes to
+	pub fn column_position(&self) -> usize {
+		self.column_position
+	}
+
+	/// Makes the next character go to `col` of the bottom row, for text written with `write_at`
+	///
+	/// Past the last column means the next character starts a new line.
+	pub fn set_column_position(
+		&mut self,
+		col: usize,
+	) {
+		self.column_position = col.min(BUFFER_WIDTH);
+	}
+
+	/// blanks every row, the next character goes to the start of the bottom one
+	pub fn clear_screen(&mut self) {
+		for row in 0..BUFFER_HEIGHT {
+			self.clear_row(row);
+		}
+		self.column_position = 0;
+	}
+
+	/// the colors the writer currently uses for new characters
+	pub fn color_code(&self) -> ColorCode {
+		self.color_code
+	}
+
+	/// Gives direct access to a single cell, None if it's off screen
+	///
+	/// The cell stays behind `Volatile`, so reads and writes through it are never optimized away.
+	/// Doesn't move the cursor, the next `write_byte` continues where it left off.
+	pub fn cell(
+		&mut self,
+		row: usize,
+		col: usize,
+	) -> Option<&mut Volatile<ScreenChar>> {
+		self.buffer.chars.get_mut(row)?.get_mut(col)
+	}
+
+	/// a whole row of cells, None if it's off screen
+	pub fn row(
+		&mut self,
+		row: usize,
+	) -> Option<&mut Row> {
+		self.buffer.chars.get_mut(row)
+	}
+
+	/// Writes `s` starting at the given position, without moving the cursor
+	///
+	/// Whatever doesn't fit in the row is cut off, nothing wraps or scrolls. Returns how many chars
+	/// made it onto the screen, each one takes a cell.
+	pub fn write_at(
+		&mut self,
+		row: usize,
+		col: usize,
+		s: &str,
+	) -> Result<usize, OutOfRange> {
+		if row >= BUFFER_HEIGHT || col >= BUFFER_WIDTH {
+			return Err(OutOfRange { row, col });
+		}
+
+		let color_code = self.color_code;
+		let cells = &mut self.buffer.chars[row][col..];
+
+		let mut written = 0;
+		for (cell, c) in cells.iter_mut().zip(s.chars()) {
+			cell.write(ScreenChar { ascii_character: to_cp437(c), color_code });
+			written += 1;
+		}
+		Ok(written)
+	}
+
 	/// clears a raw by writing all of its characters with a space character
 	fn clear_row(
 		&mut self,
@@ -139,44 +263,98 @@ impl Writer {
 	/// parameters: <br>
 	/// s: &str
 	///
-	/// <br> prints a '■' for unprintable bytes
+	/// <br> every char takes one cell, prints a '■' for chars the VGA font doesn't have
 	pub fn write_string(
 		&mut self,
 		s: &str,
 	) {
-		for byte in s.bytes() {
-			// rust strings are UTF-8 by default so they might contain some unsupported chars by
-			// the vga_buffer
-			match byte {
-				// check for printable ascii_character or new_line
-				// 0x20 is 32 in decimal for ' '
-				// 0x7e is 126 in decimal for '~'
-				// they denote the printable ASCII range
-				// range inclusive notation -- remember it
-				0x20..=0x7e | b'\n' => self.write_byte(byte),
-				// not part of the of the printable ASCII range
-				_ => self.write_byte(0xfe), // print a ■ for unprintable bytes
+		// rust strings are UTF-8, the VGA font is code page 437, so go char by char and not byte
+		// by byte
+		for c in s.chars() {
+			match c {
+				'\n' => self.new_line(),
+				c => self.write_byte(to_cp437(c)),
 			}
 		}
 	}
 }
 
+/// what code page 437 has from 0x80 up, indexed by the byte minus 0x80
+#[rustfmt::skip]
+const CP437_HIGH: [char; 128] = [
+	'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', // 0x80
+	'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', // 0x90
+	'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', // 0xA0
+	'░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', // 0xB0
+	'└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', // 0xC0
+	'╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', // 0xD0
+	'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', // 0xE0
+	'≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}', // 0xF0
+];
+
+/// the glyph VGA shows for chars it has no glyph for
+const REPLACEMENT: u8 = 0xfe; // ■
+
+/// The code page 437 byte that shows `c`, a ■ if there is none
+///
+/// Printable ASCII maps to itself, the rest is looked up in the upper half of the code page.
+pub fn to_cp437(c: char) -> u8 {
+	match c {
+		// 0x20 is 32 in decimal for ' '
+		// 0x7e is 126 in decimal for '~'
+		// they denote the printable ASCII range
+		' '..='~' => c as u8,
+		_ => CP437_HIGH.iter().position(|&high| high == c).map_or(REPLACEMENT, |i| 0x80 + i as u8),
+	}
+}
+
+use crate::hw::ports::{self, ClaimedPort};
+
+/// the CRT controller's index port, its data port comes right after
+const CRTC_INDEX: u16 = 0x3D4;
+
+/// the CRT controller's ports, claimed the first time the cursor moves
+static CRTC_PORTS: spin::Once<ClaimedPort> = spin::Once::new();
+
+/// Moves the blinking hardware cursor to the given cell, off screen positions are ignored
+///
+/// The writer doesn't touch the cursor on its own, whoever wants it somewhere puts it there.
+pub fn set_cursor(
+	row: usize,
+	col: usize,
+) {
+	if row >= BUFFER_HEIGHT || col >= BUFFER_WIDTH {
+		return;
+	}
+
+	let crtc = CRTC_PORTS.call_once(|| {
+		ports::claim(CRTC_INDEX..=CRTC_INDEX + 1, "vga").expect("VGA CRTC ports are taken")
+	});
+	let cell = (row * BUFFER_WIDTH + col) as u16;
+	unsafe {
+		// registers 0x0F and 0x0E hold the low and high byte of the cell the cursor is on
+		crtc.write(CRTC_INDEX, 0x0Fu8);
+		crtc.write(CRTC_INDEX + 1, cell as u8);
+		crtc.write(CRTC_INDEX, 0x0Eu8);
+		crtc.write(CRTC_INDEX + 1, (cell >> 8) as u8);
+	}
+}
+
+/// a demo of the writer, through the WRITER since nothing else gets the buffer
 pub fn print_something() {
 	use core::fmt::Write;
+	use x86_64::instructions::interrupts;
 
-	let mut writer = Writer {
-		column_position: 0,
-		color_code: ColorCode::new(Color::Yellow, Color::Blue),
-		buffer: unsafe {
-			&mut *(0xb8000 as *mut Buffer)
-			// casting it into a raw mutable pointer and then derefencing through * and then
-			// again getting a mutable pointer from that ..
-		},
-	};
-
-	writer.write_byte(b'H');
-	writer.write_string("ello ");
-	write!(writer, "The numbers are {} and {}", 42, 1.0 / 3.0).unwrap();
+	interrupts::without_interrupts(|| {
+		let mut writer = WRITER.lock();
+		let previous = writer.color_code;
+		writer.color_code = ColorCode::new(Color::Yellow, Color::Blue);
+
+		writer.write_byte(b'H');
+		writer.write_string("ello ");
+		write!(writer, "The numbers are {} and {}", 42, 1.0 / 3.0).unwrap();
+		writer.color_code = previous;
+	});
 }
 
 use core::fmt;
@@ -204,18 +382,16 @@ lazy_static! { // so that this is only made once in the runtime
 	pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
 		column_position: 0,
 		color_code: ColorCode::new(Color::Yellow, Color::Red),
-		buffer: unsafe {
-			&mut *(0xb8000 as *mut Buffer)
-		},
+		buffer: Buffer::take().expect("VGA buffer taken before the WRITER"),
 	});
 }
 
 #[macro_export] // makes it availble for the entire crate to use
 macro_rules! print {
     // tt stands for token tree
-    ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
     // expansion of the macro ... is shown in the arm
-    // this macro invokes _print
+    // this macro invokes the console's _print, which fans out to the enabled targets
 }
 
 // Picked from the standard library
@@ -226,6 +402,19 @@ macro_rules! println {
     // print! invokes the print! macro which in turn invokes _print
 }
 
+/// prints straight to the VGA buffer, regardless of the console targets
+#[macro_export]
+macro_rules! vga_print {
+    ($($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*)));
+}
+
+/// prints straight to the VGA buffer, appending a newline
+#[macro_export]
+macro_rules! vga_println {
+    () => ($crate::vga_print!("\n"));
+    ($($arg:tt)*) => ($crate::vga_print!("{}\n", format_args!($($arg)*)));
+}
+
 // $crate helps us expand to the current crate's root path
 
 /// Prints the given formatted string to the VGA text buffer
@@ -273,3 +462,169 @@ fn test_println_output() {
 		}
 	});
 }
+
+/// handlers run with interrupts off and print through the same path, neither may deadlock
+#[test_case]
+fn test_println_from_interrupt_context() {
+	use crate::console::{self, Targets};
+	use x86_64::instructions::interrupts;
+
+	// what a handler looks like: interrupts are off, and _print must leave them that way
+	interrupts::without_interrupts(|| {
+		vga_println!("test_println_from_interrupt_context with interrupts off");
+		assert!(!interrupts::are_enabled());
+	});
+	assert!(interrupts::are_enabled());
+
+	// a real one: the breakpoint handler prints its stack frame through both targets
+	let previous = console::targets();
+	console::set_targets(Targets::ALL);
+	interrupts::int3();
+	console::set_targets(previous);
+
+	vga_println!("test_println_from_interrupt_context after the handler");
+}
+
+#[test_case]
+fn test_cell_draws_box() {
+	use x86_64::instructions::interrupts;
+
+	// code page 437 double line box drawing characters
+	const TOP_LEFT: u8 = 0xC9;
+	const TOP_RIGHT: u8 = 0xBB;
+	const BOTTOM_LEFT: u8 = 0xC8;
+	const BOTTOM_RIGHT: u8 = 0xBC;
+	const HORIZONTAL: u8 = 0xCD;
+	const VERTICAL: u8 = 0xBA;
+
+	let (top, left, bottom, right) = (2, 10, 6, 30);
+
+	interrupts::without_interrupts(|| {
+		let mut writer = WRITER.lock();
+		let color = writer.color_code();
+
+		for row in top..=bottom {
+			for col in left..=right {
+				let byte = match (row, col) {
+					(r, c) if r == top && c == left => TOP_LEFT,
+					(r, c) if r == top && c == right => TOP_RIGHT,
+					(r, c) if r == bottom && c == left => BOTTOM_LEFT,
+					(r, c) if r == bottom && c == right => BOTTOM_RIGHT,
+					(r, _) if r == top || r == bottom => HORIZONTAL,
+					(_, c) if c == left || c == right => VERTICAL,
+					_ => b' ',
+				};
+				writer.cell(row, col).unwrap().write(ScreenChar::new(byte, color));
+			}
+		}
+
+		assert_eq!(writer.char_at(top, left), TOP_LEFT);
+		assert_eq!(writer.char_at(top, right), TOP_RIGHT);
+		assert_eq!(writer.char_at(bottom, left), BOTTOM_LEFT);
+		assert_eq!(writer.char_at(bottom, right), BOTTOM_RIGHT);
+		assert_eq!(writer.char_at(top, left + 1), HORIZONTAL);
+		assert_eq!(writer.char_at(top + 1, right), VERTICAL);
+		assert_eq!(writer.row(top + 1).unwrap()[left + 1].read().ascii_character, b' ');
+
+		// reading back through the cell goes through Volatile too
+		assert_eq!(writer.cell(bottom, left).unwrap().read(), ScreenChar::new(BOTTOM_LEFT, color));
+
+		assert!(writer.cell(BUFFER_HEIGHT, 0).is_none());
+		assert!(writer.cell(0, BUFFER_WIDTH).is_none());
+		assert!(writer.row(BUFFER_HEIGHT).is_none());
+	});
+}
+
+#[test_case]
+fn test_write_at_bounds() {
+	use x86_64::instructions::interrupts;
+
+	interrupts::without_interrupts(|| {
+		let mut writer = WRITER.lock();
+
+		assert_eq!(
+			writer.write_at(BUFFER_HEIGHT, 0, "x"),
+			Err(OutOfRange { row: BUFFER_HEIGHT, col: 0 })
+		);
+		assert_eq!(
+			writer.write_at(0, BUFFER_WIDTH, "x"),
+			Err(OutOfRange { row: 0, col: BUFFER_WIDTH })
+		);
+
+		// the last cell is fine, the rest of the string is cut off
+		assert_eq!(writer.write_at(BUFFER_HEIGHT - 1, BUFFER_WIDTH - 1, "ab"), Ok(1));
+		assert_eq!(writer.char_at(BUFFER_HEIGHT - 1, BUFFER_WIDTH - 1), b'a');
+
+		assert_eq!(writer.write_at(3, 5, "hi\u{7}"), Ok(3));
+		assert_eq!(writer.char_at(3, 5), b'h');
+		assert_eq!(writer.char_at(3, 7), 0xfe);
+	});
+}
+
+#[test_case]
+fn test_utf8_one_cell_per_char() {
+	use core::fmt::Write;
+	use x86_64::instructions::interrupts;
+
+	interrupts::without_interrupts(|| {
+		let mut writer = WRITER.lock();
+		let row = BUFFER_HEIGHT - 1;
+
+		write!(writer, "\na\u{e9}\u{2502}b\u{20ac}").unwrap();
+
+		// é and │ are two and three bytes of UTF-8, but one cell each
+		assert_eq!(writer.column_position, 5);
+		assert_eq!(writer.char_at(row, 0), b'a');
+		assert_eq!(writer.char_at(row, 1), 0x82);
+		assert_eq!(writer.char_at(row, 2), 0xB3);
+		assert_eq!(writer.char_at(row, 3), b'b');
+		// no euro sign in code page 437
+		assert_eq!(writer.char_at(row, 4), REPLACEMENT);
+
+		assert_eq!(writer.write_at(0, BUFFER_WIDTH - 2, "\u{250c}\u{2500}\u{2510}"), Ok(2));
+		assert_eq!(writer.char_at(0, BUFFER_WIDTH - 1), 0xC4);
+	});
+}
+
+#[test_case]
+fn test_cp437_table() {
+	let expected = [
+		('\u{2500}', 0xC4), // ─
+		('\u{2502}', 0xB3), // │
+		('\u{250c}', 0xDA), // ┌
+		('\u{2510}', 0xBF), // ┐
+		('\u{2514}', 0xC0), // └
+		('\u{2518}', 0xD9), // ┘
+		('\u{251c}', 0xC3), // ├
+		('\u{2524}', 0xB4), // ┤
+		('\u{252c}', 0xC2), // ┬
+		('\u{2534}', 0xC1), // ┴
+		('\u{253c}', 0xC5), // ┼
+		('\u{2591}', 0xB0), // ░
+		('\u{2592}', 0xB1), // ▒
+		('\u{2593}', 0xB2), // ▓
+		('\u{2588}', 0xDB), // █
+		('\u{fc}', 0x81),   // ü
+		('\u{f1}', 0xA4),   // ñ
+		('\u{b0}', 0xF8),   // °
+		('\u{b1}', 0xF1),   // ±
+		('~', b'~'),
+		('\t', REPLACEMENT),
+	];
+	for &(c, byte) in expected.iter() {
+		assert_eq!(to_cp437(c), byte, "{:?}", c);
+	}
+}
+
+#[test_case]
+fn test_only_the_writer_owns_the_buffer() {
+	use x86_64::instructions::interrupts;
+
+	interrupts::without_interrupts(|| {
+		// the WRITER has taken it by now, whoever asks next gets nothing
+		let writer = WRITER.lock();
+		assert!(Buffer::take().is_none());
+		assert!(Buffer::take().is_none());
+		assert!(core::ptr::eq(&*writer.buffer, BUFFER_ADDR as *const Buffer));
+	});
+}
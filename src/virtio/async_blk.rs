@@ -0,0 +1,245 @@
+//! in src/virtio/async_blk.rs
+//!
+//! Interrupt driven VirtIO block I/O. A request is submitted without waiting on it, the task is
+//! parked and the IRQ handler wakes it once the device put the request on the used ring.
+//!
+//! `VirtIOBlkAsync` is the fallback for devices without an interrupt line, it still reads
+//! synchronously but lets the other tasks run around each read.
+
+use super::{OsHal, pci};
+use crate::{
+	fs::{
+		block_dev::{BlockDevice, BlockDeviceAsync},
+		simple_fs::FileSystemError,
+	},
+	interrupts, println,
+	task::{sync::Semaphore, yield_now},
+};
+use conquer_once::spin::OnceCell;
+use core::{
+	future::Future,
+	pin::Pin,
+	task::{Context, Poll, Waker},
+};
+use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
+use virtio_drivers::{
+	Error as VirtIOError, Hal,
+	device::blk::{BlkReq, BlkResp, VirtIOBlk},
+	transport::{
+		Transport,
+		pci::{PciTransport, bus::DeviceFunction},
+	},
+};
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// the device, reachable from the interrupt handler
+static BLK: OnceCell<AsyncVirtIOBlk> = OnceCell::uninit();
+
+/// tasks waiting on a request in flight, all of them are woken whenever the device completes one
+static COMPLETION_WAKERS: OnceCell<ArrayQueue<Waker>> = OnceCell::uninit();
+
+/// descriptors a request takes up in the virtqueue, the header, the buffer and the status
+const DESCRIPTORS_PER_REQUEST: u16 = 3;
+
+/// A VirtIO block device whose requests complete through its interrupt
+///
+/// As many requests are in flight as fit into the virtqueue, tasks beyond that park on `slots`
+/// until one is done. The device is only ever locked with interrupts disabled so the interrupt
+/// handler can't deadlock on it.
+pub struct AsyncVirtIOBlk {
+	device: Mutex<VirtIOBlk<OsHal, PciTransport>>,
+	/// one permit per request the virtqueue has room for
+	slots: Semaphore,
+}
+
+#[derive(Debug)]
+pub enum AsyncBlkInitError {
+	AlreadyInitialized,
+	/// the device has no INTx pin, it only interrupts through MSI-X
+	NoInterruptPin,
+	/// the device's line can't take a handler, see `interrupts::register_irq_handler`
+	Irq(interrupts::IrqError),
+}
+
+/// Moves a block device into interrupt driven mode
+///
+/// `device_function` is where the device sits on the PCI bus, its interrupt line is read from
+/// the config space. Completions come in over legacy INTx, the transport never sets up MSI-X
+/// vectors, so this works the same with `msix=off`.
+pub fn init(
+	device: VirtIOBlk<OsHal, PciTransport>,
+	device_function: DeviceFunction,
+) -> Result<&'static AsyncVirtIOBlk, AsyncBlkInitError> {
+	if pci::interrupt_pin(device_function).is_none() {
+		return Err(AsyncBlkInitError::NoInterruptPin);
+	}
+	let line = pci::interrupt_line(device_function);
+	let depth = (device.virt_queue_size() / DESCRIPTORS_PER_REQUEST).max(1);
+
+	// a spare waker per request, for tasks that get polled again before the interrupt
+	COMPLETION_WAKERS
+		.try_init_once(|| ArrayQueue::new(2 * depth as usize))
+		.map_err(|_| AsyncBlkInitError::AlreadyInitialized)?;
+	BLK.try_init_once(|| AsyncVirtIOBlk {
+		device: Mutex::new(device),
+		slots: Semaphore::new(depth as u64),
+	})
+	.map_err(|_| AsyncBlkInitError::AlreadyInitialized)?;
+
+	interrupts::register_irq_handler(line, handle_interrupt).map_err(AsyncBlkInitError::Irq)?;
+
+	println!("[VirtIO] Block device completions on IRQ {}, {} requests in flight", line, depth);
+
+	Ok(BLK.try_get().expect("just initialized"))
+}
+
+/// Called from the shared IRQ handler, for anything on the line
+///
+/// Must not block or allocate!
+fn handle_interrupt() {
+	let blk = match BLK.try_get() {
+		Ok(blk) => blk,
+		Err(_) => return,
+	};
+
+	let mut device = blk.device.lock();
+	device.ack_interrupt();
+
+	// the token stays on the used ring until its task completes the request, the tasks look for
+	// their own
+	if device.peek_used().is_some() {
+		wake_completions();
+	}
+}
+
+/// wakes every task parked on a request, the ones whose request isn't done park again
+fn wake_completions() {
+	let wakers = match COMPLETION_WAKERS.try_get() {
+		Ok(wakers) => wakers,
+		Err(_) => return,
+	};
+	// only drain what is there now, woken tasks that park again go in behind
+	for _ in 0..wakers.len() {
+		match wakers.pop() {
+			Some(waker) => waker.wake(),
+			None => break,
+		}
+	}
+}
+
+/// Reads blocks starting at `sector` into `buf`, parking the task until the device is done
+///
+/// Parks first while the virtqueue is full. Dropping the future while the request is in flight
+/// leaves it at the head of the used ring for good, and every request after it with it.
+pub async fn read_blocks_async(
+	blk: &AsyncVirtIOBlk,
+	sector: usize,
+	buf: &mut [u8],
+) -> Result<(), VirtIOError> {
+	blk.slots.acquire().await;
+	let result = read_in_slot(blk, sector, buf).await;
+	blk.slots.release();
+	result
+}
+
+/// `read_blocks_async` once it has its place in the virtqueue
+async fn read_in_slot(
+	blk: &AsyncVirtIOBlk,
+	sector: usize,
+	buf: &mut [u8],
+) -> Result<(), VirtIOError> {
+	let mut req = BlkReq::default();
+	let mut resp = BlkResp::default();
+
+	let token = without_interrupts(|| unsafe {
+		blk.device.lock().read_blocks_nb(sector, &mut req, buf, &mut resp)
+	})?;
+
+	Completion { blk, token }.await;
+
+	let result = without_interrupts(|| unsafe {
+		blk.device.lock().complete_read_blocks(token, &req, buf, &mut resp)
+	});
+	// the request after this one may have been done for a while, its task saw this token in
+	// front of it and parked
+	wake_completions();
+	result
+}
+
+/// Resolves once `token` is next on the used ring
+///
+/// The device may finish requests in any order, but they have to be completed in the order they
+/// show up on the used ring.
+struct Completion<'a> {
+	blk: &'a AsyncVirtIOBlk,
+	token: u16,
+}
+
+impl Future for Completion<'_> {
+	type Output = ();
+
+	fn poll(
+		self: Pin<&mut Self>,
+		cx: &mut Context,
+	) -> Poll<()> {
+		let wakers = COMPLETION_WAKERS.try_get().expect("async block device not initialized");
+
+		// register before looking, the interrupt could come in right after the check
+		if let Err(waker) = wakers.push(cx.waker().clone()) {
+			// too many parked already, come back on the next round
+			waker.wake();
+		}
+
+		let next = without_interrupts(|| self.blk.device.lock().peek_used());
+		if next == Some(self.token) { Poll::Ready(()) } else { Poll::Pending }
+	}
+}
+
+/// A VirtIO block device whose reads give the other tasks a turn
+///
+/// Not asynchronous I/O, the read itself still spins until the device is done. But the task
+/// yields right before and right after it, so on a single threaded executor a run of reads
+/// doesn't keep the keyboard task from the scancodes its interrupt handler queued up.
+pub struct VirtIOBlkAsync<H: Hal, T: Transport>(VirtIOBlk<H, T>);
+
+impl<H: Hal, T: Transport> VirtIOBlkAsync<H, T> {
+	pub fn new(device: VirtIOBlk<H, T>) -> Self {
+		VirtIOBlkAsync(device)
+	}
+
+	/// the synchronous device again
+	pub fn into_inner(self) -> VirtIOBlk<H, T> {
+		self.0
+	}
+}
+
+impl<H: Hal, T: Transport> VirtIOBlkAsync<H, T>
+where
+	VirtIOBlk<H, T>: BlockDevice,
+{
+	/// Reads blocks starting at `block` into `buf`, like `BlockDevice::read_blocks` does
+	pub async fn read_blocks_async(
+		&mut self,
+		block: u64,
+		buf: &mut [u8],
+	) -> Result<(), FileSystemError> {
+		yield_now().await;
+		let result = BlockDevice::read_blocks(&mut self.0, block, buf);
+		yield_now().await;
+		result
+	}
+}
+
+impl<H: Hal, T: Transport> BlockDeviceAsync for VirtIOBlkAsync<H, T>
+where
+	VirtIOBlk<H, T>: BlockDevice,
+{
+	fn read_blocks_async<'a>(
+		&'a mut self,
+		block: u64,
+		buf: &'a mut [u8],
+	) -> impl Future<Output = Result<(), FileSystemError>> + 'a {
+		VirtIOBlkAsync::read_blocks_async(self, block, buf)
+	}
+}
@@ -1,56 +1,89 @@
 //! in src/virtio/mod.rs
 
+pub mod async_blk;
 pub mod pci;
 
 use crate::memory::BootInfoFrameAllocator;
 use crate::println;
+use crate::sync::Mutex;
+use core::ops::{Deref, DerefMut};
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
-use spin::Mutex;
-use virtio_drivers::{BufferDirection, Hal};
+use virtio_drivers::{BufferDirection, Hal, PAGE_SIZE};
 use x86_64::structures::paging::{Mapper, Page, PageTableFlags};
 use x86_64::{
 	PhysAddr, VirtAddr,
-	structures::paging::{FrameAllocator, OffsetPageTable},
+	structures::paging::{FrameAllocator, OffsetPageTable, PhysFrame},
 };
 
 // Global reference to the frame allocator
 // gotta set it in kernel init function
+// sync::Mutex, so the debug-mutex feature catches these being locked twice
 lazy_static! {
 	pub static ref FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
 	pub static ref PAGE_MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
 }
 
+/// Gives a frame back to the global frame allocator, see `BootInfoFrameAllocator::dealloc_frame`
+pub fn dealloc_frame(frame: PhysFrame) {
+	FRAME_ALLOCATOR.lock().as_mut().expect("Frame allocator not initialized").dealloc_frame(frame);
+}
+
 pub struct OsHal;
 
-pub static mut PHYSICAL_MEMORY_OFFSET: u64 = 0;
+/// where the bootloader mapped all of physical memory, 0 until `set_physical_memory_offset`
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Records where the bootloader mapped physical memory
+///
+/// Kernel init and `test_harness::init_full` call it once, before any driver starts or the HPET
+/// is looked for. Everything that turns physical addresses into virtual ones reads it after that.
+pub fn set_physical_memory_offset(offset: u64) {
+	PHYSICAL_MEMORY_OFFSET.store(offset, Ordering::Release);
+}
+
+/// the offset `set_physical_memory_offset` recorded, 0 before that
+pub fn physical_memory_offset() -> u64 {
+	PHYSICAL_MEMORY_OFFSET.load(Ordering::Acquire)
+}
 
 unsafe impl Hal for OsHal {
 	fn dma_alloc(
 		pages: usize,
 		_direction: BufferDirection,
 	) -> (virtio_drivers::PhysAddr, NonNull<u8>) {
-		if pages > 1 {
-			panic!("dma_alloc: multipage contiguous allocation not supported yet");
-		}
-
 		let mut frame_allocator_lock = FRAME_ALLOCATOR.lock();
 		let allocator = frame_allocator_lock.as_mut().expect("Frame allocator not initialized");
 
-		// 1. Allocate a physical frame.
-		let frame = allocator.allocate_frame().expect("Failed to allocate frame for DMA");
+		// 1. Allocate physical frames, the device sees the buffer as one physical run.
+		// out of frames is no reason to take the kernel down, the driver turns a zero paddr into
+		// Error::DmaError. Frame 0 is below LOW_MEMORY_LIMIT, so it never is a real allocation
+		let frame = match pages {
+			1 => allocator.allocate_frame(),
+			_ => allocator.allocate_contiguous(pages),
+		};
+		let Some(frame) = frame else {
+			println!(
+				"[DMA] out of physical frames ({} of {} in use), can't allocate {} page(s)",
+				allocator.frames_used(),
+				allocator.frames_total(),
+				pages
+			);
+			return (0, NonNull::dangling());
+		};
 		let paddr = frame.start_address();
 
 		// 2. Calculate its virtual address in the higher-half mapping.
-		let vaddr = VirtAddr::new(paddr.as_u64() + unsafe { PHYSICAL_MEMORY_OFFSET });
+		let vaddr = VirtAddr::new(paddr.as_u64() + physical_memory_offset());
 
 		println!("[DMA] Allocating DMA buffer ({} pages):", pages);
 		println!("  - Physical Address (for device): {:#x}", paddr);
 		println!("  - Virtual Address (for CPU):  {:#x}", vaddr);
 
 		// NO MAPPING IS NEEDED. The bootloader's huge page mapping already covers this.
-		// Here, there is no work with Pages. The Frame is an actual block of physical memory --
-		// here 4 KiB in size.
+		// Here, there is no work with Pages. The Frames are actual blocks of physical memory --
+		// 4 KiB each, and contiguous, so the virtual range is contiguous too.
 
 		// Here, we return the physical address
 		(paddr.as_u64() as usize, NonNull::new(vaddr.as_mut_ptr()).unwrap())
@@ -60,9 +93,15 @@ unsafe impl Hal for OsHal {
 		vaddr: NonNull<u8>,
 		pages: usize,
 	) -> i32 {
-		println!("[DMA] Warning: Leaking DMA memory at paddr={:#x}, pages={}", paddr, pages);
-
-		// TODO: Currently leaking memory, add logic for deallocation of the frame
+		println!("[DMA] Freeing DMA buffer at paddr={:#x}, pages={}", paddr, pages);
+
+		// there is no mapping to undo: dma_alloc goes through the bootloader's physical memory
+		// mapping and never calls map_to, so no page tables get allocated for DMA buffers and there
+		// are none to reclaim here. Only the frames go back.
+		let first = PhysFrame::containing_address(PhysAddr::new(paddr as u64));
+		for frame in PhysFrame::range(first, first + pages as u64) {
+			dealloc_frame(frame);
+		}
 		0
 	}
 
@@ -73,7 +112,7 @@ unsafe impl Hal for OsHal {
 		// For MMIO, we use identity mapping with the physical memory offset
 		// This avoids issues with huge pages in the bootloader's page tables
 		let paddr = PhysAddr::new(paddr as u64);
-		let vaddr = VirtAddr::new(paddr.as_u64() + PHYSICAL_MEMORY_OFFSET);
+		let vaddr = VirtAddr::new(paddr.as_u64() + physical_memory_offset());
 
 		println!("[MMAP] Mapping device MMIO region:");
 		println!("  - Physical Address: {:#x}", paddr);
@@ -93,10 +132,12 @@ unsafe impl Hal for OsHal {
 		let vaddr = VirtAddr::new(buffer.as_ptr() as *mut u8 as u64);
 
 		// We use the offset you've already calculated to translate.
-		let offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET);
+		let offset = VirtAddr::new(physical_memory_offset());
 
 		// This is the function you wrote in memory.rs!
+		// huge pages are fine, the buffer just has to be mapped at all
 		let phyaddr = crate::memory::translate_addr(vaddr, offset)
+			.phys_addr()
 			.expect("Failed to translate virtual address for sharing");
 
 		println!("[SHARE] Translating buffer address for device:");
@@ -113,4 +154,68 @@ unsafe impl Hal for OsHal {
 	) {
 		// Do nothing
 	}
-}
\ No newline at end of file
+}
+/// Physically contiguous DMA memory that is handed back to `OsHal` once dropped
+///
+/// Heap buffers are only contiguous within a page, so anything the device reads or writes in one
+/// go across a page boundary has to go through one of these
+pub struct DmaBuffer {
+	phys: usize,
+	virt: NonNull<u8>,
+	pages: usize,
+}
+
+impl DmaBuffer {
+	/// Allocates `pages` zeroed pages of DMA memory
+	///
+	/// The pages are physically contiguous. Returns None for zero pages, and when the frame
+	/// allocator has no run of `pages` fresh frames left
+	pub fn alloc(pages: usize) -> Option<Self> {
+		if pages == 0 {
+			return None;
+		}
+
+		let (phys, virt) = OsHal::dma_alloc(pages, BufferDirection::Both);
+		if phys == 0 {
+			return None;
+		}
+
+		// frames come straight from the frame allocator, whatever was there before is still there
+		unsafe {
+			core::ptr::write_bytes(virt.as_ptr(), 0, pages * PAGE_SIZE);
+		}
+
+		Some(DmaBuffer { phys, virt, pages })
+	}
+
+	/// physical address of the first byte, this is what the device gets to see
+	pub fn phys_addr(&self) -> usize {
+		self.phys
+	}
+
+	pub fn pages(&self) -> usize {
+		self.pages
+	}
+}
+
+impl Deref for DmaBuffer {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		unsafe { core::slice::from_raw_parts(self.virt.as_ptr(), self.pages * PAGE_SIZE) }
+	}
+}
+
+impl DerefMut for DmaBuffer {
+	fn deref_mut(&mut self) -> &mut [u8] {
+		unsafe { core::slice::from_raw_parts_mut(self.virt.as_ptr(), self.pages * PAGE_SIZE) }
+	}
+}
+
+impl Drop for DmaBuffer {
+	fn drop(&mut self) {
+		unsafe {
+			OsHal::dma_dealloc(self.phys, self.virt, self.pages);
+		}
+	}
+}
@@ -1,45 +1,398 @@
 // in src/virtio/pci
 
+use crate::hw::ports::{self, ClaimedPort};
 use crate::println;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
 use virtio_drivers::transport::pci::bus::{ConfigurationAccess, DeviceFunction, PciRoot};
-use x86_64::instructions::port::Port;
+use x86_64::instructions::port::PortWrite;
 
 const CONFIG_ADDRESS: u16 = 0xCF8;
 const CONFIG_DATA: u16 = 0xCFC;
 
-/// Reads a 32-bit value from the PCI configuration space.
-unsafe fn read_config_dword(
+/// the address and data registers, claimed on the first config space access
+static CONFIG_PORTS: spin::Once<ClaimedPort> = spin::Once::new();
+
+fn config_ports() -> &'static ClaimedPort {
+	CONFIG_PORTS.call_once(|| {
+		ports::claim(CONFIG_ADDRESS..=CONFIG_DATA + 3, "pci").expect("PCI config ports are taken")
+	})
+}
+
+/// Builds the CONFIG_ADDRESS value selecting the dword that holds `offset`
+fn config_address(
 	bus: u8,
 	device: u8,
 	function: u8,
 	offset: u8,
 ) -> u32 {
-	let mut address_port = Port::new(CONFIG_ADDRESS);
-	let mut data_port = Port::new(CONFIG_DATA);
-
-	// Construct the address packet
-	let address = (bus as u32) << 16
+	(bus as u32) << 16
 		| (device as u32) << 11
 		| (function as u32) << 8
 		| (offset as u32 & 0xFC) // align to 4 bytes
-		| 0x80000000; // Enable bit
+		| 0x80000000 // Enable bit
+}
+
+/// where a byte or word at `offset` shows up in CONFIG_DATA's four ports
+fn data_port(offset: u8) -> u16 {
+	CONFIG_DATA + (offset & 3) as u16
+}
+
+/// how far `offset`'s bytes are shifted up within their dword
+fn field_shift(offset: u8) -> u32 {
+	(offset & 3) as u32 * 8
+}
+
+fn extract_u8(
+	dword: u32,
+	offset: u8,
+) -> u8 {
+	(dword >> field_shift(offset)) as u8
+}
+
+fn extract_u16(
+	dword: u32,
+	offset: u8,
+) -> u16 {
+	(dword >> field_shift(offset)) as u16
+}
+
+/// Reads the dword holding `offset` straight from the ports
+///
+/// Address and data are two accesses, interrupts stay off in between so nobody moves the address.
+fn read_dword_uncached(
+	device_function: DeviceFunction,
+	offset: u8,
+) -> u32 {
+	let DeviceFunction { bus, device, function } = device_function;
+	let config = config_ports();
+
+	x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+		config.write(CONFIG_ADDRESS, config_address(bus, device, function, offset));
+		config.read(CONFIG_DATA)
+	})
+}
+
+/// Writes `value` with an access exactly as wide as `T`, then drops the function's cached header
+///
+/// CONFIG_DATA takes byte and word accesses at its offsets, the byte enables make sure only the
+/// addressed bytes of the register change. No read-modify-write, so a 16 bit write to the command
+/// register leaves the status register's write-1-to-clear bits alone.
+unsafe fn write_sized<T: PortWrite>(
+	device_function: DeviceFunction,
+	offset: u8,
+	value: T,
+) {
+	let DeviceFunction { bus, device, function } = device_function;
+	let config = config_ports();
+
+	x86_64::instructions::interrupts::without_interrupts(|| {
+		unsafe {
+			config.write(CONFIG_ADDRESS, config_address(bus, device, function, offset));
+			config.write(data_port(offset), value);
+		}
+		invalidate(device_function);
+	});
+}
+
+/// cached bytes at the start of a function's config space, the common header
+const CACHED_HEADER_BYTES: u8 = 64;
+
+/// how many functions the header cache remembers at once
+const CACHE_SLOTS: usize = 32;
+
+/// the command and status dword, status bits change under us so it's never served from the cache
+const COMMAND_STATUS_DWORD: u8 = 0x04;
+
+#[derive(Clone, Copy)]
+struct CachedHeader {
+	/// bus, device and function of the header
+	key: (u8, u8, u8),
+	dwords: [u32; CACHED_HEADER_BYTES as usize / 4],
+}
+
+/// Headers of present functions, filled the first time something reads them
+///
+/// Any write to a function drops its entry, there's no telling what the write changed.
+static HEADER_CACHE: Mutex<[Option<CachedHeader>; CACHE_SLOTS]> = Mutex::new([None; CACHE_SLOTS]);
+
+/// the slot the next new header replaces once the cache is full
+static NEXT_VICTIM: AtomicUsize = AtomicUsize::new(0);
+
+fn cache_key(device_function: DeviceFunction) -> (u8, u8, u8) {
+	(device_function.bus, device_function.device, device_function.function)
+}
+
+/// drops `device_function`'s cached header, if there is one
+fn invalidate(device_function: DeviceFunction) {
+	let key = cache_key(device_function);
+	for slot in HEADER_CACHE.lock().iter_mut() {
+		if slot.map_or(false, |header| header.key == key) {
+			*slot = None;
+		}
+	}
+}
+
+/// true if `device_function`'s header is being served from the cache
+fn is_cached(device_function: DeviceFunction) -> bool {
+	let key = cache_key(device_function);
+	HEADER_CACHE.lock().iter().flatten().any(|header| header.key == key)
+}
+
+/// The dword holding `offset`, from the cache for the header of a present function
+fn read_dword(
+	device_function: DeviceFunction,
+	offset: u8,
+) -> u32 {
+	let dword = offset & 0xFC;
+	if dword >= CACHED_HEADER_BYTES || dword == COMMAND_STATUS_DWORD {
+		return read_dword_uncached(device_function, offset);
+	}
+
+	let key = cache_key(device_function);
+	x86_64::instructions::interrupts::without_interrupts(|| {
+		let mut cache = HEADER_CACHE.lock();
+		if let Some(header) = cache.iter().flatten().find(|header| header.key == key) {
+			return header.dwords[dword as usize / 4];
+		}
+
+		// nothing there, don't remember a header full of ones
+		let id = read_dword_uncached(device_function, 0x00);
+		if split_ids(id).is_none() {
+			return if dword == 0 { id } else { read_dword_uncached(device_function, offset) };
+		}
+
+		let mut header = CachedHeader { key, dwords: [0; CACHED_HEADER_BYTES as usize / 4] };
+		header.dwords[0] = id;
+		for (i, value) in header.dwords.iter_mut().enumerate().skip(1) {
+			*value = read_dword_uncached(device_function, i as u8 * 4);
+		}
+
+		let slot = match cache.iter().position(|slot| slot.is_none()) {
+			Some(slot) => slot,
+			None => NEXT_VICTIM.fetch_add(1, Ordering::Relaxed) % CACHE_SLOTS,
+		};
+		cache[slot] = Some(header);
+
+		header.dwords[dword as usize / 4]
+	})
+}
+
+/// Reads the config space byte at `offset`
+pub fn read_config_u8(
+	device_function: DeviceFunction,
+	offset: u8,
+) -> u8 {
+	extract_u8(read_dword(device_function, offset), offset)
+}
+
+/// Reads the config space word at `offset`, which has to be 2 byte aligned
+pub fn read_config_u16(
+	device_function: DeviceFunction,
+	offset: u8,
+) -> u16 {
+	assert!(offset % 2 == 0, "unaligned 16 bit config read at {:#04x}", offset);
+	extract_u16(read_dword(device_function, offset), offset)
+}
+
+/// Reads the config space dword at `offset`, which has to be 4 byte aligned
+pub fn read_config_u32(
+	device_function: DeviceFunction,
+	offset: u8,
+) -> u32 {
+	assert!(offset % 4 == 0, "unaligned 32 bit config read at {:#04x}", offset);
+	read_dword(device_function, offset)
+}
+
+/// Writes the config space byte at `offset` and nothing else
+///
+/// Unsafe because it reconfigures the device behind its driver's back.
+pub unsafe fn write_config_u8(
+	device_function: DeviceFunction,
+	offset: u8,
+	value: u8,
+) {
+	unsafe { write_sized(device_function, offset, value) }
+}
+
+/// Writes the config space word at `offset`, which has to be 2 byte aligned
+///
+/// Unsafe because it reconfigures the device behind its driver's back.
+pub unsafe fn write_config_u16(
+	device_function: DeviceFunction,
+	offset: u8,
+	value: u16,
+) {
+	assert!(offset % 2 == 0, "unaligned 16 bit config write at {:#04x}", offset);
+	unsafe { write_sized(device_function, offset, value) }
+}
+
+/// Writes the config space dword at `offset`, which has to be 4 byte aligned
+///
+/// A dword write at 0x04 writes the status register too, set only the status bits to clear.
+/// Unsafe because it reconfigures the device behind its driver's back.
+pub unsafe fn write_config_u32(
+	device_function: DeviceFunction,
+	offset: u8,
+	value: u32,
+) {
+	assert!(offset % 4 == 0, "unaligned 32 bit config write at {:#04x}", offset);
+	unsafe { write_sized(device_function, offset, value) }
+}
+
+/// Maps a vendor/device ID pair to a human-readable name
+///
+/// Only covers the devices we expect to see under QEMU, anything else is "Unknown"
+pub const fn pci_device_name(
+	vendor: u16,
+	device: u16,
+) -> &'static str {
+	match (vendor, device) {
+		(0x1AF4, 0x1000) => "VirtIO Legacy Network",
+		(0x1AF4, 0x1001) => "VirtIO Legacy Block",
+		(0x1AF4, 0x1041) => "VirtIO Network",
+		(0x1AF4, 0x1042) => "VirtIO Block",
+		(0x8086, 0x100E) => "Intel e1000",
+		_ => "Unknown",
+	}
+}
+
+/// Vendor ID assigned to VirtIO devices (Red Hat)
+const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+
+/// the header type register
+const HEADER_TYPE: u8 = 0x0E;
+
+/// bit 7 of the header type marks a multi-function device
+const MULTI_FUNCTION_BIT: u8 = 1 << 7;
+
+/// true if the device at function 0 reports itself as multi-function
+fn is_multi_function(
+	bus: u8,
+	device: u8,
+) -> bool {
+	let header_type = read_config_u8(DeviceFunction { bus, device, function: 0 }, HEADER_TYPE);
+	header_type & MULTI_FUNCTION_BIT != 0
+}
+
+/// what the vendor id of a missing function reads as, config reads of nothing return all ones
+const ABSENT_VENDOR_ID: u16 = 0xFFFF;
+
+/// false for a vendor id that means nothing answered the config read
+fn is_present(vendor_id: u16) -> bool {
+	vendor_id != ABSENT_VENDOR_ID
+}
+
+/// splits the id dword at offset 0 into (vendor, device), None if the function doesn't exist
+fn split_ids(id: u32) -> Option<(u16, u16)> {
+	let vendor_id = (id & 0xFFFF) as u16;
+	if !is_present(vendor_id) {
+		return None;
+	}
+	Some((vendor_id, (id >> 16) as u16))
+}
+
+/// returns (vendor, device) of a function, or None if the function doesn't exist
+fn probe_function(
+	bus: u8,
+	device: u8,
+	function: u8,
+) -> Option<(u16, u16)> {
+	split_ids(read_config_u32(DeviceFunction { bus, device, function }, 0x00))
+}
+
+/// the interrupt line register
+const INTERRUPT_LINE: u8 = 0x3C;
 
-	address_port.write(address);
-	data_port.read()
+/// Returns the legacy INTx line the firmware routed a function to
+///
+/// 0xFF means the function isn't connected to the PIC
+pub fn interrupt_line(device_function: DeviceFunction) -> u8 {
+	read_config_u8(device_function, INTERRUPT_LINE)
+}
+
+/// the interrupt pin register
+const INTERRUPT_PIN: u8 = 0x3D;
+
+/// Returns which INTx pin a function interrupts on, 1 for INTA# up to 4 for INTD#
+///
+/// None if it has no pin, a function like that can only use MSI or MSI-X.
+pub fn interrupt_pin(device_function: DeviceFunction) -> Option<u8> {
+	match read_config_u8(device_function, INTERRUPT_PIN) {
+		pin @ 1..=4 => Some(pin),
+		_ => None,
+	}
 }
 
-/// Scans the PCI bus for a VirtIO device using the correct `enumerate_bus` method.
+/// buses below this are scanned by `scan`, QEMU's default topology doesn't go past bus 0
+pub const DEFAULT_MAX_BUS: u16 = 8;
+
+/// Scans the first `DEFAULT_MAX_BUS` buses for a VirtIO device
 pub fn scan(root: &mut PciRoot<PciConfigIo>) -> Option<DeviceFunction> {
-	println!("[PCI] Scanning for devices...");
-	for bus_num in 0..=255 {
-		for (device_func, header) in root.enumerate_bus(bus_num) {
-			println!(
-				"  - Found device on bus {}, device {} -> Vendor={:?}, Device={:?}",
-				bus_num, device_func.device, header.vendor_id, header.device_id
-			);
-			if header.vendor_id == 0x1AF4 {
+	scan_buses(root, DEFAULT_MAX_BUS)
+}
+
+/// Scans buses `0..max_bus` for a VirtIO device using the correct `enumerate_bus` method.
+///
+/// Multi-function devices get every function probed, the first VirtIO function found is
+/// returned and any other functions of that device are logged.
+pub fn scan_buses(
+	root: &mut PciRoot<PciConfigIo>,
+	max_bus: u16,
+) -> Option<DeviceFunction> {
+	println!("[PCI] Scanning buses 0..{} for devices...", max_bus.min(BUS_COUNT));
+	for_each_bus(max_bus, |bus_num| scan_bus(root, bus_num))
+}
+
+/// there are only 256 buses, whatever bound the caller picks
+const BUS_COUNT: u16 = 256;
+
+/// Calls `f` for buses `0..max_bus` until it returns something
+fn for_each_bus<T>(
+	max_bus: u16,
+	mut f: impl FnMut(u8) -> Option<T>,
+) -> Option<T> {
+	(0..max_bus.min(BUS_COUNT)).find_map(|bus| f(bus as u8))
+}
+
+/// Looks for a VirtIO function on a single bus
+fn scan_bus(
+	root: &mut PciRoot<PciConfigIo>,
+	bus_num: u8,
+) -> Option<DeviceFunction> {
+	for (device_func, header) in root.enumerate_bus(bus_num) {
+		// we probe the functions ourselves below, so only look at each device once
+		if device_func.function != 0 {
+			continue;
+		}
+
+		// a phantom device, don't log it or probe its functions
+		if !is_present(header.vendor_id) {
+			continue;
+		}
+
+		println!(
+			"  - Found device on bus {}, device {} -> {} (Vendor={:#06x}, Device={:#06x})",
+			bus_num,
+			device_func.device,
+			pci_device_name(header.vendor_id, header.device_id),
+			header.vendor_id,
+			header.device_id
+		);
+
+		let function_count = if is_multi_function(bus_num, device_func.device) { 8 } else { 1 };
+
+		let mut virtio_function = None;
+		for function in 0..function_count {
+			let (vendor_id, device_id) = match probe_function(bus_num, device_func.device, function)
+			{
+				Some(ids) => ids,
+				None => continue,
+			};
+
+			if vendor_id == VIRTIO_VENDOR_ID && virtio_function.is_none() {
 				// Vendor IDs assigned by RedHat
-				println!("6900 -> Found a VirtIO device!");
+				println!("6900 -> Found a VirtIO device! (function {})", function);
 
 				// Read BAR0 to find the MMIO base address.
 				// The lower bits of the BAR value have flags, so we mask them off.
@@ -48,18 +401,108 @@ pub fn scan(root: &mut PciRoot<PciConfigIo>) -> Option<DeviceFunction> {
 					None => return None, // or handle the missing BAR as needed
 				};
 				println!("    -> Device BAR0 (MMIO Physical Address): {:#x}", bar0);*/
-				let device_function =
-					DeviceFunction { bus: bus_num, device: device_func.device, function: 0 };
-
-				return Some(device_function);
+				virtio_function =
+					Some(DeviceFunction { bus: bus_num, device: device_func.device, function });
+			} else if function != 0 {
+				println!(
+					"    - function {} -> {} (Vendor={:#06x}, Device={:#06x})",
+					function,
+					pci_device_name(vendor_id, device_id),
+					vendor_id,
+					device_id
+				);
 			}
 		}
+
+		if virtio_function.is_some() {
+			return virtio_function;
+		}
 	}
 
-	// If we get here, no VirtIO device was found on any bus.
 	None
 }
 
+#[test_case]
+fn test_bus_loop_honors_bound() {
+	let mut visited = 0;
+	assert_eq!(
+		for_each_bus(DEFAULT_MAX_BUS, |_| -> Option<()> {
+			visited += 1;
+			None
+		}),
+		None
+	);
+	assert_eq!(visited, DEFAULT_MAX_BUS);
+
+	// stops as soon as something is found
+	let mut last = 0;
+	assert_eq!(
+		for_each_bus(DEFAULT_MAX_BUS, |bus| {
+			last = bus;
+			if bus == 3 { Some(bus) } else { None }
+		}),
+		Some(3)
+	);
+	assert_eq!(last, 3);
+
+	// can't go past the last bus
+	let mut visited = 0;
+	for_each_bus(1000, |_| -> Option<()> {
+		visited += 1;
+		None
+	});
+	assert_eq!(visited, BUS_COUNT);
+}
+
+#[test_case]
+fn test_absent_vendor_filtered() {
+	assert!(!is_present(0xFFFF));
+	assert!(is_present(VIRTIO_VENDOR_ID));
+
+	assert_eq!(split_ids(0xFFFF_FFFF), None);
+	// the device id doesn't matter, the vendor id alone says nothing is there
+	assert_eq!(split_ids(0x1042_FFFF), None);
+	assert_eq!(split_ids(0x1042_1AF4), Some((0x1AF4, 0x1042)));
+}
+
+#[test_case]
+fn test_sized_access_math() {
+	let dword = 0x4433_2211;
+
+	// every byte lane, and the port its byte or word access goes to
+	for offset in 0x40..0x44u8 {
+		assert_eq!(extract_u8(dword, offset), 0x11 * (offset - 0x40 + 1));
+		assert_eq!(data_port(offset), CONFIG_DATA + (offset - 0x40) as u16);
+		assert_eq!(field_shift(offset), (offset as u32 - 0x40) * 8);
+	}
+	assert_eq!(extract_u16(dword, 0x40), 0x2211);
+	assert_eq!(extract_u16(dword, 0x42), 0x4433);
+
+	// the dword address ignores the low bits, they pick the data port instead
+	for offset in 0x3C..0x40u8 {
+		assert_eq!(config_address(1, 2, 3, offset), 0x8001_133C);
+	}
+}
+
+#[test_case]
+fn test_header_cache_invalidated_by_writes() {
+	// the host bridge, always there under QEMU
+	let bridge = DeviceFunction { bus: 0, device: 0, function: 0 };
+	invalidate(bridge);
+
+	let id = read_config_u32(bridge, 0x00);
+	assert!(split_ids(id).is_some());
+	assert!(is_cached(bridge));
+	assert_eq!(read_config_u16(bridge, 0x02), (id >> 16) as u16);
+	assert_eq!(read_config_u32(bridge, 0x00), read_dword_uncached(bridge, 0x00));
+
+	// writing back what's there changes nothing but still drops the entry
+	let line = read_config_u8(bridge, INTERRUPT_LINE);
+	unsafe { write_config_u8(bridge, INTERRUPT_LINE, line) };
+	assert!(!is_cached(bridge));
+	assert_eq!(read_config_u8(bridge, INTERRUPT_LINE), line);
+}
+
 // In src/pci.rs
 
 /// An implementation of `ConfigurationAccess` that uses x86 I/O ports to access the
@@ -73,22 +516,7 @@ impl ConfigurationAccess for PciConfigIo {
 		device_function: DeviceFunction,
 		register_offset: u8,
 	) -> u32 {
-		let mut address_port = Port::new(0xCF8);
-		let mut data_port = Port::new(0xCFC);
-
-		let DeviceFunction { bus, device, function } = device_function;
-
-		// Construct the address packet
-		let address = (bus as u32) << 16
-			| (device as u32) << 11
-			| (function as u32) << 8
-			| (register_offset as u32 & 0xFC) // align to 4 bytes
-			| 0x80000000; // Enable bit
-
-		unsafe {
-			address_port.write(address);
-			data_port.read()
-		}
+		read_config_u32(device_function, register_offset & 0xFC)
 	}
 
 	fn write_word(
@@ -97,22 +525,7 @@ impl ConfigurationAccess for PciConfigIo {
 		register_offset: u8,
 		data: u32,
 	) {
-		let mut address_port = Port::new(0xCF8);
-		let mut data_port = Port::new(0xCFC);
-
-		let DeviceFunction { bus, device, function } = device_function;
-
-		// Construct the address packet
-		let address = (bus as u32) << 16
-			| (device as u32) << 11
-			| (function as u32) << 8
-			| (register_offset as u32 & 0xFC) // align to 4 bytes
-			| 0x80000000; // Enable bit
-
-		unsafe {
-			address_port.write(address);
-			data_port.write(data);
-		}
+		unsafe { write_config_u32(device_function, register_offset & 0xFC, data) }
 	}
 
 	unsafe fn unsafe_clone(&self) -> Self {
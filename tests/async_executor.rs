@@ -0,0 +1,130 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use blog_os::{
+	fs::simple_fs::FileSystem,
+	interrupts,
+	task::{channel, timer},
+	test_harness::TestEnv,
+};
+use core::future;
+
+#[test_case]
+fn ready_future_returns_its_output() {
+	let mut env = TestEnv::new();
+	assert_eq!(env.run_async(async { 40 + 2 }), Some(42));
+}
+
+#[test_case]
+fn sleep_finishes_within_budget() {
+	let mut env = TestEnv::new();
+	let start = interrupts::ticks();
+
+	let woke_at = env.run_async(async {
+		timer::sleep_ticks(3).await;
+		interrupts::ticks()
+	});
+
+	assert!(woke_at.expect("sleep didn't finish") >= start + 3);
+}
+
+#[test_case]
+fn pending_future_runs_out_of_budget() {
+	let mut env = TestEnv::new();
+	let start = interrupts::ticks();
+
+	assert_eq!(env.run_async_for(future::pending::<()>(), 2), None);
+	assert!(interrupts::ticks() >= start + 2);
+}
+
+/// a second task on the same executor feeds the one being waited for
+#[test_case]
+fn other_tasks_keep_running() {
+	use blog_os::task::Task;
+	use futures_util::stream::StreamExt;
+
+	let mut env = TestEnv::new();
+	let (sender, mut receiver) = channel::channel(4);
+
+	env.executor().spawn(Task::new(async move {
+		for i in 1..=5u32 {
+			sender.send(i).await.expect("receiver went away");
+		}
+	}));
+
+	let sum = env.run_async(async move {
+		let mut sum = 0;
+		while let Some(i) = receiver.next().await {
+			sum += i;
+		}
+		sum
+	});
+
+	assert_eq!(sum, Some(15));
+}
+
+/// both tasks yield between their two entries, so neither gets two in a row
+#[test_case]
+fn yield_now_lets_the_other_task_in() {
+	use alloc::{rc::Rc, vec::Vec};
+	use blog_os::task::{Task, yield_now};
+	use core::cell::RefCell;
+
+	let mut env = TestEnv::new();
+	let log = Rc::new(RefCell::new(Vec::new()));
+
+	let other = log.clone();
+	env.executor().spawn(Task::new(async move {
+		other.borrow_mut().push('a');
+		yield_now().await;
+		other.borrow_mut().push('a');
+	}));
+
+	let log = env.run_async(async move {
+		log.borrow_mut().push('b');
+		yield_now().await;
+		log.borrow_mut().push('b');
+		log.take()
+	});
+
+	let log = log.expect("yield never came back");
+	assert!(log.len() >= 3);
+	assert!(log.windows(2).all(|pair| pair[0] != pair[1]), "{:?}", log);
+}
+
+#[test_case]
+fn filesystem_from_the_harness() {
+	let mut env = TestEnv::new();
+	let fs = env.fs();
+
+	let handle = fs.create_file("harness").expect("create failed");
+	assert_eq!(fs.write_file(handle, b"async").expect("write failed"), 5);
+
+	let handle = fs.open_file("harness").expect("open failed");
+	let mut buffer = [0u8; 5];
+	assert_eq!(fs.read_file(handle, &mut buffer).expect("read failed"), 5);
+	assert_eq!(&buffer, b"async");
+}
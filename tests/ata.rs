@@ -0,0 +1,75 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use blog_os::{
+	ata::{self, AtaDrive, Channel, Drive},
+	fs::{block_dev::BlockDevice, layout::BLOCK_SIZE},
+};
+
+// QEMU attaches the test's boot image as the primary master, it's only ever read here
+
+fn boot_disk() -> AtaDrive {
+	AtaDrive::identify(Channel::Primary, Drive::Master).expect("no primary master")
+}
+
+#[test_case]
+fn probe_finds_the_boot_disk() {
+	let drives = ata::probe();
+	let first = drives.first().expect("no ATA disk found");
+	assert_eq!((first.channel(), first.drive()), (Channel::Primary, Drive::Master));
+
+	let mut disk = boot_disk();
+	assert!(disk.sectors() > 0);
+	assert_eq!(disk.capacity() as u64, disk.sectors());
+	assert!(disk.has_boot_signature().expect("reading sector 0 failed"));
+}
+
+#[test_case]
+fn multi_sector_reads_match_single_ones() {
+	let mut disk = boot_disk();
+
+	let mut both = [0u8; 2 * BLOCK_SIZE];
+	disk.read_blocks(0, &mut both).expect("read failed");
+
+	let mut one = [0u8; BLOCK_SIZE];
+	disk.read_blocks(1, &mut one).expect("read failed");
+	assert_eq!(&both[BLOCK_SIZE..], &one[..]);
+	disk.read_blocks(0, &mut one).expect("read failed");
+	assert_eq!(&both[..BLOCK_SIZE], &one[..]);
+}
+
+#[test_case]
+fn requests_past_the_end_fail() {
+	let mut disk = boot_disk();
+	let end = disk.sectors();
+
+	let mut buf = [0u8; 2 * BLOCK_SIZE];
+	assert!(disk.read_blocks(end, &mut buf[..BLOCK_SIZE]).is_err());
+	assert!(disk.read_blocks(end - 1, &mut buf).is_err());
+	assert!(disk.read_blocks(0, &mut buf[..100]).is_err());
+	// nothing got written, the checks come first
+	assert!(disk.write_blocks(end, &buf[..BLOCK_SIZE]).is_err());
+}
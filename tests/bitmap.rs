@@ -0,0 +1,115 @@
+// in tests/bitmap.rs
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::fs::layout::{Bitmap, BitmapError};
+use core::panic::PanicInfo;
+
+/// plain bit fiddling, no heap or interrupts needed
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn set_bit_zero() {
+	let mut bytes = [0u8; 4];
+	let mut bitmap = Bitmap::new(&mut bytes);
+
+	assert!(!bitmap.is_set(0));
+	assert_eq!(bitmap.set(0), Ok(()));
+	assert!(bitmap.is_set(0));
+	assert!(!bitmap.is_set(1));
+
+	assert_eq!(bytes[0], 0b1);
+}
+
+#[test_case]
+fn clear_bit_zero() {
+	let mut bytes = [0u8; 4];
+	let mut bitmap = Bitmap::new(&mut bytes);
+
+	bitmap.set(0).expect("set failed");
+	assert_eq!(bitmap.clear(0), Ok(()));
+	assert!(!bitmap.is_set(0));
+
+	assert_eq!(bytes, [0u8; 4]);
+}
+
+#[test_case]
+fn bits_past_the_first_byte() {
+	let mut bytes = [0u8; 4];
+	let mut bitmap = Bitmap::new(&mut bytes);
+
+	bitmap.set(13).expect("set failed");
+	assert!(bitmap.is_set(13));
+	assert!(!bitmap.is_set(12) && !bitmap.is_set(14));
+
+	assert_eq!(bytes[1], 1 << 5);
+}
+
+#[test_case]
+fn double_set_is_rejected() {
+	let mut bytes = [0u8; 4];
+	let mut bitmap = Bitmap::new(&mut bytes);
+
+	bitmap.set(9).expect("set failed");
+	assert_eq!(bitmap.set(9), Err(BitmapError::AlreadyAllocated));
+	assert!(bitmap.is_set(9));
+}
+
+#[test_case]
+fn double_clear_is_rejected() {
+	let mut bytes = [0u8; 4];
+	let mut bitmap = Bitmap::new(&mut bytes);
+
+	assert_eq!(bitmap.clear(9), Err(BitmapError::AlreadyCleared));
+
+	bitmap.set(9).expect("set failed");
+	bitmap.clear(9).expect("clear failed");
+	assert_eq!(bitmap.clear(9), Err(BitmapError::AlreadyCleared));
+}
+
+#[test_case]
+fn full_bitmap_has_no_free_bit() {
+	let mut bytes = [0xFFu8; 4];
+	let mut bitmap = Bitmap::new(&mut bytes);
+
+	assert_eq!(bitmap.find_and_set_first_free(), None);
+	assert_eq!(bytes, [0xFFu8; 4]);
+}
+
+#[test_case]
+fn first_free_in_the_last_byte() {
+	let mut bytes = [0xFFu8; 4];
+	bytes[3] = 0;
+	let mut bitmap = Bitmap::new(&mut bytes);
+
+	// the first bit of the last byte, and it's taken afterwards
+	assert_eq!(bitmap.find_and_set_first_free(), Some(24));
+	assert!(bitmap.is_set(24));
+	assert_eq!(bitmap.find_and_set_first_free(), Some(25));
+
+	assert_eq!(bytes[3], 0b11);
+}
+
+#[test_case]
+fn first_free_skips_used_bits() {
+	let mut bytes = [0b0000_0111u8, 0];
+	let mut bitmap = Bitmap::new(&mut bytes);
+
+	assert_eq!(bitmap.find_and_set_first_free(), Some(3));
+	bitmap.clear(1).expect("clear failed");
+	assert_eq!(bitmap.find_and_set_first_free(), Some(1));
+}
@@ -0,0 +1,398 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use alloc::{rc::Rc, vec};
+use blog_os::fs::{
+	block_cache::CachedDevice,
+	block_dev::{BlockDevice, MemBlockDevice},
+	layout::BLOCK_SIZE,
+	simple_fs::{FileHandler, FileSystem, FileSystemError, SFS},
+};
+use blog_os::serial_println;
+use blog_os::task::{Task, executor::Executor};
+use core::cell::{Cell, RefCell};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// small enough for the test heap, large enough for a few files
+const TEST_BLOCKS: usize = 64;
+
+/// MemBlockDevice that counts the write and flush requests reaching it
+struct CountingDevice {
+	inner: MemBlockDevice,
+	writes: u64,
+	flushes: u64,
+	/// `writes` at the latest flush
+	writes_at_flush: u64,
+}
+
+impl CountingDevice {
+	fn new() -> Self {
+		CountingDevice {
+			inner: MemBlockDevice::new(TEST_BLOCKS),
+			writes: 0,
+			flushes: 0,
+			writes_at_flush: 0,
+		}
+	}
+}
+
+impl BlockDevice for CountingDevice {
+	fn read_blocks(
+		&mut self,
+		block_id: u64,
+		buffer: &mut [u8],
+	) -> Result<(), FileSystemError> {
+		self.inner.read_blocks(block_id, buffer)
+	}
+
+	fn write_blocks(
+		&mut self,
+		block_id: u64,
+		buffer: &[u8],
+	) -> Result<(), FileSystemError> {
+		self.writes += 1;
+		self.inner.write_blocks(block_id, buffer)
+	}
+
+	fn capacity(&self) -> usize {
+		self.inner.capacity()
+	}
+
+	fn flush(&mut self) -> Result<(), FileSystemError> {
+		self.flushes += 1;
+		self.writes_at_flush = self.writes;
+		Ok(())
+	}
+}
+
+static NOW: AtomicU64 = AtomicU64::new(0);
+
+fn fake_ticks() -> u64 {
+	NOW.load(Ordering::Relaxed)
+}
+
+fn cached_fs() -> SFS<CachedDevice<MemBlockDevice>> {
+	let mut fs =
+		SFS::format(CachedDevice::new(MemBlockDevice::new(TEST_BLOCKS))).expect("format failed");
+	fs.init_root_directory().expect("root directory init failed");
+	fs
+}
+
+/// reads a single block straight from the device
+fn device_block(
+	device: &mut impl BlockDevice,
+	block: u64,
+) -> [u8; BLOCK_SIZE] {
+	let mut buf = [0u8; BLOCK_SIZE];
+	device.read_blocks(block, &mut buf).expect("device read failed");
+	buf
+}
+
+#[test_case]
+fn writes_wait_for_a_flush() {
+	let mut cache = CachedDevice::new(MemBlockDevice::new(TEST_BLOCKS));
+	cache.write_blocks(3, &[0xAB; BLOCK_SIZE]).expect("write failed");
+
+	assert_eq!(device_block(&mut cache, 3), [0xAB; BLOCK_SIZE]);
+	assert_eq!(cache.dirty_state().blocks, 1);
+
+	cache.write_back().expect("write back failed");
+	assert_eq!(cache.dirty_state().blocks, 0);
+
+	let mut device = cache.power_cut();
+	assert_eq!(device_block(&mut device, 3), [0xAB; BLOCK_SIZE]);
+}
+
+#[test_case]
+fn power_cut_loses_unflushed_writes() {
+	let mut cache = CachedDevice::new(MemBlockDevice::new(TEST_BLOCKS));
+	cache.write_blocks(3, &[0xAB; BLOCK_SIZE]).expect("write failed");
+
+	let mut device = cache.power_cut();
+	assert_eq!(device_block(&mut device, 3), [0u8; BLOCK_SIZE]);
+}
+
+#[test_case]
+fn adjacent_dirty_blocks_coalesce() {
+	let mut cache = CachedDevice::new(CountingDevice::new());
+
+	for &block in &[7, 5, 9, 6, 6] {
+		cache.write_blocks(block, &[block as u8; BLOCK_SIZE]).expect("write failed");
+	}
+	assert_eq!(cache.get_ref().writes, 0);
+
+	// 5..=7 in one request, 9 on its own
+	cache.write_back().expect("write back failed");
+	assert_eq!(cache.get_ref().writes, 2);
+	assert_eq!(cache.stats().device_writes, 2);
+
+	let mut device = cache.power_cut();
+	for &block in &[5, 6, 7, 9] {
+		assert_eq!(device_block(&mut device, block), [block as u8; BLOCK_SIZE]);
+	}
+}
+
+#[test_case]
+fn only_old_dirty_blocks_are_flushed() {
+	let mut cache = CachedDevice::new(CountingDevice::new());
+	cache.set_clock(fake_ticks);
+
+	NOW.store(100, Ordering::Relaxed);
+	cache.write_blocks(1, &[1; BLOCK_SIZE]).expect("write failed");
+	NOW.store(110, Ordering::Relaxed);
+	cache.write_blocks(2, &[2; BLOCK_SIZE]).expect("write failed");
+	// rewriting doesn't make a dirty block any younger
+	cache.write_blocks(1, &[3; BLOCK_SIZE]).expect("write failed");
+
+	NOW.store(115, Ordering::Relaxed);
+	let state = cache.dirty_state();
+	assert_eq!((state.blocks, state.oldest_age_ticks), (2, 15));
+
+	cache.flush_older_than(10).expect("flush failed");
+	assert_eq!(cache.get_ref().writes, 1);
+
+	let state = cache.dirty_state();
+	assert_eq!((state.blocks, state.oldest_age_ticks), (1, 5));
+
+	let mut device = cache.power_cut();
+	assert_eq!(device_block(&mut device, 1), [3; BLOCK_SIZE]);
+	assert_eq!(device_block(&mut device, 2), [0; BLOCK_SIZE]);
+}
+
+#[test_case]
+fn reads_see_dirty_blocks_inside_a_run() {
+	let mut cache = CachedDevice::new(MemBlockDevice::new(TEST_BLOCKS));
+	cache.write_blocks(10, &[1; BLOCK_SIZE * 3]).expect("write failed");
+	cache.write_back().expect("write back failed");
+	cache.write_blocks(11, &[2; BLOCK_SIZE]).expect("write failed");
+
+	let mut buf = [0u8; BLOCK_SIZE * 3];
+	cache.read_blocks(10, &mut buf).expect("read failed");
+
+	assert!(buf[..BLOCK_SIZE].iter().all(|&b| b == 1));
+	assert!(buf[BLOCK_SIZE..2 * BLOCK_SIZE].iter().all(|&b| b == 2));
+	assert!(buf[2 * BLOCK_SIZE..].iter().all(|&b| b == 1));
+}
+
+#[test_case]
+fn eviction_writes_out_a_full_cache() {
+	let mut cache = CachedDevice::with_capacity(MemBlockDevice::new(TEST_BLOCKS), 4);
+
+	for block in 0..6u64 {
+		cache.write_blocks(block, &[block as u8 + 1; BLOCK_SIZE]).expect("write failed");
+	}
+
+	for block in 0..6u64 {
+		assert_eq!(device_block(&mut cache, block), [block as u8 + 1; BLOCK_SIZE]);
+	}
+}
+
+/// one step of the crash test workload, false once there are no steps left
+fn run_step(
+	fs: &mut SFS<CachedDevice<MemBlockDevice>>,
+	step: usize,
+) -> bool {
+	let a = FileHandler(1);
+	let b = FileHandler(2);
+
+	match step {
+		0 => assert!(fs.create_file("a.txt").is_ok()),
+		1 => assert!(fs.create_file("b.txt").is_ok()),
+		2 => assert!(fs.write_file(a, &vec![b'a'; BLOCK_SIZE * 3]).is_ok()),
+		3 => assert!(fs.write_file(b, &vec![b'b'; BLOCK_SIZE * 2]).is_ok()),
+		// shrinking frees blocks
+		4 => assert!(fs.write_file(a, b"short").is_ok()),
+		5 => assert!(fs.create_file("c.txt").is_ok()),
+		6 => assert!(fs.write_file(b, &[]).is_ok()),
+		7 => assert!(fs.write_file(FileHandler(3), &vec![b'c'; BLOCK_SIZE * 4]).is_ok()),
+		_ => return false,
+	}
+	true
+}
+
+#[test_case]
+fn power_cut_after_any_step_passes_fsck() {
+	let mut cut = 0;
+
+	loop {
+		let mut fs = cached_fs();
+		let mut more = true;
+		for step in 0..cut {
+			more = run_step(&mut fs, step);
+		}
+
+		let device = fs.into_device().power_cut();
+		let mut fs = SFS::mount(device).expect("remount failed");
+		let report = fs.fsck().expect("fsck failed");
+		assert!(report.is_clean(), "power cut after {} steps: {:?}", cut, report.problems);
+
+		if !more {
+			break;
+		}
+		cut += 1;
+	}
+}
+
+#[test_case]
+fn fsync_survives_a_power_cut() {
+	let mut fs = cached_fs();
+	let handle = fs.create_file("keep.txt").expect("create failed");
+	fs.write_file(handle, b"still here").expect("write failed");
+	fs.fsync(handle).expect("fsync failed");
+
+	let fs = SFS::mount(fs.into_device().power_cut()).expect("remount failed");
+	let handle = fs.open_file("keep.txt").expect("file lost in the power cut");
+
+	let mut buf = [0u8; 16];
+	let len = fs.read_file(handle, &mut buf).expect("read failed");
+	assert_eq!(&buf[..len], b"still here");
+}
+
+#[test_case]
+fn stats_show_dirty_blocks() {
+	let mut fs = cached_fs();
+	assert_eq!(fs.stats().dirty_blocks, 0);
+
+	let handle = fs.create_file("a.txt").expect("create failed");
+	fs.write_file(handle, b"dirty").expect("write failed");
+	assert!(fs.stats().dirty_blocks > 0);
+
+	fs.device_mut().write_back().expect("write back failed");
+	assert_eq!(fs.stats().dirty_blocks, 0);
+}
+
+/// sets up the root directory and rewrites one small file `count` times
+fn small_writes<D: BlockDevice>(
+	fs: &mut SFS<D>,
+	count: usize,
+) -> Result<(), FileSystemError> {
+	fs.init_root_directory()?;
+	let handle = fs.create_file("log.txt").expect("create failed");
+
+	for i in 0..count {
+		let line = [b'0' + (i % 10) as u8; 64];
+		fs.write_file(handle, &line).expect("write failed");
+	}
+	Ok(())
+}
+
+#[test_case]
+fn cache_cuts_device_writes_for_small_writes() {
+	const WRITES: usize = 50;
+
+	let mut direct = SFS::format(CountingDevice::new()).expect("format failed");
+	let before = direct.device().writes;
+	small_writes(&mut direct, WRITES).expect("direct writes failed");
+	let direct_writes = direct.device().writes - before;
+
+	let mut cached = SFS::format(CachedDevice::new(CountingDevice::new())).expect("format failed");
+	let before = cached.device().get_ref().writes;
+	small_writes(&mut cached, WRITES).expect("cached writes failed");
+	let cached_device = cached.unmount().into_inner().expect("flush failed");
+	let cached_writes = cached_device.writes - before;
+
+	serial_println!(
+		"{} small writes: {} device writes direct, {} cached",
+		WRITES,
+		direct_writes,
+		cached_writes
+	);
+	assert!(cached_writes * 4 <= direct_writes);
+}
+
+/// a cached filesystem on a CountingDevice with one dirty file, and the file's handle
+fn dirty_counting_fs() -> (SFS<CachedDevice<CountingDevice>>, FileHandler) {
+	let mut fs = SFS::format(CachedDevice::new(CountingDevice::new())).expect("format failed");
+	fs.init_root_directory().expect("root directory init failed");
+
+	let handle = fs.create_file("durable.txt").expect("create failed");
+	fs.write_file(handle, b"flush me").expect("write failed");
+	assert!(fs.stats().dirty_blocks > 0);
+
+	(fs, handle)
+}
+
+#[test_case]
+fn fsync_flushes_the_device_once() {
+	let (mut fs, handle) = dirty_counting_fs();
+	let (writes, flushes) = (fs.device().get_ref().writes, fs.device().get_ref().flushes);
+
+	fs.fsync(handle).expect("fsync failed");
+
+	let cache = fs.device();
+	let device = cache.get_ref();
+	assert_eq!(device.flushes, flushes + 1);
+	// the dirty blocks went out first, nothing came after the flush
+	assert!(device.writes > writes);
+	assert_eq!(device.writes_at_flush, device.writes);
+}
+
+#[test_case]
+fn unmount_flushes_the_device_once() {
+	let (fs, _) = dirty_counting_fs();
+	let (writes, flushes) = (fs.device().get_ref().writes, fs.device().get_ref().flushes);
+
+	let cache = fs.unmount();
+
+	let device = cache.get_ref();
+	assert_eq!(device.flushes, flushes + 1);
+	assert!(device.writes > writes);
+	assert_eq!(device.writes_at_flush, device.writes);
+}
+
+#[test_case]
+fn eviction_write_wakes_waiters() {
+	let cache =
+		Rc::new(RefCell::new(CachedDevice::with_capacity(MemBlockDevice::new(TEST_BLOCKS), 2)));
+	cache.borrow_mut().write_blocks(5, &[5u8; BLOCK_SIZE]).expect("write failed");
+	let written = cache.borrow().written();
+
+	let done = Rc::new(Cell::new(false));
+	let mut executor = Executor::new();
+	{
+		let (cache, done) = (cache.clone(), done.clone());
+		executor.spawn(Task::new(async move {
+			written.wait_until(|| !cache.borrow().is_dirty(5)).await;
+			done.set(true);
+		}));
+	}
+	executor.run_polls(100);
+	assert!(!done.get());
+
+	// the cache is full of dirty blocks after this one, the next write evicts
+	cache.borrow_mut().write_blocks(6, &[6u8; BLOCK_SIZE]).expect("write failed");
+	executor.run_polls(100);
+	assert!(!done.get());
+	cache.borrow_mut().write_blocks(7, &[7u8; BLOCK_SIZE]).expect("write failed");
+
+	executor.run_polls(100);
+	assert!(done.get());
+
+	// the device has it, not just the cache
+	drop(executor);
+	let cache = Rc::try_unwrap(cache).ok().expect("cache still shared").into_inner();
+	assert_eq!(device_block(&mut cache.power_cut(), 5), [5u8; BLOCK_SIZE]);
+}
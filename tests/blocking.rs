@@ -0,0 +1,117 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use alloc::vec::Vec;
+use blog_os::{
+	task::{Priority, Task, blocking},
+	test_harness::TestEnv,
+};
+use core::{
+	future::Future,
+	pin::Pin,
+	sync::atomic::{AtomicBool, AtomicU64, Ordering},
+	task::{Context, Poll},
+};
+use spin::Mutex;
+
+/// Counts its polls and wakes itself until `stop` is set
+struct Counter {
+	count: &'static AtomicU64,
+	stop: &'static AtomicBool,
+}
+
+impl Future for Counter {
+	type Output = ();
+
+	fn poll(
+		self: Pin<&mut Self>,
+		cx: &mut Context,
+	) -> Poll<()> {
+		if self.stop.load(Ordering::Relaxed) {
+			return Poll::Ready(());
+		}
+		self.count.fetch_add(1, Ordering::Relaxed);
+		cx.waker().wake_by_ref();
+		Poll::Pending
+	}
+}
+
+#[test_case]
+fn chunks_interleave_with_other_tasks() {
+	static COUNT: AtomicU64 = AtomicU64::new(0);
+	static STOP: AtomicBool = AtomicBool::new(false);
+
+	let mut env = TestEnv::new();
+	env.executor().spawn(Task::with_priority(Priority::LOW, blocking::worker()));
+	env.executor()
+		.spawn(Task::with_priority(Priority::LOW, Counter { count: &COUNT, stop: &STOP }));
+
+	// every chunk notes which chunk it was and how far the counter had got
+	let results = env
+		.run_async(blocking::run_blocking_chunked(0..50u64, |i| (i, COUNT.load(Ordering::Relaxed))))
+		.expect("chunks didn't finish");
+	STOP.store(true, Ordering::Relaxed);
+
+	assert_eq!(results.len(), 50);
+	for (expected, &(i, _)) in results.iter().enumerate() {
+		assert_eq!(i, expected as u64);
+	}
+	for pair in results.windows(2) {
+		assert!(pair[1].1 > pair[0].1, "counter stood still between chunks: {:?}", pair);
+	}
+	assert_eq!(blocking::queued(), 0);
+}
+
+#[test_case]
+fn results_in_submission_order() {
+	static RAN: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+	let mut env = TestEnv::new();
+	env.executor().spawn(Task::with_priority(Priority::LOW, blocking::worker()));
+
+	let outputs = env
+		.run_async(async {
+			let first = blocking::run_blocking(|| {
+				RAN.lock().push(1);
+				10
+			});
+			let second = blocking::run_blocking(|| {
+				RAN.lock().push(2);
+				20
+			});
+			let third = blocking::run_blocking(|| {
+				RAN.lock().push(3);
+				30
+			});
+
+			// awaited backwards, still ran in the order they were queued
+			(third.await, second.await, first.await)
+		})
+		.expect("jobs didn't finish");
+
+	assert_eq!(outputs, (30, 20, 10));
+	assert_eq!(core::mem::take(&mut *RAN.lock()), [1, 2, 3]);
+}
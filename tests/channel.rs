@@ -0,0 +1,252 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use alloc::{rc::Rc, sync::Arc, task::Wake, vec::Vec};
+use blog_os::task::{
+	Task,
+	channel::{self, SendError, TrySendError},
+	executor::Executor,
+};
+use core::{
+	cell::RefCell,
+	pin::Pin,
+	sync::atomic::{AtomicUsize, Ordering},
+	task::{Context, Poll, Waker},
+};
+use futures_util::stream::{Stream, StreamExt};
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// enough polls for every test here to run until nothing is ready anymore
+const MAX_POLLS: usize = 10_000;
+
+#[test_case]
+fn items_arrive_in_order() {
+	const N: u32 = 64;
+
+	let (sender, mut receiver) = channel::channel(N as usize);
+	for i in 0..N {
+		sender.try_send(i).unwrap();
+	}
+	// one past the capacity is refused, not dropped silently
+	assert_eq!(sender.try_send(N), Err(TrySendError::Full(N)));
+	drop(sender);
+
+	let received = Rc::new(RefCell::new(Vec::new()));
+	let out = received.clone();
+
+	let mut executor = Executor::new();
+	executor.spawn(Task::new(async move {
+		while let Some(i) = receiver.next().await {
+			out.borrow_mut().push(i);
+		}
+	}));
+	executor.run_polls(MAX_POLLS);
+
+	assert_eq!(*received.borrow(), (0..N).collect::<Vec<u32>>());
+}
+
+#[test_case]
+fn full_channel_parks_sender() {
+	let (sender, mut receiver) = channel::channel(2);
+	let sent = Rc::new(RefCell::new(0));
+	let received = Rc::new(RefCell::new(Vec::new()));
+
+	let mut executor = Executor::new();
+	let counter = sent.clone();
+	executor.spawn(Task::new(async move {
+		for i in 0..10u32 {
+			sender.send(i).await.unwrap();
+			*counter.borrow_mut() += 1;
+		}
+	}));
+	executor.run_polls(MAX_POLLS);
+
+	// nobody is receiving, so the third send has to wait
+	assert_eq!(*sent.borrow(), 2);
+
+	let out = received.clone();
+	executor.spawn(Task::new(async move {
+		while let Some(i) = receiver.next().await {
+			out.borrow_mut().push(i);
+		}
+	}));
+	executor.run_polls(MAX_POLLS);
+
+	assert_eq!(*sent.borrow(), 10);
+	assert_eq!(*received.borrow(), (0..10).collect::<Vec<u32>>());
+}
+
+#[test_case]
+fn producers_interleave() {
+	let (sender, mut receiver) = channel::channel(1);
+	let received = Rc::new(RefCell::new(Vec::new()));
+
+	let mut executor = Executor::new();
+	for producer in 0..3u32 {
+		let sender = sender.clone();
+		executor.spawn(Task::new(async move {
+			for i in 0..5u32 {
+				sender.send((producer, i)).await.unwrap();
+			}
+		}));
+	}
+	// only the clones keep the stream open
+	drop(sender);
+
+	let out = received.clone();
+	executor.spawn(Task::new(async move {
+		while let Some(item) = receiver.next().await {
+			out.borrow_mut().push(item);
+		}
+	}));
+	executor.run_polls(MAX_POLLS);
+
+	let received = received.borrow();
+	assert_eq!(received.len(), 15);
+
+	// every producer's items arrive in the order it sent them
+	for producer in 0..3 {
+		let mine: Vec<u32> =
+			received.iter().filter(|(p, _)| *p == producer).map(|(_, i)| *i).collect();
+		assert_eq!(mine, (0..5).collect::<Vec<u32>>());
+	}
+}
+
+#[test_case]
+fn stream_ends_when_senders_dropped() {
+	let (sender, mut receiver) = channel::channel(4);
+	let second = sender.clone();
+
+	sender.try_send(1u8).unwrap();
+	drop(sender);
+	second.try_send(2).unwrap();
+	drop(second);
+
+	let received = Rc::new(RefCell::new(Vec::new()));
+	let out = received.clone();
+
+	let mut executor = Executor::new();
+	executor.spawn(Task::new(async move {
+		while let Some(item) = receiver.next().await {
+			out.borrow_mut().push(item);
+		}
+		// the stream is over, it stays over
+		assert_eq!(receiver.next().await, None);
+		out.borrow_mut().push(0);
+	}));
+	executor.run_polls(MAX_POLLS);
+
+	// what was sent before the drop still comes out
+	assert_eq!(*received.borrow(), [1, 2, 0]);
+}
+
+#[test_case]
+fn send_fails_without_receiver() {
+	let (sender, receiver) = channel::channel(4);
+	drop(receiver);
+
+	assert_eq!(sender.try_send(7u8), Err(TrySendError::Closed(7)));
+
+	let result = Rc::new(RefCell::new(None));
+	let out = result.clone();
+
+	let mut executor = Executor::new();
+	executor.spawn(Task::new(async move {
+		*out.borrow_mut() = Some(sender.send(8).await);
+	}));
+	executor.run_polls(MAX_POLLS);
+
+	assert_eq!(*result.borrow(), Some(Err(SendError(8))));
+}
+
+#[test_case]
+fn parked_sender_sees_receiver_drop() {
+	let (sender, receiver) = channel::channel(1);
+	sender.try_send(1u8).unwrap();
+
+	let result = Rc::new(RefCell::new(None));
+	let out = result.clone();
+
+	let mut executor = Executor::new();
+	executor.spawn(Task::new(async move {
+		*out.borrow_mut() = Some(sender.send(2).await);
+	}));
+	executor.run_polls(MAX_POLLS);
+	assert_eq!(*result.borrow(), None);
+
+	drop(receiver);
+	executor.run_polls(MAX_POLLS);
+	assert_eq!(*result.borrow(), Some(Err(SendError(2))));
+}
+
+/// counts how often it was woken
+struct CountingWaker(AtomicUsize);
+
+impl Wake for CountingWaker {
+	fn wake(self: Arc<Self>) {
+		self.0.fetch_add(1, Ordering::Relaxed);
+	}
+}
+
+#[test_case]
+fn try_send_from_interrupt_context() {
+	let (sender, mut receiver) = channel::channel(2);
+	let wakes = Arc::new(CountingWaker(AtomicUsize::new(0)));
+	let waker = Waker::from(wakes.clone());
+	let mut cx = Context::from_waker(&waker);
+
+	// the receiver parks with nothing to read
+	assert_eq!(Pin::new(&mut receiver).poll_next(&mut cx), Poll::Pending);
+
+	// what a handler does: never wait, hand the item back when there's no room
+	without_interrupts(|| {
+		assert_eq!(sender.try_send(1u8), Ok(()));
+		assert_eq!(sender.try_send(2), Ok(()));
+		assert_eq!(sender.try_send(3), Err(TrySendError::Full(3)));
+	});
+
+	assert!(wakes.0.load(Ordering::Relaxed) >= 1);
+	assert_eq!(Pin::new(&mut receiver).poll_next(&mut cx), Poll::Ready(Some(1)));
+	assert_eq!(Pin::new(&mut receiver).poll_next(&mut cx), Poll::Ready(Some(2)));
+	assert_eq!(Pin::new(&mut receiver).poll_next(&mut cx), Poll::Pending);
+}
+
+#[test_case]
+fn take_back_empties_from_the_sending_side() {
+	let (sender, receiver) = channel::channel(3);
+	for i in 0..3u32 {
+		sender.try_send(i).unwrap();
+	}
+
+	// oldest first, and the room is there again right away
+	assert_eq!(sender.take_back(), Some(0));
+	assert_eq!(sender.try_send(3), Ok(()));
+	assert_eq!(sender.take_back(), Some(1));
+	assert_eq!(receiver.try_recv(), Some(2));
+	assert_eq!(sender.take_back(), Some(3));
+	assert_eq!(sender.take_back(), None);
+	assert_eq!(receiver.try_recv(), None);
+}
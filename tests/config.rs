@@ -0,0 +1,212 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+	// subscribers stay for good, so before the first test and not in it
+	config::subscribe("alpha.", accept, apply_alpha);
+	config::subscribe("beta.", check_number, apply_beta);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use alloc::{format, string::String, vec::Vec};
+use blog_os::{
+	assert_err, assert_ok,
+	config::{self, CONFIG_FILE, ConfigError},
+	fs::{
+		block_dev::MemBlockDevice,
+		simple_fs::{FileError, FileSystem, SFS},
+	},
+};
+use spin::Mutex;
+
+/// what the subscribers below were handed, `key=value` in the order they got it
+static ALPHA: Mutex<Vec<String>> = Mutex::new(Vec::new());
+static BETA: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+fn accept(
+	_fs: &dyn FileSystem,
+	_key: &str,
+	_value: &str,
+) -> Result<(), &'static str> {
+	Ok(())
+}
+
+/// numbers only
+fn check_number(
+	_fs: &dyn FileSystem,
+	_key: &str,
+	value: &str,
+) -> Result<(), &'static str> {
+	value.parse::<u32>().map(|_| ()).map_err(|_| "not a number")
+}
+
+fn apply_alpha(
+	_fs: &dyn FileSystem,
+	key: &str,
+	value: &str,
+) {
+	ALPHA.lock().push(format!("{}={}", key, value));
+}
+
+fn apply_beta(
+	_fs: &dyn FileSystem,
+	key: &str,
+	value: &str,
+) {
+	BETA.lock().push(format!("{}={}", key, value));
+}
+
+/// takes what the subscribers got since the last call
+fn fired() -> (Vec<String>, Vec<String>) {
+	(core::mem::take(&mut *ALPHA.lock()), core::mem::take(&mut *BETA.lock()))
+}
+
+/// a filesystem with `text` in kernel.cfg and the file loaded
+fn fs_with_config(text: &str) -> SFS<MemBlockDevice> {
+	let mut fs = assert_ok!(SFS::format(MemBlockDevice::new(64)));
+	assert_ok!(fs.init_root_directory());
+	write_config(&mut fs, text);
+	// whatever an earlier test left loaded, this file is the baseline now
+	let _ = config::reload(&fs);
+	fired();
+	fs
+}
+
+/// Loads a file without settings, so the ones a test loaded don't stay on the heap
+///
+/// Nothing fires, keys taken out of the file aren't changes.
+fn unload(mut fs: SFS<MemBlockDevice>) {
+	write_config(&mut fs, "# nothing\n");
+	assert_ok!(config::reload(&fs));
+	assert_eq!(fired(), (Vec::new(), Vec::new()));
+}
+
+/// the in-kernel write path, what an edit of the file comes down to
+fn write_config(
+	fs: &mut SFS<MemBlockDevice>,
+	text: &str,
+) {
+	let handle = match fs.open_file(CONFIG_FILE) {
+		Err(FileError::FileNotFound) => assert_ok!(fs.create_file(CONFIG_FILE)),
+		found => assert_ok!(found),
+	};
+	assert_ok!(fs.write_file(handle, text.as_bytes()));
+}
+
+fn read_config(fs: &SFS<MemBlockDevice>) -> String {
+	let handle = assert_ok!(fs.open_file(CONFIG_FILE));
+	let mut buf = [0u8; 256];
+	let len = assert_ok!(fs.read_file(handle, &mut buf));
+	String::from(core::str::from_utf8(&buf[..len]).unwrap())
+}
+
+#[test_case]
+fn only_affected_subscribers_fire() {
+	let mut fs = fs_with_config("alpha.one = 1\nbeta.two = 2\n");
+
+	write_config(&mut fs, "alpha.one = 1\nbeta.two = 3\n");
+	let report = assert_ok!(config::reload(&fs));
+	assert_eq!(report.applied, ["beta.two"]);
+	assert_eq!(fired(), (Vec::new(), ["beta.two=3"].map(String::from).to_vec()));
+	assert_eq!(config::get("beta.two").as_deref(), Some("3"));
+
+	// nothing changed, nobody hears about it
+	let report = assert_ok!(config::reload(&fs));
+	assert!(report.is_empty());
+	assert_eq!(fired(), (Vec::new(), Vec::new()));
+	unload(fs);
+}
+
+#[test_case]
+fn invalid_edits_apply_nothing() {
+	let mut fs = fs_with_config("alpha.one = 1\nbeta.two = 2\n");
+
+	// alpha.one on its own would be fine
+	write_config(&mut fs, "alpha.one = 5\nbeta.two = many\n");
+	let rejected = assert_err!(config::reload(&fs), ConfigError::Rejected { .. });
+	assert_eq!(
+		rejected,
+		ConfigError::Rejected { key: String::from("beta.two"), line: 2, reason: "not a number" }
+	);
+	assert_eq!(fired(), (Vec::new(), Vec::new()));
+	assert_eq!(config::get("alpha.one").as_deref(), Some("1"));
+
+	write_config(&mut fs, "alpha.one = 5\nbeta.two\n");
+	assert_err!(config::reload(&fs), ConfigError::Syntax { line: 2 });
+	assert_eq!(fired(), (Vec::new(), Vec::new()));
+
+	// fixed, both changes go through
+	write_config(&mut fs, "alpha.one = 5\nbeta.two = 4\n");
+	assert_ok!(config::reload(&fs));
+	assert_eq!(
+		fired(),
+		(["alpha.one=5"].map(String::from).to_vec(), ["beta.two=4"].map(String::from).to_vec())
+	);
+	unload(fs);
+}
+
+#[test_case]
+fn boot_only_and_unknown_keys_are_reported() {
+	let mut fs = fs_with_config("heap.size = 100\n");
+
+	write_config(&mut fs, "heap.size = 200\ngamma.three = 3\n");
+	let report = assert_ok!(config::reload(&fs));
+	assert_eq!(report.needs_reboot, ["heap.size"]);
+	assert_eq!(report.ignored, ["gamma.three"]);
+	assert!(report.applied.is_empty());
+	assert_eq!(format!("{}", report), "heap.size needs a reboot; nobody uses gamma.three");
+	unload(fs);
+}
+
+#[test_case]
+fn check_notices_edits() {
+	let mut fs = fs_with_config("alpha.one = 1\n");
+	assert_eq!(assert_ok!(config::check(&fs)), None);
+
+	write_config(&mut fs, "alpha.one = 22\n");
+	let report = assert_ok!(config::check(&fs));
+	assert_eq!(report.map(|report| report.applied), Some(["alpha.one"].map(String::from).to_vec()));
+	assert_eq!(fired().0, ["alpha.one=22"]);
+	assert_eq!(assert_ok!(config::check(&fs)), None);
+	unload(fs);
+}
+
+#[test_case]
+fn set_writes_the_file_and_applies() {
+	let mut fs = fs_with_config("# settings\nbeta.two = 2\n");
+
+	let report = assert_ok!(config::set(&mut fs, "beta.two", "7"));
+	assert_eq!(report.applied, ["beta.two"]);
+	assert_eq!(fired().1, ["beta.two=7"]);
+	assert_eq!(read_config(&fs), "# settings\nbeta.two = 7\n");
+	// written and applied, the watcher has nothing left to do
+	assert_eq!(assert_ok!(config::check(&fs)), None);
+
+	assert_ok!(config::set(&mut fs, "alpha.new", "on"));
+	assert_eq!(read_config(&fs), "# settings\nbeta.two = 7\nalpha.new = on\n");
+
+	// turned down before it reaches the file
+	assert_err!(config::set(&mut fs, "beta.two", "lots"), ConfigError::Rejected { .. });
+	assert_eq!(read_config(&fs), "# settings\nbeta.two = 7\nalpha.new = on\n");
+	assert_eq!(fired(), (["alpha.new=on"].map(String::from).to_vec(), Vec::new()));
+	unload(fs);
+}
@@ -0,0 +1,98 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use alloc::boxed::Box;
+use blog_os::{
+	memory::{BootInfoFrameAllocator, translate_addr},
+	virtio::{DmaBuffer, FRAME_ALLOCATOR, OsHal, physical_memory_offset},
+};
+use bootloader::bootinfo::MemoryMap;
+use virtio_drivers::{BufferDirection, Hal, PAGE_SIZE};
+use x86_64::VirtAddr;
+
+/// Runs `f` with an allocator that has no frames at all in place of the global one
+fn with_exhausted_allocator(f: impl FnOnce()) {
+	// an empty memory map, allocate_frame fails right away
+	let empty = unsafe { BootInfoFrameAllocator::init(Box::leak(Box::new(MemoryMap::new()))) };
+	let original = FRAME_ALLOCATOR.lock().replace(empty);
+
+	f();
+
+	*FRAME_ALLOCATOR.lock() = original;
+}
+
+// the memory map is leaked to get a 'static one
+#[test_case]
+const DMA_ALLOC_RETURNS_ZERO_WHEN_OUT_OF_FRAMES: blog_os::MayLeak =
+	blog_os::may_leak!(dma_alloc_returns_zero_when_out_of_frames);
+
+fn dma_alloc_returns_zero_when_out_of_frames() {
+	with_exhausted_allocator(|| {
+		let (paddr, _) = OsHal::dma_alloc(1, BufferDirection::Both);
+		assert_eq!(paddr, 0);
+	});
+}
+
+#[test_case]
+const DMA_BUFFER_ALLOC_RETURNS_NONE_WHEN_OUT_OF_FRAMES: blog_os::MayLeak =
+	blog_os::may_leak!(dma_buffer_alloc_returns_none_when_out_of_frames);
+
+fn dma_buffer_alloc_returns_none_when_out_of_frames() {
+	with_exhausted_allocator(|| assert!(DmaBuffer::alloc(1).is_none()));
+
+	// and with the real allocator back it works again
+	let buffer = DmaBuffer::alloc(1).expect("allocation failed with the real allocator");
+	assert_ne!(buffer.phys_addr(), 0);
+	assert!(buffer.iter().all(|&b| b == 0));
+}
+
+#[test_case]
+fn dma_buffer_spans_contiguous_pages() {
+	const PAGES: usize = 3;
+
+	let mut buffer = DmaBuffer::alloc(PAGES).expect("no contiguous run of frames");
+	assert_eq!(buffer.pages(), PAGES);
+	assert_eq!(buffer.len(), PAGES * PAGE_SIZE);
+	assert!(buffer.iter().all(|&b| b == 0));
+
+	// every page sits right behind the one before it, as the device sees it
+	let offset = VirtAddr::new(physical_memory_offset());
+	for page in 0..PAGES {
+		let virt = VirtAddr::from_ptr(buffer[page * PAGE_SIZE..].as_ptr());
+		// the bootloader maps all of physical memory at the offset
+		let phys =
+			unsafe { translate_addr(virt, offset) }.phys_addr().expect("DMA page isn't mapped");
+		assert_eq!(phys.as_u64() as usize, buffer.phys_addr() + page * PAGE_SIZE);
+	}
+
+	buffer.fill(0xAB);
+	assert_eq!(buffer[PAGES * PAGE_SIZE - 1], 0xAB);
+}
+
+#[test_case]
+fn dma_buffer_of_zero_pages_is_none() {
+	assert!(DmaBuffer::alloc(0).is_none());
+}
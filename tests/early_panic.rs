@@ -0,0 +1,25 @@
+#![no_std]
+#![no_main]
+
+use blog_os::{QemuExitCode, early_print, early_println, exit::exit_qemu_or_halt};
+use core::panic::PanicInfo;
+
+/// With `panic-in-init`, `blog_os::init()` panics before SERIAL1 is up. The panic goes through the
+/// kernel's early path, which has to get the message out on its own and end the run with the
+/// KernelPanic code; scripts/expect_exit.sh checks both
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+	blog_os::early_serial::init();
+	early_print!("early_panic::panic_inside_init...\t");
+	blog_os::init();
+
+	early_println!("[failed]\n");
+	early_println!("Error: init returned, build with --features panic-in-init\n");
+	exit_qemu_or_halt(QemuExitCode::Failed);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	// what main.rs's panic handler does this early
+	blog_os::early_boot_panic(info, x86_64::registers::read_rip().as_u64());
+}
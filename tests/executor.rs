@@ -0,0 +1,345 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use blog_os::{
+	allocator::{self, Owner},
+	interrupts,
+	task::{
+		NUM_PRIORITIES, Priority, Task, TaskGroup,
+		executor::{Executor, ExecutorConfig},
+		yield_now,
+	},
+};
+use core::{
+	future::Future,
+	pin::Pin,
+	sync::atomic::{AtomicBool, AtomicU64, Ordering},
+	task::{Context, Poll},
+};
+
+/// A task that never finishes and wakes itself on every poll, counting the polls
+struct SelfWaking {
+	polls: &'static AtomicU64,
+}
+
+impl Future for SelfWaking {
+	type Output = ();
+
+	fn poll(
+		self: Pin<&mut Self>,
+		cx: &mut Context,
+	) -> Poll<()> {
+		self.polls.fetch_add(1, Ordering::Relaxed);
+		cx.waker().wake_by_ref();
+		Poll::Pending
+	}
+}
+
+#[test_case]
+fn higher_priority_polled_first() {
+	static HIGH: AtomicU64 = AtomicU64::new(0);
+	static LOW: AtomicU64 = AtomicU64::new(0);
+
+	let mut executor = Executor::new();
+	executor.spawn(Task::with_priority(Priority::LOW, SelfWaking { polls: &LOW }));
+	executor.spawn(Task::with_priority(Priority::HIGH, SelfWaking { polls: &HIGH }));
+
+	assert_eq!(executor.run_polls(1), 1);
+	assert_eq!(HIGH.load(Ordering::Relaxed), 1);
+	assert_eq!(LOW.load(Ordering::Relaxed), 0);
+}
+
+/// A high priority task that keeps itself ready must not starve a low priority one
+#[test_case]
+fn low_priority_not_starved() {
+	static HIGH: AtomicU64 = AtomicU64::new(0);
+	static LOW: AtomicU64 = AtomicU64::new(0);
+
+	const BURST: usize = 16;
+	const POLLS: usize = 1700;
+
+	// aging off, so only the burst limit is at work
+	let mut executor = Executor::with_config(ExecutorConfig { burst_limit: BURST, aging_ticks: 0 });
+	executor.spawn(Task::with_priority(Priority::HIGH, SelfWaking { polls: &HIGH }));
+	executor.spawn(Task::with_priority(Priority::LOW, SelfWaking { polls: &LOW }));
+
+	assert_eq!(executor.run_polls(POLLS), POLLS);
+
+	// one low poll per burst of high polls
+	let min_low = (POLLS / (BURST + 1)) as u64 - 1;
+	assert!(LOW.load(Ordering::Relaxed) >= min_low);
+	assert!(HIGH.load(Ordering::Relaxed) > LOW.load(Ordering::Relaxed));
+
+	let stats = executor.stats();
+	assert!(stats.polls_per_bucket[Priority::LOW.bucket()] >= min_low);
+	assert_eq!(
+		stats.polls_per_bucket[Priority::LOW.bucket()]
+			+ stats.polls_per_bucket[Priority::HIGH.bucket()],
+		POLLS as u64
+	);
+}
+
+#[test_case]
+fn priority_parses_names_and_numbers() {
+	assert_eq!("idle".parse(), Ok(Priority::IDLE));
+	assert_eq!("Low".parse(), Ok(Priority::LOW));
+	assert_eq!("NORMAL".parse(), Ok(Priority::NORMAL));
+	assert_eq!("high".parse(), Ok(Priority::HIGH));
+	assert_eq!("critical".parse(), Ok(Priority::CRITICAL));
+	assert_eq!("100".parse(), Ok(Priority::new(100)));
+	assert_eq!("255".parse(), Ok(Priority::CRITICAL));
+
+	for bad in ["", "256", "-1", "urgent", "high "].iter() {
+		assert!(bad.parse::<Priority>().is_err(), "{:?}", bad);
+	}
+}
+
+#[test_case]
+fn priority_clamps_and_buckets() {
+	assert_eq!(Priority::clamped(-5), Priority::IDLE);
+	assert_eq!(Priority::clamped(1000), Priority::CRITICAL);
+	assert_eq!(Priority::clamped(64), Priority::LOW);
+
+	assert_eq!(Priority::CRITICAL.boosted(1), Priority::CRITICAL);
+	assert_eq!(Priority::HIGH.boosted(4), Priority::CRITICAL);
+	assert_eq!(Priority::LOW.boosted(2), Priority::NORMAL);
+
+	// every named level in a bucket of its own
+	let buckets =
+		[Priority::IDLE, Priority::LOW, Priority::NORMAL, Priority::HIGH, Priority::CRITICAL]
+			.iter()
+			.map(|priority| priority.bucket())
+			.collect::<alloc::vec::Vec<_>>();
+	assert_eq!(buckets, [0, 2, 4, 6, 7]);
+	assert_eq!(Priority::new(255).bucket(), NUM_PRIORITIES - 1);
+}
+
+#[test_case]
+fn new_tasks_are_normal() {
+	static FIRST: AtomicU64 = AtomicU64::new(0);
+	static SECOND: AtomicU64 = AtomicU64::new(0);
+
+	let mut executor = Executor::with_config(ExecutorConfig { burst_limit: 16, aging_ticks: 0 });
+	executor.spawn(Task::new(SelfWaking { polls: &FIRST }));
+	executor.spawn(Task::new(SelfWaking { polls: &SECOND }));
+	assert!(executor.task_infos().iter().all(|info| info.priority == Priority::NORMAL));
+
+	// one bucket, so it's round robin like before there were priorities
+	assert_eq!(executor.run_polls(100), 100);
+	assert_eq!(FIRST.load(Ordering::Relaxed), 50);
+	assert_eq!(SECOND.load(Ordering::Relaxed), 50);
+	assert_eq!(executor.stats().polls_per_bucket[Priority::NORMAL.bucket()], 100);
+}
+
+#[test_case]
+fn set_priority_moves_a_queued_task() {
+	static LOW: AtomicU64 = AtomicU64::new(0);
+	static HIGH: AtomicU64 = AtomicU64::new(0);
+
+	let mut executor = Executor::with_config(ExecutorConfig { burst_limit: 16, aging_ticks: 0 });
+	let low = Task::with_priority(Priority::LOW, SelfWaking { polls: &LOW });
+	let low_id = low.id();
+	executor.spawn(low);
+	executor.spawn(Task::with_priority(Priority::HIGH, SelfWaking { polls: &HIGH }));
+
+	// the low task is waiting in its bucket after this one
+	assert_eq!(executor.run_polls(1), 1);
+	assert_eq!(HIGH.load(Ordering::Relaxed), 1);
+
+	assert!(executor.set_priority(low_id, Priority::CRITICAL));
+	assert_eq!(executor.run_polls(1), 1);
+	assert_eq!(LOW.load(Ordering::Relaxed), 1);
+	assert_eq!(HIGH.load(Ordering::Relaxed), 1);
+
+	let info = executor.task_infos().into_iter().find(|info| info.id == low_id).unwrap();
+	assert_eq!(info.priority, Priority::CRITICAL);
+	assert_eq!(info.dyn_priority, Priority::CRITICAL);
+
+	assert!(!executor.set_priority(u64::MAX, Priority::LOW));
+}
+
+#[test_case]
+fn dump_lists_every_priority() {
+	use alloc::string::String;
+	use blog_os::task::executor;
+
+	static COUNT: AtomicU64 = AtomicU64::new(0);
+
+	let mut executor = Executor::with_config(ExecutorConfig { burst_limit: 16, aging_ticks: 0 });
+	let mut expected = alloc::vec::Vec::new();
+	for &priority in &[Priority::LOW, Priority::NORMAL, Priority::HIGH] {
+		let task = Task::with_priority(priority, SelfWaking { polls: &COUNT });
+		expected.push((task.id(), priority));
+		executor.spawn(task);
+	}
+
+	let infos = executor.task_infos();
+	assert_eq!(infos.len(), 3);
+	for (info, &(id, priority)) in infos.iter().zip(&expected) {
+		assert_eq!(info.id, id);
+		assert_eq!(info.priority, priority);
+		assert_eq!(info.dyn_priority, priority);
+	}
+
+	let mut report = String::new();
+	executor::write_report(&mut report, &infos).unwrap();
+	assert!(report.starts_with("3 tasks\n┌"));
+	for &(id, priority) in expected.iter() {
+		let line = alloc::format!("│ {:>6} │ {:>4} │ {:>4} │\n", id, priority, priority);
+		assert!(report.contains(&line), "{} missing from\n{}", line, report);
+	}
+}
+
+/// Pending `left` more times, waking itself each time so the other tasks get a turn in between
+struct Yield {
+	left: u32,
+}
+
+impl Future for Yield {
+	type Output = ();
+
+	fn poll(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context,
+	) -> Poll<()> {
+		if self.left == 0 {
+			return Poll::Ready(());
+		}
+		self.left -= 1;
+		cx.waker().wake_by_ref();
+		Poll::Pending
+	}
+}
+
+#[test_case]
+fn join_all_waits_for_the_whole_group() {
+	static FINISHED: AtomicU64 = AtomicU64::new(0);
+	static JOINED_AFTER: AtomicU64 = AtomicU64::new(u64::MAX);
+
+	let mut executor = Executor::new();
+	let mut group = TaskGroup::new(&mut executor);
+	for i in 0..10 {
+		// every task takes a different number of rounds, the last one finishes well after the
+		// first
+		group.spawn(Priority::LOW, async move {
+			Yield { left: i * 3 }.await;
+			FINISHED.fetch_add(1, Ordering::Relaxed);
+		});
+	}
+	assert_eq!(group.task_ids().len(), 10);
+	let joined = group.join_all();
+
+	// polled ahead of the group, so it sees every task finish
+	executor.spawn(Task::with_priority(Priority::HIGH, async move {
+		joined.await;
+		JOINED_AFTER.store(FINISHED.load(Ordering::Relaxed), Ordering::Relaxed);
+	}));
+	executor.run_polls(10_000);
+
+	assert_eq!(FINISHED.load(Ordering::Relaxed), 10);
+	assert_eq!(JOINED_AFTER.load(Ordering::Relaxed), 10);
+}
+
+#[test_case]
+fn empty_group_joins_right_away() {
+	static JOINED: AtomicU64 = AtomicU64::new(0);
+
+	let mut executor = Executor::new();
+	let joined = TaskGroup::new(&mut executor).join_all();
+	executor.spawn(Task::new(async move {
+		joined.await;
+		JOINED.store(1, Ordering::Relaxed);
+	}));
+
+	assert_eq!(executor.run_polls(1), 1);
+	assert_eq!(JOINED.load(Ordering::Relaxed), 1);
+}
+
+/// allocates a buffer of each size and keeps them until `release` is set
+async fn hold(
+	sizes: &'static [usize],
+	release: &'static AtomicBool,
+) {
+	let held: Vec<Vec<u8>> = sizes.iter().map(|&size| vec![0u8; size]).collect();
+	while !release.load(Ordering::Relaxed) {
+		yield_now().await;
+	}
+	drop(held);
+}
+
+#[test_case]
+fn heap_is_charged_to_the_allocating_task() {
+	static RELEASE: AtomicBool = AtomicBool::new(false);
+
+	let mut executor = Executor::new();
+	// one allocation from the fallback heap, and four 256 byte blocks
+	let large = Task::new(hold(&[3000], &RELEASE));
+	let small = Task::new(hold(&[200; 4], &RELEASE));
+	let (large_id, small_id) = (large.id(), small.id());
+	executor.spawn(large);
+	executor.spawn(small);
+	assert_eq!(executor.run_polls(2), 2);
+
+	// each with the outer vec on top
+	let heap = allocator::heap_by_task();
+	let large_row = *heap.get(Owner::Task(large_id)).expect("no row for the large task");
+	let small_row = *heap.get(Owner::Task(small_id)).expect("no row for the small task");
+	assert!((3000..3000 + 64).contains(&large_row.bytes), "{:?}", large_row);
+	assert!((4 * 256..=4 * 256 + 128).contains(&small_row.bytes), "{:?}", small_row);
+	assert_eq!((large_row.allocations, small_row.allocations), (2, 5));
+
+	// done, and their rows with them
+	RELEASE.store(true, Ordering::Relaxed);
+	assert_eq!(executor.run_polls(2), 2);
+	let heap = allocator::heap_by_task();
+	assert_eq!(heap.get(Owner::Task(large_id)), None);
+	assert_eq!(heap.get(Owner::Task(small_id)), None);
+}
+
+/// what the handler below allocated
+static FROM_IRQ: spin::Mutex<Option<Box<[u8; 100]>>> = spin::Mutex::new(None);
+
+/// breaks the rule that interrupt handlers don't allocate
+fn allocating_irq_handler() {
+	*FROM_IRQ.lock() = Some(Box::new([0; 100]));
+}
+
+#[test_case]
+fn interrupt_allocations_go_to_the_irq_row() {
+	let irq_allocations =
+		|| allocator::heap_by_task().get(Owner::Irq).map_or(0, |row| row.allocations);
+	let before = irq_allocations();
+
+	// nothing is wired to IRQ 10 under test, `int 42` comes in on its vector
+	blog_os::assert_ok!(interrupts::register_irq_handler(10, allocating_irq_handler));
+	unsafe { core::arch::asm!("int 42") };
+	assert!(interrupts::unregister_irq_handler(10, allocating_irq_handler));
+
+	assert!(FROM_IRQ.lock().take().is_some());
+	assert_eq!(irq_allocations(), before + 1);
+	assert!(!interrupts::in_interrupt());
+}
@@ -0,0 +1,22 @@
+#![no_std]
+#![no_main]
+
+use blog_os::{QemuExitCode, exit::exit_qemu_or_halt, serial_print, serial_println};
+use core::panic::PanicInfo;
+
+/// Meant to also be booted without the isa-debug-exit device -- the runner then has to find the
+/// `[QEMU-EXIT] status=success` marker on serial instead of relying on the exit code, which is
+/// what scripts/check_exit_marker.sh does
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+	serial_print!("exit_marker::exit_without_device...\t");
+	blog_os::init();
+	serial_println!("[ok]");
+
+	exit_qemu_or_halt(QemuExitCode::Success);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
@@ -0,0 +1,99 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use blog_os::fb::{self, DOUBLE_BUFFER, DoubleBufferedFb, Framebuffer};
+use blog_os::interrupts;
+
+const WIDTH: u32 = 16;
+const HEIGHT: u32 = 8;
+const STRIDE: u32 = 20;
+
+fn front_memory() -> Vec<u32> {
+	vec![0u32; (STRIDE * HEIGHT) as usize]
+}
+
+#[test_case]
+fn drawing_stays_in_back_buffer_until_present() {
+	let mut memory = front_memory();
+	let front = unsafe { Framebuffer::new(memory.as_mut_ptr(), WIDTH, HEIGHT, STRIDE) };
+	let mut screen = DoubleBufferedFb::new(front);
+
+	screen.fill_rect(2, 2, 100, 3, 0x00FF_0000);
+	screen.put_pixel(0, 0, 0x0000_FF00);
+	screen.put_pixel(WIDTH, 0, 0xFFFF_FFFF);
+
+	assert_eq!(screen.pixel(15, 4), Some(0x00FF_0000));
+	assert_eq!(screen.pixel(15, 5), Some(0));
+	assert!(screen.is_dirty());
+
+	// nothing on screen yet
+	assert_eq!(screen.front().pixel(0, 0), Some(0));
+	assert_eq!(screen.front().pixel(2, 2), Some(0));
+
+	screen.present();
+	assert!(!screen.is_dirty());
+	assert_eq!(screen.front().pixel(0, 0), Some(0x0000_FF00));
+	assert_eq!(screen.front().pixel(15, 4), Some(0x00FF_0000));
+	drop(screen);
+
+	// the rectangle was clipped at the width, the padding stays black
+	for row in 0..HEIGHT as usize {
+		for col in WIDTH as usize..STRIDE as usize {
+			assert_eq!(memory[row * STRIDE as usize + col], 0);
+		}
+	}
+	assert_eq!(memory.iter().filter(|&&p| p == 0x00FF_0000).count(), 3 * 14);
+}
+
+// the front buffer is leaked into the global console on purpose
+#[test_case]
+const TIMER_PRESENTS_THE_GLOBAL_BUFFER: blog_os::MayLeak =
+	blog_os::may_leak!(timer_presents_the_global_buffer);
+
+fn timer_presents_the_global_buffer() {
+	// the timer keeps a pointer into it, so it has to outlive the test
+	let memory: &'static mut [u32] = Box::leak(front_memory().into_boxed_slice());
+	let front = unsafe { Framebuffer::new(memory.as_mut_ptr(), WIDTH, HEIGHT, STRIDE) };
+	fb::init_double_buffered(front);
+
+	let frames = fb::frames_presented();
+	DOUBLE_BUFFER.lock().as_mut().unwrap().fill_rect(0, 0, WIDTH, HEIGHT, 0x0012_3456);
+
+	// a couple of ticks is plenty at any PRESENT_HZ the PIT can do
+	let deadline = interrupts::ticks() + 3;
+	while interrupts::ticks() < deadline {
+		x86_64::instructions::hlt();
+	}
+
+	let presented = DOUBLE_BUFFER.lock().take().unwrap();
+	assert!(!presented.is_dirty());
+	drop(presented);
+	assert!(fb::frames_presented() > frames);
+
+	assert_eq!(memory[0], 0x0012_3456);
+	assert_eq!(memory[((HEIGHT - 1) * STRIDE + WIDTH - 1) as usize], 0x0012_3456);
+}
@@ -0,0 +1,107 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use blog_os::{
+	fpu,
+	task::{FpuState, Task},
+	test_harness::TestEnv,
+};
+use core::{
+	arch::asm,
+	future::Future,
+	pin::Pin,
+	sync::atomic::{AtomicU64, Ordering},
+	task::{Context, Poll},
+};
+
+fn write_xmm0(value: u64) {
+	unsafe { asm!("movq xmm0, {}", in(reg) value, options(nostack, nomem)) };
+}
+
+fn read_xmm0() -> u64 {
+	let value;
+	unsafe { asm!("movq {}, xmm0", out(reg) value, options(nostack, nomem)) };
+	value
+}
+
+/// Pending once, so the executor polls someone else in between
+struct YieldOnce(bool);
+
+impl Future for YieldOnce {
+	type Output = ();
+
+	fn poll(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context,
+	) -> Poll<()> {
+		if self.0 {
+			return Poll::Ready(());
+		}
+		self.0 = true;
+		cx.waker().wake_by_ref();
+		Poll::Pending
+	}
+}
+
+/// sets xmm0, lets the other task run and reports what xmm0 holds afterwards
+async fn keep_xmm0(
+	value: u64,
+	seen: &'static AtomicU64,
+) {
+	write_xmm0(value);
+	YieldOnce(false).await;
+	seen.store(read_xmm0(), Ordering::Relaxed);
+}
+
+#[test_case]
+fn fxsave_round_trip() {
+	let mut state = FpuState::new();
+
+	write_xmm0(0x1234_5678_9ABC_DEF0);
+	unsafe { fpu::fxsave(&mut state) };
+	assert!(state.is_initialized());
+
+	write_xmm0(0);
+	unsafe { fpu::fxrstor(&state) };
+	assert_eq!(read_xmm0(), 0x1234_5678_9ABC_DEF0);
+}
+
+#[test_case]
+fn xmm_register_survives_a_task_switch() {
+	static SEEN_A: AtomicU64 = AtomicU64::new(0);
+	static SEEN_B: AtomicU64 = AtomicU64::new(0);
+
+	let mut env = TestEnv::new();
+	let executor = env.executor();
+	executor.spawn(Task::new(keep_xmm0(0xAAAA_AAAA, &SEEN_A)).with_fpu());
+	executor.spawn(Task::new(keep_xmm0(0xBBBB_BBBB, &SEEN_B)).with_fpu());
+
+	// a, b, then each of them once more
+	assert_eq!(executor.run_polls(4), 4);
+
+	assert_eq!(SEEN_A.load(Ordering::Relaxed), 0xAAAA_AAAA);
+	assert_eq!(SEEN_B.load(Ordering::Relaxed), 0xBBBB_BBBB);
+}
@@ -0,0 +1,201 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{self, BootInfoFrameAllocator, LOW_MEMORY_LIMIT};
+use bootloader::{
+	BootInfo,
+	bootinfo::{FrameRange, MemoryMap, MemoryRegion, MemoryRegionType},
+	entry_point,
+};
+use conquer_once::spin::OnceCell;
+use core::{ops::Range, panic::PanicInfo};
+use x86_64::{
+	PhysAddr,
+	structures::paging::{FrameAllocator, PhysFrame},
+};
+
+entry_point!(main);
+
+static MEMORY_MAP: OnceCell<&'static MemoryMap> = OnceCell::uninit();
+static TEST_RANGE: OnceCell<Range<u64>> = OnceCell::uninit();
+
+/// frames the test range covers
+const TEST_RANGE_FRAMES: u64 = 16;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	blog_os::init();
+
+	// the first usable frames above 1 MiB, exactly what allocate_frame would hand out first
+	let start = boot_info
+		.memory_map
+		.iter()
+		.filter(|r| r.region_type == MemoryRegionType::Usable)
+		.map(|r| r.range.start_addr().max(LOW_MEMORY_LIMIT)..r.range.end_addr())
+		.find(|r| r.end >= r.start + TEST_RANGE_FRAMES * 4096)
+		.expect("no usable memory above 1 MiB")
+		.start;
+	let range = start..start + TEST_RANGE_FRAMES * 4096;
+
+	assert!(memory::reserve_range(PhysAddr::new(range.start), PhysAddr::new(range.end)));
+	MEMORY_MAP.init_once(|| &boot_info.memory_map);
+	TEST_RANGE.init_once(|| range);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+/// A fresh allocator over the boot memory map
+///
+/// Nothing in here writes to the frames, so handing out the same ones again is fine
+fn allocator() -> BootInfoFrameAllocator {
+	unsafe { BootInfoFrameAllocator::init(MEMORY_MAP.get().unwrap()) }
+}
+
+#[test_case]
+fn no_regular_frame_below_one_mib() {
+	let mut allocator = allocator();
+
+	for _ in 0..256 {
+		let frame = allocator.allocate_frame().expect("out of frames");
+		assert!(frame.start_address().as_u64() >= LOW_MEMORY_LIMIT);
+	}
+}
+
+#[test_case]
+fn low_frames_on_request() {
+	let mut allocator = allocator();
+	let limit = PhysAddr::new(LOW_MEMORY_LIMIT);
+
+	let first = allocator.allocate_frame_below(limit).expect("no frame below 1 MiB");
+	let second = allocator.allocate_frame_below(limit).expect("only one frame below 1 MiB");
+
+	assert!(first.start_address() + first.size() <= limit);
+	assert!(second.start_address() + second.size() <= limit);
+	assert_ne!(first, second);
+	assert!(allocator.reserved_bytes() > 0);
+}
+
+#[test_case]
+fn registered_range_respected() {
+	let range = TEST_RANGE.get().unwrap().clone();
+	let mut allocator = allocator();
+
+	for _ in 0..(2 * TEST_RANGE_FRAMES) {
+		let addr = allocator.allocate_frame().expect("out of frames").start_address().as_u64();
+		assert!(!range.contains(&addr));
+	}
+
+	// the range itself is still reachable explicitly
+	let frame = allocator
+		.allocate_frame_in(PhysAddr::new(range.start)..PhysAddr::new(range.end))
+		.expect("reserved range not in the pool");
+	assert!(range.contains(&frame.start_address().as_u64()));
+}
+
+#[test_case]
+fn frames_used_counts_allocations() {
+	const K: usize = 32;
+	let mut allocator = allocator();
+
+	let total = allocator.frames_total();
+	assert!(total > K);
+	assert_eq!(allocator.frames_used(), 0);
+	assert_eq!(allocator.frames_free(), total);
+	assert_eq!(allocator.usable_bytes(), total as u64 * 4096);
+
+	for _ in 0..K {
+		allocator.allocate_frame().expect("out of frames");
+	}
+	assert_eq!(allocator.frames_used(), K);
+	assert_eq!(allocator.frames_free(), total - K);
+
+	// the reserved pool is counted apart
+	allocator.allocate_frame_below(PhysAddr::new(LOW_MEMORY_LIMIT)).expect("no frame below 1 MiB");
+	assert_eq!(allocator.frames_used(), K);
+	assert_eq!(allocator.frames_total(), total);
+}
+
+#[test_case]
+fn freed_frames_come_back() {
+	let mut allocator = allocator();
+
+	let frames: [PhysFrame; 10] =
+		core::array::from_fn(|_| allocator.allocate_frame().expect("out of frames"));
+	let used = allocator.frames_used();
+
+	for &frame in frames.iter() {
+		allocator.dealloc_frame(frame);
+	}
+	assert_eq!(allocator.freed_count(), 10);
+	assert_eq!(allocator.frames_used(), used - 10);
+
+	let mut again: [PhysFrame; 10] =
+		core::array::from_fn(|_| allocator.allocate_frame().expect("out of frames"));
+	assert_eq!(allocator.freed_count(), 0);
+	assert_eq!(allocator.frames_used(), used);
+
+	// freed ones go out last in first out
+	again.reverse();
+	assert_eq!(again, frames);
+}
+
+#[test_case]
+fn freed_reserved_frame_returns_to_the_pool() {
+	let mut allocator = allocator();
+	let limit = PhysAddr::new(LOW_MEMORY_LIMIT);
+
+	let frame = allocator.allocate_frame_below(limit).expect("no frame below 1 MiB");
+	allocator.dealloc_frame(frame);
+
+	// not on the regular free list, but the pool hands it out again
+	assert_eq!(allocator.freed_count(), 0);
+	assert_eq!(allocator.allocate_frame_below(limit), Some(frame));
+}
+
+#[test_case]
+fn failed_contiguous_allocation_keeps_every_frame() {
+	let mut allocator = allocator();
+	let free = allocator.frames_free();
+
+	// more than there is, every fresh frame is looked at and none may go missing
+	assert_eq!(allocator.allocate_contiguous(free + 1), None);
+	assert_eq!(allocator.frames_free(), free);
+	assert_eq!(allocator.freed_count(), 0);
+
+	// and the fresh frames are all still there for the next run
+	assert!(allocator.allocate_contiguous(4).is_some());
+	assert_eq!(allocator.frames_used(), 4);
+}
+
+#[test_case]
+fn boot_report_counts_only_usable_regions() {
+	let region = |start: u64, end: u64, region_type| MemoryRegion {
+		range: FrameRange::new(start, end),
+		region_type,
+	};
+	let mut map = MemoryMap::new();
+	map.add_region(region(0, 0x1000, MemoryRegionType::FrameZero));
+	map.add_region(region(0x1000, 0x9_f000, MemoryRegionType::Usable));
+	map.add_region(region(0x10_0000, 0x40_0000, MemoryRegionType::Kernel));
+	map.add_region(region(0x40_0000, 0x800_0000, MemoryRegionType::Usable));
+	map.add_region(region(0x800_0000, 0x800_4000, MemoryRegionType::Reserved));
+
+	let report = memory::BootReport::new(&map, 0x100_0000_0000);
+	assert_eq!(report.total_usable_bytes, 0x9_e000 + 0x7c0_0000);
+	assert_eq!(report.region_count, 5);
+	assert_eq!(report.largest_region, Some(PhysAddr::new(0x40_0000)..PhysAddr::new(0x800_0000)));
+	assert_eq!(report.physical_memory_offset, 0x100_0000_0000);
+
+	let empty = memory::BootReport::new(&MemoryMap::new(), 0);
+	assert_eq!(empty.total_usable_bytes, 0);
+	assert_eq!(empty.largest_region, None);
+}
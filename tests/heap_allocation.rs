@@ -12,16 +12,7 @@ use core::panic::PanicInfo;
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
-	use blog_os::allocator;
-	use blog_os::memory::{self, BootInfoFrameAllocator};
-	use x86_64::VirtAddr;
-
-	blog_os::init();
-	let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-	let mut mapper = unsafe { memory::init(phys_mem_offset) };
-	let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-
-	allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+	let _env = blog_os::test_harness::init_full(boot_info);
 
 	test_main();
 
@@ -79,3 +70,139 @@ fn many_boxes_long_lived_memory_reuse() {
 	// This one leads to an out of memory error after a few iterations
 	assert_eq!(*long_lived, 1);
 }
+
+/// Prints what an alloc and free pair costs, run it with and without zero-on-free to compare
+#[test_case]
+fn alloc_free_cost() {
+	use alloc::alloc::{Layout, alloc, dealloc};
+	use blog_os::{serial_println, task::trace};
+
+	const ROUNDS: u64 = 1000;
+
+	for &size in &[64usize, 2048, 8192] {
+		let layout = Layout::from_size_align(size, 8).unwrap();
+
+		let start = trace::cycles();
+		for _ in 0..ROUNDS {
+			unsafe {
+				let ptr = alloc(layout);
+				assert!(!ptr.is_null());
+				dealloc(ptr, layout);
+			}
+		}
+		let cycles = trace::cycles().wrapping_sub(start) / ROUNDS;
+
+		serial_println!(
+			"\n  {} bytes: {} cycles per alloc+free (zero-on-free {})",
+			size,
+			cycles,
+			if cfg!(feature = "zero-on-free") { "on" } else { "off" }
+		);
+	}
+}
+
+/// a freed block comes back without what its previous owner wrote
+#[cfg(feature = "zero-on-free")]
+#[test_case]
+fn freed_blocks_come_back_zeroed() {
+	use alloc::alloc::{Layout, alloc, dealloc};
+
+	for &size in &[8usize, 64, 2048] {
+		let layout = Layout::from_size_align(size, 8).unwrap();
+
+		unsafe {
+			let first = alloc(layout);
+			assert!(!first.is_null());
+			core::ptr::write_bytes(first, 0xA5, size);
+			dealloc(first, layout);
+
+			// the free lists are LIFO, so the same block comes straight back
+			let second = alloc(layout);
+			assert_eq!(second, first);
+			let bytes = core::slice::from_raw_parts(second, size);
+			assert!(bytes.iter().all(|&b| b == 0), "{} byte block wasn't wiped", size);
+			dealloc(second, layout);
+		}
+	}
+}
+
+/// a leak shows up in the diff, with its call site under `alloc-debug`
+#[test_case]
+const LEAK_SHOWS_UP_IN_THE_DIFF: blog_os::MayLeak = blog_os::may_leak!(leak_shows_up_in_the_diff);
+
+fn leak_shows_up_in_the_diff() {
+	use alloc::alloc::Layout;
+	use blog_os::allocator::{self, fixed_size_block::size_class};
+
+	let before = allocator::checkpoint();
+	let leaked: &'static mut [u8; 40] = Box::leak(Box::new([0x5A; 40]));
+	let diff = allocator::diff(&before);
+
+	assert!(diff.has_leaks());
+	// charged the whole 64 byte block
+	assert_eq!(diff.bytes, 64);
+	let class = size_class(&Layout::new::<[u8; 40]>());
+	assert_eq!(diff.live[class], 1);
+	assert_eq!(diff.live.iter().sum::<i64>(), 1);
+	let summary = alloc::format!("{}", diff);
+	assert!(summary.starts_with("+64 bytes (+1 in 64 byte blocks)"), "{}", summary);
+
+	if cfg!(feature = "alloc-debug") {
+		let sample = diff
+			.samples()
+			.iter()
+			.find(|sample| sample.ptr == leaked.as_ptr() as usize)
+			.expect("the leak isn't listed");
+		assert_eq!(sample.size, 64);
+		assert_ne!(sample.callers[0], 0);
+	} else {
+		assert!(diff.samples().is_empty());
+	}
+}
+
+/// what's freed again doesn't count, the runner's own check passes too
+#[test_case]
+fn freed_allocations_leave_no_diff() {
+	use blog_os::allocator;
+
+	let before = allocator::checkpoint();
+	let small: Vec<Box<u64>> = (0..32).map(Box::new).collect();
+	let large = alloc::vec![0u8; 4096];
+	assert!(allocator::diff(&before).has_leaks());
+
+	drop(small);
+	drop(large);
+	let diff = allocator::diff(&before);
+	assert!(diff.is_zero(), "{}", diff);
+	assert!(!diff.has_leaks());
+	assert_eq!(alloc::format!("{}", diff), "no change");
+}
+
+/// holes between live allocations split the free memory up, freeing them joins it again
+#[test_case]
+fn freeing_every_other_block_fragments_the_heap() {
+	use alloc::alloc::{Layout, alloc, dealloc};
+	use blog_os::allocator;
+
+	// past the largest block size, so they come from the fallback heap and go back to it
+	let layout = Layout::from_size_align(4096, 8).unwrap();
+	let mut blocks = Vec::with_capacity(12);
+	let before = allocator::fragmentation();
+
+	for _ in 0..12 {
+		let block = unsafe { alloc(layout) };
+		assert!(!block.is_null());
+		blocks.push(block);
+	}
+	for &block in blocks.iter().step_by(2) {
+		unsafe { dealloc(block, layout) };
+	}
+	let scattered = allocator::fragmentation();
+	assert!(scattered > before, "{} after, {} before", scattered, before);
+	assert!(scattered <= 1000);
+
+	for &block in blocks.iter().skip(1).step_by(2) {
+		unsafe { dealloc(block, layout) };
+	}
+	assert_eq!(allocator::fragmentation(), before);
+}
@@ -0,0 +1,108 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use blog_os::hpet::{self, HpetError, HpetInfo};
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+/// what init_hpet found, QEMU's machines all have an HPET
+static INFO: spin::Once<HpetInfo> = spin::Once::new();
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	let table = hpet::find_table().expect("no ACPI HPET table");
+	INFO.call_once(|| hpet::init_hpet(table).expect("HPET init failed"));
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use blog_os::{
+	interrupts,
+	task::timer::{self, TICKS_PER_SECOND},
+	test_harness::TestEnv,
+};
+
+fn info() -> &'static HpetInfo {
+	INFO.r#try().unwrap()
+}
+
+/// spins until the tick count moves, returns the new one
+fn next_tick() -> u64 {
+	let start = interrupts::ticks();
+	loop {
+		let now = interrupts::ticks();
+		if now != start {
+			return now;
+		}
+		x86_64::instructions::hlt();
+	}
+}
+
+#[test_case]
+fn reports_a_sane_hpet() {
+	let info = info();
+	assert!(info.period_fs > 0 && info.period_fs <= 100_000_000, "{:?}", info);
+	// at least 10 MHz, that's what the spec asks for
+	assert!(info.frequency() >= 10_000_000, "{:?}", info);
+	assert!(info.timers >= 3, "{:?}", info);
+
+	assert_eq!(hpet::init_hpet(info.base), Err(HpetError::AlreadyInitialized));
+	// a tick is still 1/TICKS_PER_SECOND s, give or take the rounding
+	let per_tick = hpet::counts_per_tick().unwrap();
+	assert!(per_tick.abs_diff(info.frequency() / TICKS_PER_SECOND) <= 1, "{}", per_tick);
+}
+
+#[test_case]
+fn counter_runs() {
+	let first = hpet::hpet_read_counter();
+	let second = hpet::hpet_read_counter();
+	assert!(first > 0);
+	assert!(second >= first);
+
+	next_tick();
+	assert!(hpet::hpet_read_counter() > second);
+}
+
+#[test_case]
+fn ticks_keep_their_length() {
+	let per_tick = hpet::counts_per_tick().unwrap();
+
+	let start_tick = next_tick();
+	let start = hpet::hpet_read_counter();
+	while interrupts::ticks() < start_tick + 5 {
+		x86_64::instructions::hlt();
+	}
+	let elapsed = hpet::hpet_read_counter() - start;
+
+	// the interrupt comes every millisecond, so a tick is late by that at most
+	let slack = info().frequency() / 1000 + per_tick / 10;
+	assert!(elapsed + slack >= 5 * per_tick, "{} counts for 5 ticks of {}", elapsed, per_tick);
+	assert!(elapsed <= 5 * per_tick + slack, "{} counts for 5 ticks of {}", elapsed, per_tick);
+}
+
+#[test_case]
+fn sleep_ticks_waits_whole_ticks() {
+	let per_tick = hpet::counts_per_tick().unwrap();
+	let mut env = TestEnv::new();
+
+	let start = hpet::hpet_read_counter();
+	env.run_async(timer::sleep_ticks(2)).expect("sleep never ended");
+	let elapsed = hpet::hpet_read_counter() - start;
+
+	assert!(elapsed >= 2 * per_tick, "slept {} counts, wanted {}", elapsed, 2 * per_tick);
+}
@@ -0,0 +1,95 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use blog_os::stack::{self, KernelStack};
+use blog_os::virtio::{FRAME_ALLOCATOR, PAGE_MAPPER};
+use blog_os::{QemuExitCode, exit::exit_qemu_or_halt, serial_print, serial_println};
+use bootloader::{BootInfo, entry_point};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+/// what the page fault handler has to report for a run off the test stack
+const EXPECTED: &str = "kernel stack overflow in test::deep";
+
+entry_point!(main);
+
+/// Recurses off the bottom of a managed stack, the page fault handler has to name the stack
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	let deep = {
+		let mut mapper = PAGE_MAPPER.lock();
+		let mut frame_allocator = FRAME_ALLOCATOR.lock();
+		KernelStack::new(
+			"test::deep",
+			4,
+			mapper.as_mut().unwrap(),
+			frame_allocator.as_mut().unwrap(),
+		)
+		.expect("stack allocation failed")
+	};
+
+	serial_print!("kernel_stack::runs_on_the_stack...\t");
+	let rsp = stack::run_on(&deep, current_rsp);
+	assert!(rsp < deep.top().as_u64() && rsp >= deep.top().as_u64() - deep.size() as u64);
+	// already on it, the nested call stays put
+	assert_eq!(stack::run_on(&deep, || stack::run_on(&deep, || 40 + 2)), 42);
+	serial_println!("[ok]");
+
+	serial_print!("kernel_stack::overflow_hits_guard_page...\t");
+	stack::run_on(&deep, || recurse(0));
+
+	serial_println!("[failed]\n");
+	serial_println!("Error: recursion came back without overflowing\n");
+	exit_qemu_or_halt(QemuExitCode::Failed);
+}
+
+fn current_rsp() -> u64 {
+	let rsp: u64;
+	unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp) };
+	rsp
+}
+
+#[allow(unconditional_recursion)]
+fn recurse(depth: u64) -> u64 {
+	// a few hundred bytes per frame, 16 KiB are gone quickly
+	let frame = [depth; 32];
+	let below = recurse(volatile::Volatile::new(depth + 1).read());
+	below + frame[(depth % 32) as usize]
+}
+
+/// keeps the start of the panic message, enough to look for `EXPECTED`
+struct MessageBuf {
+	buf: [u8; 256],
+	len: usize,
+}
+
+impl Write for MessageBuf {
+	fn write_str(
+		&mut self,
+		s: &str,
+	) -> fmt::Result {
+		let n = s.len().min(self.buf.len() - self.len);
+		self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+		self.len += n;
+		Ok(())
+	}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	let mut message = MessageBuf { buf: [0; 256], len: 0 };
+	let _ = write!(message, "{}", info);
+	let message = core::str::from_utf8(&message.buf[..message.len]).unwrap_or("");
+
+	if message.contains(EXPECTED) {
+		serial_println!("[ok]");
+		exit_qemu_or_halt(QemuExitCode::Success);
+	} else {
+		serial_println!("[failed]\n");
+		serial_println!("Error: {}\n", info);
+		exit_qemu_or_halt(QemuExitCode::Failed);
+	}
+}
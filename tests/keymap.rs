@@ -0,0 +1,204 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use alloc::{string::String, vec::Vec};
+use blog_os::{
+	fs::{
+		block_dev::MemBlockDevice,
+		simple_fs::{FileError, FileSystem, SFS},
+	},
+	shell::run_command,
+	task::{
+		keyboard::{KeyDecoder, load_keymap, set_keymap},
+		keymap::{FLAG_CAPS, KEYMAP_FILE_SIZE, Keymap, KeymapError, keymap_active},
+	},
+};
+use pc_keyboard::DecodedKey;
+
+/// a few Colemak keys, enough to tell it from QWERTY
+const COLEMAK: &str = "\
+# make  base  shifted  altgr
+0x12  f  F
+0x13  p  P
+0x1f  r  R
+0x21  t  T
+0x25  e  E
+0x1e  a  A  æ
+0x28  !´  !¨    # the apostrophe key is dead
+0x39  U+0020  U+0020
+";
+
+const PATH: &str = "/keymaps/colemak.kmap";
+
+const LSHIFT: u8 = 0x2A;
+const LCTRL: u8 = 0x1D;
+const CAPS_LOCK: u8 = 0x3A;
+
+/// an SFS with the test keymap at `PATH`
+fn fs_with_keymap() -> SFS<MemBlockDevice> {
+	let mut fs = SFS::format(MemBlockDevice::new(64)).expect("format failed");
+	fs.init_root_directory().expect("root directory init failed");
+
+	let bytes = Keymap::from_text(COLEMAK).expect("compiling failed").to_bytes();
+	let handle = fs.create_file(&PATH[1..]).expect("create failed");
+	fs.write_file(handle, &bytes).expect("write failed");
+	fs
+}
+
+/// every character the scancodes decode to, queued second events included
+fn type_scancodes(
+	decoder: &mut KeyDecoder,
+	scancodes: &[u8],
+) -> String {
+	let mut typed = String::new();
+	for &scancode in scancodes {
+		let first = decoder.feed(scancode);
+		for event in first.into_iter().chain(decoder.take_queued()) {
+			if let DecodedKey::Unicode(c) = event.key {
+				typed.push(c);
+			}
+		}
+	}
+	typed
+}
+
+/// make then break
+fn tap(make: u8) -> [u8; 2] {
+	[make, make | 0x80]
+}
+
+fn taps(makes: &[u8]) -> Vec<u8> {
+	makes.iter().flat_map(|&make| tap(make)).collect()
+}
+
+#[test_case]
+fn loaded_keymap_decodes_and_composes() {
+	let fs = fs_with_keymap();
+	let keymap = load_keymap(&fs, PATH).expect("loading failed");
+	assert_eq!(keymap, Keymap::from_text(COLEMAK).unwrap());
+	assert_ne!(keymap.entry(0x12).flags & FLAG_CAPS, 0);
+	set_keymap(Some(keymap));
+
+	let mut decoder = KeyDecoder::new();
+	// QWERTY e r s f k
+	assert_eq!(type_scancodes(&mut decoder, &taps(&[0x12, 0x13, 0x1F, 0x21, 0x25])), "fprte");
+
+	let shifted = [LSHIFT, 0x12, 0x92, LSHIFT | 0x80];
+	assert_eq!(type_scancodes(&mut decoder, &shifted), "F");
+	let caps = [tap(CAPS_LOCK), tap(0x13), tap(CAPS_LOCK)].concat();
+	assert_eq!(type_scancodes(&mut decoder, &caps), "P");
+	// right alt is E0 38
+	let altgr = [0xE0, 0x38, 0x1E, 0x9E, 0xE0, 0xB8];
+	assert_eq!(type_scancodes(&mut decoder, &altgr), "æ");
+
+	// keys the keymap leaves out keep their US meaning: q and Enter
+	assert_eq!(type_scancodes(&mut decoder, &taps(&[0x10, 0x1C])), "q\n");
+	// Ctrl with a mapped letter still gives the control character
+	let ctrl_r = [LCTRL, 0x1F, 0x9F, LCTRL | 0x80];
+	assert_eq!(type_scancodes(&mut decoder, &ctrl_r), "\u{12}");
+
+	// dead keys
+	assert_eq!(type_scancodes(&mut decoder, &taps(&[0x28, 0x25])), "é");
+	assert_eq!(type_scancodes(&mut decoder, &taps(&[0x28, 0x39])), "´");
+	assert_eq!(type_scancodes(&mut decoder, &taps(&[0x28, 0x13])), "´p");
+	let shifted_dead = [LSHIFT, 0x28, 0xA8, LSHIFT | 0x80, 0x1E, 0x9E];
+	assert_eq!(type_scancodes(&mut decoder, &shifted_dead), "ä");
+	// something that isn't a character sends the accent out on its own
+	assert_eq!(type_scancodes(&mut decoder, &taps(&[0x28, 0x1C])), "´\n");
+
+	set_keymap(None);
+	assert_eq!(type_scancodes(&mut decoder, &taps(&[0x12, 0x13])), "er");
+}
+
+#[test_case]
+fn kbd_command_loads_and_reverts() {
+	let mut fs = fs_with_keymap();
+	let mut out = String::new();
+
+	run_command("kbd load /keymaps/missing.kmap", Some(&mut fs as &mut dyn FileSystem), &mut out)
+		.unwrap();
+	assert!(out.contains("FileNotFound"), "{}", out);
+	assert!(!keymap_active());
+
+	out.clear();
+	run_command("kbd load /keymaps/colemak.kmap", Some(&mut fs as &mut dyn FileSystem), &mut out)
+		.unwrap();
+	assert_eq!(out, "keymap /keymaps/colemak.kmap active\n");
+	assert!(keymap_active());
+	let mut decoder = KeyDecoder::new();
+	assert_eq!(type_scancodes(&mut decoder, &tap(0x12)), "f");
+
+	out.clear();
+	run_command("kbd builtin us", None, &mut out).unwrap();
+	assert_eq!(out, "builtin US layout active\n");
+	assert!(!keymap_active());
+	assert_eq!(type_scancodes(&mut decoder, &tap(0x12)), "e");
+}
+
+#[test_case]
+fn bad_keymaps_rejected() {
+	let good = Keymap::from_text(COLEMAK).unwrap().to_bytes();
+	assert_eq!(good.len(), KEYMAP_FILE_SIZE);
+
+	let mut bytes = good.clone();
+	bytes[0] = b'X';
+	assert_eq!(Keymap::from_bytes(&bytes), Err(KeymapError::BadMagic));
+
+	let mut bytes = good.clone();
+	bytes[4] = 9;
+	assert_eq!(Keymap::from_bytes(&bytes), Err(KeymapError::BadVersion(9)));
+
+	// a surrogate as the shifted character of 0x10
+	let mut bytes = good.clone();
+	let entry = 8 + 16 * 0x10;
+	bytes[entry + 4..entry + 8].copy_from_slice(&0xD800u32.to_le_bytes());
+	assert_eq!(
+		Keymap::from_bytes(&bytes),
+		Err(KeymapError::BadScalar { scancode: 0x10, value: 0xD800 })
+	);
+
+	// dead on a layer without a character
+	let mut bytes = good.clone();
+	bytes[entry + 12] = 1;
+	assert_eq!(Keymap::from_bytes(&bytes), Err(KeymapError::BadFlags { scancode: 0x10, flags: 1 }));
+
+	assert_eq!(Keymap::from_bytes(&good[1..]), Err(KeymapError::WrongSize(KEYMAP_FILE_SIZE - 1)));
+
+	// the text side: a key twice, a make code out of range, a word that's no character
+	assert_eq!(Keymap::from_text("0x10 q\n0x10 w"), Err(KeymapError::Syntax { line: 2 }));
+	assert_eq!(Keymap::from_text("0x80 q"), Err(KeymapError::Syntax { line: 1 }));
+	assert_eq!(Keymap::from_text("# fine\n0x10 qq"), Err(KeymapError::Syntax { line: 2 }));
+	assert_eq!(
+		Keymap::from_text("0x10 U+D800"),
+		Err(KeymapError::BadScalar { scancode: 0x10, value: 0xD800 })
+	);
+
+	let mut fs = fs_with_keymap();
+	let handle = fs.create_file("short.kmap").unwrap();
+	fs.write_file(handle, b"KMAP").unwrap();
+	assert_eq!(load_keymap(&fs, "short.kmap"), Err(KeymapError::WrongSize(4)));
+	assert_eq!(load_keymap(&fs, "/nope.kmap"), Err(KeymapError::File(FileError::FileNotFound)));
+}
@@ -0,0 +1,253 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use blog_os::net::{
+	Ipv4Addr, MacAddr,
+	arp::{ARP_PACKET_LEN, ArpCache, ETHERTYPE_ARP, arp_request, handle_arp_reply},
+	ethernet::{self, ETHERNET_HEADER_LEN, EtherType, EthernetFrame},
+	icmp::{ICMP_ECHO_REPLY, echo_reply, echo_reply_frame},
+	ipv4::{self, IPV4_HEADER_LEN, IpProto, Ipv4Header},
+};
+
+const OUR_MAC: MacAddr = MacAddr([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+const OUR_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+const GATEWAY_MAC: MacAddr = MacAddr([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]);
+const GATEWAY_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+
+/// an Ethernet frame carrying an ARP packet with the given operation, from the gateway to us
+fn arp_frame(oper: u16) -> [u8; 14 + ARP_PACKET_LEN] {
+	let mut frame = [0u8; 14 + ARP_PACKET_LEN];
+	frame[0..6].copy_from_slice(&OUR_MAC.0);
+	frame[6..12].copy_from_slice(&GATEWAY_MAC.0);
+	frame[12..14].copy_from_slice(&ETHERTYPE_ARP.to_be_bytes());
+
+	let packet = &mut frame[14..];
+	packet[0..8].copy_from_slice(&[0, 1, 0x08, 0x00, 6, 4, 0, 0]);
+	packet[6..8].copy_from_slice(&oper.to_be_bytes());
+	packet[8..14].copy_from_slice(&GATEWAY_MAC.0);
+	packet[14..18].copy_from_slice(&GATEWAY_IP.octets());
+	packet[18..24].copy_from_slice(&OUR_MAC.0);
+	packet[24..28].copy_from_slice(&OUR_IP.octets());
+	frame
+}
+
+#[test_case]
+fn request_layout() {
+	let packet = arp_request(GATEWAY_IP, OUR_IP, OUR_MAC);
+
+	assert_eq!(&packet[0..8], &[0, 1, 0x08, 0x00, 6, 4, 0, 1]);
+	assert_eq!(&packet[8..14], &OUR_MAC.0);
+	assert_eq!(&packet[14..18], &OUR_IP.octets());
+	assert_eq!(&packet[18..24], &[0u8; 6]);
+	assert_eq!(&packet[24..28], &GATEWAY_IP.octets());
+}
+
+#[test_case]
+fn reply_fills_cache() {
+	let mut cache = ArpCache::new();
+
+	handle_arp_reply(&arp_frame(2), &mut cache);
+
+	assert_eq!(cache.get(GATEWAY_IP), Some(GATEWAY_MAC));
+	assert_eq!(cache.len(), 1);
+}
+
+#[test_case]
+fn requests_and_garbage_ignored() {
+	let mut cache = ArpCache::new();
+
+	// someone asking about us isn't a reply
+	handle_arp_reply(&arp_frame(1), &mut cache);
+
+	// not ARP at all
+	let mut ipv4 = arp_frame(2);
+	ipv4[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+	handle_arp_reply(&ipv4, &mut cache);
+
+	// cut short
+	handle_arp_reply(&arp_frame(2)[..30], &mut cache);
+
+	assert!(cache.is_empty());
+}
+
+/// the gateway answering our ARP request, as captured from QEMU user networking (padded to 60)
+const CAPTURED_ARP_REPLY: [u8; 60] = [
+	0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x52, 0x55, 0x0a, 0x00, 0x02, 0x02, 0x08, 0x06, 0x00, 0x01,
+	0x08, 0x00, 0x06, 0x04, 0x00, 0x02, 0x52, 0x55, 0x0a, 0x00, 0x02, 0x02, 0x0a, 0x00, 0x02, 0x02,
+	0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x0a, 0x00, 0x02, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+	0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+#[test_case]
+fn captured_frame_parses() {
+	let frame = EthernetFrame::new(&CAPTURED_ARP_REPLY).expect("frame too short");
+
+	assert_eq!(frame.dst_mac(), OUR_MAC.0);
+	assert_eq!(frame.src_mac(), GATEWAY_MAC.0);
+	assert_eq!(frame.ethertype(), EtherType::Arp);
+	assert_eq!(frame.payload().len(), 60 - ETHERNET_HEADER_LEN);
+	assert_eq!(&frame.payload()[..8], &[0, 1, 0x08, 0x00, 6, 4, 0, 2]);
+
+	let mut cache = ArpCache::new();
+	handle_arp_reply(&CAPTURED_ARP_REPLY, &mut cache);
+	assert_eq!(cache.get(GATEWAY_IP), Some(GATEWAY_MAC));
+}
+
+#[test_case]
+fn ethertypes_round_trip() {
+	for &(raw, ethertype) in &[
+		(0x0800, EtherType::Ipv4),
+		(0x0806, EtherType::Arp),
+		(0x86DD, EtherType::Ipv6),
+		(0x88CC, EtherType::Unknown(0x88CC)),
+	] {
+		assert_eq!(EtherType::from(raw), ethertype);
+		assert_eq!(u16::from(ethertype), raw);
+	}
+}
+
+#[test_case]
+fn built_frame_matches_capture() {
+	let payload = &CAPTURED_ARP_REPLY[ETHERNET_HEADER_LEN..];
+	let frame = ethernet::build(GATEWAY_MAC.0, OUR_MAC.0, EtherType::Arp, payload);
+
+	assert_eq!(&frame[..], &CAPTURED_ARP_REPLY[..]);
+}
+
+#[test_case]
+fn runt_frame_rejected() {
+	assert!(EthernetFrame::new(&CAPTURED_ARP_REPLY[..ETHERNET_HEADER_LEN - 1]).is_none());
+
+	let empty = EthernetFrame::new(&CAPTURED_ARP_REPLY[..ETHERNET_HEADER_LEN]).unwrap();
+	assert!(empty.payload().is_empty());
+}
+
+/// a UDP packet's header from 192.168.0.1 to 192.168.0.199, checksum 0xb861
+const CAPTURED_IPV4_HEADER: [u8; 20] = [
+	0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0xb8, 0x61, 0xc0, 0xa8, 0x00, 0x01,
+	0xc0, 0xa8, 0x00, 0xc7,
+];
+
+#[test_case]
+fn captured_ipv4_header_parses() {
+	let header = Ipv4Header::parse(&CAPTURED_IPV4_HEADER).expect("header too short");
+
+	assert_eq!(header.version(), 4);
+	assert_eq!(header.ihl(), 5);
+	assert_eq!(header.total_length(), 0x73);
+	assert_eq!(header.ttl(), 64);
+	assert_eq!(header.protocol(), IpProto::Udp);
+	assert_eq!(header.src_addr(), Ipv4Addr::new(192, 168, 0, 1));
+	assert_eq!(header.dst_addr(), Ipv4Addr::new(192, 168, 0, 199));
+	assert!(header.verify_checksum());
+
+	assert!(Ipv4Header::parse(&CAPTURED_IPV4_HEADER[..IPV4_HEADER_LEN - 1]).is_none());
+}
+
+#[test_case]
+fn corrupted_ipv4_header_fails_checksum() {
+	let mut raw = CAPTURED_IPV4_HEADER;
+	raw[8] -= 1; // one hop later, without fixing the checksum
+
+	assert!(!Ipv4Header::parse(&raw).unwrap().verify_checksum());
+}
+
+#[test_case]
+fn built_ipv4_header_matches_capture() {
+	let header = ipv4::build(
+		Ipv4Addr::new(192, 168, 0, 1),
+		Ipv4Addr::new(192, 168, 0, 199),
+		IpProto::Udp,
+		64,
+		0x73 - IPV4_HEADER_LEN as u16,
+	);
+
+	assert_eq!(header, Ipv4Header::parse(&CAPTURED_IPV4_HEADER).unwrap());
+	assert!(header.verify_checksum());
+}
+
+#[test_case]
+fn checksum_pads_odd_length() {
+	assert_eq!(ipv4::checksum(&[]), 0xFFFF);
+	assert_eq!(ipv4::checksum(&[0x12]), !0x1200);
+	assert_eq!(ipv4::checksum(&[0x12, 0x34, 0x56]), !(0x1234 + 0x5600));
+}
+
+/// a ping from the host: id 0x1234, seq 1, 32 bytes of Windows style data
+const CAPTURED_ECHO_REQUEST: [u8; 40] = [
+	0x08, 0x00, 0x3b, 0x27, 0x12, 0x34, 0x00, 0x01, b'a', b'b', b'c', b'd', b'e', b'f', b'g', b'h',
+	b'i', b'j', b'k', b'l', b'm', b'n', b'o', b'p', b'q', b'r', b's', b't', b'u', b'v', b'w', b'a',
+	b'b', b'c', b'd', b'e', b'f', b'g', b'h', b'i',
+];
+
+#[test_case]
+fn echo_request_answered() {
+	let reply = echo_reply(&CAPTURED_ECHO_REQUEST).expect("no reply to a ping");
+
+	assert_eq!(reply[0], ICMP_ECHO_REPLY);
+	assert_eq!(reply[1], 0);
+	assert_eq!(&reply[2..4], &[0x43, 0x27]);
+	assert_eq!(ipv4::checksum(&reply), 0);
+	// identifier, sequence number and data come back untouched
+	assert_eq!(&reply[4..], &CAPTURED_ECHO_REQUEST[4..]);
+}
+
+#[test_case]
+fn only_valid_echo_requests_answered() {
+	// a reply isn't answered
+	let mut reply = CAPTURED_ECHO_REQUEST;
+	reply[0] = ICMP_ECHO_REPLY;
+	assert!(echo_reply(&reply).is_none());
+
+	// neither is a corrupted request
+	let mut corrupted = CAPTURED_ECHO_REQUEST;
+	corrupted[10] ^= 0xFF;
+	assert!(echo_reply(&corrupted).is_none());
+
+	assert!(echo_reply(&CAPTURED_ECHO_REQUEST[..7]).is_none());
+}
+
+#[test_case]
+fn echo_reply_frame_addressed_to_sender() {
+	let frame = echo_reply_frame(&CAPTURED_ECHO_REQUEST, GATEWAY_IP, GATEWAY_MAC, OUR_IP, OUR_MAC)
+		.expect("no reply to a ping");
+	let frame = EthernetFrame::new(&frame).unwrap();
+
+	assert_eq!(frame.dst_mac(), GATEWAY_MAC.0);
+	assert_eq!(frame.src_mac(), OUR_MAC.0);
+	assert_eq!(frame.ethertype(), EtherType::Ipv4);
+
+	let header = Ipv4Header::parse(frame.payload()).unwrap();
+	assert!(header.verify_checksum());
+	assert_eq!(header.protocol(), IpProto::Icmp);
+	assert_eq!(header.src_addr(), OUR_IP);
+	assert_eq!(header.dst_addr(), GATEWAY_IP);
+	assert_eq!(header.total_length() as usize, IPV4_HEADER_LEN + CAPTURED_ECHO_REQUEST.len());
+
+	let icmp = &frame.payload()[IPV4_HEADER_LEN..];
+	assert_eq!(icmp[0], ICMP_ECHO_REPLY);
+	assert_eq!(&icmp[4..], &CAPTURED_ECHO_REQUEST[4..]);
+}
@@ -0,0 +1,124 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use blog_os::{
+	fs::{
+		block_dev::{BlockDevice, MemBlockDevice},
+		layout::BLOCK_SIZE,
+		simple_fs::{FileSystem, SFS},
+	},
+	panic_record::{self, MESSAGE_LEN, PanicRecord, PanicReserved},
+};
+
+#[test_case]
+fn record_round_trips() {
+	let mut device = MemBlockDevice::new(16);
+	let record = PanicRecord::new(format_args!("boom at {}", 7), 0x1234, &[0xaa, 0xbb, 0xcc]);
+	record.write_to(&mut device).expect("writing the record failed");
+
+	let read = PanicRecord::read_from_device(&mut device).unwrap().expect("no record found");
+	assert_eq!(read.message(), "boom at 7");
+	assert_eq!(read.rip, 0x1234);
+	assert_eq!(read.backtrace(), &[0xaa, 0xbb, 0xcc]);
+	assert_eq!(read.timestamp, record.timestamp);
+	// the heap is up and in use, so the peak is too
+	assert!(read.allocator_peak > 0);
+
+	// it went into the last block
+	let mut block = [0u8; BLOCK_SIZE];
+	device.read_blocks(15, &mut block).unwrap();
+	assert_eq!(&block[..4], b"PANC");
+}
+
+#[test_case]
+fn blank_device_has_no_record() {
+	let mut device = MemBlockDevice::new(16);
+	assert!(PanicRecord::read_from_device(&mut device).unwrap().is_none());
+}
+
+#[test_case]
+fn long_message_cut_at_a_char_boundary() {
+	let mut message = alloc::string::String::from("a");
+	for _ in 0..200 {
+		message.push('é');
+	}
+
+	let record = PanicRecord::new(&message, 0, &[]);
+	// one byte short of MESSAGE_LEN, the next 'é' would have been split
+	assert_eq!(record.message_len as usize, MESSAGE_LEN - 1);
+	assert!(message.starts_with(record.message()));
+	assert!(record.backtrace().is_empty());
+}
+
+#[test_case]
+fn backtrace_finds_frames() {
+	let mut frames = [0; panic_record::BACKTRACE_DEPTH];
+	let count = panic_record::capture_backtrace(&mut frames);
+
+	assert!(count > 0);
+	assert!(frames[..count].iter().all(|&ret| ret != 0));
+}
+
+#[test_case]
+fn filesystem_stays_out_of_the_record_block() {
+	const BLOCKS: usize = 256;
+
+	let mut device = PanicReserved::new(MemBlockDevice::new(BLOCKS));
+	assert_eq!(device.capacity(), BLOCKS - 1);
+	assert!(device.write_blocks((BLOCKS - 1) as u64, &[0; BLOCK_SIZE]).is_err());
+
+	PanicRecord::new("before format", 1, &[]).write_to(device.inner_mut()).unwrap();
+
+	let mut fs = SFS::format(device).expect("format failed");
+	fs.init_root_directory().expect("root directory failed");
+	fs.create_file("a.txt").expect("create failed");
+
+	let mut device = fs.unmount();
+	let record = PanicRecord::read_from_device(device.inner_mut()).unwrap().expect("record gone");
+	assert_eq!(record.message(), "before format");
+}
+
+/// a disk formatted on the bare device, the superblock claims the last block too
+fn formatted_whole(blocks: usize) -> MemBlockDevice {
+	let mut fs = SFS::format(MemBlockDevice::new(blocks)).expect("format failed");
+	fs.init_root_directory().expect("root directory failed");
+	fs.create_file("kept.txt").expect("create failed");
+	fs.unmount()
+}
+
+#[test_case]
+fn whole_disk_filesystem_mounts_without_a_record_block() {
+	const BLOCKS: usize = 256;
+
+	assert!(SFS::mount(PanicReserved::new(formatted_whole(BLOCKS))).is_err());
+
+	let device = PanicReserved::whole(formatted_whole(BLOCKS));
+	assert!(!device.has_record_block());
+	assert_eq!(device.capacity(), BLOCKS);
+
+	let fs = SFS::mount(device).expect("mount failed");
+	assert!(fs.open_file("kept.txt").is_ok());
+}
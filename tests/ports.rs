@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use alloc::string::String;
+use blog_os::hw::ports::{self, ClaimError};
+use blog_os::serial::{self, DEFAULT_BAUD};
+use blog_os::virtio::pci::PciConfigIo;
+use virtio_drivers::transport::pci::bus::{ConfigurationAccess, DeviceFunction};
+
+fn report() -> String {
+	let mut out = String::new();
+	ports::report(&mut out).unwrap();
+	out
+}
+
+#[test_case]
+fn init_claims_serial_and_keyboard() {
+	let listing = report();
+
+	assert!(listing.contains("03f8-03ff : serial\n"), "{}", listing);
+	assert!(listing.contains("0060-0064 : ps2\n"), "{}", listing);
+}
+
+#[test_case]
+fn second_keyboard_driver_rejected() {
+	// what a PS/2 mouse driver grabbing the data port on its own would run into
+	assert_eq!(
+		ports::claim(0x60..=0x60, "ps2-mouse").err(),
+		Some(ClaimError::Overlap { owner: "ps2", first: 0x60, last: 0x64 })
+	);
+}
+
+#[test_case]
+fn report_is_sorted_and_forgets_dropped_claims() {
+	let high = ports::claim(0x0700..=0x0703, "test-high").unwrap();
+	let low = ports::claim(0x0200..=0x0201, "test-low").unwrap();
+
+	let listing = report();
+	let low_at = listing.find("0200-0201 : test-low").expect("low claim missing");
+	let high_at = listing.find("0700-0703 : test-high").expect("high claim missing");
+	assert!(low_at < high_at);
+
+	drop(high);
+	drop(low);
+	assert!(!report().contains("test-"));
+}
+
+#[test_case]
+fn serial_still_works_through_the_claim() {
+	// reprograms the divisor latch through the claimed ports, then prints
+	assert_eq!(serial::configure(115200), Ok(()));
+	blog_os::serial_println!("printed at {} baud", serial::baud());
+	assert_eq!(serial::configure(DEFAULT_BAUD), Ok(()));
+	blog_os::serial_println!("back at {} baud", serial::baud());
+}
+
+#[test_case]
+fn pci_config_reads_through_the_claim() {
+	// the host bridge is always at 00:00.0, and it's an Intel one under QEMU
+	let id = PciConfigIo.read_word(DeviceFunction { bus: 0, device: 0, function: 0 }, 0x00);
+	assert_eq!(id & 0xFFFF, 0x8086);
+
+	assert!(report().contains("0cf8-0cff : pci\n"));
+}
@@ -0,0 +1,130 @@
+// in tests/priority_queue.rs
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::task::priority_queue::FixedPriorityQueue;
+use core::panic::PanicInfo;
+
+/// the queue is inline, no heap or interrupts needed
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+/// a priority with a name to tell equal ones apart, only the priority counts for the order
+#[derive(Debug, Clone, Copy)]
+struct Job(u8, char);
+
+impl PartialEq for Job {
+	fn eq(
+		&self,
+		other: &Self,
+	) -> bool {
+		self.0 == other.0
+	}
+}
+
+impl Eq for Job {}
+
+impl PartialOrd for Job {
+	fn partial_cmp(
+		&self,
+		other: &Self,
+	) -> Option<core::cmp::Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for Job {
+	fn cmp(
+		&self,
+		other: &Self,
+	) -> core::cmp::Ordering {
+		self.0.cmp(&other.0)
+	}
+}
+
+#[test_case]
+fn pops_largest_first() {
+	let mut queue: FixedPriorityQueue<u32, 16> = FixedPriorityQueue::new();
+	assert!(queue.is_empty());
+	assert_eq!(queue.pop_max(), None);
+
+	for &n in [5, 1, 9, 3, 7, 9, 0, 4].iter() {
+		assert_eq!(queue.push(n), Ok(()));
+	}
+	assert_eq!(queue.len(), 8);
+	assert_eq!(queue.peek_max(), Some(&9));
+
+	let mut out = [0u32; 8];
+	for slot in out.iter_mut() {
+		*slot = queue.pop_max().unwrap();
+	}
+	assert_eq!(out, [9, 9, 7, 5, 4, 3, 1, 0]);
+	assert!(queue.is_empty());
+}
+
+#[test_case]
+fn equal_priorities_keep_their_order() {
+	let mut queue: FixedPriorityQueue<Job, 8> = FixedPriorityQueue::new();
+	for &job in [Job(1, 'a'), Job(2, 'b'), Job(1, 'c'), Job(2, 'd'), Job(1, 'e')].iter() {
+		queue.push(job).unwrap();
+	}
+
+	let mut names = [' '; 5];
+	for name in names.iter_mut() {
+		*name = queue.pop_max().unwrap().1;
+	}
+	assert_eq!(names, ['b', 'd', 'a', 'c', 'e']);
+
+	// pushing after some pops still goes behind the ones already waiting
+	queue.push(Job(3, 'x')).unwrap();
+	queue.push(Job(3, 'y')).unwrap();
+	queue.push(Job(3, 'z')).unwrap();
+	assert_eq!(queue.pop_max().unwrap().1, 'x');
+	queue.push(Job(3, 'w')).unwrap();
+	assert_eq!(queue.pop_max().unwrap().1, 'y');
+	assert_eq!(queue.pop_max().unwrap().1, 'z');
+	assert_eq!(queue.pop_max().unwrap().1, 'w');
+}
+
+#[test_case]
+fn full_queue_hands_the_item_back() {
+	let mut queue: FixedPriorityQueue<u8, 3> = FixedPriorityQueue::new();
+	assert_eq!(queue.capacity(), 3);
+	assert_eq!(queue.push(1), Ok(()));
+	assert_eq!(queue.push(2), Ok(()));
+	assert_eq!(queue.push(3), Ok(()));
+	assert!(queue.is_full());
+
+	// a bigger item doesn't push anything out either
+	assert_eq!(queue.push(10), Err(10));
+	assert_eq!(queue.len(), 3);
+
+	assert_eq!(queue.pop_max(), Some(3));
+	assert_eq!(queue.push(10), Ok(()));
+	assert_eq!(queue.pop_max(), Some(10));
+
+	queue.clear();
+	assert!(queue.is_empty());
+	assert_eq!(queue.pop_max(), None);
+}
+
+#[test_case]
+fn zero_capacity_is_always_full() {
+	let mut queue: FixedPriorityQueue<u8, 0> = FixedPriorityQueue::new();
+	assert!(queue.is_empty() && queue.is_full());
+	assert_eq!(queue.push(1), Err(1));
+	assert_eq!(queue.peek_max(), None);
+}
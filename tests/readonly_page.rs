@@ -0,0 +1,94 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use blog_os::{QemuExitCode, exit::exit_qemu_or_halt, serial_print, serial_println};
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use x86_64::{
+	VirtAddr,
+	registers::control::{Cr0, Cr0Flags, Cr2},
+	structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+	structures::paging::{Page, PageTableFlags, Translate, mapper::TranslateResult},
+};
+
+/// somewhere nothing else maps
+const TEST_PAGE: u64 = 0x_5555_0000_0000;
+
+entry_point!(main);
+
+/// Maps a page, makes it read-only and writes to it, the page fault handler ends the test
+fn main(boot_info: &'static BootInfo) -> ! {
+	blog_os::init();
+	let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+	let mut mapper = unsafe { memory::init(phys_mem_offset) };
+	let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+	serial_print!("readonly_page::write_faults...\t");
+
+	// without WP ring 0 writes straight through read-only pages
+	assert!(Cr0::read().contains(Cr0Flags::WRITE_PROTECT));
+
+	let page = Page::containing_address(VirtAddr::new(TEST_PAGE));
+	memory::map_range(
+		Page::range_inclusive(page, page),
+		PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+		&mut mapper,
+		&mut frame_allocator,
+	)
+	.expect("mapping the test page failed");
+
+	let ptr = TEST_PAGE as *mut u64;
+	unsafe { ptr.write_volatile(42) };
+
+	memory::make_readonly(page, &mut mapper).expect("make_readonly failed");
+	match mapper.translate(page.start_address()) {
+		TranslateResult::Mapped { flags, .. } => {
+			assert!(flags.contains(PageTableFlags::PRESENT));
+			assert!(!flags.contains(PageTableFlags::WRITABLE));
+		},
+		_ => panic!("the page went away"),
+	}
+	// still readable
+	assert_eq!(unsafe { ptr.read_volatile() }, 42);
+
+	// the test IDT has no timer or keyboard handlers
+	x86_64::instructions::interrupts::disable();
+	TEST_IDT.load();
+	unsafe { ptr.write_volatile(43) };
+
+	serial_println!("[failed]\n");
+	serial_println!("Error: wrote to a read-only page\n");
+	exit_qemu_or_halt(QemuExitCode::Failed);
+}
+
+lazy_static! {
+	static ref TEST_IDT: InterruptDescriptorTable = {
+		let mut idt = InterruptDescriptorTable::new();
+		idt.page_fault.set_handler_fn(test_page_fault_handler);
+		idt
+	};
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+	_stack_frame: InterruptStackFrame,
+	error_code: PageFaultErrorCode,
+) {
+	let expected = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+
+	if error_code.contains(expected) && Cr2::read().as_u64() == TEST_PAGE {
+		serial_println!("[ok]");
+		exit_qemu_or_halt(QemuExitCode::Success);
+	} else {
+		serial_println!("[failed]\n");
+		serial_println!("Error: page fault at {:?} with {:?}\n", Cr2::read(), error_code);
+		exit_qemu_or_halt(QemuExitCode::Failed);
+	}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
@@ -0,0 +1,115 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use alloc::rc::Rc;
+use blog_os::{
+	assert_ok,
+	fs::{
+		block_dev::MemBlockDevice,
+		simple_fs::{FileSystem, SFS},
+	},
+	task::{Task, executor::Executor, sync::AsyncRwLock},
+};
+use core::{
+	cell::Cell,
+	future::Future,
+	pin::Pin,
+	task::{Context, Poll},
+};
+
+/// enough polls for every test here to run until nothing is ready anymore
+const MAX_POLLS: usize = 10_000;
+
+/// Pending once, so the task goes back into the ready queue and the others get a turn
+struct YieldOnce(bool);
+
+impl Future for YieldOnce {
+	type Output = ();
+
+	fn poll(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context,
+	) -> Poll<()> {
+		if self.0 {
+			return Poll::Ready(());
+		}
+		self.0 = true;
+		cx.waker().wake_by_ref();
+		Poll::Pending
+	}
+}
+
+#[test_case]
+fn readers_share_and_the_writer_waits() {
+	let mut fs = assert_ok!(SFS::format(MemBlockDevice::new(64)));
+	assert_ok!(fs.init_root_directory());
+	let handle = assert_ok!(fs.create_file("shared.txt"));
+	assert_ok!(fs.write_file(handle, b"before"));
+
+	let fs = Rc::new(AsyncRwLock::new(fs));
+	let most_readers = Rc::new(Cell::new(0));
+	let reads_done = Rc::new(Cell::new(0u32));
+	let written = Rc::new(Cell::new(false));
+
+	let mut executor = Executor::new();
+	for _ in 0..2 {
+		let (fs, most_readers, reads_done) = (fs.clone(), most_readers.clone(), reads_done.clone());
+		executor.spawn(Task::new(async move {
+			let reader = fs.read().await;
+			most_readers.set(most_readers.get().max(fs.readers()));
+			// held across yields, the other reader gets in meanwhile and the writer doesn't
+			for _ in 0..3 {
+				YieldOnce(false).await;
+				let mut buf = [0u8; 6];
+				assert_ok!(reader.read_file(handle, &mut buf));
+				assert_eq!(&buf, b"before");
+			}
+			assert_eq!(assert_ok!(reader.list_file()), ["shared.txt"]);
+			reads_done.set(reads_done.get() + 1);
+		}));
+	}
+	{
+		let (fs, reads_done, written) = (fs.clone(), reads_done.clone(), written.clone());
+		executor.spawn(Task::new(async move {
+			let mut writer = fs.write().await;
+			assert_eq!(reads_done.get(), 2);
+			assert_ok!(writer.create_file("later.txt"));
+			assert_ok!(writer.write_file(handle, b"after!"));
+			written.set(true);
+		}));
+	}
+	executor.run_polls(MAX_POLLS);
+
+	assert_eq!(most_readers.get(), 2);
+	assert_eq!(reads_done.get(), 2);
+	assert!(written.get());
+
+	let fs = assert_ok!(Rc::try_unwrap(fs).map_err(|_| "still shared")).into_inner();
+	let mut buf = [0u8; 6];
+	assert_ok!(fs.read_file(handle, &mut buf));
+	assert_eq!(&buf, b"after!");
+	assert_eq!(assert_ok!(fs.list_file()), ["shared.txt", "later.txt"]);
+}
@@ -0,0 +1,210 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use alloc::{boxed::Box, rc::Rc, sync::Arc, task::Wake};
+use blog_os::task::{
+	Task,
+	executor::Executor,
+	sync::{Semaphore, WaitQueue},
+};
+use core::{
+	cell::Cell,
+	future::Future,
+	pin::Pin,
+	sync::atomic::{AtomicUsize, Ordering},
+	task::{Context, Poll, Waker},
+};
+
+/// enough polls for every test here to run until nothing is ready anymore
+const MAX_POLLS: usize = 10_000;
+
+/// Pending once, so the task goes back into the ready queue and the others get a turn
+struct YieldOnce(bool);
+
+impl Future for YieldOnce {
+	type Output = ();
+
+	fn poll(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context,
+	) -> Poll<()> {
+		if self.0 {
+			return Poll::Ready(());
+		}
+		self.0 = true;
+		cx.waker().wake_by_ref();
+		Poll::Pending
+	}
+}
+
+#[test_case]
+fn permits_bound_the_holders() {
+	let semaphore = Rc::new(Semaphore::new(2));
+	let holding = Rc::new(Cell::new(0u32));
+	let most = Rc::new(Cell::new(0u32));
+	let finished = Rc::new(Cell::new(0u32));
+
+	let mut executor = Executor::new();
+	for _ in 0..6 {
+		let (semaphore, holding, most, finished) =
+			(semaphore.clone(), holding.clone(), most.clone(), finished.clone());
+		executor.spawn(Task::new(async move {
+			semaphore.acquire().await;
+			holding.set(holding.get() + 1);
+			most.set(most.get().max(holding.get()));
+			for _ in 0..3 {
+				YieldOnce(false).await;
+			}
+			holding.set(holding.get() - 1);
+			semaphore.release();
+			finished.set(finished.get() + 1);
+		}));
+	}
+	executor.run_polls(MAX_POLLS);
+
+	assert_eq!(finished.get(), 6);
+	assert_eq!(most.get(), 2);
+	assert_eq!(semaphore.available(), 2);
+}
+
+/// counts how often it was woken
+struct CountingWaker(AtomicUsize);
+
+impl Wake for CountingWaker {
+	fn wake(self: Arc<Self>) {
+		self.0.fetch_add(1, Ordering::Relaxed);
+	}
+}
+
+fn counting_waker() -> (Arc<CountingWaker>, Waker) {
+	let wakes = Arc::new(CountingWaker(AtomicUsize::new(0)));
+	(wakes.clone(), Waker::from(wakes))
+}
+
+#[test_case]
+fn release_wakes_the_oldest_waiter() {
+	let semaphore = Semaphore::new(0);
+	let (first_wakes, first_waker) = counting_waker();
+	let (second_wakes, second_waker) = counting_waker();
+
+	let mut first = Box::pin(semaphore.acquire());
+	let mut second = Box::pin(semaphore.acquire());
+	assert_eq!(first.as_mut().poll(&mut Context::from_waker(&first_waker)), Poll::Pending);
+	assert_eq!(second.as_mut().poll(&mut Context::from_waker(&second_waker)), Poll::Pending);
+	// polled again before anything happened, it keeps its place in the queue
+	assert_eq!(first.as_mut().poll(&mut Context::from_waker(&first_waker)), Poll::Pending);
+
+	semaphore.release();
+	assert_eq!(first_wakes.0.load(Ordering::Relaxed), 1);
+	assert_eq!(second_wakes.0.load(Ordering::Relaxed), 0);
+	assert_eq!(first.as_mut().poll(&mut Context::from_waker(&first_waker)), Poll::Ready(()));
+	assert_eq!(semaphore.available(), 0);
+
+	semaphore.release();
+	assert_eq!(second_wakes.0.load(Ordering::Relaxed), 1);
+	assert_eq!(second.as_mut().poll(&mut Context::from_waker(&second_waker)), Poll::Ready(()));
+}
+
+#[test_case]
+fn dropped_waiter_passes_the_wakeup_on() {
+	let semaphore = Semaphore::new(0);
+	let (_, first_waker) = counting_waker();
+	let (second_wakes, second_waker) = counting_waker();
+
+	let mut first = Box::pin(semaphore.acquire());
+	let mut second = Box::pin(semaphore.acquire());
+	assert_eq!(first.as_mut().poll(&mut Context::from_waker(&first_waker)), Poll::Pending);
+	assert_eq!(second.as_mut().poll(&mut Context::from_waker(&second_waker)), Poll::Pending);
+
+	// the release wakes the first, which goes away without taking the permit
+	semaphore.release();
+	drop(first);
+	assert_eq!(second_wakes.0.load(Ordering::Relaxed), 1);
+	assert_eq!(second.as_mut().poll(&mut Context::from_waker(&second_waker)), Poll::Ready(()));
+
+	// a parked waiter that's dropped leaves nothing behind for a release to wake
+	let mut third = Box::pin(semaphore.acquire());
+	assert_eq!(third.as_mut().poll(&mut Context::from_waker(&first_waker)), Poll::Pending);
+	drop(third);
+	assert!(!semaphore.try_acquire());
+	semaphore.release();
+	assert!(semaphore.try_acquire());
+}
+
+#[test_case]
+fn wait_until_returns_once_the_condition_holds() {
+	let queue = Rc::new(WaitQueue::new());
+	let ready = Rc::new(Cell::new(false));
+	let finished = Rc::new(Cell::new(0u32));
+
+	let mut executor = Executor::new();
+	for _ in 0..2 {
+		let (queue, ready, finished) = (queue.clone(), ready.clone(), finished.clone());
+		executor.spawn(Task::new(async move {
+			queue.wait_until(|| ready.get()).await;
+			finished.set(finished.get() + 1);
+		}));
+	}
+	executor.run_polls(MAX_POLLS);
+	assert_eq!(queue.waiting(), 2);
+
+	// woken for nothing, they look and park again
+	queue.wake_all();
+	executor.run_polls(MAX_POLLS);
+	assert_eq!(finished.get(), 0);
+	assert_eq!(queue.waiting(), 2);
+
+	ready.set(true);
+	queue.wake_all();
+	executor.run_polls(MAX_POLLS);
+	assert_eq!(finished.get(), 2);
+	assert_eq!(queue.waiting(), 0);
+}
+
+#[test_case]
+fn wake_one_wakes_the_oldest_waiter() {
+	let queue = WaitQueue::new();
+	let (first_wakes, first_waker) = counting_waker();
+	let (second_wakes, second_waker) = counting_waker();
+
+	let mut first = Box::pin(queue.wait_until(|| false));
+	let mut second = Box::pin(queue.wait_until(|| false));
+	assert_eq!(first.as_mut().poll(&mut Context::from_waker(&first_waker)), Poll::Pending);
+	assert_eq!(second.as_mut().poll(&mut Context::from_waker(&second_waker)), Poll::Pending);
+	// polled again, still the one in front
+	assert_eq!(first.as_mut().poll(&mut Context::from_waker(&first_waker)), Poll::Pending);
+	assert_eq!(queue.waiting(), 2);
+
+	queue.wake_one();
+	assert_eq!(first_wakes.0.load(Ordering::Relaxed), 1);
+	assert_eq!(second_wakes.0.load(Ordering::Relaxed), 0);
+
+	// a dropped waiter leaves nothing behind
+	drop(second);
+	assert_eq!(queue.waiting(), 0);
+	queue.wake_one();
+	assert_eq!(second_wakes.0.load(Ordering::Relaxed), 0);
+}
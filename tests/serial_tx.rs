@@ -0,0 +1,96 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use alloc::format;
+use blog_os::{interrupts, serial, serial_print, serial_println};
+
+/// more than the UART sends in a couple of ticks, less than the ring holds
+const LINES: usize = 48;
+const LINE_LEN: usize = 64;
+
+/// the letters of line `i`, different for every line so a swapped one shows
+fn payload(i: usize) -> [u8; LINE_LEN] {
+	let mut line = [0u8; LINE_LEN];
+	for (j, byte) in line.iter_mut().enumerate() {
+		*byte = b'a' + ((i * 7 + j) % 26) as u8;
+	}
+	line
+}
+
+/// ticks `print` took for every line
+fn ticks_for(mut print: impl FnMut(usize, &str)) -> u64 {
+	serial::flush();
+	let start = interrupts::ticks();
+	for i in 0..LINES {
+		let line = payload(i);
+		print(i, core::str::from_utf8(&line).unwrap());
+	}
+	interrupts::ticks() - start
+}
+
+#[test_case]
+fn queued_output_doesnt_wait_for_the_uart() {
+	let dropped = serial::tx_dropped();
+
+	serial_println!();
+	let polled =
+		ticks_for(|i, line| serial::print_polled(format_args!("tx-sync {:04} {}\n", i, line)));
+	let queued = ticks_for(|i, line| serial_println!("tx-queued {:04} {}", i, line));
+	serial::flush();
+
+	serial_println!("tx-timing polled={} queued={} ticks", polled, queued);
+	assert!(queued <= polled);
+	assert!(queued <= 1, "queueing {} lines took {} ticks", LINES, queued);
+	assert_eq!(serial::tx_dropped(), dropped);
+}
+
+/// Every `tx-check` line and a hash over them, scripts/check_serial_tx.sh hashes what reached
+/// the host and compares
+///
+/// The hash is `h = h * 31 + byte` over each line with its `\n`, mod 2^32, so a lost byte and
+/// two bytes swapped both change it.
+#[test_case]
+fn queued_output_arrives_whole_and_in_order() {
+	let dropped = serial::tx_dropped();
+	let mut hash: u32 = 0;
+	let mut add = |bytes: &[u8]| {
+		for &byte in bytes {
+			hash = hash.wrapping_mul(31).wrapping_add(byte as u32);
+		}
+	};
+
+	serial_println!();
+	for i in 0..LINES {
+		let letters = payload(i);
+		let line = format!("tx-check {:04} {}\n", i, core::str::from_utf8(&letters).unwrap());
+		serial_print!("{}", line);
+		add(line.as_bytes());
+	}
+	serial_println!("tx-check-end lines={} hash={}", LINES, hash);
+	serial::flush();
+
+	assert_eq!(serial::tx_dropped(), dropped);
+}
@@ -0,0 +1,432 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use blog_os::fs::{
+	block_dev::MemBlockDevice,
+	simple_fs::{FileSystem, SFS},
+};
+use blog_os::shell::run_command;
+use blog_os::task::{Priority, Task, executor::Executor};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+fn run(
+	line: &str,
+	fs: Option<&mut dyn FileSystem>,
+) -> String {
+	let mut out = String::new();
+	run_command(line, fs, &mut out).unwrap();
+	out
+}
+
+#[test_case]
+fn mem_reports_heap_usage() {
+	let before = blog_os::allocator::allocator_stats();
+	let kept: Vec<Box<u64>> = (0..10).map(Box::new).collect();
+	let after = blog_os::allocator::allocator_stats();
+
+	assert!(after.allocations >= before.allocations + 10);
+	assert!(after.used > before.used);
+	assert_eq!(after.heap_size, blog_os::allocator::HEAP_SIZE);
+	drop(kept);
+
+	let out = run("mem", None);
+	assert!(out.starts_with("heap: "), "{}", out);
+	assert!(out.contains(" of 102400 bytes used"), "{}", out);
+}
+
+#[test_case]
+fn tasks_lists_unfinished_tasks() {
+	let mut executor = Executor::new();
+	let task = Task::with_priority(Priority::LOW, core::future::pending());
+	let id = task.id();
+	executor.spawn(task);
+	executor.run_polls(10);
+
+	let out = run("tasks", None);
+	assert!(out.contains(&alloc::format!("task {:>4}  priority {}", id, Priority::LOW)), "{}", out);
+
+	// gone once its executor is
+	drop(executor);
+	assert!(!run("tasks", None).contains(&alloc::format!("task {:>4} ", id)));
+}
+
+#[test_case]
+fn nice_changes_a_priority() {
+	let mut executor = Executor::new();
+	let task = Task::new(core::future::pending());
+	let id = task.id();
+	executor.spawn(task);
+	executor.run_polls(10);
+
+	assert_eq!(
+		run(&alloc::format!("nice {} high", id), None),
+		alloc::format!("task {} gets priority 192\n", id)
+	);
+	// applied on the executor's next poll
+	executor.run_polls(1);
+	let info =
+		blog_os::task::executor::list_tasks().into_iter().find(|task| task.id == id).unwrap();
+	assert_eq!(info.priority, Priority::HIGH);
+
+	assert_eq!(
+		run(&alloc::format!("nice {} 256", id), None),
+		"nice: 256: not a priority, try high or 0 to 255\n"
+	);
+	assert_eq!(run("nice x low", None), "nice: x: not a task id\n");
+	assert_eq!(run("nice 999999 low", None), "nice: no task 999999\n");
+}
+
+#[test_case]
+fn ls_lists_files() {
+	let mut fs = SFS::format(MemBlockDevice::new(64)).expect("format failed");
+	fs.init_root_directory().expect("root directory init failed");
+	fs.create_file("notes.txt").unwrap();
+
+	assert!(run("ls", Some(&mut fs)).lines().any(|name| name == "notes.txt"));
+	assert_eq!(run("ls", None), "ls: no filesystem mounted\n");
+}
+
+#[test_case]
+fn ls_r_and_find_walk_the_disk() {
+	let mut fs = SFS::format(MemBlockDevice::new(64)).expect("format failed");
+	fs.init_root_directory().expect("root directory init failed");
+	let notes = fs.create_file("notes.txt").unwrap();
+	fs.write_file(notes, b"four").unwrap();
+	fs.create_file("keymap.bin").unwrap();
+
+	assert_eq!(run("ls -R", Some(&mut fs)), "/\n  notes.txt  4 bytes\n  keymap.bin  0 bytes\n");
+	assert_eq!(run("ls -R /notes.txt", Some(&mut fs)), "notes.txt  4 bytes\n");
+	assert_eq!(run("ls -R /nope", Some(&mut fs)), "ls: /nope: FileNotFound\n");
+
+	assert_eq!(run("find *.txt", Some(&mut fs)), "/notes.txt\n");
+	assert_eq!(run("find / [a-m]*", Some(&mut fs)), "/keymap.bin\n");
+	assert_eq!(run("find /* ", Some(&mut fs)), "/notes.txt\n/keymap.bin\n");
+	assert_eq!(run("find nothing", Some(&mut fs)), "");
+}
+
+static NOW: AtomicU64 = AtomicU64::new(0);
+
+fn test_clock() -> u64 {
+	NOW.load(Ordering::Relaxed)
+}
+
+#[test_case]
+fn ls_l_and_stat_show_the_times() {
+	NOW.store(10, Ordering::Relaxed);
+	let mut fs = SFS::format(MemBlockDevice::new(64)).expect("format failed");
+	fs.set_clock(test_clock);
+	fs.init_root_directory().expect("root directory init failed");
+	let notes = fs.create_file("notes.txt").unwrap();
+	NOW.store(20, Ordering::Relaxed);
+	fs.write_file(notes, b"four").unwrap();
+	// only noted in memory, both still show it
+	NOW.store(30, Ordering::Relaxed);
+	fs.read_file(notes, &mut [0u8; 4]).unwrap();
+
+	assert_eq!(
+		run("ls -l", Some(&mut fs)),
+		"       4  modified     20s  accessed     30s  notes.txt\n"
+	);
+	assert_eq!(
+		run("stat /notes.txt", Some(&mut fs)),
+		alloc::format!(
+			"  file: /notes.txt\n inode: {}\n  size: 4 bytes\naccess: 30s\nmodify: 20s\nchange: 10s\n",
+			notes.0
+		)
+	);
+	assert_eq!(run("stat nope", Some(&mut fs)), "stat: nope: FileNotFound\n");
+	assert_eq!(run("stat notes.txt", None), "stat: no filesystem mounted\n");
+}
+
+#[test_case]
+fn rm_wants_r_for_a_directory() {
+	let mut fs = SFS::format(MemBlockDevice::new(64)).expect("format failed");
+	fs.init_root_directory().expect("root directory init failed");
+	for name in ["a", "b", "c"] {
+		let handle = fs.create_file(name).unwrap();
+		fs.write_file(handle, &[1u8; 600]).unwrap();
+	}
+
+	assert_eq!(run("rm a", Some(&mut fs)), "");
+	assert_eq!(run("rm a", Some(&mut fs)), "rm: a: FileNotFound\n");
+	assert_eq!(run("rm /", Some(&mut fs)), "rm: /: is a directory, use -r\n");
+	assert_eq!(fs.list_file().unwrap().len(), 2);
+
+	assert_eq!(run("rm -r /", Some(&mut fs)), "removed 2 files\n");
+	assert!(fs.list_file().unwrap().is_empty());
+	assert_eq!(run("ls -R", Some(&mut fs)), "/\n");
+	assert!(fs.fsck().unwrap().is_clean());
+}
+
+#[test_case]
+fn uptime_and_ioports() {
+	assert!(run("uptime", None).starts_with("up "));
+	assert!(run("ioports", None).contains("03f8-03ff : serial"));
+}
+
+#[test_case]
+fn trace_commands_toggle_tracing() {
+	assert_eq!(run("trace exec on", None), "executor tracing on\n");
+	assert!(blog_os::task::trace::enabled());
+	assert_eq!(run("trace exec off", None), "executor tracing off\n");
+	assert!(!blog_os::task::trace::enabled());
+	assert!(run("trace exec dump", None).starts_with("tick,task,event,cycles\n"));
+}
+
+#[test_case]
+fn unknown_command_is_reported() {
+	assert_eq!(run("  frobnicate now ", None), "unknown command: frobnicate now, try help\n");
+	assert_eq!(run("trace exec", None), "unknown command: trace exec, try help\n");
+}
+
+use blog_os::shell::line_editor::{
+	Action, HISTORY_LEN, Key, LineEditor, ReadLineError, Redraw, SerialKeys, read_line,
+};
+use futures_util::{FutureExt, stream};
+
+fn type_str(
+	editor: &mut LineEditor,
+	s: &str,
+) {
+	for c in s.chars() {
+		editor.feed(Key::Char(c));
+	}
+}
+
+fn submit(
+	editor: &mut LineEditor,
+	s: &str,
+) {
+	type_str(editor, s);
+	assert_eq!(editor.feed(Key::Enter), Action::Submit(String::from(s)));
+}
+
+#[test_case]
+fn editor_edits_at_the_cursor() {
+	let mut editor = LineEditor::new(64);
+	type_str(&mut editor, "hllo");
+	editor.feed(Key::Home);
+	editor.feed(Key::Right);
+	editor.feed(Key::Char('e'));
+	assert_eq!((editor.text().as_str(), editor.cursor()), ("hello", 2));
+
+	editor.feed(Key::End);
+	editor.feed(Key::Backspace);
+	assert_eq!((editor.text().as_str(), editor.cursor()), ("hell", 4));
+
+	editor.feed(Key::Home);
+	editor.feed(Key::Delete);
+	assert_eq!((editor.text().as_str(), editor.cursor()), ("ell", 0));
+
+	// nothing to do at the edges
+	assert_eq!(editor.feed(Key::Left), Action::None);
+	assert_eq!(editor.feed(Key::Backspace), Action::None);
+	editor.feed(Key::End);
+	assert_eq!(editor.feed(Key::Right), Action::None);
+	assert_eq!(editor.feed(Key::Delete), Action::None);
+	assert_eq!((editor.text().as_str(), editor.cursor()), ("ell", 3));
+}
+
+#[test_case]
+fn editor_caps_the_line_length() {
+	let mut editor = LineEditor::new(4);
+	type_str(&mut editor, "abcdef");
+	assert_eq!((editor.text().as_str(), editor.cursor()), ("abcd", 4));
+
+	editor.feed(Key::Home);
+	assert_eq!(editor.feed(Key::Char('x')), Action::None);
+	assert_eq!((editor.text().as_str(), editor.cursor()), ("abcd", 0));
+}
+
+#[test_case]
+fn editor_walks_the_history() {
+	let mut editor = LineEditor::new(64);
+	submit(&mut editor, "one");
+	submit(&mut editor, "two");
+	type_str(&mut editor, "thr");
+
+	editor.feed(Key::Up);
+	assert_eq!((editor.text().as_str(), editor.cursor()), ("two", 3));
+	editor.feed(Key::Up);
+	assert_eq!(editor.text(), "one");
+	assert_eq!(editor.feed(Key::Up), Action::None);
+
+	editor.feed(Key::Down);
+	assert_eq!(editor.text(), "two");
+	// back to what was being typed
+	editor.feed(Key::Down);
+	assert_eq!((editor.text().as_str(), editor.cursor()), ("thr", 3));
+	assert_eq!(editor.feed(Key::Down), Action::None);
+}
+
+#[test_case]
+fn edited_history_entry_stays_in_slot_zero() {
+	let mut editor = LineEditor::new(64);
+	submit(&mut editor, "ls");
+	submit(&mut editor, "mem");
+
+	editor.feed(Key::Up);
+	editor.feed(Key::Up);
+	editor.feed(Key::Char('x'));
+	assert_eq!(editor.text(), "lsx");
+
+	// the entries themselves are untouched, Up starts over at the newest one
+	editor.feed(Key::Up);
+	assert_eq!(editor.text(), "mem");
+	editor.feed(Key::Down);
+	assert_eq!((editor.text().as_str(), editor.cursor()), ("lsx", 3));
+	assert!(editor.history().eq(["ls", "mem"]));
+}
+
+#[test_case]
+fn history_keeps_the_last_commands() {
+	let mut editor = LineEditor::new(64);
+	for i in 0..HISTORY_LEN + 8 {
+		submit(&mut editor, &alloc::format!("cmd {}", i));
+	}
+	assert_eq!(editor.history().count(), HISTORY_LEN);
+	assert_eq!(editor.history().next(), Some("cmd 8"));
+
+	// blank lines and repeats in a row aren't kept
+	submit(&mut editor, "  ");
+	submit(&mut editor, "again");
+	submit(&mut editor, "again");
+	let history: Vec<&str> = editor.history().collect();
+	assert_eq!(history.len(), HISTORY_LEN);
+	assert_eq!(
+		history[HISTORY_LEN - 2..],
+		[alloc::format!("cmd {}", HISTORY_LEN + 7).as_str(), "again"]
+	);
+}
+
+#[test_case]
+fn ctrl_c_drops_the_line_and_ctrl_l_keeps_it() {
+	let mut editor = LineEditor::new(64);
+	type_str(&mut editor, "abc");
+	assert_eq!(editor.feed(Key::ClearScreen), Action::ClearScreen);
+	assert_eq!(editor.text(), "abc");
+
+	assert_eq!(editor.feed(Key::Cancel), Action::Cancel);
+	assert_eq!((editor.text().as_str(), editor.cursor()), ("", 0));
+	assert_eq!(editor.history().count(), 0);
+}
+
+#[test_case]
+fn read_line_stops_at_ctrl_c() {
+	let mut editor = LineEditor::new(64);
+	let mut keys = stream::iter([Key::Char('a'), Key::Char('b'), Key::Cancel, Key::Char('c')]);
+	let mut redraws = 0;
+
+	let line = read_line(&mut editor, &mut keys, |_, _| redraws += 1).now_or_never();
+	assert_eq!(line, Some(Err(ReadLineError::Interrupted)));
+	assert_eq!(redraws, 2);
+	assert_eq!(editor.text(), "");
+
+	// the key after Ctrl+C starts the next line
+	let mut rest = stream::iter([Key::Char('d'), Key::Enter]);
+	assert_eq!(
+		read_line(&mut editor, &mut keys, |_, _| {}).now_or_never(),
+		Some(Err(ReadLineError::Closed))
+	);
+	assert_eq!(editor.text(), "c");
+	assert_eq!(
+		read_line(&mut editor, &mut rest, |_, _| {}).now_or_never(),
+		Some(Ok(String::from("cd")))
+	);
+}
+
+#[test_case]
+fn serial_escape_sequences_decode_to_keys() {
+	fn feed(
+		editor: &mut LineEditor,
+		keys: &mut SerialKeys,
+		bytes: &[u8],
+	) {
+		for &byte in bytes {
+			if let Some(key) = keys.feed(byte) {
+				editor.feed(key);
+			}
+		}
+	}
+
+	let mut editor = LineEditor::new(64);
+	let mut keys = SerialKeys::new();
+
+	// left twice, insert, then DEL and Delete as ESC [ 3 ~
+	feed(&mut editor, &mut keys, b"abc\x1b[D\x1b[Dx\x7f\x1b[3~");
+	// Home the xterm way, End the vt220 way, and a sequence nobody knows
+	feed(&mut editor, &mut keys, b"\x1bOHy\x1b[4~z\x1b[99q");
+	assert_eq!(editor.text(), "yacz");
+
+	feed(&mut editor, &mut keys, b"\r\x1b[A");
+	assert_eq!((editor.text().as_str(), editor.cursor()), ("yacz", 4));
+}
+
+#[test_case]
+fn keyboard_events_decode_to_keys() {
+	use blog_os::task::keyboard::{KeyEvent, Modifiers};
+	use pc_keyboard::{DecodedKey, KeyCode};
+
+	let plain = |key| KeyEvent { key, modifiers: Modifiers::default() };
+	let ctrl = |key| KeyEvent { key, modifiers: Modifiers { ctrl: true, ..Modifiers::default() } };
+
+	assert_eq!(Key::from_event(&plain(DecodedKey::RawKey(KeyCode::ArrowUp))), Some(Key::Up));
+	assert_eq!(Key::from_event(&plain(DecodedKey::RawKey(KeyCode::Home))), Some(Key::Home));
+	assert_eq!(Key::from_event(&plain(DecodedKey::Unicode('\n'))), Some(Key::Enter));
+	assert_eq!(Key::from_event(&plain(DecodedKey::Unicode('\u{8}'))), Some(Key::Backspace));
+	assert_eq!(Key::from_event(&plain(DecodedKey::Unicode('q'))), Some(Key::Char('q')));
+	assert_eq!(Key::from_event(&ctrl(DecodedKey::Unicode('\u{3}'))), Some(Key::Cancel));
+	assert_eq!(Key::from_event(&ctrl(DecodedKey::Unicode('\u{c}'))), Some(Key::ClearScreen));
+	assert_eq!(Key::from_event(&plain(DecodedKey::RawKey(KeyCode::F1))), None);
+}
+
+#[test_case]
+fn serial_redraw_repaints_only_the_tail() {
+	let mut editor = LineEditor::new(64);
+	type_str(&mut editor, "abc");
+	editor.feed(Key::Left);
+	editor.feed(Key::Left);
+
+	let redraw = match editor.feed(Key::Char('x')) {
+		Action::Redraw(redraw) => redraw,
+		other => panic!("expected a redraw, got {:?}", other),
+	};
+	assert_eq!(redraw, Redraw { from: 1, old_cursor: 1, old_len: 3 });
+
+	let mut out = String::new();
+	editor.render_serial(redraw, &mut out).unwrap();
+	assert_eq!(out, "xbc\x08\x08");
+
+	// a shorter line blanks out what's left of the old one
+	let redraw = match editor.feed(Key::Backspace) {
+		Action::Redraw(redraw) => redraw,
+		other => panic!("expected a redraw, got {:?}", other),
+	};
+	out.clear();
+	editor.render_serial(redraw, &mut out).unwrap();
+	assert_eq!(out, "\x08bc \x08\x08\x08");
+}
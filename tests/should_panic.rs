@@ -7,7 +7,7 @@
 #![reexport_test_harness_main = "test_main"]
 
 use core::panic::PanicInfo;
-use blog_os::{QemuExitCode, exit_qemu, serial_println, serial_print};
+use blog_os::{QemuExitCode, exit::exit_qemu_or_halt, serial_println, serial_print};
 
 
 /// panic handler for should_panic tests
@@ -16,9 +16,7 @@ fn panic(_info: &PanicInfo) -> ! {
 
     // any function within this module that reaches the panic handler is a correct function!
     serial_println!("[ok]");
-    exit_qemu(QemuExitCode::Success);
-
-    loop{}
+    exit_qemu_or_halt(QemuExitCode::Success);
 }
 
 
@@ -27,14 +25,15 @@ pub fn test_runner(tests: &[&dyn Fn()])
 {
     serial_println!("Running {} tests..", tests.len());
 
-    for test in tests
+    // the panic handler ends the run, so whatever comes back from its test didn't panic
+    if let Some(test) = tests.first()
     {
         test();
         serial_println!("[test did not panic]");
-        exit_qemu(QemuExitCode::Failed);
+        exit_qemu_or_halt(QemuExitCode::Failed);
     }
 
-    exit_qemu(QemuExitCode::Success);
+    exit_qemu_or_halt(QemuExitCode::Success);
 }
 
 #[test_case]
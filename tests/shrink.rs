@@ -0,0 +1,175 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use alloc::{
+	alloc::{alloc, dealloc},
+	vec::Vec,
+};
+use blog_os::{
+	allocator::{HEAP_SIZE, ShrinkerStats, shrink::in_shrinker, shrinker_stats},
+	assert_ok,
+	fs::{
+		block_cache::CachedDevice,
+		block_dev::{BlockDevice, MemBlockDevice},
+		layout::BLOCK_SIZE,
+		simple_fs::{FileSystem, SFS},
+	},
+	kassert::failed_assertions,
+};
+use core::alloc::Layout;
+
+/// as big as a cached block, so a block the cache lets go of makes room for exactly one
+const BALLAST: Layout = match Layout::from_size_align(BLOCK_SIZE, 8) {
+	Ok(layout) => layout,
+	Err(_) => panic!("bad ballast layout"),
+};
+
+/// Heap blocks that fill whatever the heap has left
+struct Ballast(Vec<*mut u8>);
+
+impl Ballast {
+	/// allocates until the heap says no, the shrinkers have had their turn by then
+	fn fill() -> Self {
+		let mut blocks = Vec::with_capacity(HEAP_SIZE / BALLAST.size() + 1);
+		loop {
+			let block = unsafe { alloc(BALLAST) };
+			if block.is_null() {
+				return Ballast(blocks);
+			}
+			blocks.push(block);
+		}
+	}
+
+	/// gives the last `count` blocks back
+	fn release(
+		&mut self,
+		count: usize,
+	) {
+		for _ in 0..count {
+			let block = self.0.pop().expect("not that much ballast");
+			unsafe { dealloc(block, BALLAST) };
+		}
+	}
+}
+
+impl Drop for Ballast {
+	fn drop(&mut self) {
+		let count = self.0.len();
+		self.release(count);
+	}
+}
+
+fn stats_of(name: &str) -> ShrinkerStats {
+	shrinker_stats()
+		.into_iter()
+		.find(|stats| stats.name == name)
+		.unwrap_or_else(|| panic!("no shrinker called {}", name))
+}
+
+#[test_case]
+fn full_heap_clears_the_directory_index() {
+	let asserts = failed_assertions();
+	let mut fs = assert_ok!(SFS::format(MemBlockDevice::new(64)));
+	assert_ok!(fs.init_root_directory());
+	for name in ["alpha", "beta", "gamma", "delta"] {
+		assert_ok!(fs.create_file(name));
+	}
+	assert_ok!(fs.open_file("beta"));
+	let misses = fs.stats().dir_index_misses;
+	let before = stats_of("directory index");
+
+	let ballast = Ballast::fill();
+	drop(ballast);
+
+	let after = stats_of("directory index");
+	assert!(after.invocations > before.invocations);
+	assert!(after.reclaimed > before.reclaimed);
+
+	// rebuilt by the next lookup
+	assert_ok!(fs.open_file("gamma"));
+	assert_eq!(fs.stats().dir_index_misses, misses + 1);
+	assert_ok!(fs.open_file("delta"));
+	assert_eq!(fs.stats().dir_index_misses, misses + 1);
+
+	assert_eq!(failed_assertions(), asserts);
+}
+
+#[test_case]
+fn full_heap_sheds_clean_cache_blocks() {
+	const CACHED: u64 = 3;
+
+	let asserts = failed_assertions();
+	let mut cache = CachedDevice::with_capacity(MemBlockDevice::new(16), 16);
+	let mut block = [0u8; BLOCK_SIZE];
+
+	// room for the cached blocks and a bit for the cache's tree, nothing more
+	let mut ballast = Ballast::fill();
+	ballast.release(CACHED as usize + 1);
+	for id in 0..CACHED {
+		cache.read_blocks(id, &mut block).unwrap();
+	}
+	assert_eq!(cache.stats().device_reads, CACHED);
+
+	let blocks_before = stats_of("block cache");
+	let index_before = stats_of("directory index");
+
+	// doesn't fit until the cache lets go of a block
+	let squeezed = unsafe { alloc(BALLAST) };
+	assert!(!squeezed.is_null());
+	unsafe { dealloc(squeezed, BALLAST) };
+	drop(ballast);
+
+	let blocks_after = stats_of("block cache");
+	assert_eq!(blocks_after.invocations, blocks_before.invocations + 1);
+	assert!(blocks_after.reclaimed >= blocks_before.reclaimed + BLOCK_SIZE as u64);
+	// one shrinker was enough, the next one wasn't asked
+	assert_eq!(stats_of("directory index"), index_before);
+
+	// block 0 was the least recently used, the others are still cached
+	for id in (1..CACHED).chain(0..1) {
+		cache.read_blocks(id, &mut block).unwrap();
+	}
+	assert_eq!(cache.stats().device_reads, CACHED + 1);
+
+	assert!(!in_shrinker());
+	assert_eq!(failed_assertions(), asserts);
+}
+
+#[test_case]
+fn dirty_blocks_survive_shrinking() {
+	let mut cache = CachedDevice::with_capacity(MemBlockDevice::new(16), 16);
+	cache.write_blocks(5, &[0xAB; BLOCK_SIZE]).unwrap();
+
+	let ballast = Ballast::fill();
+	drop(ballast);
+
+	// the write is still only in the cache, and it's all there
+	assert_eq!(cache.dirty_state().blocks, 1);
+	let mut device = cache.into_inner().unwrap();
+	let mut block = [0u8; BLOCK_SIZE];
+	device.read_blocks(5, &mut block).unwrap();
+	assert!(block.iter().all(|&b| b == 0xAB));
+}
@@ -0,0 +1,1220 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use alloc::{format, vec::Vec};
+use blog_os::fs::{
+	block_dev::{BlockDevice, MemBlockDevice},
+	dir_index::{DirIndex, DirIndexCache, DirSlot, fnv1a},
+	image::{self, ImageError},
+	layout::{
+		BITS_PER_BITMAP_BLOCK, BLOCK_SIZE, DIR_ENTRIES_PER_BLOCK, DIR_NAME_MAX, DIRENT_USED,
+		DiskDirEntry, DiskInode, DiskSuperBlock, FileType, Inode, SUPERBLOCK_DIRTY,
+		SUPERBLOCK_VERSION, SuperBlock,
+	},
+	simple_fs::{
+		FileError, FileHandler, FileSystem, FileSystemError, FormatOptions, INODE_CACHE_LEN,
+		MAX_INODE_RATIO_PERCENT, ROOT_DIRECTORY_INODE, SFS,
+	},
+};
+use blog_os::task::trace;
+use blog_os::{assert_err, assert_ok, serial_println};
+use core::convert::TryFrom;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use zerocopy::{FromBytes, IntoBytes, U16, U64};
+
+/// small enough for the test heap, large enough for a few files
+const TEST_BLOCKS: usize = 64;
+
+const MIB: usize = 1024 * 1024;
+
+fn fresh_fs() -> SFS<MemBlockDevice> {
+	let mut fs = assert_ok!(SFS::format(MemBlockDevice::new(TEST_BLOCKS)));
+	assert_ok!(fs.init_root_directory());
+	fs
+}
+
+#[test_case]
+fn open_finds_created_files() {
+	let mut fs = fresh_fs();
+
+	let a = assert_ok!(fs.create_file("a.txt"));
+	let b = assert_ok!(fs.create_file("b.txt"));
+
+	assert_eq!(assert_ok!(fs.open_file("a.txt")).0, a.0);
+	assert_eq!(assert_ok!(fs.open_file("b.txt")).0, b.0);
+	assert_err!(fs.open_file("c.txt"), FileError::FileNotFound);
+
+	// the first lookup built the index, everything after that is served from it
+	let stats = fs.stats();
+	assert_eq!(stats.dir_index_misses, 1);
+	assert!(stats.dir_index_hits >= 3);
+}
+
+#[test_case]
+fn create_rejects_duplicate_names() {
+	let mut fs = fresh_fs();
+
+	assert_ok!(fs.create_file("dup"));
+	assert!(fs.create_file("dup").is_err());
+}
+
+#[test_case]
+fn index_rebuilt_lazily_after_mount() {
+	let mut fs = fresh_fs();
+	let handle = assert_ok!(fs.create_file("persist.txt"));
+
+	let mut fs = assert_ok!(SFS::mount(fs.unmount()));
+	assert_eq!(fs.stats().dir_index_misses, 0);
+
+	assert_eq!(assert_ok!(fs.open_file("persist.txt")).0, handle.0);
+	assert_eq!(fs.stats().dir_index_misses, 1);
+
+	assert_ok!(fs.open_file("persist.txt"));
+	assert_eq!(fs.stats().dir_index_misses, 1);
+}
+
+#[test_case]
+fn colliding_hashes_keep_every_candidate() {
+	let mut index = DirIndex::new();
+	let first = DirSlot { block: 10, slot: 2 };
+	let second = DirSlot { block: 11, slot: 5 };
+
+	// pretend both names hash the same
+	index.insert(42, first);
+	index.insert(42, second);
+	assert_eq!(index.candidates(42), &[first, second]);
+
+	index.remove(42, first);
+	assert_eq!(index.candidates(42), &[second]);
+	assert!(index.candidates(fnv1a(b"missing")).is_empty());
+}
+
+#[test_case]
+fn least_recently_used_directory_evicted() {
+	let mut cache = DirIndexCache::new(2);
+
+	let mut dir_one = DirIndex::new();
+	dir_one.insert(1, DirSlot { block: 1, slot: 0 });
+	cache.insert_dir(1, dir_one);
+
+	let mut dir_two = DirIndex::new();
+	dir_two.insert(2, DirSlot { block: 2, slot: 0 });
+	cache.insert_dir(2, dir_two);
+
+	// touch dir 1 so dir 2 becomes the eviction candidate
+	assert!(cache.get(1).is_some());
+
+	let mut dir_three = DirIndex::new();
+	dir_three.insert(3, DirSlot { block: 3, slot: 0 });
+	cache.insert_dir(3, dir_three);
+
+	assert!(cache.get(2).is_none());
+	assert!(cache.get(1).is_some());
+	assert!(cache.get(3).is_some());
+}
+
+#[test_case]
+fn oversized_directory_stays_indexed() {
+	let mut cache = DirIndexCache::new(2);
+
+	let mut large = DirIndex::new();
+	for hash in 0..5 {
+		large.insert(hash, DirSlot { block: 1, slot: hash as usize });
+	}
+	cache.insert_dir(1, large);
+	// over the budget on its own, but dropping it would leave big directories never indexed
+	assert!(cache.get(1).is_some());
+
+	let mut small = DirIndex::new();
+	small.insert(9, DirSlot { block: 2, slot: 0 });
+	cache.insert_dir(2, small);
+	assert!(cache.get(1).is_none());
+	assert!(cache.get(2).is_some());
+
+	let mut off = DirIndexCache::new(0);
+	let mut dir = DirIndex::new();
+	dir.insert(1, DirSlot { block: 1, slot: 0 });
+	off.insert_dir(1, dir);
+	assert!(off.get(1).is_none());
+}
+
+#[test_case]
+fn delete_drops_the_name_from_the_index() {
+	let mut fs = fresh_fs();
+	let old = assert_ok!(fs.create_file("victim"));
+	assert_eq!(assert_ok!(fs.open_file("victim")).0, old.0);
+	let misses = fs.stats().dir_index_misses;
+
+	assert_ok!(fs.delete_file("victim"));
+	assert_err!(fs.open_file("victim"), FileError::FileNotFound);
+
+	// the slot is reused, the index has to point the name at the new entry
+	let new = assert_ok!(fs.create_file("victim"));
+	assert_eq!(assert_ok!(fs.open_file("victim")).0, new.0);
+	assert_eq!(fs.stats().dir_index_misses, misses);
+}
+
+/// the first block of the root directory and what's in it
+fn first_root_block<D: BlockDevice>(fs: &mut SFS<D>) -> (u64, [u8; BLOCK_SIZE]) {
+	let block = assert_ok!(fs.read_inode(ROOT_DIRECTORY_INODE)).direct_pointers[0];
+	let mut buf = [0u8; BLOCK_SIZE];
+	assert_ok!(fs.device_mut().read_blocks(block, &mut buf));
+	(block, buf)
+}
+
+#[test_case]
+fn lookup_falls_back_when_only_collisions_match() {
+	let mut fs = fresh_fs();
+	let a = assert_ok!(fs.create_file("a"));
+	let b = assert_ok!(fs.create_file("b"));
+	assert_eq!(assert_ok!(fs.open_file("a")).0, a.0);
+
+	// swap the entries after "." and ".." behind the index's back, the slot it has for "a" now
+	// holds "b" as a colliding hash would
+	let size = size_of::<DiskDirEntry>();
+	let (block, mut buf) = first_root_block(&mut fs);
+	let (first, second) = buf.split_at_mut(3 * size);
+	first[2 * size..].swap_with_slice(&mut second[..size]);
+	assert_ok!(fs.device_mut().write_blocks(block, &buf));
+	let entry = DiskDirEntry::read_from_bytes(&buf[2 * size..3 * size]).expect("bad dirent");
+	assert_eq!(entry.inode.get(), b.0 as u64);
+
+	let misses = fs.stats().dir_index_misses;
+	assert_eq!(assert_ok!(fs.open_file("a")).0, a.0);
+	assert_eq!(fs.stats().dir_index_misses, misses + 1);
+	// the rebuilt index has both right again
+	assert_eq!(assert_ok!(fs.open_file("b")).0, b.0);
+	assert_eq!(fs.stats().dir_index_misses, misses + 1);
+}
+
+#[test_case]
+fn root_directory_grows_past_its_direct_blocks() {
+	let options = FormatOptions { inode_ratio_percent: MAX_INODE_RATIO_PERCENT };
+	let mut fs = assert_ok!(SFS::format_with(MemBlockDevice::new(2 * TEST_BLOCKS), options));
+	assert_ok!(fs.init_root_directory());
+
+	// "." and ".." plus these fill the ten direct blocks and spill into the indirect one
+	let count = 10 * DIR_ENTRIES_PER_BLOCK;
+	for i in 0..count {
+		assert_ok!(fs.create_file(&format!("f{:03}", i)));
+	}
+	assert!(assert_ok!(fs.read_inode(ROOT_DIRECTORY_INODE)).indirect_pointer != 0);
+	assert!(assert_ok!(fs.fsck()).is_clean());
+
+	let fs = assert_ok!(SFS::mount(fs.unmount()));
+	assert_eq!(assert_ok!(fs.list_file()).len(), count);
+	for i in (0..count).step_by(7) {
+		assert_ok!(fs.open_file(&format!("f{:03}", i)));
+	}
+}
+
+/// blocks of the directory benchmark's disk, 50% of it is room for 640 inodes
+const BENCH_BLOCKS: usize = 320;
+
+/// a disk outside the heap, which has no room for hundreds of inodes and their dirents
+static BENCH_DISK: Mutex<[[u8; BLOCK_SIZE]; BENCH_BLOCKS]> =
+	Mutex::new([[0; BLOCK_SIZE]; BENCH_BLOCKS]);
+
+/// `BENCH_DISK` as a device, counting reads
+struct StaticDevice {
+	reads: usize,
+}
+
+impl StaticDevice {
+	fn new() -> Self {
+		for block in BENCH_DISK.lock().iter_mut() {
+			block.fill(0);
+		}
+		StaticDevice { reads: 0 }
+	}
+}
+
+impl BlockDevice for StaticDevice {
+	fn read_blocks(
+		&mut self,
+		block_id: u64,
+		buffer: &mut [u8],
+	) -> Result<(), FileSystemError> {
+		self.reads += 1;
+		let disk = BENCH_DISK.lock();
+		let first = block_id as usize;
+		let blocks = disk
+			.get(first..first + buffer.len() / BLOCK_SIZE)
+			.ok_or(FileSystemError::BlockError)?;
+		for (chunk, block) in buffer.chunks_exact_mut(BLOCK_SIZE).zip(blocks) {
+			chunk.copy_from_slice(block);
+		}
+		Ok(())
+	}
+
+	fn write_blocks(
+		&mut self,
+		block_id: u64,
+		buffer: &[u8],
+	) -> Result<(), FileSystemError> {
+		let mut disk = BENCH_DISK.lock();
+		let first = block_id as usize;
+		let blocks = disk
+			.get_mut(first..first + buffer.len() / BLOCK_SIZE)
+			.ok_or(FileSystemError::BlockError)?;
+		for (block, chunk) in blocks.iter_mut().zip(buffer.chunks_exact(BLOCK_SIZE)) {
+			block.copy_from_slice(chunk);
+		}
+		Ok(())
+	}
+
+	fn capacity(&self) -> usize {
+		BENCH_BLOCKS
+	}
+}
+
+/// how many opens each measurement of the benchmark times
+const BENCH_OPENS: usize = 50;
+
+/// cycles and device reads per open of `BENCH_OPENS` files among the first `count`
+fn time_opens(
+	fs: &SFS<StaticDevice>,
+	count: usize,
+) -> (u64, usize) {
+	let names: Vec<_> =
+		(0..BENCH_OPENS).map(|i| format!("f{:03}", i * count / BENCH_OPENS)).collect();
+	let reads = fs.device().reads;
+	let start = trace::cycles();
+	for name in &names {
+		assert_ok!(fs.open_file(name));
+	}
+	let cycles = trace::cycles() - start;
+	(cycles / BENCH_OPENS as u64, (fs.device().reads - reads) / BENCH_OPENS)
+}
+
+#[test_case]
+fn open_by_name_stays_flat_as_the_directory_grows() {
+	const FILES: usize = 500;
+
+	let options = FormatOptions { inode_ratio_percent: MAX_INODE_RATIO_PERCENT };
+	let mut fs = assert_ok!(SFS::format_with(StaticDevice::new(), options));
+	assert_ok!(fs.init_root_directory());
+
+	let mut small = (0, 0);
+	for i in 0..FILES {
+		assert_ok!(fs.create_file(&format!("f{:03}", i)));
+		if i + 1 == BENCH_OPENS {
+			small = time_opens(&fs, BENCH_OPENS);
+		}
+	}
+	let large = time_opens(&fs, FILES);
+	fs.set_dir_index_budget(0);
+	let linear = time_opens(&fs, FILES);
+
+	serial_println!(
+		"open by name: {} cycles with {} files, {} with {}, {} scanning {} without the index",
+		small.0,
+		BENCH_OPENS,
+		large.0,
+		FILES,
+		linear.0,
+		FILES
+	);
+	// cycles wobble under emulation, the reads say the same and don't
+	assert_eq!(small.1, 1);
+	assert_eq!(large.1, 1);
+	assert!(linear.1 > FILES / DIR_ENTRIES_PER_BLOCK);
+}
+
+fn fs_of_size(bytes: usize) -> SFS<MemBlockDevice> {
+	let mut fs = assert_ok!(SFS::format(MemBlockDevice::new(bytes / BLOCK_SIZE)));
+	assert_ok!(fs.init_root_directory());
+	fs
+}
+
+/// allocates `count` data blocks and checks they come out in order
+fn allocate_data_blocks(
+	fs: &mut SFS<MemBlockDevice>,
+	count: u64,
+) {
+	let start = fs.superblock().data_block_start;
+	// the root directory already took the first one
+	for i in 1..=count {
+		assert_eq!(assert_ok!(fs.allocate_data_block()), start + i);
+	}
+}
+
+#[test_case]
+fn geometry_scales_with_device_size() {
+	for &size in [MIB, 8 * MIB, 64 * MIB].iter() {
+		let fs = fs_of_size(size);
+		let sb = *fs.superblock();
+
+		assert_eq!(sb.version, SUPERBLOCK_VERSION);
+		assert!(sb.is_consistent());
+		assert_eq!(sb.data_bitmap_blocks, sb.data_block_count.div_ceil(BITS_PER_BITMAP_BLOCK));
+		assert_eq!(sb.inode_bitmap_blocks, SuperBlock::bitmap_blocks_for(sb.inode_count));
+
+		// every block of the device belongs to some region
+		let metadata = 1 + sb.inode_bitmap_blocks + sb.data_bitmap_blocks + sb.inode_count / 4;
+		assert_eq!(metadata + sb.data_block_count, sb.total_blocks);
+	}
+}
+
+#[test_case]
+fn inode_ratio_sizes_the_inode_table() {
+	let blocks = 8 * MIB / BLOCK_SIZE;
+	let default = *assert_ok!(SFS::format(MemBlockDevice::new(blocks))).superblock();
+	let options = FormatOptions { inode_ratio_percent: 25 };
+	let mut fs = assert_ok!(SFS::format_with(MemBlockDevice::new(blocks), options));
+	let sb = *fs.superblock();
+
+	assert!(sb.is_consistent());
+	assert_eq!(default.inode_count, (blocks as u64 / 10) * 4);
+	assert_eq!(sb.inode_count, (blocks as u64 / 4) * 4);
+	assert!(sb.data_block_count < default.data_block_count);
+
+	// still a working filesystem
+	assert_ok!(fs.init_root_directory());
+	let handle = assert_ok!(fs.create_file("many_small_files"));
+	assert_ok!(fs.write_file(handle, b"and one of them"));
+	assert!(assert_ok!(fs.fsck()).is_clean());
+
+	for ratio in [0, 51, 100] {
+		let options = FormatOptions { inode_ratio_percent: ratio };
+		assert!(matches!(
+			SFS::format_with(MemBlockDevice::new(blocks), options),
+			Err(FileSystemError::BadFormatOptions)
+		));
+	}
+}
+
+#[test_case]
+fn small_device_fills_up_exactly() {
+	let mut fs = fs_of_size(MIB);
+	let count = fs.superblock().data_block_count;
+
+	allocate_data_blocks(&mut fs, count - 1);
+	assert_err!(fs.allocate_data_block(), FileSystemError::NoSpace);
+
+	let last = fs.superblock().data_block_start + count - 1;
+	assert_ok!(fs.free_data_block(last));
+	assert_eq!(assert_ok!(fs.allocate_data_block()), last);
+
+	let report = assert_ok!(fs.fsck());
+	assert!(report.is_clean());
+	assert_eq!(report.used_data_blocks, count);
+}
+
+#[test_case]
+fn allocation_crosses_bitmap_blocks() {
+	let mut fs = fs_of_size(8 * MIB);
+	assert!(fs.superblock().data_bitmap_blocks > 1);
+
+	// past the old 2 MiB limit, so into the second data bitmap block
+	let count = BITS_PER_BITMAP_BLOCK + 64;
+	allocate_data_blocks(&mut fs, count);
+
+	// the first bit of the second bitmap block
+	let boundary = fs.superblock().data_block_start + BITS_PER_BITMAP_BLOCK;
+	assert_ok!(fs.free_data_block(boundary));
+	assert_err!(fs.free_data_block(boundary), FileSystemError::CorruptLayout);
+	assert_eq!(assert_ok!(fs.allocate_data_block()), boundary);
+
+	let report = assert_ok!(fs.fsck());
+	assert!(report.is_clean());
+	assert_eq!(report.used_inodes, 1);
+	assert_eq!(report.used_data_blocks, count + 1);
+}
+
+#[test_case]
+fn large_device_uses_every_bitmap_block() {
+	let mut fs = fs_of_size(64 * MIB);
+	let sb = *fs.superblock();
+	assert!(sb.data_block_count > 16 * BITS_PER_BITMAP_BLOCK);
+
+	// spans three bitmap blocks
+	let count = 2 * BITS_PER_BITMAP_BLOCK + 1;
+	allocate_data_blocks(&mut fs, count);
+
+	let third_block = sb.data_block_start + 2 * BITS_PER_BITMAP_BLOCK;
+	assert_ok!(fs.free_data_block(third_block));
+
+	let report = assert_ok!(fs.fsck());
+	assert!(report.is_clean());
+	assert_eq!(report.used_data_blocks, count);
+
+	// freeing past the end of the data region is refused
+	let past_end = sb.data_block_start + sb.data_block_count;
+	assert!(fs.free_data_block(past_end).is_err());
+}
+
+#[test_case]
+fn mount_rejects_inconsistent_geometry() {
+	let fs = fs_of_size(MIB);
+	let mut sb = *fs.superblock();
+	let mut device = fs.unmount();
+
+	// the data bitmap would now run into the inode table
+	sb.data_bitmap_blocks += sb.inode_count;
+
+	let mut block = [0u8; BLOCK_SIZE];
+	block[..size_of::<DiskSuperBlock>()].copy_from_slice(DiskSuperBlock::from(sb).as_bytes());
+	assert_ok!(device.write_blocks(0, &block));
+
+	assert_err!(SFS::mount(device), FileSystemError::InvalidSuperBlock);
+}
+
+#[test_case]
+fn version_one_images_still_mount() {
+	let mut device = MemBlockDevice::new(TEST_BLOCKS);
+
+	// what the old format wrote: fixed bitmap blocks, no version and no run lengths
+	let sb = SuperBlock {
+		total_blocks: TEST_BLOCKS as u64,
+		inode_bitmap_block: 1,
+		data_bitmap_block: 2,
+		inode_table_start_block: 3,
+		inode_count: 24,
+		data_block_start: 9,
+		data_block_count: 55,
+		inode_bitmap_blocks: 0,
+		data_bitmap_blocks: 0,
+		magic_number: 0xDEAD_BEEF,
+		version: 0,
+		flags: 0,
+	};
+
+	let mut block = [0u8; BLOCK_SIZE];
+	block[..size_of::<DiskSuperBlock>()].copy_from_slice(DiskSuperBlock::from(sb).as_bytes());
+	assert_ok!(device.write_blocks(0, &block));
+
+	let mut fs = assert_ok!(SFS::mount(device));
+	assert_eq!(fs.superblock().version, 1);
+	assert_eq!(fs.superblock().inode_bitmap_blocks, 1);
+	assert_eq!(fs.superblock().data_bitmap_blocks, 1);
+
+	assert_eq!(assert_ok!(fs.allocate_data_block()), 9);
+	assert!(assert_ok!(fs.fsck()).is_clean());
+}
+
+/// the flags as they are on the device, not as the mounted filesystem has them
+fn flags_on_disk<D: BlockDevice>(fs: &mut SFS<D>) -> u32 {
+	let mut block = [0u8; BLOCK_SIZE];
+	assert_ok!(fs.device_mut().read_blocks(0, &mut block));
+	let size = size_of::<DiskSuperBlock>();
+	DiskSuperBlock::read_from_bytes(&block[..size]).expect("bad superblock").flags.get()
+}
+
+#[test_case]
+fn remount_without_unmount_is_unclean() {
+	let mut fs = fresh_fs();
+	let handle = assert_ok!(fs.create_file("crash.txt"));
+	assert_ok!(fs.write_file(handle, b"half done"));
+	assert_ne!(flags_on_disk(&mut fs) & SUPERBLOCK_DIRTY, 0);
+
+	// no unmount, as if the machine went down
+	let fs = assert_ok!(SFS::mount(fs.into_device()));
+	assert!(fs.mounted_unclean());
+
+	let mut fs = assert_ok!(SFS::mount(fs.unmount()));
+	assert!(!fs.mounted_unclean());
+	assert!(assert_ok!(fs.fsck()).is_clean());
+}
+
+#[test_case]
+fn sync_marks_clean_until_the_next_write() {
+	let mut fs = fresh_fs();
+	let handle = assert_ok!(fs.create_file("synced.txt"));
+
+	assert_ok!(fs.sync());
+	assert_eq!(flags_on_disk(&mut fs) & SUPERBLOCK_DIRTY, 0);
+
+	assert_ok!(fs.write_file(handle, b"after the sync"));
+	assert_ne!(flags_on_disk(&mut fs) & SUPERBLOCK_DIRTY, 0);
+
+	// synced last, so going down without an unmount is fine
+	assert_ok!(fs.sync());
+	assert!(!assert_ok!(SFS::mount(fs.into_device())).mounted_unclean());
+}
+
+#[test_case]
+fn contiguous_file_read_in_one_request() {
+	let mut fs = fresh_fs();
+	let handle = assert_ok!(fs.create_file("four_k.bin"));
+
+	let mut data = [0u8; 4096];
+	for (i, byte) in data.iter_mut().enumerate() {
+		*byte = (i % 251) as u8;
+	}
+	assert_eq!(assert_ok!(fs.write_file(handle, &data)), data.len());
+
+	let before = fs.stats();
+	let mut read_back = [0u8; 4096];
+	assert_eq!(assert_ok!(fs.read_file(handle, &mut read_back)), data.len());
+	assert_eq!(&read_back[..], &data[..]);
+
+	// 8 blocks in a row, one request instead of eight
+	assert_eq!(fs.stats().data_read_requests - before.data_read_requests, 1);
+	assert_eq!(before.data_write_requests, 1);
+}
+
+#[test_case]
+fn fragmented_file_read_back_intact() {
+	let mut fs = fresh_fs();
+	let first = assert_ok!(fs.create_file("first"));
+	let second = assert_ok!(fs.create_file("second"));
+
+	// second grabs the block right after first's, so first's next blocks go elsewhere
+	assert_ok!(fs.write_file(first, &[1u8; BLOCK_SIZE]));
+	assert_ok!(fs.write_file(second, &[2u8; BLOCK_SIZE]));
+
+	let mut data = [0u8; 3 * BLOCK_SIZE + 100];
+	for (i, byte) in data.iter_mut().enumerate() {
+		*byte = (i / 7) as u8;
+	}
+	assert_ok!(fs.write_file(first, &data));
+
+	let before = fs.stats();
+	let mut read_back = [0u8; 3 * BLOCK_SIZE + 100];
+	assert_eq!(assert_ok!(fs.read_file(first, &mut read_back)), data.len());
+	assert_eq!(&read_back[..], &data[..]);
+	assert_eq!(fs.stats().data_read_requests - before.data_read_requests, 2);
+
+	let mut other = [0u8; BLOCK_SIZE];
+	assert_ok!(fs.read_file(second, &mut other));
+	assert!(other.iter().all(|&b| b == 2));
+
+	assert!(assert_ok!(fs.fsck()).is_clean());
+}
+
+#[test_case]
+fn oversized_inode_reads_as_corrupt() {
+	let mut fs = fresh_fs();
+	let handle = assert_ok!(fs.create_file("big"));
+	assert_ok!(fs.write_file(handle, b"small"));
+	let mut device = fs.unmount();
+
+	// a size more than the ten direct pointers can hold
+	let (block, offset) =
+		image::inode_location(&assert_ok!(image::read_superblock(&mut device)), handle.0 as u64);
+	let mut buf = [0u8; BLOCK_SIZE];
+	assert_ok!(device.read_blocks(block, &mut buf));
+	buf[offset..offset + size_of::<u64>()].copy_from_slice(&(11 * BLOCK_SIZE as u64).to_le_bytes());
+	assert_ok!(device.write_blocks(block, &buf));
+
+	let fs = assert_ok!(SFS::mount(device));
+	let mut contents = alloc::vec![0u8; 11 * BLOCK_SIZE];
+	assert_err!(fs.read_file(handle, &mut contents), FileError::Corrupt);
+}
+
+/// what the timestamp tests tell the filesystem the time is
+static NOW: AtomicU64 = AtomicU64::new(0);
+
+fn test_clock() -> u64 {
+	NOW.load(Ordering::Relaxed)
+}
+
+fn set_now(seconds: u64) {
+	NOW.store(seconds, Ordering::Relaxed);
+}
+
+/// a filesystem on the test clock, with a relatime interval of 1000 seconds
+fn timed_fs() -> SFS<MemBlockDevice> {
+	set_now(100);
+	let mut fs = fresh_fs();
+	fs.set_clock(test_clock);
+	fs.set_relatime_interval(1000);
+	fs
+}
+
+#[test_case]
+fn mtime_moves_on_write_not_read() {
+	let mut fs = timed_fs();
+	let handle = assert_ok!(fs.create_file("times"));
+
+	let created = assert_ok!(fs.stat(handle));
+	assert_eq!(created.creation_time, 100);
+	assert_eq!(created.last_modification_time, 100);
+	assert_eq!(created.last_access_time, 100);
+
+	set_now(200);
+	assert_ok!(fs.write_file(handle, b"hello"));
+	set_now(300);
+	assert_ok!(fs.read_file(handle, &mut [0u8; 5]));
+
+	let inode = assert_ok!(fs.stat(handle));
+	assert_eq!(inode.creation_time, 100);
+	assert_eq!(inode.last_modification_time, 200);
+	assert_eq!(inode.last_access_time, 300);
+}
+
+#[test_case]
+fn relatime_suppresses_repeated_reads() {
+	let mut fs = timed_fs();
+	let handle = assert_ok!(fs.create_file("hot"));
+	let mut buf = [0u8; 4];
+
+	set_now(200);
+	assert_ok!(fs.write_file(handle, b"data"));
+
+	// the access time is older than the last write, so this read counts
+	set_now(300);
+	assert_ok!(fs.read_file(handle, &mut buf));
+	assert_eq!(fs.stat(handle).unwrap().last_access_time, 300);
+
+	// newer than the write and fresh enough, nothing changes
+	set_now(400);
+	assert_ok!(fs.read_file(handle, &mut buf));
+	assert_eq!(fs.stat(handle).unwrap().last_access_time, 300);
+
+	// stale past the interval
+	set_now(1300);
+	assert_ok!(fs.read_file(handle, &mut buf));
+	assert_eq!(fs.stat(handle).unwrap().last_access_time, 1300);
+
+	// none of that reached the inode table yet
+	assert_eq!(fs.read_inode(handle.0 as u64).unwrap().last_access_time, 100);
+
+	assert_ok!(fs.fsync(handle));
+	assert_eq!(fs.read_inode(handle.0 as u64).unwrap().last_access_time, 1300);
+}
+
+#[test_case]
+fn relatime_goes_by_the_access_time_in_memory() {
+	let mut fs = timed_fs();
+	let handle = assert_ok!(fs.create_file("warm"));
+	let mut buf = [0u8; 4];
+
+	set_now(200);
+	assert_ok!(fs.write_file(handle, b"data"));
+	set_now(300);
+	assert_ok!(fs.read_file(handle, &mut buf));
+	assert_eq!(fs.stats().dirty_times, 1);
+
+	// fresh by the access time in memory, the table's is still older than the write
+	set_now(400);
+	assert_ok!(fs.read_file(handle, &mut buf));
+	assert_eq!(fs.stats().dirty_times, 1);
+	assert_eq!(fs.stat(handle).unwrap().last_access_time, 300);
+
+	// and once it's in the table a read doesn't note it again
+	assert_ok!(fs.fsync(handle));
+	assert_eq!(fs.stats().dirty_times, 0);
+	set_now(500);
+	assert_ok!(fs.read_file(handle, &mut buf));
+	assert_eq!(fs.stats().dirty_times, 0);
+}
+
+#[test_case]
+fn dirty_times_survive_remount() {
+	let mut fs = timed_fs();
+	let handle = assert_ok!(fs.create_file("kept"));
+
+	set_now(200);
+	assert_ok!(fs.write_file(handle, b"abc"));
+	set_now(500);
+	assert_ok!(fs.read_file(handle, &mut [0u8; 3]));
+
+	let device = fs.unmount();
+	let fs = assert_ok!(SFS::mount(device));
+
+	let inode = assert_ok!(fs.stat(handle));
+	assert_eq!(inode.last_access_time, 500);
+	assert_eq!(inode.last_modification_time, 200);
+	assert_eq!(inode.creation_time, 100);
+}
+
+#[test_case]
+fn list_shows_created_files() {
+	let mut fs = fresh_fs();
+	assert!(assert_ok!(fs.list_file()).is_empty());
+
+	fs.create_file("one").unwrap();
+	fs.create_file("two").unwrap();
+
+	assert_eq!(assert_ok!(fs.list_file()), ["one", "two"]);
+}
+
+#[test_case]
+fn read_only_device_mounts_read_only() {
+	let mut fs = fresh_fs();
+	let handle = assert_ok!(fs.create_file("ro.txt"));
+	assert_ok!(fs.write_file(handle, b"frozen"));
+
+	let mut device = fs.unmount();
+	device.set_read_only(true);
+	let mut fs = assert_ok!(SFS::mount(device));
+	assert!(fs.is_read_only());
+
+	// reads work, anything that would write is refused up front
+	let handle = assert_ok!(fs.open_file("ro.txt"));
+	let mut buf = [0u8; 16];
+	let len = assert_ok!(fs.read_file(handle, &mut buf));
+	assert_eq!(&buf[..len], b"frozen");
+
+	assert_err!(fs.write_file(handle, b"thawed"), FileError::ReadOnly);
+	assert_err!(fs.create_file("new.txt"), FileError::ReadOnly);
+	assert!(fs.fsync(handle).is_ok());
+
+	let mut device = fs.unmount();
+	device.set_read_only(true);
+	assert_err!(SFS::format(device), FileSystemError::ReadOnly);
+}
+
+/// Counts requests and fails every write once `budget` runs out, a power cut from then on
+struct CuttingDevice {
+	inner: MemBlockDevice,
+	reads: usize,
+	writes: usize,
+	budget: Option<usize>,
+}
+
+impl CuttingDevice {
+	fn new() -> Self {
+		Self::with_blocks(TEST_BLOCKS)
+	}
+
+	fn with_blocks(blocks: usize) -> Self {
+		Self { inner: MemBlockDevice::new(blocks), reads: 0, writes: 0, budget: None }
+	}
+}
+
+impl BlockDevice for CuttingDevice {
+	fn read_blocks(
+		&mut self,
+		block_id: u64,
+		buffer: &mut [u8],
+	) -> Result<(), FileSystemError> {
+		self.reads += 1;
+		self.inner.read_blocks(block_id, buffer)
+	}
+
+	fn write_blocks(
+		&mut self,
+		block_id: u64,
+		buffer: &[u8],
+	) -> Result<(), FileSystemError> {
+		if let Some(budget) = self.budget {
+			if budget == 0 {
+				return Err(FileSystemError::BlockError);
+			}
+			self.budget = Some(budget - 1);
+		}
+		self.writes += 1;
+		self.inner.write_blocks(block_id, buffer)
+	}
+
+	fn capacity(&self) -> usize {
+		self.inner.capacity()
+	}
+}
+
+const FRAGMENTED_NAMES: [&str; 6] = ["f0", "f1", "f2", "f3", "f4", "f5"];
+
+/// the ones `fragmented_fs` doesn't truncate
+const SURVIVORS: [&str; 4] = ["f0", "f2", "f3", "f5"];
+
+/// what file `name` holds after growing to `blocks` blocks
+fn fragmented_contents(
+	name: &str,
+	blocks: usize,
+) -> Vec<u8> {
+	let seed = name.as_bytes()[1];
+	(0..blocks * BLOCK_SIZE - 3).map(|i| (i / 5) as u8 ^ seed).collect()
+}
+
+/// Six files grown a block at a time in turn, so their blocks alternate, then two of them
+/// truncated to leave holes
+fn fragmented_fs<D: BlockDevice>(device: D) -> SFS<D> {
+	let mut fs = assert_ok!(SFS::format(device));
+	assert_ok!(fs.init_root_directory());
+
+	let handles: Vec<FileHandler> =
+		FRAGMENTED_NAMES.iter().map(|name| assert_ok!(fs.create_file(name))).collect();
+	for blocks in 1..=5 {
+		for (name, &handle) in FRAGMENTED_NAMES.iter().zip(&handles) {
+			assert_ok!(fs.write_file(handle, &fragmented_contents(name, blocks)));
+		}
+	}
+	assert_ok!(fs.write_file(handles[1], &[]));
+	assert_ok!(fs.write_file(handles[4], &[]));
+	fs
+}
+
+/// percent of the survivors' blocks that sit right behind the block before them in the file
+fn contiguity<D: BlockDevice>(fs: &mut SFS<D>) -> u64 {
+	let (mut pairs, mut contiguous) = (0, 0);
+	for name in SURVIVORS {
+		let handle = assert_ok!(fs.open_file(name));
+		let inode = assert_ok!(fs.stat(handle));
+		let blocks = (inode.size_in_bytes as usize).div_ceil(BLOCK_SIZE);
+		for pair in inode.direct_pointers[..blocks].windows(2) {
+			pairs += 1;
+			if pair[1] == pair[0] + 1 {
+				contiguous += 1;
+			}
+		}
+	}
+	contiguous * 100 / pairs
+}
+
+fn assert_survivors_intact<D: BlockDevice>(fs: &mut SFS<D>) {
+	for name in SURVIVORS {
+		let handle = assert_ok!(fs.open_file(name));
+		let expected = fragmented_contents(name, 5);
+		let mut read_back = alloc::vec![0u8; 5 * BLOCK_SIZE];
+		assert_eq!(assert_ok!(fs.read_file(handle, &mut read_back)), expected.len());
+		assert_eq!(&read_back[..expected.len()], &expected[..], "{} changed", name);
+	}
+}
+
+#[test_case]
+fn defragment_makes_files_contiguous() {
+	let mut fs = fragmented_fs(MemBlockDevice::new(TEST_BLOCKS));
+	assert_eq!(contiguity(&mut fs), 0);
+
+	let mut calls = Vec::new();
+	let report = assert_ok!(fs.defragment(&mut |done, total| calls.push((done, total))));
+
+	assert!(contiguity(&mut fs) > 90);
+	assert_eq!(report.files, 4);
+	assert_eq!(report.files_moved, 4);
+	assert_eq!(report.files_partial, 0);
+	assert_eq!(report.blocks_relocated, 20);
+	assert!(report.largest_free_run_after >= report.largest_free_run_before);
+	assert_eq!(calls, [(1, 4), (2, 4), (3, 4), (4, 4)]);
+	assert_survivors_intact(&mut fs);
+	assert!(assert_ok!(fs.fsck()).is_clean());
+
+	// nothing left to do the second time
+	let again = assert_ok!(fs.defragment(&mut |_, _| {}));
+	assert_eq!(again.files_moved, 0);
+
+	let mut fs = assert_ok!(SFS::mount(fs.into_device()));
+	assert_survivors_intact(&mut fs);
+}
+
+#[test_case]
+fn defragment_survives_power_cut() {
+	let mut fs = fragmented_fs(CuttingDevice::new());
+	let before = fs.device().writes;
+	assert_ok!(fs.defragment(&mut |_, _| {}));
+	let total = fs.device().writes - before;
+
+	for cut in 0..total {
+		let mut fs = fragmented_fs(CuttingDevice::new());
+		fs.device_mut().budget = Some(cut);
+		assert!(fs.defragment(&mut |_, _| {}).is_err());
+
+		let mut device = fs.into_device();
+		device.budget = None;
+		let mut fs = assert_ok!(SFS::mount(device));
+		assert!(assert_ok!(fs.fsck()).is_clean(), "fsck unhappy after {} writes", cut);
+		assert_survivors_intact(&mut fs);
+	}
+}
+
+#[test_case]
+fn repeated_inode_reads_hit_the_cache() {
+	let mut fs = assert_ok!(SFS::format(CuttingDevice::new()));
+	assert_ok!(fs.init_root_directory());
+	let handle = assert_ok!(fs.create_file("cached"));
+	assert_ok!(fs.write_file(handle, b"hello"));
+
+	// nothing is cached after a mount
+	let mut fs = assert_ok!(SFS::mount(fs.unmount()));
+	let inode_index = handle.0 as u64;
+
+	let reads = fs.device().reads;
+	let first = assert_ok!(fs.read_inode(inode_index));
+	let second = assert_ok!(fs.read_inode(inode_index));
+	assert_eq!(fs.device().reads - reads, 1);
+	assert_eq!(first.size_in_bytes, 5);
+	assert_eq!(second.size_in_bytes, 5);
+
+	// a write still goes to the device, and what it wrote is what the next read sees
+	let mut changed = second;
+	changed.size_in_bytes = 3;
+	assert_ok!(fs.write_inode(changed, inode_index));
+	let reads = fs.device().reads;
+	assert_eq!(assert_ok!(fs.read_inode(inode_index)).size_in_bytes, 3);
+	assert_eq!(fs.device().reads, reads);
+
+	let fs = assert_ok!(SFS::mount(fs.unmount()));
+	assert_eq!(assert_ok!(fs.read_inode(inode_index)).size_in_bytes, 3);
+}
+
+#[test_case]
+fn inode_cache_evicts_the_least_recently_used() {
+	// an inode table with room for more inodes than the cache holds
+	let mut fs = assert_ok!(SFS::format(CuttingDevice::with_blocks(256)));
+	assert_ok!(fs.init_root_directory());
+	assert!(fs.superblock().inode_count > INODE_CACHE_LEN as u64);
+	let mut fs = assert_ok!(SFS::mount(fs.unmount()));
+
+	// fills the cache, the root inode first
+	let root = assert_ok!(fs.read_inode(ROOT_DIRECTORY_INODE));
+	for inode_index in 1..INODE_CACHE_LEN as u64 {
+		assert_ok!(fs.write_inode(root, inode_index));
+	}
+
+	// the root is the oldest entry, a read makes it the newest
+	let reads = fs.device().reads;
+	assert_ok!(fs.read_inode(ROOT_DIRECTORY_INODE));
+	assert_eq!(fs.device().reads, reads);
+
+	// so the one written first goes instead
+	assert_ok!(fs.write_inode(root, INODE_CACHE_LEN as u64));
+	let reads = fs.device().reads;
+	assert_ok!(fs.read_inode(ROOT_DIRECTORY_INODE));
+	assert_eq!(fs.device().reads, reads);
+	assert_ok!(fs.read_inode(1));
+	assert_eq!(fs.device().reads, reads + 1);
+}
+
+#[test_case]
+fn open_or_create_creates_once() {
+	let mut fs = fresh_fs();
+
+	let created = assert_ok!(fs.open_or_create("notes.txt"));
+	assert_ok!(fs.write_file(created, b"kept"));
+
+	let opened = assert_ok!(fs.open_or_create("notes.txt"));
+	assert_eq!(opened.0, created.0);
+	assert_eq!(assert_ok!(fs.list_file()).len(), 1);
+
+	let mut buf = [0u8; 8];
+	let len = assert_ok!(fs.read_file(opened, &mut buf));
+	assert_eq!(&buf[..len], b"kept");
+	assert!(assert_ok!(fs.fsck()).is_clean());
+
+	// opening doesn't write, so it still works read-only
+	let mut device = fs.unmount();
+	device.set_read_only(true);
+	let mut fs = assert_ok!(SFS::mount(device));
+	assert_eq!(assert_ok!(fs.open_or_create("notes.txt")).0, created.0);
+	assert_err!(fs.open_or_create("other.txt"), FileError::ReadOnly);
+}
+
+#[test_case]
+fn delete_frees_the_name_and_the_blocks() {
+	let mut fs = fresh_fs();
+	let kept = assert_ok!(fs.create_file("kept"));
+	assert_ok!(fs.write_file(kept, b"stays"));
+	let empty = assert_ok!(fs.fsck());
+
+	let handle = assert_ok!(fs.create_file("gone"));
+	assert_ok!(fs.write_file(handle, &[7u8; 3 * BLOCK_SIZE]));
+
+	assert_ok!(fs.delete_file("gone"));
+	assert_err!(fs.open_file("gone"), FileError::FileNotFound);
+	assert_err!(fs.delete_file("gone"), FileError::FileNotFound);
+	assert_eq!(assert_ok!(fs.list_file()), ["kept"]);
+
+	let report = assert_ok!(fs.fsck());
+	assert!(report.is_clean());
+	assert_eq!(report.used_inodes, empty.used_inodes);
+	assert_eq!(report.used_data_blocks, empty.used_data_blocks);
+
+	// the name can be taken again, and the other file never noticed
+	assert_ok!(fs.create_file("gone"));
+	let mut buf = [0u8; 8];
+	let len = assert_ok!(fs.read_file(kept, &mut buf));
+	assert_eq!(&buf[..len], b"stays");
+}
+
+#[test_case]
+fn image_reader_agrees_with_the_mounted_fs() {
+	let mut fs = fresh_fs();
+	let small = assert_ok!(fs.create_file("small"));
+	assert_ok!(fs.write_file(small, b"hello"));
+	let big = assert_ok!(fs.create_file("big"));
+	let contents: Vec<u8> = (0..3 * BLOCK_SIZE + 10).map(|i| i as u8).collect();
+	assert_ok!(fs.write_file(big, &contents));
+	assert_ok!(fs.sync());
+	let report = assert_ok!(fs.fsck());
+
+	// what sfs-inspect sees in the same blocks
+	let sb = assert_ok!(image::read_superblock(fs.device_mut()));
+	assert_eq!(sb.inode_count, fs.superblock().inode_count);
+	assert_eq!(sb.data_block_start, fs.superblock().data_block_start);
+
+	let image_report = assert_ok!(image::fsck(fs.device_mut(), &sb));
+	assert!(image_report.is_clean());
+	assert_eq!(image_report.used_inodes, report.used_inodes);
+	assert_eq!(image_report.used_data_blocks, report.used_data_blocks);
+
+	let names: Vec<_> = assert_ok!(image::list_dir(fs.device_mut(), &sb, ROOT_DIRECTORY_INODE))
+		.into_iter()
+		.map(|entry| entry.name)
+		.collect();
+	assert_eq!(names, assert_ok!(fs.list_file()));
+
+	let inode = assert_ok!(image::resolve(fs.device_mut(), &sb, "/big"));
+	assert_eq!(inode, big.0 as u64);
+	assert_eq!(assert_ok!(image::read_file(fs.device_mut(), &sb, inode)), contents);
+	assert_eq!(image::resolve(fs.device_mut(), &sb, "/nope"), Err(ImageError::NotFound));
+}
+
+// layout.rs is built for the host too, which can't take #[test_case]
+#[test_case]
+fn file_type_keeps_unknown_modes() {
+	assert_eq!(FileType::from(0x1), FileType::File);
+	assert_eq!(FileType::from(0x2), FileType::Directory);
+	assert_eq!(FileType::from(0x7), FileType::Unknown);
+
+	// the rest of the inode survives a mode we don't know
+	let mut disk_inode = DiskInode::from(Inode {
+		mode: FileType::File,
+		user_id: 0,
+		group_id: 0,
+		link_count: 1,
+		size_in_bytes: 42,
+		last_access_time: 0,
+		last_modification_time: 0,
+		creation_time: 0,
+		direct_pointers: [0u64; 10],
+		indirect_pointer: 0,
+	});
+	disk_inode.mode = U16::new(0x7);
+
+	let inode = Inode::try_from(disk_inode).expect("inode with unknown mode rejected");
+	assert_eq!(inode.mode, FileType::Unknown);
+	assert_eq!(inode.size_in_bytes, 42);
+}
+
+#[test_case]
+fn file_type_strict_rejects_unknown_modes() {
+	assert_eq!(FileType::try_from_strict(0x1), Ok(FileType::File));
+	assert_eq!(FileType::try_from_strict(0x2), Ok(FileType::Directory));
+	assert_eq!(FileType::try_from_strict(0), Ok(FileType::Unknown));
+	assert_eq!(FileType::try_from_strict(0x7), Err(()));
+}
+
+#[test_case]
+fn superblock_and_inodes_display_readably() {
+	let fs = fresh_fs();
+	let sb = format!("{}", fs.superblock());
+	assert!(sb.starts_with("SuperBlock {\n    total_blocks: 64 (0x40),\n"));
+	assert!(sb.contains("\n    magic_number: 0xdeadbeef,\n"));
+	assert!(sb.ends_with("\n}"));
+
+	let root = format!("{}", assert_ok!(fs.read_inode(ROOT_DIRECTORY_INODE)));
+	assert!(root.contains("\n    mode: Directory,\n"));
+
+	let mut direct_pointers = [0u64; 10];
+	direct_pointers[..2].copy_from_slice(&[0x12, 0x13]);
+	let inode = Inode {
+		mode: FileType::File,
+		user_id: 0,
+		group_id: 0,
+		link_count: 1,
+		size_in_bytes: 1000,
+		last_access_time: 0,
+		last_modification_time: 0,
+		creation_time: 0,
+		direct_pointers,
+		indirect_pointer: 0,
+	};
+	let inode = format!("{}", inode);
+	assert!(inode.contains("\n    mode: File,\n    size_in_bytes: 1000 (0x3e8),\n"));
+	assert!(inode.contains(
+		"\n    direct_pointers: [0x12, 0x13, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0],\n"
+	));
+
+	let mut entry = DiskDirEntry {
+		inode: U64::new(3),
+		name_len: U16::new(9),
+		flags: U16::new(DIRENT_USED),
+		name: [0; DIR_NAME_MAX],
+	};
+	entry.name[..9].copy_from_slice(b"notes.txt");
+	assert_eq!(format!("{}", entry), "DiskDirEntry { inode: 3 (0x3), name: \"notes.txt\", used }");
+
+	// only prints, but has to get through all three parts
+	fs.dump();
+}
+
+#[test_case]
+fn byte_ranges_keep_the_rest_of_their_blocks() {
+	let mut device = MemBlockDevice::new(4);
+	assert_ok!(device.write_blocks(0, &[0xAA; 3 * BLOCK_SIZE]));
+
+	// the tail of block 0, all of block 1 and the head of block 2
+	let data: Vec<u8> = (0..600).map(|i| i as u8).collect();
+	assert_ok!(device.write_bytes(500, &data));
+
+	let mut blocks = [0u8; 3 * BLOCK_SIZE];
+	assert_ok!(device.read_blocks(0, &mut blocks));
+	assert!(blocks[..500].iter().all(|&b| b == 0xAA));
+	assert_eq!(&blocks[500..1100], &data[..]);
+	assert!(blocks[1100..].iter().all(|&b| b == 0xAA));
+
+	// inside one block
+	assert_ok!(device.write_bytes(BLOCK_SIZE as u64 + 10, &[1, 2, 3]));
+	let mut back = [0u8; 5];
+	assert_ok!(device.read_bytes(BLOCK_SIZE as u64 + 9, &mut back));
+	assert_eq!(back, [data[BLOCK_SIZE + 9 - 500], 1, 2, 3, data[BLOCK_SIZE + 13 - 500]]);
+
+	assert_err!(
+		device.write_bytes(4 * BLOCK_SIZE as u64 - 1, &[0, 0]),
+		FileSystemError::BlockError
+	);
+	assert_err!(device.read_bytes(u64::MAX, &mut back), FileSystemError::BlockError);
+}
+
+#[test_case]
+fn appends_read_back_in_order() {
+	let mut fs = fresh_fs();
+	let handle = assert_ok!(fs.create_file("log"));
+	assert_ok!(fs.write_file(handle, b"boot\n"));
+
+	// ends inside the first block, then one that crosses into a new block, then two more blocks
+	let chunks: [Vec<u8>; 3] = [
+		b"first line\n".to_vec(),
+		(0..BLOCK_SIZE).map(|i| b'a' + (i % 26) as u8).collect(),
+		(0..2 * BLOCK_SIZE).map(|i| b'0' + (i % 10) as u8).collect(),
+	];
+	let mut expected = b"boot\n".to_vec();
+	for chunk in &chunks {
+		assert_eq!(assert_ok!(fs.append(handle, chunk)), chunk.len());
+		expected.extend_from_slice(chunk);
+	}
+
+	assert_eq!(assert_ok!(fs.stat(handle)).size_in_bytes, expected.len() as u64);
+	let mut buf = alloc::vec![0u8; expected.len()];
+	assert_eq!(assert_ok!(fs.read_file(handle, &mut buf)), expected.len());
+	assert_eq!(buf, expected);
+
+	// the same after a trip through the device
+	let mut fs = assert_ok!(SFS::mount(fs.unmount()));
+	let handle = assert_ok!(fs.open_file("log"));
+	buf.fill(0);
+	assert_ok!(fs.read_file(handle, &mut buf));
+	assert_eq!(buf, expected);
+
+	// nothing written past the direct pointers
+	let rest = alloc::vec![0u8; 10 * BLOCK_SIZE];
+	assert_err!(fs.append(handle, &rest), FileError::NoSpace);
+	assert_eq!(assert_ok!(fs.stat(handle)).size_in_bytes, expected.len() as u64);
+}
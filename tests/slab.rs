@@ -0,0 +1,166 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use alloc::{
+	alloc::{alloc, dealloc},
+	vec::Vec,
+};
+use blog_os::allocator::{
+	HEAP_SIZE, ShrinkerStats, SlabCache, shrinker_stats,
+	slab::{self, SLAB_GRANULE},
+};
+use core::{
+	alloc::Layout,
+	sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// a 64 byte object that counts its drops
+struct Widget {
+	id: usize,
+	_pad: [u64; 7],
+}
+
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+impl Drop for Widget {
+	fn drop(&mut self) {
+		DROPPED.fetch_add(1, Ordering::Relaxed);
+	}
+}
+
+const INITIAL: usize = 8;
+
+/// widgets per slab, the initial capacity rounds up to a whole one
+const PER_SLAB: usize = SLAB_GRANULE / 64;
+
+static WIDGETS: SlabCache<Widget> = SlabCache::new("widgets", INITIAL);
+
+fn widget(id: usize) -> slab::SlabBox<Widget> {
+	WIDGETS.alloc(Widget { id, _pad: [0; 7] }).expect("slab cache can't grow")
+}
+
+#[test_case]
+fn drop_returns_the_slot() {
+	let dropped = DROPPED.load(Ordering::Relaxed);
+
+	let first = widget(1);
+	let address = &*first as *const Widget;
+	assert_eq!(first.id, 1);
+	assert_eq!(WIDGETS.stats().in_use, 1);
+
+	drop(first);
+	assert_eq!(DROPPED.load(Ordering::Relaxed), dropped + 1);
+	assert_eq!(WIDGETS.stats().in_use, 0);
+
+	// the slot freed last is the next one out
+	let second = widget(2);
+	assert_eq!(&*second as *const Widget, address);
+	assert_eq!(second.id, 2);
+}
+
+#[test_case]
+fn alloc_and_free_past_the_initial_capacity() {
+	const COUNT: usize = PER_SLAB + PER_SLAB / 2;
+
+	for round in 0..4 {
+		let widgets: Vec<_> = (0..COUNT).map(|id| widget(round * COUNT + id)).collect();
+		assert!(widgets.iter().enumerate().all(|(id, w)| w.id == round * COUNT + id));
+
+		let stats = WIDGETS.stats();
+		assert_eq!(stats.in_use, COUNT);
+		// the first round grew it, the others found the slabs it left
+		assert_eq!(stats.slabs, 2);
+		assert_eq!(stats.capacity, 2 * PER_SLAB);
+		assert!(stats.high_water >= COUNT);
+		assert_eq!(stats.object_size, 64);
+	}
+	assert_eq!(WIDGETS.stats().in_use, 0);
+
+	assert!(WIDGETS.grow(3 * PER_SLAB));
+	assert_eq!(WIDGETS.stats().capacity, 5 * PER_SLAB);
+	assert!(slab::slab_stats().iter().any(|stats| stats.name == "widgets"));
+}
+
+/// as big as a slab, so an empty one the shrinker gives back makes room for exactly one
+const BALLAST: Layout = match Layout::from_size_align(SLAB_GRANULE, 8) {
+	Ok(layout) => layout,
+	Err(_) => panic!("bad ballast layout"),
+};
+
+/// Heap blocks that fill whatever the heap has left
+struct Ballast(Vec<*mut u8>);
+
+impl Ballast {
+	/// allocates until the heap says no, the shrinkers have had their turn by then
+	fn fill() -> Self {
+		let mut blocks = Vec::with_capacity(HEAP_SIZE / BALLAST.size() + 1);
+		loop {
+			let block = unsafe { alloc(BALLAST) };
+			if block.is_null() {
+				return Ballast(blocks);
+			}
+			blocks.push(block);
+		}
+	}
+}
+
+impl Drop for Ballast {
+	fn drop(&mut self) {
+		for block in self.0.drain(..) {
+			unsafe { dealloc(block, BALLAST) };
+		}
+	}
+}
+
+fn stats_of(name: &str) -> ShrinkerStats {
+	shrinker_stats()
+		.into_iter()
+		.find(|stats| stats.name == name)
+		.unwrap_or_else(|| panic!("no shrinker called {}", name))
+}
+
+#[test_case]
+fn full_heap_gives_back_empty_slabs() {
+	let mut widgets: Vec<_> = (0..3 * PER_SLAB).map(widget).collect();
+	assert_eq!(WIDGETS.stats().slabs, 3);
+
+	// the ones that stay fill the first slab, the other two empty out
+	widgets.truncate(PER_SLAB);
+	assert_eq!(WIDGETS.stats().slabs, 3);
+	let before = stats_of("slab caches");
+
+	let ballast = Ballast::fill();
+	// the initial capacity stays, the slabs past it went
+	let stats = WIDGETS.stats();
+	drop(ballast);
+	assert_eq!(stats.slabs, 1);
+	assert_eq!(stats.in_use, PER_SLAB);
+
+	let after = stats_of("slab caches");
+	assert!(after.invocations > before.invocations);
+	assert!(after.reclaimed >= before.reclaimed + 2 * SLAB_GRANULE as u64);
+	assert!(widgets.iter().enumerate().all(|(id, w)| w.id == id));
+}
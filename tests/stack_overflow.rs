@@ -12,7 +12,7 @@ pub extern "C" fn _start() -> !
 
     blog_os::gdt::init();
 
-    // make a custom double fault handler that does an exit_qemu(QemuExitCode::Success) instead of panicking
+    // make a custom double fault handler that does an exit_qemu_or_halt(QemuExitCode::Success) instead of panicking
     init_test_idt();
 
     stack_overflow();
@@ -60,12 +60,11 @@ pub fn init_test_idt()
 }
 
 
-use blog_os::{exit_qemu, QemuExitCode, serial_println};
+use blog_os::{exit::exit_qemu_or_halt, QemuExitCode, serial_println};
 use x86_64::structures::idt::InterruptStackFrame;
 
 extern "x86-interrupt" fn test_double_fault_handler(_stack_frame: InterruptStackFrame, _error_code: u64) -> !
 {
     serial_println!("[ok]");
-    exit_qemu(QemuExitCode::Success);
-    loop{}
+    exit_qemu_or_halt(QemuExitCode::Success);
 }
@@ -0,0 +1,144 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use alloc::{rc::Rc, string::String, vec::Vec};
+use blog_os::interrupts;
+use blog_os::task::{
+	Task,
+	executor::Executor,
+	timer::sleep_ticks,
+	trace::{self, TRACE_CAPACITY},
+};
+use core::cell::Cell;
+
+/// (tick, task, event) of every line of a dump
+fn parse_dump() -> Vec<(u64, u64, String)> {
+	let mut csv = String::new();
+	trace::dump(&mut csv).unwrap();
+
+	let mut lines = csv.lines();
+	assert_eq!(lines.next(), Some("tick,task,event,cycles"));
+
+	lines
+		.map(|line| {
+			let fields: Vec<&str> = line.split(',').collect();
+			assert_eq!(fields.len(), 4, "bad line {:?}", line);
+			fields[3].parse::<u64>().expect("cycles isn't a number");
+			(fields[0].parse().unwrap(), fields[1].parse().unwrap(), String::from(fields[2]))
+		})
+		.collect()
+}
+
+/// true if `wanted` shows up in `events` in this order, other events in between are fine
+fn in_order(
+	events: &[&str],
+	wanted: &[&str],
+) -> bool {
+	let mut rest = events.iter();
+	wanted.iter().all(|w| rest.any(|e| e == w))
+}
+
+#[test_case]
+fn sleeps_and_compute_are_traced() {
+	trace::clear();
+	trace::set_enabled(true);
+
+	let done = Rc::new(Cell::new(0));
+	let mut executor = Executor::new();
+
+	let short = Task::new({
+		let done = done.clone();
+		async move {
+			sleep_ticks(2).await;
+			done.set(done.get() + 1);
+		}
+	});
+	let long = Task::new({
+		let done = done.clone();
+		async move {
+			sleep_ticks(4).await;
+			done.set(done.get() + 1);
+		}
+	});
+	let compute = Task::new({
+		let done = done.clone();
+		async move {
+			let sum: u64 = (0..10_000u64).sum();
+			assert_eq!(sum, 49_995_000);
+			done.set(done.get() + 1);
+		}
+	});
+	let (short_id, long_id, compute_id) = (short.id(), long.id(), compute.id());
+
+	executor.spawn(short);
+	executor.spawn(long);
+	executor.spawn(compute);
+
+	let deadline = interrupts::ticks() + 50;
+	while done.get() < 3 {
+		assert!(interrupts::ticks() < deadline, "tasks didn't finish");
+		executor.run_polls(100);
+		x86_64::instructions::hlt();
+	}
+	trace::set_enabled(false);
+
+	let events = parse_dump();
+	assert!(events.len() < TRACE_CAPACITY, "the ring wrapped, the test can't see the start");
+
+	// timestamps never go backwards
+	assert!(events.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+
+	let of = |task: u64| -> Vec<&str> {
+		events.iter().filter(|(_, t, _)| *t == task).map(|(_, _, e)| e.as_str()).collect()
+	};
+
+	// the sleepers go to sleep, the timer wakes them, then they finish
+	let sleeping = ["ready", "polled", "woken-irq", "ready", "completed"];
+	assert!(in_order(&of(short_id), &sleeping), "{:?}", of(short_id));
+	assert!(in_order(&of(long_id), &sleeping), "{:?}", of(long_id));
+	assert_eq!(of(compute_id), ["ready", "completed"]);
+
+	let completed = |task: u64| {
+		events.iter().find(|(_, t, e)| *t == task && e == "completed").map(|(tick, _, _)| *tick)
+	};
+	let started = events.iter().find(|(_, t, _)| *t == short_id).unwrap().0;
+	assert!(completed(short_id).unwrap() >= started + 2);
+	assert!(completed(long_id).unwrap() >= started + 4);
+	assert!(completed(short_id) <= completed(long_id));
+
+	trace::clear();
+}
+
+#[test_case]
+fn nothing_recorded_while_off() {
+	trace::clear();
+
+	let mut executor = Executor::new();
+	executor.spawn(Task::new(async {}));
+	executor.run_polls(10);
+
+	assert!(parse_dump().is_empty());
+}
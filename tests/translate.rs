@@ -0,0 +1,114 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{self, LOW_MEMORY_LIMIT, MappedPageSize, TranslateResult};
+use bootloader::{BootInfo, bootinfo::MemoryRegionType, entry_point};
+use conquer_once::spin::OnceCell;
+use core::panic::PanicInfo;
+use x86_64::{
+	PhysAddr, VirtAddr, registers::control::Cr3, structures::paging::PageTableFlags as Flags,
+};
+
+entry_point!(main);
+
+static PHYS_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+
+/// first usable frame above 1 MiB, known to be covered by the physmap
+static USABLE_FRAME: OnceCell<PhysAddr> = OnceCell::uninit();
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	blog_os::init();
+
+	let usable = boot_info
+		.memory_map
+		.iter()
+		.filter(|r| r.region_type == MemoryRegionType::Usable)
+		.filter(|r| r.range.end_addr() > LOW_MEMORY_LIMIT)
+		.map(|r| r.range.start_addr().max(LOW_MEMORY_LIMIT))
+		.next()
+		.expect("no usable memory above 1 MiB");
+
+	PHYS_OFFSET.init_once(|| VirtAddr::new(boot_info.physical_memory_offset));
+	USABLE_FRAME.init_once(|| PhysAddr::new(usable));
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+fn translate(addr: VirtAddr) -> TranslateResult {
+	unsafe { memory::translate_addr(addr, *PHYS_OFFSET.get().unwrap()) }
+}
+
+/// the physmap address of `phys`
+fn physmap(phys: PhysAddr) -> VirtAddr {
+	*PHYS_OFFSET.get().unwrap() + phys.as_u64()
+}
+
+/// physical addresses we know the physmap has to cover
+fn known_frames() -> [PhysAddr; 3] {
+	let (level_4_table_frame, _) = Cr3::read();
+	[PhysAddr::new(0xb8000), level_4_table_frame.start_address(), *USABLE_FRAME.get().unwrap()]
+}
+
+#[test_case]
+fn physmap_round_trips() {
+	for &phys in &known_frames() {
+		for &offset in &[0, 0x123, 0xfff] {
+			match translate(physmap(phys + offset)) {
+				TranslateResult::Mapped { phys: found, flags, .. } => {
+					assert_eq!(found, phys + offset);
+					assert!(flags.contains(Flags::PRESENT | Flags::WRITABLE));
+				},
+				TranslateResult::NotMapped => panic!("{:?} missing from the physmap", phys),
+			}
+		}
+	}
+}
+
+#[test_case]
+fn whole_page_translates_consistently() {
+	// whatever size the bootloader picked, both ends of the page have to land in the same frame
+	for &phys in &known_frames() {
+		let page_size = match translate(physmap(phys)) {
+			TranslateResult::Mapped { page_size, .. } => page_size,
+			TranslateResult::NotMapped => panic!("{:?} missing from the physmap", phys),
+		};
+		let size = page_size.bytes();
+		let base = PhysAddr::new(phys.as_u64() & !(size - 1));
+
+		let first = translate(physmap(base));
+		let last = translate(physmap(base + (size - 1)));
+
+		assert_eq!(first.phys_addr(), Some(base));
+		assert_eq!(last.phys_addr(), Some(base + (size - 1)));
+		assert!(matches!(last, TranslateResult::Mapped { page_size: s, .. } if s == page_size));
+	}
+}
+
+#[test_case]
+fn page_sizes_match_their_mask() {
+	assert_eq!(MappedPageSize::Size4KiB.bytes(), 1 << 12);
+	assert_eq!(MappedPageSize::Size2MiB.bytes(), 1 << 21);
+	assert_eq!(MappedPageSize::Size1GiB.bytes(), 1 << 30);
+}
+
+#[test_case]
+fn vga_buffer_identity_mapped() {
+	// the bootloader maps the VGA buffer 1:1, that's what vga_buffer writes to
+	let vga = translate(VirtAddr::new(0xb8000));
+	assert_eq!(vga.phys_addr(), Some(PhysAddr::new(0xb8000)));
+}
+
+#[test_case]
+fn null_page_not_mapped() {
+	assert_eq!(translate(VirtAddr::new(0)), TranslateResult::NotMapped);
+}
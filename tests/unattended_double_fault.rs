@@ -0,0 +1,31 @@
+#![no_std]
+#![no_main]
+
+use blog_os::{serial_print, serial_println};
+use core::panic::PanicInfo;
+
+/// Overflows the stack with the kernel's own IDT, its double fault handler has to end the run
+/// with the DoubleFault code and a `PANIC code=0x14` line, scripts/expect_exit.sh checks both
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+	serial_print!("unattended_double_fault::double_fault_exits_with_its_code...\t");
+	blog_os::init();
+	serial_println!("[overflowing]");
+
+	stack_overflow();
+
+	panic!("execution continued after the stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+	stack_overflow();
+	// keeps it from becoming a loop
+	volatile::Volatile::new(0).read();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	// the wrong code, so the check fails
+	blog_os::exit::report_panic(info, x86_64::registers::read_rip().as_u64());
+}
@@ -0,0 +1,23 @@
+#![no_std]
+#![no_main]
+
+use blog_os::{serial_print, serial_println};
+use core::panic::PanicInfo;
+
+/// A panic after init, like a kernel bug, has to end the run with the KernelPanic code and a
+/// `PANIC code=0x12` line, scripts/expect_exit.sh checks both
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+	serial_print!("unattended_panic::panic_exits_with_its_code...\t");
+	blog_os::init();
+	serial_println!("[panicking]");
+
+	// the marker stays one line
+	panic!("deliberate \"panic\"\non two lines");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	// where main.rs's panic handler ends up with the feature on
+	blog_os::exit::report_panic(info, x86_64::registers::read_rip().as_u64());
+}
@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+	// the device stays in interrupt driven mode for good, so before the first test and not in it
+	BLK.call_once(init_blk);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use blog_os::{
+	assert_ok, interrupts,
+	test_harness::TestEnv,
+	virtio::{
+		OsHal,
+		async_blk::{self, AsyncVirtIOBlk},
+		pci::{self, PciConfigIo},
+	},
+};
+use spin::Once;
+use virtio_drivers::{
+	device::blk::VirtIOBlk,
+	transport::pci::{PciTransport, bus::PciRoot},
+};
+
+/// the device and the PIC line its completions come in on
+static BLK: Once<(&'static AsyncVirtIOBlk, u8)> = Once::new();
+
+/// The virtio-blk device `cargo test-virtio-intx` adds, moved into interrupt driven mode
+fn init_blk() -> (&'static AsyncVirtIOBlk, u8) {
+	let mut pci_root = PciRoot::new(PciConfigIo);
+	let device_function =
+		pci::scan(&mut pci_root).expect("no VirtIO device, run this with `cargo test-virtio-intx`");
+	assert!(pci::interrupt_pin(device_function).is_some(), "the device has no INTx pin");
+
+	let transport = assert_ok!(PciTransport::new::<OsHal, _>(&mut pci_root, device_function));
+	let device = assert_ok!(VirtIOBlk::<OsHal, _>::new(transport));
+	let blk = assert_ok!(async_blk::init(device, device_function));
+	(blk, pci::interrupt_line(device_function))
+}
+
+#[test_case]
+fn read_completes_over_intx() {
+	let &(blk, line) = BLK.r#try().expect("set up in main");
+	let before = interrupts::irq_stats(line);
+
+	let mut env = TestEnv::new();
+	let read = env
+		.run_async(async move {
+			let mut buffer = [0u8; 512];
+			async_blk::read_blocks_async(blk, 0, &mut buffer).await
+		})
+		.expect("the read never completed, no interrupt came in");
+	assert_ok!(read);
+
+	let after = interrupts::irq_stats(line);
+	assert!(after.count > before.count, "no interrupt on IRQ {}: {:?}", line, after);
+	// the handler was there for it
+	assert_eq!(after.unhandled, before.unhandled);
+}
@@ -0,0 +1,184 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+	let _env = blog_os::test_harness::init_full(boot_info);
+
+	test_main();
+
+	loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+	blog_os::test_panic_handler(info)
+}
+
+use alloc::{string::String, vec::Vec};
+use blog_os::assert_ok;
+use blog_os::fs::{
+	block_dev::MemBlockDevice,
+	glob::{matches, matches_path},
+	layout::FileType,
+	simple_fs::{FileError, FileSystem, SFS},
+	walk::{WalkDir, WalkEntry},
+};
+
+const TEST_BLOCKS: u64 = 64;
+
+/// a disk with `a.txt` (5 bytes), `b.bin` (empty) and `c.txt` (600 bytes), in that order
+fn populated_fs() -> SFS<MemBlockDevice> {
+	let mut fs = assert_ok!(SFS::format(MemBlockDevice::new(TEST_BLOCKS)));
+	assert_ok!(fs.init_root_directory());
+	let a = assert_ok!(fs.create_file("a.txt"));
+	assert_ok!(fs.write_file(a, b"hello"));
+	assert_ok!(fs.create_file("b.bin"));
+	let c = assert_ok!(fs.create_file("c.txt"));
+	assert_ok!(fs.write_file(c, &[0u8; 600]));
+	fs
+}
+
+fn paths(walk: WalkDir) -> Vec<String> {
+	walk.map(|entry| assert_ok!(entry).path).collect()
+}
+
+#[test_case]
+fn root_comes_before_its_files() {
+	let mut fs = populated_fs();
+	let entries: Vec<WalkEntry> =
+		WalkDir::new(&mut fs, "/").map(|entry| assert_ok!(entry)).collect();
+
+	assert_eq!(entries.len(), 4);
+	assert_eq!(entries[0].path, "/");
+	assert_eq!(entries[0].name(), "/");
+	assert_eq!(entries[0].depth, 0);
+	assert_eq!(entries[0].file_type, FileType::Directory);
+
+	let files: Vec<(&str, usize, u64)> =
+		entries[1..].iter().map(|entry| (entry.name(), entry.depth, entry.size)).collect();
+	assert_eq!(files, [("a.txt", 1, 5), ("b.bin", 1, 0), ("c.txt", 1, 600)]);
+	assert!(entries[1..].iter().all(|entry| entry.file_type == FileType::File));
+
+	// an empty start path is the root as well
+	assert_eq!(paths(WalkDir::new(&mut fs, "")).len(), 4);
+}
+
+#[test_case]
+fn contents_first_puts_the_root_last() {
+	let mut fs = populated_fs();
+	assert_eq!(
+		paths(WalkDir::new(&mut fs, "/").contents_first(true)),
+		["/a.txt", "/b.bin", "/c.txt", "/"]
+	);
+}
+
+#[test_case]
+fn max_depth_and_prune_keep_the_root() {
+	let mut fs = populated_fs();
+	assert_eq!(paths(WalkDir::new(&mut fs, "/").max_depth(0)), ["/"]);
+	assert_eq!(paths(WalkDir::new(&mut fs, "/").max_depth(0).contents_first(true)), ["/"]);
+
+	let mut asked = Vec::new();
+	let walk = WalkDir::new(&mut fs, "/").prune(|entry| {
+		asked.push(entry.path.clone());
+		true
+	});
+	assert_eq!(paths(walk), ["/"]);
+	// only directories are asked about
+	assert_eq!(asked, ["/"]);
+}
+
+#[test_case]
+fn starting_at_a_file_yields_just_the_file() {
+	let mut fs = populated_fs();
+	let entries: Vec<WalkEntry> =
+		WalkDir::new(&mut fs, "/c.txt").map(|entry| assert_ok!(entry)).collect();
+	assert_eq!(entries.len(), 1);
+	assert_eq!(entries[0].path, "/c.txt");
+	assert_eq!(entries[0].depth, 0);
+	assert_eq!(entries[0].size, 600);
+
+	let mut missing = WalkDir::new(&mut fs, "/nope");
+	assert_eq!(missing.next(), Some(Err(FileError::FileNotFound)));
+	assert!(missing.next().is_none());
+
+	// there are no directories below the root to go through
+	let mut nested = WalkDir::new(&mut fs, "/a.txt/x");
+	assert_eq!(nested.next(), Some(Err(FileError::FileNotFound)));
+}
+
+#[test_case]
+fn walk_sees_deleted_files_gone() {
+	let mut fs = populated_fs();
+	assert_ok!(fs.delete_file("b.bin"));
+	assert_eq!(paths(WalkDir::new(&mut fs, "/")), ["/", "/a.txt", "/c.txt"]);
+}
+
+#[test_case]
+fn glob_wildcards() {
+	assert!(matches("*", ""));
+	assert!(matches("*", "anything"));
+	assert!(matches("*.txt", "notes.txt"));
+	assert!(matches("*.txt", ".txt"));
+	assert!(!matches("*.txt", "notes.txt.bak"));
+	assert!(matches("a*b*c", "aXbYbZc"));
+	assert!(matches("**a", "a"));
+	assert!(!matches("a*", "ba"));
+
+	assert!(matches("?", "x"));
+	assert!(!matches("?", ""));
+	assert!(!matches("?", "xy"));
+	assert!(matches("??.rs", "ab.rs"));
+
+	// an empty pattern only matches an empty name
+	assert!(matches("", ""));
+	assert!(!matches("", "a"));
+
+	// multibyte chars are one char each
+	assert!(matches("?", "ä"));
+	assert!(matches("*ö", "äö"));
+}
+
+#[test_case]
+fn glob_classes() {
+	assert!(matches("[abc]", "b"));
+	assert!(!matches("[abc]", "d"));
+	assert!(matches("file[0-9].txt", "file7.txt"));
+	assert!(!matches("file[0-9].txt", "fileA.txt"));
+	assert!(matches("[a-cx-z]", "y"));
+
+	assert!(matches("[!a-c]", "d"));
+	assert!(!matches("[!a-c]", "b"));
+	assert!(matches("[^a-c]", "d"));
+
+	// `]` first in the class and `-` last are plain chars
+	assert!(matches("[]]", "]"));
+	assert!(matches("[!]]", "a"));
+	assert!(!matches("[!]]", "]"));
+	assert!(matches("[a-]", "-"));
+
+	// a `[` that's never closed is a plain `[`
+	assert!(matches("[abc", "[abc"));
+	assert!(!matches("[abc", "a"));
+	assert!(matches("*[", "x["));
+}
+
+#[test_case]
+fn glob_paths_go_by_component() {
+	assert!(matches_path("/*.txt", "/notes.txt"));
+	assert!(matches_path("*/*", "/dir/file"));
+	assert!(!matches_path("*", "/dir/file"));
+	assert!(!matches_path("/*", "/"));
+	assert!(matches_path("/", "/"));
+	assert!(matches_path("dir//[f]ile/", "/dir/file"));
+}
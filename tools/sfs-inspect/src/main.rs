@@ -0,0 +1,334 @@
+//! in tools/sfs-inspect/src/main.rs
+//!
+//! looks at an SFS image file on the host, without booting the kernel. Nothing is written to the
+//! image. The format code is the kernel's own, layout.rs and image.rs built for the host.
+//!
+//! usage: sfs-inspect <image> <command>
+//!   superblock      the superblock's fields and whether the kernel would mount it
+//!   inode <n>       one inode, allocated or not
+//!   dir [path]      what's in a directory, the root if there's no path
+//!   cat <path>      a file's contents, raw on stdout
+//!   fsck            the kernel's consistency check
+//!   hexblock <n>    one block as hex and ASCII
+//!
+//! Exits with 1 if the image has problems (a superblock that doesn't mount, anything fsck
+//! finds, something unreadable) and 2 for a wrong command line.
+
+extern crate alloc;
+extern crate static_assertions as sa;
+
+// what the format code prints, it's `crate::println!` there
+use std::println;
+
+#[allow(dead_code)]
+#[path = "../../../src/fs/layout.rs"]
+mod layout;
+
+#[allow(dead_code)]
+#[path = "../../../src/fs/image.rs"]
+mod image;
+
+#[cfg(test)]
+mod tests;
+
+use image::{BlockSource, ImageError, SuperBlockProblem};
+use layout::{BLOCK_SIZE, FileType, SUPERBLOCK_BLOCK, SUPERBLOCK_DIRTY};
+use std::{
+	env, fmt,
+	fs::File,
+	io::{self, Read, Seek, SeekFrom, Write},
+	process,
+};
+
+const USAGE: &str = "\
+usage: sfs-inspect <image> <command>
+  superblock      the superblock's fields and whether the kernel would mount it
+  inode <n>       one inode, allocated or not
+  dir [path]      what's in a directory, the root if there's no path
+  cat <path>      a file's contents, raw on stdout
+  fsck            the kernel's consistency check
+  hexblock <n>    one block as hex and ASCII
+";
+
+/// An image file, or anything else that reads and seeks, as a `BlockSource`
+pub struct ImageFile<R> {
+	inner: R,
+	/// whole blocks in it, a partial one at the end doesn't count
+	blocks: u64,
+}
+
+impl<R: Read + Seek> ImageFile<R> {
+	pub fn new(mut inner: R) -> io::Result<Self> {
+		let len = inner.seek(SeekFrom::End(0))?;
+		Ok(ImageFile { inner, blocks: len / BLOCK_SIZE as u64 })
+	}
+}
+
+impl<R: Read + Seek> BlockSource for ImageFile<R> {
+	fn read_at(
+		&mut self,
+		block: u64,
+		buffer: &mut [u8],
+	) -> Result<(), ImageError> {
+		// past the end fails like it would on a device, instead of reading short
+		let count = (buffer.len() / BLOCK_SIZE) as u64;
+		if block.checked_add(count).is_none_or(|end| end > self.blocks) {
+			return Err(ImageError::Read);
+		}
+
+		self.inner
+			.seek(SeekFrom::Start(block * BLOCK_SIZE as u64))
+			.map_err(|_| ImageError::Read)?;
+		self.inner.read_exact(buffer).map_err(|_| ImageError::Read)
+	}
+
+	fn block_count(&self) -> u64 {
+		self.blocks
+	}
+}
+
+/// Why a command didn't get to the end
+#[derive(Debug)]
+pub enum Error {
+	/// the command line doesn't make sense
+	Usage,
+	Image(ImageError),
+	Io(io::Error),
+}
+
+impl From<ImageError> for Error {
+	fn from(e: ImageError) -> Self {
+		Error::Image(e)
+	}
+}
+
+impl From<io::Error> for Error {
+	fn from(e: io::Error) -> Self {
+		Error::Io(e)
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(
+		&self,
+		f: &mut fmt::Formatter,
+	) -> fmt::Result {
+		match self {
+			Error::Usage => f.write_str("bad command line"),
+			Error::Image(e) => write!(f, "{:?}", e),
+			Error::Io(e) => write!(f, "{}", e),
+		}
+	}
+}
+
+fn main() {
+	let args: Vec<String> = env::args().skip(1).collect();
+	if args.len() < 2 {
+		eprint!("{}", USAGE);
+		process::exit(2);
+	}
+
+	let file = File::open(&args[0]).unwrap_or_else(|e| {
+		eprintln!("sfs-inspect: {}: {}", args[0], e);
+		process::exit(1);
+	});
+	let mut image = ImageFile::new(file).unwrap_or_else(|e| {
+		eprintln!("sfs-inspect: {}: {}", args[0], e);
+		process::exit(1);
+	});
+
+	let stdout = io::stdout();
+	let mut out = stdout.lock();
+	match run(&mut image, &args[1..], &mut out) {
+		Ok(true) => {},
+		Ok(false) => process::exit(1),
+		Err(Error::Usage) => {
+			eprint!("{}", USAGE);
+			process::exit(2);
+		},
+		Err(e) => {
+			let _ = out.flush();
+			eprintln!("sfs-inspect: {}", e);
+			process::exit(1);
+		},
+	}
+}
+
+/// Runs one command on the image in `src`, false if it found the image broken
+pub fn run<S: BlockSource + ?Sized>(
+	src: &mut S,
+	args: &[String],
+	out: &mut impl Write,
+) -> Result<bool, Error> {
+	let args: Vec<&str> = args.iter().map(String::as_str).collect();
+	match args.as_slice() {
+		["superblock"] => superblock(src, out),
+		["inode", n] => inode(src, parse(n)?, out),
+		["dir"] => dir(src, "/", out),
+		["dir", path] => dir(src, path, out),
+		["cat", path] => {
+			let sb = image::read_superblock(src)?;
+			let inode = image::resolve(src, &sb, path)?;
+			out.write_all(&image::read_file(src, &sb, inode)?)?;
+			Ok(true)
+		},
+		["fsck"] => fsck(src, out),
+		["hexblock", n] => hexblock(src, parse(n)?, out),
+		_ => Err(Error::Usage),
+	}
+}
+
+/// a number in decimal or with `0x` in hex
+fn parse(n: &str) -> Result<u64, Error> {
+	match n.strip_prefix("0x") {
+		Some(hex) => u64::from_str_radix(hex, 16),
+		None => n.parse(),
+	}
+	.map_err(|_| Error::Usage)
+}
+
+fn superblock<S: BlockSource + ?Sized>(
+	src: &mut S,
+	out: &mut impl Write,
+) -> Result<bool, Error> {
+	let mut block = [0u8; BLOCK_SIZE];
+	src.read_at(SUPERBLOCK_BLOCK, &mut block)?;
+	let disk = image::disk_superblock(&block);
+
+	let fields: [(&str, u64); 11] = [
+		("total_blocks", disk.total_blocks.get()),
+		("inode_bitmap_block", disk.inode_bitmap_block.get()),
+		("inode_bitmap_blocks", disk.inode_bitmap_blocks.get()),
+		("data_bitmap_block", disk.data_bitmap_block.get()),
+		("data_bitmap_blocks", disk.data_bitmap_blocks.get()),
+		("inode_table_start_block", disk.inode_table_start_block.get()),
+		("inode_count", disk.inode_count.get()),
+		("data_block_start", disk.data_block_start.get()),
+		("data_block_count", disk.data_block_count.get()),
+		("version", disk.version.get() as u64),
+		("flags", disk.flags.get() as u64),
+	];
+	writeln!(out, "{:<24}{:#x}", "magic_number", disk.magic_number.get())?;
+	for (name, value) in fields.iter() {
+		writeln!(out, "{:<24}{}", name, value)?;
+	}
+	if disk.flags.get() & SUPERBLOCK_DIRTY != 0 {
+		writeln!(out, "not unmounted cleanly")?;
+	}
+
+	match image::check_superblock(disk, src.block_count()) {
+		Ok(_) => {
+			writeln!(out, "valid, {} blocks in the image", src.block_count())?;
+			Ok(true)
+		},
+		Err(problem) => {
+			let why = match problem {
+				SuperBlockProblem::UnknownVersion => "a version this code doesn't know",
+				SuperBlockProblem::BadMagic => "wrong magic number, not an SFS image",
+				SuperBlockProblem::Inconsistent => "the regions overlap or don't fit together",
+				SuperBlockProblem::PastTheEnd => "more blocks than the image has",
+			};
+			writeln!(out, "INVALID: {}", why)?;
+			Ok(false)
+		},
+	}
+}
+
+fn inode<S: BlockSource + ?Sized>(
+	src: &mut S,
+	n: u64,
+	out: &mut impl Write,
+) -> Result<bool, Error> {
+	let sb = image::read_superblock(src)?;
+	let inode = image::read_inode(src, &sb, n)?;
+	let allocated = image::bitmap_bit(src, sb.inode_bitmap_block, n)?;
+
+	writeln!(out, "inode {}{}", n, if allocated { "" } else { " (free in the bitmap)" })?;
+	writeln!(out, "{:<24}{:?}", "mode", inode.mode)?;
+	writeln!(out, "{:<24}{}", "size_in_bytes", inode.size_in_bytes)?;
+	writeln!(out, "{:<24}{}", "link_count", inode.link_count)?;
+	writeln!(out, "{:<24}{}:{}", "user:group", inode.user_id, inode.group_id)?;
+	writeln!(out, "{:<24}{}", "last_access_time", inode.last_access_time)?;
+	writeln!(out, "{:<24}{}", "last_modification_time", inode.last_modification_time)?;
+	writeln!(out, "{:<24}{}", "creation_time", inode.creation_time)?;
+	writeln!(out, "{:<24}{:?}", "direct_pointers", inode.direct_pointers)?;
+	writeln!(out, "{:<24}{}", "indirect_pointer", inode.indirect_pointer)?;
+	Ok(true)
+}
+
+fn dir<S: BlockSource + ?Sized>(
+	src: &mut S,
+	path: &str,
+	out: &mut impl Write,
+) -> Result<bool, Error> {
+	let sb = image::read_superblock(src)?;
+	let dir = image::resolve(src, &sb, path)?;
+
+	let mut clean = true;
+	for entry in image::list_dir(src, &sb, dir)? {
+		match image::read_inode(src, &sb, entry.inode) {
+			Ok(inode) => {
+				let kind = match inode.mode {
+					FileType::File => "file",
+					FileType::Directory => "dir",
+					FileType::Unknown => "?",
+				};
+				writeln!(
+					out,
+					"{:>6}  {:<4} {:>8}  {}",
+					entry.inode, kind, inode.size_in_bytes, entry.name
+				)?;
+			},
+			Err(e) => {
+				writeln!(out, "{:>6}  {:?}  {}", entry.inode, e, entry.name)?;
+				clean = false;
+			},
+		}
+	}
+	Ok(clean)
+}
+
+fn fsck<S: BlockSource + ?Sized>(
+	src: &mut S,
+	out: &mut impl Write,
+) -> Result<bool, Error> {
+	let sb = image::read_superblock(src)?;
+	let report = image::fsck(src, &sb)?;
+
+	writeln!(
+		out,
+		"{} inodes and {} data blocks in use",
+		report.used_inodes, report.used_data_blocks
+	)?;
+	for problem in &report.problems {
+		writeln!(out, "{:?}", problem)?;
+	}
+	if report.is_clean() {
+		writeln!(out, "clean")?;
+	} else {
+		writeln!(out, "{} problems", report.problems.len())?;
+	}
+	Ok(report.is_clean())
+}
+
+fn hexblock<S: BlockSource + ?Sized>(
+	src: &mut S,
+	n: u64,
+	out: &mut impl Write,
+) -> Result<bool, Error> {
+	let mut block = [0u8; BLOCK_SIZE];
+	src.read_at(n, &mut block)?;
+
+	for (i, line) in block.chunks(16).enumerate() {
+		write!(out, "{:04x} ", i * 16)?;
+		for byte in line {
+			write!(out, " {:02x}", byte)?;
+		}
+		let ascii: String = line
+			.iter()
+			.map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+			.collect();
+		writeln!(out, "  |{}|", ascii)?;
+	}
+	Ok(true)
+}
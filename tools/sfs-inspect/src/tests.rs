@@ -0,0 +1,306 @@
+//! in tools/sfs-inspect/src/tests.rs
+//!
+//! the commands against small images laid out by hand, good ones and broken ones
+
+use super::{Error, ImageFile, run};
+use crate::image::{FsckProblem, ImageError, SuperBlockProblem};
+use crate::layout::{
+	BLOCK_SIZE, DIR_ENTRY_SIZE, DIR_NAME_MAX, DIRENT_USED, DiskDirEntry, DiskInode, DiskSuperBlock,
+	FileType, INODE_SIZE, Inode, MAGIC_NUMBER, SUPERBLOCK_DIRTY, SUPERBLOCK_VERSION, SuperBlock,
+};
+use std::io::{Cursor, Write};
+use zerocopy::{IntoBytes, U16, U64};
+
+const BLOCKS: u64 = 64;
+const INODE_COUNT: u64 = 16;
+const INODE_TABLE: u64 = 3;
+const DATA_START: u64 = 7;
+
+/// what `hello.txt` holds
+const HELLO: &[u8] = b"hello";
+/// `big.bin` spans two blocks, 700 bytes counting up
+const BIG_LEN: usize = 700;
+
+fn big() -> Vec<u8> {
+	(0..BIG_LEN).map(|i| i as u8).collect()
+}
+
+/// An image like `SFS::format` would leave it with three files written
+///
+/// Inode 0 is the root with its entries in block 7, `hello.txt` is inode 1 in block 8, `big.bin`
+/// inode 2 in blocks 9 and 10, `empty` inode 3 with no blocks.
+fn fixture() -> Vec<u8> {
+	let mut image = vec![0u8; BLOCKS as usize * BLOCK_SIZE];
+
+	let sb = SuperBlock {
+		total_blocks: BLOCKS,
+		inode_bitmap_block: 1,
+		data_bitmap_block: 2,
+		inode_table_start_block: INODE_TABLE,
+		inode_count: INODE_COUNT,
+		data_block_start: DATA_START,
+		data_block_count: BLOCKS - DATA_START,
+		inode_bitmap_blocks: 1,
+		data_bitmap_blocks: 1,
+		magic_number: MAGIC_NUMBER,
+		version: SUPERBLOCK_VERSION,
+		flags: 0,
+	};
+	put(&mut image, 0, 0, DiskSuperBlock::from(sb).as_bytes());
+
+	// inodes 0 to 3 and data blocks 7 to 10
+	put(&mut image, 1, 0, &[0b1111]);
+	put(&mut image, 2, 0, &[0b1111]);
+
+	put_inode(&mut image, 0, FileType::Directory, 0, &[DATA_START]);
+	put_inode(&mut image, 1, FileType::File, HELLO.len() as u64, &[8]);
+	put_inode(&mut image, 2, FileType::File, BIG_LEN as u64, &[9, 10]);
+	put_inode(&mut image, 3, FileType::File, 0, &[]);
+
+	let entries = [(".", 0), ("..", 0), ("hello.txt", 1), ("big.bin", 2), ("empty", 3)];
+	for (slot, &(name, inode)) in entries.iter().enumerate() {
+		let mut entry = DiskDirEntry {
+			inode: U64::new(inode),
+			name_len: U16::new(name.len() as u16),
+			flags: U16::new(DIRENT_USED),
+			name: [0; DIR_NAME_MAX],
+		};
+		entry.name[..name.len()].copy_from_slice(name.as_bytes());
+		put(&mut image, DATA_START, slot * DIR_ENTRY_SIZE, entry.as_bytes());
+	}
+
+	put(&mut image, 8, 0, HELLO);
+	put(&mut image, 9, 0, &big());
+	image
+}
+
+/// copies `bytes` into the image at `offset` in `block`
+fn put(
+	image: &mut [u8],
+	block: u64,
+	offset: usize,
+	bytes: &[u8],
+) {
+	let at = block as usize * BLOCK_SIZE + offset;
+	image[at..at + bytes.len()].copy_from_slice(bytes);
+}
+
+fn put_inode(
+	image: &mut [u8],
+	index: u64,
+	mode: FileType,
+	size: u64,
+	blocks: &[u64],
+) {
+	let mut direct_pointers = [0u64; 10];
+	direct_pointers[..blocks.len()].copy_from_slice(blocks);
+	let inode = Inode {
+		mode,
+		user_id: 0,
+		group_id: 0,
+		link_count: 1,
+		size_in_bytes: size,
+		last_access_time: 0,
+		last_modification_time: 0,
+		creation_time: 0,
+		direct_pointers,
+		indirect_pointer: 0,
+	};
+
+	let block = INODE_TABLE + index / 4;
+	let offset = (index % 4) as usize * INODE_SIZE;
+	put(image, block, offset, DiskInode::from(inode).as_bytes());
+}
+
+/// runs `command` on `image`, what it printed and whether the image was fine
+fn inspect(
+	image: Vec<u8>,
+	command: &str,
+) -> Result<(String, bool), Error> {
+	let mut source = ImageFile::new(Cursor::new(image)).unwrap();
+	let args: Vec<String> = command.split_whitespace().map(String::from).collect();
+	let mut out = Vec::new();
+	let fine = run(&mut source, &args, &mut out)?;
+	Ok((String::from_utf8_lossy(&out).into_owned(), fine))
+}
+
+fn image_error(result: Result<(String, bool), Error>) -> ImageError {
+	match result {
+		Err(Error::Image(e)) => e,
+		other => panic!("expected an image error, got {:?}", other),
+	}
+}
+
+#[test]
+fn superblock_of_a_good_image() {
+	let (out, fine) = inspect(fixture(), "superblock").unwrap();
+	assert!(fine);
+	assert!(out.starts_with("magic_number            0xdeadbeef\n"));
+	assert!(out.contains("inode_count             16\n"));
+	assert!(out.ends_with("valid, 64 blocks in the image\n"));
+	assert!(!out.contains("not unmounted cleanly"));
+}
+
+#[test]
+fn superblock_verdicts() {
+	let mut image = fixture();
+	put(&mut image, 0, 56, &0u32.to_le_bytes());
+	let (out, fine) = inspect(image, "superblock").unwrap();
+	assert!(!fine);
+	assert!(out.ends_with("INVALID: wrong magic number, not an SFS image\n"));
+
+	// the image got cut short
+	let mut image = fixture();
+	image.truncate(32 * BLOCK_SIZE);
+	let (out, fine) = inspect(image, "superblock").unwrap();
+	assert!(!fine);
+	assert!(out.ends_with("INVALID: more blocks than the image has\n"));
+
+	let mut image = fixture();
+	put(&mut image, 0, 80, &SUPERBLOCK_DIRTY.to_le_bytes());
+	let (out, fine) = inspect(image, "superblock").unwrap();
+	assert!(fine);
+	assert!(out.contains("not unmounted cleanly\n"));
+}
+
+#[test]
+fn broken_superblock_stops_everything_else() {
+	let mut image = fixture();
+	put(&mut image, 0, 56, &0u32.to_le_bytes());
+	for command in ["fsck", "dir", "cat hello.txt", "inode 1"].iter() {
+		assert_eq!(
+			image_error(inspect(image.clone(), command)),
+			ImageError::InvalidSuperBlock(SuperBlockProblem::BadMagic),
+			"{}",
+			command
+		);
+	}
+	// raw blocks are still there to look at
+	assert!(inspect(image, "hexblock 8").is_ok());
+}
+
+#[test]
+fn dir_lists_the_root() {
+	let (out, fine) = inspect(fixture(), "dir").unwrap();
+	assert!(fine);
+	assert_eq!(
+		out,
+		"     1  file        5  hello.txt\n     2  file      700  big.bin\n     3  file        0  empty\n"
+	);
+	assert_eq!(inspect(fixture(), "dir /").unwrap().0, out);
+
+	assert_eq!(image_error(inspect(fixture(), "dir /hello.txt")), ImageError::WrongType);
+	assert_eq!(image_error(inspect(fixture(), "dir /nope")), ImageError::NotFound);
+}
+
+#[test]
+fn cat_reads_across_blocks() {
+	assert_eq!(inspect(fixture(), "cat hello.txt").unwrap().0.as_bytes(), HELLO);
+	assert_eq!(inspect(fixture(), "cat /empty").unwrap().0, "");
+
+	let mut source = ImageFile::new(Cursor::new(fixture())).unwrap();
+	let mut out = Vec::new();
+	assert!(run(&mut source, &["cat".to_string(), "/big.bin".to_string()], &mut out).unwrap());
+	assert_eq!(out, big());
+
+	assert_eq!(image_error(inspect(fixture(), "cat /")), ImageError::WrongType);
+}
+
+#[test]
+fn cat_refuses_pointers_it_cant_follow() {
+	// pointing into the inode table
+	let mut image = fixture();
+	put_inode(&mut image, 1, FileType::File, HELLO.len() as u64, &[INODE_TABLE]);
+	assert_eq!(image_error(inspect(image, "cat hello.txt")), ImageError::Corrupt);
+
+	// a hole
+	let mut image = fixture();
+	put_inode(&mut image, 2, FileType::File, BIG_LEN as u64, &[9]);
+	assert_eq!(image_error(inspect(image, "cat big.bin")), ImageError::Corrupt);
+
+	// more than the direct pointers hold, nothing gets allocated for it
+	let mut image = fixture();
+	put_inode(&mut image, 1, FileType::File, u64::MAX, &[8]);
+	assert_eq!(image_error(inspect(image, "cat hello.txt")), ImageError::Corrupt);
+}
+
+#[test]
+fn fsck_of_a_good_image() {
+	let (out, fine) = inspect(fixture(), "fsck").unwrap();
+	assert!(fine);
+	assert_eq!(out, "4 inodes and 4 data blocks in use\nclean\n");
+}
+
+#[test]
+fn fsck_finds_what_the_kernel_would() {
+	// big.bin's second block is free in the bitmap
+	let mut image = fixture();
+	put(&mut image, 2, 0, &[0b0111]);
+	let (out, fine) = inspect(image, "fsck").unwrap();
+	assert!(!fine);
+	assert!(
+		out.contains(&format!("{:?}", FsckProblem::PointerToFreeBlock { inode: 2, block: 10 }))
+	);
+	assert!(out.ends_with("1 problems\n"));
+
+	// hello.txt points past the end of the image
+	let mut image = fixture();
+	put_inode(&mut image, 1, FileType::File, HELLO.len() as u64, &[BLOCKS + 5]);
+	let out = inspect(image, "fsck").unwrap().0;
+	assert!(out.contains(&format!("{:?}", FsckProblem::PointerOutOfRange { inode: 1, block: 69 })));
+
+	// a bit for an inode that doesn't exist
+	let mut image = fixture();
+	put(&mut image, 1, (INODE_COUNT / 8) as usize, &[1]);
+	let out = inspect(image, "fsck").unwrap().0;
+	assert!(out.contains(&format!("{:?}", FsckProblem::InodeBitmapTailSet)));
+}
+
+#[test]
+fn inode_shows_the_fields() {
+	let (out, fine) = inspect(fixture(), "inode 2").unwrap();
+	assert!(fine);
+	assert!(out.starts_with("inode 2\n"));
+	assert!(out.contains("mode                    File\n"));
+	assert!(out.contains("size_in_bytes           700\n"));
+	assert!(out.contains("direct_pointers         [9, 10, 0, 0, 0, 0, 0, 0, 0, 0]\n"));
+
+	assert!(
+		inspect(fixture(), "inode 0x5").unwrap().0.starts_with("inode 5 (free in the bitmap)\n")
+	);
+	assert_eq!(image_error(inspect(fixture(), "inode 16")), ImageError::NoSuchInode(16));
+}
+
+#[test]
+fn hexblock_dumps_bytes() {
+	let (out, fine) = inspect(fixture(), "hexblock 8").unwrap();
+	assert!(fine);
+	assert_eq!(out.lines().count(), BLOCK_SIZE / 16);
+	assert_eq!(
+		out.lines().next().unwrap(),
+		"0000  68 65 6c 6c 6f 00 00 00 00 00 00 00 00 00 00 00  |hello...........|"
+	);
+
+	assert_eq!(image_error(inspect(fixture(), "hexblock 64")), ImageError::Read);
+}
+
+#[test]
+fn bad_command_lines() {
+	for command in ["", "nope", "inode", "inode x", "cat", "dir a b"].iter() {
+		assert!(matches!(inspect(fixture(), command), Err(Error::Usage)), "{}", command);
+	}
+}
+
+#[test]
+fn reads_an_image_file() {
+	let path = std::env::temp_dir().join(format!("sfs-inspect-{}.img", std::process::id()));
+	std::fs::File::create(&path).unwrap().write_all(&fixture()).unwrap();
+
+	let mut source = ImageFile::new(std::fs::File::open(&path).unwrap()).unwrap();
+	let mut out = Vec::new();
+	let fine = run(&mut source, &["fsck".to_string()], &mut out);
+	std::fs::remove_file(&path).unwrap();
+
+	assert!(fine.unwrap());
+	assert!(String::from_utf8(out).unwrap().ends_with("clean\n"));
+}